@@ -11,13 +11,47 @@ fn guess_name() -> Option<Ident> {
     }
 }
 
+/// Register a derived component for `World::clone_entity` if it's `Clone`. Generic
+/// components can't be registered, since there's no single type to register.
+fn clone_registration(input: &DeriveInput, root: &Option<Ident>) -> proc_macro2::TokenStream {
+    if !input.generics.params.is_empty() {
+        return quote!();
+    }
+
+    let name = &input.ident;
+    quote! {
+        #root::sludge::inventory::submit! {
+            {
+                #[allow(unused_imports)]
+                use #root::sludge::{ProbeClone as _, ProbeNoClone as _};
+                (&#root::sludge::CloneProbe::<#name>(::std::marker::PhantomData)).clone_component()
+            }
+        }
+    }
+}
+
+/// Register a derived component's name and size for memory reports. Like the clone
+/// registration, this is skipped for generic components.
+fn layout_registration(input: &DeriveInput, root: &Option<Ident>) -> proc_macro2::TokenStream {
+    if !input.generics.params.is_empty() {
+        return quote!();
+    }
+
+    let name = &input.ident;
+    quote! {
+        #root::sludge::inventory::submit! {
+            #root::sludge::ComponentLayout::of::<#name>()
+        }
+    }
+}
+
 #[proc_macro_derive(SimpleComponent)]
 pub fn derive_simple_component(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     // Parse the input tokens into a syntax tree.
     let input = parse_macro_input!(input as DeriveInput);
 
     // Used in the quasi-quotation below as `#name`.
-    let name = input.ident;
+    let name = &input.ident;
 
     let context_lifetime = Lifetime::new("'a", Span::call_site());
     let mut generics = input.generics.clone();
@@ -29,11 +63,16 @@ pub fn derive_simple_component(input: proc_macro::TokenStream) -> proc_macro::To
     let (_, ty_generics, _) = input.generics.split_for_impl();
 
     let root = guess_name();
+    let clone_registration = clone_registration(&input, &root);
+    let layout_registration = layout_registration(&input, &root);
 
     let expanded = quote! {
         // The generated impl.
         impl #impl_generics #root::sludge::SmartComponent<#root::sludge::ScContext<#context_lifetime>>
             for #name #ty_generics #where_clause {}
+
+        #clone_registration
+        #layout_registration
     };
 
     // Hand the output tokens back to the compiler.
@@ -46,7 +85,7 @@ pub fn derive_flagged_component(input: proc_macro::TokenStream) -> proc_macro::T
     let input = parse_macro_input!(input as DeriveInput);
 
     // Used in the quasi-quotation below as `#name`.
-    let name = input.ident;
+    let name = &input.ident;
 
     let context_lifetime = Lifetime::new("'a", Span::call_site());
     let mut generics = input.generics.clone();
@@ -58,6 +97,8 @@ pub fn derive_flagged_component(input: proc_macro::TokenStream) -> proc_macro::T
     let (_, original_generics, _) = input.generics.split_for_impl();
 
     let root = guess_name();
+    let clone_registration = clone_registration(&input, &root);
+    let layout_registration = layout_registration(&input, &root);
 
     let expanded = quote! {
         // The generated impl.
@@ -72,8 +113,505 @@ pub fn derive_flagged_component(input: proc_macro::TokenStream) -> proc_macro::T
         #root::sludge::inventory::submit! {
             #root::sludge::FlaggedComponent::of::<#name #original_generics>()
         }
+
+        #clone_registration
+        #layout_registration
     };
 
     // Hand the output tokens back to the compiler.
     proc_macro::TokenStream::from(expanded)
 }
+
+struct AccessorField {
+    member: Member,
+    name: String,
+    ty: Type,
+    readonly: bool,
+}
+
+/// Parse a field's `#[lua(...)]` attributes, returning `None` if the field is skipped.
+fn accessor_field(member: Member, field: &Field) -> Result<Option<AccessorField>> {
+    let mut name = match &member {
+        Member::Named(ident) => ident.to_string(),
+        Member::Unnamed(_) => String::new(),
+    };
+    let mut readonly = false;
+
+    for attr in field.attrs.iter().filter(|attr| attr.path.is_ident("lua")) {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            other => return Err(Error::new_spanned(other, "expected `#[lua(...)]`")),
+        };
+
+        for nested in list.nested {
+            match nested {
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("skip") => return Ok(None),
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("readonly") => readonly = true,
+                NestedMeta::Meta(Meta::NameValue(MetaNameValue {
+                    path,
+                    lit: Lit::Str(lit),
+                    ..
+                })) if path.is_ident("rename") => name = lit.value(),
+                other => {
+                    return Err(Error::new_spanned(
+                        other,
+                        "expected `skip`, `readonly` or `rename = \"...\"`",
+                    ))
+                }
+            }
+        }
+    }
+
+    Ok(Some(AccessorField {
+        member,
+        name,
+        ty: field.ty.clone(),
+        readonly,
+    }))
+}
+
+fn lua_accessor(input: &DeriveInput) -> Result<proc_macro2::TokenStream> {
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "`LuaAccessor` can't be derived for generic types",
+        ));
+    }
+
+    let name = &input.ident;
+    let name_str = name.to_string();
+    let accessor = Ident::new(&format!("{}Accessor", name), name.span());
+    let root = guess_name();
+
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => {
+            return Err(Error::new_spanned(
+                name,
+                "`LuaAccessor` can only be derived for structs",
+            ))
+        }
+    };
+
+    let methods = match fields {
+        // Named fields are exposed as fields of the accessor, through `__index` and
+        // `__newindex`.
+        Fields::Named(named) => {
+            let mut exposed = Vec::new();
+            for field in &named.named {
+                let member = Member::Named(field.ident.clone().unwrap());
+                exposed.extend(accessor_field(member, field)?);
+            }
+
+            let get_names = exposed.iter().map(|f| &f.name);
+            let get_members = exposed.iter().map(|f| &f.member);
+            let writable = exposed.iter().filter(|f| !f.readonly).collect::<Vec<_>>();
+            let set_names = writable.iter().map(|f| &f.name);
+            let set_members = writable.iter().map(|f| &f.member);
+            let set_tys = writable.iter().map(|f| &f.ty);
+            let readonly_names = exposed
+                .iter()
+                .filter(|f| f.readonly)
+                .map(|f| &f.name)
+                .collect::<Vec<_>>();
+            let readonly_patterns = &readonly_names;
+
+            quote! {
+                methods.add_meta_method(LuaMetaMethod::Index, |lua, this, key: LuaString| {
+                    let tmp = lua.fetch_one::<World>()?;
+                    let world = tmp.borrow();
+                    let component = world.get::<#name>(this.0).to_lua_err()?;
+                    match key.to_str()? {
+                        #(#get_names => component.#get_members.clone().to_lua(lua),)*
+                        _ => Ok(LuaValue::Nil),
+                    }
+                });
+
+                methods.add_meta_method(
+                    LuaMetaMethod::NewIndex,
+                    |lua, this, (key, value): (LuaString, LuaValue)| {
+                        let tmp = lua.fetch_one::<World>()?;
+                        let world = tmp.borrow();
+                        let mut component = world.get_mut::<#name>(this.0).to_lua_err()?;
+                        match key.to_str()? {
+                            #(#set_names => {
+                                component.#set_members = <#set_tys>::from_lua(value, lua)?;
+                            })*
+                            #(#readonly_patterns => {
+                                return Err(anyhow!(
+                                    "field {} of {} is read-only",
+                                    #readonly_names,
+                                    #name_str,
+                                )
+                                .to_lua_err());
+                            })*
+                            other => {
+                                return Err(anyhow!("no such field {} for {}", other, #name_str)
+                                    .to_lua_err());
+                            }
+                        }
+                        Ok(())
+                    },
+                );
+            }
+        }
+        // Newtypes are exposed through `get` and `set` methods, like `Name`.
+        Fields::Unnamed(unnamed) if unnamed.unnamed.len() == 1 => {
+            let field = &unnamed.unnamed[0];
+            let member = Member::Unnamed(Index::from(0));
+            match accessor_field(member, field)? {
+                Some(AccessorField {
+                    member,
+                    ty,
+                    readonly,
+                    ..
+                }) => {
+                    let set = if readonly {
+                        quote!()
+                    } else {
+                        quote! {
+                            methods.add_method("set", |lua, this, value: LuaValue| {
+                                let tmp = lua.fetch_one::<World>()?;
+                                let world = tmp.borrow();
+                                let mut component =
+                                    world.get_mut::<#name>(this.0).to_lua_err()?;
+                                component.#member = <#ty>::from_lua(value, lua)?;
+                                Ok(())
+                            });
+                        }
+                    };
+
+                    quote! {
+                        methods.add_method("get", |lua, this, ()| {
+                            let tmp = lua.fetch_one::<World>()?;
+                            let world = tmp.borrow();
+                            let component = world.get::<#name>(this.0).to_lua_err()?;
+                            component.#member.clone().to_lua(lua)
+                        });
+
+                        #set
+                    }
+                }
+                None => quote!(),
+            }
+        }
+        _ => {
+            return Err(Error::new_spanned(
+                fields,
+                "`LuaAccessor` needs named fields or a single unnamed field",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        #[derive(Debug, Clone, Copy)]
+        pub struct #accessor(#root::sludge::Entity);
+
+        const _: () = {
+            #[allow(unused_imports)]
+            use #root::sludge::{
+                anyhow::anyhow, rlua::prelude::*, rlua_serde, EntityBuilder,
+                LuaComponentInterface, SludgeLuaContextExt, World,
+            };
+
+            impl LuaUserData for #accessor {
+                fn add_methods<'lua, T: LuaUserDataMethods<'lua, Self>>(methods: &mut T) {
+                    #methods
+
+                    methods.add_method("to_table", |lua, this, ()| {
+                        let tmp = lua.fetch_one::<World>()?;
+                        let world = tmp.borrow();
+                        let component = world.get::<#name>(this.0).to_lua_err()?;
+                        rlua_serde::to_value(lua, &*component)
+                    });
+                }
+            }
+
+            impl LuaComponentInterface for #name {
+                fn accessor<'lua>(
+                    lua: LuaContext<'lua>,
+                    entity: #root::sludge::Entity,
+                ) -> LuaResult<LuaValue<'lua>> {
+                    #accessor(entity).to_lua(lua)
+                }
+
+                fn bundler<'lua>(
+                    _lua: LuaContext<'lua>,
+                    args: LuaValue<'lua>,
+                    builder: &mut EntityBuilder,
+                ) -> LuaResult<()> {
+                    builder.add(rlua_serde::from_value::<#name>(args)?);
+                    Ok(())
+                }
+            }
+        };
+    })
+}
+
+/// Generate a Lua accessor and a `LuaComponentInterface` impl for a component.
+///
+/// For a component `Foo`, this generates a `FooAccessor` userdata. Named fields are
+/// read and written as fields of the accessor; a newtype's inner value is read and
+/// written through `get` and `set` methods instead. Exposed fields must be `Clone`,
+/// `ToLua` and `FromLua`. The accessor also gets a `to_table` method, and the bundler
+/// takes the same tables `to_table` returns; both go through `serde`, so the component
+/// must implement `Serialize` and `Deserialize`.
+///
+/// Fields can be annotated with `#[lua(...)]`:
+/// - `skip` hides the field from the accessor. It's still saved by `to_table`.
+/// - `readonly` makes assigning to the field an error.
+/// - `rename = "name"` exposes the field under a different name.
+///
+/// The component still has to be registered with `LuaComponent::new` by hand.
+#[proc_macro_derive(LuaAccessor, attributes(lua))]
+pub fn derive_lua_accessor(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    match lua_accessor(&input) {
+        Ok(expanded) => proc_macro::TokenStream::from(expanded),
+        Err(err) => proc_macro::TokenStream::from(err.to_compile_error()),
+    }
+}
+
+/// Parse the `#[lua(name = "...")]` attribute on a bundle struct, if there is one.
+fn bundle_name(input: &DeriveInput) -> Result<String> {
+    let mut name = input.ident.to_string();
+
+    for attr in input.attrs.iter().filter(|attr| attr.path.is_ident("lua")) {
+        match attr.parse_meta()? {
+            Meta::List(list) => {
+                for nested in list.nested {
+                    match nested {
+                        NestedMeta::Meta(Meta::NameValue(MetaNameValue {
+                            path,
+                            lit: Lit::Str(lit),
+                            ..
+                        })) if path.is_ident("name") => name = lit.value(),
+                        other => {
+                            return Err(Error::new_spanned(other, "expected `name = \"...\"`"))
+                        }
+                    }
+                }
+            }
+            other => return Err(Error::new_spanned(other, "expected `#[lua(...)]`")),
+        }
+    }
+
+    Ok(name)
+}
+
+enum BundleDefault {
+    Default,
+    Path(Path),
+    Optional,
+}
+
+struct BundleField {
+    ident: Ident,
+    key: Option<String>,
+    ty: Type,
+    default: BundleDefault,
+}
+
+fn bundle_field(field: &Field) -> Result<BundleField> {
+    let ident = field.ident.clone().unwrap();
+
+    // By default, a field is keyed by the name of its type, so that the table a bundle is
+    // spawned with looks just like a table passed to `sludge.spawn`.
+    let mut key = match &field.ty {
+        Type::Path(TypePath { path, .. }) => path.segments.last().map(|s| s.ident.to_string()),
+        _ => None,
+    };
+    let mut default = BundleDefault::Default;
+
+    for attr in field.attrs.iter().filter(|attr| attr.path.is_ident("lua")) {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            other => return Err(Error::new_spanned(other, "expected `#[lua(...)]`")),
+        };
+
+        for nested in list.nested {
+            match nested {
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("skip") => key = None,
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("optional") => {
+                    default = BundleDefault::Optional
+                }
+                NestedMeta::Meta(Meta::NameValue(MetaNameValue {
+                    path,
+                    lit: Lit::Str(lit),
+                    ..
+                })) if path.is_ident("rename") => key = Some(lit.value()),
+                NestedMeta::Meta(Meta::NameValue(MetaNameValue {
+                    path,
+                    lit: Lit::Str(lit),
+                    ..
+                })) if path.is_ident("default") => default = BundleDefault::Path(lit.parse()?),
+                other => {
+                    return Err(Error::new_spanned(
+                        other,
+                        "expected `skip`, `optional`, `rename = \"...\"` or `default = \"...\"`",
+                    ))
+                }
+            }
+        }
+    }
+
+    if key.is_none() {
+        if let BundleDefault::Optional = default {
+            return Err(Error::new_spanned(
+                field,
+                "a skipped field can't be optional, since it's never read from Lua",
+            ));
+        }
+    }
+
+    Ok(BundleField {
+        ident,
+        key,
+        ty: field.ty.clone(),
+        default,
+    })
+}
+
+fn lua_bundle(input: &DeriveInput) -> Result<proc_macro2::TokenStream> {
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "`LuaBundle` can't be derived for generic types",
+        ));
+    }
+
+    let name = &input.ident;
+    let bundle_name = bundle_name(input)?;
+    let root = guess_name();
+
+    let fields = match &input.data {
+        Data::Struct(DataStruct {
+            fields: Fields::Named(named),
+            ..
+        }) => named
+            .named
+            .iter()
+            .map(bundle_field)
+            .collect::<Result<Vec<_>>>()?,
+        _ => {
+            return Err(Error::new_spanned(
+                name,
+                "`LuaBundle` can only be derived for structs with named fields",
+            ))
+        }
+    };
+
+    let keys = fields.iter().filter_map(|f| f.key.as_ref());
+    let components = fields.iter().map(|field| {
+        let BundleField {
+            ident, key, ty, default, ..
+        } = field;
+
+        let default = match default {
+            BundleDefault::Default => quote! {
+                builder.add(<#ty as ::std::default::Default>::default());
+            },
+            BundleDefault::Path(path) => quote! {
+                builder.add::<#ty>(#path());
+            },
+            BundleDefault::Optional => quote!(),
+        };
+
+        let value = match key {
+            Some(key) => quote! {
+                table
+                    .as_ref()
+                    .map(|t| t.get::<_, LuaValue>(#key))
+                    .transpose()?
+                    .filter(|v| !matches!(v, LuaValue::Nil))
+            },
+            None => quote!(None::<LuaValue>),
+        };
+
+        quote! {
+            match #value {
+                Some(value) => {
+                    <#ty as LuaComponentInterface>::bundler(lua, value, builder)
+                        .map_err(|err| {
+                            anyhow!("error bundling {}.{}: {}", #bundle_name, stringify!(#ident), err)
+                                .to_lua_err()
+                        })?;
+                }
+                None => {
+                    #default
+                }
+            }
+        }
+    });
+
+    Ok(quote! {
+        const _: () = {
+            #[allow(unused_imports)]
+            use #root::sludge::{
+                anyhow::anyhow, rlua::prelude::*, EntityBuilder, LuaBundleInterface,
+                LuaComponentInterface,
+            };
+
+            impl LuaBundleInterface for #name {
+                const FIELDS: &'static [&'static str] = &[#(#keys),*];
+
+                fn bundler<'lua>(
+                    lua: LuaContext<'lua>,
+                    table: Option<LuaTable<'lua>>,
+                    builder: &mut EntityBuilder,
+                ) -> LuaResult<()> {
+                    #(#components)*
+                    Ok(())
+                }
+            }
+        };
+
+        #root::sludge::inventory::submit! {
+            #root::sludge::LuaBundle::new::<#name>(#bundle_name)
+        }
+    })
+}
+
+/// Register a struct of components as a bundle which can be spawned from Lua by name.
+///
+/// Each field is read from the bundle's table using its component's bundler, under the
+/// name of the field's type; `Position` is read from `table.Position`. Fields missing
+/// from the table get their `Default` value. Any other keys in the table are looked up
+/// as ordinary components, so extra components can be added when spawning:
+///
+/// ```ignore
+/// #[derive(LuaBundle)]
+/// #[lua(name = "Bullet")]
+/// pub struct BulletBundle {
+///     position: Position,
+///     velocity: Velocity,
+///     #[lua(default = "Collision::bullet")]
+///     collision: Collision,
+///     #[lua(optional)]
+///     name: Name,
+/// }
+/// ```
+///
+/// ```lua
+/// sludge.spawn { Bullet = { Position = { x = 16, y = 32 }, Sprite = "bullet" } }
+/// ```
+///
+/// The struct can be renamed with `#[lua(name = "...")]`; it's registered under its own
+/// name otherwise. Fields can be annotated with `#[lua(...)]`:
+/// - `rename = "Name"` reads the field from a different key.
+/// - `default = "path::to::fn"` calls a function for the field's default instead.
+/// - `optional` leaves the component out entirely if it's missing from the table.
+/// - `skip` never reads the field from the table, always using its default.
+///
+/// Every field's type must implement `LuaComponentInterface`, and `Default` unless it's
+/// optional or has a `default` function.
+#[proc_macro_derive(LuaBundle, attributes(lua))]
+pub fn derive_lua_bundle(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    match lua_bundle(&input) {
+        Ok(expanded) => proc_macro::TokenStream::from(expanded),
+        Err(err) => proc_macro::TokenStream::from(err.to_compile_error()),
+    }
+}
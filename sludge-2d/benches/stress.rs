@@ -0,0 +1,69 @@
+//! Benchmarks for the hot paths exercised by `sludge_2d::stress`. Run with
+//! `cargo bench --features bench`.
+
+use {
+    criterion::{criterion_group, criterion_main, BenchmarkId, Criterion},
+    sludge_2d::stress::{Stress, StressConfig},
+};
+
+fn scheduler(c: &mut Criterion) {
+    let mut group = c.benchmark_group("scheduler");
+    for &threads in &[256, 1024, 4096] {
+        let stress = Stress::new(&StressConfig {
+            bullets: 0,
+            threads,
+            ..StressConfig::default()
+        })
+        .unwrap();
+
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, _| {
+            b.iter(|| stress.update_scheduler().unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn kinematics(c: &mut Criterion) {
+    let mut group = c.benchmark_group("kinematics");
+    for &bullets in &[1024, 4096, 16384] {
+        let stress = Stress::new(&StressConfig {
+            bullets,
+            threads: 0,
+            ..StressConfig::default()
+        })
+        .unwrap();
+
+        group.bench_with_input(BenchmarkId::from_parameter(bullets), &bullets, |b, _| {
+            b.iter(|| stress.update_kinematics().unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn spatial_hash(c: &mut Criterion) {
+    let mut group = c.benchmark_group("spatial_hash");
+    for &bullets in &[1024, 4096, 16384] {
+        let stress = Stress::new(&StressConfig {
+            bullets,
+            threads: 0,
+            ..StressConfig::default()
+        })
+        .unwrap();
+
+        // Every bullet moves each frame, so every frame updates every bullet's buckets.
+        group.bench_with_input(BenchmarkId::new("update", bullets), &bullets, |b, _| {
+            b.iter(|| {
+                stress.update_kinematics().unwrap();
+                stress.update_spatial_hash().unwrap();
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("query", bullets), &bullets, |b, _| {
+            b.iter(|| stress.query_spatial_hash().unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, scheduler, kinematics, spatial_hash);
+criterion_main!(benches);
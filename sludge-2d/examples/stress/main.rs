@@ -0,0 +1,74 @@
+//! A headless stress test, printing the average time each hot path takes per frame.
+//!
+//! ```text
+//! cargo run --release --features bench --example stress -- [bullets] [threads] [frames]
+//! ```
+
+use {
+    sludge::prelude::*,
+    sludge_2d::stress::{Stress, StressConfig},
+    std::{
+        env,
+        time::{Duration, Instant},
+    },
+};
+
+fn arg<T: std::str::FromStr>(n: usize, default: T) -> Result<T> {
+    match env::args().nth(n) {
+        Some(s) => s
+            .parse()
+            .map_err(|_| anyhow!("bad argument {}: {:?}", n, s)),
+        None => Ok(default),
+    }
+}
+
+fn time(total: &mut Duration, f: impl FnOnce() -> Result<()>) -> Result<()> {
+    let start = Instant::now();
+    f()?;
+    *total += start.elapsed();
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let config = StressConfig {
+        bullets: arg(1, 16384)?,
+        threads: arg(2, 1024)?,
+        ..StressConfig::default()
+    };
+    let frames = arg(3, 600u32)?;
+
+    println!(
+        "{} bullets, {} Lua threads, {} frames",
+        config.bullets, config.threads, frames
+    );
+
+    let setup = Instant::now();
+    let stress = Stress::new(&config)?;
+    println!("setup: {:?}", setup.elapsed());
+
+    let mut scheduler = Duration::default();
+    let mut kinematics = Duration::default();
+    let mut spatial_hash = Duration::default();
+    let mut potential_collisions = 0;
+
+    for _ in 0..frames {
+        time(&mut scheduler, || stress.update_scheduler())?;
+        time(&mut kinematics, || stress.update_kinematics())?;
+        time(&mut spatial_hash, || {
+            stress.update_spatial_hash()?;
+            potential_collisions += stress.query_spatial_hash()?;
+            Ok(())
+        })?;
+    }
+
+    println!("per frame:");
+    println!("  scheduler:    {:?}", scheduler / frames);
+    println!("  kinematics:   {:?}", kinematics / frames);
+    println!("  spatial hash: {:?}", spatial_hash / frames);
+    println!(
+        "  potential collisions: {}",
+        potential_collisions / frames as usize
+    );
+
+    Ok(())
+}
@@ -41,7 +41,7 @@ impl MainState {
             .borrow_mut()
             .insert(DefaultCache::new(space.resources().clone()));
 
-        let font_atlas_key = Key::from_structured(&FontAtlasKey::new(
+        let font_atlas_key = Key::structured(&FontAtlasKey::new(
             "/font.ttf",
             20,
             CharacterListType::AsciiSubset,
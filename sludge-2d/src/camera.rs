@@ -0,0 +1,665 @@
+//! A 2D camera, for converting between screen space and world space.
+//!
+//! The [`Camera2d`] resource describes which part of the world is on screen: the point
+//! at the center of the screen, how far the view is rotated, and how far it's zoomed in.
+//! Push [`Camera2d::to_matrix4`] onto the graphics transform stack before drawing the
+//! world, and use [`Camera2d::pick`] to find out where in the world the mouse is.
+//!
+//! From Lua, the space's camera is `sludge.camera`:
+//!
+//! ```lua
+//! local camera = sludge.camera
+//! camera:set_position(player.Position:coords())
+//! camera:set_zoom(2)
+//! local x, y = camera:pick(mouse_x, mouse_y)
+//! ```
+//!
+//! # Following and bounds
+//!
+//! Rather than moving the camera by hand every frame, give any entity a
+//! [`CameraFollow`] naming the entity to follow, and register the [`CameraSystem`]. The
+//! camera then eases towards the target whenever it leaves the follow's deadzone. A
+//! [`CameraBounds`] keeps everything on screen inside a box, such as the edges of the
+//! level:
+//!
+//! ```lua
+//! sludge.spawn {
+//!     CameraFollow = { target = player, lerp = 0.15, deadzone = { x = -16, y = -8, w = 32, h = 16 } },
+//!     CameraBounds = { x = 0, y = 0, w = 1280, h = 720 },
+//! }
+//! ```
+//!
+//! # Screen shake
+//!
+//! `camera:shake(amount)` adds to the camera's trauma, which runs from zero to one and
+//! decays over time. How far the view shakes goes with the square of the trauma, so
+//! small knocks barely register while big ones stack up into a violent shake. Shaking
+//! moves and rotates the view through [`Camera2d::shake_offset`] and
+//! [`Camera2d::shake_rotation`] without changing where the camera actually is. The
+//! shake itself is configured through the [`CameraShake`] resource.
+
+use {
+    serde::{Deserialize, Serialize},
+    sludge::{
+        api::{math::Coords, LuaComponent, LuaComponentInterface, LuaEntity},
+        ecs::*,
+        pause,
+        prelude::*,
+        reflect::ReflectedComponent,
+        timestep,
+    },
+};
+
+use crate::Position;
+
+/// The camera of a space. See the [module documentation](self) for details.
+#[derive(Debug, Clone, Copy)]
+pub struct Camera2d {
+    /// The point in world space at the center of the screen.
+    pub position: Point2<f32>,
+    /// The rotation of the view, in radians. Rotating the camera clockwise rotates the
+    /// world counterclockwise on screen.
+    pub rotation: f32,
+    /// How many pixels a unit of world space takes up.
+    pub zoom: f32,
+    /// The size of the screen, in pixels.
+    pub viewport: Vector2<f32>,
+    /// How far the view is currently shaken away from `position`, in world units.
+    pub shake_offset: Vector2<f32>,
+    /// How far the view is currently shaken away from `rotation`, in radians.
+    pub shake_rotation: f32,
+}
+
+impl Camera2d {
+    /// Create a camera looking at the origin, for a screen of the given size.
+    pub fn new(width: f32, height: f32) -> Self {
+        Self {
+            position: Point2::origin(),
+            rotation: 0.,
+            zoom: 1.,
+            viewport: Vector2::new(width, height),
+            shake_offset: Vector2::zeros(),
+            shake_rotation: 0.,
+        }
+    }
+
+    /// The point at the center of the screen, including any shake.
+    pub fn view_position(&self) -> Point2<f32> {
+        self.position + self.shake_offset
+    }
+
+    /// The rotation of the view, including any shake.
+    pub fn view_rotation(&self) -> f32 {
+        self.rotation + self.shake_rotation
+    }
+
+    /// The transformation from world space into screen space.
+    pub fn to_matrix3(&self) -> Matrix3<f32> {
+        let center = Translation2::from(self.viewport / 2.).to_homogeneous();
+        let zoom = Matrix3::new_scaling(self.zoom);
+        let view = Isometry2::new(Vector2::zeros(), self.view_rotation()).inverse()
+            * Translation2::from(-self.view_position().coords);
+
+        center * zoom * view.to_homogeneous()
+    }
+
+    /// The transformation from world space into screen space, for pushing onto the
+    /// graphics transform stack.
+    pub fn to_matrix4(&self) -> Matrix4<f32> {
+        homogeneous_mat3_to_mat4(&self.to_matrix3())
+    }
+
+    /// Find the point in world space under a point on the screen.
+    pub fn pick(&self, screen: Point2<f32>) -> Point2<f32> {
+        let centered = (screen.coords - self.viewport / 2.) / self.zoom;
+        self.view_position() + UnitComplex::new(self.view_rotation()) * centered
+    }
+
+    /// Find where on the screen a point in world space ends up.
+    pub fn to_screen(&self, world: Point2<f32>) -> Point2<f32> {
+        let relative =
+            UnitComplex::new(self.view_rotation()).inverse() * (world - self.view_position());
+        Point2::from(relative * self.zoom + self.viewport / 2.)
+    }
+
+    /// The smallest box in world space containing everything on the screen.
+    pub fn visible_bounds(&self) -> Box2<f32> {
+        let corners = [
+            Point2::origin(),
+            Point2::new(self.viewport.x, 0.),
+            Point2::new(0., self.viewport.y),
+            Point2::from(self.viewport),
+        ];
+
+        let mut bounds = Box2::invalid();
+        for &corner in &corners {
+            let point = self.pick(corner);
+            bounds.merge(&Box2::from_corners(point, point));
+        }
+        bounds
+    }
+}
+
+/// `sludge.camera`, a handle to the space's [`Camera2d`].
+#[derive(Debug, Clone, Copy)]
+pub struct CameraAccessor;
+
+impl LuaUserData for CameraAccessor {
+    fn add_methods<'lua, T: LuaUserDataMethods<'lua, Self>>(methods: &mut T) {
+        methods.add_method("position", |lua, _this, ()| {
+            let camera = *lua.fetch_one::<Camera2d>()?.borrow();
+            Ok((camera.position.x, camera.position.y))
+        });
+
+        methods.add_method("set_position", |lua, _this, coords: Coords| {
+            lua.fetch_one::<Camera2d>()?.borrow_mut().position = coords.point();
+            Ok(())
+        });
+
+        methods.add_method("rotation", |lua, _this, ()| {
+            Ok(lua.fetch_one::<Camera2d>()?.borrow().rotation)
+        });
+
+        methods.add_method("set_rotation", |lua, _this, rotation: f32| {
+            lua.fetch_one::<Camera2d>()?.borrow_mut().rotation = rotation;
+            Ok(())
+        });
+
+        methods.add_method("zoom", |lua, _this, ()| {
+            Ok(lua.fetch_one::<Camera2d>()?.borrow().zoom)
+        });
+
+        methods.add_method("set_zoom", |lua, _this, zoom: f32| {
+            lua.fetch_one::<Camera2d>()?.borrow_mut().zoom = zoom;
+            Ok(())
+        });
+
+        methods.add_method("pick", |lua, _this, coords: Coords| {
+            let point = lua.fetch_one::<Camera2d>()?.borrow().pick(coords.point());
+            Ok((point.x, point.y))
+        });
+
+        methods.add_method("to_screen", |lua, _this, coords: Coords| {
+            let point = lua
+                .fetch_one::<Camera2d>()?
+                .borrow()
+                .to_screen(coords.point());
+            Ok((point.x, point.y))
+        });
+
+        methods.add_method("visible_bounds", |lua, _this, ()| {
+            Ok(lua.fetch_one::<Camera2d>()?.borrow().visible_bounds())
+        });
+
+        methods.add_method("shake", |lua, _this, amount: f32| {
+            lua.fetch_one::<CameraShake>()?
+                .borrow_mut()
+                .add_trauma(amount);
+            Ok(())
+        });
+
+        methods.add_method("trauma", |lua, _this, ()| {
+            Ok(lua.fetch_one::<CameraShake>()?.borrow().trauma)
+        });
+    }
+}
+
+/// Makes the camera follow another entity. Only the first enabled entity with a
+/// `CameraFollow` is used.
+#[derive(Debug, Clone, Copy)]
+pub struct CameraFollow {
+    /// The entity to follow, which needs a [`Position`].
+    pub target: Entity,
+    /// How much of the way towards the target the camera moves each fixed step, from
+    /// `0` (not at all) to `1` (snapping straight to it).
+    pub lerp: f32,
+    /// A box around the center of the view, in world units, inside which the target can
+    /// move without the camera following.
+    pub deadzone: Box2<f32>,
+}
+
+impl<'a> SmartComponent<ScContext<'a>> for CameraFollow {}
+
+inventory::submit! {
+    CloneComponent::of::<CameraFollow>()
+}
+
+impl CameraFollow {
+    pub fn new(target: Entity) -> Self {
+        Self {
+            target,
+            lerp: 1.,
+            deadzone: Box2::new(0., 0., 0., 0.),
+        }
+    }
+
+    /// Where the camera should be to have `target` just inside the deadzone, starting
+    /// from `position`.
+    fn goal(&self, position: Point2<f32>, target: Point2<f32>) -> Point2<f32> {
+        let relative = target - position;
+        let mut goal = position;
+        for i in 0..2 {
+            if relative[i] < self.deadzone.mins[i] {
+                goal[i] += relative[i] - self.deadzone.mins[i];
+            } else if relative[i] > self.deadzone.maxs[i] {
+                goal[i] += relative[i] - self.deadzone.maxs[i];
+            }
+        }
+        goal
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CameraFollowAccessor(Entity);
+
+impl LuaUserData for CameraFollowAccessor {
+    fn add_methods<'lua, T: LuaUserDataMethods<'lua, Self>>(methods: &mut T) {
+        methods.add_method("target", |lua, this, ()| {
+            let world = lua.fetch_one::<World>()?;
+            let target = world
+                .borrow()
+                .get::<CameraFollow>(this.0)
+                .to_lua_err()?
+                .target;
+            Ok(LuaEntity::from(target))
+        });
+
+        methods.add_method("set_target", |lua, this, target: LuaEntity| {
+            let world = lua.fetch_one::<World>()?;
+            world
+                .borrow()
+                .get_mut::<CameraFollow>(this.0)
+                .to_lua_err()?
+                .target = target.into();
+            Ok(())
+        });
+
+        methods.add_method("set_lerp", |lua, this, lerp: f32| {
+            let world = lua.fetch_one::<World>()?;
+            world
+                .borrow()
+                .get_mut::<CameraFollow>(this.0)
+                .to_lua_err()?
+                .lerp = lerp;
+            Ok(())
+        });
+
+        methods.add_method("set_deadzone", |lua, this, deadzone: Box2<f32>| {
+            let world = lua.fetch_one::<World>()?;
+            world
+                .borrow()
+                .get_mut::<CameraFollow>(this.0)
+                .to_lua_err()?
+                .deadzone = deadzone;
+            Ok(())
+        });
+    }
+}
+
+/// `CameraFollow`s are spawned from tables with a `target` entity, and optionally a
+/// `lerp` factor and a `deadzone` box.
+impl LuaComponentInterface for CameraFollow {
+    fn accessor<'lua>(lua: LuaContext<'lua>, entity: Entity) -> LuaResult<LuaValue<'lua>> {
+        CameraFollowAccessor(entity).to_lua(lua)
+    }
+
+    fn bundler<'lua>(
+        lua: LuaContext<'lua>,
+        args: LuaValue<'lua>,
+        builder: &mut EntityBuilder,
+    ) -> LuaResult<()> {
+        let table = LuaTable::from_lua(args, lua)?;
+        let mut follow = CameraFollow::new(table.get::<_, LuaEntity>("target")?.into());
+        if let Some(lerp) = table.get::<_, Option<f32>>("lerp")? {
+            follow.lerp = lerp;
+        }
+        if let Some(deadzone) = table.get::<_, Option<Box2<f32>>>("deadzone")? {
+            follow.deadzone = deadzone;
+        }
+        builder.add(follow);
+        Ok(())
+    }
+}
+
+inventory::submit! {
+    LuaComponent::new::<CameraFollow>("CameraFollow")
+}
+
+/// Keeps everything on screen inside a box in world space. If the box is smaller than
+/// the view, the view is centered on it instead. Rotation isn't accounted for. Only
+/// the first enabled entity with `CameraBounds` is used.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct CameraBounds(pub Box2<f32>);
+
+impl<'a> SmartComponent<ScContext<'a>> for CameraBounds {}
+
+inventory::submit! {
+    CloneComponent::of::<CameraBounds>()
+}
+
+impl CameraBounds {
+    /// Clamp the center of a view with the given half extents.
+    fn clamp(&self, position: Point2<f32>, half_extents: Vector2<f32>) -> Point2<f32> {
+        let bounds = &self.0;
+        let mut clamped = position;
+        for i in 0..2 {
+            let (min, max) = (
+                bounds.mins[i] + half_extents[i],
+                bounds.maxs[i] - half_extents[i],
+            );
+            clamped[i] = if min > max {
+                (bounds.mins[i] + bounds.maxs[i]) / 2.
+            } else {
+                position[i].max(min).min(max)
+            };
+        }
+        clamped
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CameraBoundsAccessor(Entity);
+
+impl LuaUserData for CameraBoundsAccessor {
+    fn add_methods<'lua, T: LuaUserDataMethods<'lua, Self>>(methods: &mut T) {
+        methods.add_method("to_table", |lua, this, ()| {
+            let world = lua.fetch_one::<World>()?;
+            let bounds = *world.borrow().get::<CameraBounds>(this.0).to_lua_err()?;
+            rlua_serde::to_value(lua, bounds)
+        });
+
+        methods.add_method("set", |lua, this, bounds: Box2<f32>| {
+            let world = lua.fetch_one::<World>()?;
+            *world
+                .borrow()
+                .get_mut::<CameraBounds>(this.0)
+                .to_lua_err()? = CameraBounds(bounds);
+            Ok(())
+        });
+    }
+}
+
+impl LuaComponentInterface for CameraBounds {
+    fn accessor<'lua>(lua: LuaContext<'lua>, entity: Entity) -> LuaResult<LuaValue<'lua>> {
+        CameraBoundsAccessor(entity).to_lua(lua)
+    }
+
+    fn bundler<'lua>(
+        lua: LuaContext<'lua>,
+        args: LuaValue<'lua>,
+        builder: &mut EntityBuilder,
+    ) -> LuaResult<()> {
+        builder.add(CameraBounds(Box2::from_lua(args, lua)?));
+        Ok(())
+    }
+}
+
+inventory::submit! {
+    LuaComponent::new::<CameraBounds>("CameraBounds")
+}
+
+inventory::submit! {
+    ReflectedComponent::new::<CameraBounds>("CameraBounds")
+}
+
+/// Trauma-based screen shake. See the [module documentation](self) for details.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraShake {
+    /// How shaken up the camera is, from `0` to `1`.
+    pub trauma: f32,
+    /// How much trauma wears off per second.
+    pub decay: f32,
+    /// How far the view moves at full trauma, in world units.
+    pub max_offset: f32,
+    /// How far the view rotates at full trauma, in radians.
+    pub max_rotation: f32,
+    /// Roughly how many times per second the view changes direction.
+    pub frequency: f32,
+    time: f32,
+}
+
+impl Default for CameraShake {
+    fn default() -> Self {
+        Self {
+            trauma: 0.,
+            decay: 1.5,
+            max_offset: 8.,
+            max_rotation: 0.05,
+            frequency: 15.,
+            time: 0.,
+        }
+    }
+}
+
+impl CameraShake {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add to the trauma, up to a maximum of `1`.
+    pub fn add_trauma(&mut self, amount: f32) {
+        self.trauma = (self.trauma + amount).max(0.).min(1.);
+    }
+
+    /// Advance the shake by `dt` seconds, returning the offset and rotation to shake
+    /// the view by.
+    pub fn update(&mut self, dt: f32) -> (Vector2<f32>, f32) {
+        self.time += dt;
+        self.trauma = (self.trauma - self.decay * dt).max(0.);
+
+        // Sums of incommensurate sines make a cheap, smooth and deterministic stand-in
+        // for noise, with a different phase for each axis.
+        let t = self.time * self.frequency;
+        let wobble = |phase: f32| ((t + phase).sin() + (t * 1.73 + phase * 2.1).sin()) / 2.;
+
+        let shake = self.trauma * self.trauma;
+        let offset = Vector2::new(wobble(0.), wobble(17.)) * self.max_offset * shake;
+        let rotation = wobble(41.) * self.max_rotation * shake;
+        (offset, rotation)
+    }
+}
+
+/// Moves the [`Camera2d`] to follow the first enabled [`CameraFollow`], keeps it inside
+/// the first enabled [`CameraBounds`], and applies [`CameraShake`], once per fixed step.
+/// Does nothing while the gameplay channel is [paused](sludge::pause).
+pub struct CameraSystem;
+
+impl System for CameraSystem {
+    fn init(
+        &self,
+        _lua: LuaContext,
+        local: &mut OwnedResources,
+        _global: Option<&SharedResources>,
+    ) -> Result<()> {
+        if !local.has_value::<CameraShake>() {
+            local.insert(CameraShake::new());
+        }
+        Ok(())
+    }
+
+    fn update(&self, _lua: LuaContext, resources: &UnifiedResources) -> Result<()> {
+        if pause::is_paused(resources, pause::GAMEPLAY) {
+            return Ok(());
+        }
+
+        let camera = match resources.fetch_one::<Camera2d>() {
+            Ok(camera) => camera,
+            Err(_) => return Ok(()),
+        };
+        let mut camera = camera.borrow_mut();
+        let world = resources.fetch_one::<World>()?;
+        let world = world.borrow();
+
+        let follow = world
+            .query_enabled::<&CameraFollow>()
+            .iter()
+            .next()
+            .map(|(_, follow)| *follow);
+        if let Some(follow) = follow {
+            if let Ok(target) = world.get::<Position>(follow.target) {
+                let target = Point2::from(target.translation.vector);
+                let goal = follow.goal(camera.position, target);
+                camera.position += (goal - camera.position) * follow.lerp.max(0.).min(1.);
+            }
+        }
+
+        let bounds = world
+            .query_enabled::<&CameraBounds>()
+            .iter()
+            .next()
+            .map(|(_, bounds)| *bounds);
+        if let Some(bounds) = bounds {
+            let half_extents = camera.viewport / (2. * camera.zoom);
+            camera.position = bounds.clamp(camera.position, half_extents);
+        }
+
+        if let Ok(shake) = resources.fetch_one::<CameraShake>() {
+            let (offset, rotation) = shake.borrow_mut().update(timestep::fixed_dt(resources));
+            camera.shake_offset = offset;
+            camera.shake_rotation = rotation;
+        }
+
+        Ok(())
+    }
+}
+
+inventory::submit! {
+    sludge::api::Module::parse("sludge.camera", |lua| Ok(CameraAccessor.to_lua(lua)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pick_inverts_to_screen() {
+        let camera = Camera2d {
+            position: Point2::new(100., -40.),
+            rotation: 0.7,
+            zoom: 2.5,
+            viewport: Vector2::new(320., 240.),
+            shake_offset: Vector2::zeros(),
+            shake_rotation: 0.,
+        };
+
+        let world = Point2::new(12., 34.);
+        let screen = camera.to_screen(world);
+        assert!((camera.pick(screen) - world).norm() < 1e-3);
+
+        let transformed = camera.to_matrix3() * world.to_homogeneous();
+        let transformed = Point2::from_homogeneous(transformed).unwrap();
+        assert!((transformed - screen).norm() < 1e-3);
+
+        let center = camera.pick(Point2::new(160., 120.));
+        assert!((center - camera.position).norm() < 1e-3);
+    }
+
+    #[test]
+    fn follow_goals_keep_the_target_in_the_deadzone() {
+        let mut follow = CameraFollow::new(Entity::from_bits(0));
+        follow.deadzone = Box2::new(-10., -5., 20., 10.);
+        let position = Point2::new(100., 100.);
+
+        let inside = Point2::new(105., 98.);
+        assert_eq!(follow.goal(position, inside), position);
+
+        let goal = follow.goal(position, Point2::new(125., 102.));
+        assert!((goal - Point2::new(115., 100.)).norm() < 1e-4, "{}", goal);
+
+        let goal = follow.goal(position, Point2::new(80., 90.));
+        assert!((goal - Point2::new(90., 95.)).norm() < 1e-4, "{}", goal);
+    }
+
+    #[test]
+    fn bounds_clamp_the_view_or_center_it() {
+        let bounds = CameraBounds(Box2::new(0., 0., 100., 50.));
+        let half_extents = Vector2::new(20., 10.);
+
+        let clamped = bounds.clamp(Point2::new(-5., 30.), half_extents);
+        assert_eq!(clamped, Point2::new(20., 30.));
+        let clamped = bounds.clamp(Point2::new(200., 45.), half_extents);
+        assert_eq!(clamped, Point2::new(80., 40.));
+
+        // The view is wider than the bounds, so it's centered horizontally.
+        let clamped = bounds.clamp(Point2::new(-5., 30.), Vector2::new(60., 10.));
+        assert_eq!(clamped, Point2::new(50., 30.));
+    }
+
+    #[test]
+    fn trauma_saturates_decays_and_scales_the_shake() {
+        let mut shake = CameraShake::new();
+        assert_eq!(shake.update(0.1), (Vector2::zeros(), 0.));
+
+        shake.add_trauma(0.75);
+        shake.add_trauma(0.75);
+        assert_eq!(shake.trauma, 1.);
+
+        let dt = 0.02;
+        for _ in 0..10 {
+            let trauma = shake.trauma - shake.decay * dt;
+            let (offset, rotation) = shake.update(dt);
+            assert!((shake.trauma - trauma).abs() < 1e-6);
+            let scale = shake.trauma * shake.trauma;
+            assert!(offset.x.abs() <= shake.max_offset * scale + 1e-6);
+            assert!(offset.y.abs() <= shake.max_offset * scale + 1e-6);
+            assert!(rotation.abs() <= shake.max_rotation * scale + 1e-6);
+        }
+
+        shake.update(1.);
+        assert_eq!(shake.trauma, 0.);
+        assert_eq!(shake.update(dt), (Vector2::zeros(), 0.));
+    }
+
+    #[test]
+    fn camera_system_follows_clamps_and_shakes() -> Result<()> {
+        let mut space = Space::new()?;
+        space
+            .resources()
+            .borrow_mut()
+            .insert(Camera2d::new(40., 20.));
+        space.register(CameraSystem, "Camera", &[])?;
+
+        {
+            let world = space.world()?;
+            let mut world = world.borrow_mut();
+            let target = world.spawn((Position(Isometry2::translation(30., 0.)),));
+            let mut follow = CameraFollow::new(target);
+            follow.lerp = 0.5;
+            world.spawn((follow, CameraBounds(Box2::new(-100., -10., 200., 20.))));
+        }
+
+        space.fixed_update()?;
+        let camera = space.fetch_one::<Camera2d>()?;
+        assert_eq!(camera.borrow().position, Point2::new(15., 0.));
+        assert_eq!(camera.borrow().view_position(), camera.borrow().position);
+
+        // Following the target up is stopped by the bounds.
+        {
+            let world = space.world()?;
+            let world = world.borrow();
+            let target = world
+                .query::<&CameraFollow>()
+                .iter()
+                .next()
+                .map(|(_, follow)| follow.target)
+                .unwrap();
+            world.get_mut::<Position>(target)?.0 = Isometry2::translation(15., 40.);
+        }
+        space.fixed_update()?;
+        assert_eq!(camera.borrow().position, Point2::new(15., 0.));
+
+        space
+            .lua()
+            .context(|lua| lua.load("sludge.camera:shake(0.5)").exec())?;
+        space.fixed_update()?;
+        let trauma = space.fetch_one::<CameraShake>()?.borrow().trauma;
+        let dt = timestep::fixed_dt(space.resources());
+        assert!((trauma - (0.5 - 1.5 * dt)).abs() < 1e-6);
+        let camera = camera.borrow();
+        assert_ne!(camera.shake_offset, Vector2::zeros());
+        assert_eq!(camera.position, Point2::new(15., 0.));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+-- The Lua half of `sludge.cutscene`. Everything which waits is written here in terms
+-- of `yield`, so that a cutscene is an ordinary scheduler thread and is persisted
+-- along with the rest of the scheduler.
+return function(cutscene)
+    local ADVANCE = cutscene.ADVANCE_EVENT
+
+    -- Wait for a number of ticks, scaled by the fast-forward speed. Returns early if
+    -- the cutscene is skipped.
+    local function wait_ticks(ticks)
+        local elapsed = 0
+        while elapsed < ticks and not cutscene.is_skipping() do
+            sludge.thread.yield(1)
+            elapsed = elapsed + cutscene.speed()
+        end
+    end
+
+    -- Start a cutscene on its own thread. If `f` raises an error, the cutscene is still
+    -- finished, so that its line doesn't stay on screen, before the error is rethrown.
+    function cutscene.play(f, ...)
+        return sludge.thread.spawn(function(...)
+            cutscene.begin()
+            local ok, err = pcall(f, ...)
+            cutscene.finish()
+            if not ok then
+                error(err, 0)
+            end
+        end, ...)
+    end
+
+    function cutscene.wait_input()
+        if not cutscene.is_skipping() then
+            sludge.thread.yield(ADVANCE)
+        end
+    end
+
+    function cutscene.say(speaker, text)
+        cutscene.set_line(speaker, text)
+        cutscene.wait_input()
+        cutscene.set_line(nil, nil)
+    end
+
+    function cutscene.wait(seconds)
+        wait_ticks(seconds * cutscene.ticks_per_second())
+    end
+
+    function cutscene.move_entity(entity, x, y, seconds)
+        local position = entity.Position
+        local x0, y0 = position:coords()
+        local ticks = seconds * cutscene.ticks_per_second()
+        local elapsed = 0
+
+        while elapsed < ticks and not cutscene.is_skipping() do
+            local t = elapsed / ticks
+            position:set_coords(x0 + (x - x0) * t, y0 + (y - y0) * t)
+            sludge.thread.yield(1)
+            elapsed = elapsed + cutscene.speed()
+        end
+
+        position:set_coords(x, y)
+    end
+
+    -- Move an entity along a `sludge.math` curve, at a constant speed unless an easing
+    -- such as "quad_in_out" is given. The curve is in world coordinates, so the entity
+    -- jumps to its start if it isn't already there.
+    function cutscene.move_along(entity, curve, seconds, easing)
+        local position = entity.Position
+        local ticks = seconds * cutscene.ticks_per_second()
+        local elapsed = 0
+        easing = easing or "linear"
+
+        while elapsed < ticks and not cutscene.is_skipping() do
+            position:set_coords(curve:point_uniform(sludge.math.ease(easing, elapsed / ticks)))
+            sludge.thread.yield(1)
+            elapsed = elapsed + cutscene.speed()
+        end
+
+        position:set_coords(curve:point_uniform(1))
+    end
+end
@@ -0,0 +1,494 @@
+//! Cutscenes and dialogue, written as Lua coroutines running on the scheduler.
+//!
+//! A cutscene is just a scheduled Lua thread started with `sludge.cutscene.play`,
+//! which uses helpers like `say`, `wait_input`, `wait` and `move_entity` to wait on
+//! the player or on time passing. The currently displayed line, along with whether
+//! the cutscene is being skipped and how fast it's running, is kept in a
+//! [`CutsceneState`] component on a persistent entity, so that saving the space in the
+//! middle of a cutscene saves both the thread and everything it's displaying.
+//!
+//! On the Rust side, the [`CutsceneRunner`] resource is used to advance dialogue,
+//! skip or fast-forward, and draw the current line with a [`Ui`]. It must be present
+//! in a space's resources for the Lua helpers to work.
+//!
+//! `move_entity` moves its entity linearly, one tick at a time. For anything smoother,
+//! `move_along` moves an entity along a curve built with `sludge.math.Bezier` or
+//! `sludge.math.CatmullRom`, optionally eased with one of the
+//! [`Easing`](sludge::math::tween::Easing)s.
+
+use {
+    serde::{Deserialize, Serialize},
+    sludge::{
+        api::{LuaComponent, LuaComponentInterface},
+        components::Persistent,
+        ecs::*,
+        prelude::*,
+        reflect::ReflectedComponent,
+        timestep,
+    },
+};
+
+use crate::ui::Ui;
+
+/// The event the cutscene thread waits on when waiting for input.
+pub const ADVANCE_EVENT: &'static str = "cutscene.advance";
+
+/// Space left between the edges of the dialogue box and its text.
+const DIALOGUE_PADDING: f32 = 8.;
+
+/// The state of the cutscene currently playing.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CutsceneState {
+    /// The name of whoever is speaking the current line, if anyone.
+    pub speaker: Option<String>,
+    /// The line currently being displayed, if any.
+    pub text: Option<String>,
+    /// If `true`, every wait in the cutscene finishes immediately.
+    pub skipping: bool,
+    /// How many ticks of cutscene time pass per tick. A second of cutscene time is as
+    /// many ticks as the space's [fixed timestep](sludge::timestep) runs per second.
+    pub speed: f32,
+}
+
+impl Default for CutsceneState {
+    fn default() -> Self {
+        Self {
+            speaker: None,
+            text: None,
+            skipping: false,
+            speed: 1.,
+        }
+    }
+}
+
+impl<'a> SmartComponent<ScContext<'a>> for CutsceneState {}
+
+inventory::submit! {
+    CloneComponent::of::<CutsceneState>()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CutsceneStateAccessor(Entity);
+
+impl LuaUserData for CutsceneStateAccessor {
+    fn add_methods<'lua, T: LuaUserDataMethods<'lua, Self>>(methods: &mut T) {
+        methods.add_method("to_table", |lua, this, ()| {
+            let world = lua.fetch_one::<World>()?;
+            let state = world
+                .borrow()
+                .get::<CutsceneState>(this.0)
+                .to_lua_err()?
+                .clone();
+            rlua_serde::to_value(lua, state)
+        });
+    }
+}
+
+impl LuaComponentInterface for CutsceneState {
+    fn accessor<'lua>(lua: LuaContext<'lua>, entity: Entity) -> LuaResult<LuaValue<'lua>> {
+        CutsceneStateAccessor(entity).to_lua(lua)
+    }
+
+    fn bundler<'lua>(
+        _lua: LuaContext<'lua>,
+        args: LuaValue<'lua>,
+        builder: &mut EntityBuilder,
+    ) -> LuaResult<()> {
+        builder.add(rlua_serde::from_value::<CutsceneState>(args)?);
+        Ok(())
+    }
+}
+
+inventory::submit! {
+    LuaComponent::new::<CutsceneState>("CutsceneState")
+}
+
+inventory::submit! {
+    ReflectedComponent::new::<CutsceneState>("CutsceneState")
+}
+
+/// Drives the cutscene currently playing, if any.
+#[derive(Debug, Default)]
+pub struct CutsceneRunner {
+    entity: Option<Entity>,
+}
+
+impl CutsceneRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Find the entity holding the current cutscene's state. The entity is cached, but
+    /// looked up again if it goes missing, for example after loading a save.
+    fn find(&mut self, world: &World) -> Option<Entity> {
+        if let Some(entity) = self.entity {
+            if world.get::<CutsceneState>(entity).is_ok() {
+                return Some(entity);
+            }
+        }
+
+        self.entity = world
+            .query::<&CutsceneState>()
+            .iter()
+            .next()
+            .map(|(entity, _)| entity);
+        self.entity
+    }
+
+    pub fn is_playing(&mut self, world: &World) -> bool {
+        self.find(world).is_some()
+    }
+
+    /// A copy of the current cutscene's state, if one is playing.
+    pub fn state(&mut self, world: &World) -> Option<CutsceneState> {
+        let entity = self.find(world)?;
+        world.get::<CutsceneState>(entity).ok().map(|s| s.clone())
+    }
+
+    fn with_state<R>(
+        &mut self,
+        world: &World,
+        f: impl FnOnce(&mut CutsceneState) -> R,
+    ) -> Option<R> {
+        let entity = self.find(world)?;
+        let mut state = world.get_mut::<CutsceneState>(entity).ok()?;
+        Some(f(&mut *state))
+    }
+
+    /// Start tracking a new cutscene, creating its state entity. Fails if a cutscene
+    /// is already playing.
+    pub fn begin(&mut self, world: &mut World) -> Result<Entity> {
+        ensure!(
+            !self.is_playing(world),
+            "a cutscene is already playing; only one may play at a time"
+        );
+
+        let entity = world.spawn((CutsceneState::default(), Persistent));
+        self.entity = Some(entity);
+        Ok(entity)
+    }
+
+    /// Stop tracking the current cutscene, removing its state entity.
+    pub fn finish(&mut self, world: &mut World) {
+        if let Some(entity) = self.find(world) {
+            let _ = world.despawn(entity);
+        }
+        self.entity = None;
+    }
+
+    /// Set the line currently being displayed.
+    pub fn set_line(&mut self, world: &World, speaker: Option<String>, text: Option<String>) {
+        self.with_state(world, |state| {
+            state.speaker = speaker;
+            state.text = text;
+        });
+    }
+
+    /// Wake up the cutscene if it's waiting for input.
+    pub fn advance<'lua>(&self, lua: LuaContext<'lua>) -> Result<()> {
+        lua.broadcast(ADVANCE_EVENT, ())?;
+        Ok(())
+    }
+
+    /// Skip to the end of the current cutscene. Every remaining wait in it finishes
+    /// immediately, and entities being moved are snapped to their destinations.
+    pub fn skip<'lua>(&mut self, lua: LuaContext<'lua>, world: &World) -> Result<()> {
+        if self
+            .with_state(world, |state| state.skipping = true)
+            .is_some()
+        {
+            self.advance(lua)?;
+        }
+
+        Ok(())
+    }
+
+    /// Run the current cutscene at `speed` times normal speed. Waiting for input is
+    /// unaffected.
+    pub fn fast_forward(&mut self, world: &World, speed: f32) {
+        self.with_state(world, |state| state.speed = speed.max(0.));
+    }
+
+    /// Draw the current line, if any, as a dialogue box filling `bounds`.
+    pub fn draw_dialogue(&mut self, world: &World, ui: &mut Ui, bounds: Box2<f32>) {
+        let state = match self.state(world) {
+            Some(state) if state.text.is_some() => state,
+            _ => return,
+        };
+
+        let mut cursor = bounds.mins + Vector2::repeat(DIALOGUE_PADDING);
+
+        ui.panel(bounds);
+        if let Some(speaker) = &state.speaker {
+            cursor.y = ui.label(cursor, speaker).maxs.y + DIALOGUE_PADDING;
+        }
+        if let Some(text) = &state.text {
+            ui.label(cursor, text);
+        }
+    }
+}
+
+fn load<'lua>(lua: LuaContext<'lua>) -> Result<LuaValue<'lua>> {
+    let table = lua.create_table()?;
+    table.set("ADVANCE_EVENT", ADVANCE_EVENT)?;
+
+    table.set(
+        "ticks_per_second",
+        lua.create_function(|lua, ()| Ok(timestep::fixed_timestep(&lua).fps()))?,
+    )?;
+
+    table.set(
+        "begin",
+        lua.create_function(|lua, ()| {
+            let (world, runner) = lua.fetch::<(World, CutsceneRunner)>()?;
+            let entity = runner
+                .borrow_mut()
+                .begin(&mut world.borrow_mut())
+                .to_lua_err()?;
+            Ok(LuaEntity::from(entity))
+        })?,
+    )?;
+
+    table.set(
+        "finish",
+        lua.create_function(|lua, ()| {
+            let (world, runner) = lua.fetch::<(World, CutsceneRunner)>()?;
+            runner.borrow_mut().finish(&mut world.borrow_mut());
+            Ok(())
+        })?,
+    )?;
+
+    table.set(
+        "set_line",
+        lua.create_function(|lua, (speaker, text): (Option<String>, Option<String>)| {
+            let (world, runner) = lua.fetch::<(World, CutsceneRunner)>()?;
+            runner.borrow_mut().set_line(&world.borrow(), speaker, text);
+            Ok(())
+        })?,
+    )?;
+
+    table.set(
+        "is_playing",
+        lua.create_function(|lua, ()| {
+            let (world, runner) = lua.fetch::<(World, CutsceneRunner)>()?;
+            let playing = runner.borrow_mut().is_playing(&world.borrow());
+            Ok(playing)
+        })?,
+    )?;
+
+    table.set(
+        "is_skipping",
+        lua.create_function(|lua, ()| {
+            let (world, runner) = lua.fetch::<(World, CutsceneRunner)>()?;
+            let state = runner.borrow_mut().state(&world.borrow());
+            Ok(state.map(|s| s.skipping).unwrap_or(false))
+        })?,
+    )?;
+
+    table.set(
+        "speed",
+        lua.create_function(|lua, ()| {
+            let (world, runner) = lua.fetch::<(World, CutsceneRunner)>()?;
+            let state = runner.borrow_mut().state(&world.borrow());
+            Ok(state.map(|s| s.speed).unwrap_or(1.))
+        })?,
+    )?;
+
+    table.set(
+        "advance",
+        lua.create_function(|lua, ()| {
+            lua.fetch_one::<CutsceneRunner>()?
+                .borrow()
+                .advance(lua)
+                .to_lua_err()
+        })?,
+    )?;
+
+    table.set(
+        "skip",
+        lua.create_function(|lua, ()| {
+            let (world, runner) = lua.fetch::<(World, CutsceneRunner)>()?;
+            let world = world.borrow();
+            runner.borrow_mut().skip(lua, &world).to_lua_err()
+        })?,
+    )?;
+
+    table.set(
+        "fast_forward",
+        lua.create_function(|lua, speed: f32| {
+            let (world, runner) = lua.fetch::<(World, CutsceneRunner)>()?;
+            runner.borrow_mut().fast_forward(&world.borrow(), speed);
+            Ok(())
+        })?,
+    )?;
+
+    // Everything which needs to yield is defined in Lua.
+    lua.load(include_str!("cutscene.lua"))
+        .set_name("sludge.cutscene")?
+        .eval::<LuaFunction>()?
+        .call::<_, ()>(table.clone())?;
+
+    Ok(LuaValue::Table(table))
+}
+
+inventory::submit! {
+    sludge::api::Module::parse("sludge.cutscene", load)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Position;
+
+    const SCENE: &'static str = r#"
+        done = false
+        sludge.cutscene.play(function(mover)
+            sludge.cutscene.say("Alice", "hello")
+            sludge.cutscene.wait(10)
+            sludge.cutscene.move_entity(mover, 10, 0, 5)
+            done = true
+        end, ...)
+    "#;
+
+    fn cutscene_space(fps: u32) -> Result<Space> {
+        Space::builder()
+            .with_fixed_fps(fps)
+            .with_resource(CutsceneRunner::new())
+            .build()
+    }
+
+    fn play(space: &Space, scene: &str) -> Result<()> {
+        let mover = space
+            .world()?
+            .borrow_mut()
+            .spawn((Position(Isometry2::identity()),));
+        space
+            .lua()
+            .context(|lua| lua.load(scene).call::<_, ()>(LuaEntity::from(mover)))?;
+        Ok(())
+    }
+
+    fn done(space: &Space) -> Result<bool> {
+        Ok(space
+            .lua()
+            .context(|lua| lua.globals().get::<_, bool>("done"))?)
+    }
+
+    fn state(space: &Space) -> Result<Option<CutsceneState>> {
+        let world = space.world()?;
+        let state = space
+            .fetch_one::<CutsceneRunner>()?
+            .borrow_mut()
+            .state(&world.borrow());
+        Ok(state)
+    }
+
+    #[test]
+    fn skipping_finishes_every_wait() -> Result<()> {
+        let mut space = cutscene_space(sludge::FIXED_FPS)?;
+        play(&space, SCENE)?;
+        space.fixed_update()?;
+
+        let playing = state(&space)?.unwrap();
+        assert_eq!(playing.speaker.as_deref(), Some("Alice"));
+        assert_eq!(playing.text.as_deref(), Some("hello"));
+        assert!(!playing.skipping);
+
+        {
+            let world = space.world()?;
+            let runner = space.fetch_one::<CutsceneRunner>()?;
+            space
+                .lua()
+                .context(|lua| runner.borrow_mut().skip(lua, &world.borrow()))?;
+        }
+        space.fixed_update()?;
+        space.fixed_update()?;
+
+        assert!(done(&space)?);
+        assert_eq!(state(&space)?, None);
+        let world = space.world()?;
+        let world = world.borrow();
+        let (_, position) = world.query::<&Position>().iter().next().unwrap();
+        assert_eq!(position.translation.vector, Vector2::new(10., 0.));
+
+        Ok(())
+    }
+
+    #[test]
+    fn waits_follow_the_timestep_and_fast_forward() -> Result<()> {
+        let updates_to_finish = |speed: f32| -> Result<u32> {
+            let mut space = cutscene_space(10)?;
+            play(
+                &space,
+                "done = false; sludge.cutscene.play(function() sludge.cutscene.wait(1); done = true end)",
+            )?;
+            space.fixed_update()?;
+            {
+                let world = space.world()?;
+                let runner = space.fetch_one::<CutsceneRunner>()?;
+                runner.borrow_mut().fast_forward(&world.borrow(), speed);
+            }
+
+            let mut updates = 0;
+            while !done(&space)? {
+                space.fixed_update()?;
+                updates += 1;
+                assert!(updates <= 100, "the cutscene never finished waiting");
+            }
+            Ok(updates)
+        };
+
+        let normal = updates_to_finish(1.)?;
+        assert!((10..=11).contains(&normal), "took {} updates", normal);
+        let fast = updates_to_finish(2.)?;
+        assert!((5..=6).contains(&fast), "took {} updates", fast);
+
+        Ok(())
+    }
+
+    #[test]
+    fn state_survives_saving_and_loading() -> Result<()> {
+        let mut space = cutscene_space(sludge::FIXED_FPS)?;
+        play(
+            &space,
+            r#"sludge.cutscene.play(function()
+                sludge.cutscene.say("Alice", "hello")
+                sludge.cutscene.wait(10)
+            end)"#,
+        )?;
+        space.fixed_update()?;
+        {
+            let world = space.world()?;
+            let runner = space.fetch_one::<CutsceneRunner>()?;
+            runner.borrow_mut().fast_forward(&world.borrow(), 4.);
+        }
+
+        let mut bytes = Vec::new();
+        space.save(&mut bytes)?;
+        let mut loaded = cutscene_space(sludge::FIXED_FPS)?;
+        loaded.load(&mut &bytes[..])?;
+
+        let restored = state(&loaded)?.unwrap();
+        assert_eq!(restored.speaker.as_deref(), Some("Alice"));
+        assert_eq!(restored.text.as_deref(), Some("hello"));
+        assert_eq!(restored.speed, 4.);
+
+        loaded
+            .lua()
+            .context(|lua| loaded.fetch_one::<CutsceneRunner>()?.borrow().advance(lua))?;
+        loaded.fixed_update()?;
+        assert_eq!(state(&loaded)?.unwrap().text, None);
+
+        // Ten seconds of waiting, at four times speed.
+        let mut updates = 0;
+        while state(&loaded)?.is_some() {
+            loaded.fixed_update()?;
+            updates += 1;
+            assert!(updates <= 152, "the cutscene never finished");
+        }
+        assert!(updates >= 148);
+
+        Ok(())
+    }
+}
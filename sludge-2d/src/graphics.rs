@@ -1,6 +1,8 @@
 use {
     sludge::{
-        graphics::{Drawable, Sprite, SpriteBatch, Mesh, Texture},
+        graphics::{
+            Drawable, LineRenderer, Mesh, Silhouette, Sprite, SpriteBatch, Styled, Texture,
+        },
         prelude::*,
     },
     std::any::Any,
@@ -111,8 +113,30 @@ impl Drawable2 for SpriteBatch {
     }
 }
 
+/// The bounds of the lines' points. Thickness and arrowheads are measured in pixels, so
+/// they can't be accounted for here.
+impl Drawable2 for LineRenderer {
+    fn aabb(&self) -> Box2<f32> {
+        let mut initial = Box2::invalid();
+        for (_, line) in self.iter() {
+            for point in &line.points {
+                initial.merge(&Box2::from_corners(*point, *point));
+            }
+        }
+
+        initial
+    }
+}
+
 impl Drawable2 for Mesh {
     fn aabb(&self) -> Box2<f32> {
         self.aabb
     }
 }
+
+/// The bounds of the drawable, grown to cover its shadow and outline.
+impl<D: Drawable2 + Silhouette> Drawable2 for Styled<D> {
+    fn aabb(&self) -> Box2<f32> {
+        self.effects.grow(&self.drawable.aabb())
+    }
+}
@@ -3,7 +3,9 @@ use {
     ordered_float::OrderedFloat,
     sludge::{
         graphics::{Drawable, Graphics, InstanceParam},
+        layers::SortingLayers,
         math::*,
+        prelude::Result,
     },
     std::{
         any::{self},
@@ -173,6 +175,12 @@ impl<'a, T: AnyDrawable2> DrawableNodeBuilder<'a, T> {
         self
     }
 
+    /// Put the node on a layer declared in the [`SortingLayers`], failing if there's no
+    /// such layer.
+    pub fn sorting_layer(&mut self, layers: &SortingLayers, name: &str) -> Result<&mut Self> {
+        Ok(self.layer(layers.id(name)?))
+    }
+
     pub fn parent(&mut self, index: impl Into<Option<ErasedDrawable2Id>>) -> &mut Self {
         let index = index.into();
         if let Some(parent_idx) = index {
@@ -383,6 +391,18 @@ impl DrawableGraph {
         object.layer = layer;
     }
 
+    /// Move a node to a layer declared in the [`SortingLayers`], failing if there's no
+    /// such layer.
+    pub fn set_sorting_layer(
+        &mut self,
+        object: impl Into<ErasedDrawable2Id>,
+        layers: &SortingLayers,
+        name: &str,
+    ) -> Result<()> {
+        self.set_layer(object, layers.id(name)?);
+        Ok(())
+    }
+
     pub fn set_hidden(&mut self, object: impl Into<ErasedDrawable2Id>, hidden: bool) {
         let object = &mut self.objects[object.into().0];
         *self.dirty.get_mut() |= object.hidden != hidden;
@@ -102,8 +102,7 @@ impl ParticleState for PixelPerfectQuadraticState {
                 InstanceParam::new()
                     .translate2(pixel_pos.coords)
                     .rotate2(self.position.rotation.angle())
-                    .translate2(frame.offset)
-                    .src(frame.uvs),
+                    .frame(&frame),
             )
         }
     }
@@ -9,7 +9,8 @@ use {
         graphics::*,
         prelude::*,
     },
-    std::{borrow::Cow, ffi::OsStr, io::Read, path::Path},
+    std::{borrow::Cow, ffi::OsStr, io::Read, iter, path::Path, ptr},
+    thunderdome::{Arena, Index},
 };
 
 #[derive(Debug, Clone)]
@@ -96,13 +97,19 @@ pub struct FontAtlas {
 }
 
 impl FontAtlas {
-    pub(crate) fn from_rusttype_font<F: FnMut(f32) -> f32>(
-        ctx: &mut Graphics,
+    /// Rasterize a font into an atlas. The atlas texture is created by `make_texture`,
+    /// from its width, height and RGBA bytes.
+    pub(crate) fn from_rusttype_font<F, G>(
+        make_texture: G,
         rusttype_font: &rusttype::Font,
         height_px: f32,
         char_list_type: CharacterListType,
         mut threshold: F,
-    ) -> Result<FontAtlas> {
+    ) -> Result<FontAtlas>
+    where
+        F: FnMut(f32) -> f32,
+        G: FnOnce(u16, u16, &[u8]) -> Result<Texture>,
+    {
         use rusttype as rt;
 
         let font_scale = rt::Scale::uniform(height_px);
@@ -219,8 +226,7 @@ impl FontAtlas {
             texture_cursor.x = 0;
         }
 
-        let texture_obj =
-            Texture::from_rgba8(ctx, texture_width as u16, texture_height as u16, &texture);
+        let texture_obj = make_texture(texture_width as u16, texture_height as u16, &texture)?;
 
         Ok(FontAtlas {
             font_texture: Cached::new(texture_obj),
@@ -243,7 +249,13 @@ impl FontAtlas {
             "Unable to create a rusttype::Font using bytes_font"
         ))?;
 
-        Self::from_rusttype_font(ctx, &rusttype_font, height_px, char_list_type, |v| v)
+        Self::from_rusttype_font(
+            |width, height, bytes| Ok(Texture::from_rgba8(ctx, width, height, bytes)),
+            &rusttype_font,
+            height_px,
+            char_list_type,
+            |v| v,
+        )
     }
 
     fn get_char_list(char_list_type: CharacterListType) -> Result<Vec<char>> {
@@ -301,9 +313,100 @@ impl Drawable for FontAtlas {
 
 const DEFAULT_TEXT_BUFFER_SIZE: usize = 64;
 
+/// A glyph already in a sprite batch, along with what it was built from so that it
+/// can be skipped if it hasn't changed.
+#[derive(Debug, Clone, Copy)]
+struct Glyph {
+    id: SpriteId,
+    c: char,
+    color: Color,
+    origin: Point2<f32>,
+}
+
+fn glyph_param(
+    font_atlas: &FontAtlas,
+    c: char,
+    color: Color,
+    origin: &Point2<f32>,
+    offset: &Vector2<f32>,
+) -> InstanceParam {
+    let c_info = font_atlas
+        .font_map
+        .get(&c)
+        .unwrap_or(&font_atlas.font_map[&'?']);
+    InstanceParam::new()
+        .src(c_info.uvs)
+        .color(color)
+        .translate2(origin.coords + offset)
+}
+
+/// Bring the glyphs in `batch` in line with a laid out string, touching only the
+/// sprites which differ. If nothing differs the batch isn't marked dirty, and so isn't
+/// uploaded again.
+fn sync_glyphs(
+    batch: &mut SpriteBatch,
+    glyphs: &mut Vec<Glyph>,
+    font_atlas: &FontAtlas,
+    chars: &[LayoutCharInfo],
+    offset: &Vector2<f32>,
+) {
+    for (i, layout_c) in chars.iter().enumerate() {
+        let origin = layout_c.coords.mins;
+        match glyphs.get_mut(i) {
+            Some(glyph)
+                if glyph.c == layout_c.c
+                    && glyph.color == layout_c.color
+                    && glyph.origin == origin => {}
+            Some(glyph) => {
+                batch[glyph.id] =
+                    glyph_param(font_atlas, layout_c.c, layout_c.color, &origin, offset);
+                glyph.c = layout_c.c;
+                glyph.color = layout_c.color;
+                glyph.origin = origin;
+            }
+            None => {
+                let id = batch.insert(glyph_param(
+                    font_atlas,
+                    layout_c.c,
+                    layout_c.color,
+                    &origin,
+                    offset,
+                ));
+                glyphs.push(Glyph {
+                    id,
+                    c: layout_c.c,
+                    color: layout_c.color,
+                    origin,
+                });
+            }
+        }
+    }
+
+    if glyphs.len() > chars.len() {
+        for glyph in glyphs.drain(chars.len()..) {
+            batch.remove(glyph.id);
+        }
+    }
+}
+
+/// Push a run of differently colored strings onto a layout.
+fn push_spans(layout: &mut TextLayout, spans: &[(&str, Color)]) {
+    for &(text, color) in spans {
+        layout.push_str(text, iter::repeat(color));
+    }
+}
+
+/// A drawable block of text.
+///
+/// Text can be changed after it's built with [`Text::set_str`], [`Text::set_rich`] or
+/// [`Text::apply_layout`]; only the glyphs which actually change are rewritten, and if
+/// none do the text isn't uploaded to the GPU again. This makes it cheap to update
+/// something like a score display every frame.
 #[derive(Debug)]
 pub struct Text {
     batch: SpriteBatch,
+    glyphs: Vec<Glyph>,
+    font_atlas: Option<Cached<FontAtlas>>,
 }
 
 impl Text {
@@ -314,6 +417,8 @@ impl Text {
     pub fn with_capacity(ctx: &mut Graphics, capacity: usize) -> Self {
         Text {
             batch: SpriteBatch::with_capacity(ctx, ctx.null_texture.clone(), capacity),
+            glyphs: Vec::new(),
+            font_atlas: None,
         }
     }
 
@@ -330,20 +435,37 @@ impl Text {
 
     pub fn apply_layout(&mut self, layout: &TextLayout) {
         let font_atlas = layout.font_atlas.load();
-        let question_mark = &font_atlas.font_map[&'?'];
-        self.batch.clear();
-        self.batch.set_texture(font_atlas.font_texture.clone());
-        for layout_c in layout.chars.iter() {
-            let c_info = font_atlas
-                .font_map
-                .get(&layout_c.c)
-                .unwrap_or(question_mark);
-            let i_param = InstanceParam::new()
-                .src(c_info.uvs)
-                .color(layout_c.color)
-                .translate2(Vector2::new(layout_c.coords.mins.x, layout_c.coords.mins.y));
-            self.batch.insert(i_param);
+        let same_atlas = self
+            .font_atlas
+            .as_ref()
+            .map_or(false, |current| ptr::eq(&*current.load(), &*font_atlas));
+        if !same_atlas {
+            // Every glyph's source rectangle is different in a new atlas.
+            self.batch.clear();
+            self.glyphs.clear();
+            self.batch.set_texture(font_atlas.font_texture.clone());
+            self.font_atlas = Some(layout.font_atlas.clone());
         }
+
+        sync_glyphs(
+            &mut self.batch,
+            &mut self.glyphs,
+            &font_atlas,
+            &layout.chars,
+            &Vector2::zeros(),
+        );
+    }
+
+    /// Replace the text with a single string in a single color.
+    pub fn set_str(&mut self, font_atlas: &Cached<FontAtlas>, text: &str, color: Color) {
+        self.set_rich(font_atlas, &[(text, color)]);
+    }
+
+    /// Replace the text with a sequence of differently colored strings.
+    pub fn set_rich(&mut self, font_atlas: &Cached<FontAtlas>, spans: &[(&str, Color)]) {
+        let mut layout = TextLayout::new(font_atlas.clone());
+        push_spans(&mut layout, spans);
+        self.apply_layout(&layout);
     }
 }
 
@@ -359,6 +481,149 @@ impl Drawable2 for Text {
     }
 }
 
+/// Text can be drawn with a shadow and an outline through [`Effects`].
+impl Silhouette for Text {
+    fn draw_silhouette(&self, ctx: &mut Graphics, instance: InstanceParam, color: Color) {
+        self.batch.draw_silhouette(ctx, instance, color);
+    }
+}
+
+/// Identifies a label in a [`TextBatch`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LabelId(Index);
+
+#[derive(Debug)]
+struct Label {
+    position: Point2<f32>,
+    glyphs: Vec<Glyph>,
+}
+
+/// Many small labels sharing a single font atlas, drawn together in one draw call.
+/// Like [`Text`], updating a label only rewrites the glyphs which change.
+pub struct TextBatch {
+    font_atlas: Cached<FontAtlas>,
+    batch: SpriteBatch,
+    labels: Arena<Label>,
+    layout: TextLayout,
+}
+
+impl TextBatch {
+    pub fn new(ctx: &mut Graphics, font_atlas: impl Into<Cached<FontAtlas>>) -> Self {
+        Self::with_capacity(ctx, font_atlas, DEFAULT_TEXT_BUFFER_SIZE)
+    }
+
+    pub fn with_capacity(
+        ctx: &mut Graphics,
+        font_atlas: impl Into<Cached<FontAtlas>>,
+        capacity: usize,
+    ) -> Self {
+        let font_atlas = font_atlas.into();
+        let texture = font_atlas.load().font_texture.clone();
+        Self {
+            batch: SpriteBatch::with_capacity(ctx, texture, capacity),
+            labels: Arena::new(),
+            layout: TextLayout::new(font_atlas.clone()),
+            font_atlas,
+        }
+    }
+
+    pub fn insert(&mut self, position: Point2<f32>, text: &str, color: Color) -> LabelId {
+        self.insert_rich(position, &[(text, color)])
+    }
+
+    pub fn insert_rich(&mut self, position: Point2<f32>, spans: &[(&str, Color)]) -> LabelId {
+        let id = LabelId(self.labels.insert(Label {
+            position,
+            glyphs: Vec::new(),
+        }));
+        self.set_rich(id, spans);
+        id
+    }
+
+    pub fn set_str(&mut self, id: LabelId, text: &str, color: Color) {
+        self.set_rich(id, &[(text, color)]);
+    }
+
+    pub fn set_rich(&mut self, id: LabelId, spans: &[(&str, Color)]) {
+        let label = match self.labels.get_mut(id.0) {
+            Some(label) => label,
+            None => return,
+        };
+
+        self.layout.clear();
+        push_spans(&mut self.layout, spans);
+        sync_glyphs(
+            &mut self.batch,
+            &mut label.glyphs,
+            &self.font_atlas.load(),
+            &self.layout.chars,
+            &label.position.coords,
+        );
+    }
+
+    pub fn position(&self, id: LabelId) -> Option<Point2<f32>> {
+        self.labels.get(id.0).map(|label| label.position)
+    }
+
+    pub fn set_position(&mut self, id: LabelId, position: Point2<f32>) {
+        let label = match self.labels.get_mut(id.0) {
+            Some(label) if label.position != position => label,
+            _ => return,
+        };
+
+        label.position = position;
+        let font_atlas = self.font_atlas.load();
+        for glyph in &label.glyphs {
+            self.batch[glyph.id] = glyph_param(
+                &font_atlas,
+                glyph.c,
+                glyph.color,
+                &glyph.origin,
+                &position.coords,
+            );
+        }
+    }
+
+    pub fn remove(&mut self, id: LabelId) {
+        if let Some(label) = self.labels.remove(id.0) {
+            for glyph in label.glyphs {
+                self.batch.remove(glyph.id);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.labels.clear();
+        self.batch.clear();
+    }
+}
+
+impl Drawable for TextBatch {
+    fn draw(&self, ctx: &mut Graphics, instance: InstanceParam) {
+        self.batch.draw(ctx, instance);
+    }
+}
+
+impl Drawable2 for TextBatch {
+    fn aabb(&self) -> Box2<f32> {
+        self.batch.aabb()
+    }
+}
+
+impl Silhouette for TextBatch {
+    fn draw_silhouette(&self, ctx: &mut Graphics, instance: InstanceParam, color: Color) {
+        self.batch.draw_silhouette(ctx, instance, color);
+    }
+}
+
 // end - ending index of current word within TextLayout.chars (we always
 // start at 0 and will use the previous word's end to figure out the size
 // of the next word)
@@ -554,19 +819,19 @@ impl Asset for FontAtlas {
         resources: &R,
     ) -> Result<Loaded<Self>> {
         let key = key.to_rust::<FontAtlasKey>()?;
-        let mut font = cache.get::<Font>(&Key::from_path(&key.path))?;
-        let tmp = resources.fetch_one::<Graphics>()?;
-        let gfx = &mut *tmp.borrow_mut();
+        let mut font = cache.get::<Font>(&Key::path(&key.path))?;
+        let make_texture =
+            |width, height, bytes: &[u8]| Texture::from_resources(resources, width, height, bytes);
         let atlas = match key.threshold {
             Some(t) => FontAtlas::from_rusttype_font(
-                gfx,
+                make_texture,
                 &font.load_cached().inner,
                 key.size as f32,
                 key.char_list_type,
                 |v| if v > t { 1. } else { 0. },
             )?,
             None => FontAtlas::from_rusttype_font(
-                gfx,
+                make_texture,
                 &font.load_cached().inner,
                 key.size as f32,
                 key.char_list_type,
@@ -0,0 +1,75 @@
+//! An on-screen display of the input currently being held, for debugging input handling
+//! and for showing inputs in recorded footage.
+//!
+//! The [`InputOverlay`] draws a panel with a [`Ui`] listing every held button and every
+//! active axis of an [`InputState`], formatted with their `Debug` implementations. It
+//! works just as well on an input state being driven by an
+//! [`InputPlayback`](sludge::input::InputPlayback), which makes it handy for checking
+//! that a replay is doing what it should.
+
+use {
+    sludge::input::InputState,
+    std::{fmt::Debug, hash::Hash},
+};
+
+use crate::{overlay::OverlayPanel, ui::Ui};
+
+/// Draws the held buttons and active axes of an [`InputState`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputOverlay {
+    pub panel: OverlayPanel,
+    /// Whether to show the position of the mouse cursor as well.
+    pub show_cursor: bool,
+}
+
+impl Default for InputOverlay {
+    fn default() -> Self {
+        Self {
+            panel: OverlayPanel::new(160.),
+            show_cursor: false,
+        }
+    }
+}
+
+impl InputOverlay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declare the overlay's panel.
+    pub fn draw<Axes, Buttons>(&self, input: &InputState<Axes, Buttons>, ui: &mut Ui)
+    where
+        Axes: Eq + Hash + Clone + Debug,
+        Buttons: Eq + Hash + Clone + Debug,
+    {
+        if !self.panel.enabled {
+            return;
+        }
+
+        // Hash map iteration order isn't stable from frame to frame, so sort the rows to
+        // keep them from jumping around.
+        let mut buttons = input
+            .buttons_down()
+            .map(|button| format!("{:?}", button))
+            .collect::<Vec<_>>();
+        buttons.sort();
+
+        let mut axes = input
+            .axes_active()
+            .map(|(axis, position)| format!("{:?}: {:+.2}", axis, position))
+            .collect::<Vec<_>>();
+        axes.sort();
+
+        let mut rows = buttons;
+        rows.extend(axes);
+        if self.show_cursor {
+            let cursor = input.mouse_position();
+            rows.push(format!("cursor: ({:.0}, {:.0})", cursor.x, cursor.y));
+        }
+        if rows.is_empty() {
+            rows.push("no input".to_owned());
+        }
+
+        self.panel.draw_rows(&rows, ui);
+    }
+}
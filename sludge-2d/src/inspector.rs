@@ -0,0 +1,312 @@
+//! A runtime entity inspector, drawn with a [`Ui`].
+//!
+//! The [`Inspector`] resource shows every [reflected](sludge::reflect) component on a
+//! selected entity, and lets numbers be nudged up and down and booleans be flipped,
+//! writing the changes straight back to the world. Anything else is displayed but
+//! can't be edited. Entities are selected either directly, or by picking the entity
+//! under a point in world space, which [`Camera2d::pick`](crate::camera::Camera2d::pick)
+//! can find from the mouse position.
+//!
+//! The inspector keeps all of its state in its own resource and never touches the
+//! world unless a field is edited, so it has no effect on what gets persisted. It
+//! starts out disabled, and is toggled with [`Inspector::toggle`] or from Lua with
+//! `sludge.inspector.toggle()`.
+
+use {
+    serde_json::Value,
+    sludge::{
+        ecs::*,
+        prelude::*,
+        reflect::{FieldKind, ReflectionRegistry},
+    },
+};
+
+use crate::{query::PointQuery, ui::Ui, Position, Shape};
+
+/// Space left between the edges of the inspector panel and its contents.
+const INSPECTOR_PADDING: f32 = 8.;
+
+/// Vertical space taken up by a single row of the inspector.
+const ROW_HEIGHT: f32 = 20.;
+
+/// Width of the buttons used to edit fields.
+const BUTTON_WIDTH: f32 = 20.;
+
+/// The entity inspector. See the [module documentation](self) for details.
+#[derive(Debug)]
+pub struct Inspector {
+    enabled: bool,
+    selected: Option<Entity>,
+    registry: ReflectionRegistry,
+    /// How much numeric fields change per click. Integer fields always change by at
+    /// least one.
+    pub step: f64,
+    /// How close to an entity's position a point must be to pick it, for entities
+    /// without a [`Shape`].
+    pub pick_radius: f32,
+}
+
+impl Default for Inspector {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            selected: None,
+            registry: ReflectionRegistry::new(),
+            step: 1.,
+            pick_radius: 8.,
+        }
+    }
+}
+
+impl Inspector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn toggle(&mut self) {
+        self.enabled = !self.enabled;
+    }
+
+    pub fn selected(&self) -> Option<Entity> {
+        self.selected
+    }
+
+    pub fn select(&mut self, entity: Option<Entity>) {
+        self.selected = entity;
+    }
+
+    /// Find the entity under a point in world space. Entities with a [`Shape`] are
+    /// picked if the point is inside the shape, and entities with only a [`Position`]
+    /// are picked if the point is within [`Inspector::pick_radius`] of them. If
+    /// several entities are under the point, the closest one wins.
+    pub fn pick(&self, world: &World, point: Point2<f32>) -> Option<Entity> {
+        world
+            .query::<(&Position, Option<&Shape>)>()
+            .iter()
+            .filter_map(|(entity, (position, shape))| {
+                let distance = (position.0.translation.vector - point.coords).norm();
+                let hit = match shape {
+                    Some(shape) => shape.handle.as_point_query().map_or(false, |q| {
+                        q.contains_point(&(position.0 * shape.local), &point)
+                    }),
+                    None => distance <= self.pick_radius,
+                };
+                if hit {
+                    Some((entity, distance))
+                } else {
+                    None
+                }
+            })
+            .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+            .map(|(entity, _)| entity)
+    }
+
+    /// Select the entity under a point in world space, or clear the selection if
+    /// there isn't one.
+    pub fn select_at(&mut self, world: &World, point: Point2<f32>) -> Option<Entity> {
+        self.selected = self.pick(world, point);
+        self.selected
+    }
+
+    /// Draw the inspector into `bounds` and apply any edits made through it. Does
+    /// nothing if the inspector is disabled.
+    pub fn draw(&mut self, world: &mut World, ui: &mut Ui, bounds: Box2<f32>) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        ui.panel(bounds);
+        let mut cursor = bounds.mins + Vector2::repeat(INSPECTOR_PADDING);
+        let right = bounds.maxs.x - INSPECTOR_PADDING;
+
+        let entity = match self.selected {
+            Some(entity) if world.contains(entity) => entity,
+            _ => {
+                self.selected = None;
+                ui.label(cursor, "no entity selected");
+                return Ok(());
+            }
+        };
+
+        ui.label(cursor, &format!("entity {:?}", entity));
+        let deselect = Box2::new(right - BUTTON_WIDTH, cursor.y, BUTTON_WIDTH, ROW_HEIGHT);
+        if ui.button_with_id("inspector.deselect", deselect, "x") {
+            self.selected = None;
+            return Ok(());
+        }
+        cursor.y += ROW_HEIGHT;
+
+        let mut components = self
+            .registry
+            .components_of(world, entity)
+            .map(|c| Ok((c.name(), c.get(world, entity)?)))
+            .collect::<Result<Vec<_>>>()?;
+        components.sort_by_key(|&(name, _)| name);
+
+        let mut edits = Vec::new();
+        for (name, mut value) in components {
+            if cursor.y + ROW_HEIGHT > bounds.maxs.y {
+                break;
+            }
+
+            ui.label(cursor, name);
+            cursor.y += ROW_HEIGHT;
+
+            let mut edited = false;
+            match &mut value {
+                Value::Object(fields) => {
+                    for (field, field_value) in fields.iter_mut() {
+                        let id = format!("inspector.{}.{}", name, field);
+                        edited |= self.field(ui, &id, field, field_value, cursor, right);
+                        cursor.y += ROW_HEIGHT;
+                    }
+                }
+                other => {
+                    let id = format!("inspector.{}", name);
+                    edited |= self.field(ui, &id, "", other, cursor, right);
+                    cursor.y += ROW_HEIGHT;
+                }
+            }
+
+            if edited {
+                edits.push((name, value));
+            }
+        }
+
+        for (name, value) in edits {
+            // The component was found in the registry a moment ago, so it's still there.
+            self.registry.get(name).unwrap().set(world, entity, value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Draw a single field, returning `true` if it was edited.
+    fn field(
+        &self,
+        ui: &mut Ui,
+        id: &str,
+        name: &str,
+        value: &mut Value,
+        cursor: Point2<f32>,
+        right: f32,
+    ) -> bool {
+        let position = cursor + Vector2::x() * INSPECTOR_PADDING;
+        let text = match value {
+            Value::String(s) => format!("{:?}", s),
+            other => other.to_string(),
+        };
+        if name.is_empty() {
+            ui.label(position, &text);
+        } else {
+            ui.label(position, &format!("{}: {}", name, text));
+        }
+
+        let button = |offset: f32| {
+            Box2::new(
+                right - offset * BUTTON_WIDTH,
+                cursor.y,
+                BUTTON_WIDTH,
+                ROW_HEIGHT,
+            )
+        };
+
+        match FieldKind::from(&*value) {
+            FieldKind::Number => {
+                let decrement = ui.button_with_id(&format!("{}.-", id), button(2.), "-");
+                let increment = ui.button_with_id(&format!("{}.+", id), button(1.), "+");
+                let sign = match (decrement, increment) {
+                    (true, false) => -1.,
+                    (false, true) => 1.,
+                    _ => return false,
+                };
+
+                *value = match value.as_i64() {
+                    Some(i) => Value::from(i + sign as i64 * (self.step.round() as i64).max(1)),
+                    None => Value::from(value.as_f64().unwrap_or(0.) + sign * self.step),
+                };
+                true
+            }
+            FieldKind::Bool => {
+                if ui.button_with_id(&format!("{}.toggle", id), button(1.), "!") {
+                    *value = Value::Bool(!value.as_bool().unwrap_or(false));
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+inventory::submit! {
+    sludge::api::Module::parse("sludge.inspector", |lua| {
+        let table = lua.create_table()?;
+
+        table.set(
+            "toggle",
+            lua.create_function(|lua, ()| {
+                lua.fetch_one::<Inspector>()?.borrow_mut().toggle();
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "set_enabled",
+            lua.create_function(|lua, enabled: bool| {
+                lua.fetch_one::<Inspector>()?.borrow_mut().set_enabled(enabled);
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "is_enabled",
+            lua.create_function(|lua, ()| {
+                Ok(lua.fetch_one::<Inspector>()?.borrow().is_enabled())
+            })?,
+        )?;
+
+        table.set(
+            "select",
+            lua.create_function(|lua, entity: Option<LuaEntity>| {
+                lua.fetch_one::<Inspector>()?
+                    .borrow_mut()
+                    .select(entity.map(Entity::from));
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "select_at",
+            lua.create_function(|lua, (x, y): (f32, f32)| {
+                let (world, inspector) = lua.fetch::<(World, Inspector)>()?;
+                let selected = inspector
+                    .borrow_mut()
+                    .select_at(&world.borrow(), Point2::new(x, y));
+                Ok(selected.map(LuaEntity::from))
+            })?,
+        )?;
+
+        table.set(
+            "selected",
+            lua.create_function(|lua, ()| {
+                Ok(lua
+                    .fetch_one::<Inspector>()?
+                    .borrow()
+                    .selected()
+                    .map(LuaEntity::from))
+            })?,
+        )?;
+
+        Ok(LuaValue::Table(table))
+    })
+}
@@ -0,0 +1,252 @@
+//! Simple kinematic motion: integrating [`Velocity`] into [`Position`].
+//!
+//! Every entity with both a `Position` and a `Velocity` is moved by the
+//! [`KinematicsSystem`] once per fixed step, by the space's
+//! [fixed timestep](sludge::timestep). Its motion can be further shaped with the
+//! optional [`Acceleration`], [`Damping`] and [`MaxSpeed`] components, which are applied
+//! in that order before the velocity is integrated. The system is a
+//! [`DefaultSystem`](sludge::DefaultSystem) named `"Kinematics"`, so every space runs it
+//! in the [fixed stage](Stage::Fixed) unless it's left out with
+//! [`SpaceBuilder::without_default_system`](sludge::SpaceBuilder::without_default_system).
+
+use {
+    serde::{Deserialize, Serialize},
+    sludge::{
+        api::{LuaComponent, LuaComponentInterface},
+        dispatcher::Stage,
+        ecs::*,
+        pause,
+        prelude::*,
+        reflect::ReflectedComponent,
+        timestep, DefaultSystem,
+    },
+};
+
+use crate::{math::Velocity2, Position, Velocity};
+
+/// A constant change in an entity's [`Velocity`], in units per second squared (and
+/// radians per second squared, for `angular`.)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Acceleration {
+    pub x: f32,
+    pub y: f32,
+    pub angular: f32,
+}
+
+impl<'a> SmartComponent<ScContext<'a>> for Acceleration {}
+
+inventory::submit! {
+    CloneComponent::of::<Acceleration>()
+}
+
+/// Exponential decay of an entity's [`Velocity`]. A damping of `d` scales the velocity
+/// by `exp(-d)` every second.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Damping {
+    pub linear: f32,
+    pub angular: f32,
+}
+
+impl<'a> SmartComponent<ScContext<'a>> for Damping {}
+
+inventory::submit! {
+    CloneComponent::of::<Damping>()
+}
+
+/// A cap on the speed of an entity's linear [`Velocity`].
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MaxSpeed(pub f32);
+
+impl<'a> SmartComponent<ScContext<'a>> for MaxSpeed {}
+
+inventory::submit! {
+    CloneComponent::of::<MaxSpeed>()
+}
+
+macro_rules! serde_component {
+    ($component:ident, $accessor:ident, $name:literal) => {
+        #[derive(Debug, Clone, Copy)]
+        pub struct $accessor(Entity);
+
+        impl LuaUserData for $accessor {
+            fn add_methods<'lua, T: LuaUserDataMethods<'lua, Self>>(methods: &mut T) {
+                methods.add_method("to_table", |lua, this, ()| {
+                    let world = lua.fetch_one::<World>()?;
+                    let value = *world.borrow().get::<$component>(this.0).to_lua_err()?;
+                    rlua_serde::to_value(lua, value)
+                });
+
+                methods.add_method("set", |lua, this, value: LuaValue<'lua>| {
+                    let value = rlua_serde::from_value::<$component>(value)?;
+                    let world = lua.fetch_one::<World>()?;
+                    *world.borrow().get_mut::<$component>(this.0).to_lua_err()? = value;
+                    Ok(())
+                });
+            }
+        }
+
+        impl LuaComponentInterface for $component {
+            fn accessor<'lua>(lua: LuaContext<'lua>, entity: Entity) -> LuaResult<LuaValue<'lua>> {
+                $accessor(entity).to_lua(lua)
+            }
+
+            fn bundler<'lua>(
+                _lua: LuaContext<'lua>,
+                args: LuaValue<'lua>,
+                builder: &mut EntityBuilder,
+            ) -> LuaResult<()> {
+                builder.add(rlua_serde::from_value::<$component>(args)?);
+                Ok(())
+            }
+        }
+
+        inventory::submit! {
+            LuaComponent::new::<$component>($name)
+        }
+
+        inventory::submit! {
+            ReflectedComponent::new::<$component>($name)
+        }
+    };
+}
+
+serde_component!(Acceleration, AccelerationAccessor, "Acceleration");
+serde_component!(Damping, DampingAccessor, "Damping");
+serde_component!(MaxSpeed, MaxSpeedAccessor, "MaxSpeed");
+
+/// Advance a single entity's motion by `dt` seconds.
+fn step(
+    position: &mut Position,
+    velocity: &mut Velocity2<f32>,
+    acceleration: Option<&Acceleration>,
+    damping: Option<&Damping>,
+    max_speed: Option<&MaxSpeed>,
+    dt: f32,
+) {
+    if let Some(a) = acceleration {
+        velocity.linear += Vector2::new(a.x, a.y) * dt;
+        velocity.angular += a.angular * dt;
+    }
+
+    if let Some(d) = damping {
+        velocity.linear *= (-d.linear * dt).exp();
+        velocity.angular *= (-d.angular * dt).exp();
+    }
+
+    if let Some(&MaxSpeed(max)) = max_speed {
+        let speed = velocity.linear.norm();
+        if speed > max {
+            velocity.linear *= max / speed;
+        }
+    }
+
+    position.translation.vector += velocity.linear * dt;
+    position.rotation *= UnitComplex::new(velocity.angular * dt);
+}
+
+/// Integrates [`Velocity`] into [`Position`], applying [`Acceleration`], [`Damping`] and
+/// [`MaxSpeed`] where present. Does nothing while the gameplay channel is
+/// [paused](sludge::pause).
+pub struct KinematicsSystem;
+
+inventory::submit! {
+    DefaultSystem::new("Kinematics", &[], Stage::Fixed, || Box::new(KinematicsSystem))
+}
+
+impl System for KinematicsSystem {
+    fn init(
+        &self,
+        _lua: LuaContext,
+        _local: &mut OwnedResources,
+        _global: Option<&SharedResources>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn update(&self, _lua: LuaContext, resources: &UnifiedResources) -> Result<()> {
+        if pause::is_paused(resources, pause::GAMEPLAY) {
+            return Ok(());
+        }
+
+        let dt = timestep::fixed_dt(resources);
+        let world = resources.fetch_one::<World>()?;
+        let world = world.borrow();
+
+        for (_, (mut position, mut velocity, acceleration, damping, max_speed)) in world
+            .query_enabled::<(
+                &mut Position,
+                &mut Velocity,
+                Option<&Acceleration>,
+                Option<&Damping>,
+                Option<&MaxSpeed>,
+            )>()
+            .iter()
+        {
+            step(
+                &mut *position,
+                &mut velocity.0,
+                acceleration.as_deref(),
+                damping.as_deref(),
+                max_speed.as_deref(),
+                dt,
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn max_speed_clamps_after_acceleration() {
+        let dt = 0.1;
+        let mut position = Position::default();
+        let mut velocity = Velocity2::linear(3., 0.);
+        let acceleration = Acceleration {
+            x: 60.,
+            ..Acceleration::default()
+        };
+
+        step(
+            &mut position,
+            &mut velocity,
+            Some(&acceleration),
+            None,
+            Some(&MaxSpeed(3.5)),
+            dt,
+        );
+
+        assert!((velocity.linear.x - 3.5).abs() < 1e-6);
+        assert!((position.translation.vector.x - 3.5 * dt).abs() < 1e-6);
+    }
+
+    #[test]
+    fn spaces_run_kinematics_by_default() -> Result<()> {
+        let moved = |mut space: Space| -> Result<f32> {
+            let entity = space
+                .world()?
+                .borrow_mut()
+                .spawn((Position::default(), Velocity(Velocity2::linear(60., 0.))));
+            space.fixed_update()?;
+            let world = space.world()?;
+            let world = world.borrow();
+            let x = world.get::<Position>(entity)?.translation.vector.x;
+            Ok(x)
+        };
+
+        assert!((moved(Space::new()?)? - 1.).abs() < 1e-4);
+        let faster = Space::builder().with_fixed_fps(30).build()?;
+        assert!((moved(faster)? - 2.).abs() < 1e-4);
+        let without = Space::builder()
+            .without_default_system("Kinematics")
+            .build()?;
+        assert_eq!(moved(without)?, 0.);
+
+        Ok(())
+    }
+}
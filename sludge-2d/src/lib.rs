@@ -1,12 +1,14 @@
-#![feature(clamp)]
-
 use {
     serde::{Deserialize, Serialize},
     sludge::{
-        api::{LuaComponent, LuaComponentInterface},
+        api::{
+            math::{Coords, LuaIsometry2, LuaVec2},
+            LuaComponent, LuaComponentInterface,
+        },
         ecs::*,
         math::*,
         prelude::*,
+        reflect::ReflectedComponent,
     },
     std::ops,
 };
@@ -15,12 +17,29 @@ pub use ncollide2d::{
     self as nc,
     bounding_volume::{self, BoundingVolume, HasBoundingVolume},
     query::{self, DefaultTOIDispatcher, Proximity},
-    shape::{Ball, Cuboid, ShapeHandle},
+    shape::{Ball, Capsule, Compound, ConvexPolygon, Cuboid, ShapeHandle},
 };
 
+pub mod camera;
+pub mod cutscene;
 pub mod graphics;
+pub mod input_overlay;
+pub mod inspector;
+pub mod kinematics;
+pub mod log_overlay;
 pub mod math;
+pub mod nav;
+pub mod overlay;
+pub mod particles;
+pub mod pick;
+pub mod profile_overlay;
+pub mod shape_render;
 pub mod spatial_hash;
+#[cfg(feature = "bench")]
+pub mod stress;
+pub mod tilemap;
+pub mod ui;
+pub mod virtual_gamepad;
 
 pub mod prelude {
     pub use crate::math::*;
@@ -80,6 +99,10 @@ impl From<Position> for PositionProxy {
 
 impl<'a> SmartComponent<ScContext<'a>> for Position {}
 
+inventory::submit! {
+    CloneComponent::of::<Position>()
+}
+
 impl ops::Deref for Position {
     type Target = Isometry2<f32>;
 
@@ -144,14 +167,26 @@ impl LuaUserData for PositionAccessor {
             (x, y).to_lua_multi(lua)
         });
 
-        methods.add_method("set_coords", |lua, this, (x, y): (f32, f32)| {
+        methods.add_method("set_coords", |lua, this, Coords(coords)| {
             let world = lua.fetch_one::<World>()?;
             world
                 .borrow()
                 .get_mut::<Position>(this.0)
                 .to_lua_err()?
                 .translation
-                .vector = Vector2::new(x, y);
+                .vector = coords;
+            Ok(())
+        });
+
+        methods.add_method("isometry", |lua, this, ()| {
+            let world = lua.fetch_one::<World>()?;
+            let pos = *world.borrow().get::<Position>(this.0).to_lua_err()?;
+            Ok(LuaIsometry2(pos.0))
+        });
+
+        methods.add_method("set_isometry", |lua, this, iso: LuaIsometry2| {
+            let world = lua.fetch_one::<World>()?;
+            world.borrow().get_mut::<Position>(this.0).to_lua_err()?.0 = iso.0;
             Ok(())
         });
 
@@ -173,7 +208,10 @@ impl LuaComponentInterface for Position {
         args: LuaValue<'lua>,
         builder: &mut EntityBuilder,
     ) -> LuaResult<()> {
-        let position = rlua_serde::from_value::<Position>(args)?;
+        let position = match args {
+            LuaValue::UserData(ud) => Position(ud.borrow::<LuaIsometry2>()?.0),
+            other => rlua_serde::from_value::<Position>(other)?,
+        };
         builder.add(position);
         Ok(())
     }
@@ -183,6 +221,10 @@ inventory::submit! {
     LuaComponent::new::<Position>("Position")
 }
 
+inventory::submit! {
+    ReflectedComponent::new::<Position>("Position")
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 #[serde(from = "VelocityProxy", into = "VelocityProxy")]
 pub struct Velocity(pub Velocity2<f32>);
@@ -230,6 +272,10 @@ impl From<Velocity> for VelocityProxy {
 
 impl<'a> SmartComponent<ScContext<'a>> for Velocity {}
 
+inventory::submit! {
+    CloneComponent::of::<Velocity>()
+}
+
 impl ops::Deref for Velocity {
     type Target = Velocity2<f32>;
 
@@ -286,16 +332,22 @@ impl LuaUserData for VelocityAccessor {
             (x, y).to_lua_multi(lua)
         });
 
-        methods.add_method("set_linear", |lua, this, (x, y)| {
+        methods.add_method("set_linear", |lua, this, Coords(linear)| {
             let world = lua.fetch_one::<World>()?;
             world
                 .borrow()
                 .get_mut::<Velocity>(this.0)
                 .to_lua_err()?
-                .linear = Vector2::new(x, y);
+                .linear = linear;
             Ok(())
         });
 
+        methods.add_method("linear_vec", |lua, this, ()| {
+            let world = lua.fetch_one::<World>()?;
+            let velocity = *world.borrow().get::<Velocity>(this.0).to_lua_err()?;
+            Ok(LuaVec2(velocity.0.linear))
+        });
+
         methods.add_method("to_table", |lua, this, ()| {
             let tmp = lua.fetch_one::<World>()?;
             let world = tmp.borrow();
@@ -325,6 +377,10 @@ inventory::submit! {
     LuaComponent::new::<Velocity>("Velocity")
 }
 
+inventory::submit! {
+    ReflectedComponent::new::<Velocity>("Velocity")
+}
+
 #[derive(Clone)]
 pub struct Shape {
     pub local: Isometry2<f32>,
@@ -333,12 +389,37 @@ pub struct Shape {
 
 impl<'a> SmartComponent<ScContext<'a>> for Shape {}
 
+inventory::submit! {
+    CloneComponent::of::<Shape>()
+}
+
 impl Shape {
     pub fn new(local: Isometry2<f32>, handle: ShapeHandle<f32>) -> Self {
         Self { local, handle }
     }
 }
 
+impl Serialize for Shape {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ShapeTable::from_shape(&self.local, &self.handle)
+            .map_err(serde::ser::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Shape {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (local, handle) = ShapeTable::deserialize(deserializer)?
+            .to_shape()
+            .map_err(serde::de::Error::custom)?;
+        Ok(Self { local, handle })
+    }
+}
+
+/// The serialized form of a [`Shape`], which is also how shapes are described to and
+/// from Lua. Every shape has a `position` relative to its parent: for the top-level
+/// shape that's the entity's [`Position`], and for the children of a compound shape
+/// it's the compound itself.
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ShapeTable {
@@ -351,6 +432,105 @@ pub enum ShapeTable {
         position: Position,
         radius: f32,
     },
+    /// A convex polygon. If the points don't describe a convex polygon, their convex
+    /// hull is used instead.
+    Polygon {
+        position: Position,
+        points: Vec<Point2<f32>>,
+    },
+    /// A capsule aligned with the Y axis, with `half_height` being half the length of
+    /// its straight section.
+    Capsule {
+        position: Position,
+        half_height: f32,
+        radius: f32,
+    },
+    Compound {
+        position: Position,
+        shapes: Vec<ShapeTable>,
+    },
+}
+
+impl ShapeTable {
+    /// Describe a shape handle and its local isometry.
+    pub fn from_shape(local: &Isometry2<f32>, handle: &ShapeHandle<f32>) -> Result<Self> {
+        let position = Position(*local);
+        if let Some(cuboid) = handle.as_shape::<Cuboid<f32>>() {
+            let extents = cuboid.half_extents * 2.;
+            Ok(ShapeTable::Box {
+                position,
+                width: extents.x,
+                height: extents.y,
+            })
+        } else if let Some(ball) = handle.as_shape::<Ball<f32>>() {
+            Ok(ShapeTable::Circle {
+                position,
+                radius: ball.radius,
+            })
+        } else if let Some(polygon) = handle.as_shape::<ConvexPolygon<f32>>() {
+            Ok(ShapeTable::Polygon {
+                position,
+                points: polygon.points().to_vec(),
+            })
+        } else if let Some(capsule) = handle.as_shape::<Capsule<f32>>() {
+            Ok(ShapeTable::Capsule {
+                position,
+                half_height: capsule.half_height,
+                radius: capsule.radius,
+            })
+        } else if let Some(compound) = handle.as_shape::<Compound<f32>>() {
+            let shapes = compound
+                .shapes()
+                .iter()
+                .map(|(child_local, child)| Self::from_shape(child_local, child))
+                .collect::<Result<_>>()?;
+            Ok(ShapeTable::Compound { position, shapes })
+        } else {
+            bail!("unsupported shape")
+        }
+    }
+
+    /// Build the shape this describes, along with its local isometry.
+    pub fn to_shape(&self) -> Result<(Isometry2<f32>, ShapeHandle<f32>)> {
+        Ok(match self {
+            ShapeTable::Box {
+                position,
+                width,
+                height,
+            } => (
+                **position,
+                ShapeHandle::new(Cuboid::new(Vector2::new(width / 2., height / 2.))),
+            ),
+            ShapeTable::Circle { position, radius } => {
+                (**position, ShapeHandle::new(Ball::new(*radius)))
+            }
+            ShapeTable::Polygon { position, points } => {
+                let polygon = ConvexPolygon::try_from_points(points).ok_or_else(|| {
+                    anyhow!("a polygon shape needs at least three non-collinear points")
+                })?;
+                (**position, ShapeHandle::new(polygon))
+            }
+            ShapeTable::Capsule {
+                position,
+                half_height,
+                radius,
+            } => (
+                **position,
+                ShapeHandle::new(Capsule::new(*half_height, *radius)),
+            ),
+            ShapeTable::Compound { position, shapes } => {
+                ensure!(
+                    !shapes.is_empty(),
+                    "a compound shape needs at least one child"
+                );
+                let children = shapes
+                    .iter()
+                    .map(ShapeTable::to_shape)
+                    .collect::<Result<Vec<_>>>()?;
+                (**position, ShapeHandle::new(Compound::new(children)))
+            }
+        })
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -362,29 +542,7 @@ impl LuaUserData for ShapeAccessor {
             let tmp = lua.fetch_one::<World>()?;
             let world = tmp.borrow();
             let shape = world.get::<Shape>(this.0).to_lua_err()?;
-
-            if let Some(cuboid) = shape.handle.as_shape::<Cuboid<f32>>() {
-                let extents = cuboid.half_extents * 2.;
-                rlua_serde::to_value(
-                    lua,
-                    ShapeTable::Box {
-                        position: Position(shape.local),
-                        width: extents.x,
-                        height: extents.y,
-                    },
-                )
-            } else if let Some(ball) = shape.handle.as_shape::<Ball<f32>>() {
-                let radius = ball.radius;
-                rlua_serde::to_value(
-                    lua,
-                    ShapeTable::Circle {
-                        position: Position(shape.local),
-                        radius,
-                    },
-                )
-            } else {
-                Err(format_err!("unsupported shape")).to_lua_err()
-            }
+            rlua_serde::to_value(lua, &*shape)
         });
     }
 }
@@ -399,30 +557,7 @@ impl LuaComponentInterface for Shape {
         args: LuaValue<'lua>,
         builder: &mut EntityBuilder,
     ) -> LuaResult<()> {
-        let shape_table = rlua_serde::from_value::<ShapeTable>(args)?;
-        match shape_table {
-            ShapeTable::Box {
-                position,
-                width,
-                height,
-            } => {
-                let local = *position;
-                let cuboid = Cuboid::new(Vector2::new(width / 2., height / 2.));
-                builder.add(Shape {
-                    local,
-                    handle: ShapeHandle::new(cuboid),
-                });
-            }
-            ShapeTable::Circle { position, radius } => {
-                let local = *position;
-                let ball = Ball::new(radius);
-                builder.add(Shape {
-                    local,
-                    handle: ShapeHandle::new(ball),
-                });
-            }
-        }
-
+        builder.add(rlua_serde::from_value::<Shape>(args)?);
         Ok(())
     }
 }
@@ -430,3 +565,50 @@ impl LuaComponentInterface for Shape {
 inventory::submit! {
     LuaComponent::new::<Shape>("Shape")
 }
+
+inventory::submit! {
+    ReflectedComponent::new::<Shape>("Shape")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compound_shape_round_trip() {
+        let table = ShapeTable::Compound {
+            position: Position(Isometry2::translation(4., 0.)),
+            shapes: vec![
+                ShapeTable::Capsule {
+                    position: Position::default(),
+                    half_height: 8.,
+                    radius: 2.,
+                },
+                ShapeTable::Polygon {
+                    position: Position(Isometry2::translation(0., 10.)),
+                    points: vec![
+                        Point2::new(-1., 0.),
+                        Point2::new(1., 0.),
+                        Point2::new(0., 2.),
+                    ],
+                },
+            ],
+        };
+
+        let (local, handle) = table.to_shape().unwrap();
+        let json = serde_json::to_string(&Shape::new(local, handle)).unwrap();
+        let shape = serde_json::from_str::<Shape>(&json).unwrap();
+
+        match ShapeTable::from_shape(&shape.local, &shape.handle).unwrap() {
+            ShapeTable::Compound { position, shapes } => {
+                assert_eq!(position.translation.vector, Vector2::new(4., 0.));
+                assert_eq!(shapes.len(), 2);
+                assert!(matches!(shapes[0], ShapeTable::Capsule { radius, .. } if radius == 2.));
+                assert!(
+                    matches!(&shapes[1], ShapeTable::Polygon { points, .. } if points.len() == 3)
+                );
+            }
+            other => panic!("expected a compound shape, got {:?}", other),
+        }
+    }
+}
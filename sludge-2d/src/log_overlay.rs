@@ -0,0 +1,54 @@
+//! An on-screen display of recent log messages, for seeing what scripts are saying
+//! without a terminal.
+//!
+//! The [`LogOverlay`] draws a panel with a [`Ui`] listing the most recent messages in a
+//! [`LogBuffer`] which pass its [`LogFilter`]. The filter can be changed at any time,
+//! since the buffer keeps everything regardless of what's being shown.
+
+use sludge::log_buffer::{LogBuffer, LogFilter};
+
+use crate::{overlay::OverlayPanel, ui::Ui};
+
+/// Draws the most recent messages in a [`LogBuffer`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogOverlay {
+    pub panel: OverlayPanel,
+    /// How many messages to show at once.
+    pub lines: usize,
+    pub filter: LogFilter,
+}
+
+impl Default for LogOverlay {
+    fn default() -> Self {
+        Self {
+            panel: OverlayPanel::new(640.),
+            lines: 12,
+            filter: LogFilter::default(),
+        }
+    }
+}
+
+impl LogOverlay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declare the overlay's panel.
+    pub fn draw(&self, log: &LogBuffer, ui: &mut Ui) {
+        if !self.panel.enabled {
+            return;
+        }
+
+        let mut rows = log
+            .recent(&self.filter, self.lines)
+            .into_iter()
+            .map(|entry| format!("{:<5} {}: {}", entry.level, entry.target, entry.message))
+            .collect::<Vec<_>>();
+
+        if rows.is_empty() {
+            rows.push("no messages".to_owned());
+        }
+
+        self.panel.draw_rows(&rows, ui);
+    }
+}
@@ -0,0 +1,36 @@
+-- The Lua half of `sludge.nav`. Everything which waits is written here in terms of
+-- `yield`, so that it can be called from any scheduler thread.
+return function(nav)
+    local PATH = nav.PATH_EVENT
+    local ARRIVED = nav.ARRIVED_EVENT
+
+    -- Queue a path request and wait for the answer. Returns the path, or nil if there
+    -- isn't one.
+    function nav.find_path_async(x0, y0, x1, y1)
+        local id = nav.request_path(x0, y0, x1, y1)
+        while true do
+            local _, _, found, path = sludge.thread.yield(PATH)
+            if found == id then
+                return path
+            end
+        end
+    end
+
+    -- Find a path to a point and follow it, waiting until the entity gets there.
+    -- Returns false if there's no path, or if something else stops the entity first.
+    function nav.go_to(entity, x, y, speed, arrive_radius, smooth)
+        local x0, y0 = entity.Position:coords()
+        local path = nav.find_path_async(x0, y0, x, y)
+        if not path then
+            return false
+        end
+
+        nav.follow(entity, path, speed, arrive_radius, smooth)
+        while true do
+            local _, _, stopped, arrived = sludge.thread.yield(ARRIVED)
+            if stopped == entity then
+                return arrived
+            end
+        end
+    end
+end
@@ -0,0 +1,659 @@
+//! Grid-based pathfinding.
+//!
+//! A [`NavGrid`] resource records which cells of a uniform grid are blocked, in a
+//! [`ChunkedBitGrid`] so that it can cover an unbounded world. It can be filled in by
+//! hand with [`NavGrid::set_blocked`], or built from a tile layer of a Tiled map with
+//! [`NavGrid::from_tiled`], which asks a callback whether each tile is solid. Paths are
+//! found with A* over the eight neighbors of each cell; diagonal moves aren't allowed
+//! to cut the corners of blocked cells.
+//!
+//! Entities with a [`Position`], a [`Velocity`] and a [`PathFollow`] are steered along
+//! their path by the [`PathFollowSystem`], which removes the `PathFollow` and broadcasts
+//! [`ARRIVED_EVENT`] once the end of the path is reached. Paths can be abandoned early
+//! with `sludge.nav.stop(entity)`. Grid paths turn sharply at every waypoint; they can
+//! be rounded off with [`PathFollow::smoothed`], or by passing a waypoint spacing as the
+//! last argument of `sludge.nav.follow`.
+//!
+//! From Lua, `sludge.nav.find_path(x0, y0, x1, y1)` finds a path immediately. Long
+//! searches can instead be spread out with `sludge.nav.find_path_async`, which queues a
+//! request for the [`NavSystem`] and waits for the answer:
+//!
+//! ```lua
+//! local path = sludge.nav.find_path_async(x0, y0, x1, y1)
+//! if path then
+//!     sludge.nav.follow(entity, path, 120)
+//! end
+//!
+//! -- Or, all at once, returning whether the entity got there:
+//! local arrived = sludge.nav.go_to(entity, x1, y1, 120)
+//! ```
+//!
+//! Queued requests aren't saved with the space, so a thread waiting on one when the
+//! space is persisted will never be woken after loading.
+
+use {
+    hashbrown::HashMap,
+    serde::{Deserialize, Serialize},
+    sludge::{
+        api::{math::Coords, LuaComponent, LuaComponentInterface, LuaEntity},
+        chunked_grid::ChunkedBitGrid,
+        ecs::*,
+        math::curve::Curve,
+        pause,
+        prelude::*,
+        reflect::ReflectedComponent,
+        tiled::{Layer, TileData, TiledMap},
+        timestep,
+    },
+    std::{
+        cmp::Reverse,
+        collections::{BTreeMap, BinaryHeap, VecDeque},
+    },
+};
+
+use crate::{Position, Velocity};
+
+/// Broadcast by the [`NavSystem`] when a requested path has been searched for, with
+/// the request's ID and the path, or `nil` if there isn't one.
+pub const PATH_EVENT: &'static str = "sludge.nav.path";
+
+/// Broadcast when an entity stops following its path, with the entity and `true` if it
+/// reached the end, or `false` if it was stopped early with `sludge.nav.stop`.
+pub const ARRIVED_EVENT: &'static str = "sludge.nav.arrived";
+
+/// The coordinates of a cell in a [`NavGrid`].
+pub type Cell = (i32, i32);
+
+/// The cost of moving to an orthogonal neighbor. Diagonal moves cost
+/// `DIAGONAL_COST`, which is close enough to `STRAIGHT_COST * sqrt(2)`.
+const STRAIGHT_COST: u32 = 10;
+const DIAGONAL_COST: u32 = 14;
+
+const NEIGHBORS: [(i32, i32); 8] = [
+    (1, 0),
+    (-1, 0),
+    (0, 1),
+    (0, -1),
+    (1, 1),
+    (1, -1),
+    (-1, 1),
+    (-1, -1),
+];
+
+/// The octile distance between two cells, which never overestimates the cost of a
+/// path between them.
+fn heuristic((x0, y0): Cell, (x1, y1): Cell) -> u32 {
+    let (dx, dy) = ((x1 - x0).abs() as u32, (y1 - y0).abs() as u32);
+    let (min, max) = (dx.min(dy), dx.max(dy));
+    DIAGONAL_COST * min + STRAIGHT_COST * (max - min)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PathRequest {
+    id: u64,
+    from: Point2<f32>,
+    to: Point2<f32>,
+}
+
+/// Which cells are blocked, and the queue of paths requested from Lua. See the
+/// [module documentation](self) for details.
+#[derive(Debug, Clone)]
+pub struct NavGrid {
+    blocked: ChunkedBitGrid,
+    requests: VecDeque<PathRequest>,
+    next_request: u64,
+    /// The most cells a single search will expand before giving up. Without a limit,
+    /// searching for an unreachable cell in an open world would never end.
+    pub search_limit: usize,
+    /// How many queued requests the [`NavSystem`] answers per update.
+    pub requests_per_update: usize,
+}
+
+impl NavGrid {
+    /// Create an empty grid with square cells of the given size.
+    pub fn new(cell_size: f32) -> Self {
+        Self {
+            blocked: ChunkedBitGrid::new(cell_size),
+            requests: VecDeque::new(),
+            next_request: 0,
+            search_limit: 4096,
+            requests_per_update: 4,
+        }
+    }
+
+    /// Build a grid from the tile layers of a Tiled map, with one cell per tile. Cells
+    /// are blocked wherever `is_solid` returns `true` for a tile, which is passed the
+    /// tile's global ID and its tile data, if it has any. If `layer` is given, only the
+    /// tile layer with that name is used.
+    pub fn from_tiled<L, T, O, F>(
+        map: &TiledMap<L, T, O>,
+        layer: Option<&str>,
+        mut is_solid: F,
+    ) -> Result<Self>
+    where
+        F: FnMut(u32, Option<&TileData<T>>) -> bool,
+    {
+        let (tile_width, tile_height) = map.tile_dimensions();
+        ensure!(
+            tile_width == tile_height,
+            "navigation grids need square tiles, but the map's tiles are {}x{}",
+            tile_width,
+            tile_height
+        );
+
+        let mut grid = Self::new(tile_width as f32);
+        let tile_layers = map.layers().iter().filter_map(|l| match l {
+            Layer::TileLayer(tile_layer) => Some(tile_layer),
+            _ => None,
+        });
+
+        for tile_layer in tile_layers {
+            if layer.is_some() && tile_layer.name.as_deref() != layer {
+                continue;
+            }
+
+            for (_, chunk) in tile_layer.chunks() {
+                for (cell, gid) in chunk.tiles() {
+                    if gid != 0 && is_solid(gid, map.get_tile_data_for_gid(gid)) {
+                        grid.set_blocked(cell, true);
+                    }
+                }
+            }
+        }
+
+        Ok(grid)
+    }
+
+    pub fn cell_size(&self) -> f32 {
+        self.blocked.scale()
+    }
+
+    /// The cell containing a point.
+    pub fn cell_at(&self, point: Point2<f32>) -> Cell {
+        let size = self.cell_size();
+        (
+            (point.x / size).floor() as i32,
+            (point.y / size).floor() as i32,
+        )
+    }
+
+    pub fn cell_center(&self, cell: Cell) -> Point2<f32> {
+        self.blocked.bounds_at(cell).center()
+    }
+
+    pub fn is_blocked(&self, cell: Cell) -> bool {
+        self.blocked.get(cell)
+    }
+
+    pub fn set_blocked(&mut self, cell: Cell, blocked: bool) {
+        self.blocked.set(cell, blocked);
+    }
+
+    /// Block or unblock every cell overlapping a box.
+    pub fn set_region_blocked(&mut self, aabb: &Box2<f32>, blocked: bool) {
+        let (mins, maxs) = (self.cell_at(aabb.mins), self.cell_at(aabb.maxs));
+        for x in mins.0..=maxs.0 {
+            for y in mins.1..=maxs.1 {
+                self.blocked.set((x, y), blocked);
+            }
+        }
+    }
+
+    /// Unblock every cell.
+    pub fn clear(&mut self) {
+        self.blocked.clear();
+        self.blocked.sweep_chunks();
+    }
+
+    /// Find the cheapest sequence of cells from `from` to `to`, including both. The
+    /// starting cell is allowed to be blocked, so that entities which have been pushed
+    /// into a wall can still find their way out.
+    pub fn find_cells(&self, from: Cell, to: Cell) -> Option<Vec<Cell>> {
+        if self.is_blocked(to) {
+            return None;
+        }
+
+        let mut open = BinaryHeap::new();
+        let mut came_from = HashMap::<Cell, Cell>::new();
+        let mut costs = HashMap::<Cell, u32>::new();
+        let mut expanded = 0;
+
+        open.push(Reverse((heuristic(from, to), 0, from)));
+        costs.insert(from, 0);
+
+        while let Some(Reverse((_, cost, cell))) = open.pop() {
+            if cell == to {
+                let mut path = vec![cell];
+                let mut current = cell;
+                while let Some(&previous) = came_from.get(&current) {
+                    path.push(previous);
+                    current = previous;
+                }
+                path.reverse();
+                return Some(path);
+            }
+
+            // Skip stale entries for cells which have since been reached more cheaply.
+            if costs.get(&cell).map_or(false, |&best| best < cost) {
+                continue;
+            }
+
+            expanded += 1;
+            if expanded > self.search_limit {
+                return None;
+            }
+
+            for &(dx, dy) in NEIGHBORS.iter() {
+                let next = (cell.0 + dx, cell.1 + dy);
+                if self.is_blocked(next) {
+                    continue;
+                }
+
+                let step = if dx != 0 && dy != 0 {
+                    if self.is_blocked((cell.0 + dx, cell.1))
+                        || self.is_blocked((cell.0, cell.1 + dy))
+                    {
+                        continue;
+                    }
+                    DIAGONAL_COST
+                } else {
+                    STRAIGHT_COST
+                };
+
+                let next_cost = cost + step;
+                if costs.get(&next).map_or(true, |&best| next_cost < best) {
+                    costs.insert(next, next_cost);
+                    came_from.insert(next, cell);
+                    open.push(Reverse((next_cost + heuristic(next, to), next_cost, next)));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Find a path between two points, as a list of waypoints to move through in order.
+    /// The starting point isn't included, and the last waypoint is `to` itself; the rest
+    /// are the centers of the cells where the path changes direction.
+    pub fn find_path(&self, from: Point2<f32>, to: Point2<f32>) -> Option<Vec<Point2<f32>>> {
+        let cells = self.find_cells(self.cell_at(from), self.cell_at(to))?;
+
+        let mut path = Vec::new();
+        for window in cells.windows(3) {
+            let (a, b, c) = (window[0], window[1], window[2]);
+            if (b.0 - a.0, b.1 - a.1) != (c.0 - b.0, c.1 - b.1) {
+                path.push(self.cell_center(b));
+            }
+        }
+        path.push(to);
+
+        Some(path)
+    }
+
+    /// Queue a path to be found by the [`NavSystem`], returning the ID which will be
+    /// broadcast along with it in a [`PATH_EVENT`].
+    pub fn request_path(&mut self, from: Point2<f32>, to: Point2<f32>) -> u64 {
+        self.next_request += 1;
+        let id = self.next_request;
+        self.requests.push_back(PathRequest { id, from, to });
+        id
+    }
+}
+
+/// Steers an entity's [`Velocity`] along a path. Once the last waypoint is reached, the
+/// `PathFollow` is removed and [`ARRIVED_EVENT`] is broadcast.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PathFollow {
+    pub path: Vec<Point2<f32>>,
+    /// The index of the waypoint currently being moved towards.
+    #[serde(default)]
+    pub next: usize,
+    /// Speed in units per second.
+    pub speed: f32,
+    /// How close the entity has to get to a waypoint to count as having reached it.
+    #[serde(default = "PathFollow::default_arrive_radius")]
+    pub arrive_radius: f32,
+}
+
+impl<'a> SmartComponent<ScContext<'a>> for PathFollow {}
+
+inventory::submit! {
+    CloneComponent::of::<PathFollow>()
+}
+
+impl PathFollow {
+    pub fn new(path: Vec<Point2<f32>>, speed: f32) -> Self {
+        Self {
+            path,
+            next: 0,
+            speed,
+            arrive_radius: Self::default_arrive_radius(),
+        }
+    }
+
+    fn default_arrive_radius() -> f32 {
+        0.5
+    }
+
+    /// Round off the corners of the path with a Catmull-Rom spline starting at `from`,
+    /// resampled into waypoints roughly `spacing` units apart.
+    pub fn smoothed(mut self, from: Point2<f32>, spacing: f32) -> Self {
+        if self.path.is_empty() || spacing <= 0. {
+            return self;
+        }
+
+        let mut points = Vec::with_capacity(self.path.len() + 1);
+        points.push(from);
+        points.extend(self.path.iter().skip(self.next).copied());
+        let spline = Curve::catmull_rom(points, false);
+        let count = (spline.length() / spacing).ceil() as usize + 1;
+
+        self.path = spline.resample(count).into_iter().skip(1).collect();
+        self.next = 0;
+        self
+    }
+
+    /// Set the entity's velocity for a step of `dt` seconds, returning `true` once the
+    /// path is done.
+    fn steer(&mut self, position: &Position, velocity: &mut Velocity, dt: f32) -> bool {
+        while let Some(&waypoint) = self.path.get(self.next) {
+            let offset = waypoint.coords - position.translation.vector;
+            let distance = offset.norm();
+            if distance <= self.arrive_radius {
+                self.next += 1;
+                continue;
+            }
+
+            // Don't overshoot the waypoint, or the entity will orbit it.
+            velocity.linear = offset / distance * self.speed.min(distance / dt);
+            return false;
+        }
+
+        velocity.linear = Vector2::zeros();
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PathFollowAccessor(Entity);
+
+impl LuaUserData for PathFollowAccessor {
+    fn add_methods<'lua, T: LuaUserDataMethods<'lua, Self>>(methods: &mut T) {
+        methods.add_method("to_table", |lua, this, ()| {
+            let world = lua.fetch_one::<World>()?;
+            let world = world.borrow();
+            let follow = world.get::<PathFollow>(this.0).to_lua_err()?;
+            rlua_serde::to_value(lua, &*follow)
+        });
+
+        methods.add_method("set", |lua, this, value: LuaValue<'lua>| {
+            let value = rlua_serde::from_value::<PathFollow>(value)?;
+            let world = lua.fetch_one::<World>()?;
+            *world.borrow().get_mut::<PathFollow>(this.0).to_lua_err()? = value;
+            Ok(())
+        });
+    }
+}
+
+impl LuaComponentInterface for PathFollow {
+    fn accessor<'lua>(lua: LuaContext<'lua>, entity: Entity) -> LuaResult<LuaValue<'lua>> {
+        PathFollowAccessor(entity).to_lua(lua)
+    }
+
+    fn bundler<'lua>(
+        _lua: LuaContext<'lua>,
+        args: LuaValue<'lua>,
+        builder: &mut EntityBuilder,
+    ) -> LuaResult<()> {
+        builder.add(rlua_serde::from_value::<PathFollow>(args)?);
+        Ok(())
+    }
+}
+
+inventory::submit! {
+    LuaComponent::new::<PathFollow>("PathFollow")
+}
+
+inventory::submit! {
+    ReflectedComponent::new::<PathFollow>("PathFollow")
+}
+
+/// Answers path requests queued on the [`NavGrid`], a few per update, broadcasting a
+/// [`PATH_EVENT`] for each. Inserts an empty `NavGrid` with 16-unit cells if the space
+/// doesn't already have one.
+pub struct NavSystem;
+
+impl System for NavSystem {
+    fn init(
+        &self,
+        _lua: LuaContext,
+        local: &mut OwnedResources,
+        _global: Option<&SharedResources>,
+    ) -> Result<()> {
+        if !local.has_value::<NavGrid>() {
+            local.insert(NavGrid::new(16.));
+        }
+        Ok(())
+    }
+
+    fn update(&self, lua: LuaContext, resources: &UnifiedResources) -> Result<()> {
+        let found = {
+            let nav = resources.fetch_one::<NavGrid>()?;
+            let nav = &mut *nav.borrow_mut();
+            let count = nav.requests_per_update.min(nav.requests.len());
+            nav.requests
+                .drain(..count)
+                .collect::<Vec<_>>()
+                .into_iter()
+                .map(|request| (request.id, nav.find_path(request.from, request.to)))
+                .collect::<Vec<_>>()
+        };
+
+        for (id, path) in found {
+            lua.broadcast(PATH_EVENT, (id, path.map(api::path_to_table)))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Steers every entity with a [`PathFollow`]. Does nothing while the gameplay channel
+/// is [paused](sludge::pause).
+pub struct PathFollowSystem;
+
+impl System for PathFollowSystem {
+    fn update(&self, lua: LuaContext, resources: &UnifiedResources) -> Result<()> {
+        if pause::is_paused(resources, pause::GAMEPLAY) {
+            return Ok(());
+        }
+
+        let dt = timestep::fixed_dt(resources);
+        let world = resources.fetch_one::<World>()?;
+        let arrived = world
+            .borrow()
+            .query_enabled::<(&Position, &mut Velocity, &mut PathFollow)>()
+            .iter()
+            .filter_map(|(entity, (position, mut velocity, mut follow))| {
+                if follow.steer(&position, &mut velocity, dt) {
+                    Some(entity)
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        for entity in arrived {
+            world.borrow_mut().remove_one::<PathFollow>(entity)?;
+            lua.broadcast(ARRIVED_EVENT, (LuaEntity::from(entity), true))?;
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) mod api {
+    use super::*;
+
+    /// Paths are handed to Lua as arrays of `{ x, y }` tables.
+    pub fn path_to_table(path: Vec<Point2<f32>>) -> Vec<BTreeMap<&'static str, f32>> {
+        path.into_iter()
+            .map(|p| vec![("x", p.x), ("y", p.y)].into_iter().collect())
+            .collect()
+    }
+
+    fn path_from_table(table: LuaTable) -> LuaResult<Vec<Point2<f32>>> {
+        table
+            .sequence_values::<LuaTable>()
+            .map(|point| {
+                let point = point?;
+                Ok(Point2::new(point.get("x")?, point.get("y")?))
+            })
+            .collect()
+    }
+
+    pub fn find_path<'lua>(
+        lua: LuaContext<'lua>,
+        (x0, y0, x1, y1): (f32, f32, f32, f32),
+    ) -> LuaResult<Option<Vec<BTreeMap<&'static str, f32>>>> {
+        let path = lua
+            .fetch_one::<NavGrid>()?
+            .borrow()
+            .find_path(Point2::new(x0, y0), Point2::new(x1, y1));
+        Ok(path.map(path_to_table))
+    }
+
+    pub fn request_path<'lua>(
+        lua: LuaContext<'lua>,
+        (x0, y0, x1, y1): (f32, f32, f32, f32),
+    ) -> LuaResult<u64> {
+        Ok(lua
+            .fetch_one::<NavGrid>()?
+            .borrow_mut()
+            .request_path(Point2::new(x0, y0), Point2::new(x1, y1)))
+    }
+
+    /// If `smooth` is given, the path is smoothed into waypoints that far apart.
+    pub fn follow<'lua>(
+        lua: LuaContext<'lua>,
+        (entity, path, speed, arrive_radius, smooth): (
+            LuaEntity,
+            LuaTable<'lua>,
+            f32,
+            Option<f32>,
+            Option<f32>,
+        ),
+    ) -> LuaResult<()> {
+        let mut follow = PathFollow::new(path_from_table(path)?, speed);
+        if let Some(arrive_radius) = arrive_radius {
+            follow.arrive_radius = arrive_radius;
+        }
+
+        let world = lua.fetch_one::<World>()?;
+        if let Some(spacing) = smooth {
+            let from = world
+                .borrow()
+                .get::<Position>(entity.into())
+                .to_lua_err()?
+                .translation
+                .vector;
+            follow = follow.smoothed(Point2::from(from), spacing);
+        }
+
+        world
+            .borrow_mut()
+            .insert_one(entity.into(), follow)
+            .to_lua_err()?;
+        Ok(())
+    }
+
+    pub fn stop<'lua>(lua: LuaContext<'lua>, entity: LuaEntity) -> LuaResult<()> {
+        let removed = lua
+            .fetch_one::<World>()?
+            .borrow_mut()
+            .remove_one::<PathFollow>(entity.into());
+        if removed.is_ok() {
+            lua.broadcast(ARRIVED_EVENT, (entity, false))?;
+        }
+        Ok(())
+    }
+
+    pub fn is_following<'lua>(lua: LuaContext<'lua>, entity: LuaEntity) -> LuaResult<bool> {
+        Ok(lua
+            .fetch_one::<World>()?
+            .borrow()
+            .get::<PathFollow>(entity.into())
+            .is_ok())
+    }
+
+    pub fn cell_at<'lua>(lua: LuaContext<'lua>, coords: Coords) -> LuaResult<(i32, i32)> {
+        Ok(lua.fetch_one::<NavGrid>()?.borrow().cell_at(coords.point()))
+    }
+
+    pub fn is_blocked<'lua>(lua: LuaContext<'lua>, (x, y): (i32, i32)) -> LuaResult<bool> {
+        Ok(lua.fetch_one::<NavGrid>()?.borrow().is_blocked((x, y)))
+    }
+
+    pub fn set_blocked<'lua>(
+        lua: LuaContext<'lua>,
+        (x, y, blocked): (i32, i32, Option<bool>),
+    ) -> LuaResult<()> {
+        lua.fetch_one::<NavGrid>()?
+            .borrow_mut()
+            .set_blocked((x, y), blocked.unwrap_or(true));
+        Ok(())
+    }
+
+    pub fn load<'lua>(lua: LuaContext<'lua>) -> Result<LuaValue<'lua>> {
+        let table = lua.create_table_from(vec![
+            ("find_path", lua.create_function(find_path)?),
+            ("request_path", lua.create_function(request_path)?),
+            ("follow", lua.create_function(follow)?),
+            ("stop", lua.create_function(stop)?),
+            ("is_following", lua.create_function(is_following)?),
+            ("cell_at", lua.create_function(cell_at)?),
+            ("is_blocked", lua.create_function(is_blocked)?),
+            ("set_blocked", lua.create_function(set_blocked)?),
+        ])?;
+        table.set("PATH_EVENT", PATH_EVENT)?;
+        table.set("ARRIVED_EVENT", ARRIVED_EVENT)?;
+
+        // Everything which needs to wait is defined in Lua.
+        lua.load(include_str!("nav.lua"))
+            .set_name("sludge.nav")?
+            .eval::<LuaFunction>()?
+            .call::<_, ()>(table.clone())?;
+
+        Ok(LuaValue::Table(table))
+    }
+}
+
+inventory::submit! {
+    sludge::api::Module::parse("sludge.nav", api::load)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_go_around_walls_without_cutting_corners() {
+        let mut nav = NavGrid::new(1.);
+        for y in -2..=2 {
+            nav.set_blocked((1, y), true);
+        }
+
+        let cells = nav.find_cells((0, 0), (2, 0)).unwrap();
+        assert_eq!(cells.first(), Some(&(0, 0)));
+        assert_eq!(cells.last(), Some(&(2, 0)));
+        assert!(cells.iter().all(|&cell| !nav.is_blocked(cell)));
+        for pair in cells.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            if a.0 != b.0 && a.1 != b.1 {
+                assert!(!nav.is_blocked((b.0, a.1)) && !nav.is_blocked((a.0, b.1)));
+            }
+        }
+
+        nav.set_blocked((2, 0), true);
+        assert_eq!(nav.find_cells((0, 0), (2, 0)), None);
+    }
+}
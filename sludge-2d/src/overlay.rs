@@ -0,0 +1,71 @@
+//! The panel every debug overlay draws itself into.
+//!
+//! Each overlay ([`InputOverlay`], [`ProfileOverlay`], [`LogOverlay`]) boils whatever it
+//! shows down to rows of text, and hands them to its [`OverlayPanel`], which draws them
+//! one per line in a [`Ui`] panel sized to fit. The panel also decides whether the
+//! overlay is drawn at all and where, so that every overlay can be toggled and moved the
+//! same way.
+//!
+//! [`InputOverlay`]: crate::input_overlay::InputOverlay
+//! [`ProfileOverlay`]: crate::profile_overlay::ProfileOverlay
+//! [`LogOverlay`]: crate::log_overlay::LogOverlay
+
+use sludge::prelude::*;
+
+use crate::ui::Ui;
+
+/// Space left between the edges of an overlay panel and its contents.
+pub const OVERLAY_PADDING: f32 = 8.;
+
+/// Vertical space taken up by a single row of an overlay.
+pub const ROW_HEIGHT: f32 = 20.;
+
+/// Whether and where an overlay is drawn.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OverlayPanel {
+    pub enabled: bool,
+    /// The top-left corner of the panel.
+    pub position: Point2<f32>,
+    pub width: f32,
+}
+
+impl OverlayPanel {
+    /// An enabled panel in the top-left corner of the screen.
+    pub fn new(width: f32) -> Self {
+        Self {
+            enabled: true,
+            position: Point2::new(8., 8.),
+            width,
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        self.enabled = !self.enabled;
+    }
+
+    /// How tall the panel is with `rows` rows in it.
+    pub fn height(rows: usize) -> f32 {
+        rows as f32 * ROW_HEIGHT + OVERLAY_PADDING * 2.
+    }
+
+    /// Declare a panel listing `rows`, one per line. Does nothing if the panel is
+    /// disabled.
+    pub fn draw_rows<S: AsRef<str>>(&self, rows: &[S], ui: &mut Ui) {
+        if !self.enabled {
+            return;
+        }
+
+        ui.panel(Box2::new(
+            self.position.x,
+            self.position.y,
+            self.width,
+            Self::height(rows.len()),
+        ));
+
+        let mut cursor = self.position + Vector2::repeat(OVERLAY_PADDING);
+        for row in rows {
+            ui.label(cursor, row.as_ref());
+            cursor.y += ROW_HEIGHT;
+        }
+    }
+}
@@ -0,0 +1,608 @@
+//! Particle effects, for sparks, smoke, explosions and the like.
+//!
+//! Particles are emitted by entities with a [`ParticleEmitter`] component, at the
+//! entity's [`Position`] and rotated with it, but once emitted they live on their own in
+//! the [`Particles`] resource rather than as entities: moving an emitter doesn't drag its
+//! particles along with it. Particles are simulated by the [`ParticleEmitterSystem`] and
+//! drawn with [`Particles::draw`], which renders every particle sharing a texture with a
+//! single sprite batch.
+//!
+//! How an emitter behaves is described by a [`ParticlePreset`], which can be loaded as an
+//! asset from a RON file or written inline from Lua:
+//!
+//! ```lua
+//! sludge.spawn {
+//!     Position = { x = 100, y = 100 },
+//!     ParticleEmitter = "/particles/smoke.ron",
+//! }
+//!
+//! sludge.spawn {
+//!     Position = { x = 100, y = 100 },
+//!     ParticleEmitter = {
+//!         texture = "/particles/spark.png",
+//!         rate = 30,
+//!         lifetime = { 0.5, 1.0 },
+//!         speed = { 40, 80 },
+//!         spread = math.pi / 8,
+//!         color = { { 0, { r = 1, g = 0.8, b = 0.2, a = 1 } }, { 1, { r = 1, g = 0, b = 0, a = 0 } } },
+//!     },
+//! }
+//!
+//! -- One-off bursts don't need an emitter at all.
+//! sludge.particles.burst(x, y, "/particles/explosion.ron", 64)
+//! ```
+//!
+//! Random values are drawn from the `"particles"` stream of the space's
+//! [`RngResource`], so particles never disturb gameplay randomness.
+
+use {
+    hashbrown::HashMap,
+    rand::Rng,
+    serde::{Deserialize, Serialize},
+    sludge::{
+        api::{LuaComponent, LuaComponentInterface},
+        assets::{Asset, Cache, Cached, DefaultCache, Key, Loaded},
+        ecs::*,
+        filesystem::Filesystem,
+        graphics::{Color, Graphics, InstanceParam, SpriteBatch, Texture},
+        pause,
+        prelude::*,
+        rng::RngResource,
+        shutdown::{Finalizer, ShutdownStage},
+        timestep,
+    },
+    std::{io::Read, sync::Arc},
+};
+
+use crate::Position;
+
+/// The name of the random number stream particles draw from.
+const PARTICLE_RNG_STREAM: &str = "particles";
+
+/// Values which can be interpolated along a [`Curve`].
+pub trait Lerp: Copy {
+    fn lerp(self, other: Self, t: f32) -> Self;
+}
+
+impl Lerp for f32 {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        self + (other - self) * t
+    }
+}
+
+impl Lerp for Color {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        Color::new(
+            Lerp::lerp(self.r, other.r, t),
+            Lerp::lerp(self.g, other.g, t),
+            Lerp::lerp(self.b, other.b, t),
+            Lerp::lerp(self.a, other.a, t),
+        )
+    }
+}
+
+/// A value which changes over a particle's lifetime, as a list of `(t, value)` keyframes
+/// with `t` running from 0 at birth to 1 at death. Values between keyframes are linearly
+/// interpolated, and values before the first or after the last keyframe are held.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Curve<T> {
+    keys: Vec<(f32, T)>,
+}
+
+impl<T: Lerp> Curve<T> {
+    /// Create a curve from keyframes, which are sorted by `t`.
+    pub fn new(mut keys: Vec<(f32, T)>) -> Self {
+        keys.sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        Self { keys }
+    }
+
+    pub fn constant(value: T) -> Self {
+        Self {
+            keys: vec![(0., value)],
+        }
+    }
+
+    /// Sample the curve, or return `default` if it has no keyframes.
+    pub fn sample_or(&self, t: f32, default: T) -> T {
+        let i = self.keys.iter().position(|&(key_t, _)| key_t > t);
+        match i {
+            None => self.keys.last().map_or(default, |&(_, v)| v),
+            Some(0) => self.keys[0].1,
+            Some(i) => {
+                let (t0, v0) = self.keys[i - 1];
+                let (t1, v1) = self.keys[i];
+                v0.lerp(v1, (t - t0) / (t1 - t0))
+            }
+        }
+    }
+}
+
+impl<T> Default for Curve<T> {
+    fn default() -> Self {
+        Self { keys: Vec::new() }
+    }
+}
+
+/// A description of how an emitter spawns particles and how they behave.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ParticlePreset {
+    /// Path to the texture every particle is drawn with.
+    pub texture: String,
+    /// Particles emitted per second.
+    pub rate: f32,
+    /// The range a particle's lifetime is picked from, in seconds.
+    pub lifetime: [f32; 2],
+    /// The range a particle's initial speed is picked from.
+    pub speed: [f32; 2],
+    /// The range a particle's rotational speed is picked from, in radians per second.
+    pub spin: [f32; 2],
+    /// The direction particles are emitted in, in radians, relative to the emitter's
+    /// rotation.
+    pub direction: f32,
+    /// Half the angle of the cone particles are emitted in, in radians.
+    pub spread: f32,
+    /// A constant acceleration applied to every particle, such as gravity.
+    pub acceleration: [f32; 2],
+    /// Exponential decay of particle velocity; see [`Damping`](crate::kinematics::Damping).
+    pub damping: f32,
+    /// Particle color over its lifetime. Defaults to white.
+    pub color: Curve<Color>,
+    /// Particle scale over its lifetime, relative to the texture's size. Defaults to 1.
+    pub size: Curve<f32>,
+}
+
+impl Default for ParticlePreset {
+    fn default() -> Self {
+        Self {
+            texture: String::new(),
+            rate: 10.,
+            lifetime: [1., 1.],
+            speed: [0., 0.],
+            spin: [0., 0.],
+            direction: 0.,
+            spread: std::f32::consts::PI,
+            acceleration: [0., 0.],
+            damping: 0.,
+            color: Curve::default(),
+            size: Curve::default(),
+        }
+    }
+}
+
+impl Asset for ParticlePreset {
+    fn load<'a, R: Resources<'a>>(
+        key: &Key,
+        _cache: &Cache<'a, R>,
+        resources: &R,
+    ) -> Result<Loaded<Self>> {
+        let path = key.to_path()?;
+        let mut fh = resources
+            .fetch_one::<Filesystem>()?
+            .borrow_mut()
+            .open(&path)?;
+        let mut buf = String::new();
+        fh.read_to_string(&mut buf)?;
+        Ok(ron::de::from_str::<ParticlePreset>(&buf)?.into())
+    }
+}
+
+fn load_preset(cache: &DefaultCache, path: &str) -> Result<Arc<ParticlePreset>> {
+    let cached = cache.get::<ParticlePreset>(&Key::path(path))?;
+    let preset = (*cached.load()).clone();
+    Ok(Arc::new(preset))
+}
+
+/// Read a preset from Lua, either as a path to a preset asset or as an inline table.
+fn preset_from_lua<'lua>(
+    lua: LuaContext<'lua>,
+    value: LuaValue<'lua>,
+) -> LuaResult<Arc<ParticlePreset>> {
+    match value {
+        LuaValue::String(path) => {
+            load_preset(&lua.fetch_one::<DefaultCache>()?.borrow(), path.to_str()?).to_lua_err()
+        }
+        LuaValue::Table(table) => match table.get::<_, Option<LuaString>>("preset")? {
+            Some(path) => {
+                load_preset(&lua.fetch_one::<DefaultCache>()?.borrow(), path.to_str()?).to_lua_err()
+            }
+            None => Ok(Arc::new(rlua_serde::from_value(LuaValue::Table(table))?)),
+        },
+        other => Err(anyhow!(
+            "expected a particle preset path or table, found {}",
+            other.type_name()
+        ))
+        .to_lua_err(),
+    }
+}
+
+/// Emits particles at its entity's [`Position`].
+#[derive(Debug, Clone)]
+pub struct ParticleEmitter {
+    pub preset: Arc<ParticlePreset>,
+    pub enabled: bool,
+    accumulator: f32,
+    bursts: u32,
+}
+
+impl<'a> SmartComponent<ScContext<'a>> for ParticleEmitter {}
+
+inventory::submit! {
+    CloneComponent::of::<ParticleEmitter>()
+}
+
+impl ParticleEmitter {
+    pub fn new(preset: impl Into<Arc<ParticlePreset>>) -> Self {
+        Self {
+            preset: preset.into(),
+            enabled: true,
+            accumulator: 0.,
+            bursts: 0,
+        }
+    }
+
+    /// Emit `count` particles on the next update, in addition to the emitter's usual
+    /// rate. Disabled emitters still emit bursts.
+    pub fn burst(&mut self, count: u32) {
+        self.bursts += count;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ParticleEmitterAccessor(Entity);
+
+impl LuaUserData for ParticleEmitterAccessor {
+    fn add_methods<'lua, T: LuaUserDataMethods<'lua, Self>>(methods: &mut T) {
+        methods.add_method("burst", |lua, this, count: u32| {
+            let world = lua.fetch_one::<World>()?;
+            world
+                .borrow()
+                .get_mut::<ParticleEmitter>(this.0)
+                .to_lua_err()?
+                .burst(count);
+            Ok(())
+        });
+
+        methods.add_method("set_enabled", |lua, this, enabled: bool| {
+            let world = lua.fetch_one::<World>()?;
+            world
+                .borrow()
+                .get_mut::<ParticleEmitter>(this.0)
+                .to_lua_err()?
+                .enabled = enabled;
+            Ok(())
+        });
+
+        methods.add_method("is_enabled", |lua, this, ()| {
+            let world = lua.fetch_one::<World>()?;
+            let enabled = world
+                .borrow()
+                .get::<ParticleEmitter>(this.0)
+                .to_lua_err()?
+                .enabled;
+            Ok(enabled)
+        });
+
+        methods.add_method("to_table", |lua, this, ()| {
+            let world = lua.fetch_one::<World>()?;
+            let world = world.borrow();
+            let emitter = world.get::<ParticleEmitter>(this.0).to_lua_err()?;
+            let table = LuaTable::from_lua(rlua_serde::to_value(lua, &*emitter.preset)?, lua)?;
+            table.set("enabled", emitter.enabled)?;
+            Ok(table)
+        });
+    }
+}
+
+impl LuaComponentInterface for ParticleEmitter {
+    fn accessor<'lua>(lua: LuaContext<'lua>, entity: Entity) -> LuaResult<LuaValue<'lua>> {
+        ParticleEmitterAccessor(entity).to_lua(lua)
+    }
+
+    fn bundler<'lua>(
+        lua: LuaContext<'lua>,
+        args: LuaValue<'lua>,
+        builder: &mut EntityBuilder,
+    ) -> LuaResult<()> {
+        let enabled = match &args {
+            LuaValue::Table(table) => table.get::<_, Option<bool>>("enabled")?.unwrap_or(true),
+            _ => true,
+        };
+
+        let mut emitter = ParticleEmitter::new(preset_from_lua(lua, args)?);
+        emitter.enabled = enabled;
+        builder.add(emitter);
+        Ok(())
+    }
+}
+
+inventory::submit! {
+    LuaComponent::new::<ParticleEmitter>("ParticleEmitter")
+}
+
+/// Pick a value uniformly from a `[min, max]` range.
+fn range(rng: &mut impl Rng, [min, max]: [f32; 2]) -> f32 {
+    if max > min {
+        rng.gen_range(min, max)
+    } else {
+        min
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Particle {
+    preset: Arc<ParticlePreset>,
+    position: Point2<f32>,
+    velocity: Vector2<f32>,
+    rotation: f32,
+    spin: f32,
+    age: f32,
+    lifetime: f32,
+}
+
+impl Particle {
+    fn spawn(preset: &Arc<ParticlePreset>, at: &Isometry2<f32>, rng: &mut impl Rng) -> Self {
+        let angle =
+            at.rotation.angle() + preset.direction + range(rng, [-preset.spread, preset.spread]);
+        let speed = range(rng, preset.speed);
+
+        Self {
+            preset: preset.clone(),
+            position: Point2::from(at.translation.vector),
+            velocity: Vector2::new(angle.cos(), angle.sin()) * speed,
+            rotation: angle,
+            spin: range(rng, preset.spin),
+            age: 0.,
+            lifetime: range(rng, preset.lifetime),
+        }
+    }
+
+    /// Advance the particle by `dt` seconds, returning `false` once it's dead.
+    fn update(&mut self, dt: f32) -> bool {
+        self.age += dt;
+        if self.age >= self.lifetime {
+            return false;
+        }
+
+        let [ax, ay] = self.preset.acceleration;
+        self.velocity += Vector2::new(ax, ay) * dt;
+        self.velocity *= (-self.preset.damping * dt).exp();
+        self.position += self.velocity * dt;
+        self.rotation += self.spin * dt;
+        true
+    }
+
+    fn param(&self, texture_size: Vector2<f32>) -> InstanceParam {
+        let t = if self.lifetime > 0. {
+            self.age / self.lifetime
+        } else {
+            1.
+        };
+
+        InstanceParam::new()
+            .color(self.preset.color.sample_or(t, Color::WHITE))
+            .translate2(self.position.coords)
+            .rotate2(self.rotation)
+            .scale2(Vector2::repeat(self.preset.size.sample_or(t, 1.)))
+            .translate2(-texture_size / 2.)
+    }
+}
+
+/// Every live particle sharing a texture.
+#[derive(Debug)]
+struct ParticlePool {
+    texture: Cached<Texture>,
+    particles: Vec<Particle>,
+    batch: Option<SpriteBatch>,
+}
+
+/// Every live particle, grouped by texture. Dead particles are removed in place, so each
+/// pool's storage is reused rather than reallocated as particles come and go.
+#[derive(Debug, Default)]
+pub struct Particles {
+    pools: HashMap<String, ParticlePool>,
+}
+
+impl Particles {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of live particles.
+    pub fn len(&self) -> usize {
+        self.pools.values().map(|pool| pool.particles.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove every live particle.
+    pub fn clear(&mut self) {
+        for pool in self.pools.values_mut() {
+            pool.particles.clear();
+        }
+    }
+
+    fn pool(&mut self, cache: &DefaultCache, texture: &str) -> Result<&mut ParticlePool> {
+        if !self.pools.contains_key(texture) {
+            let pool = ParticlePool {
+                texture: cache.get::<Texture>(&Key::path(texture))?,
+                particles: Vec::new(),
+                batch: None,
+            };
+            self.pools.insert(texture.to_owned(), pool);
+        }
+
+        Ok(self.pools.get_mut(texture).unwrap())
+    }
+
+    /// Emit `count` particles from a preset at once.
+    pub fn burst(
+        &mut self,
+        cache: &DefaultCache,
+        rng: &mut RngResource,
+        preset: &Arc<ParticlePreset>,
+        at: &Isometry2<f32>,
+        count: u32,
+    ) -> Result<()> {
+        let rng = rng.stream(PARTICLE_RNG_STREAM);
+        let pool = self.pool(cache, &preset.texture)?;
+        pool.particles
+            .extend((0..count).map(|_| Particle::spawn(preset, at, rng)));
+        Ok(())
+    }
+
+    /// Advance every live particle by `dt` seconds.
+    pub fn update(&mut self, dt: f32) {
+        for pool in self.pools.values_mut() {
+            let mut i = 0;
+            while i < pool.particles.len() {
+                if pool.particles[i].update(dt) {
+                    i += 1;
+                } else {
+                    pool.particles.swap_remove(i);
+                }
+            }
+        }
+    }
+
+    /// Draw every live particle, with one sprite batch per texture.
+    pub fn draw(&mut self, gfx: &mut Graphics) {
+        for pool in self.pools.values_mut() {
+            if pool.particles.is_empty() {
+                continue;
+            }
+
+            let texture = pool.texture.load();
+            let texture_size = Vector2::new(texture.width() as f32, texture.height() as f32);
+            if pool.batch.is_none() {
+                pool.batch = Some(SpriteBatch::with_capacity(
+                    gfx,
+                    pool.texture.clone(),
+                    pool.particles.len(),
+                ));
+            }
+
+            let batch = pool.batch.as_mut().unwrap();
+
+            batch.clear();
+            for particle in &pool.particles {
+                batch.insert(particle.param(texture_size));
+            }
+
+            gfx.draw(&*batch, None);
+        }
+    }
+}
+
+/// Emits particles from every [`ParticleEmitter`] and simulates the [`Particles`]
+/// resource, once per fixed step. Does nothing while the gameplay channel is
+/// [paused](sludge::pause).
+pub struct ParticleEmitterSystem;
+
+impl System for ParticleEmitterSystem {
+    fn init(
+        &self,
+        _lua: LuaContext,
+        _local: &mut OwnedResources,
+        _global: Option<&SharedResources>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn update(&self, _lua: LuaContext, resources: &UnifiedResources) -> Result<()> {
+        if pause::is_paused(resources, pause::GAMEPLAY) {
+            return Ok(());
+        }
+
+        let (world, particles, rng, cache) =
+            resources.fetch::<(World, Particles, RngResource, DefaultCache)>()?;
+        let world = world.borrow();
+        let mut particles = particles.borrow_mut();
+        let mut rng = rng.borrow_mut();
+        let cache = cache.borrow();
+        let dt = timestep::fixed_dt(resources);
+
+        particles.update(dt);
+
+        for (_, (position, mut emitter)) in world
+            .query_enabled::<(&Position, &mut ParticleEmitter)>()
+            .iter()
+        {
+            let mut count = std::mem::take(&mut emitter.bursts);
+            if emitter.enabled {
+                emitter.accumulator += emitter.preset.rate * dt;
+                let whole = emitter.accumulator.floor();
+                emitter.accumulator -= whole;
+                count += whole as u32;
+            }
+
+            if count > 0 {
+                particles.burst(&cache, &mut rng, &emitter.preset, &position.0, count)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+inventory::submit! {
+    sludge::api::Module::parse("sludge.particles", |lua| {
+        let table = lua.create_table()?;
+
+        table.set(
+            "burst",
+            lua.create_function(|lua, (x, y, preset, count): (f32, f32, LuaValue, u32)| {
+                let preset = preset_from_lua(lua, preset)?;
+                let (particles, rng, cache) =
+                    lua.fetch::<(Particles, RngResource, DefaultCache)>()?;
+                particles
+                    .borrow_mut()
+                    .burst(
+                        &cache.borrow(),
+                        &mut rng.borrow_mut(),
+                        &preset,
+                        &Isometry2::translation(x, y),
+                        count,
+                    )
+                    .to_lua_err()
+            })?,
+        )?;
+
+        table.set(
+            "clear",
+            lua.create_function(|lua, ()| {
+                lua.fetch_one::<Particles>()?.borrow_mut().clear();
+                Ok(())
+            })?,
+        )?;
+
+        Ok(LuaValue::Table(table))
+    })
+}
+
+// Particle pools hold onto sprite batches and textures.
+inventory::submit! {
+    Finalizer::new("sludge_2d.particles", ShutdownStage::QueueGpu, |_lua, resources| {
+        if let Ok(particles) = resources.local.fetch_one::<Particles>() {
+            *particles.borrow_mut() = Particles::new();
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curve_interpolates_and_holds() {
+        let curve = Curve::new(vec![(1., 0.), (0.5, 4.)]);
+        assert_eq!(curve.sample_or(0., -1.), 4.);
+        assert_eq!(curve.sample_or(0.75, -1.), 2.);
+        assert_eq!(curve.sample_or(2., -1.), 0.);
+        assert_eq!(Curve::<f32>::default().sample_or(0.5, -1.), -1.);
+    }
+}
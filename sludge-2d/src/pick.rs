@@ -0,0 +1,135 @@
+//! Finding the entities under a point, for clicking on things.
+//!
+//! [`pick`] finds every entity under a point in world space. Entities with a [`Shape`]
+//! are hit if the point is inside the shape, and entities with a [`SpriteAnimation`]
+//! are hit if the point is inside the sprite's current frame. Either way the entity
+//! needs a [`Position`]. Hits are sorted by their [`Layer`], topmost first; entities
+//! without a `Layer` are on layer zero. [`Disabled`] entities are never picked.
+//!
+//! From Lua, `sludge.pick(x, y)` takes a point on the screen, converts it into world
+//! space with the space's [`Camera2d`] if it has one, and returns a list of the entities
+//! under it:
+//!
+//! ```lua
+//! local hits = sludge.pick(mouse_x, mouse_y)
+//! if hits[1] then
+//!     select(hits[1])
+//! end
+//! ```
+
+use {
+    sludge::{api::math::Coords, ecs::*, prelude::*, sprite::SpriteAnimation},
+    std::cmp::{Ordering, Reverse},
+};
+
+pub use sludge::layers::Layer;
+
+use crate::{camera::Camera2d, query::PointQuery, spatial_hash::SpatialHasher, Position, Shape};
+
+fn shape_contains(position: &Position, shape: &Shape, point: &Point2<f32>) -> bool {
+    shape.handle.as_point_query().map_or(false, |q| {
+        q.contains_point(&(position.0 * shape.local), point)
+    })
+}
+
+fn sprite_contains(position: &Position, animation: &SpriteAnimation, point: &Point2<f32>) -> bool {
+    let frame = animation.current();
+    let extents = frame.frame.extents();
+    let bounds = Box2::from_extents(
+        Point2::from(frame.offset),
+        Vector2::new(extents.x as f32, extents.y as f32),
+    );
+    let local = position.0.inverse_transform_point(point);
+
+    local.x >= bounds.mins.x
+        && local.y >= bounds.mins.y
+        && local.x <= bounds.maxs.x
+        && local.y <= bounds.maxs.y
+}
+
+/// Find every entity under a point in world space, topmost first. If a
+/// [`SpatialHasher`] is given, entities with a [`Shape`] are looked up through it
+/// rather than by checking every shape in the world. See the
+/// [module documentation](self) for details.
+pub fn pick(world: &World, hasher: Option<&SpatialHasher>, point: Point2<f32>) -> Vec<Entity> {
+    let mut hits = Vec::new();
+
+    match hasher {
+        Some(hasher) => {
+            for entity in hasher.entities_at(point) {
+                if !world.is_enabled(entity) {
+                    continue;
+                }
+
+                let mut query = match world.query_one::<(&Position, &Shape)>(entity) {
+                    Ok(query) => query,
+                    Err(_) => continue,
+                };
+
+                if let Some((position, shape)) = query.get() {
+                    if shape_contains(position, shape, &point) {
+                        hits.push(entity);
+                    }
+                }
+            }
+        }
+        None => {
+            for (entity, (position, shape)) in world.query_enabled::<(&Position, &Shape)>().iter() {
+                if shape_contains(position, shape, &point) {
+                    hits.push(entity);
+                }
+            }
+        }
+    }
+
+    for (entity, (position, animation)) in world
+        .query_enabled::<(&Position, &SpriteAnimation)>()
+        .without::<Shape>()
+        .iter()
+    {
+        if sprite_contains(position, animation, &point) {
+            hits.push(entity);
+        }
+    }
+
+    // Ties between layers go to whichever entity's position is closest to the point.
+    let mut keyed = hits
+        .into_iter()
+        .map(|entity| {
+            let layer = world.get::<Layer>(entity).map(|l| *l).unwrap_or_default();
+            let distance = world
+                .get::<Position>(entity)
+                .map(|p| (p.0.translation.vector - point.coords).norm())
+                .unwrap_or(f32::INFINITY);
+            (Reverse(layer), distance, entity)
+        })
+        .collect::<Vec<_>>();
+
+    keyed.sort_by(|a, b| {
+        a.0.cmp(&b.0)
+            .then_with(|| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal))
+    });
+
+    keyed.into_iter().map(|(_, _, entity)| entity).collect()
+}
+
+fn pick_screen<'lua>(lua: LuaContext<'lua>, coords: Coords) -> LuaResult<Vec<LuaEntity>> {
+    let point = match lua.fetch_one::<Camera2d>() {
+        Ok(camera) => camera.borrow().pick(coords.point()),
+        Err(_) => coords.point(),
+    };
+
+    let world = lua.fetch_one::<World>()?;
+    let hits = match lua.fetch_one::<SpatialHasher>() {
+        Ok(hasher) => pick(&world.borrow(), Some(&hasher.borrow()), point),
+        Err(_) => pick(&world.borrow(), None, point),
+    };
+
+    Ok(hits.into_iter().map(LuaEntity::from).collect())
+}
+
+inventory::submit! {
+    sludge::api::Module::parse("sludge.pick", |lua| {
+        Ok(LuaValue::Function(lua.create_function(pick_screen)?))
+    })
+}
@@ -0,0 +1,157 @@
+//! An on-screen display of where the time in a frame is going, for finding out why a
+//! frame is slow.
+//!
+//! The [`ProfileOverlay`] draws a panel with a [`Ui`] listing the [`GfxStats`] of the
+//! last committed frame, then how full the scheduler's event channel is and what the
+//! garbage collector is up to, and finally the slowest threads in the scheduler's
+//! [`SchedulerStats`].
+//!
+//! Draw calls and pipeline switches are the graphics numbers to watch: each one costs
+//! far more than drawing another instance in a batch, so a count which grows with the
+//! number of things on screen means something isn't being batched. Threads are only
+//! timed while profiling is turned on; see [`sludge::profile`].
+//!
+//! Given a [`MemoryReport`], the overlay adds a row totalling how much memory the world
+//! is using, for catching leaks as they happen. Building a report walks every entity in
+//! the world, so it's best to refresh it every second or so rather than every frame.
+//!
+//! Other subsystems report to the overlay with rows of their own, drawn before the
+//! thread list; for instance, sludge-fmod's one-shot pool counts, formatted from its
+//! `Fmod::total_pool_stats`.
+
+use sludge::{graphics::GfxStats, memory::MemoryReport, SchedulerStats};
+
+use crate::{overlay::OverlayPanel, ui::Ui};
+
+/// Draws a frame's [`GfxStats`] and [`SchedulerStats`], and optionally a summary of a
+/// [`MemoryReport`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProfileOverlay {
+    pub panel: OverlayPanel,
+}
+
+impl Default for ProfileOverlay {
+    fn default() -> Self {
+        Self {
+            panel: OverlayPanel::new(400.),
+        }
+    }
+}
+
+impl ProfileOverlay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declare the overlay's panel.
+    ///
+    /// The graphics stats usually come from the space's [`GfxStats`] resource, and so
+    /// describe the frame before the one the overlay is drawn in; the overlay's own
+    /// draws show up a frame late.
+    ///
+    /// Times are totals since profiling was turned on or last reset, so a thread which
+    /// runs every tick will climb steadily; the longest single resume is shown
+    /// alongside, for spotting scripts which stall a frame now and then. `extra` rows are
+    /// drawn as they are, after the garbage collector's.
+    pub fn draw(
+        &self,
+        gfx: &GfxStats,
+        scheduler: &SchedulerStats,
+        memory: Option<&MemoryReport>,
+        extra: &[String],
+        ui: &mut Ui,
+    ) {
+        if self.panel.enabled {
+            let rows = Self::rows(gfx, scheduler, memory, extra);
+            self.panel.draw_rows(&rows, ui);
+        }
+    }
+
+    fn rows(
+        gfx: &GfxStats,
+        scheduler: &SchedulerStats,
+        memory: Option<&MemoryReport>,
+        extra: &[String],
+    ) -> Vec<String> {
+        let mut rows = vec![
+            format!("draw calls: {}", gfx.draw_calls),
+            format!("instances: {}", gfx.instances),
+            format!("buffer uploads: {}", gfx.buffer_uploads),
+            format!("pipeline switches: {}", gfx.pipeline_switches),
+            format!("passes: {}", gfx.passes),
+        ];
+
+        if let Some(report) = memory {
+            rows.push(format!(
+                "memory: {} entities, {} KiB components, {} KiB resources",
+                report.entities,
+                report.component_bytes() / 1024,
+                report.resource_bytes() / 1024,
+            ));
+        }
+
+        rows.push(format!(
+            "events: {}/{} (peak {})",
+            scheduler.events.depth, scheduler.events.capacity, scheduler.events.high_water
+        ));
+
+        rows.push(format!(
+            "lua heap: {} KiB, {} gc/s, {} steps in {:.2}ms",
+            scheduler.gc.heap / 1024,
+            scheduler.gc.cycles_per_second,
+            scheduler.gc.steps,
+            scheduler.gc.time.as_secs_f64() * 1000.,
+        ));
+
+        rows.extend(extra.iter().cloned());
+
+        if scheduler.scripts.is_empty() {
+            rows.push("no threads profiled".to_owned());
+        }
+
+        for script in &scheduler.scripts {
+            rows.push(format!(
+                "{}: {:.2}ms, max {:.2}ms, {} KiB",
+                script.name,
+                script.time.as_secs_f64() * 1000.,
+                script.max_time.as_secs_f64() * 1000.,
+                script.memory / 1024,
+            ));
+        }
+
+        rows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn graphics_stats_lead_and_extra_rows_come_before_threads() {
+        let gfx = GfxStats {
+            draw_calls: 3,
+            ..GfxStats::default()
+        };
+        let memory = MemoryReport {
+            entities: 12,
+            ..MemoryReport::default()
+        };
+        let audio = vec!["one-shots: 2 playing".to_owned()];
+
+        let rows = ProfileOverlay::rows(&gfx, &SchedulerStats::default(), Some(&memory), &audio);
+        assert_eq!(rows[0], "draw calls: 3");
+        assert_eq!(
+            rows[5],
+            "memory: 12 entities, 0 KiB components, 0 KiB resources"
+        );
+        assert!(rows[6].starts_with("events: "));
+        assert!(rows[7].starts_with("lua heap: "));
+        assert_eq!(rows[8], audio[0]);
+        assert_eq!(rows[9], "no threads profiled");
+        assert_eq!(rows.len(), 10);
+
+        let rows = ProfileOverlay::rows(&gfx, &SchedulerStats::default(), None, &[]);
+        assert_eq!(rows.len(), 8);
+    }
+}
@@ -0,0 +1,176 @@
+//! Drawing [`Shape`]s, for prototyping before there's any art and for seeing where
+//! collision shapes really are.
+//!
+//! [`Shape::to_mesh`] tessellates a shape into a [`MeshBuilder`], either filled or as an
+//! outline. The [`ShapeRenderSystem`] does that for every enabled entity with both a
+//! [`Position`] and a [`Shape`], every frame:
+//!
+//! ```ignore
+//! let shapes = ShapeRenderSystem::new(&mut gfx, DrawMode::stroke(1.), Color::GREEN);
+//!
+//! // Every frame, with the camera's transform pushed:
+//! shapes.draw(&mut gfx, &world.borrow())?;
+//! ```
+//!
+//! Meshes are rebuilt from scratch on every draw, which is plenty fast for a few hundred
+//! shapes but isn't meant to stand in for real rendering.
+
+use sludge::{
+    assets::Cached,
+    ecs::World,
+    graphics::{Color, DrawMode, Graphics, MeshBuilder, Texture},
+    prelude::*,
+};
+
+use crate::{Ball, Capsule, Compound, ConvexPolygon, Cuboid, Position, Shape, ShapeHandle};
+
+/// How closely curves follow the true circle, in the same units as the shapes. See
+/// [`MeshBuilder::circle`].
+const CIRCLE_TOLERANCE: f32 = 0.1;
+
+/// How many segments each rounded end of a capsule is split into.
+const CAPSULE_SEGMENTS: usize = 16;
+
+/// Once a mesh has this many vertices it's drawn and a new one started, so that the
+/// 16-bit indices of a single mesh never overflow.
+const MAX_BATCH_VERTICES: usize = 32768;
+
+impl Shape {
+    /// Tessellate the shape into `builder`, placed at the shape's local isometry.
+    /// Compound shapes add each of their children.
+    pub fn to_mesh(&self, builder: &mut MeshBuilder, mode: DrawMode, color: Color) -> Result<()> {
+        add_shape(builder, &self.local, &self.handle, mode, color)
+    }
+}
+
+fn add_shape(
+    builder: &mut MeshBuilder,
+    iso: &Isometry2<f32>,
+    handle: &ShapeHandle<f32>,
+    mode: DrawMode,
+    color: Color,
+) -> Result<()> {
+    if let Some(cuboid) = handle.as_shape::<Cuboid<f32>>() {
+        let h = cuboid.half_extents;
+        let corners = [
+            Point2::new(-h.x, -h.y),
+            Point2::new(h.x, -h.y),
+            Point2::new(h.x, h.y),
+            Point2::new(-h.x, h.y),
+        ];
+        let points = corners.iter().map(|p| iso * p).collect::<Vec<_>>();
+        builder.polygon(mode, &points, color)?;
+    } else if let Some(ball) = handle.as_shape::<Ball<f32>>() {
+        let center = iso * Point2::origin();
+        builder.circle(mode, center, ball.radius, CIRCLE_TOLERANCE, color);
+    } else if let Some(polygon) = handle.as_shape::<ConvexPolygon<f32>>() {
+        let points = polygon.points().iter().map(|p| iso * p).collect::<Vec<_>>();
+        builder.polygon(mode, &points, color)?;
+    } else if let Some(capsule) = handle.as_shape::<Capsule<f32>>() {
+        let points = capsule_outline(capsule.half_height, capsule.radius)
+            .iter()
+            .map(|p| iso * p)
+            .collect::<Vec<_>>();
+        builder.polygon(mode, &points, color)?;
+    } else if let Some(compound) = handle.as_shape::<Compound<f32>>() {
+        for (child_local, child) in compound.shapes() {
+            add_shape(builder, &(iso * child_local), child, mode, color)?;
+        }
+    } else {
+        bail!("unsupported shape");
+    }
+
+    Ok(())
+}
+
+/// The outline of a capsule aligned with the Y axis: the top end from right to left,
+/// then the bottom end from left to right.
+fn capsule_outline(half_height: f32, radius: f32) -> Vec<Point2<f32>> {
+    let end = |center_y: f32, start_angle: f32| {
+        (0..=CAPSULE_SEGMENTS).map(move |i| {
+            let angle = start_angle + std::f32::consts::PI * i as f32 / CAPSULE_SEGMENTS as f32;
+            Point2::new(radius * angle.cos(), center_y + radius * angle.sin())
+        })
+    };
+
+    end(half_height, 0.)
+        .chain(end(-half_height, std::f32::consts::PI))
+        .collect()
+}
+
+/// Draws the [`Shape`] of every enabled entity with a [`Position`]. See the [module
+/// documentation](self) for details.
+#[derive(Debug)]
+pub struct ShapeRenderSystem {
+    /// Whether shapes are filled or drawn as outlines.
+    pub mode: DrawMode,
+    pub color: Color,
+    /// A single white pixel, since meshes need a texture.
+    white: Cached<Texture>,
+}
+
+impl ShapeRenderSystem {
+    pub fn new(gfx: &mut Graphics, mode: DrawMode, color: Color) -> Self {
+        Self {
+            mode,
+            color,
+            white: Texture::from_rgba8(gfx, 1, 1, &[255; 4]).into(),
+        }
+    }
+
+    /// Draw filled shapes.
+    pub fn filled(gfx: &mut Graphics, color: Color) -> Self {
+        Self::new(gfx, DrawMode::fill(), color)
+    }
+
+    /// Draw shape outlines `width` units thick.
+    pub fn wireframe(gfx: &mut Graphics, width: f32, color: Color) -> Self {
+        Self::new(gfx, DrawMode::stroke(width), color)
+    }
+
+    /// Draw every shape in the world, offset by its entity's position. The camera's
+    /// transform, if any, should already be on the transform stack.
+    pub fn draw(&self, gfx: &mut Graphics, world: &World) -> Result<()> {
+        let mut builder = MeshBuilder::new(self.white.clone());
+
+        for (_, (position, shape)) in world.query_enabled::<(&Position, &Shape)>().iter() {
+            add_shape(
+                &mut builder,
+                &(position.0 * shape.local),
+                &shape.handle,
+                self.mode,
+                self.color,
+            )?;
+
+            if builder.buffer.vertices.len() >= MAX_BATCH_VERTICES {
+                let mesh = builder.build(gfx);
+                gfx.draw(&mesh, None);
+                builder = MeshBuilder::new(self.white.clone());
+            }
+        }
+
+        if !builder.buffer.vertices.is_empty() {
+            let mesh = builder.build(gfx);
+            gfx.draw(&mesh, None);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capsule_outline_spans_both_ends() {
+        let points = capsule_outline(3., 1.);
+        assert_eq!(points.len(), (CAPSULE_SEGMENTS + 1) * 2);
+
+        let top = points.iter().map(|p| p.y).fold(f32::MIN, f32::max);
+        let bottom = points.iter().map(|p| p.y).fold(f32::MAX, f32::min);
+        assert!((top - 4.).abs() < 1e-5);
+        assert!((bottom + 4.).abs() < 1e-5);
+        assert!(points.iter().all(|p| p.x.abs() <= 1. + 1e-5));
+    }
+}
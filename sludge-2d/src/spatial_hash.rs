@@ -13,6 +13,11 @@ pub struct SpatialIndex(Index);
 
 impl<'a> SmartComponent<ScContext<'a>> for SpatialIndex {}
 
+// The spatial hashing system gives copies their own index.
+inventory::submit! {
+    CloneComponent::skip::<SpatialIndex>()
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct BucketIndex(Index);
 
@@ -214,6 +219,14 @@ impl<T> HashGrid<T> {
         dirty
     }
 
+    /// Remove every object, keeping the buckets around to be filled again.
+    pub fn clear(&mut self) {
+        self.objects.clear();
+        for (_, bucket) in self.buckets.iter_mut() {
+            bucket.members.clear();
+        }
+    }
+
     pub fn buckets(&self) -> impl Iterator<Item = (BucketIndex, &Bucket)> + '_ {
         self.buckets.iter().map(|(i, b)| (BucketIndex(i), b))
     }
@@ -242,10 +255,13 @@ impl<T> HashGrid<T> {
     }
 }
 
+/// Keeps a [`HashGrid`] of every enabled entity with a [`Position`] and a [`Shape`] up
+/// to date. [`Disabled`] entities are taken out of the grid until they're enabled again.
 #[derive(Debug)]
 pub struct SpatialHasher {
     position_events: ComponentSubscriber<Position>,
     shape_events: ComponentSubscriber<Shape>,
+    disabled_events: ComponentSubscriber<Disabled>,
 
     grid: HashGrid<Entity>,
     current_ids: HashMap<Entity, SpatialIndex>,
@@ -259,10 +275,12 @@ impl SpatialHasher {
     pub fn new(bucket_size: f32, world: &mut World) -> Self {
         let position_events = world.track::<Position>();
         let shape_events = world.track::<Shape>();
+        let disabled_events = world.track::<Disabled>();
 
         Self {
             position_events,
             shape_events,
+            disabled_events,
 
             grid: HashGrid::new(bucket_size),
             current_ids: HashMap::new(),
@@ -277,6 +295,28 @@ impl SpatialHasher {
         &self.grid
     }
 
+    /// Find the entities whose bounding boxes contain a point, as of the last update.
+    pub fn entities_at(&self, point: Point2<f32>) -> Vec<Entity> {
+        // A point has no area, so query a tiny box around it to be sure it lands in
+        // the buckets on either side of a bucket boundary.
+        let query = Box2::from_half_extents(point, Vector2::repeat(0.5));
+        let mut entities = self
+            .grid
+            .query(&query)
+            .filter(|&index| {
+                let bounds = self.grid[index].bounds();
+                point.x >= bounds.mins.x
+                    && point.y >= bounds.mins.y
+                    && point.x <= bounds.maxs.x
+                    && point.y <= bounds.maxs.y
+            })
+            .map(|index| *self.grid[index].userdata())
+            .collect::<Vec<_>>();
+        entities.sort_unstable();
+        entities.dedup();
+        entities
+    }
+
     pub fn update<'a, R: Resources<'a>>(&mut self, resources: &R) -> Result<()> {
         self.added.clear();
         self.modified.clear();
@@ -285,6 +325,23 @@ impl SpatialHasher {
         let tmp = resources.fetch_one::<World>()?;
         let world = &*tmp.borrow();
 
+        // Disabling an entity takes it out of the grid and enabling it puts it back.
+        // These go first, since despawning a disabled entity also removes its
+        // `Disabled`, and the removal of its position has to win.
+        for &event in world.poll::<Disabled>(&mut self.disabled_events) {
+            match event {
+                ComponentEvent::Inserted(entity) => {
+                    self.added.remove(&entity);
+                    self.removed.insert(entity);
+                }
+                ComponentEvent::Removed(entity) => {
+                    self.added.insert(entity);
+                    self.removed.remove(&entity);
+                }
+                ComponentEvent::Modified(_) => {}
+            }
+        }
+
         for &event in world.poll::<Position>(&mut self.position_events) {
             match event {
                 ComponentEvent::Inserted(entity) => {
@@ -320,7 +377,14 @@ impl SpatialHasher {
         let mut cmds = world.get_buffer();
 
         for added in self.added.drain() {
-            let mut query = world.query_one::<(&Position, &Shape)>(added).unwrap();
+            if !world.is_enabled(added) || self.current_ids.contains_key(&added) {
+                continue;
+            }
+
+            let mut query = match world.query_one::<(&Position, &Shape)>(added) {
+                Ok(query) => query,
+                Err(_) => continue,
+            };
             if let Some((pos, shape)) = query.get() {
                 let index = self.grid.insert(
                     nc::bounding_volume::aabb(&*shape.handle, &(**pos * shape.local)),
@@ -331,7 +395,10 @@ impl SpatialHasher {
             }
         }
 
-        for (_, (pos, shape, index)) in world.query::<(&Position, &Shape, &SpatialIndex)>().iter() {
+        for (_, (pos, shape, index)) in world
+            .query_enabled::<(&Position, &Shape, &SpatialIndex)>()
+            .iter()
+        {
             self.grid.update(
                 *index,
                 nc::bounding_volume::aabb(&*shape.handle, &(**pos * shape.local)),
@@ -374,7 +441,7 @@ impl System for SpatialHashingSystem {
         let tmp = resources.fetch_one::<SpatialHasher>()?;
         let spatial_hasher = &mut *tmp.borrow_mut();
         let mut added_buf = Vec::new();
-        for (e, (pos, shape)) in world.borrow().query::<(&Position, &Shape)>().iter() {
+        for (e, (pos, shape)) in world.borrow().query_enabled::<(&Position, &Shape)>().iter() {
             let index = spatial_hasher.grid.insert(
                 nc::bounding_volume::aabb(&*shape.handle, &(**pos * shape.local)),
                 e,
@@ -536,4 +603,43 @@ mod tests {
             set_of(vec![c])
         );
     }
+
+    #[test]
+    fn disabled_entities_leave_the_hash() -> Result<()> {
+        use crate::{Ball, ShapeHandle};
+
+        let resources = SharedResources::new();
+        let mut world = World::new();
+        let mut spatial_hasher = SpatialHasher::new(64., &mut world);
+        let entity = world.spawn((
+            Position(Isometry2::translation(10., 10.)),
+            Shape::new(Isometry2::identity(), ShapeHandle::new(Ball::new(4.))),
+        ));
+        resources.borrow_mut().insert(world);
+        let world = resources.fetch_one::<World>()?;
+
+        let point = Point2::new(10., 10.);
+        spatial_hasher.update(&resources)?;
+        world.borrow_mut().flush_queue()?;
+        assert_eq!(spatial_hasher.entities_at(point), vec![entity]);
+
+        world.borrow_mut().insert_one(entity, Disabled)?;
+        spatial_hasher.update(&resources)?;
+        world.borrow_mut().flush_queue()?;
+        assert!(spatial_hasher.entities_at(point).is_empty());
+        assert!(world.borrow().get::<SpatialIndex>(entity).is_err());
+
+        world.borrow_mut().remove_one::<Disabled>(entity)?;
+        spatial_hasher.update(&resources)?;
+        world.borrow_mut().flush_queue()?;
+        assert_eq!(spatial_hasher.entities_at(point), vec![entity]);
+
+        world.borrow_mut().insert_one(entity, Disabled)?;
+        world.borrow_mut().despawn(entity)?;
+        spatial_hasher.update(&resources)?;
+        world.borrow_mut().flush_queue()?;
+        assert!(spatial_hasher.entities_at(point).is_empty());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,211 @@
+//! Synthetic workloads for the engine's hot paths.
+//!
+//! A [`Stress`] space is filled with bullets, entities with a [`Position`], a
+//! [`Shape`] and a [`Velocity`], some of which also have an [`Acceleration`] or
+//! [`Damping`] and a [`MaxSpeed`], along with Lua threads which sleep, broadcast events
+//! and wait on them. Each of the hot paths can then be stepped on its own:
+//! [`Stress::update_scheduler`], [`Stress::update_kinematics`] and
+//! [`Stress::update_spatial_hash`].
+//!
+//! This is shared by the criterion benchmarks in `benches/stress.rs` and the `stress`
+//! example, and only exists with the `bench` feature. Nothing here needs a graphics
+//! context, so both can run headless.
+
+use {
+    rand::{rngs::StdRng, Rng, SeedableRng},
+    sludge::{dispatcher::Dispatcher, prelude::*},
+};
+
+use crate::{
+    kinematics::{Acceleration, Damping, KinematicsSystem, MaxSpeed},
+    math::Velocity2,
+    spatial_hash::{SpatialHasher, SpatialHashingSystem, SpatialIndex},
+    Ball, Position, Shape, ShapeHandle, Velocity,
+};
+
+/// The event broadcast by the stress test's Lua threads.
+pub const PING_EVENT: &'static str = "sludge.stress.ping";
+
+const THREADS: &'static str = r#"
+return function(count, ping)
+    for i = 1, count do
+        local kind = i % 3
+        if kind == 0 then
+            -- Sleepers wake up every few ticks and go right back to sleep.
+            sludge.thread.spawn(function()
+                while true do
+                    sludge.thread.yield(1 + i % 7)
+                end
+            end)
+        elseif kind == 1 then
+            -- Broadcasters ping every few ticks.
+            sludge.thread.spawn(function()
+                while true do
+                    sludge.thread.yield(1 + i % 5)
+                    sludge.thread.broadcast(ping, i)
+                end
+            end)
+        else
+            -- Listeners wait for pings.
+            sludge.thread.spawn(function()
+                while true do
+                    sludge.thread.yield(ping)
+                end
+            end)
+        end
+    end
+end
+"#;
+
+/// The size of a stress test.
+#[derive(Debug, Clone)]
+pub struct StressConfig {
+    /// The number of bullets to spawn.
+    pub bullets: usize,
+    /// The number of Lua threads to spawn.
+    pub threads: usize,
+    /// The side length of the square the bullets are scattered across.
+    pub extent: f32,
+    /// The bucket size of the spatial hash.
+    pub bucket_size: f32,
+    /// The seed for placing the bullets, so that runs can be compared.
+    pub seed: u64,
+}
+
+impl Default for StressConfig {
+    fn default() -> Self {
+        Self {
+            bullets: 4096,
+            threads: 256,
+            extent: 1024.,
+            bucket_size: 64.,
+            seed: 0,
+        }
+    }
+}
+
+/// A space full of bullets and Lua threads. See the [module documentation](self).
+pub struct Stress {
+    pub space: Space,
+    pub dispatcher: Dispatcher<'static>,
+}
+
+impl Stress {
+    pub fn new(config: &StressConfig) -> Result<Self> {
+        let space = Space::new()?;
+        space.resources().borrow_mut().insert(SpatialHasher::new(
+            config.bucket_size,
+            &mut space.world()?.borrow_mut(),
+        ));
+
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.register(KinematicsSystem, "Kinematics", &[])?;
+        dispatcher.register(SpatialHashingSystem, "SpatialHashing", &["Kinematics"])?;
+        space.refresh(&mut dispatcher)?;
+
+        let mut rng = StdRng::seed_from_u64(config.seed);
+        {
+            let world = space.world()?;
+            let mut world = world.borrow_mut();
+
+            for i in 0..config.bullets {
+                let x = rng.gen_range(0., config.extent);
+                let y = rng.gen_range(0., config.extent);
+                let angle = rng.gen_range(0., std::f32::consts::PI * 2.);
+                let speed = rng.gen_range(10., 100.);
+
+                let position = Position(Isometry2::new(Vector2::new(x, y), angle));
+                let velocity = Velocity(Velocity2::new(
+                    Vector2::new(angle.cos(), angle.sin()) * speed,
+                    rng.gen_range(-1., 1.),
+                ));
+                let shape = Shape::new(Isometry2::identity(), ShapeHandle::new(Ball::new(2.)));
+                let entity = world.spawn((position, velocity, shape));
+
+                // Mix up the archetypes, so that iteration isn't over a single one.
+                match i % 3 {
+                    0 => {}
+                    1 => world.insert_one(
+                        entity,
+                        Acceleration {
+                            x: rng.gen_range(-10., 10.),
+                            y: rng.gen_range(-10., 10.),
+                            angular: 0.,
+                        },
+                    )?,
+                    _ => world.insert(
+                        entity,
+                        (
+                            Damping {
+                                linear: 0.5,
+                                angular: 0.5,
+                            },
+                            MaxSpeed(speed),
+                        ),
+                    )?,
+                }
+            }
+        }
+
+        space.lua().context(|lua| -> Result<()> {
+            lua.load(THREADS)
+                .set_name("sludge.stress")?
+                .eval::<LuaFunction>()?
+                .call::<_, ()>((config.threads, PING_EVENT))?;
+            Ok(())
+        })?;
+
+        // Get the bullets into the spatial hash and the threads started, so that
+        // measurements start from a steady state.
+        let stress = Self { space, dispatcher };
+        stress.update_scheduler()?;
+        stress.update_spatial_hash()?;
+
+        Ok(stress)
+    }
+
+    /// Run the scheduler for one tick.
+    pub fn update_scheduler(&self) -> Result<()> {
+        let scheduler = self.space.scheduler()?;
+        self.space
+            .lua()
+            .context(|lua| scheduler.borrow_mut().update(lua, 1.0))
+    }
+
+    /// Integrate every bullet's motion for one fixed step.
+    pub fn update_kinematics(&self) -> Result<()> {
+        self.space
+            .lua()
+            .context(|lua| KinematicsSystem.update(lua, self.space.resources()))
+    }
+
+    /// Update the spatial hash with whatever's moved since the last update.
+    pub fn update_spatial_hash(&self) -> Result<()> {
+        self.space
+            .lua()
+            .context(|lua| SpatialHashingSystem.update(lua, self.space.resources()))
+    }
+
+    /// Count the potential collisions between bullets, querying the spatial hash once
+    /// for each of them.
+    pub fn query_spatial_hash(&self) -> Result<usize> {
+        let hasher = self.space.fetch_one::<SpatialHasher>()?;
+        let hasher = hasher.borrow();
+        let grid = hasher.grid();
+
+        let world = self.space.world()?;
+        let world = world.borrow();
+        let mut count = 0;
+        for (_, index) in world.query::<&SpatialIndex>().iter() {
+            count += grid.find_potential_collisions(*index).count();
+        }
+
+        Ok(count)
+    }
+
+    /// Run a whole frame: one scheduler tick, then the dispatcher.
+    pub fn tick(&mut self) -> Result<()> {
+        self.update_scheduler()?;
+        self.space.dispatch(&mut self.dispatcher)
+    }
+}
@@ -0,0 +1,259 @@
+//! Drawing Tiled maps, including image layers and parallax scrolling.
+//!
+//! A [`TiledMapRenderer`] turns the visible tile and image layers of a [`TiledMap`] into
+//! sprite batches and textures once, up front, and then draws them every frame in the
+//! order they appear in the map. Object layers aren't drawn; their objects are usually
+//! spawned as entities instead.
+//!
+//! Each layer is shifted by its [parallax offset](Layer::parallax_offset) from the
+//! position of the [`Camera2d`], so backgrounds set to scroll slower than the rest of
+//! the map in Tiled do the same in game. Image layers marked as repeating, either with
+//! Tiled's own setting or with a boolean `repeat_x` or `repeat_y` layer property, are
+//! drawn as many times as it takes to cover the screen in that direction, which is how a
+//! side-scroller's sky or distant hills are usually set up:
+//!
+//! ```ignore
+//! let renderer = TiledMapRenderer::new(&mut gfx, &cache, &map)?;
+//!
+//! // Every frame, with the camera's transform pushed:
+//! gfx.push_multiplied_transform(camera.to_matrix4());
+//! renderer.draw(&mut gfx, &map, &camera);
+//! gfx.pop_transform();
+//! ```
+//!
+//! Flipped and rotated tiles are drawn the way Tiled shows them. Animated tiles are
+//! drawn as their first frame.
+
+use sludge::{
+    assets::{Cached, DefaultCache, Key},
+    graphics::{Color, Graphics, InstanceParam, SpriteBatch, Texture},
+    prelude::*,
+    tiled::{ImageLayer, Layer, TileFlip, TileLayer, TiledMap},
+};
+
+use crate::camera::Camera2d;
+
+/// A single layer, ready to draw.
+#[derive(Debug)]
+enum RenderedLayer {
+    /// One batch per tile sheet the layer uses.
+    Tiles { batches: Vec<SpriteBatch> },
+    Image {
+        texture: Cached<Texture>,
+        size: Vector2<f32>,
+        offset: Vector2<f32>,
+        color: Color,
+        repeat_x: bool,
+        repeat_y: bool,
+    },
+}
+
+/// Map a unit square onto itself the way a tile is flipped. The diagonal flip comes
+/// first, then the horizontal and vertical ones, the same as in Tiled.
+fn flip_transform(flip: TileFlip) -> Transform3<f32> {
+    let mut m = Matrix4::identity();
+    if flip.vertical {
+        m *= Matrix4::new_nonuniform_scaling(&Vector3::new(1., -1., 1.))
+            .append_translation(&Vector3::new(0., 1., 0.));
+    }
+    if flip.horizontal {
+        m *= Matrix4::new_nonuniform_scaling(&Vector3::new(-1., 1., 1.))
+            .append_translation(&Vector3::new(1., 0., 0.));
+    }
+    if flip.diagonal {
+        m *= Matrix4::new(
+            0., 1., 0., 0., //
+            1., 0., 0., 0., //
+            0., 0., 1., 0., //
+            0., 0., 0., 1.,
+        );
+    }
+    Transform3::from_matrix_unchecked(m)
+}
+
+/// Draws the tile and image layers of a Tiled map. See the [module
+/// documentation](self) for details.
+#[derive(Debug)]
+pub struct TiledMapRenderer {
+    /// Each rendered layer, along with its index into the map's layers.
+    layers: Vec<(usize, RenderedLayer)>,
+}
+
+impl TiledMapRenderer {
+    /// Build sprite batches for every visible tile layer and load the texture of every
+    /// visible image layer. Textures are loaded through `cache`, so they're shared with
+    /// anything else drawing from the same images.
+    pub fn new<L, T, O>(
+        gfx: &mut Graphics,
+        cache: &DefaultCache,
+        map: &TiledMap<L, T, O>,
+    ) -> Result<Self> {
+        let mut layers = Vec::new();
+        for (index, layer) in map.layers().iter().enumerate() {
+            if !layer.is_visible() {
+                continue;
+            }
+
+            match layer {
+                Layer::TileLayer(tile_layer) => {
+                    let rendered = Self::tile_layer(gfx, cache, map, layer, tile_layer)?;
+                    layers.push((index, rendered));
+                }
+                Layer::ImageLayer(image_layer) => {
+                    let rendered = Self::image_layer(cache, layer, image_layer)?;
+                    layers.push((index, rendered));
+                }
+                Layer::ObjectLayer(_) => {}
+            }
+        }
+
+        Ok(Self { layers })
+    }
+
+    fn tile_layer<L, T, O>(
+        gfx: &mut Graphics,
+        cache: &DefaultCache,
+        map: &TiledMap<L, T, O>,
+        layer: &Layer<L, O>,
+        tile_layer: &TileLayer<L>,
+    ) -> Result<RenderedLayer> {
+        let (tile_width, tile_height) = map.tile_dimensions();
+        let color = Color::new(1., 1., 1., layer.opacity());
+
+        // Batches are indexed the same as the map's tile sheets, and only created for
+        // sheets the layer actually uses.
+        let mut batches = (0..map.tile_sheets().len())
+            .map(|_| None)
+            .collect::<Vec<Option<SpriteBatch>>>();
+
+        for (_, chunk) in tile_layer.chunks() {
+            for ((x, y), gid, flip) in chunk.tiles_with_flips() {
+                if gid == 0 {
+                    continue;
+                }
+
+                let index = match map
+                    .tile_sheets()
+                    .iter()
+                    .position(|ts| ts.first_global_id() <= gid && gid <= ts.last_global_id())
+                {
+                    Some(index) => index,
+                    None => bail!("no tile sheet contains tile {} at ({}, {})", gid, x, y),
+                };
+                let sheet = &map.tile_sheets()[index];
+                let region = sheet.get_region_from_global_id(gid);
+                let bounds = region.bounds.extents();
+                // A diagonal flip turns the tile on its side, swapping its width and height.
+                let extents = if flip.diagonal {
+                    Vector2::new(bounds.y as f32, bounds.x as f32)
+                } else {
+                    Vector2::new(bounds.x as f32, bounds.y as f32)
+                };
+
+                if batches[index].is_none() {
+                    let texture = cache.get::<Texture>(&Key::path(sheet.source()))?;
+                    batches[index] = Some(SpriteBatch::new(gfx, texture));
+                }
+                let batch = batches[index].as_mut().unwrap();
+
+                // Tiled lines tiles up with the bottom left corner of their cell, so tiles
+                // taller than the map's grid stick up out of it.
+                let corner = Vector2::new(
+                    (x * tile_width as i32) as f32,
+                    ((y + 1) * tile_height as i32) as f32 - extents.y,
+                );
+                batch.insert(
+                    InstanceParam::new()
+                        .src(region.uv)
+                        .color(color)
+                        .translate2(corner)
+                        .scale2(extents)
+                        .prepend_transform(&flip_transform(flip)),
+                );
+            }
+        }
+
+        Ok(RenderedLayer::Tiles {
+            batches: batches.into_iter().flatten().collect(),
+        })
+    }
+
+    fn image_layer<L, O>(
+        cache: &DefaultCache,
+        layer: &Layer<L, O>,
+        image_layer: &ImageLayer<L>,
+    ) -> Result<RenderedLayer> {
+        let texture = cache.get::<Texture>(&Key::path(&image_layer.source))?;
+        ensure!(
+            image_layer.image_width > 0 && image_layer.image_height > 0,
+            "image layer {:?} has an empty image",
+            image_layer.name
+        );
+
+        Ok(RenderedLayer::Image {
+            texture,
+            size: Vector2::new(
+                image_layer.image_width as f32,
+                image_layer.image_height as f32,
+            ),
+            offset: Vector2::new(image_layer.offset_x, image_layer.offset_y),
+            color: Color::new(1., 1., 1., layer.opacity()),
+            repeat_x: image_layer.repeat_x,
+            repeat_y: image_layer.repeat_y,
+        })
+    }
+
+    /// Draw every layer, shifted for parallax relative to the camera. `map` must be the
+    /// map the renderer was built from. The camera's transform should already be on
+    /// the transform stack.
+    pub fn draw<L, T, O>(&self, gfx: &mut Graphics, map: &TiledMap<L, T, O>, camera: &Camera2d) {
+        let view = camera.view_position();
+        let visible = camera.visible_bounds();
+
+        for (index, layer) in &self.layers {
+            let shift = map.layers()[*index].parallax_offset(&view);
+
+            match layer {
+                RenderedLayer::Tiles { batches, .. } => {
+                    for batch in batches {
+                        gfx.draw(batch, InstanceParam::new().translate2(shift));
+                    }
+                }
+                RenderedLayer::Image {
+                    texture,
+                    size,
+                    offset,
+                    color,
+                    repeat_x,
+                    repeat_y,
+                    ..
+                } => {
+                    let origin = shift + offset;
+                    // The range of copies of the image which overlap the screen, along
+                    // one axis; just the one copy if it doesn't repeat that way.
+                    let copies = |repeat: bool, min: f32, max: f32, origin: f32, size: f32| {
+                        if repeat {
+                            ((min - origin) / size).floor() as i32
+                                ..((max - origin) / size).ceil() as i32
+                        } else {
+                            0..1
+                        }
+                    };
+
+                    let texture = texture.load();
+                    for i in copies(*repeat_x, visible.mins.x, visible.maxs.x, origin.x, size.x) {
+                        for j in copies(*repeat_y, visible.mins.y, visible.maxs.y, origin.y, size.y)
+                        {
+                            let corner =
+                                origin + Vector2::new(i as f32, j as f32).component_mul(size);
+                            gfx.draw(
+                                &*texture,
+                                InstanceParam::new().color(*color).translate2(corner),
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,653 @@
+//! A minimal immediate-mode UI, for menus and HUDs.
+//!
+//! Every frame, [`Ui::begin_frame`] is called with the current state of the mouse,
+//! and then widgets are declared by calling methods like [`Ui::button`] and
+//! [`Ui::slider`], which return the result of any interaction immediately. Widgets
+//! queue draw commands, which are rendered in the order they were declared by
+//! [`Ui::draw`]. Widgets are identified by string IDs (for buttons, the label is
+//! used by default) which need to be stable from frame to frame.
+//!
+//! Text fields take their text from a [`TextInput`], which they focus when clicked.
+//!
+//! The `Ui` is also exposed to Lua through the `sludge.ui` module whenever it's
+//! present in a space's resources, so menus can be scripted, or described as plain
+//! tables and passed to `sludge.ui.build`.
+
+use {
+    sludge::{
+        assets::Cached,
+        graphics::{Color, Drawable, Graphics, InstanceParam, Texture},
+        input::{InputState, TextInput},
+        prelude::*,
+    },
+    std::{
+        collections::hash_map::DefaultHasher,
+        hash::{Hash, Hasher},
+    },
+};
+
+use crate::graphics::text::{FontAtlas, Text, TextLayout};
+
+/// The identity of a widget, used to track which widget the mouse is over and
+/// which one is being interacted with across frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WidgetId(u64);
+
+impl WidgetId {
+    pub fn new(id: &str) -> Self {
+        let mut hasher = DefaultHasher::new();
+        id.hash(&mut hasher);
+        Self(hasher.finish())
+    }
+}
+
+/// The state of the pointer which drives the UI for a single frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UiInput {
+    pub cursor: Point2<f32>,
+    pub down: bool,
+}
+
+impl UiInput {
+    pub fn new(cursor: Point2<f32>, down: bool) -> Self {
+        Self { cursor, down }
+    }
+
+    /// Read the pointer from an `InputState`, treating `button` as the UI's
+    /// "click" button.
+    pub fn from_state<Axes, Buttons>(state: &InputState<Axes, Buttons>, button: Buttons) -> Self
+    where
+        Axes: Eq + Hash + Clone,
+        Buttons: Eq + Hash + Clone,
+    {
+        Self {
+            cursor: state.mouse_position(),
+            down: state.get_button_down(button),
+        }
+    }
+}
+
+/// A texture drawn as a resizable panel: the corners are drawn at their original
+/// size, the edges are stretched along one axis and the center is stretched along
+/// both.
+#[derive(Debug, Clone)]
+pub struct NineSlice {
+    pub texture: Cached<Texture>,
+    /// The widths of the left and right borders, and the heights of the top and bottom
+    /// borders, in texels.
+    pub left: f32,
+    pub right: f32,
+    pub top: f32,
+    pub bottom: f32,
+}
+
+impl NineSlice {
+    pub fn new(texture: impl Into<Cached<Texture>>, border: f32) -> Self {
+        Self {
+            texture: texture.into(),
+            left: border,
+            right: border,
+            top: border,
+            bottom: border,
+        }
+    }
+
+    fn draw(&self, ctx: &mut Graphics, bounds: &Box2<f32>, color: Color) {
+        let texture = self.texture.load();
+        let (tw, th) = (texture.width() as f32, texture.height() as f32);
+
+        // Texel and screen-space coordinates of the column and row boundaries.
+        let src_xs = [0., self.left, tw - self.right, tw];
+        let src_ys = [0., self.top, th - self.bottom, th];
+        let dst_xs = [
+            bounds.mins.x,
+            bounds.mins.x + self.left,
+            bounds.maxs.x - self.right,
+            bounds.maxs.x,
+        ];
+        let dst_ys = [
+            bounds.mins.y,
+            bounds.mins.y + self.top,
+            bounds.maxs.y - self.bottom,
+            bounds.maxs.y,
+        ];
+
+        for row in 0..3 {
+            for col in 0..3 {
+                let src_w = src_xs[col + 1] - src_xs[col];
+                let src_h = src_ys[row + 1] - src_ys[row];
+                let dst_w = dst_xs[col + 1] - dst_xs[col];
+                let dst_h = dst_ys[row + 1] - dst_ys[row];
+
+                if src_w <= 0. || src_h <= 0. || dst_w <= 0. || dst_h <= 0. {
+                    continue;
+                }
+
+                let param = InstanceParam::new()
+                    .src(Box2::new(
+                        src_xs[col] / tw,
+                        src_ys[row] / th,
+                        src_w / tw,
+                        src_h / th,
+                    ))
+                    .color(color)
+                    .translate2(Vector2::new(dst_xs[col], dst_ys[row]))
+                    .scale2(Vector2::new(dst_w / src_w, dst_h / src_h));
+                texture.draw(ctx, param);
+            }
+        }
+    }
+}
+
+/// Colors, fonts and textures used to draw widgets.
+#[derive(Debug, Clone)]
+pub struct UiStyle {
+    pub font: Cached<FontAtlas>,
+    pub text: Color,
+    pub button: Color,
+    pub button_hot: Color,
+    pub button_active: Color,
+    pub button_slice: Option<NineSlice>,
+    pub panel: Color,
+    pub panel_slice: Option<NineSlice>,
+    pub slider_track: Color,
+    pub slider_handle: Color,
+    pub slider_handle_width: f32,
+}
+
+impl UiStyle {
+    pub fn new(font: impl Into<Cached<FontAtlas>>) -> Self {
+        Self {
+            font: font.into(),
+            text: Color::WHITE,
+            button: Color::from_rgb(64, 64, 64),
+            button_hot: Color::from_rgb(96, 96, 96),
+            button_active: Color::from_rgb(40, 40, 40),
+            button_slice: None,
+            panel: Color::from_rgba(24, 24, 24, 220),
+            panel_slice: None,
+            slider_track: Color::from_rgb(40, 40, 40),
+            slider_handle: Color::from_rgb(160, 160, 160),
+            slider_handle_width: 8.,
+        }
+    }
+}
+
+enum UiCommand {
+    Rect {
+        bounds: Box2<f32>,
+        color: Color,
+    },
+    NineSlice {
+        slice: NineSlice,
+        bounds: Box2<f32>,
+        color: Color,
+    },
+    Text {
+        layout: TextLayout,
+        position: Point2<f32>,
+    },
+}
+
+/// An immediate-mode UI context. See the [module documentation](self) for details.
+pub struct Ui {
+    style: UiStyle,
+
+    input: UiInput,
+    pressed: bool,
+    released: bool,
+
+    hot: Option<WidgetId>,
+    active: Option<WidgetId>,
+
+    commands: Vec<UiCommand>,
+    text_pool: Vec<Text>,
+}
+
+impl Ui {
+    pub fn new(style: UiStyle) -> Self {
+        Self {
+            style,
+
+            input: UiInput::new(Point2::origin(), false),
+            pressed: false,
+            released: false,
+
+            hot: None,
+            active: None,
+
+            commands: Vec::new(),
+            text_pool: Vec::new(),
+        }
+    }
+
+    pub fn style(&self) -> &UiStyle {
+        &self.style
+    }
+
+    pub fn style_mut(&mut self) -> &mut UiStyle {
+        &mut self.style
+    }
+
+    /// Start a new frame, discarding the draw commands of the last one.
+    pub fn begin_frame(&mut self, input: UiInput) {
+        // If the widget which was active wasn't declared on the frame the mouse was
+        // released, make sure it doesn't stay active forever.
+        if !input.down && !self.input.down {
+            self.active = None;
+        }
+
+        self.pressed = input.down && !self.input.down;
+        self.released = !input.down && self.input.down;
+        self.input = input;
+        self.hot = None;
+        self.commands.clear();
+    }
+
+    /// Whether the mouse is over any widget this frame, or is interacting with one.
+    /// Useful for deciding whether clicks should pass through the UI to the game.
+    pub fn wants_mouse(&self) -> bool {
+        self.hot.is_some() || self.active.is_some()
+    }
+
+    fn cursor_in(&self, bounds: &Box2<f32>) -> bool {
+        let p = self.input.cursor;
+        p.x >= bounds.mins.x && p.x < bounds.maxs.x && p.y >= bounds.mins.y && p.y < bounds.maxs.y
+    }
+
+    /// Update hot/active state for a widget, returning whether the cursor is over it.
+    fn interact(&mut self, id: WidgetId, bounds: &Box2<f32>) -> bool {
+        let inside = self.cursor_in(bounds);
+        if inside && self.active.map_or(true, |active| active == id) {
+            self.hot = Some(id);
+            if self.pressed {
+                self.active = Some(id);
+            }
+        }
+        inside
+    }
+
+    fn finish_interaction(&mut self, id: WidgetId) {
+        if self.released && self.active == Some(id) {
+            self.active = None;
+        }
+    }
+
+    fn layout_text(&self, text: &str, color: Color) -> TextLayout {
+        let mut layout = TextLayout::new(self.style.font.clone());
+        layout.push_str(text, std::iter::repeat(color));
+        layout
+    }
+
+    fn text_bounds(layout: &TextLayout) -> Box2<f32> {
+        let mut chars = layout.chars().iter().map(|info| info.coords);
+        match chars.next() {
+            Some(first) => chars.fold(first, |acc, coords| {
+                Box2::from_corners(acc.mins.inf(&coords.mins), acc.maxs.sup(&coords.maxs))
+            }),
+            None => Box2::new(0., 0., 0., 0.),
+        }
+    }
+
+    fn push_rect(&mut self, bounds: Box2<f32>, color: Color, slice: Option<NineSlice>) {
+        self.commands.push(match slice {
+            Some(slice) => UiCommand::NineSlice {
+                slice,
+                bounds,
+                color,
+            },
+            None => UiCommand::Rect { bounds, color },
+        });
+    }
+
+    /// Draw a string of text with its top-left corner at `position`, returning the
+    /// screen-space bounds of the text.
+    pub fn label(&mut self, position: Point2<f32>, text: &str) -> Box2<f32> {
+        let layout = self.layout_text(text, self.style.text);
+        let text_bounds = Self::text_bounds(&layout);
+        let offset = text_bounds.mins.coords;
+        self.commands.push(UiCommand::Text {
+            layout,
+            position: position - offset,
+        });
+        Box2::from_corners(position, position + text_bounds.extents())
+    }
+
+    /// Draw a string of text centered in `bounds`.
+    pub fn label_centered(&mut self, bounds: Box2<f32>, text: &str) {
+        let layout = self.layout_text(text, self.style.text);
+        let text_bounds = Self::text_bounds(&layout);
+        let position = bounds.center() - text_bounds.center().coords;
+        self.commands.push(UiCommand::Text { layout, position });
+    }
+
+    /// Draw a plain colored rectangle, which doesn't interact with the mouse.
+    pub fn rect(&mut self, bounds: Box2<f32>, color: Color) {
+        self.push_rect(bounds, color, None);
+    }
+
+    /// Draw a panel, using the style's panel nine-slice if it has one.
+    pub fn panel(&mut self, bounds: Box2<f32>) {
+        self.push_rect(bounds, self.style.panel, self.style.panel_slice.clone());
+    }
+
+    /// A button with a centered label, identified by its label. Returns `true` on the
+    /// frame it's clicked.
+    pub fn button(&mut self, bounds: Box2<f32>, label: &str) -> bool {
+        self.button_with_id(label, bounds, label)
+    }
+
+    /// A button with a centered label and an explicit ID, for when several buttons
+    /// share a label.
+    pub fn button_with_id(&mut self, id: &str, bounds: Box2<f32>, label: &str) -> bool {
+        let id = WidgetId::new(id);
+        let inside = self.interact(id, &bounds);
+        let clicked = inside && self.released && self.active == Some(id);
+        self.finish_interaction(id);
+
+        let color = if self.active == Some(id) || clicked {
+            self.style.button_active
+        } else if self.hot == Some(id) {
+            self.style.button_hot
+        } else {
+            self.style.button
+        };
+        self.push_rect(bounds, color, self.style.button_slice.clone());
+        self.label_centered(bounds, label);
+
+        clicked
+    }
+
+    /// A horizontal slider over `[min, max]`. Returns `true` if `value` was changed.
+    pub fn slider(
+        &mut self,
+        id: &str,
+        bounds: Box2<f32>,
+        value: &mut f32,
+        min: f32,
+        max: f32,
+    ) -> bool {
+        let id = WidgetId::new(id);
+        self.interact(id, &bounds);
+
+        let handle_width = self.style.slider_handle_width.min(bounds.extents().x);
+        let travel = bounds.extents().x - handle_width;
+
+        let mut changed = false;
+        if self.active == Some(id) && self.input.down && travel > 0. {
+            let t =
+                ((self.input.cursor.x - bounds.mins.x - handle_width / 2.) / travel).clamp(0., 1.);
+            let new_value = min + t * (max - min);
+            changed = new_value != *value;
+            *value = new_value;
+        }
+        self.finish_interaction(id);
+
+        let t = if max > min {
+            ((*value - min) / (max - min)).clamp(0., 1.)
+        } else {
+            0.
+        };
+
+        self.push_rect(bounds, self.style.slider_track, None);
+        let handle = Box2::new(
+            bounds.mins.x + t * travel,
+            bounds.mins.y,
+            handle_width,
+            bounds.extents().y,
+        );
+        let handle_color = if self.hot == Some(id) || self.active == Some(id) {
+            self.style.button_hot
+        } else {
+            self.style.slider_handle
+        };
+        self.push_rect(handle, handle_color, None);
+
+        changed
+    }
+
+    /// A single-line text field, which focuses `text_input` when it's clicked and gives
+    /// up focus when anything else is. While it has focus, `text` is kept in step with
+    /// what's typed, so submitting the text with Enter clears the field. Returns `true`
+    /// if `text` was changed.
+    pub fn text_field(
+        &mut self,
+        id: &str,
+        bounds: Box2<f32>,
+        text: &mut String,
+        text_input: &mut TextInput,
+    ) -> bool {
+        let widget = WidgetId::new(id);
+        let inside = self.interact(widget, &bounds);
+        if self.pressed {
+            if inside && !text_input.is_focused(id) {
+                text_input.focus(id, text.clone());
+            } else if !inside && text_input.is_focused(id) {
+                text_input.blur();
+            }
+        }
+        self.finish_interaction(widget);
+
+        let focused = text_input.is_focused(id);
+        let changed = focused && text_input.text() != text.as_str();
+        if changed {
+            *text = text_input.text().to_owned();
+        }
+
+        let color = if focused {
+            self.style.button_active
+        } else if self.hot == Some(widget) {
+            self.style.button_hot
+        } else {
+            self.style.button
+        };
+        self.push_rect(bounds, color, self.style.button_slice.clone());
+
+        // Text is laid out from its baseline, so line it up using a full-height line
+        // rather than the text itself, which might be empty or have no descenders.
+        let line = Self::text_bounds(&self.layout_text("Ay|", self.style.text));
+        let padding = (bounds.extents().y - line.extents().y).max(0.) / 2.;
+        let origin = Point2::new(
+            bounds.mins.x + padding - line.mins.x,
+            bounds.mins.y + padding - line.mins.y,
+        );
+
+        let layout = self.layout_text(text, self.style.text);
+        self.commands.push(UiCommand::Text {
+            layout,
+            position: origin,
+        });
+
+        if focused {
+            let before = &text[..text_input.cursor().min(text.len())];
+            let x = if before.is_empty() {
+                0.
+            } else {
+                Self::text_bounds(&self.layout_text(before, self.style.text))
+                    .maxs
+                    .x
+            };
+            let caret = Box2::new(origin.x + x, origin.y + line.mins.y, 1., line.extents().y);
+            self.push_rect(caret, self.style.text, None);
+        }
+
+        changed
+    }
+
+    /// Render all widgets declared since the last call to `begin_frame`.
+    pub fn draw(&mut self, ctx: &mut Graphics) {
+        let null_texture = ctx.null_texture.clone();
+        let mut texts_used = 0;
+
+        for command in &self.commands {
+            match command {
+                UiCommand::Rect { bounds, color } => {
+                    let param = InstanceParam::new()
+                        .color(*color)
+                        .translate2(bounds.mins.coords)
+                        .scale2(bounds.extents());
+                    null_texture.load().draw(ctx, param);
+                }
+                UiCommand::NineSlice {
+                    slice,
+                    bounds,
+                    color,
+                } => slice.draw(ctx, bounds, *color),
+                UiCommand::Text { layout, position } => {
+                    if texts_used == self.text_pool.len() {
+                        self.text_pool.push(Text::new(ctx));
+                    }
+
+                    let text = &mut self.text_pool[texts_used];
+                    text.apply_layout(layout);
+                    text.draw(ctx, InstanceParam::new().translate2(position.coords));
+                    texts_used += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Lua-facing description of a single widget, for `sludge.ui.build`.
+fn build_widget<'lua>(
+    lua: LuaContext<'lua>,
+    ui: &mut Ui,
+    spec: LuaTable<'lua>,
+    results: &LuaTable<'lua>,
+) -> LuaResult<()> {
+    let kind = spec.get::<_, String>("type")?;
+    let bounds = || spec.get::<_, Box2<f32>>("bounds");
+    match kind.as_str() {
+        "label" => {
+            let bounds = bounds()?;
+            ui.label(bounds.mins, &spec.get::<_, String>("text")?);
+        }
+        "panel" => ui.panel(bounds()?),
+        "button" => {
+            let label = spec.get::<_, String>("label")?;
+            let id = spec
+                .get::<_, Option<String>>("id")?
+                .unwrap_or_else(|| label.clone());
+            let clicked = ui.button_with_id(&id, bounds()?, &label);
+            results.set(id, clicked)?;
+        }
+        "slider" => {
+            let id = spec.get::<_, String>("id")?;
+            let min = spec.get::<_, Option<f32>>("min")?.unwrap_or(0.);
+            let max = spec.get::<_, Option<f32>>("max")?.unwrap_or(1.);
+            let mut value = spec.get::<_, Option<f32>>("value")?.unwrap_or(min);
+            ui.slider(&id, bounds()?, &mut value, min, max);
+            spec.set("value", value)?;
+            results.set(id, value)?;
+        }
+        "text_field" => {
+            let id = spec.get::<_, String>("id")?;
+            let mut text = spec.get::<_, Option<String>>("text")?.unwrap_or_default();
+            let text_input = lua.fetch_one::<TextInput>()?;
+            ui.text_field(&id, bounds()?, &mut text, &mut text_input.borrow_mut());
+            spec.set("text", text.clone())?;
+            results.set(id, text)?;
+        }
+        other => {
+            return Err(anyhow!("unknown UI widget type `{}`", other)).to_lua_err();
+        }
+    }
+
+    if let Some(children) = spec.get::<_, Option<LuaTable>>("children")? {
+        for child in children.sequence_values::<LuaTable>() {
+            build_widget(lua, ui, child?, results)?;
+        }
+    }
+
+    Ok(())
+}
+
+inventory::submit! {
+    sludge::api::Module::parse("sludge.ui", |lua| {
+        let table = lua.create_table()?;
+
+        table.set(
+            "label",
+            lua.create_function(|lua, (text, x, y): (String, f32, f32)| {
+                lua.fetch_one::<Ui>()?
+                    .borrow_mut()
+                    .label(Point2::new(x, y), &text);
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "panel",
+            lua.create_function(|lua, bounds: Box2<f32>| {
+                lua.fetch_one::<Ui>()?.borrow_mut().panel(bounds);
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "button",
+            lua.create_function(
+                |lua, (label, bounds, id): (String, Box2<f32>, Option<String>)| {
+                    let id = id.as_deref().unwrap_or(&label);
+                    Ok(lua
+                        .fetch_one::<Ui>()?
+                        .borrow_mut()
+                        .button_with_id(id, bounds, &label))
+                },
+            )?,
+        )?;
+
+        table.set(
+            "slider",
+            lua.create_function(
+                |lua, (id, bounds, mut value, min, max): (String, Box2<f32>, f32, f32, f32)| {
+                    lua.fetch_one::<Ui>()?
+                        .borrow_mut()
+                        .slider(&id, bounds, &mut value, min, max);
+                    Ok(value)
+                },
+            )?,
+        )?;
+
+        table.set(
+            "text_field",
+            lua.create_function(
+                |lua, (id, bounds, text): (String, Box2<f32>, Option<String>)| {
+                    let mut text = text.unwrap_or_default();
+                    let text_input = lua.fetch_one::<TextInput>()?;
+                    let changed = lua.fetch_one::<Ui>()?.borrow_mut().text_field(
+                        &id,
+                        bounds,
+                        &mut text,
+                        &mut text_input.borrow_mut(),
+                    );
+                    Ok((text, changed))
+                },
+            )?,
+        )?;
+
+        table.set(
+            "wants_mouse",
+            lua.create_function(|lua, ()| Ok(lua.fetch_one::<Ui>()?.borrow().wants_mouse()))?,
+        )?;
+
+        // Build a whole tree of widgets from a table description, returning a table
+        // mapping button IDs to whether they were clicked, slider IDs to their values
+        // and text field IDs to their text. Slider values and text are also written
+        // back into their descriptions, so the same table can be passed in every frame.
+        table.set(
+            "build",
+            lua.create_function(|lua, specs: LuaTable| {
+                let results = lua.create_table()?;
+                let tmp = lua.fetch_one::<Ui>()?;
+                let ui = &mut *tmp.borrow_mut();
+                for spec in specs.sequence_values::<LuaTable>() {
+                    build_widget(lua, ui, spec?, &results)?;
+                }
+                Ok(results)
+            })?,
+        )?;
+
+        Ok(LuaValue::Table(table))
+    })
+}
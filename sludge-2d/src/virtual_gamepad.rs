@@ -0,0 +1,372 @@
+//! On-screen sticks and buttons, so that games written against an [`InputState`] can be
+//! played on a touch screen without any changes to their logic.
+//!
+//! A [`VirtualGamepad`] is a set of [`VirtualStick`]s, each driving a pair of axes, and
+//! [`VirtualButton`]s, each driving a button. Every frame, [`VirtualGamepad::update`]
+//! reads the [`Touches`] and applies the same effects to the input state that the
+//! matching keys would, and [`VirtualGamepad::draw`] declares the controls on a [`Ui`]:
+//!
+//! ```ignore
+//! let mut pad = VirtualGamepad::new()
+//!     .with_stick(VirtualStick::new(Point2::new(96., 400.), 64., Axes::Horz, Axes::Vert))
+//!     .with_button(VirtualButton::new(Box2::new(560., 380., 56., 56.), Buttons::Fire, "A"));
+//!
+//! pad.update(&touches.borrow(), &mut input_state);
+//! pad.draw(&mut ui);
+//! ```
+//!
+//! Axes are digital, like keys: a stick pushes its axes once it's moved further from its
+//! center than its dead zone. Pushing a stick up pushes its vertical axis in the
+//! positive direction, to match the usual binding of the up arrow key. The stick's
+//! analog position is available from [`VirtualStick::value`] for anything which wants
+//! it.
+//!
+//! A stick takes the first touch which starts inside it and follows it wherever it goes
+//! until it ends, so the thumb can drift off the stick without letting go. A button is
+//! held by any touch over it which isn't already following a stick.
+
+use {
+    sludge::{
+        graphics::Color,
+        input::{InputEffect, InputState, Touches},
+        prelude::*,
+    },
+    std::hash::Hash,
+};
+
+use crate::ui::Ui;
+
+/// A virtual analog stick, driving a horizontal and a vertical axis.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VirtualStick<Axes> {
+    pub center: Point2<f32>,
+    pub radius: f32,
+    pub horizontal: Axes,
+    pub vertical: Axes,
+    /// How far the stick has to be pushed before it pushes an axis, as a fraction of
+    /// its radius.
+    pub dead_zone: f32,
+    touch: Option<u64>,
+    value: Vector2<f32>,
+    /// Which way each of the axes is being pushed, if at all, as `positive`.
+    pushed: [Option<bool>; 2],
+}
+
+impl<Axes> VirtualStick<Axes> {
+    pub fn new(center: Point2<f32>, radius: f32, horizontal: Axes, vertical: Axes) -> Self {
+        Self {
+            center,
+            radius,
+            horizontal,
+            vertical,
+            dead_zone: 0.3,
+            touch: None,
+            value: Vector2::zeros(),
+            pushed: [None, None],
+        }
+    }
+
+    pub fn with_dead_zone(self, dead_zone: f32) -> Self {
+        Self { dead_zone, ..self }
+    }
+
+    /// How far the stick is pushed in screen space, with a length of at most one.
+    pub fn value(&self) -> Vector2<f32> {
+        self.value
+    }
+
+    /// The ID of the touch holding the stick, if any.
+    pub fn touch(&self) -> Option<u64> {
+        self.touch
+    }
+
+    fn contains(&self, point: Point2<f32>) -> bool {
+        (point - self.center).norm() <= self.radius
+    }
+
+    fn bounds(&self) -> Box2<f32> {
+        Box2::from_half_extents(self.center, Vector2::repeat(self.radius))
+    }
+}
+
+/// A virtual button, driving a button of the input state.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VirtualButton<Buttons> {
+    pub bounds: Box2<f32>,
+    pub button: Buttons,
+    pub label: String,
+    pressed: bool,
+}
+
+impl<Buttons> VirtualButton<Buttons> {
+    pub fn new(bounds: Box2<f32>, button: Buttons, label: impl Into<String>) -> Self {
+        Self {
+            bounds,
+            button,
+            label: label.into(),
+            pressed: false,
+        }
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+}
+
+/// A set of on-screen controls. See the [module documentation](self) for details.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VirtualGamepad<Axes, Buttons> {
+    /// A disabled gamepad lets go of everything on its next update and isn't drawn.
+    pub enabled: bool,
+    pub sticks: Vec<VirtualStick<Axes>>,
+    pub buttons: Vec<VirtualButton<Buttons>>,
+}
+
+impl<Axes, Buttons> Default for VirtualGamepad<Axes, Buttons> {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            sticks: Vec::new(),
+            buttons: Vec::new(),
+        }
+    }
+}
+
+impl<Axes, Buttons> VirtualGamepad<Axes, Buttons>
+where
+    Axes: Eq + Hash + Clone,
+    Buttons: Eq + Hash + Clone,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_stick(mut self, stick: VirtualStick<Axes>) -> Self {
+        self.sticks.push(stick);
+        self
+    }
+
+    pub fn with_button(mut self, button: VirtualButton<Buttons>) -> Self {
+        self.buttons.push(button);
+        self
+    }
+
+    pub fn toggle(&mut self) {
+        self.enabled = !self.enabled;
+    }
+
+    /// Read the touches which are down, and apply any change in the state of the
+    /// controls to `input`. This should be called once per frame, before `input` is
+    /// read.
+    pub fn update(&mut self, touches: &Touches, input: &mut InputState<Axes, Buttons>) {
+        for i in 0..self.sticks.len() {
+            let stick = &self.sticks[i];
+            let held = stick
+                .touch
+                .and_then(|id| touches.get(id))
+                .filter(|_| self.enabled)
+                .or_else(|| {
+                    touches.iter().find(|touch| {
+                        self.enabled
+                            && stick.contains(touch.start)
+                            && !self.sticks.iter().any(|s| s.touch == Some(touch.id))
+                    })
+                })
+                .copied();
+
+            let stick = &mut self.sticks[i];
+            stick.touch = held.map(|touch| touch.id);
+            stick.value = match held {
+                Some(touch) if stick.radius > 0. => {
+                    let offset = (touch.position - stick.center) / stick.radius;
+                    offset / offset.norm().max(1.)
+                }
+                _ => Vector2::zeros(),
+            };
+
+            // Screen space points down, but up is the positive direction of a vertical
+            // axis.
+            let components = [stick.value.x, -stick.value.y];
+            let axes = [stick.horizontal.clone(), stick.vertical.clone()];
+            for ((axis, &component), pushed) in axes.iter().zip(&components).zip(&mut stick.pushed)
+            {
+                let now = if component.abs() > stick.dead_zone {
+                    Some(component > 0.)
+                } else {
+                    None
+                };
+
+                if now != *pushed {
+                    if let Some(positive) = *pushed {
+                        input.update_effect(InputEffect::Axis(axis.clone(), positive), false);
+                    }
+                    if let Some(positive) = now {
+                        input.update_effect(InputEffect::Axis(axis.clone(), positive), true);
+                    }
+                    *pushed = now;
+                }
+            }
+        }
+
+        let (enabled, sticks) = (self.enabled, &self.sticks);
+        for button in &mut self.buttons {
+            let touch = touches
+                .iter()
+                .filter(|_| enabled)
+                .filter(|touch| !sticks.iter().any(|s| s.touch == Some(touch.id)))
+                .find(|touch| {
+                    let p = touch.position;
+                    p.x >= button.bounds.mins.x
+                        && p.x < button.bounds.maxs.x
+                        && p.y >= button.bounds.mins.y
+                        && p.y < button.bounds.maxs.y
+                });
+
+            if touch.is_some() != button.pressed {
+                button.pressed = touch.is_some();
+                let point = touch.map(|touch| touch.position);
+                input.update_effect(
+                    InputEffect::Button(button.button.clone(), point),
+                    button.pressed,
+                );
+            }
+        }
+    }
+
+    /// Declare the controls on `ui`, in the style's button colors. Does nothing if the
+    /// gamepad is disabled.
+    pub fn draw(&self, ui: &mut Ui) {
+        if !self.enabled {
+            return;
+        }
+
+        let style = ui.style();
+        let (panel, button, button_active) = (style.panel, style.button, style.button_active);
+        let translucent = |color: Color| Color::new(color.r, color.g, color.b, color.a * 0.5);
+
+        for stick in &self.sticks {
+            ui.rect(stick.bounds(), translucent(panel));
+            let knob = Box2::from_half_extents(
+                stick.center + stick.value * stick.radius,
+                Vector2::repeat(stick.radius / 3.),
+            );
+            let color = if stick.touch.is_some() {
+                button_active
+            } else {
+                button
+            };
+            ui.rect(knob, translucent(color));
+        }
+
+        for virtual_button in &self.buttons {
+            let color = if virtual_button.pressed {
+                button_active
+            } else {
+                button
+            };
+            ui.rect(virtual_button.bounds, translucent(color));
+            ui.label_centered(virtual_button.bounds, &virtual_button.label);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, sludge::input::TouchPhase};
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    enum Axes {
+        Horz,
+        Vert,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    enum Buttons {
+        Fire,
+    }
+
+    fn gamepad() -> VirtualGamepad<Axes, Buttons> {
+        VirtualGamepad::new()
+            .with_stick(VirtualStick::new(
+                Point2::new(100., 100.),
+                50.,
+                Axes::Horz,
+                Axes::Vert,
+            ))
+            .with_button(VirtualButton::new(
+                Box2::new(300., 100., 50., 50.),
+                Buttons::Fire,
+                "A",
+            ))
+    }
+
+    #[test]
+    fn stick_follows_its_touch() {
+        let mut pad = gamepad();
+        let mut touches = Touches::new();
+        let mut input = InputState::new();
+
+        // Up and to the right, in screen space.
+        touches.touch_event(TouchPhase::Started, 1, Point2::new(100., 100.));
+        touches.touch_event(TouchPhase::Moved, 1, Point2::new(140., 60.));
+        pad.update(&touches, &mut input);
+        assert_eq!(pad.sticks[0].touch(), Some(1));
+        assert_eq!(input.get_axis_raw(Axes::Horz), 1.);
+        assert_eq!(input.get_axis_raw(Axes::Vert), 1.);
+        assert!(pad.sticks[0].value().norm() <= 1.);
+
+        // Drifting off the stick keeps it held, and past the dead zone.
+        touches.touch_event(TouchPhase::Moved, 1, Point2::new(0., 100.));
+        pad.update(&touches, &mut input);
+        assert_eq!(input.get_axis_raw(Axes::Horz), -1.);
+        assert_eq!(input.get_axis_raw(Axes::Vert), 0.);
+
+        touches.touch_event(TouchPhase::Ended, 1, Point2::new(0., 100.));
+        pad.update(&touches, &mut input);
+        assert_eq!(pad.sticks[0].touch(), None);
+        assert_eq!(input.get_axis_raw(Axes::Horz), 0.);
+    }
+
+    #[test]
+    fn buttons_ignore_stick_touches() {
+        let mut pad = gamepad();
+        let mut touches = Touches::new();
+        let mut input = InputState::new();
+
+        // A touch which started on the stick doesn't press the button when it slides over.
+        touches.touch_event(TouchPhase::Started, 1, Point2::new(100., 100.));
+        pad.update(&touches, &mut input);
+        touches.touch_event(TouchPhase::Moved, 1, Point2::new(320., 120.));
+        pad.update(&touches, &mut input);
+        assert!(!pad.buttons[0].is_pressed());
+        assert!(!input.get_button_down(Buttons::Fire));
+
+        touches.touch_event(TouchPhase::Started, 2, Point2::new(310., 110.));
+        pad.update(&touches, &mut input);
+        assert!(pad.buttons[0].is_pressed());
+        assert!(input.get_button_down(Buttons::Fire));
+        assert_eq!(
+            input.get_button_event_location(Buttons::Fire),
+            Some(Point2::new(310., 110.))
+        );
+    }
+
+    #[test]
+    fn disabling_lets_go() {
+        let mut pad = gamepad();
+        let mut touches = Touches::new();
+        let mut input = InputState::new();
+
+        touches.touch_event(TouchPhase::Started, 1, Point2::new(60., 100.));
+        touches.touch_event(TouchPhase::Started, 2, Point2::new(310., 110.));
+        pad.update(&touches, &mut input);
+        assert_eq!(input.get_axis_raw(Axes::Horz), -1.);
+        assert!(input.get_button_down(Buttons::Fire));
+
+        pad.toggle();
+        pad.update(&touches, &mut input);
+        assert_eq!(input.get_axis_raw(Axes::Horz), 0.);
+        assert!(!input.get_button_down(Buttons::Fire));
+        assert_eq!(pad.sticks[0].touch(), None);
+    }
+}
@@ -1,11 +1,9 @@
-#![feature(exact_size_is_empty)]
-
 use ::{
     anyhow::*,
     hashbrown::HashMap,
     sludge::{
-        assets::DefaultCache, conf::Conf, dispatcher::Dispatcher, event::EventHandler,
-        filesystem::Filesystem, graphics::*, prelude::*,
+        assets::DefaultCache, conf::Conf, dispatcher::Stage, event::EventHandler,
+        filesystem::Filesystem, graphics::*, log_buffer::LogBuffer, prelude::*,
     },
     sludge_danmaku::*,
     std::{env, path::PathBuf},
@@ -33,18 +31,26 @@ impl BulletData for EasedBullet {
         bullet_type: BulletTypeId,
         bundles: &mut Vec<Self::Bundled>,
     ) -> Result<()> {
-        let tmp = resources.fetch_one::<TestResource>()?;
+        let (tmp, danmaku) = resources.fetch::<(TestResource, Danmaku)>()?;
         let test_resource = &mut tmp.borrow_mut();
         let batch = &mut test_resource.batch;
+        let danmaku = danmaku.borrow();
 
         bundles.extend(parameters.iter().map(|ps| {
             let instance = InstanceParam::default().translate2(ps.position.translation.vector);
             let idx = SpriteIndex {
                 idx: batch.insert(instance),
             };
-            let projectile = Projectile::origin(bullet_type);
-            let motion =
-                ParametricMotion::lerp_expo_out(false, ps.duration, &ps.position, &ps.destination);
+            let projectile = Projectile::origin(bullet_type).with_layers(ps.layers);
+            let motion = match ps.curve.and_then(|id| danmaku.curve(id)) {
+                Some(curve) => ParametricMotion::sampled(false, ps.duration, &ps.position, curve),
+                None => ParametricMotion::lerp_expo_out(
+                    false,
+                    ps.duration,
+                    &ps.position,
+                    &ps.destination,
+                ),
+            };
             let collision = Collision::Circle { radius: 1.0 };
             (projectile, motion, idx, collision)
         }));
@@ -91,7 +97,7 @@ impl BulletData for TestBullet {
             let idx = SpriteIndex {
                 idx: batch.insert(instance),
             };
-            let projectile = Projectile::new(bullet_type, ps.position);
+            let projectile = Projectile::new(bullet_type, ps.position).with_layers(ps.layers);
             let motion = QuadraticMotion::new(ps.to_velocity(), ps.to_acceleration());
             let collision = Collision::Circle { radius: 1.0 };
             (projectile, motion, idx, collision)
@@ -119,14 +125,13 @@ inventory::submit! {
 
 struct MainState {
     space: Space,
-    dispatcher: Dispatcher<'static>,
     events: ComponentSubscriber<SpriteIndex>,
     indices: HashMap<Entity, SpriteId>,
     canvas: Canvas,
 }
 
 impl MainState {
-    pub fn new(mut gfx: Graphics) -> Result<MainState> {
+    pub fn new(mut gfx: Graphics, log_buffer: LogBuffer) -> Result<MainState> {
         let null_texture = gfx.null_texture.clone();
         let batch = SpriteBatch::with_capacity(&mut gfx, null_texture, 4096 * 4);
         let canvas = Canvas::new(&mut gfx, 320, 240);
@@ -144,12 +149,13 @@ impl MainState {
 
             resources.insert(fs);
             resources.insert(gfx);
+            resources.insert(log_buffer);
             resources.insert(TestResource { batch });
 
             SharedResources::from(resources)
         };
 
-        let space = Space::with_global_resources(global)?;
+        let mut space = Space::with_global_resources(global)?;
         let cache = DefaultCache::new(space.resources().clone());
 
         {
@@ -158,10 +164,7 @@ impl MainState {
             res_mut.insert(Danmaku::with_bounds(Box2::new(0., 0., 320., 240.)));
         }
 
-        let mut dispatcher = Dispatcher::new();
-        dispatcher.register(DanmakuSystem, "Danmaku", &[])?;
-
-        space.refresh(&mut dispatcher)?;
+        space.register_in(DanmakuSystem, "Danmaku", &[], Stage::Fixed)?;
 
         let events = space.world()?.borrow_mut().track::<SpriteIndex>();
 
@@ -172,7 +175,6 @@ impl MainState {
 
         Ok(MainState {
             space,
-            dispatcher,
             events,
             indices: HashMap::new(),
             canvas,
@@ -181,37 +183,37 @@ impl MainState {
 }
 
 impl EventHandler for MainState {
-    type Args = ();
+    type Args = LogBuffer;
 
-    fn init(ctx: Graphics, _: ()) -> Result<Self> {
-        Self::new(ctx)
+    fn init(ctx: Graphics, log_buffer: LogBuffer) -> Result<Self> {
+        Self::new(ctx, log_buffer)
     }
 
     fn update(&mut self) -> Result<()> {
         let Self {
             space,
-            dispatcher,
             events,
             indices,
             ..
         } = self;
 
-        let (scheduler, world, test_resource) =
-            space.fetch::<(Scheduler, World, TestResource)>()?;
-        space
-            .lua()
-            .context(|lua| scheduler.borrow_mut().update(lua, 1.0))?;
+        space.fixed_update()?;
 
-        space.dispatch(dispatcher)?;
+        let (world, test_resource) = space.fetch::<(World, TestResource)>()?;
 
         let tr = &mut *test_resource.borrow_mut();
 
-        for (_, (proj, sprite_index)) in world
+        // Disabled bullets keep their sprites, so shrink those away rather than leaving
+        // them drawn wherever they were when they were disabled.
+        for (_, (proj, sprite_index, disabled)) in world
             .borrow()
-            .query::<(&Projectile, &mut SpriteIndex)>()
+            .query::<(&Projectile, &mut SpriteIndex, Option<&Disabled>)>()
             .iter()
         {
-            let param = InstanceParam::default().translate2(proj.position().translation.vector);
+            let param = match disabled {
+                None => InstanceParam::default().translate2(proj.position().translation.vector),
+                Some(_) => InstanceParam::default().scale2(Vector2::zeros()),
+            };
             tr.batch[sprite_index.idx] = param;
         }
 
@@ -229,7 +231,7 @@ impl EventHandler for MainState {
             }
         }
 
-        space.maintain().unwrap();
+        space.frame_update()?;
 
         Ok(())
     }
@@ -266,23 +268,27 @@ fn main() -> Result<()> {
         .debug(Color::BrightMagenta)
         .trace(Color::BrightBlue);
 
-    // This sets up a `fern` logger and initializes `log`.
-    fern::Dispatch::new()
-        // Formats logs
-        .format(move |out, message, record| {
-            out.finish(format_args!(
-                "[{}][{:<5}][{}] {}",
-                chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
-                colors.color(record.level()),
-                record.target(),
-                message
-            ))
-        })
-        .level(log::LevelFilter::Info)
-        .level_for("winit", log::LevelFilter::Warn)
-        .level_for("gfx_device_gl", log::LevelFilter::Warn)
-        .chain(std::io::stdout())
-        .apply()?;
+    // This sets up a `fern` logger and initializes `log`, keeping recent messages in a
+    // buffer for scripts and overlays to show.
+    let log_buffer = LogBuffer::new();
+    log_buffer.set_level(log::LevelFilter::Info);
+    log_buffer.install(
+        fern::Dispatch::new()
+            // Formats logs
+            .format(move |out, message, record| {
+                out.finish(format_args!(
+                    "[{}][{:<5}][{}] {}",
+                    chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+                    colors.color(record.level()),
+                    record.target(),
+                    message
+                ))
+            })
+            .level(log::LevelFilter::Info)
+            .level_for("winit", log::LevelFilter::Warn)
+            .level_for("gfx_device_gl", log::LevelFilter::Warn)
+            .chain(std::io::stdout()),
+    )?;
 
     sludge::event::run::<MainState>(
         Conf {
@@ -291,7 +297,7 @@ fn main() -> Result<()> {
             window_height: 240 * 4,
             ..Conf::default()
         },
-        (),
+        log_buffer,
     );
 
     Ok(())
@@ -0,0 +1,482 @@
+//! Boss fights, described as a sequence of phases in a Lua table.
+//!
+//! A boss is any entity with a [`BossPhase`] component, which is attached from Lua
+//! with `danmaku.boss.start(entity, script)`:
+//!
+//! ```lua
+//! danmaku.boss.start(boss, {
+//!     clear_delay = 1.0, -- seconds after a transition during which no bullets spawn
+//!     phases = {
+//!         { name = "opener", hp = 800, duration = 30, run = function(boss, phase) ... end },
+//!         { name = "Sign \"Example\"", spellcard = true, hp = 1200, duration = 45, run = ... },
+//!     },
+//! })
+//! ```
+//!
+//! Every phase has its own health, which is reduced with `danmaku.boss.damage`. A phase
+//! ends when its health runs out or, if it has a `duration`, when it times out. On every
+//! transition the screen is cleared of bullets, the previous phase's `run` coroutine is
+//! killed, the next phase's `run` function is spawned on the scheduler with the boss
+//! entity and the phase table, and a [`PHASE_EVENT`] is broadcast with the boss, the
+//! new phase's (1-based) index and name, and how the previous phase ended (`nil` for
+//! the first phase.) After the last phase ends, [`DEFEATED_EVENT`] is broadcast with
+//! the boss and how its last phase ended, and the `BossPhase` component is removed.
+//!
+//! Phases are driven by the [`BossSystem`], which counts durations down by the space's
+//! [fixed timestep](sludge::timestep).
+
+use ::{
+    sludge::{api::LuaEntity, pause, prelude::*, timestep},
+    std::f32,
+};
+
+use crate::Danmaku;
+
+/// Broadcast when a boss enters a new phase.
+pub const PHASE_EVENT: &'static str = "danmaku.boss.phase";
+
+/// Broadcast when a boss's last phase ends.
+pub const DEFEATED_EVENT: &'static str = "danmaku.boss.defeated";
+
+/// Why a boss phase ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhaseEnd {
+    /// The phase's health ran out.
+    Depleted,
+    /// The phase's duration ran out.
+    TimedOut,
+    /// The phase was ended early with [`BossPhase::skip`].
+    Skipped,
+}
+
+impl PhaseEnd {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Depleted => "depleted",
+            Self::TimedOut => "timed_out",
+            Self::Skipped => "skipped",
+        }
+    }
+}
+
+/// The state of a boss's current phase.
+#[derive(SimpleComponent)]
+pub struct BossPhase {
+    script: LuaRegistryKey,
+    thread: Option<LuaRegistryKey>,
+    phase_count: usize,
+    clear_delay: f32,
+    /// The index of the current phase, or `None` if the first phase hasn't started yet.
+    phase: Option<usize>,
+    name: Option<String>,
+    spellcard: bool,
+    hp: f32,
+    max_hp: f32,
+    elapsed: f32,
+    duration: Option<f32>,
+    skipped: bool,
+}
+
+impl BossPhase {
+    pub fn new<'lua>(lua: LuaContext<'lua>, script: LuaTable<'lua>) -> Result<Self> {
+        let phase_count = script.get::<_, LuaTable>("phases")?.len()? as usize;
+        ensure!(
+            phase_count > 0,
+            "a boss script must have at least one phase"
+        );
+        let clear_delay = script.get::<_, Option<f32>>("clear_delay")?.unwrap_or(0.);
+
+        Ok(Self {
+            script: lua.create_registry_value(script)?,
+            thread: None,
+            phase_count,
+            clear_delay,
+            phase: None,
+            name: None,
+            spellcard: false,
+            hp: 0.,
+            max_hp: 0.,
+            elapsed: 0.,
+            duration: None,
+            skipped: false,
+        })
+    }
+
+    /// The 0-based index of the current phase, if the fight has started.
+    pub fn phase(&self) -> Option<usize> {
+        self.phase
+    }
+
+    pub fn phase_count(&self) -> usize {
+        self.phase_count
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn is_spellcard(&self) -> bool {
+        self.spellcard
+    }
+
+    pub fn hp(&self) -> f32 {
+        self.hp
+    }
+
+    pub fn max_hp(&self) -> f32 {
+        self.max_hp
+    }
+
+    /// How long is left before the current phase times out, if it has a duration.
+    pub fn time_remaining(&self) -> Option<f32> {
+        self.duration.map(|d| (d - self.elapsed).max(0.))
+    }
+
+    /// Reduce the current phase's health. Damage dealt before the fight starts is
+    /// ignored.
+    pub fn damage(&mut self, amount: f32) {
+        if self.phase.is_some() {
+            self.hp = (self.hp - amount).max(0.);
+        }
+    }
+
+    /// End the current phase on the next update.
+    pub fn skip(&mut self) {
+        self.skipped = true;
+    }
+
+    fn tick(&mut self, dt: f32) -> Option<Option<PhaseEnd>> {
+        if self.phase.is_none() {
+            return Some(None);
+        }
+
+        self.elapsed += dt;
+        if self.skipped {
+            Some(Some(PhaseEnd::Skipped))
+        } else if self.hp <= 0. {
+            Some(Some(PhaseEnd::Depleted))
+        } else if self.duration.map_or(false, |d| self.elapsed >= d) {
+            Some(Some(PhaseEnd::TimedOut))
+        } else {
+            None
+        }
+    }
+}
+
+/// End the boss's current phase (if it's started) and move on to the next one.
+fn transition<'lua>(lua: LuaContext<'lua>, entity: Entity, ended: Option<PhaseEnd>) -> Result<()> {
+    let (world, danmaku) = lua.fetch::<(World, Danmaku)>()?;
+    let reason = ended.map(|end| end.as_str());
+
+    let next_phase = {
+        let world = world.borrow();
+        let mut boss = world.get_mut::<BossPhase>(entity)?;
+
+        if let Some(key) = boss.thread.take() {
+            let thread = lua.registry_value::<LuaThread>(&key)?;
+            lua.remove_registry_value(key)?;
+            if thread.status() != LuaThreadStatus::Unresumable {
+                lua.kill(thread, ())?;
+            }
+        }
+
+        if boss.phase.is_some() {
+            danmaku.borrow_mut().clear(&world, Some(boss.clear_delay));
+        }
+
+        let next = boss.phase.map_or(0, |i| i + 1);
+        if next >= boss.phase_count {
+            None
+        } else {
+            let script = lua.registry_value::<LuaTable>(&boss.script)?;
+            let phase = script
+                .get::<_, LuaTable>("phases")?
+                .get::<_, LuaTable>(next + 1)?;
+
+            boss.phase = Some(next);
+            boss.name = phase.get("name")?;
+            boss.spellcard = phase.get::<_, Option<bool>>("spellcard")?.unwrap_or(false);
+            boss.max_hp = phase.get::<_, Option<f32>>("hp")?.unwrap_or(f32::INFINITY);
+            boss.hp = boss.max_hp;
+            boss.elapsed = 0.;
+            boss.duration = phase.get("duration")?;
+            boss.skipped = false;
+
+            if let Some(run) = phase.get::<_, Option<LuaFunction>>("run")? {
+                let thread = lua.spawn(run, (LuaEntity::from(entity), phase.clone()))?;
+                boss.thread = Some(lua.create_registry_value(thread)?);
+            }
+
+            Some((next, boss.name.clone()))
+        }
+    };
+
+    match next_phase {
+        Some((index, name)) => {
+            lua.broadcast(
+                PHASE_EVENT,
+                (LuaEntity::from(entity), index + 1, name, reason),
+            )?;
+        }
+        None => {
+            let boss = world.borrow_mut().remove_one::<BossPhase>(entity)?;
+            lua.remove_registry_value(boss.script)?;
+            lua.broadcast(DEFEATED_EVENT, (LuaEntity::from(entity), reason))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Advances the phases of every [`BossPhase`] component. Phase timers stop while the
+/// gameplay channel is [paused](sludge::pause).
+pub struct BossSystem;
+
+impl System for BossSystem {
+    fn init(
+        &self,
+        _lua: LuaContext,
+        _local: &mut OwnedResources,
+        _global: Option<&SharedResources>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn update(&self, lua: LuaContext, resources: &UnifiedResources) -> Result<()> {
+        if pause::is_paused(resources, pause::GAMEPLAY) {
+            return Ok(());
+        }
+
+        let dt = timestep::fixed_dt(resources);
+        let transitions = resources
+            .fetch_one::<World>()?
+            .borrow()
+            .query::<&mut BossPhase>()
+            .iter()
+            .filter_map(|(e, mut boss)| boss.tick(dt).map(|ended| (e, ended)))
+            .collect::<Vec<_>>();
+
+        for (entity, ended) in transitions {
+            transition(lua, entity, ended)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) mod api {
+    use super::*;
+
+    fn with_boss<'lua, R>(
+        lua: LuaContext<'lua>,
+        entity: LuaEntity,
+        f: impl FnOnce(&mut BossPhase) -> R,
+    ) -> LuaResult<R> {
+        let world = lua.fetch_one::<World>()?;
+        let world = world.borrow();
+        let mut boss = world.get_mut::<BossPhase>(entity.into()).to_lua_err()?;
+        Ok(f(&mut *boss))
+    }
+
+    pub fn start<'lua>(
+        lua: LuaContext<'lua>,
+        (entity, script): (LuaEntity, LuaTable<'lua>),
+    ) -> LuaResult<()> {
+        let boss = BossPhase::new(lua, script).to_lua_err()?;
+        lua.fetch_one::<World>()?
+            .borrow_mut()
+            .insert_one(entity.into(), boss)
+            .to_lua_err()?;
+        Ok(())
+    }
+
+    pub fn damage<'lua>(
+        lua: LuaContext<'lua>,
+        (entity, amount): (LuaEntity, f32),
+    ) -> LuaResult<()> {
+        with_boss(lua, entity, |boss| boss.damage(amount))
+    }
+
+    pub fn skip<'lua>(lua: LuaContext<'lua>, entity: LuaEntity) -> LuaResult<()> {
+        with_boss(lua, entity, BossPhase::skip)
+    }
+
+    pub fn hp<'lua>(lua: LuaContext<'lua>, entity: LuaEntity) -> LuaResult<(f32, f32)> {
+        with_boss(lua, entity, |boss| (boss.hp(), boss.max_hp()))
+    }
+
+    pub fn phase<'lua>(
+        lua: LuaContext<'lua>,
+        entity: LuaEntity,
+    ) -> LuaResult<(Option<usize>, Option<String>, bool)> {
+        with_boss(lua, entity, |boss| {
+            (
+                boss.phase().map(|i| i + 1),
+                boss.name().map(str::to_owned),
+                boss.is_spellcard(),
+            )
+        })
+    }
+
+    pub fn time_remaining<'lua>(
+        lua: LuaContext<'lua>,
+        entity: LuaEntity,
+    ) -> LuaResult<Option<f32>> {
+        with_boss(lua, entity, |boss| boss.time_remaining())
+    }
+
+    pub fn load<'lua>(lua: LuaContext<'lua>) -> Result<LuaValue<'lua>> {
+        let t = lua.create_table_from(vec![
+            ("start", lua.create_function(start)?),
+            ("damage", lua.create_function(damage)?),
+            ("skip", lua.create_function(skip)?),
+            ("hp", lua.create_function(hp)?),
+            ("phase", lua.create_function(phase)?),
+            ("time_remaining", lua.create_function(time_remaining)?),
+        ])?;
+        t.set("PHASE_EVENT", PHASE_EVENT)?;
+        t.set("DEFEATED_EVENT", DEFEATED_EVENT)?;
+        Ok(LuaValue::Table(t))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &'static str = r#"
+        ticks = {}
+        local function counting(name)
+            return function(boss, phase)
+                while true do
+                    ticks[name] = (ticks[name] or 0) + 1
+                    yield(1)
+                end
+            end
+        end
+
+        return {
+            clear_delay = 0.5,
+            phases = {
+                { name = "opener", hp = 100, run = counting("opener") },
+                { name = "timed", duration = 0.5, run = counting("timed") },
+                { name = "Sign \"Last\"", spellcard = true, hp = 50 },
+            },
+        }
+    "#;
+
+    fn boss_space(fps: u32) -> Result<(Space, Entity)> {
+        let mut space = Space::builder().with_fixed_fps(fps).build()?;
+        space.resources().borrow_mut().insert(Danmaku::new());
+        space.register(BossSystem, "Boss", &[])?;
+
+        let boss = space.world()?.borrow_mut().spawn(());
+        let phase = space.lua().context(|lua| -> Result<_> {
+            BossPhase::new(lua, lua.load(SCRIPT).eval::<LuaTable>()?)
+        })?;
+        space.world()?.borrow_mut().insert_one(boss, phase)?;
+
+        Ok((space, boss))
+    }
+
+    fn with_boss<R>(space: &Space, boss: Entity, f: impl FnOnce(&mut BossPhase) -> R) -> R {
+        let world = space.world().unwrap();
+        let world = world.borrow();
+        let mut phase = world.get_mut::<BossPhase>(boss).unwrap();
+        f(&mut *phase)
+    }
+
+    fn ticks(space: &Space, phase: &str) -> Result<u32> {
+        Ok(space
+            .lua()
+            .context(|lua| {
+                lua.globals()
+                    .get::<_, LuaTable>("ticks")?
+                    .get::<_, Option<u32>>(phase)
+            })?
+            .unwrap_or(0))
+    }
+
+    #[test]
+    fn phases_end_on_depletion_timeout_and_skip() -> Result<()> {
+        let (mut space, boss) = boss_space(sludge::FIXED_FPS)?;
+
+        with_boss(&space, boss, |phase| phase.damage(1000.));
+        assert_eq!(with_boss(&space, boss, |phase| phase.phase()), None);
+
+        space.fixed_update()?;
+        with_boss(&space, boss, |phase| {
+            assert_eq!(phase.phase(), Some(0));
+            assert_eq!(phase.name(), Some("opener"));
+            assert_eq!((phase.hp(), phase.max_hp()), (100., 100.));
+            assert_eq!(phase.time_remaining(), None);
+            phase.damage(40.);
+            assert_eq!(phase.hp(), 60.);
+        });
+
+        space.fixed_update()?;
+        assert_eq!(with_boss(&space, boss, |phase| phase.phase()), Some(0));
+        with_boss(&space, boss, |phase| phase.damage(100.));
+        space.fixed_update()?;
+        with_boss(&space, boss, |phase| {
+            assert_eq!(phase.phase(), Some(1));
+            assert_eq!(phase.name(), Some("timed"));
+            assert_eq!(phase.hp(), f32::INFINITY);
+            assert_eq!(phase.time_remaining(), Some(0.5));
+        });
+
+        // The opener's thread was killed when its phase ended.
+        let opener_ticks = ticks(&space, "opener")?;
+        let mut updates = 0;
+        while with_boss(&space, boss, |phase| phase.phase()) == Some(1) {
+            space.fixed_update()?;
+            updates += 1;
+            assert!(updates <= 31, "the timed phase never timed out");
+        }
+        assert!(updates >= 30);
+        assert_eq!(ticks(&space, "opener")?, opener_ticks);
+        assert!(ticks(&space, "timed")? > 0);
+
+        with_boss(&space, boss, |phase| {
+            assert_eq!(phase.phase(), Some(2));
+            assert!(phase.is_spellcard());
+            phase.skip();
+        });
+        space.fixed_update()?;
+        assert!(space.world()?.borrow().get::<BossPhase>(boss).is_err());
+        assert!(space.world()?.borrow().contains(boss));
+
+        Ok(())
+    }
+
+    #[test]
+    fn phase_durations_follow_the_space_timestep() -> Result<()> {
+        let (mut space, boss) = boss_space(10)?;
+
+        space.fixed_update()?;
+        with_boss(&space, boss, |phase| phase.damage(100.));
+        space.fixed_update()?;
+        assert_eq!(with_boss(&space, boss, |phase| phase.phase()), Some(1));
+
+        let mut updates = 0;
+        while with_boss(&space, boss, |phase| phase.phase()) == Some(1) {
+            space.fixed_update()?;
+            updates += 1;
+            assert!(updates <= 6, "the timed phase never timed out");
+        }
+        assert!(updates >= 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn boss_scripts_need_phases() {
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let script = lua.load("{ phases = {} }").eval::<LuaTable>().unwrap();
+            assert!(BossPhase::new(lua, script).is_err());
+            let script = lua.load("{}").eval::<LuaTable>().unwrap();
+            assert!(BossPhase::new(lua, script).is_err());
+        });
+    }
+}
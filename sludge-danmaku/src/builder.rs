@@ -8,6 +8,8 @@ use ::{
 
 use crate::{
     bullet::{BulletTypeId, Bundler},
+    components::{CurveId, Layers},
+    hit::UserDataId,
     pattern::Pattern,
     DanmakuResourceExt, SharedRng, RNG_REGISTRY_KEY,
 };
@@ -42,6 +44,26 @@ pub struct Parameters {
     ///
     /// Duration is in seconds.
     pub duration: f32,
+
+    /// Curve is a [`SampledCurve`](crate::SampledCurve) registered with the `Danmaku`
+    /// resource, for bullets which follow a baked trajectory with `ParametricMotion`.
+    /// Like `destination`, it is interpreted relative to `position`, and it is meant
+    /// to be followed over `duration`.
+    pub curve: Option<CurveId>,
+
+    /// Layers are the collision layers fired bullets should be put on, with
+    /// `Projectile::with_layers`. They default to every layer.
+    pub layers: Layers,
+
+    /// Delay is how many ticks of the `DanmakuSystem` to wait before spawning fired
+    /// bullets. Bullets with no delay are spawned immediately; the rest are held by
+    /// the `Danmaku` resource until their time comes.
+    pub delay: u32,
+
+    /// Data is a value interned with `Danmaku::intern_user_data`, attached to fired
+    /// bullets as a `BulletUserData` component and handed to Lua along with their hits
+    /// and grazes.
+    pub data: Option<UserDataId>,
 }
 
 impl Default for Parameters {
@@ -52,6 +74,10 @@ impl Default for Parameters {
             accel: Velocity2::zero(),
             destination: Isometry2::identity(),
             duration: 0.,
+            curve: None,
+            layers: Layers::ALL,
+            delay: 0,
+            data: None,
         }
     }
 }
@@ -101,6 +127,30 @@ impl Parameters {
         self
     }
 
+    #[inline]
+    pub fn curve(mut self, curve: CurveId) -> Self {
+        self.curve = Some(curve);
+        self
+    }
+
+    #[inline]
+    pub fn layers(mut self, layers: Layers) -> Self {
+        self.layers = layers;
+        self
+    }
+
+    #[inline]
+    pub fn data(mut self, data: UserDataId) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    #[inline]
+    pub fn delayed(mut self, frames: u32) -> Self {
+        self.delay = self.delay.saturating_add(frames);
+        self
+    }
+
     #[inline]
     pub fn to_velocity(&self) -> Velocity2<f32> {
         self.speed.transformed(&self.position)
@@ -142,6 +192,10 @@ pub enum Op {
     AimAt(Point2<f32>),
     Destination(Isometry2<f32>),
     Duration(f32),
+    Curve(CurveId),
+    Layers(Layers),
+    Data(UserDataId),
+    Delay(u32),
     Pop,
     BulletType(BulletTypeId),
     Fire,
@@ -166,7 +220,9 @@ impl<'lua> ToLuaMulti<'lua> for Op {
                 ps.destination.translation.y,
                 ps.destination.rotation.re,
                 ps.destination.rotation.im,
-                ps.duration,
+                // Tuples only convert up to sixteen elements, but the last element
+                // may itself be a multi-value.
+                (ps.duration, ps.curve, ps.layers, ps.delay, ps.data),
             )
                 .to_lua_multi(lua),
             Op::Push(None) => ("push",).to_lua_multi(lua),
@@ -200,6 +256,10 @@ impl<'lua> ToLuaMulti<'lua> for Op {
             )
                 .to_lua_multi(lua),
             Op::Duration(t) => ("duration", t).to_lua_multi(lua),
+            Op::Curve(c) => ("curve", c).to_lua_multi(lua),
+            Op::Layers(l) => ("layers", l).to_lua_multi(lua),
+            Op::Data(d) => ("data", d).to_lua_multi(lua),
+            Op::Delay(frames) => ("delay", frames).to_lua_multi(lua),
             Op::Pop => ("pop",).to_lua_multi(lua),
             Op::BulletType(bt) => ("bullet_type", bt.to_lua(lua)).to_lua_multi(lua),
             Op::Fire => ("fire",).to_lua_multi(lua),
@@ -209,12 +269,12 @@ impl<'lua> ToLuaMulti<'lua> for Op {
 
 impl<'lua> FromLuaMulti<'lua> for Op {
     fn from_lua_multi(values: LuaMultiValue<'lua>, lua: LuaContext<'lua>) -> LuaResult<Self> {
-        let mut vec = values.into_iter();
+        let mut vec = values.into_iter().peekable();
         let op_name = LuaString::from_lua(vec.next().unwrap(), lua)?;
 
         match op_name.to_str()? {
             "push" => {
-                if !vec.is_empty() {
+                if vec.peek().is_some() {
                     let position = {
                         let x = f32::from_lua(vec.next().unwrap(), lua)?;
                         let y = f32::from_lua(vec.next().unwrap(), lua)?;
@@ -254,12 +314,25 @@ impl<'lua> FromLuaMulti<'lua> for Op {
                         )
                     };
                     let duration = f32::from_lua(vec.next().unwrap(), lua)?;
+                    let curve =
+                        Option::<CurveId>::from_lua(vec.next().unwrap_or(LuaValue::Nil), lua)?;
+                    let layers =
+                        Option::<Layers>::from_lua(vec.next().unwrap_or(LuaValue::Nil), lua)?
+                            .unwrap_or(Layers::ALL);
+                    let delay = Option::<u32>::from_lua(vec.next().unwrap_or(LuaValue::Nil), lua)?
+                        .unwrap_or(0);
+                    let data =
+                        Option::<UserDataId>::from_lua(vec.next().unwrap_or(LuaValue::Nil), lua)?;
                     Ok(Op::Push(Some(Parameters {
                         position,
                         speed,
                         accel,
                         destination,
                         duration,
+                        curve,
+                        layers,
+                        delay,
+                        data,
                     })))
                 } else {
                     Ok(Op::Push(None))
@@ -331,7 +404,7 @@ impl<'lua> FromLuaMulti<'lua> for Op {
                 let destination = {
                     let x = f32::from_lua(vec.next().unwrap(), lua)?;
                     let y = f32::from_lua(vec.next().unwrap(), lua)?;
-                    let rot = if !vec.is_empty() {
+                    let rot = if vec.peek().is_some() {
                         let re = f32::from_lua(vec.next().unwrap(), lua)?;
                         let im = f32::from_lua(vec.next().unwrap(), lua)?;
                         UnitComplex::new_unchecked(Complex::new(re, im))
@@ -347,6 +420,10 @@ impl<'lua> FromLuaMulti<'lua> for Op {
                 let duration = f32::from_lua(vec.next().unwrap(), lua)?;
                 Ok(Op::Duration(duration))
             }
+            "curve" => Ok(Op::Curve(CurveId::from_lua(vec.next().unwrap(), lua)?)),
+            "layers" => Ok(Op::Layers(Layers::from_lua(vec.next().unwrap(), lua)?)),
+            "data" => Ok(Op::Data(UserDataId::from_lua(vec.next().unwrap(), lua)?)),
+            "delay" => Ok(Op::Delay(u32::from_lua(vec.next().unwrap(), lua)?)),
             "pop" => Ok(Op::Pop),
             "bullet_type" => Ok(Op::BulletType(BulletTypeId::from_lua(
                 vec.next().unwrap(),
@@ -444,6 +521,27 @@ pub trait PatternBuilder<'lua> {
         self.op(Op::Duration(duration))
     }
 
+    #[inline]
+    fn curve(&mut self, curve: CurveId) -> Result<()> {
+        self.op(Op::Curve(curve))
+    }
+
+    #[inline]
+    fn layers(&mut self, layers: Layers) -> Result<()> {
+        self.op(Op::Layers(layers))
+    }
+
+    #[inline]
+    fn data(&mut self, data: UserDataId) -> Result<()> {
+        self.op(Op::Data(data))
+    }
+
+    /// Hold back bullets fired from here on for another `frames` ticks.
+    #[inline]
+    fn delay(&mut self, frames: u32) -> Result<()> {
+        self.op(Op::Delay(frames))
+    }
+
     #[inline]
     fn pop(&mut self) -> Result<()> {
         self.op(Op::Pop)
@@ -576,6 +674,11 @@ pub struct Batch<'lua> {
     entities: Vec<Entity>,
     lua: LuaContext<'lua>,
     rng: SharedRng<XorShiftRng>,
+    /// How many bullets can be fired before the rest are set aside as overflow.
+    budget: Option<usize>,
+    fired: usize,
+    overflow: Vec<(BulletTypeId, Parameters)>,
+    delayed: Vec<(BulletTypeId, Parameters)>,
 }
 
 impl<'lua> Batch<'lua> {
@@ -597,9 +700,30 @@ impl<'lua> Batch<'lua> {
             entities: Vec::new(),
             lua,
             rng,
+            budget: None,
+            fired: 0,
+            overflow: Vec::new(),
+            delayed: Vec::new(),
         })
     }
 
+    /// Limit how many bullets this batch will spawn. Bullets fired past the limit are
+    /// kept aside, and can be taken with [`Batch::take_overflow`].
+    pub fn set_budget(&mut self, budget: Option<usize>) {
+        self.budget = budget;
+    }
+
+    /// Take the bullets fired past the batch's budget, along with their bullet types.
+    pub fn take_overflow(&mut self) -> Vec<(BulletTypeId, Parameters)> {
+        std::mem::take(&mut self.overflow)
+    }
+
+    /// Take the bullets fired with a delay, along with their bullet types. These don't
+    /// count against the batch's budget.
+    pub fn take_delayed(&mut self) -> Vec<(BulletTypeId, Parameters)> {
+        std::mem::take(&mut self.delayed)
+    }
+
     pub fn spawn(
         &mut self,
         resources: &UnifiedResources,
@@ -674,6 +798,22 @@ impl<'lua> PatternBuilder<'lua> for Batch<'lua> {
                 let top = self.parameter_stack.last_mut().unwrap();
                 top.duration = t;
             }
+            Op::Curve(c) => {
+                let top = self.parameter_stack.last_mut().unwrap();
+                top.curve = Some(c);
+            }
+            Op::Layers(l) => {
+                let top = self.parameter_stack.last_mut().unwrap();
+                top.layers = l;
+            }
+            Op::Data(d) => {
+                let top = self.parameter_stack.last_mut().unwrap();
+                top.data = Some(d);
+            }
+            Op::Delay(frames) => {
+                let top = self.parameter_stack.last_mut().unwrap();
+                *top = top.delayed(frames);
+            }
             Op::Pop => {
                 self.parameter_stack.pop().unwrap();
                 self.bullet_type_stack.pop();
@@ -686,7 +826,23 @@ impl<'lua> PatternBuilder<'lua> for Batch<'lua> {
                 self.bundler.set_id(bt_id);
             }
             Op::Fire => {
-                self.bundler.push(*self.parameter_stack.last().unwrap());
+                let params = *self.parameter_stack.last().unwrap();
+                if params.delay > 0 {
+                    // Bullets fired without a bullet type are thrown away by the
+                    // bundler, so there's no point holding on to them.
+                    if let Some(&bullet_type) = self.bullet_type_stack.last() {
+                        self.delayed.push((bullet_type, params));
+                    }
+                    return Ok(());
+                }
+
+                match (self.budget, self.bullet_type_stack.last()) {
+                    (Some(budget), Some(&bullet_type)) if self.fired >= budget => {
+                        self.overflow.push((bullet_type, params));
+                    }
+                    _ => self.bundler.push(params),
+                }
+                self.fired += 1;
             }
         }
 
@@ -811,6 +967,31 @@ impl LuaUserData for LuaPatternBuilderUserData {
                 .call::<_, ()>(("duration", t))
         });
 
+        methods.add_function("curve", |_lua, (this, curve): (LuaAnyUserData, CurveId)| {
+            this.get_user_value::<LuaFunction>()?
+                .call::<_, ()>(("curve", curve))
+        });
+
+        methods.add_function(
+            "layer",
+            |lua, (this, names): (LuaAnyUserData, LuaMultiValue)| {
+                let layers = crate::api::layers_from_lua(lua, names)?;
+                this.get_user_value::<LuaFunction>()?
+                    .call::<_, ()>(("layers", layers))
+            },
+        );
+
+        methods.add_function("data", |lua, (this, data): (LuaAnyUserData, LuaValue)| {
+            let data = crate::hit::api::intern(lua, data)?;
+            this.get_user_value::<LuaFunction>()?
+                .call::<_, ()>(("data", data))
+        });
+
+        methods.add_function("delay", |_lua, (this, frames): (LuaAnyUserData, u32)| {
+            this.get_user_value::<LuaFunction>()?
+                .call::<_, ()>(("delay", frames))
+        });
+
         methods.add_function("pop", |_lua, this: LuaAnyUserData| {
             this.get_user_value::<LuaFunction>()?.call::<_, ()>("pop")
         });
@@ -6,7 +6,11 @@ use {
     thunderdome::{Arena, Index},
 };
 
-use crate::{builder::Parameters, DanmakuResourceExt};
+use crate::{
+    builder::Parameters,
+    hit::{BulletUserData, UserDataTable},
+    DanmakuResourceExt,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct BulletTypeId(pub(crate) Index);
@@ -69,6 +73,7 @@ trait ErasedMonoBundler: Send + Sync {
         &mut self,
         resources: &UnifiedResources,
         world: &Shared<'static, World>,
+        user_data: &UserDataTable,
         entities: &mut Vec<Entity>,
     ) -> Result<()>;
 }
@@ -82,13 +87,34 @@ impl<T: BulletData> ErasedMonoBundler for MonoBundler<T> {
         &mut self,
         resources: &UnifiedResources,
         world: &Shared<'static, World>,
+        user_data: &UserDataTable,
         entities: &mut Vec<Entity>,
     ) -> Result<()> {
         self.data
             .bundle(resources, &self.params, self.id, &mut self.bundles)?;
-        world
-            .borrow_mut()
-            .spawn_batch_into_buf(self.bundles.drain(..), entities);
+        // Bullet types are free to skip parameters, in which case there's no telling
+        // which bullet was fired with which data.
+        let aligned = self.bundles.len() == self.params.len();
+        let start = entities.len();
+        let mut world = world.borrow_mut();
+        world.spawn_batch_into_buf(self.bundles.drain(..), entities);
+
+        if aligned {
+            for (&entity, params) in entities[start..].iter().zip(&self.params) {
+                if let Some(data) = params.data.and_then(|id| user_data.get(id)) {
+                    world.insert_one::<BulletUserData>(entity, data.clone())?;
+                }
+            }
+        } else if self.params.iter().any(|params| params.data.is_some()) {
+            log::warn!(
+                "bullet type {:?} bundled {} bullets from {} parameters, so the data \
+                 attached to them was dropped",
+                self.id,
+                entities.len() - start,
+                self.params.len(),
+            );
+        }
+
         Ok(())
     }
 }
@@ -98,10 +124,14 @@ pub struct Bundler {
     buf: Vec<Parameters>,
     monos: HashMap<BulletTypeId, Box<dyn ErasedMonoBundler>>,
     bullet_types: Arc<RwLock<BulletTypes>>,
+    user_data: Arc<RwLock<UserDataTable>>,
 }
 
 impl Bundler {
-    pub(crate) fn new(bullet_types: Arc<RwLock<BulletTypes>>) -> Self {
+    pub(crate) fn new(
+        bullet_types: Arc<RwLock<BulletTypes>>,
+        user_data: Arc<RwLock<UserDataTable>>,
+    ) -> Self {
         let monos = bullet_types
             .read()
             .unwrap_or_else(|p| p.into_inner())
@@ -119,6 +149,7 @@ impl Bundler {
             buf: Vec::new(),
             monos,
             bullet_types,
+            user_data,
         }
     }
 
@@ -167,8 +198,9 @@ impl Bundler {
         entities: &mut Vec<Entity>,
     ) -> Result<()> {
         self.reset();
+        let user_data = self.user_data.read().unwrap_or_else(|p| p.into_inner());
         for (_, mono) in self.monos.iter_mut() {
-            mono.bundle_erased(resources, world, entities)?;
+            mono.bundle_erased(resources, world, &user_data, entities)?;
         }
         Ok(())
     }
@@ -0,0 +1,498 @@
+//! Caps on how many bullets can be alive at once, globally and per group.
+//!
+//! A pattern which spawns more than it should, or a script which spawns it every
+//! frame, can fill the screen with bullets faster than they can be updated. The
+//! [`Danmaku`] resource can be given a global cap on live bullets with
+//! [`Danmaku::set_bullet_cap`], and every [`Group`] can be given a cap of its own with
+//! `group:set_cap(n)`. Bullets fired by `danmaku.spawn` past either cap are handled
+//! according to the [`CapPolicy`]: dropped, deferred until there's room for them, or
+//! handed to a Lua callback which decides.
+//!
+//! ```lua
+//! danmaku.set_bullet_cap(2000, "defer")
+//!
+//! local wave = danmaku.new_group()
+//! wave:set_cap(200)
+//!
+//! sludge.thread.spawn(function()
+//!     while true do
+//!         local _, _, overflow = yield(danmaku.CAP_REACHED_EVENT)
+//!         sludge.log.warn(overflow .. " bullets over the cap")
+//!     end
+//! end)
+//! ```
+//!
+//! Every time a spawn runs into a cap, [`CAP_REACHED_EVENT`] is broadcast with how
+//! many bullets didn't fit and the group they were fired into, if any. Deferred
+//! bullets are spawned oldest first by the [`DanmakuSystem`](crate::DanmakuSystem) as
+//! room frees up, and are thrown out by [`Danmaku::clear_layers`] if they're on any of
+//! the cleared layers.
+
+use ::{
+    sludge::{prelude::*, resources::Shared},
+    std::collections::VecDeque,
+};
+
+use crate::{
+    builder::Parameters, bullet::BulletTypeId, components::Layers, pattern::Group, Danmaku,
+};
+
+/// Broadcast when bullets are fired past the global cap or a group's cap.
+pub const CAP_REACHED_EVENT: &'static str = "danmaku.cap_reached";
+
+const CAP_CALLBACK_REGISTRY_KEY: &'static str = "danmaku.cap_callback";
+
+/// What happens to bullets fired past a cap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapPolicy {
+    /// Throw the extra bullets away.
+    Drop,
+    /// Spawn the extra bullets on later ticks, once there's room.
+    Defer,
+    /// Call the function registered from Lua with `danmaku.set_bullet_cap(n, f)` with
+    /// how many bullets didn't fit and their group, and defer them if it returns
+    /// `"defer"`. Anything else drops them.
+    Callback,
+}
+
+impl Default for CapPolicy {
+    fn default() -> Self {
+        Self::Drop
+    }
+}
+
+/// Counters kept by the [`Danmaku`] resource about capped spawns.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CapStats {
+    /// How many bullets have been spawned by `danmaku.spawn`, including deferred ones.
+    pub spawned: u64,
+    /// How many bullets were thrown away for being over a cap.
+    pub dropped: u64,
+    /// How many bullets have been deferred for being over a cap.
+    pub deferred: u64,
+    /// How many spawns ran into a cap.
+    pub cap_hits: u64,
+}
+
+/// Bullets from a single spawn which didn't fit under the caps.
+#[derive(Debug)]
+pub(crate) struct DeferredSpawn {
+    pub bullets: VecDeque<(BulletTypeId, Parameters)>,
+    pub group: Option<LuaRegistryKey>,
+}
+
+impl Danmaku {
+    /// Cap how many bullets can be alive at once, or remove the cap with `None`.
+    pub fn set_bullet_cap(&mut self, cap: Option<usize>) {
+        self.bullet_cap = cap;
+    }
+
+    pub fn bullet_cap(&self) -> Option<usize> {
+        self.bullet_cap
+    }
+
+    pub fn set_cap_policy(&mut self, policy: CapPolicy) {
+        self.cap_policy = policy;
+    }
+
+    pub fn cap_policy(&self) -> CapPolicy {
+        self.cap_policy
+    }
+
+    pub fn cap_stats(&self) -> CapStats {
+        self.cap_stats
+    }
+
+    /// How many bullets were alive as of the last update, plus any spawned since.
+    pub fn live_bullets(&self) -> usize {
+        self.live_bullets
+    }
+
+    /// How many deferred bullets are waiting to be spawned.
+    pub fn pending_bullets(&self) -> usize {
+        self.deferred.iter().map(|d| d.bullets.len()).sum()
+    }
+
+    /// How many more bullets can be spawned under the global cap, if there is one.
+    pub(crate) fn spawn_budget(&self) -> Option<usize> {
+        self.bullet_cap
+            .map(|cap| cap.saturating_sub(self.live_bullets))
+    }
+
+    pub(crate) fn record_spawned(&mut self, count: usize) {
+        self.live_bullets += count;
+        self.cap_stats.spawned += count as u64;
+        if count > 0 {
+            // New bullets should be hittable before the next update moves anything.
+            self.bullet_grid.get_mut().stale = true;
+        }
+    }
+
+    /// Throw out deferred bullets on any of `layers`.
+    pub(crate) fn clear_deferred(&mut self, layers: Layers) {
+        for deferred in self.deferred.iter_mut() {
+            deferred
+                .bullets
+                .retain(|(_, params)| !params.layers.intersects(layers));
+        }
+        self.deferred
+            .retain(|deferred| !deferred.bullets.is_empty());
+    }
+}
+
+impl Group {
+    /// Cap how many bullets in this group can be alive at once.
+    pub fn set_cap(&mut self, cap: Option<usize>) {
+        self.cap = cap;
+    }
+
+    pub fn cap(&self) -> Option<usize> {
+        self.cap
+    }
+
+    /// Forget about bullets which have been despawned, and return how many more can be
+    /// spawned into the group under its cap, if it has one.
+    pub(crate) fn spawn_budget(&mut self, world: &World) -> Option<usize> {
+        let cap = self.cap?;
+        self.entities.retain(|&e| world.contains(e));
+        Some(cap.saturating_sub(self.entities.len()))
+    }
+}
+
+/// The smaller of two optional budgets, where `None` means unlimited.
+pub(crate) fn min_budget(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Deal with bullets fired past a cap by `danmaku.spawn`, according to the cap policy.
+pub(crate) fn overflowed<'lua>(
+    lua: LuaContext<'lua>,
+    bullets: Vec<(BulletTypeId, Parameters)>,
+    group: Option<LuaAnyUserData<'lua>>,
+) -> Result<()> {
+    let count = bullets.len();
+    let danmaku = lua.fetch_one::<Danmaku>()?;
+    let policy = danmaku.borrow().cap_policy();
+
+    let defer = match policy {
+        CapPolicy::Drop => false,
+        CapPolicy::Defer => true,
+        CapPolicy::Callback => {
+            match lua.named_registry_value::<_, Option<LuaFunction>>(CAP_CALLBACK_REGISTRY_KEY)? {
+                Some(callback) => {
+                    let action = callback.call::<_, Option<String>>((count, group.clone()))?;
+                    action.as_deref() == Some("defer")
+                }
+                None => false,
+            }
+        }
+    };
+
+    {
+        let mut danmaku = danmaku.borrow_mut();
+        danmaku.cap_stats.cap_hits += 1;
+
+        if defer {
+            danmaku.cap_stats.deferred += count as u64;
+            danmaku.deferred.push_back(DeferredSpawn {
+                bullets: bullets.into(),
+                group: group
+                    .clone()
+                    .map(|ud| lua.create_registry_value(ud))
+                    .transpose()?,
+            });
+        } else {
+            danmaku.cap_stats.dropped += count as u64;
+        }
+    }
+
+    lua.broadcast(CAP_REACHED_EVENT, (count, group))?;
+
+    Ok(())
+}
+
+/// Spawn bullets fired earlier, without regard for any caps, and count them as spawned.
+pub(crate) fn spawn_bullets(
+    resources: &UnifiedResources,
+    world: &Shared<'static, World>,
+    danmaku: &Shared<'static, Danmaku>,
+    bullets: impl IntoIterator<Item = (BulletTypeId, Parameters)>,
+) -> Result<Vec<Entity>> {
+    let mut bundler = danmaku.borrow().bundler().detach();
+    let mut current = None;
+    for (bullet_type, params) in bullets {
+        if current != Some(bullet_type) {
+            bundler.set_id(bullet_type);
+            current = Some(bullet_type);
+        }
+        bundler.push(params);
+    }
+
+    let mut entities = Vec::new();
+    bundler.flush(resources, world, &mut entities)?;
+    danmaku.borrow_mut().record_spawned(entities.len());
+    Ok(entities)
+}
+
+/// Spawn as many deferred bullets as the caps allow, oldest first.
+pub(crate) fn spawn_deferred(lua: LuaContext) -> Result<()> {
+    let resources = lua.resources();
+    let world = resources.fetch_one::<World>()?;
+    let danmaku = resources.fetch_one::<Danmaku>()?;
+
+    loop {
+        let mut deferred = match danmaku.borrow_mut().deferred.pop_front() {
+            Some(deferred) => deferred,
+            None => break,
+        };
+
+        let group_ud = deferred
+            .group
+            .as_ref()
+            .map(|key| lua.registry_value::<LuaAnyUserData>(key))
+            .transpose()?;
+        let mut group = group_ud
+            .as_ref()
+            .map(LuaAnyUserData::borrow_mut::<Group>)
+            .transpose()?;
+
+        let budget = min_budget(
+            danmaku.borrow().spawn_budget(),
+            group
+                .as_deref_mut()
+                .and_then(|group| group.spawn_budget(&world.borrow())),
+        );
+        let count = budget.map_or(deferred.bullets.len(), |b| b.min(deferred.bullets.len()));
+
+        if count > 0 {
+            let entities = spawn_bullets(
+                &resources,
+                &world,
+                &danmaku,
+                deferred.bullets.drain(..count),
+            )?;
+            if let Some(group) = group.as_deref_mut() {
+                group.adopt(&mut world.borrow_mut(), &entities);
+            }
+        }
+
+        if !deferred.bullets.is_empty() {
+            danmaku.borrow_mut().deferred.push_front(deferred);
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) mod api {
+    use super::*;
+
+    /// `danmaku.set_bullet_cap(cap, policy)`, where `cap` is a number or `nil` and
+    /// `policy` is `"drop"`, `"defer"` or a callback function. The policy defaults to
+    /// `"drop"`.
+    pub fn set_bullet_cap<'lua>(
+        lua: LuaContext<'lua>,
+        (cap, policy): (Option<usize>, Option<LuaValue<'lua>>),
+    ) -> LuaResult<()> {
+        let policy = match policy {
+            None | Some(LuaValue::Nil) => CapPolicy::Drop,
+            Some(LuaValue::Function(callback)) => {
+                lua.set_named_registry_value(CAP_CALLBACK_REGISTRY_KEY, callback)?;
+                CapPolicy::Callback
+            }
+            Some(other) => match String::from_lua(other, lua)?.as_str() {
+                "drop" => CapPolicy::Drop,
+                "defer" => CapPolicy::Defer,
+                s => {
+                    return Err(anyhow!(
+                        "bad cap policy {} (expected \"drop\", \"defer\" or a function)",
+                        s
+                    ))
+                    .to_lua_err()
+                }
+            },
+        };
+
+        let danmaku = lua.fetch_one::<Danmaku>()?;
+        let mut danmaku = danmaku.borrow_mut();
+        danmaku.set_bullet_cap(cap);
+        danmaku.set_cap_policy(policy);
+        Ok(())
+    }
+
+    pub fn bullet_count<'lua>(lua: LuaContext<'lua>, (): ()) -> LuaResult<usize> {
+        Ok(lua.fetch_one::<Danmaku>()?.borrow().live_bullets())
+    }
+
+    pub fn cap_stats<'lua>(lua: LuaContext<'lua>, (): ()) -> LuaResult<LuaTable<'lua>> {
+        let danmaku = lua.fetch_one::<Danmaku>()?;
+        let danmaku = danmaku.borrow();
+        let stats = danmaku.cap_stats();
+        lua.create_table_from(vec![
+            ("live", danmaku.live_bullets() as u64),
+            ("pending", danmaku.pending_bullets() as u64),
+            ("spawned", stats.spawned),
+            ("dropped", stats.dropped),
+            ("deferred", stats.deferred),
+            ("cap_hits", stats.cap_hits),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bullets(layers: &[u32]) -> Vec<(BulletTypeId, Parameters)> {
+        let bullet_type = BulletTypeId(thunderdome::Arena::new().insert(()));
+        layers
+            .iter()
+            .map(|&layer| {
+                let mut params = Parameters::new();
+                params.layers = Layers(layer);
+                (bullet_type, params)
+            })
+            .collect()
+    }
+
+    fn danmaku_space() -> Result<Space> {
+        let space = Space::new()?;
+        space.resources().borrow_mut().insert(Danmaku::new());
+        Ok(space)
+    }
+
+    #[test]
+    fn min_budget_treats_none_as_unlimited() {
+        assert_eq!(min_budget(None, None), None);
+        assert_eq!(min_budget(Some(3), None), Some(3));
+        assert_eq!(min_budget(None, Some(5)), Some(5));
+        assert_eq!(min_budget(Some(3), Some(5)), Some(3));
+        assert_eq!(min_budget(Some(0), Some(5)), Some(0));
+    }
+
+    #[test]
+    fn spawn_budget_saturates_at_the_cap() {
+        let mut danmaku = Danmaku::new();
+        assert_eq!(danmaku.spawn_budget(), None);
+
+        danmaku.set_bullet_cap(Some(4));
+        danmaku.record_spawned(3);
+        assert_eq!(danmaku.spawn_budget(), Some(1));
+        danmaku.record_spawned(3);
+        assert_eq!(danmaku.spawn_budget(), Some(0));
+        assert_eq!(danmaku.cap_stats().spawned, 6);
+    }
+
+    #[test]
+    fn clear_deferred_drops_bullets_on_cleared_layers() {
+        let mut danmaku = Danmaku::new();
+        danmaku.deferred.push_back(DeferredSpawn {
+            bullets: bullets(&[0b01, 0b10, 0b11]).into(),
+            group: None,
+        });
+        danmaku.deferred.push_back(DeferredSpawn {
+            bullets: bullets(&[0b01]).into(),
+            group: None,
+        });
+        assert_eq!(danmaku.pending_bullets(), 4);
+
+        danmaku.clear_deferred(Layers(0b01));
+        assert_eq!(danmaku.pending_bullets(), 1);
+        assert_eq!(danmaku.deferred.len(), 1, "empty spawns are thrown out");
+        assert_eq!(danmaku.deferred[0].bullets[0].1.layers, Layers(0b10));
+
+        danmaku.clear_deferred(Layers::ALL);
+        assert!(danmaku.deferred.is_empty());
+    }
+
+    #[test]
+    fn drop_and_defer_policies() -> Result<()> {
+        let space = danmaku_space()?;
+        let danmaku = space.fetch_one::<Danmaku>()?;
+
+        space
+            .lua()
+            .context(|lua| overflowed(lua, bullets(&[1, 1]), None))?;
+        assert_eq!(danmaku.borrow().pending_bullets(), 0);
+        assert_eq!(
+            danmaku.borrow().cap_stats(),
+            CapStats {
+                dropped: 2,
+                cap_hits: 1,
+                ..CapStats::default()
+            }
+        );
+
+        danmaku.borrow_mut().set_cap_policy(CapPolicy::Defer);
+        space
+            .lua()
+            .context(|lua| overflowed(lua, bullets(&[1, 1, 1]), None))?;
+        assert_eq!(danmaku.borrow().pending_bullets(), 3);
+        assert_eq!(
+            danmaku.borrow().cap_stats(),
+            CapStats {
+                dropped: 2,
+                deferred: 3,
+                cap_hits: 2,
+                ..CapStats::default()
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn callback_policy_defers_on_request() -> Result<()> {
+        let space = danmaku_space()?;
+        let danmaku = space.fetch_one::<Danmaku>()?;
+
+        space.lua().context(|lua| -> Result<()> {
+            let callback = lua
+                .load(
+                    r#"
+                    return function(count, group)
+                        overflows = (overflows or 0) + 1
+                        if count > 1 then return "defer" end
+                    end
+                    "#,
+                )
+                .eval::<LuaFunction>()?;
+            api::set_bullet_cap(lua, (Some(10), Some(LuaValue::Function(callback))))?;
+            assert_eq!(danmaku.borrow().cap_policy(), CapPolicy::Callback);
+            assert_eq!(danmaku.borrow().bullet_cap(), Some(10));
+
+            overflowed(lua, bullets(&[1]), None)?;
+            overflowed(lua, bullets(&[1, 1]), None)?;
+            assert_eq!(lua.globals().get::<_, u32>("overflows")?, 2);
+            Ok(())
+        })?;
+
+        let danmaku = danmaku.borrow();
+        assert_eq!(danmaku.pending_bullets(), 2);
+        assert_eq!(danmaku.cap_stats().dropped, 1);
+        assert_eq!(danmaku.cap_stats().deferred, 2);
+        assert_eq!(danmaku.cap_stats().cap_hits, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn bad_policies_are_rejected() -> Result<()> {
+        let space = danmaku_space()?;
+        space.lua().context(|lua| -> Result<()> {
+            let policy = lua.create_string("explode")?;
+            let err =
+                api::set_bullet_cap(lua, (Some(1), Some(LuaValue::String(policy)))).unwrap_err();
+            assert!(format!("{:?}", err).contains("bad cap policy explode"));
+            assert_eq!(
+                lua.fetch_one::<Danmaku>()?.borrow().cap_policy(),
+                CapPolicy::Drop
+            );
+            Ok(())
+        })
+    }
+}
@@ -3,22 +3,67 @@ use ::{
     ncollide2d as nc,
     sludge::{
         api::{LuaComponent, LuaComponentInterface},
+        math::curve::Curve,
+        persist::PersistPolicy,
         prelude::*,
     },
     sludge_2d::math::*,
     smallbox::SmallBox,
     stack_dst::Value as StackDst,
-    std::f32,
+    std::{
+        f32,
+        sync::{Arc, Weak},
+    },
+    thunderdome::Index,
 };
 
 use crate::bullet::BulletTypeId;
 
+/// A set of collision layers, as a bitmask. Layers are usually given names (such as
+/// `"player"` or `"enemy"`) with [`Danmaku::declare_layer`](crate::Danmaku::declare_layer)
+/// and looked up with [`Danmaku::layer`](crate::Danmaku::layer); a bullet can hit
+/// anything whose layers intersect its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Layers(pub u32);
+
+impl Layers {
+    pub const NONE: Self = Self(0);
+    pub const ALL: Self = Self(!0);
+
+    pub fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl Default for Layers {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl<'lua> ToLua<'lua> for Layers {
+    fn to_lua(self, lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+        self.0.to_lua(lua)
+    }
+}
+
+impl<'lua> FromLua<'lua> for Layers {
+    fn from_lua(lua_value: LuaValue<'lua>, lua: LuaContext<'lua>) -> LuaResult<Self> {
+        Ok(Self(FromLua::from_lua(lua_value, lua)?))
+    }
+}
+
 #[derive(Debug, Clone, Copy, SimpleComponent)]
 pub struct Projectile {
     pub(crate) position: Isometry2<f32>,
     pub(crate) next_position: Isometry2<f32>,
     pub(crate) origin: Isometry2<f32>,
     pub(crate) id: BulletTypeId,
+    pub(crate) layers: Layers,
 }
 
 impl Projectile {
@@ -32,9 +77,17 @@ impl Projectile {
             next_position: origin,
             origin,
             id,
+            layers: Layers::ALL,
         }
     }
 
+    /// Put the projectile on the given collision layers. Projectiles are on every
+    /// layer by default.
+    pub fn with_layers(mut self, layers: Layers) -> Self {
+        self.layers = layers;
+        self
+    }
+
     pub fn position(&self) -> &Isometry2<f32> {
         &self.position
     }
@@ -50,6 +103,14 @@ impl Projectile {
     pub fn bullet_type(&self) -> BulletTypeId {
         self.id
     }
+
+    pub fn layers(&self) -> Layers {
+        self.layers
+    }
+
+    pub fn set_layers(&mut self, layers: Layers) {
+        self.layers = layers;
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -64,6 +125,13 @@ impl LuaUserData for ProjectileAccessor {
             let v = projectile.position.translation.vector;
             Ok((v.x, v.y, projectile.position.rotation.angle()))
         });
+
+        methods.add_method("layers", |lua, this, ()| {
+            let tmp = lua.fetch_one::<World>()?;
+            let world = tmp.borrow();
+            let projectile = world.get::<Projectile>(this.0).to_lua_err()?;
+            Ok(projectile.layers)
+        });
     }
 }
 
@@ -85,6 +153,12 @@ inventory::submit! {
     LuaComponent::new::<Projectile>("Projectile")
 }
 
+// Bullet types are identified by their index in the `Danmaku` resource, which isn't
+// persisted, so there's no saving a projectile; the rest of the entity is kept.
+inventory::submit! {
+    PersistPolicy::skip::<Projectile>("Projectile")
+}
+
 #[derive(Debug, Clone, Copy, SimpleComponent)]
 pub struct QuadraticMotion {
     pub integrated: Isometry2<f32>,
@@ -159,6 +233,55 @@ inventory::submit! {
     LuaComponent::new::<QuadraticMotion>("Quadratic")
 }
 
+fn save_quadratic<'lua>(lua: LuaContext<'lua>, entity: Entity) -> LuaResult<LuaValue<'lua>> {
+    let tmp = lua.fetch_one::<World>()?;
+    let world = tmp.borrow();
+    let motion = world.get::<QuadraticMotion>(entity).to_lua_err()?;
+    let (i, v, a) = (&motion.integrated, &motion.velocity, &motion.acceleration);
+    let saved = [
+        i.translation.vector.x,
+        i.translation.vector.y,
+        i.rotation.angle(),
+        v.linear.x,
+        v.linear.y,
+        v.angular,
+        a.linear.x,
+        a.linear.y,
+        a.angular,
+    ];
+    lua.create_sequence_from(saved.iter().copied())?.to_lua(lua)
+}
+
+fn load_quadratic<'lua>(
+    lua: LuaContext<'lua>,
+    entity: Entity,
+    saved: LuaValue<'lua>,
+) -> LuaResult<()> {
+    let saved = Vec::<f32>::from_lua(saved, lua)?;
+    if saved.len() != 9 {
+        return Err(anyhow!(
+            "expected 9 numbers for a saved `Quadratic`, got {}",
+            saved.len()
+        ))
+        .to_lua_err();
+    }
+
+    let motion = QuadraticMotion {
+        integrated: Isometry2::new(Vector2::new(saved[0], saved[1]), saved[2]),
+        velocity: Velocity2::new(Vector2::new(saved[3], saved[4]), saved[5]),
+        acceleration: Velocity2::new(Vector2::new(saved[6], saved[7]), saved[8]),
+    };
+    lua.fetch_one::<World>()?
+        .borrow_mut()
+        .insert_one(entity, motion)
+        .to_lua_err()
+}
+
+// The bundler can't build a `Quadratic` from a table, so it's saved and restored by hand.
+inventory::submit! {
+    PersistPolicy::custom::<QuadraticMotion, _, _>("Quadratic", save_quadratic, load_quadratic)
+}
+
 #[derive(Debug, Clone, Copy, SimpleComponent)]
 pub struct DirectionalMotion {
     pub integrated: Isometry2<f32>,
@@ -234,6 +357,128 @@ impl ParametricMotionFunction for ParametricEased {
     }
 }
 
+/// Identifies a [`SampledCurve`] registered with the [`Danmaku`](crate::Danmaku) resource.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CurveId(pub(crate) Index);
+
+impl<'lua> ToLua<'lua> for CurveId {
+    fn to_lua(self, lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+        self.0.to_bits().to_lua(lua)
+    }
+}
+
+impl<'lua> FromLua<'lua> for CurveId {
+    fn from_lua(lua_value: LuaValue<'lua>, lua: LuaContext<'lua>) -> LuaResult<Self> {
+        Ok(Self(Index::from_bits(FromLua::from_lua(lua_value, lua)?)))
+    }
+}
+
+/// A trajectory baked from a function of normalized time into evenly spaced
+/// keyframes of `(x, y, angle)`, relative to wherever the bullet starts. Keyframes are
+/// linearly interpolated between, and angles are not wrapped, so a curve may spin a
+/// bullet through several full turns.
+///
+/// Curves are cheap to clone, since the keyframes are shared.
+#[derive(Debug, Clone)]
+pub struct SampledCurve {
+    samples: Arc<[[f32; 3]]>,
+}
+
+impl SampledCurve {
+    /// Bake a curve by calling `f` at `samples` evenly spaced times from `0` to `1`
+    /// inclusive. At least two samples are always taken.
+    pub fn bake<F>(samples: usize, mut f: F) -> Result<Self>
+    where
+        F: FnMut(f32) -> Result<(f32, f32, f32)>,
+    {
+        let n = samples.max(2);
+        let samples = (0..n)
+            .map(|i| {
+                let (x, y, angle) = f(i as f32 / (n - 1) as f32)?;
+                Ok([x, y, angle])
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            samples: samples.into(),
+        })
+    }
+
+    /// Bake a [`Curve`] into `samples` keyframes spaced evenly along it by length, so
+    /// that bullets following it move at a constant speed. The curve is taken relative
+    /// to its own starting point. If `orient` is `true`, bullets are turned to face the
+    /// direction they're moving in.
+    pub fn from_curve(curve: &Curve, samples: usize, orient: bool) -> Self {
+        let start = curve.point(0.);
+        let mut angle = 0.;
+        let mut last_heading = None;
+        let samples = Self::bake(samples, |u| {
+            let offset = curve.point_uniform(u) - start;
+            if orient {
+                let tangent = curve.tangent_uniform(u);
+                if tangent != Vector2::zeros() {
+                    let heading = tangent.y.atan2(tangent.x);
+                    // Unwrap the heading, since keyframe angles aren't wrapped.
+                    angle = match last_heading {
+                        Some(last) => {
+                            let turn =
+                                (heading - last + f32::consts::PI).rem_euclid(2. * f32::consts::PI);
+                            angle + turn - f32::consts::PI
+                        }
+                        None => heading,
+                    };
+                    last_heading = Some(heading);
+                }
+            }
+            Ok((offset.x, offset.y, angle))
+        });
+
+        // Nothing in the closure can fail.
+        samples.unwrap()
+    }
+
+    /// Sample the curve at a normalized time, clamped to `[0, 1]`.
+    pub fn sample(&self, t: f32) -> Isometry2<f32> {
+        let last = self.samples.len() - 1;
+        let scaled = t.max(0.).min(1.) * last as f32;
+        let i = (scaled.floor() as usize).min(last.saturating_sub(1));
+        let frac = scaled - i as f32;
+
+        let [x0, y0, a0] = self.samples[i];
+        let [x1, y1, a1] = self.samples[(i + 1).min(last)];
+        let lerp = |a: f32, b: f32| a + (b - a) * frac;
+
+        Isometry2::new(Vector2::new(lerp(x0, x1), lerp(y0, y1)), lerp(a0, a1))
+    }
+}
+
+/// Moves along a [`SampledCurve`] over `duration` seconds, starting from `origin`.
+#[derive(Debug, Clone)]
+pub struct ParametricSampled {
+    pub origin: Isometry2<f32>,
+    pub duration: f32,
+    pub curve: SampledCurve,
+}
+
+impl ParametricMotionFunction for ParametricSampled {
+    fn set_endpoints(&mut self, start: &Isometry2<f32>, _end: &Isometry2<f32>) {
+        self.origin = *start;
+    }
+
+    fn transform_by(&mut self, tx: &Isometry2<f32>) {
+        self.origin = tx * self.origin;
+    }
+
+    fn calculate(&self, t: f32) -> Isometry2<f32> {
+        let normalized = if self.duration > 0. {
+            t / self.duration
+        } else {
+            1.
+        };
+        self.origin * self.curve.sample(normalized)
+    }
+}
+
 // Assume that the vast majority of parametric motion functions will be
 // under 256 bytes in size.
 //
@@ -302,6 +547,23 @@ impl ParametricMotion {
         }
     }
 
+    /// Follow a baked curve for `duration` seconds, relative to `start`.
+    pub fn sampled(
+        despawn_after_duration: bool,
+        duration: f32,
+        start: &Isometry2<f32>,
+        curve: &SampledCurve,
+    ) -> Self {
+        Self::new(
+            despawn_after_duration,
+            ParametricSampled {
+                origin: *start,
+                duration,
+                curve: curve.clone(),
+            },
+        )
+    }
+
     pub fn update(&mut self, dt: f32) -> Isometry2<f32> {
         let calculated = self.function.calculate(self.time);
         self.time += dt;
@@ -331,6 +593,20 @@ impl Collision {
         Self::Rectangle { radii }
     }
 
+    /// The axis-aligned bounding box of the shape at the given position.
+    pub fn bounds(&self, position: &Isometry2<f32>) -> Box2<f32> {
+        match *self {
+            Self::Circle { radius } => Box2::from_half_extents(
+                Point2::from(position.translation.vector),
+                Vector2::repeat(radius),
+            ),
+            Self::Rectangle { radii } => {
+                let homogeneous = homogeneous_mat3_to_mat4(&position.to_homogeneous());
+                Box2::from_half_extents(Point2::origin(), radii).transformed_by(&homogeneous)
+            }
+        }
+    }
+
     pub(crate) fn to_shape(&self) -> StackDst<dyn nc::shape::Shape<f32>> {
         match *self {
             Self::Circle { radius } => StackDst::new(nc::shape::Ball::new(radius)).unwrap(),
@@ -357,6 +633,44 @@ impl Collision {
     }
 }
 
+/// A region to look for bullets in with [`Danmaku::bullets_in`](crate::Danmaku::bullets_in):
+/// a collision shape at a position. Boxes convert into areas directly.
+#[derive(Debug, Clone, Copy)]
+pub struct BulletArea {
+    pub position: Isometry2<f32>,
+    pub collision: Collision,
+}
+
+impl BulletArea {
+    pub fn new(position: Isometry2<f32>, collision: Collision) -> Self {
+        Self {
+            position,
+            collision,
+        }
+    }
+
+    pub fn circle(center: Point2<f32>, radius: f32) -> Self {
+        Self::new(
+            Isometry2::translation(center.x, center.y),
+            Collision::circle(radius),
+        )
+    }
+
+    pub fn bounds(&self) -> Box2<f32> {
+        self.collision.bounds(&self.position)
+    }
+}
+
+impl From<Box2<f32>> for BulletArea {
+    fn from(aabb: Box2<f32>) -> Self {
+        let center = aabb.center();
+        Self::new(
+            Isometry2::translation(center.x, center.y),
+            Collision::rectangle(aabb.half_extents()),
+        )
+    }
+}
+
 #[derive(Debug, Clone, Copy, SimpleComponent)]
 pub struct MaximumVelocity {
     pub linear: f32,
@@ -366,7 +680,42 @@ pub struct MaximumVelocity {
 #[derive(Debug, Clone, Copy, SimpleComponent)]
 pub struct DespawnOutOfBounds;
 
+/// Reflect the bullet off the edges of the danmaku bounds, up to `times` more times.
+/// When the last bounce is used up, the component is removed and
+/// [`BOUNCES_EXHAUSTED_EVENT`](crate::BOUNCES_EXHAUSTED_EVENT) is broadcast with the
+/// bullet.
+///
+/// The bullet's position is reflected along with its `QuadraticMotion` or
+/// `DirectionalMotion`. Bullets with neither aren't bounced, and don't use up their
+/// bounces, since they'd only head straight back out of the bounds. Bounces are worked
+/// out in world space, so bullets in a rotated [anchored group](crate::pattern::Group)
+/// bounce off the bounds like any others.
+#[derive(Debug, Clone, Copy, SimpleComponent)]
+pub struct BounceInBounds {
+    pub times: u32,
+}
+
+/// Wrap the bullet around to the opposite edge of the danmaku bounds when it leaves
+/// them.
 #[derive(Debug, Clone, Copy, SimpleComponent)]
-pub struct DespawnAfterTimeLimit {
-    pub ttl: f32,
+pub struct WrapAround;
+
+/// Marks the anchor entity of an anchored [`Group`](crate::pattern::Group), which its
+/// bullets are positioned relative to.
+#[derive(Debug, Clone, SimpleComponent)]
+pub struct GroupAnchor {
+    /// How fast the group's bullets move, as a multiple of their usual speed.
+    pub speed: f32,
+    /// Dangling once every handle to the group has been dropped, at which point the
+    /// anchor is despawned as soon as nothing is attached to it.
+    pub(crate) group: Weak<()>,
+}
+
+impl Default for GroupAnchor {
+    fn default() -> Self {
+        Self {
+            speed: 1.,
+            group: Weak::new(),
+        }
+    }
 }
@@ -0,0 +1,202 @@
+//! Bullets which are fired now but spawned later, for staggering a pattern over time.
+//!
+//! A pattern can hold back the bullets it fires with `builder:delay(frames)`, or be
+//! wrapped with `pattern:delay(frames)` or `pattern:repeat_n(count, interval)`:
+//!
+//! ```lua
+//! local spiral = danmaku.pattern.arc(16, 0.5, 5):repeat_n(8, 4)
+//! danmaku.spawn(function(b)
+//!     b:bullet_type(small)
+//!     spiral:build(b)
+//! end)
+//! ```
+//!
+//! Delays are counted in ticks of the [`DanmakuSystem`](crate::DanmakuSystem), which
+//! runs once per scheduler frame, so they line up with `sludge.thread.yield()` in
+//! scripts and stop counting down while gameplay is paused. Bullets are fired into
+//! their group when they're spawned, not when they're fired, and count against the caps
+//! at that point; if the caps are full, they're dealt with by the [`CapPolicy`] like
+//! any other spawn. Delayed bullets are thrown out by [`Danmaku::clear_layers`] if
+//! they're on any of the cleared layers, and while the clear delay is active.
+//!
+//! [`CapPolicy`]: crate::CapPolicy
+
+use ::{
+    sludge::prelude::*,
+    std::collections::{BTreeMap, VecDeque},
+};
+
+use crate::{
+    builder::Parameters,
+    bullet::BulletTypeId,
+    cap::{self, min_budget},
+    components::Layers,
+    pattern::Group,
+    Danmaku,
+};
+
+/// Bullets from a single spawn which are held back for the same number of ticks.
+#[derive(Debug)]
+pub(crate) struct DelayedSpawn {
+    pub frames: u32,
+    pub bullets: Vec<(BulletTypeId, Parameters)>,
+    pub group: Option<LuaRegistryKey>,
+}
+
+impl Danmaku {
+    /// How many delayed bullets are waiting for their time to come.
+    pub fn delayed_bullets(&self) -> usize {
+        self.delayed.iter().map(|d| d.bullets.len()).sum()
+    }
+
+    /// Throw out delayed bullets on any of `layers`.
+    pub(crate) fn clear_delayed(&mut self, layers: Layers) {
+        for delayed in self.delayed.iter_mut() {
+            delayed
+                .bullets
+                .retain(|(_, params)| !params.layers.intersects(layers));
+        }
+        self.delayed.retain(|delayed| !delayed.bullets.is_empty());
+    }
+
+    /// Count down every delayed spawn by a tick and take the ones which are due. A
+    /// spawn delayed by `n` ticks is due on the `n`th tick after the one it was fired
+    /// in, since the countdown runs after scripts have fired their bullets.
+    fn take_due(&mut self) -> VecDeque<DelayedSpawn> {
+        let mut due = VecDeque::new();
+        for delayed in std::mem::take(&mut self.delayed) {
+            if delayed.frames == 0 {
+                due.push_back(delayed);
+            } else {
+                self.delayed.push_back(DelayedSpawn {
+                    frames: delayed.frames - 1,
+                    ..delayed
+                });
+            }
+        }
+        due
+    }
+}
+
+/// Hold on to the bullets a spawn fired with a delay, sorted by how long they wait.
+pub(crate) fn schedule<'lua>(
+    lua: LuaContext<'lua>,
+    bullets: Vec<(BulletTypeId, Parameters)>,
+    group: Option<LuaAnyUserData<'lua>>,
+) -> Result<()> {
+    let mut by_delay = BTreeMap::<u32, Vec<_>>::new();
+    for (bullet_type, params) in bullets {
+        by_delay
+            .entry(params.delay)
+            .or_default()
+            .push((bullet_type, params));
+    }
+
+    let danmaku = lua.fetch_one::<Danmaku>()?;
+    let mut danmaku = danmaku.borrow_mut();
+    for (frames, bullets) in by_delay {
+        danmaku.delayed.push_back(DelayedSpawn {
+            frames,
+            bullets,
+            group: group
+                .clone()
+                .map(|ud| lua.create_registry_value(ud))
+                .transpose()?,
+        });
+    }
+
+    Ok(())
+}
+
+/// Count down every delayed spawn by a tick, and spawn the ones which are due.
+pub(crate) fn spawn_due(lua: LuaContext) -> Result<()> {
+    let resources = lua.resources();
+    let world = resources.fetch_one::<World>()?;
+    let danmaku = resources.fetch_one::<Danmaku>()?;
+
+    let (due, clearing) = {
+        let mut danmaku = danmaku.borrow_mut();
+        (danmaku.take_due(), danmaku.is_clear_delay_active())
+    };
+
+    if clearing {
+        return Ok(());
+    }
+
+    for delayed in due {
+        let group_ud = delayed
+            .group
+            .as_ref()
+            .map(|key| lua.registry_value::<LuaAnyUserData>(key))
+            .transpose()?;
+        let mut group = group_ud
+            .as_ref()
+            .map(LuaAnyUserData::borrow_mut::<Group>)
+            .transpose()?;
+
+        let budget = min_budget(
+            danmaku.borrow().spawn_budget(),
+            group
+                .as_deref_mut()
+                .and_then(|group| group.spawn_budget(&world.borrow())),
+        );
+
+        let mut bullets = delayed.bullets;
+        let overflow = match budget {
+            Some(budget) if budget < bullets.len() => bullets.split_off(budget),
+            _ => Vec::new(),
+        };
+
+        let entities = cap::spawn_bullets(&resources, &world, &danmaku, bullets)?;
+        if let Some(group) = group.as_deref_mut() {
+            group.adopt(&mut world.borrow_mut(), &entities);
+        }
+        drop(group);
+
+        if !overflow.is_empty() {
+            cap::overflowed(lua, overflow, group_ud)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delayed_spawn(frames: u32) -> DelayedSpawn {
+        let bullet_type = BulletTypeId(thunderdome::Arena::new().insert(()));
+        DelayedSpawn {
+            frames,
+            bullets: vec![(bullet_type, Parameters::new().delayed(frames))],
+            group: None,
+        }
+    }
+
+    #[test]
+    fn delayed_spawns_wait_their_full_delay() {
+        let mut danmaku = Danmaku::new();
+        danmaku.delayed.push_back(delayed_spawn(1));
+        danmaku.delayed.push_back(delayed_spawn(3));
+
+        // The tick the bullets were fired in.
+        assert!(danmaku.take_due().is_empty());
+        assert_eq!(danmaku.delayed_bullets(), 2);
+
+        let due = danmaku.take_due();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].bullets[0].1.delay, 1);
+
+        assert!(danmaku.take_due().is_empty());
+        assert!(danmaku.take_due().is_empty());
+        assert_eq!(danmaku.take_due().len(), 1);
+        assert_eq!(danmaku.delayed_bullets(), 0);
+    }
+
+    #[test]
+    fn delays_saturate() {
+        let params = Parameters::new().delayed(u32::MAX - 1).delayed(5);
+        assert_eq!(params.delay, u32::MAX);
+    }
+}
@@ -0,0 +1,415 @@
+//! Hits, grazes, and the data bullets carry into them.
+//!
+//! Scripts usually want to know more about a bullet which hit something than which
+//! entity it was: how much damage it does, what element it is, and so on. Rather than a
+//! Rust component for every field, patterns can attach a small table of data to the
+//! bullets they fire with `builder:data(...)`, which ends up on each bullet as a
+//! [`BulletUserData`] component. Like every other parameter, data set on the builder
+//! applies to everything fired until the matching `pop`.
+//!
+//! `danmaku.collide(x, y, radius, layers, graze_radius)` checks a target against every
+//! bullet on any of `layers`, broadcasting [`HIT_EVENT`] for each bullet which hits it
+//! and [`GRAZE_EVENT`] for each bullet which comes within `graze_radius` of it without
+//! hitting. Both are broadcast with the bullet and its data, or `nil` if it has none. A
+//! bullet only grazes once, and the hits are returned as well:
+//!
+//! ```lua
+//! danmaku.spawn(function(b)
+//!     b:bullet_type(danmaku.bullet.get_type_by_name("fireball"))
+//!     b:data({ damage = 3, element = "fire" })
+//!     b:fire()
+//! end, ring)
+//!
+//! sludge.thread.spawn(function()
+//!     while true do
+//!         local _, _, bullet, data = yield(danmaku.HIT_EVENT)
+//!         player:damage(data and data.damage or 1)
+//!     end
+//! end)
+//! ```
+//!
+//! Data is interned by the [`Danmaku`] resource, so every bullet fired with the same
+//! table shares a single copy of it. Interned data is never freed, so it should come
+//! from a small set of values rather than be different for every bullet.
+
+use ::{
+    hashbrown::HashMap,
+    serde_hashkey::{Key, OrderedFloatPolicy},
+    sludge::prelude::*,
+    sludge_2d::math::*,
+    std::sync::Arc,
+};
+
+use crate::{
+    components::{Collision, Layers, Proximity},
+    Danmaku,
+};
+
+/// Broadcast with a bullet and its data for every bullet which hits a target checked
+/// with `danmaku.collide`.
+pub const HIT_EVENT: &'static str = "danmaku.hit";
+
+/// Broadcast with a bullet and its data the first time a bullet grazes a target
+/// checked with `danmaku.collide`.
+pub const GRAZE_EVENT: &'static str = "danmaku.graze";
+
+/// The data attached to a bullet by the pattern which fired it. See the [module
+/// documentation](self) for details.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SimpleComponent)]
+pub struct BulletUserData(pub Arc<Key<OrderedFloatPolicy>>);
+
+impl BulletUserData {
+    /// Deserialize the data into a Rust value.
+    pub fn to_rust<T: serde::de::DeserializeOwned>(&self) -> Result<T> {
+        Ok(serde_hashkey::from_key(&*self.0)?)
+    }
+}
+
+impl<'lua> ToLua<'lua> for BulletUserData {
+    fn to_lua(self, lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+        rlua_serde::to_value(lua, &*self.0)
+    }
+}
+
+/// Marks a bullet which has already grazed something, so that it isn't reported again.
+#[derive(Debug, Clone, Copy, SimpleComponent)]
+pub struct Grazed;
+
+/// Identifies a value interned with [`Danmaku::intern_user_data`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UserDataId(pub(crate) u32);
+
+impl<'lua> ToLua<'lua> for UserDataId {
+    fn to_lua(self, lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+        self.0.to_lua(lua)
+    }
+}
+
+impl<'lua> FromLua<'lua> for UserDataId {
+    fn from_lua(lua_value: LuaValue<'lua>, lua: LuaContext<'lua>) -> LuaResult<Self> {
+        Ok(Self(FromLua::from_lua(lua_value, lua)?))
+    }
+}
+
+/// Every value which has been attached to bullets, shared between the [`Danmaku`]
+/// resource and its bundlers.
+#[derive(Debug, Default)]
+pub(crate) struct UserDataTable {
+    values: Vec<BulletUserData>,
+    ids: HashMap<BulletUserData, UserDataId>,
+}
+
+impl UserDataTable {
+    fn intern(&mut self, data: Key<OrderedFloatPolicy>) -> UserDataId {
+        let data = BulletUserData(Arc::new(data));
+        if let Some(&id) = self.ids.get(&data) {
+            return id;
+        }
+
+        let id = UserDataId(self.values.len() as u32);
+        self.values.push(data.clone());
+        self.ids.insert(data, id);
+        id
+    }
+
+    pub(crate) fn get(&self, id: UserDataId) -> Option<&BulletUserData> {
+        self.values.get(id.0 as usize)
+    }
+}
+
+impl Danmaku {
+    /// Intern a value to be attached to bullets with [`Parameters::data`]. Interning
+    /// the same value twice gives the same ID.
+    ///
+    /// [`Parameters::data`]: crate::Parameters::data
+    pub fn intern_user_data<T: serde::Serialize>(&self, data: &T) -> Result<UserDataId> {
+        let key = serde_hashkey::to_key_with_ordered_float(data)?;
+        Ok(self
+            .user_data
+            .write()
+            .unwrap_or_else(|p| p.into_inner())
+            .intern(key))
+    }
+
+    pub fn user_data(&self, id: UserDataId) -> Option<BulletUserData> {
+        self.user_data
+            .read()
+            .unwrap_or_else(|p| p.into_inner())
+            .get(id)
+            .cloned()
+    }
+
+    /// Find every bullet on any of `layers` which comes within `margin` of the given
+    /// shape without hitting it, and hasn't grazed anything before. Like
+    /// [`Danmaku::hits`], only bullets near the shape in the spatial hash are checked.
+    pub fn grazes(
+        &self,
+        world: &World,
+        layers: Layers,
+        position: &Isometry2<f32>,
+        collision: &Collision,
+        margin: f32,
+    ) -> Vec<Entity> {
+        let bounds = collision.bounds(position).loosened(margin.max(0.));
+        self.candidates(world, &bounds)
+            .into_iter()
+            .filter(|&e| {
+                world.get::<Grazed>(e).is_err()
+                    && self.near(world, e, layers, |proj, bullet| {
+                        Collision::proximity(position, collision, &proj.position, bullet, margin)
+                            == Proximity::WithinMargin
+                    })
+            })
+            .collect()
+    }
+}
+
+pub(crate) mod api {
+    use super::*;
+
+    /// `danmaku.collide(x, y, radius, layers, graze_radius)`: broadcast hits and grazes
+    /// of a circular target, returning the bullets which hit it. Grazes are only checked
+    /// if `graze_radius` is given, and measured from the edge of the target.
+    pub fn collide<'lua>(
+        lua: LuaContext<'lua>,
+        (x, y, radius, layers, graze_radius): (f32, f32, f32, Option<Layers>, Option<f32>),
+    ) -> LuaResult<Vec<LuaEntity>> {
+        let (world, danmaku) = lua.fetch::<(World, Danmaku)>()?;
+        let layers = layers.unwrap_or(Layers::ALL);
+        let position = Isometry2::translation(x, y);
+        let collision = Collision::circle(radius);
+
+        let (hits, grazes) = {
+            let (world, danmaku) = (world.borrow(), danmaku.borrow());
+            let hits = danmaku.hits(&world, layers, &position, &collision);
+            let grazes = match graze_radius {
+                Some(margin) => danmaku.grazes(&world, layers, &position, &collision, margin),
+                None => Vec::new(),
+            };
+
+            let with_data = |entities: Vec<Entity>| {
+                entities
+                    .into_iter()
+                    .map(|e| (e, world.get::<BulletUserData>(e).ok().map(|d| (*d).clone())))
+                    .collect::<Vec<_>>()
+            };
+            (with_data(hits), with_data(grazes))
+        };
+
+        if !grazes.is_empty() {
+            let world = world.borrow();
+            let mut buf = world.get_buffer();
+            for &(e, _) in &grazes {
+                buf.insert_one(e, Grazed);
+            }
+            world.queue_buffer(buf);
+        }
+
+        for (e, data) in grazes {
+            lua.broadcast(GRAZE_EVENT, (LuaEntity::from(e), data))?;
+        }
+
+        let mut entities = Vec::with_capacity(hits.len());
+        for (e, data) in hits {
+            lua.broadcast(HIT_EVENT, (LuaEntity::from(e), data))?;
+            entities.push(LuaEntity::from(e));
+        }
+
+        Ok(entities)
+    }
+
+    /// `danmaku.user_data(bullet)`: the data attached to a bullet, or `nil`.
+    pub fn user_data<'lua>(lua: LuaContext<'lua>, bullet: LuaEntity) -> LuaResult<LuaValue<'lua>> {
+        let world = lua.fetch_one::<World>()?;
+        let data = world
+            .borrow()
+            .get::<BulletUserData>(bullet.into())
+            .ok()
+            .map(|data| (*data).clone());
+        data.to_lua(lua)
+    }
+
+    /// Convert a Lua value into interned bullet data, for `builder:data(...)`.
+    pub(crate) fn intern<'lua>(
+        lua: LuaContext<'lua>,
+        data: LuaValue<'lua>,
+    ) -> LuaResult<UserDataId> {
+        let key = rlua_serde::from_value::<Key<OrderedFloatPolicy>>(data)?;
+        let danmaku = lua.fetch_one::<Danmaku>()?;
+        let id = danmaku
+            .borrow()
+            .user_data
+            .write()
+            .unwrap_or_else(|p| p.into_inner())
+            .intern(key);
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BulletData, BulletTypeId, Parameters, Projectile};
+
+    struct Plain;
+
+    impl BulletData for Plain {
+        type Bundled = (Projectile, Collision);
+
+        fn bundle(
+            &self,
+            _resources: &UnifiedResources,
+            parameters: &[Parameters],
+            bullet_type: BulletTypeId,
+            bundles: &mut Vec<Self::Bundled>,
+        ) -> Result<()> {
+            bundles.extend(parameters.iter().map(|ps| {
+                (
+                    Projectile::new(bullet_type, ps.position),
+                    Collision::circle(1.),
+                )
+            }));
+            Ok(())
+        }
+    }
+
+    fn update_scheduler(space: &Space) -> Result<()> {
+        let scheduler = space.scheduler()?;
+        space
+            .lua()
+            .context(|lua| scheduler.borrow_mut().update(lua, 1.))
+    }
+
+    #[test]
+    fn hits_and_grazes_carry_builder_data() -> Result<()> {
+        let space = Space::new()?;
+        space.resources().borrow_mut().insert(Danmaku::new());
+        space
+            .fetch_one::<Danmaku>()?
+            .borrow_mut()
+            .insert_bullet_type_with_name(Plain, "plain");
+
+        space.lua().context(|lua| {
+            lua.load(
+                r#"
+                hits, grazes = {}, {}
+                for event, seen in pairs({ [danmaku.HIT_EVENT] = hits, [danmaku.GRAZE_EVENT] = grazes }) do
+                    sludge.thread.spawn(function()
+                        while true do
+                            local _, _, bullet, data = yield(event)
+                            table.insert(seen, data and data.damage or "none")
+                        end
+                    end)
+                end
+                "#,
+            )
+            .exec()
+        })?;
+        update_scheduler(&space)?;
+
+        space.lua().context(|lua| {
+            lua.load(
+                r#"
+                danmaku.spawn(function(b)
+                    b:bullet_type(danmaku.bullet.get_type_by_name("plain"))
+                    b:push()
+                    b:data({ damage = 3 })
+                    b:fire()
+                    b:pop()
+                    b:translate(3, 0)
+                    b:fire()
+                end)
+
+                hit_count = #danmaku.collide(0, 0, 1, nil, 2)
+                "#,
+            )
+            .exec()
+        })?;
+        update_scheduler(&space)?;
+
+        space.lua().context(|lua| -> Result<()> {
+            let globals = lua.globals();
+            assert_eq!(globals.get::<_, u32>("hit_count")?, 1);
+            assert_eq!(globals.get::<_, Vec<u32>>("hits")?, vec![3]);
+            assert_eq!(globals.get::<_, Vec<String>>("grazes")?, vec!["none"]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn hits_and_grazes_respect_layers() -> Result<()> {
+        let mut world = World::new();
+        let mut danmaku = Danmaku::new();
+        let player = danmaku.declare_layer("player", 0)?;
+        let enemy = danmaku.declare_layer("enemy", 1)?;
+
+        let bullet_type = BulletTypeId(thunderdome::Arena::new().insert(()));
+        let mut bullet = |x: f32, layers: Layers| {
+            world.spawn((
+                Projectile::new(bullet_type, Isometry2::translation(x, 0.)).with_layers(layers),
+                Collision::circle(1.),
+            ))
+        };
+        let shot = bullet(0., player);
+        let enemy_shot = bullet(0., enemy);
+        let near_miss = bullet(3., enemy);
+
+        let (target, circle) = (Isometry2::identity(), Collision::circle(1.));
+        assert_eq!(
+            danmaku.hits(&world, enemy, &target, &circle),
+            vec![enemy_shot]
+        );
+        assert_eq!(danmaku.hits(&world, player, &target, &circle), vec![shot]);
+        assert_eq!(
+            danmaku
+                .hits(&world, player.union(enemy), &target, &circle)
+                .len(),
+            2
+        );
+        assert!(danmaku
+            .hits(&world, Layers::NONE, &target, &circle)
+            .is_empty());
+
+        assert_eq!(
+            danmaku.grazes(&world, enemy, &target, &circle, 2.),
+            vec![near_miss]
+        );
+        assert!(danmaku
+            .grazes(&world, player, &target, &circle, 2.)
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn collide_only_checks_the_given_layers() -> Result<()> {
+        let space = Space::new()?;
+        let mut danmaku = Danmaku::new();
+        danmaku.declare_layers(vec![("player", 0), ("enemy", 1)])?;
+        danmaku.insert_bullet_type_with_name(Plain, "plain");
+        space.resources().borrow_mut().insert(danmaku);
+
+        let counts = space.lua().context(|lua| {
+            lua.load(
+                r#"
+                danmaku.spawn(function(b)
+                    b:bullet_type(danmaku.bullet.get_type_by_name("plain"))
+                    b:layer("player")
+                    b:fire()
+                    b:layer("enemy")
+                    b:fire()
+                    b:fire()
+                end)
+
+                return #danmaku.collide(0, 0, 1, danmaku.layer("player")),
+                    #danmaku.collide(0, 0, 1, danmaku.layer("enemy")),
+                    #danmaku.collide(0, 0, 1, danmaku.layer("player", "enemy"))
+                "#,
+            )
+            .eval::<(usize, usize, usize)>()
+        })?;
+        assert_eq!(counts, (1, 2, 3));
+
+        Ok(())
+    }
+}
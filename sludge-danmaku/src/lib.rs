@@ -1,33 +1,53 @@
-#![feature(exact_size_is_empty)]
-
 use ::{
     atomic_refcell::AtomicRefCell,
     dynamic_pool::{DynamicPool, DynamicPoolItem},
-    hashbrown::HashMap,
+    hashbrown::{HashMap, HashSet},
     hibitset::{BitSet, DrainableBitSet},
     rand::RngCore,
-    sludge::{api::Module, prelude::*},
-    sludge_2d::math::*,
+    sludge::{
+        api::Module,
+        components::Parent,
+        pause,
+        prelude::*,
+        settings::Settings,
+        shutdown::{Finalizer, ShutdownStage},
+        timestep,
+        transform::Transform2d,
+    },
+    sludge_2d::{
+        math::*,
+        spatial_hash::{HashGrid, SpatialIndex},
+    },
     std::{
+        collections::{BTreeMap, VecDeque},
         f32,
         ops::Deref,
         sync::{Arc, RwLock, RwLockReadGuard},
     },
+    thunderdome::Arena,
 };
 
+pub mod boss;
 mod builder;
 mod bullet;
+mod cap;
 mod components;
+mod delay;
+mod hit;
 pub mod pattern;
 
 #[doc(inline)]
 pub use crate::{
+    boss::{BossPhase, BossSystem},
     builder::{LuaPatternBuilder, Op, Parameters, PatternBuilder},
     bullet::{BulletData, BulletMetatype, BulletTypeId, Bundler},
+    cap::{CapPolicy, CapStats, CAP_REACHED_EVENT},
     components::{
-        Collision, DespawnAfterTimeLimit, DespawnOutOfBounds, DirectionalMotion, MaximumVelocity,
-        ParametricMotion, Projectile, Proximity, QuadraticMotion,
+        BounceInBounds, BulletArea, Collision, CurveId, DespawnOutOfBounds, DirectionalMotion,
+        GroupAnchor, Layers, MaximumVelocity, ParametricMotion, ParametricSampled, Projectile,
+        Proximity, QuadraticMotion, SampledCurve, WrapAround,
     },
+    hit::{BulletUserData, Grazed, UserDataId, GRAZE_EVENT, HIT_EVENT},
 };
 
 pub use sludge::inventory;
@@ -35,11 +55,30 @@ pub use sludge::inventory;
 use crate::{
     builder::Batch,
     bullet::BulletTypes,
+    cap::DeferredSpawn,
+    delay::DelayedSpawn,
+    hit::UserDataTable,
     pattern::{Group, LuaPattern, RustPattern},
 };
 
 const RNG_REGISTRY_KEY: &'static str = "danmaku.rng";
 
+/// The size of the buckets in the spatial hash behind [`Danmaku::bullets_in`].
+const BULLET_GRID_BUCKET_SIZE: f32 = 32.;
+
+/// The section of the [settings](sludge::settings) file named collision layers are
+/// declared in, as a table of layer names to bit indices:
+///
+/// ```toml
+/// [collision_layers]
+/// player = 0
+/// enemy = 1
+/// ```
+pub const LAYERS_SECTION: &'static str = "collision_layers";
+
+/// Broadcast with a bullet when it uses up the last of its [`BounceInBounds`] bounces.
+pub const BOUNCES_EXHAUSTED_EVENT: &'static str = "danmaku.bounces_exhausted";
+
 #[derive(Clone)]
 pub struct SharedRng<R: RngCore> {
     rng: Arc<AtomicRefCell<R>>,
@@ -101,7 +140,18 @@ pub struct Danmaku {
     bullet_metatypes: HashMap<String, BulletMetatype>,
     bullet_types: Arc<RwLock<BulletTypes>>,
     bundler_pool: DynamicPool<Bundler>,
+    curves: Arena<SampledCurve>,
+    user_data: Arc<RwLock<UserDataTable>>,
+    bullet_grid: AtomicRefCell<BulletGrid>,
+    layer_names: HashMap<String, Layers>,
+    bounces_exhausted: Vec<Entity>,
     clear_delay: f32,
+    bullet_cap: Option<usize>,
+    cap_policy: CapPolicy,
+    cap_stats: CapStats,
+    live_bullets: usize,
+    deferred: VecDeque<DeferredSpawn>,
+    delayed: VecDeque<DelayedSpawn>,
 }
 
 impl Danmaku {
@@ -111,9 +161,13 @@ impl Danmaku {
             .into_iter()
             .map(|bmt| (bmt.name.to_owned(), *bmt))
             .collect();
+        let user_data = Arc::new(RwLock::new(UserDataTable::default()));
         let bundler_pool = {
             let bt_cloned = bullet_types.clone();
-            DynamicPool::new(4, 32, move || Bundler::new(bt_cloned.clone()))
+            let ud_cloned = user_data.clone();
+            DynamicPool::new(4, 32, move || {
+                Bundler::new(bt_cloned.clone(), ud_cloned.clone())
+            })
         };
         Self {
             bounds: None,
@@ -121,7 +175,18 @@ impl Danmaku {
             bullet_metatypes,
             bullet_types,
             bundler_pool,
+            curves: Arena::new(),
+            user_data,
+            bullet_grid: AtomicRefCell::new(BulletGrid::new()),
+            layer_names: HashMap::new(),
+            bounces_exhausted: Vec::new(),
             clear_delay: 0.,
+            bullet_cap: None,
+            cap_policy: CapPolicy::default(),
+            cap_stats: CapStats::default(),
+            live_bullets: 0,
+            deferred: VecDeque::new(),
+            delayed: VecDeque::new(),
         }
     }
 
@@ -159,6 +224,79 @@ impl Danmaku {
         }
     }
 
+    pub fn insert_curve(&mut self, curve: SampledCurve) -> CurveId {
+        CurveId(self.curves.insert(curve))
+    }
+
+    pub fn curve(&self, id: CurveId) -> Option<&SampledCurve> {
+        self.curves.get(id.0)
+    }
+
+    pub fn remove_curve(&mut self, id: CurveId) -> Option<SampledCurve> {
+        self.curves.remove(id.0)
+    }
+
+    /// Declare a named collision layer on the given bit, from 0 to 31, or move an
+    /// existing layer to a new bit. Bits are given explicitly rather than handed out
+    /// as names are used, so that a layer is the same bit in every run and in every
+    /// save; [`DanmakuSystem`] declares the layers in the [`LAYERS_SECTION`] of the
+    /// settings when it creates the `Danmaku` resource.
+    pub fn declare_layer(&mut self, name: impl Into<String>, bit: u32) -> Result<Layers> {
+        let name = name.into();
+        ensure!(
+            bit < 32,
+            "collision layer `{}` is on bit {}, but there are only 32 bits",
+            name,
+            bit
+        );
+        let layers = Layers(1 << bit);
+        self.layer_names.insert(name, layers);
+        Ok(layers)
+    }
+
+    /// Declare several collision layers at once.
+    pub fn declare_layers<S: Into<String>>(
+        &mut self,
+        layers: impl IntoIterator<Item = (S, u32)>,
+    ) -> Result<()> {
+        for (name, bit) in layers {
+            self.declare_layer(name, bit)?;
+        }
+        Ok(())
+    }
+
+    /// Look up a named collision layer, or an error naming the layer if it hasn't been
+    /// [declared](Danmaku::declare_layer).
+    pub fn layer<S>(&self, name: &S) -> Result<Layers>
+    where
+        S: AsRef<str> + ?Sized,
+    {
+        self.layer_names
+            .get(name.as_ref())
+            .copied()
+            .ok_or_else(|| anyhow!("unknown collision layer `{}`", name.as_ref()))
+    }
+
+    /// Find every bullet on any of `layers` which intersects the given shape, using the
+    /// same spatial hash as [`Danmaku::bullets_in`].
+    pub fn hits(
+        &self,
+        world: &World,
+        layers: Layers,
+        position: &Isometry2<f32>,
+        collision: &Collision,
+    ) -> Vec<Entity> {
+        self.candidates(world, &collision.bounds(position))
+            .into_iter()
+            .filter(|&e| {
+                self.near(world, e, layers, |proj, bullet| {
+                    Collision::proximity(position, collision, &proj.position, bullet, 0.)
+                        == Proximity::Intersecting
+                })
+            })
+            .collect()
+    }
+
     pub fn bundler(&self) -> DynamicPoolItem<Bundler> {
         self.bundler_pool.take()
     }
@@ -171,17 +309,176 @@ impl Danmaku {
         self.clear_delay > 0.
     }
 
+    /// Despawn every bullet, and if `delay` is given, keep new bullets from being spawned
+    /// for that many seconds.
+    pub fn clear(&mut self, world: &World, delay: Option<f32>) {
+        self.clear_layers(world, Layers::ALL, delay);
+    }
+
+    /// Like [`Danmaku::clear`], but only despawn bullets on any of `layers`. Deferred
+    /// and delayed bullets on any of `layers` are thrown out as well.
+    pub fn clear_layers(&mut self, world: &World, layers: Layers, delay: Option<f32>) {
+        self.clear_deferred(layers);
+        self.clear_delayed(layers);
+
+        let mut buf = world.get_buffer();
+        world
+            .query::<&Projectile>()
+            .iter()
+            .filter(|(_, proj)| proj.layers.intersects(layers))
+            .for_each(|(e, _)| {
+                buf.despawn(e);
+            });
+        world.queue_buffer(buf);
+
+        if let Some(delay) = delay {
+            self.set_clear_delay(delay);
+        }
+    }
+
+    /// Take the bullets which have used up their last bounce since this was last called.
+    pub fn drain_bounces_exhausted(&mut self) -> impl Iterator<Item = Entity> + '_ {
+        self.bounces_exhausted.drain(..)
+    }
+
+    fn bounce_and_wrap(&mut self, world: &mut World, bounds: &Box2<f32>) {
+        let already_exhausted = self.bounces_exhausted.len();
+        for (e, (mut proj, mut bounce, quadratic, directional, parent)) in world
+            .query_enabled::<(
+                &mut Projectile,
+                &mut BounceInBounds,
+                Option<&mut QuadraticMotion>,
+                Option<&mut DirectionalMotion>,
+                Option<&Parent>,
+            )>()
+            .iter()
+        {
+            // Bounces are worked out in world space, but the origin and the motion are in
+            // the frame of the bullet's group, if it's in an anchored one.
+            let frame = group_frame(world, parent).map_or(0., |frame| frame.rotation.angle());
+            let to_world = UnitComplex::new(frame);
+            let to_local = to_world.inverse();
+
+            let p = proj.position.translation.vector;
+            let mut shift = Vector2::zeros();
+            let (mut flip_x, mut flip_y) = (false, false);
+
+            if p.x < bounds.mins.x {
+                shift.x = 2. * (bounds.mins.x - p.x);
+                flip_x = true;
+            } else if p.x > bounds.maxs.x {
+                shift.x = 2. * (bounds.maxs.x - p.x);
+                flip_x = true;
+            }
+
+            if p.y < bounds.mins.y {
+                shift.y = 2. * (bounds.mins.y - p.y);
+                flip_y = true;
+            } else if p.y > bounds.maxs.y {
+                shift.y = 2. * (bounds.maxs.y - p.y);
+                flip_y = true;
+            }
+
+            // Bullets without motion we know how to reflect would only leave the bounds
+            // again, so they're left alone altogether.
+            let reflectable = quadratic.is_some() || directional.is_some();
+            if !(flip_x || flip_y) || bounce.times == 0 || !reflectable {
+                continue;
+            }
+
+            // The motion components are all applied on top of the origin, so moving the
+            // origin moves the bullet without disturbing its motion.
+            proj.origin.translation.vector += to_local * shift;
+            proj.position.translation.vector += shift;
+
+            // Mirroring in a single axis reverses the direction of any spin.
+            let mirrored = flip_x != flip_y;
+
+            if let Some(mut quadratic) = quadratic {
+                let mut linear = to_world * quadratic.velocity.linear;
+                if flip_x {
+                    linear.x = -linear.x;
+                }
+                if flip_y {
+                    linear.y = -linear.y;
+                }
+                quadratic.velocity.linear = to_local * linear;
+                if mirrored {
+                    quadratic.velocity.angular = -quadratic.velocity.angular;
+                }
+            }
+
+            if let Some(mut directional) = directional {
+                let mut angle = directional.integrated.rotation.angle() + frame;
+                if flip_x {
+                    angle = f32::consts::PI - angle;
+                }
+                if flip_y {
+                    angle = -angle;
+                }
+                directional.integrated.rotation = UnitComplex::new(angle - frame);
+                if mirrored {
+                    directional.velocity.angular = -directional.velocity.angular;
+                }
+            }
+
+            bounce.times -= 1;
+            if bounce.times == 0 {
+                self.bounces_exhausted.push(e);
+            }
+        }
+
+        for &e in &self.bounces_exhausted[already_exhausted..] {
+            let _ = world.remove_one::<BounceInBounds>(e);
+        }
+
+        let extents = bounds.extents();
+        for (_e, (mut proj, _, parent)) in world
+            .query_enabled::<(&mut Projectile, &WrapAround, Option<&Parent>)>()
+            .iter()
+        {
+            let to_local = group_frame(world, parent)
+                .map_or_else(UnitComplex::identity, |frame| frame.rotation.inverse());
+
+            let p = proj.position.translation.vector;
+            let mut shift = Vector2::zeros();
+
+            if p.x < bounds.mins.x {
+                shift.x = extents.x;
+            } else if p.x > bounds.maxs.x {
+                shift.x = -extents.x;
+            }
+
+            if p.y < bounds.mins.y {
+                shift.y = extents.y;
+            } else if p.y > bounds.maxs.y {
+                shift.y = -extents.y;
+            }
+
+            proj.origin.translation.vector += to_local * shift;
+            proj.position.translation.vector += shift;
+        }
+    }
+
+    /// Advance every bullet's motion by `dt` seconds and despawn the ones which left
+    /// their bounds. Bullet lifetimes aren't counted here: give a bullet a
+    /// [`Lifetime`](sludge::lifetime::Lifetime), which replaces the old
+    /// `DespawnAfterTimeLimit`, and the space's `LifetimeSystem` counts it down by the
+    /// space's [fixed timestep](sludge::timestep), the same step the [`DanmakuSystem`]
+    /// passes here.
     pub fn update(&mut self, world: &mut World, dt: f32) {
         self.clear_delay = (self.clear_delay - dt).max(0.);
 
-        for (_e, (mut proj, mut quadratic, maximum)) in world
-            .query::<(
+        for (_e, (mut proj, mut quadratic, maximum, parent)) in world
+            .query_enabled::<(
                 &mut Projectile,
                 &mut QuadraticMotion,
                 Option<&MaximumVelocity>,
+                Option<&Parent>,
             )>()
             .iter()
         {
+            let dt = dt * group_speed(world, parent);
             let quadratic = &mut *quadratic;
             quadratic.velocity += quadratic.acceleration * dt;
 
@@ -206,14 +503,16 @@ impl Danmaku {
             proj.next_position.rotation *= quadratic.integrated.rotation;
         }
 
-        for (_e, (mut proj, mut directional, maximum)) in world
-            .query::<(
+        for (_e, (mut proj, mut directional, maximum, parent)) in world
+            .query_enabled::<(
                 &mut Projectile,
                 &mut DirectionalMotion,
                 Option<&MaximumVelocity>,
+                Option<&Parent>,
             )>()
             .iter()
         {
+            let dt = dt * group_speed(world, parent);
             let directional = &mut *directional;
             directional.velocity += directional.acceleration * dt;
 
@@ -236,12 +535,12 @@ impl Danmaku {
             proj.next_position.rotation *= directional.integrated.rotation;
         }
 
-        for (e, (mut proj, mut motion)) in world
-            .query::<(&mut Projectile, &mut ParametricMotion)>()
+        for (e, (mut proj, mut motion, parent)) in world
+            .query_enabled::<(&mut Projectile, &mut ParametricMotion, Option<&Parent>)>()
             .iter()
         {
             let (proj, motion) = (&mut *proj, &mut *motion);
-            let iso = motion.update(dt);
+            let iso = motion.update(dt * group_speed(world, parent));
             proj.next_position.translation *= iso.translation;
             proj.next_position.rotation *= iso.rotation;
 
@@ -250,49 +549,160 @@ impl Danmaku {
             }
         }
 
-        for (_e, (mut proj,)) in world.query::<(&mut Projectile,)>().iter() {
+        for (_e, (mut proj, parent)) in world
+            .query_enabled::<(&mut Projectile, Option<&Parent>)>()
+            .iter()
+        {
             let proj = &mut *proj;
-            proj.position = proj.next_position;
+            proj.position = match group_frame(world, parent) {
+                Some(frame) => frame * proj.next_position,
+                None => proj.next_position,
+            };
             proj.next_position = proj.origin;
         }
 
+        if let Some(bounds) = self.bounds {
+            self.bounce_and_wrap(world, &bounds);
+        }
+
         if let Some(bounds) = self.bounds {
             for (e, (proj, collision, _)) in world
-                .query::<(&Projectile, &Collision, &DespawnOutOfBounds)>()
+                .query_enabled::<(&Projectile, &Collision, &DespawnOutOfBounds)>()
                 .iter()
             {
-                let bb = match *collision {
-                    Collision::Circle { radius } => Box2::from_half_extents(
-                        Point2::from(proj.position.translation.vector),
-                        Vector2::repeat(radius),
-                    ),
-                    Collision::Rectangle { radii } => {
-                        let homogeneous = homogeneous_mat3_to_mat4(&proj.position.to_homogeneous());
-                        Box2::from_half_extents(Point2::origin(), radii)
-                            .transformed_by(&homogeneous)
-                    }
-                };
-
-                if !bounds.intersects(&bb) {
+                if !bounds.intersects(&collision.bounds(&proj.position)) {
                     self.to_despawn.add(e.id());
                 }
             }
         }
 
-        for (e, (_, mut time_limit)) in world
-            .query::<(&Projectile, &mut DespawnAfterTimeLimit)>()
-            .iter()
-        {
-            time_limit.ttl -= dt;
-            if time_limit.ttl <= 0. {
-                self.to_despawn.add(e.id());
-            }
-        }
-
         for id in self.to_despawn.drain() {
             let entity = unsafe { world.find_entity_from_id(id) };
             world.despawn(entity).unwrap();
         }
+
+        despawn_abandoned_anchors(world);
+
+        self.live_bullets = world.query::<&Projectile>().iter().count();
+        self.bullet_grid.get_mut().stale = true;
+    }
+
+    /// Find every enabled bullet which intersects an area, using a spatial hash of the
+    /// bullets. The hash is brought up to date by the first query after each
+    /// [`update`](Danmaku::update) or spawn, so bullets moved by hand between updates
+    /// are found where they were, which is close enough for mechanics like grazing
+    /// without searching every bullet in the world.
+    pub fn bullets_in<'a>(
+        &'a self,
+        world: &'a World,
+        area: impl Into<BulletArea>,
+    ) -> impl Iterator<Item = Entity> + 'a {
+        let area = area.into();
+        self.candidates(world, &area.bounds())
+            .into_iter()
+            .filter(move |&e| {
+                self.near(world, e, Layers::ALL, |proj, collision| {
+                    Collision::proximity(
+                        &area.position,
+                        &area.collision,
+                        &proj.position,
+                        collision,
+                        0.,
+                    ) == Proximity::Intersecting
+                })
+            })
+    }
+
+    /// Every bullet in the spatial hash whose bounds might overlap `bounds`.
+    pub(crate) fn candidates(&self, world: &World, bounds: &Box2<f32>) -> Vec<Entity> {
+        let mut bullet_grid = self.bullet_grid.borrow_mut();
+        bullet_grid.refresh(world);
+        let mut candidates = bullet_grid
+            .grid
+            .query(bounds)
+            .map(|index| *bullet_grid.grid[index].userdata())
+            .collect::<Vec<_>>();
+        // Bullets spanning several buckets turn up once for each.
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+    }
+
+    /// Check a candidate from [`Danmaku::candidates`]: it has to still be an enabled
+    /// bullet on any of `layers`, and pass `test`.
+    pub(crate) fn near(
+        &self,
+        world: &World,
+        entity: Entity,
+        layers: Layers,
+        test: impl FnOnce(&Projectile, &Collision) -> bool,
+    ) -> bool {
+        if !world.is_enabled(entity) {
+            return false;
+        }
+
+        let mut query = match world.query_one::<(&Projectile, &Collision)>(entity) {
+            Ok(query) => query,
+            Err(_) => return false,
+        };
+        let near = match query.get() {
+            Some((proj, collision)) => proj.layers.intersects(layers) && test(proj, collision),
+            None => false,
+        };
+        near
+    }
+}
+
+/// The spatial hash behind [`Danmaku::bullets_in`]. Like sludge-2d's `SpatialHasher`,
+/// it keeps each bullet's index in the grid and moves it rather than starting over, but
+/// it only does so when it's queried, so frames where nothing asks where the bullets
+/// are don't pay for it.
+struct BulletGrid {
+    grid: HashGrid<Entity>,
+    indices: HashMap<Entity, SpatialIndex>,
+    seen: HashSet<Entity>,
+    stale: bool,
+}
+
+impl BulletGrid {
+    fn new() -> Self {
+        Self {
+            grid: HashGrid::new(BULLET_GRID_BUCKET_SIZE),
+            indices: HashMap::new(),
+            seen: HashSet::new(),
+            stale: true,
+        }
+    }
+
+    fn refresh(&mut self, world: &World) {
+        if !self.stale {
+            return;
+        }
+        self.stale = false;
+
+        self.seen.clear();
+        for (e, (proj, collision)) in world.query::<(&Projectile, &Collision)>().iter() {
+            let bounds = collision.bounds(&proj.position);
+            match self.indices.get(&e) {
+                Some(&index) => {
+                    self.grid.update(index, bounds);
+                }
+                None => {
+                    let index = self.grid.insert(bounds, e);
+                    self.indices.insert(e, index);
+                }
+            }
+            self.seen.insert(e);
+        }
+
+        let (grid, seen) = (&mut self.grid, &self.seen);
+        self.indices.retain(|e, &mut index| {
+            let alive = seen.contains(e);
+            if !alive {
+                grid.remove(index);
+            }
+            alive
+        });
     }
 }
 
@@ -468,6 +878,47 @@ impl<'a, R: Resources<'a>> DanmakuResourceExt for R {
 
 // impl LuaUserData for LuaBullet {}
 
+/// The global transform of the anchor of the group a bullet is in, if it's in an
+/// anchored group.
+fn group_frame(world: &World, parent: Option<&Parent>) -> Option<Isometry2<f32>> {
+    let anchor = parent?.parent_entity;
+    world.get::<GroupAnchor>(anchor).ok()?;
+    let frame = world.get::<Transform2d>(anchor).ok()?.global().isometry;
+    Some(frame)
+}
+
+/// Despawn the anchors of groups which have been dropped, once nothing is attached to
+/// them any more.
+fn despawn_abandoned_anchors(world: &mut World) {
+    let abandoned = world
+        .query::<&GroupAnchor>()
+        .iter()
+        .filter(|(_, anchor)| anchor.group.strong_count() == 0)
+        .map(|(e, _)| e)
+        .collect::<HashSet<_>>();
+    if abandoned.is_empty() {
+        return;
+    }
+
+    let attached = world
+        .query::<&Parent>()
+        .iter()
+        .map(|(_, parent)| parent.parent_entity)
+        .filter(|anchor| abandoned.contains(anchor))
+        .collect::<HashSet<_>>();
+    for &anchor in abandoned.difference(&attached) {
+        let _ = world.despawn(anchor);
+    }
+}
+
+/// The speed multiplier of the group a bullet is in, or `1` if it isn't in an anchored
+/// group.
+fn group_speed(world: &World, parent: Option<&Parent>) -> f32 {
+    parent
+        .and_then(|parent| world.get::<GroupAnchor>(parent.parent_entity).ok())
+        .map_or(1., |anchor| anchor.speed)
+}
+
 pub struct DanmakuSystem;
 
 impl System for DanmakuSystem {
@@ -475,20 +926,41 @@ impl System for DanmakuSystem {
         &self,
         _lua: LuaContext,
         local: &mut OwnedResources,
-        _global: Option<&SharedResources>,
+        global: Option<&SharedResources>,
     ) -> Result<()> {
         if !local.has_value::<Danmaku>() {
-            local.insert(Danmaku::new());
+            let mut danmaku = Danmaku::new();
+            if let Some(settings) = global.and_then(|global| global.fetch_one::<Settings>().ok()) {
+                danmaku.declare_layers(
+                    settings
+                        .borrow()
+                        .section::<BTreeMap<String, u32>>(LAYERS_SECTION),
+                )?;
+            }
+            local.insert(danmaku);
         }
 
         Ok(())
     }
 
-    fn update(&self, _lua: LuaContext, resources: &UnifiedResources) -> Result<()> {
+    fn update(&self, lua: LuaContext, resources: &UnifiedResources) -> Result<()> {
+        if pause::is_paused(resources, pause::GAMEPLAY) {
+            return Ok(());
+        }
+
         let (world, danmaku) = resources.fetch::<(World, Danmaku)>()?;
-        danmaku
-            .borrow_mut()
-            .update(&mut *world.borrow_mut(), 1. / 60.);
+        let exhausted = {
+            let mut danmaku = danmaku.borrow_mut();
+            danmaku.update(&mut *world.borrow_mut(), timestep::fixed_dt(resources));
+            danmaku.drain_bounces_exhausted().collect::<Vec<_>>()
+        };
+
+        for entity in exhausted {
+            lua.broadcast(BOUNCES_EXHAUSTED_EVENT, LuaEntity::from(entity))?;
+        }
+
+        cap::spawn_deferred(lua)?;
+        delay::spawn_due(lua)?;
 
         Ok(())
     }
@@ -496,6 +968,7 @@ impl System for DanmakuSystem {
 
 pub mod api {
     use super::*;
+    use sludge::api::math::LuaCurve;
 
     fn wrap<'lua2, A, R, F>(lua: LuaContext<'lua2>, f: F) -> LuaResult<LuaValue<'lua2>>
     where
@@ -506,8 +979,23 @@ pub mod api {
         lua.create_function(f)?.to_lua(lua)
     }
 
-    pub fn new_group<'lua>(_lua: LuaContext<'lua>, _: ()) -> LuaResult<Group> {
-        Ok(Group::new())
+    /// `danmaku.new_group()` creates a plain group; `danmaku.new_group(x, y, angle)`
+    /// creates an anchored group with its anchor at the given position.
+    pub fn new_group<'lua>(
+        lua: LuaContext<'lua>,
+        (x, y, angle): (Option<f32>, Option<f32>, Option<f32>),
+    ) -> LuaResult<Group> {
+        if x.is_none() && y.is_none() && angle.is_none() {
+            return Ok(Group::new());
+        }
+
+        let position = Isometry2::new(
+            Vector2::new(x.unwrap_or(0.), y.unwrap_or(0.)),
+            angle.unwrap_or(0.),
+        );
+        let world = lua.fetch_one::<World>()?;
+        let group = Group::anchored(&mut world.borrow_mut(), position);
+        Ok(group)
     }
 
     pub fn spawn<'lua>(
@@ -524,8 +1012,16 @@ pub mod api {
             .transpose()?;
         let resources = lua.resources();
         let world = resources.fetch_one::<World>()?;
+        let danmaku = resources.fetch_one::<Danmaku>()?;
+        let budget = cap::min_budget(
+            danmaku.borrow().spawn_budget(),
+            maybe_group
+                .as_deref_mut()
+                .and_then(|group| group.spawn_budget(&world.borrow())),
+        );
 
         let mut batch = Batch::new(lua).to_lua_err()?;
+        batch.set_budget(budget);
         lua.scope(|scope| -> LuaResult<()> {
             let emit_closure =
                 scope.create_function_mut(|_lua, op: Op| batch.op(op).to_lua_err())?;
@@ -534,31 +1030,126 @@ pub mod api {
             Ok(())
         })?;
 
-        let entities = batch.spawn(&resources, &world).to_lua_err()?;
+        let entities = batch
+            .spawn(&resources, &world)
+            .to_lua_err()?
+            .collect::<Vec<_>>();
+        danmaku.borrow_mut().record_spawned(entities.len());
         if let Some(group) = maybe_group.as_deref_mut() {
-            group.entities.extend(entities);
+            group.adopt(&mut world.borrow_mut(), &entities);
+        }
+        drop(maybe_group);
+
+        let delayed = batch.take_delayed();
+        if !delayed.is_empty() {
+            delay::schedule(lua, delayed, maybe_lua_group.clone()).to_lua_err()?;
+        }
+
+        let overflow = batch.take_overflow();
+        if !overflow.is_empty() {
+            cap::overflowed(lua, overflow, maybe_lua_group).to_lua_err()?;
         }
 
         Ok(())
     }
 
-    pub fn clear_screen<'lua>(lua: LuaContext<'lua>, delay: Option<f32>) -> LuaResult<()> {
-        let (world, danmaku) = lua.fetch::<(World, Danmaku)>()?;
-        let world = world.borrow();
-        let mut buf = world.get_buffer();
-        world
-            .query::<()>()
-            .with::<Projectile>()
-            .iter()
-            .for_each(|(e, ())| {
-                buf.despawn(e);
-            });
-        world.queue_buffer(buf);
+    /// Bake a Lua function `f(t) -> x, y, angle` over `t` from `0` to `1` into a curve
+    /// with `samples` keyframes (64 by default), and register it for use with
+    /// `builder:curve(id)`. A `sludge.math` curve can be given in place of the function,
+    /// in which case bullets follow it at a constant speed, turning to face along it if
+    /// `orient` is `true`.
+    pub fn new_curve<'lua>(
+        lua: LuaContext<'lua>,
+        (shape, samples, orient): (LuaValue<'lua>, Option<usize>, Option<bool>),
+    ) -> LuaResult<CurveId> {
+        let samples = samples.unwrap_or(64);
+        let curve = match shape {
+            LuaValue::Function(f) => SampledCurve::bake(samples, |t| {
+                let (x, y, angle) = f.call::<_, (f32, f32, Option<f32>)>(t)?;
+                Ok((x, y, angle.unwrap_or(0.)))
+            })
+            .to_lua_err()?,
+            other => {
+                let curve = LuaCurve::from_lua(other, lua)?;
+                SampledCurve::from_curve(&curve.0, samples, orient.unwrap_or(false))
+            }
+        };
 
-        if let Some(delay) = delay {
-            danmaku.borrow_mut().set_clear_delay(delay);
+        Ok(lua.fetch_one::<Danmaku>()?.borrow_mut().insert_curve(curve))
+    }
+
+    pub fn remove_curve<'lua>(lua: LuaContext<'lua>, id: CurveId) -> LuaResult<()> {
+        lua.fetch_one::<Danmaku>()?.borrow_mut().remove_curve(id);
+        Ok(())
+    }
+
+    /// Resolve any mix of layer names and raw layer masks into a single set of layers.
+    pub(crate) fn layers_from_lua<'lua>(
+        lua: LuaContext<'lua>,
+        values: LuaMultiValue<'lua>,
+    ) -> LuaResult<Layers> {
+        let danmaku = lua.fetch_one::<Danmaku>()?;
+        let danmaku = danmaku.borrow();
+        let mut layers = Layers::NONE;
+        for value in values {
+            let layer = match value {
+                LuaValue::String(name) => danmaku.layer(name.to_str()?).to_lua_err()?,
+                other => Layers::from_lua(other, lua)?,
+            };
+            layers = layers.union(layer);
         }
+        Ok(layers)
+    }
+
+    pub fn layer<'lua>(lua: LuaContext<'lua>, names: LuaMultiValue<'lua>) -> LuaResult<Layers> {
+        layers_from_lua(lua, names)
+    }
+
+    pub fn hits<'lua>(
+        lua: LuaContext<'lua>,
+        (x, y, radius, layers): (f32, f32, f32, Option<Layers>),
+    ) -> LuaResult<Vec<LuaEntity>> {
+        let (world, danmaku) = lua.fetch::<(World, Danmaku)>()?;
+        let hits = danmaku.borrow().hits(
+            &world.borrow(),
+            layers.unwrap_or(Layers::ALL),
+            &Isometry2::translation(x, y),
+            &Collision::circle(radius),
+        );
+        Ok(hits.into_iter().map(LuaEntity::from).collect())
+    }
+
+    /// Collect the bullets in a circle into a group, optionally only those on any of
+    /// `layers`. See [`Danmaku::bullets_in`] for the caveats.
+    pub fn query_circle<'lua>(
+        lua: LuaContext<'lua>,
+        (x, y, radius, layers): (f32, f32, f32, Option<Layers>),
+    ) -> LuaResult<Group> {
+        let (world, danmaku) = lua.fetch::<(World, Danmaku)>()?;
+        let (world, danmaku) = (world.borrow(), danmaku.borrow());
+        let layers = layers.unwrap_or(Layers::ALL);
+
+        let mut group = Group::new();
+        group.entities.extend(
+            danmaku
+                .bullets_in(&world, BulletArea::circle(Point2::new(x, y), radius))
+                .filter(|&e| {
+                    world
+                        .get::<Projectile>(e)
+                        .map_or(false, |proj| proj.layers.intersects(layers))
+                }),
+        );
+        Ok(group)
+    }
 
+    pub fn clear_screen<'lua>(
+        lua: LuaContext<'lua>,
+        (delay, layers): (Option<f32>, Option<Layers>),
+    ) -> LuaResult<()> {
+        let (world, danmaku) = lua.fetch::<(World, Danmaku)>()?;
+        danmaku
+            .borrow_mut()
+            .clear_layers(&world.borrow(), layers.unwrap_or(Layers::ALL), delay);
         Ok(())
     }
 
@@ -605,7 +1196,7 @@ pub mod api {
 
     pub mod pattern {
         use super::*;
-        use crate::pattern::{Aimed, Arc, Destination, Ring, Stack};
+        use crate::pattern::{Aimed, Arc, Destination, Pattern, Ring, Stack};
 
         pub fn aimed<'lua>(_lua: LuaContext<'lua>, (x, y): (f32, f32)) -> LuaResult<RustPattern> {
             Ok(RustPattern::new(Aimed {
@@ -642,6 +1233,39 @@ pub mod api {
             Ok(RustPattern::new(pattern))
         }
 
+        /// `danmaku.pattern.of(pattern, subpattern)`, where either may be a pattern or a
+        /// Lua function taking a builder: fire `subpattern` everywhere `pattern` fires.
+        pub fn of<'lua>(
+            lua: LuaContext<'lua>,
+            (pattern, subpattern): (LuaValue<'lua>, LuaValue<'lua>),
+        ) -> LuaResult<RustPattern> {
+            let pattern = RustPattern::from_lua_or_function(pattern, lua)?;
+            let subpattern = RustPattern::from_lua_or_function(subpattern, lua)?;
+            Ok(RustPattern::new(pattern.of(subpattern)))
+        }
+
+        /// `danmaku.pattern.repeat_n(pattern, count, interval)`: build `pattern` `count`
+        /// times, each `interval` ticks (0 by default) after the last.
+        pub fn repeat_n<'lua>(
+            lua: LuaContext<'lua>,
+            (pattern, count, interval): (LuaValue<'lua>, u32, Option<u32>),
+        ) -> LuaResult<RustPattern> {
+            let pattern = RustPattern::from_lua_or_function(pattern, lua)?;
+            Ok(RustPattern::new(
+                pattern.repeat_n(count, interval.unwrap_or(0)),
+            ))
+        }
+
+        /// `danmaku.pattern.delay(pattern, frames)`: hold back everything `pattern`
+        /// fires for `frames` ticks.
+        pub fn delay<'lua>(
+            lua: LuaContext<'lua>,
+            (pattern, frames): (LuaValue<'lua>, u32),
+        ) -> LuaResult<RustPattern> {
+            let pattern = RustPattern::from_lua_or_function(pattern, lua)?;
+            Ok(RustPattern::new(pattern.delay(frames)))
+        }
+
         pub fn ring<'lua>(
             _lua: LuaContext<'lua>,
             (radius, count): (f32, u32),
@@ -663,8 +1287,11 @@ pub mod api {
             let t = lua.create_table_from(vec![
                 ("aimed", wrap(lua, aimed)?),
                 ("arc", wrap(lua, arc)?),
+                ("delay", wrap(lua, delay)?),
                 ("destination", wrap(lua, destination)?),
                 ("new", wrap(lua, new)?),
+                ("of", wrap(lua, of)?),
+                ("repeat_n", wrap(lua, repeat_n)?),
                 ("ring", wrap(lua, ring)?),
                 ("stack", wrap(lua, stack)?),
             ])?;
@@ -676,11 +1303,26 @@ pub mod api {
         let t = lua.create_table_from(vec![
             ("pattern", pattern::load(lua)?),
             ("bullet", bullet::load(lua)?),
+            ("boss", crate::boss::api::load(lua)?),
             ("new_group", wrap(lua, new_group)?),
+            ("new_curve", wrap(lua, new_curve)?),
+            ("remove_curve", wrap(lua, remove_curve)?),
+            ("layer", wrap(lua, layer)?),
+            ("hits", wrap(lua, hits)?),
+            ("collide", wrap(lua, crate::hit::api::collide)?),
+            ("user_data", wrap(lua, crate::hit::api::user_data)?),
+            ("query_circle", wrap(lua, query_circle)?),
             ("spawn", wrap(lua, spawn)?),
             ("clear_screen", wrap(lua, clear_screen)?),
             ("set_clear_delay", wrap(lua, set_clear_delay)?),
+            ("set_bullet_cap", wrap(lua, cap::api::set_bullet_cap)?),
+            ("bullet_count", wrap(lua, cap::api::bullet_count)?),
+            ("cap_stats", wrap(lua, cap::api::cap_stats)?),
         ])?;
+        t.set("BOUNCES_EXHAUSTED_EVENT", BOUNCES_EXHAUSTED_EVENT)?;
+        t.set("CAP_REACHED_EVENT", CAP_REACHED_EVENT)?;
+        t.set("HIT_EVENT", HIT_EVENT)?;
+        t.set("GRAZE_EVENT", GRAZE_EVENT)?;
         Ok(LuaValue::Table(t))
     }
 }
@@ -817,3 +1459,250 @@ pub mod api {
 inventory::submit! {
     Module::parse("danmaku", api::load)
 }
+
+// Delayed and deferred spawns hold registry keys for their groups, which have to go
+// before the Lua state does.
+inventory::submit! {
+    Finalizer::new("danmaku.spawns", ShutdownStage::StopScripts, |_lua, resources| {
+        if let Ok(danmaku) = resources.local.fetch_one::<Danmaku>() {
+            let mut danmaku = danmaku.borrow_mut();
+            danmaku.delayed.clear();
+            danmaku.deferred.clear();
+        }
+        Ok(())
+    })
+}
+
+// Bullet types are free to hold onto sprite batches and textures for drawing their
+// bullets.
+inventory::submit! {
+    Finalizer::new("danmaku.bullet_types", ShutdownStage::QueueGpu, |_lua, resources| {
+        if let Ok(danmaku) = resources.local.fetch_one::<Danmaku>() {
+            let danmaku = danmaku.borrow();
+            *danmaku
+                .bullet_types
+                .write()
+                .unwrap_or_else(|p| p.into_inner()) = BulletTypes::new();
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pattern::Group;
+
+    fn bullet_type() -> BulletTypeId {
+        BulletTypeId(thunderdome::Arena::new().insert(()))
+    }
+
+    #[test]
+    fn dropped_groups_despawn_their_anchors() {
+        let mut world = World::new();
+        let mut danmaku = Danmaku::new();
+
+        let group = Group::anchored(&mut world, Isometry2::identity());
+        let anchor = group.anchor().unwrap();
+        let bullet = world.spawn((Parent::new(anchor),));
+
+        let clone = group.clone();
+        drop(group);
+        danmaku.update(&mut world, 0.);
+        assert!(
+            world.contains(anchor),
+            "a clone of the group is still alive"
+        );
+
+        drop(clone);
+        danmaku.update(&mut world, 0.);
+        assert!(world.contains(anchor), "a bullet is still attached");
+
+        world.despawn(bullet).unwrap();
+        danmaku.update(&mut world, 0.);
+        assert!(!world.contains(anchor));
+    }
+
+    #[test]
+    fn bullets_in_rotated_groups_bounce_in_world_space() {
+        let mut world = World::new();
+        let bounds = Box2::new(-10., -10., 20., 20.);
+        let mut danmaku = Danmaku::with_bounds(bounds);
+
+        // The group's x axis points up the world's y axis.
+        let mut group = Group::anchored(
+            &mut world,
+            Isometry2::new(Vector2::zeros(), f32::consts::FRAC_PI_2),
+        );
+        let origin = Isometry2::translation(9.5, 0.);
+        let bullet = world.spawn((
+            Projectile::new(bullet_type(), origin),
+            QuadraticMotion::with_velocity(Velocity2::new(Vector2::new(1., 0.), 0.)),
+            BounceInBounds { times: 2 },
+        ));
+        group.adopt(&mut world, &[bullet]);
+
+        // Heading up out of the bounds, and reflected back down.
+        danmaku.update(&mut world, 1.);
+
+        let proj = world.get::<Projectile>(bullet).unwrap();
+        let position = proj.position().translation.vector;
+        assert!(
+            (position - Vector2::new(0., 9.5)).norm() < 1e-4,
+            "{}",
+            position
+        );
+
+        let velocity = world
+            .get::<QuadraticMotion>(bullet)
+            .unwrap()
+            .velocity
+            .linear;
+        assert!(
+            (velocity - Vector2::new(-1., 0.)).norm() < 1e-4,
+            "{}",
+            velocity
+        );
+        assert_eq!(world.get::<BounceInBounds>(bullet).unwrap().times, 1);
+    }
+
+    #[test]
+    fn bullets_in_follows_moves_and_despawns() {
+        let mut world = World::new();
+        let mut danmaku = Danmaku::new();
+        let area = BulletArea::circle(Point2::new(100., 0.), 4.);
+
+        let bullet = world.spawn((
+            Projectile::new(bullet_type(), Isometry2::translation(0., 0.)),
+            Collision::circle(2.),
+            QuadraticMotion::with_velocity(Velocity2::new(Vector2::new(100., 0.), 0.)),
+        ));
+        let bystander = world.spawn((
+            Projectile::new(bullet_type(), Isometry2::translation(200., 0.)),
+            Collision::circle(2.),
+        ));
+
+        let near_origin = BulletArea::circle(Point2::origin(), 4.);
+        assert_eq!(
+            danmaku.bullets_in(&world, near_origin).collect::<Vec<_>>(),
+            vec![bullet],
+            "the first query builds the hash",
+        );
+
+        danmaku.update(&mut world, 1.);
+        assert_eq!(
+            danmaku.bullets_in(&world, area).collect::<Vec<_>>(),
+            vec![bullet]
+        );
+        assert_eq!(danmaku.bullets_in(&world, near_origin).count(), 0);
+
+        world.insert_one(bullet, Disabled).unwrap();
+        assert_eq!(danmaku.bullets_in(&world, area).count(), 0);
+
+        world.despawn(bullet).unwrap();
+        danmaku.update(&mut world, 0.);
+        assert_eq!(danmaku.bullets_in(&world, area).count(), 0);
+        assert_eq!(danmaku.bullet_grid.borrow().indices.len(), 1);
+        assert_eq!(
+            danmaku
+                .bullets_in(&world, BulletArea::circle(Point2::new(200., 0.), 1.))
+                .collect::<Vec<_>>(),
+            vec![bystander]
+        );
+    }
+
+    #[test]
+    fn bullets_bounce_until_exhausted() {
+        let mut world = World::new();
+        let mut danmaku = Danmaku::with_bounds(Box2::new(-10., -10., 20., 20.));
+
+        let bullet = world.spawn((
+            Projectile::new(bullet_type(), Isometry2::translation(9., 0.)),
+            QuadraticMotion::with_velocity(Velocity2::new(Vector2::new(2., 0.), 0.)),
+            BounceInBounds { times: 1 },
+        ));
+        let parametric = world.spawn((
+            Projectile::new(bullet_type(), Isometry2::translation(11., 0.)),
+            BounceInBounds { times: 1 },
+        ));
+
+        // Overshooting the right edge by one is reflected back inside by one.
+        danmaku.update(&mut world, 1.);
+        let x = world
+            .get::<Projectile>(bullet)
+            .unwrap()
+            .position()
+            .translation
+            .x;
+        assert!((x - 9.).abs() < 1e-4, "{}", x);
+        let velocity = world.get::<QuadraticMotion>(bullet).unwrap().velocity;
+        assert!((velocity.linear.x + 2.).abs() < 1e-4);
+        assert_eq!(
+            danmaku.drain_bounces_exhausted().collect::<Vec<_>>(),
+            vec![bullet]
+        );
+        assert!(world.get::<BounceInBounds>(bullet).is_err());
+
+        // Nothing reflects the other bullet's motion, so it isn't bounced at all.
+        let proj = world.get::<Projectile>(parametric).unwrap();
+        assert_eq!(proj.position().translation.x, 11.);
+        assert_eq!(world.get::<BounceInBounds>(parametric).unwrap().times, 1);
+    }
+
+    #[test]
+    fn bullets_wrap_to_the_opposite_edge() {
+        let mut world = World::new();
+        let mut danmaku = Danmaku::with_bounds(Box2::new(0., 0., 100., 50.));
+
+        let bullet = world.spawn((
+            Projectile::new(bullet_type(), Isometry2::translation(98., 49.)),
+            QuadraticMotion::with_velocity(Velocity2::new(Vector2::new(4., 2.), 0.)),
+            WrapAround,
+        ));
+
+        danmaku.update(&mut world, 1.);
+        let position = world
+            .get::<Projectile>(bullet)
+            .unwrap()
+            .position()
+            .translation
+            .vector;
+        assert!(
+            (position - Vector2::new(2., 1.)).norm() < 1e-4,
+            "{}",
+            position
+        );
+        let velocity = world.get::<QuadraticMotion>(bullet).unwrap().velocity;
+        assert_eq!(velocity.linear, Vector2::new(4., 2.));
+    }
+
+    #[test]
+    fn layers_keep_their_declared_bits() -> Result<()> {
+        let mut danmaku = Danmaku::new();
+        assert!(danmaku.layer("enemy").is_err());
+
+        danmaku.declare_layers(vec![("enemy", 1), ("player", 0)])?;
+        assert_eq!(danmaku.layer("player")?, Layers(1));
+        assert_eq!(danmaku.layer("enemy")?, Layers(2));
+        assert!(danmaku.declare_layer("too far", 32).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn the_system_declares_layers_from_settings() -> Result<()> {
+        let mut section = BTreeMap::new();
+        section.insert("player", 5u32);
+        let mut settings = Settings::new();
+        settings.set_section(LAYERS_SECTION, &section)?;
+        let global = SharedResources::new();
+        global.borrow_mut().insert(settings);
+
+        let mut local = OwnedResources::new();
+        Lua::new().context(|lua| DanmakuSystem.init(lua, &mut local, Some(&global)))?;
+        let danmaku = local.remove::<Danmaku>().unwrap();
+        assert_eq!(danmaku.layer("player")?, Layers(1 << 5));
+
+        Ok(())
+    }
+}
@@ -1,13 +1,13 @@
 use ::{
     im::Vector,
-    sludge::prelude::*,
+    sludge::{components::Parent, prelude::*, transform::Transform2d},
     sludge_2d::math::*,
     std::{f32, sync},
 };
 
 use crate::{
     builder::{LuaPatternBuilder, Op, PatternBuilder},
-    components::Projectile,
+    components::{DirectionalMotion, GroupAnchor, Projectile, QuadraticMotion},
 };
 
 pub trait Pattern: Send + Sync {
@@ -24,6 +24,32 @@ pub trait Pattern: Send + Sync {
             subpattern,
         }
     }
+
+    /// Build this pattern `count` times, holding each repetition back `interval` ticks
+    /// longer than the last.
+    #[inline]
+    fn repeat_n(self, count: u32, interval: u32) -> Repeat<Self>
+    where
+        Self: Sized,
+    {
+        Repeat {
+            pattern: self,
+            count,
+            interval,
+        }
+    }
+
+    /// Hold back every bullet this pattern fires for `frames` ticks.
+    #[inline]
+    fn delay(self, frames: u32) -> Delay<Self>
+    where
+        Self: Sized,
+    {
+        Delay {
+            pattern: self,
+            frames,
+        }
+    }
 }
 
 impl<P: Pattern + ?Sized> Pattern for &'_ P {
@@ -60,6 +86,41 @@ where
     }
 }
 
+pub struct Repeat<P: Pattern> {
+    pattern: P,
+    count: u32,
+    interval: u32,
+}
+
+impl<P: Pattern> Pattern for Repeat<P> {
+    fn build<'lua>(&self, builder: &mut dyn PatternBuilder<'lua>) -> Result<()> {
+        for i in 0..self.count {
+            builder.push(None)?;
+            builder.delay(i * self.interval)?;
+            self.pattern.build(builder)?;
+            builder.pop()?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Delay<P: Pattern> {
+    pattern: P,
+    frames: u32,
+}
+
+impl<P: Pattern> Pattern for Delay<P> {
+    fn build<'lua>(&self, builder: &mut dyn PatternBuilder<'lua>) -> Result<()> {
+        builder.push(None)?;
+        builder.delay(self.frames)?;
+        self.pattern.build(builder)?;
+        builder.pop()?;
+
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct LuaPattern {
     key: LuaRegistryKey,
@@ -107,6 +168,18 @@ impl RustPattern {
     pub fn new<P: Pattern + 'static>(pattern: P) -> Self {
         Self(sync::Arc::new(pattern))
     }
+
+    /// Accept either a pattern object or a plain Lua function taking a builder, so that
+    /// Lua-defined patterns can be composed with Rust ones without wrapping them first.
+    pub fn from_lua_or_function<'lua>(
+        value: LuaValue<'lua>,
+        lua: LuaContext<'lua>,
+    ) -> LuaResult<Self> {
+        match value {
+            LuaValue::Function(_) => Ok(Self::new(LuaPattern::from_lua(value, lua)?)),
+            other => Self::from_lua(other, lua),
+        }
+    }
 }
 
 impl Pattern for RustPattern {
@@ -125,12 +198,24 @@ impl LuaUserData for RustPattern {
             Ok(())
         });
 
-        methods.add_function(
-            "of",
-            |_lua, (pattern, subpattern): (RustPattern, RustPattern)| {
-                Ok(RustPattern::new(pattern.of(subpattern)))
+        methods.add_function("of", |lua, (pattern, subpattern): (LuaValue, LuaValue)| {
+            let pattern = RustPattern::from_lua_or_function(pattern, lua)?;
+            let subpattern = RustPattern::from_lua_or_function(subpattern, lua)?;
+            Ok(RustPattern::new(pattern.of(subpattern)))
+        });
+
+        methods.add_method(
+            "repeat_n",
+            |_lua, this, (count, interval): (u32, Option<u32>)| {
+                Ok(RustPattern::new(
+                    this.clone().repeat_n(count, interval.unwrap_or(0)),
+                ))
             },
         );
+
+        methods.add_method("delay", |_lua, this, frames: u32| {
+            Ok(RustPattern::new(this.clone().delay(frames)))
+        });
     }
 }
 
@@ -263,15 +348,172 @@ impl Pattern for Destination {
     }
 }
 
+/// A set of bullets which can be cancelled, capped and turned into a pattern together.
+///
+/// A group can also be *anchored*, in which case it owns an anchor entity with a
+/// [`Transform2d`] and its bullets are positioned relative to that anchor: moving or
+/// rotating the anchor moves or rotates every bullet in the group along with it, which
+/// makes rotating rings and orbiting shields a matter of spawning them once and turning
+/// the group. Bullets fired into an anchored group are fired in its frame, so a ring
+/// fired at `0, 0` is centered on the anchor.
+///
+/// ```lua
+/// local ring = danmaku.new_group(boss_x, boss_y)
+/// danmaku.spawn(function(b) ... end, ring)
+///
+/// while true do
+///     ring:rotate(0.02)
+///     yield()
+/// end
+/// ```
+///
+/// Bullets find their anchor through a [`Parent`] component, and the anchor's global
+/// transform is kept up to date by the 2D transform system, so register that before
+/// the [`DanmakuSystem`](crate::DanmakuSystem). The anchor can be given a parent of its
+/// own to carry the whole group around, for example with a boss. Once every handle to
+/// the group has been dropped and nothing is attached to the anchor any more, the
+/// anchor is despawned by the next [`Danmaku::update`](crate::Danmaku::update).
 #[derive(Debug, Clone, Default)]
 pub struct Group {
     pub(crate) entities: Vector<Entity>,
+    pub(crate) cap: Option<usize>,
+    pub(crate) anchor: Option<Entity>,
+    /// Shared by every clone of an anchored group, so the anchor can tell when the
+    /// group is gone.
+    handle: Option<sync::Arc<()>>,
 }
 
 impl Group {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Create an anchored group, spawning its anchor at the given position.
+    pub fn anchored(world: &mut World, position: Isometry2<f32>) -> Self {
+        let handle = sync::Arc::new(());
+        let anchor = world.spawn((
+            Transform2d::new(position),
+            GroupAnchor {
+                group: sync::Arc::downgrade(&handle),
+                ..GroupAnchor::default()
+            },
+        ));
+        Self {
+            anchor: Some(anchor),
+            handle: Some(handle),
+            ..Self::new()
+        }
+    }
+
+    pub fn anchor(&self) -> Option<Entity> {
+        self.anchor
+    }
+
+    fn anchor_or_err(&self) -> Result<Entity> {
+        self.anchor.ok_or_else(|| {
+            anyhow!("group isn't anchored; anchored groups are created with a position")
+        })
+    }
+
+    /// Add newly spawned bullets to the group, attaching them to its anchor if it has
+    /// one.
+    pub(crate) fn adopt(&mut self, world: &mut World, entities: &[Entity]) {
+        self.entities.extend(entities.iter().copied());
+
+        let anchor = match self.anchor {
+            Some(anchor) => anchor,
+            None => return,
+        };
+
+        // The bullets won't be moved into the group's frame until the next update, so
+        // do it now to keep them from drawing in the wrong place until then.
+        if let Ok(frame) = world
+            .get::<Transform2d>(anchor)
+            .map(|tx| tx.global().isometry)
+        {
+            for &entity in entities {
+                if let Ok(mut proj) = world.get_mut::<Projectile>(entity) {
+                    proj.position = frame * proj.position;
+                }
+            }
+        }
+
+        for &entity in entities {
+            let _ = world.insert_one(entity, Parent::new(anchor));
+        }
+    }
+
+    /// The position of the anchor, relative to its parent if it has one.
+    pub fn position(&self, world: &World) -> Result<Isometry2<f32>> {
+        Ok(world
+            .get::<Transform2d>(self.anchor_or_err()?)?
+            .local()
+            .isometry)
+    }
+
+    pub fn set_position(&self, world: &World, position: Isometry2<f32>) -> Result<()> {
+        world
+            .get_mut::<Transform2d>(self.anchor_or_err()?)?
+            .local_mut()
+            .isometry = position;
+        Ok(())
+    }
+
+    /// Rotate the anchor about its own position.
+    pub fn rotate(&self, world: &World, angle: f32) -> Result<()> {
+        let mut tx = world.get_mut::<Transform2d>(self.anchor_or_err()?)?;
+        let isometry = &mut tx.local_mut().isometry;
+        isometry.rotation = UnitComplex::new(isometry.rotation.angle() + angle);
+        Ok(())
+    }
+
+    pub fn speed(&self, world: &World) -> Result<f32> {
+        Ok(world.get::<GroupAnchor>(self.anchor_or_err()?)?.speed)
+    }
+
+    /// Scale how fast every bullet in the group moves.
+    pub fn set_speed(&self, world: &World, speed: f32) -> Result<()> {
+        world.get_mut::<GroupAnchor>(self.anchor_or_err()?)?.speed = speed;
+        Ok(())
+    }
+
+    /// Turn every bullet in the group with a [`QuadraticMotion`] or a
+    /// [`DirectionalMotion`] to head towards `target`, keeping its speed.
+    pub fn retarget(&mut self, world: &World, target: Point2<f32>) {
+        self.entities.retain(|&e| world.contains(e));
+
+        let frame = self
+            .anchor
+            .and_then(|anchor| world.get::<Transform2d>(anchor).ok())
+            .map(|tx| tx.global().isometry)
+            .unwrap_or_else(Isometry2::identity);
+
+        for &entity in &self.entities {
+            let position = match world.get::<Projectile>(entity) {
+                Ok(proj) => Point2::from(proj.position.translation.vector),
+                Err(_) => continue,
+            };
+
+            // Motions are integrated in the group's frame, not in world space.
+            let direction = frame.rotation.inverse() * (target - position);
+            if direction.norm_squared() <= f32::EPSILON {
+                continue;
+            }
+            let direction = direction.normalize();
+
+            if let Ok(mut quadratic) = world.get_mut::<QuadraticMotion>(entity) {
+                let speed = quadratic.velocity.linear.norm();
+                quadratic.velocity.linear = direction * speed;
+            }
+
+            if let Ok(mut directional) = world.get_mut::<DirectionalMotion>(entity) {
+                let linear = directional.velocity.linear;
+                let heading = linear.y.atan2(linear.x);
+                directional.integrated.rotation =
+                    UnitComplex::new(direction.y.atan2(direction.x) - heading);
+            }
+        }
+    }
 }
 
 impl LuaUserData for Group {
@@ -291,9 +533,81 @@ impl LuaUserData for Group {
             Ok(())
         });
 
+        methods.add_method_mut("set_cap", |_lua, this, cap: Option<usize>| {
+            this.set_cap(cap);
+            Ok(())
+        });
+
+        methods.add_method("cap", |_lua, this, ()| Ok(this.cap()));
+
+        methods.add_method_mut("count", |lua, this, ()| {
+            let tmp = lua.fetch_one::<World>()?;
+            let world = tmp.borrow();
+            this.entities.retain(|&e| world.contains(e));
+            Ok(this.entities.len())
+        });
+
         methods.add_method("to_pattern", |_lua, this, ()| {
             Ok(RustPattern::new(this.clone()))
         });
+
+        methods.add_method("anchor", |_lua, this, ()| {
+            Ok(this.anchor().map(LuaEntity::from))
+        });
+
+        methods.add_method("position", |lua, this, ()| {
+            let tmp = lua.fetch_one::<World>()?;
+            let position = this.position(&tmp.borrow()).to_lua_err()?;
+            let v = position.translation.vector;
+            Ok((v.x, v.y, position.rotation.angle()))
+        });
+
+        methods.add_method(
+            "set_position",
+            |lua, this, (x, y, angle): (f32, f32, Option<f32>)| {
+                let tmp = lua.fetch_one::<World>()?;
+                let world = tmp.borrow();
+                let angle = match angle {
+                    Some(angle) => angle,
+                    None => this.position(&world).to_lua_err()?.rotation.angle(),
+                };
+                this.set_position(&world, Isometry2::new(Vector2::new(x, y), angle))
+                    .to_lua_err()
+            },
+        );
+
+        methods.add_method("translate", |lua, this, (dx, dy): (f32, f32)| {
+            let tmp = lua.fetch_one::<World>()?;
+            let world = tmp.borrow();
+            let mut position = this.position(&world).to_lua_err()?;
+            position.translation.vector += Vector2::new(dx, dy);
+            this.set_position(&world, position).to_lua_err()
+        });
+
+        methods.add_method("rotate", |lua, this, angle: f32| {
+            let tmp = lua.fetch_one::<World>()?;
+            let world = tmp.borrow();
+            this.rotate(&world, angle).to_lua_err()
+        });
+
+        methods.add_method("speed", |lua, this, ()| {
+            let tmp = lua.fetch_one::<World>()?;
+            let world = tmp.borrow();
+            this.speed(&world).to_lua_err()
+        });
+
+        methods.add_method("set_speed", |lua, this, speed: f32| {
+            let tmp = lua.fetch_one::<World>()?;
+            let world = tmp.borrow();
+            this.set_speed(&world, speed).to_lua_err()
+        });
+
+        methods.add_method_mut("retarget", |lua, this, (x, y): (f32, f32)| {
+            let tmp = lua.fetch_one::<World>()?;
+            let world = tmp.borrow();
+            this.retarget(&world, Point2::new(x, y));
+            Ok(())
+        });
     }
 }
 
@@ -0,0 +1,136 @@
+use crate::{CheckError, Fmod, StopMode};
+use {
+    sludge::{pause, prelude::*},
+    sludge_fmod_sys::*,
+};
+
+/// A mixing bus, as set up in FMOD Studio. The master bus has the path `bus:/`.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Bus {
+    pub(crate) ptr: *mut FMOD_STUDIO_BUS,
+}
+
+unsafe impl Send for Bus {}
+unsafe impl Sync for Bus {}
+
+impl Bus {
+    pub(crate) unsafe fn from_ptr(ptr: *mut FMOD_STUDIO_BUS) -> Self {
+        Self { ptr }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        unsafe { FMOD_Studio_Bus_IsValid(self.ptr) != 0 }
+    }
+
+    pub fn is_paused(&self) -> Result<bool> {
+        let mut is_paused = 0i32;
+        unsafe {
+            FMOD_Studio_Bus_GetPaused(self.ptr, &mut is_paused as *mut _).check_err()?;
+        }
+        Ok(is_paused != 0)
+    }
+
+    /// Pause or unpause every event routed through this bus.
+    pub fn set_paused(&self, paused: bool) -> Result<()> {
+        unsafe {
+            FMOD_Studio_Bus_SetPaused(self.ptr, paused as i32).check_err()?;
+        }
+        Ok(())
+    }
+
+    /// Set the bus's volume as a unitless scaling factor.
+    pub fn set_volume(&self, volume: f32) -> Result<()> {
+        unsafe {
+            FMOD_Studio_Bus_SetVolume(self.ptr, volume).check_err()?;
+        }
+        Ok(())
+    }
+
+    /// The bus's volume as set by `set_volume`, and its final volume after modulation.
+    pub fn get_volume(&self) -> Result<(f32, f32)> {
+        let (mut volume, mut final_volume) = (0., 0.);
+        unsafe {
+            FMOD_Studio_Bus_GetVolume(self.ptr, &mut volume, &mut final_volume).check_err()?;
+        }
+        Ok((volume, final_volume))
+    }
+
+    pub fn stop_all_events(&self, stop_mode: StopMode) -> Result<()> {
+        unsafe {
+            FMOD_Studio_Bus_StopAllEvents(self.ptr, stop_mode.into()).check_err()?;
+        }
+        Ok(())
+    }
+}
+
+impl LuaUserData for Bus {
+    fn add_methods<'lua, T: LuaUserDataMethods<'lua, Self>>(methods: &mut T) {
+        methods.add_method("is_valid", |_lua, this, ()| Ok(this.is_valid()));
+
+        methods.add_method("is_paused", |_lua, this, ()| this.is_paused().to_lua_err());
+        methods.add_method("set_paused", |_lua, this, paused| {
+            this.set_paused(paused).to_lua_err()
+        });
+
+        methods.add_method("set_volume", |_lua, this, volume| {
+            this.set_volume(volume).to_lua_err()
+        });
+        methods.add_method("get_volume", |_lua, this, ()| {
+            this.get_volume().to_lua_err()
+        });
+
+        methods.add_method("stop_all_events", |_lua, this, stop_mode: StopMode| {
+            this.stop_all_events(stop_mode).to_lua_err()
+        });
+    }
+}
+
+/// Keeps an FMOD bus paused for as long as a [pause channel](sludge::pause) is paused.
+/// By default, the master bus follows the `audio` channel:
+///
+/// ```ignore
+/// space.register(BusPauseSystem::default(), "BusPause", &[])?;
+/// space.register(BusPauseSystem::new("gameplay", "bus:/sfx"), "SfxPause", &[])?;
+/// ```
+///
+/// The bus is looked up every update, so it's fine to register the system before the
+/// bank containing the bus is loaded. Spaces sharing an `Fmod` resource shouldn't map
+/// different channels onto the same bus, or they'll fight over it.
+#[derive(Debug, Clone)]
+pub struct BusPauseSystem {
+    channel: String,
+    bus: String,
+}
+
+impl BusPauseSystem {
+    pub fn new(channel: impl Into<String>, bus: impl Into<String>) -> Self {
+        Self {
+            channel: channel.into(),
+            bus: bus.into(),
+        }
+    }
+}
+
+impl Default for BusPauseSystem {
+    fn default() -> Self {
+        Self::new(pause::AUDIO, "bus:/")
+    }
+}
+
+impl System for BusPauseSystem {
+    fn update(&self, _lua: LuaContext, resources: &UnifiedResources) -> Result<()> {
+        let paused = pause::is_paused(resources, &self.channel);
+        let bus = match resources.fetch_one::<Fmod>()?.borrow().get_bus(&self.bus) {
+            Ok(bus) => bus,
+            // Most likely the bank holding the bus isn't loaded yet.
+            Err(_) => return Ok(()),
+        };
+
+        if bus.is_paused()? != paused {
+            bus.set_paused(paused)?;
+        }
+
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{CheckError, Fmod};
+use crate::{CheckError, Fmod, Guid};
 use {
     enum_primitive_derive::*,
     libc::c_void,
@@ -9,7 +9,7 @@ use {
     std::{
         ffi::{CStr, CString},
         ptr, str,
-        sync::Arc,
+        sync::{Arc, Mutex},
     },
 };
 
@@ -77,7 +77,7 @@ pub struct TimelineBeatProperties {
     pub time_signature_denominator: i32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum EventCallbackInfo {
     Created,
     Destroyed,
@@ -111,6 +111,84 @@ union EventCallbackParameters {
 
 type BoxedEventCallback = Box<dyn Fn(EventInstance, EventCallbackInfo) -> Result<()>>;
 
+/// Identifies one of the callbacks added to an event instance, for removing it again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CallbackId(u64);
+
+impl<'lua> ToLua<'lua> for CallbackId {
+    fn to_lua(self, lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+        self.0.to_lua(lua)
+    }
+}
+
+impl<'lua> FromLua<'lua> for CallbackId {
+    fn from_lua(lua_value: LuaValue<'lua>, lua: LuaContext<'lua>) -> LuaResult<Self> {
+        Ok(Self(u64::from_lua(lua_value, lua)?))
+    }
+}
+
+struct Subscription {
+    id: CallbackId,
+    mask: EventCallbackMask,
+    callback: BoxedEventCallback,
+}
+
+/// Every callback on an event instance or description. FMOD only keeps a single
+/// callback for each, so this is what's kept in its user data, and the shim passes each
+/// callback on to whichever callbacks asked for its kind.
+///
+/// The lock is held while the callbacks run, so a callback must not add or remove
+/// callbacks on the instance it was called for.
+#[derive(Default)]
+struct EventCallbacks {
+    next_id: u64,
+    subscriptions: Vec<Subscription>,
+}
+
+impl EventCallbacks {
+    fn add(&mut self, callback: BoxedEventCallback, mask: EventCallbackMask) -> CallbackId {
+        let id = CallbackId(self.next_id);
+        self.next_id += 1;
+        self.subscriptions.push(Subscription { id, mask, callback });
+        id
+    }
+
+    fn remove(&mut self, id: CallbackId) -> bool {
+        let len = self.subscriptions.len();
+        self.subscriptions.retain(|sub| sub.id != id);
+        self.subscriptions.len() != len
+    }
+
+    /// Every kind of callback which at least one of the callbacks wants.
+    fn mask(&self) -> EventCallbackMask {
+        self.subscriptions
+            .iter()
+            .fold(EventCallbackMask::empty(), |mask, sub| mask | sub.mask)
+    }
+
+    fn dispatch(
+        &self,
+        ev: EventInstance,
+        kind: EventCallbackMask,
+        info: EventCallbackInfo,
+    ) -> Result<()> {
+        let mut result = Ok(());
+        for sub in self
+            .subscriptions
+            .iter()
+            .filter(|sub| sub.mask.intersects(kind))
+        {
+            // Keep going after an error, so one broken callback doesn't starve the rest.
+            if let Err(err) = (sub.callback)(ev, info.clone()) {
+                result = Err(err);
+            }
+        }
+        result
+    }
+}
+
+type SharedCallbacks = Mutex<EventCallbacks>;
+
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum StopMode {
     Immediate,
@@ -181,21 +259,31 @@ unsafe fn callback_shim(
     type_: FMOD_STUDIO_EVENT_CALLBACK_TYPE,
     parameters: *mut c_void,
     ev: EventInstance,
-    cb: &BoxedEventCallback,
+    callbacks: &SharedCallbacks,
 ) -> FMOD_RESULT {
     let parameters = parameters as *mut EventCallbackParameters;
+    let kind = EventCallbackMask::from_bits_truncate(type_);
+    // The lock has to be released before the destroyed callback lets go of the
+    // callbacks, so it's only held for each dispatch. A panicking callback can't have
+    // left the list of callbacks in a bad state, so poisoning is ignored.
+    let cb = |ev, info| {
+        callbacks
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .dispatch(ev, kind, info)
+    };
     let result = match type_ {
         FMOD_STUDIO_EVENT_CALLBACK_CREATED => {
             let fmod_result = cb(ev, EventCallbackInfo::Created);
             if let Ok(Some(ud)) = ev.get_userdata() {
-                Arc::incr_strong_count(ud);
+                Arc::increment_strong_count(ud);
             }
             fmod_result
         }
         FMOD_STUDIO_EVENT_CALLBACK_DESTROYED => {
             let fmod_result = cb(ev, EventCallbackInfo::Destroyed);
             if let Ok(Some(ud)) = ev.get_userdata() {
-                Arc::decr_strong_count(ud);
+                Arc::decrement_strong_count(ud);
             }
             fmod_result
         }
@@ -363,6 +451,20 @@ impl EventInstance {
         Ok(())
     }
 
+    /// Mark this instance for destruction. The instance is destroyed once it stops, so
+    /// releasing a playing instance lets it finish first. The handle should not be
+    /// used after this.
+    pub fn release(&self) -> Result<()> {
+        unsafe {
+            FMOD_Studio_EventInstance_Release(self.ptr).check_err()?;
+        }
+        Ok(())
+    }
+
+    pub fn is_valid(&self) -> bool {
+        unsafe { FMOD_Studio_EventInstance_IsValid(self.ptr) != 0 }
+    }
+
     pub fn set_pitch(&self, pitch_multiplier: f32) -> Result<()> {
         unsafe {
             FMOD_Studio_EventInstance_SetPitch(self.ptr, pitch_multiplier).check_err()?;
@@ -556,13 +658,13 @@ impl EventInstance {
         let this = EventInstance { ptr };
 
         if let Some(ud_ptr) = this.get_userdata()? {
-            Arc::incr_strong_count(ud_ptr);
+            Arc::increment_strong_count(ud_ptr);
         }
 
         Ok(this)
     }
 
-    unsafe fn get_userdata(&self) -> Result<Option<*const BoxedEventCallback>> {
+    unsafe fn get_userdata(&self) -> Result<Option<*const SharedCallbacks>> {
         let mut ud_ptr = ptr::null_mut();
         FMOD_Studio_EventInstance_GetUserData(self.ptr, &mut ud_ptr).check_err()?;
 
@@ -573,9 +675,9 @@ impl EventInstance {
         }
     }
 
-    unsafe fn set_userdata(&self, ud: Arc<BoxedEventCallback>) -> Result<()> {
+    unsafe fn set_userdata(&self, ud: Arc<SharedCallbacks>) -> Result<()> {
         if let Some(ud_ptr) = self.get_userdata()? {
-            Arc::decr_strong_count(ud_ptr);
+            Arc::decrement_strong_count(ud_ptr);
         }
 
         FMOD_Studio_EventInstance_SetUserData(self.ptr, Arc::into_raw(ud) as *mut _).check_err()?;
@@ -583,27 +685,82 @@ impl EventInstance {
         Ok(())
     }
 
+    /// Replace every callback on this instance with a single one.
     pub fn set_callback<F>(&self, callback: F, mask: EventCallbackMask) -> Result<()>
     where
         F: Fn(EventInstance, EventCallbackInfo) -> Result<()> + 'static + Send + Sync,
     {
-        let boxed = Box::new(callback) as BoxedEventCallback;
+        self.unset_callback()?;
+        self.add_callback(callback, mask)?;
+        Ok(())
+    }
+
+    /// Add a callback for the kinds of callback in `mask`, alongside any others this
+    /// instance already has. Returns an ID for removing it with
+    /// [`remove_callback`](EventInstance::remove_callback).
+    pub fn add_callback<F>(&self, callback: F, mask: EventCallbackMask) -> Result<CallbackId>
+    where
+        F: Fn(EventInstance, EventCallbackInfo) -> Result<()> + 'static + Send + Sync,
+    {
         unsafe {
-            self.set_userdata(Arc::new(boxed))?;
+            let callbacks = match self.get_userdata()? {
+                Some(ud_ptr) => &*ud_ptr,
+                None => {
+                    self.set_userdata(Arc::new(Mutex::new(EventCallbacks::default())))?;
+                    &*self.get_userdata()?.unwrap()
+                }
+            };
+
+            let (id, mask) = {
+                let mut callbacks = callbacks.lock().unwrap();
+                let id = callbacks.add(Box::new(callback), mask);
+                (id, callbacks.mask())
+            };
+
             FMOD_Studio_EventInstance_SetCallback(
                 self.ptr,
                 Some(event_instance_callback_shim),
                 mask.bits,
             )
             .check_err()?;
+
+            Ok(id)
+        }
+    }
+
+    /// Remove a callback added with [`add_callback`](EventInstance::add_callback),
+    /// returning whether it was still there.
+    pub fn remove_callback(&self, id: CallbackId) -> Result<bool> {
+        unsafe {
+            let callbacks = match self.get_userdata()? {
+                Some(ud_ptr) => &*ud_ptr,
+                None => return Ok(false),
+            };
+
+            let (removed, mask) = {
+                let mut callbacks = callbacks.lock().unwrap();
+                (callbacks.remove(id), callbacks.mask())
+            };
+
+            if mask.is_empty() {
+                self.unset_callback()?;
+            } else if removed {
+                FMOD_Studio_EventInstance_SetCallback(
+                    self.ptr,
+                    Some(event_instance_callback_shim),
+                    mask.bits,
+                )
+                .check_err()?;
+            }
+
+            Ok(removed)
         }
-        Ok(())
     }
 
     pub fn unset_callback(&self) -> Result<()> {
         unsafe {
             if let Some(ud_ptr) = self.get_userdata().unwrap() {
-                Arc::decr_strong_count(ud_ptr);
+                Arc::decrement_strong_count(ud_ptr);
             }
 
             FMOD_Studio_EventInstance_SetUserData(self.ptr, ptr::null_mut()).check_err()?;
@@ -627,6 +784,7 @@ impl LuaUserData for EventInstance {
         methods.add_method("trigger_cue", |_lua, this, ()| {
             this.trigger_cue().to_lua_err()
         });
+        methods.add_method("release", |_lua, this, ()| this.release().to_lua_err());
 
         methods.add_method("get_playback_state", |_lua, this, ()| {
             match this.get_playback_state().to_lua_err()? {
@@ -658,14 +816,9 @@ impl LuaUserData for EventInstance {
                 if let Some(cb) = maybe_cb {
                     let resources = lua.resources();
                     let fmod = resources.fetch_one::<Fmod>()?;
-                    let cq_send = fmod.borrow().cq_send.clone();
                     let key = Arc::new(lua.create_registry_value(cb)?);
                     this.set_callback(
-                        move |event_instance, event_info| {
-                            cq_send
-                                .send((key.clone(), event_instance, event_info))
-                                .map_err(|_| anyhow!("error while sending callback info"))
-                        },
+                        fmod.borrow().callback_forwarder(Some(key)),
                         mask.unwrap_or(EventCallbackMask::ALL),
                     )
                     .to_lua_err()?;
@@ -676,6 +829,53 @@ impl LuaUserData for EventInstance {
                 Ok(())
             },
         );
+
+        // Add a callback alongside any others, returning an ID for `remove_callback`.
+        methods.add_method(
+            "add_callback",
+            |lua, this, (cb, mask): (LuaFunction, Option<EventCallbackMask>)| {
+                let resources = lua.resources();
+                let fmod = resources.fetch_one::<Fmod>()?;
+                let key = Arc::new(lua.create_registry_value(cb)?);
+                this.add_callback(
+                    fmod.borrow().callback_forwarder(Some(key)),
+                    mask.unwrap_or(EventCallbackMask::ALL),
+                )
+                .to_lua_err()
+            },
+        );
+
+        methods.add_method("remove_callback", |_lua, this, id: CallbackId| {
+            this.remove_callback(id).to_lua_err()
+        });
+
+        // Call `cb` with the instance and the marker's properties whenever the timeline
+        // passes a marker with the given name. Returns an ID for `remove_callback`.
+        methods.add_method(
+            "on_marker",
+            |lua, this, (name, cb): (String, LuaFunction)| {
+                let resources = lua.resources();
+                let fmod = resources.fetch_one::<Fmod>()?;
+                let key = Arc::new(lua.create_registry_value(cb)?);
+                this.add_callback(
+                    fmod.borrow().marker_forwarder(name, key),
+                    EventCallbackMask::TIMELINE_MARKER,
+                )
+                .to_lua_err()
+            },
+        );
+
+        // Broadcast timeline beats and markers through the scheduler without a Lua
+        // callback, alongside any other callbacks. Returns an ID for `remove_callback`.
+        methods.add_method("broadcast_timeline", |lua, this, ()| {
+            let resources = lua.resources();
+            let fmod = resources.fetch_one::<Fmod>()?;
+            this.add_callback(
+                fmod.borrow().callback_forwarder(None),
+                EventCallbackMask::TIMELINE_MARKER | EventCallbackMask::TIMELINE_BEAT,
+            )
+            .to_lua_err()
+        });
     }
 }
 
@@ -693,6 +893,43 @@ impl EventDescription {
         unsafe { FMOD_Studio_EventDescription_IsValid(self.ptr) != 0 }
     }
 
+    pub fn get_id(&self) -> Result<Guid> {
+        let mut guid = Guid {
+            data1: 0,
+            data2: 0,
+            data3: 0,
+            data4: [0; 8],
+        };
+        unsafe {
+            FMOD_Studio_EventDescription_GetID(self.ptr, &mut guid as *mut Guid as *mut FMOD_GUID)
+                .check_err()?;
+        }
+        Ok(guid)
+    }
+
+    /// The event's path, like `event:/sfx/shot`. Requires the strings bank to be
+    /// loaded.
+    pub fn get_path(&self) -> Result<String> {
+        let mut len = 0;
+        unsafe {
+            FMOD_Studio_EventDescription_GetPath(self.ptr, ptr::null_mut(), 0, &mut len)
+                .check_err()?;
+        }
+
+        let mut buf = vec![0u8; len as usize];
+        unsafe {
+            FMOD_Studio_EventDescription_GetPath(
+                self.ptr,
+                buf.as_mut_ptr() as *mut _,
+                buf.len() as i32,
+                &mut len,
+            )
+            .check_err()?;
+        }
+
+        Ok(CStr::from_bytes_with_nul(&buf)?.to_str()?.to_owned())
+    }
+
     pub fn release_all_instances(&self) -> Result<()> {
         unsafe {
             FMOD_Studio_EventDescription_ReleaseAllInstances(self.ptr).check_err()?;
@@ -709,7 +946,7 @@ impl EventDescription {
 
         unsafe {
             if let Some(ud) = self.get_userdata()? {
-                Arc::incr_strong_count(ud);
+                Arc::increment_strong_count(ud);
             }
         }
 
@@ -720,13 +957,13 @@ impl EventDescription {
         let this = EventDescription { ptr };
 
         if let Some(ud_ptr) = this.get_userdata()? {
-            Arc::incr_strong_count(ud_ptr);
+            Arc::increment_strong_count(ud_ptr);
         }
 
         Ok(this)
     }
 
-    unsafe fn get_userdata(&self) -> Result<Option<*const BoxedEventCallback>> {
+    unsafe fn get_userdata(&self) -> Result<Option<*const SharedCallbacks>> {
         let mut ud_ptr = ptr::null_mut();
         FMOD_Studio_EventDescription_GetUserData(self.ptr, &mut ud_ptr).check_err()?;
 
@@ -737,9 +974,9 @@ impl EventDescription {
         }
     }
 
-    unsafe fn set_userdata(&self, ud: Arc<BoxedEventCallback>) -> Result<()> {
+    unsafe fn set_userdata(&self, ud: Arc<SharedCallbacks>) -> Result<()> {
         if let Some(ud_ptr) = self.get_userdata()? {
-            Arc::decr_strong_count(ud_ptr);
+            Arc::decrement_strong_count(ud_ptr);
         }
 
         FMOD_Studio_EventDescription_SetUserData(self.ptr, Arc::into_raw(ud) as *mut _)
@@ -752,9 +989,10 @@ impl EventDescription {
     where
         F: Fn(EventInstance, EventCallbackInfo) -> Result<()> + 'static + Send + Sync,
     {
-        let boxed = Box::new(callback) as BoxedEventCallback;
+        let mut callbacks = EventCallbacks::default();
+        callbacks.add(Box::new(callback), mask);
         unsafe {
-            self.set_userdata(Arc::new(boxed))?;
+            self.set_userdata(Arc::new(Mutex::new(callbacks)))?;
             FMOD_Studio_EventDescription_SetCallback(
                 self.ptr,
                 Some(event_description_callback_shim),
@@ -768,7 +1006,7 @@ impl EventDescription {
     pub fn unset_callback(&self) -> Result<()> {
         unsafe {
             if let Some(ud_ptr) = self.get_userdata().unwrap() {
-                Arc::decr_strong_count(ud_ptr);
+                Arc::decrement_strong_count(ud_ptr);
             }
 
             FMOD_Studio_EventDescription_SetUserData(self.ptr, ptr::null_mut()).check_err()?;
@@ -795,14 +1033,9 @@ impl LuaUserData for EventDescription {
                 if let Some(cb) = maybe_cb {
                     let resources = lua.resources();
                     let fmod = resources.fetch_one::<Fmod>()?;
-                    let cq_send = fmod.borrow().cq_send.clone();
                     let key = Arc::new(lua.create_registry_value(cb)?);
                     this.set_callback(
-                        move |event_instance, event_info| {
-                            cq_send
-                                .send((key.clone(), event_instance, event_info))
-                                .map_err(|_| anyhow!("error while sending callback info"))
-                        },
+                        fmod.borrow().callback_forwarder(Some(key)),
                         mask.unwrap_or(EventCallbackMask::ALL),
                     )
                     .to_lua_err()?;
@@ -813,6 +1046,18 @@ impl LuaUserData for EventDescription {
                 Ok(())
             },
         );
+
+        // Broadcast timeline beats and markers through the scheduler without a Lua
+        // callback. This replaces any callback previously set.
+        methods.add_method("broadcast_timeline", |lua, this, ()| {
+            let resources = lua.resources();
+            let fmod = resources.fetch_one::<Fmod>()?;
+            this.set_callback(
+                fmod.borrow().callback_forwarder(None),
+                EventCallbackMask::TIMELINE_MARKER | EventCallbackMask::TIMELINE_BEAT,
+            )
+            .to_lua_err()
+        });
     }
 }
 
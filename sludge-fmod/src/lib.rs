@@ -3,22 +3,35 @@
 //! to those Rust interfaces through Lua by way of Sludge's module registration
 //! API.
 
-#![feature(arc_mutate_strong_count)]
-
 use ::{
     crossbeam_channel::{Receiver, Sender},
     lazy_static::lazy_static,
     regex::Regex,
-    sludge::{api::Module, prelude::*},
+    sludge::{
+        api::Module,
+        prelude::*,
+        shutdown::{Finalizer, ShutdownStage},
+        SchedulerQueue,
+    },
     sludge_fmod_sys::*,
-    std::{ffi::CString, ptr, str, sync::Arc},
+    std::{
+        ffi::CString,
+        ptr, str,
+        sync::{Arc, Mutex},
+    },
 };
 
 pub mod bank;
+pub mod bus;
 pub mod event;
+pub mod music;
+pub mod pool;
 
 pub use bank::*;
+pub use bus::*;
 pub use event::*;
+pub use music::*;
+pub use pool::*;
 
 trait CheckError {
     fn check_err(self) -> Result<()>;
@@ -257,6 +270,38 @@ bitflags::bitflags! {
     }
 }
 
+/// The scheduler event broadcast for every timeline beat of an event instance which
+/// has its timeline callbacks forwarded. Its arguments are the event instance and a
+/// table of the beat's properties.
+pub const BEAT_EVENT: &'static str = "fmod.beat";
+
+/// The scheduler event broadcast for every timeline marker of an event instance which
+/// has its timeline callbacks forwarded. Its arguments are the event instance and a
+/// table of the marker's properties. The same arguments are also broadcast on an event
+/// specific to the marker, named `"fmod.marker:<marker name>"`.
+pub const MARKER_EVENT: &'static str = "fmod.marker";
+
+/// A timeline callback from an event instance, for Rust code which wants to sync to
+/// music without going through Lua.
+#[derive(Debug, Clone)]
+pub enum TimelineEvent {
+    Beat(EventInstance, TimelineBeatProperties),
+    Marker(EventInstance, TimelineMarkerProperties),
+}
+
+/// Where a callback forwarded to `flush_callbacks` goes.
+#[derive(Debug, Clone)]
+pub(crate) enum Forward {
+    /// Broadcast timeline beats and markers through the scheduler.
+    Timeline,
+    /// Call a Lua function with the callback's kind and properties.
+    Callback(Arc<LuaRegistryKey>),
+    /// Call a Lua function with the properties of a timeline marker.
+    Marker(Arc<LuaRegistryKey>),
+}
+
+type CallbackMessage = (Forward, EventInstance, EventCallbackInfo);
+
 /// A builder struct for initializing the FMOD Studio System. At current we don't
 /// really have any options to set here in between `create` and `initialize` but
 /// they'll be implemented eventually.
@@ -307,6 +352,8 @@ impl FmodSystemBuilder {
             ptr: self.system,
             cq_recv,
             cq_send,
+            timeline_subscribers: Mutex::new(Vec::new()),
+            pool: Mutex::new(InstancePool::new()),
         };
 
         Ok(fmod)
@@ -319,8 +366,10 @@ impl FmodSystemBuilder {
 #[derive(Debug)]
 pub struct Fmod {
     pub(crate) ptr: *mut FMOD_STUDIO_SYSTEM,
-    pub(crate) cq_recv: Receiver<(Arc<LuaRegistryKey>, EventInstance, EventCallbackInfo)>,
-    pub(crate) cq_send: Sender<(Arc<LuaRegistryKey>, EventInstance, EventCallbackInfo)>,
+    pub(crate) cq_recv: Receiver<CallbackMessage>,
+    pub(crate) cq_send: Sender<CallbackMessage>,
+    timeline_subscribers: Mutex<Vec<Sender<TimelineEvent>>>,
+    pool: Mutex<InstancePool>,
 }
 
 // FMOD Studio API is thread safe by default, and we panic if we see something which
@@ -344,6 +393,104 @@ impl Fmod {
         unsafe {
             FMOD_Studio_System_Update(self.ptr).check_err()?;
         }
+        self.pool.lock().unwrap().prune()?;
+        Ok(())
+    }
+
+    /// Create a callback for an event instance or description which defers to
+    /// `flush_callbacks`. If `key` is `Some`, it should be the registry key of a Lua
+    /// function to call with the callback info. Either way, timeline beats and markers
+    /// are broadcast as well; see [`flush_callbacks`](Fmod::flush_callbacks).
+    ///
+    /// ```ignore
+    /// // Sync Lua threads to the music, with no Lua callback.
+    /// instance.add_callback(
+    ///     fmod.callback_forwarder(None),
+    ///     EventCallbackMask::TIMELINE_MARKER | EventCallbackMask::TIMELINE_BEAT,
+    /// )?;
+    /// ```
+    pub fn callback_forwarder(
+        &self,
+        key: Option<Arc<LuaRegistryKey>>,
+    ) -> impl Fn(EventInstance, EventCallbackInfo) -> Result<()> + Send + Sync + 'static {
+        let forward = match key {
+            Some(key) => Forward::Callback(key),
+            None => Forward::Timeline,
+        };
+        let cq_send = self.cq_send.clone();
+        move |event_instance, event_info| {
+            cq_send
+                .send((forward.clone(), event_instance, event_info))
+                .map_err(|_| anyhow!("error while sending callback info"))
+        }
+    }
+
+    /// Create a callback which defers to `flush_callbacks` for timeline markers named
+    /// `name`, calling the Lua function under `key` with the event instance and the
+    /// marker's properties. Other markers are filtered out before they reach the queue,
+    /// so scripts can have a handler per marker instead of checking names themselves.
+    pub fn marker_forwarder(
+        &self,
+        name: String,
+        key: Arc<LuaRegistryKey>,
+    ) -> impl Fn(EventInstance, EventCallbackInfo) -> Result<()> + Send + Sync + 'static {
+        let cq_send = self.cq_send.clone();
+        move |event_instance, event_info| match &event_info {
+            EventCallbackInfo::TimelineMarker(marker) if marker.name == name => cq_send
+                .send((Forward::Marker(key.clone()), event_instance, event_info))
+                .map_err(|_| anyhow!("error while sending callback info")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Subscribe to all timeline beats and markers delivered through
+    /// `flush_callbacks`. Dropping the receiver unsubscribes.
+    pub fn subscribe_timeline(&self) -> Receiver<TimelineEvent> {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        self.timeline_subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Broadcast a timeline beat or marker to the scheduler of the space owning `lua`
+    /// (if it has one) and to any Rust subscribers.
+    fn broadcast_timeline<'lua>(
+        &self,
+        lua: LuaContext<'lua>,
+        event_instance: EventInstance,
+        event_info: &EventCallbackInfo,
+    ) -> Result<()> {
+        let timeline_event = match event_info {
+            EventCallbackInfo::TimelineBeat(beat) => TimelineEvent::Beat(event_instance, *beat),
+            EventCallbackInfo::TimelineMarker(marker) => {
+                TimelineEvent::Marker(event_instance, marker.clone())
+            }
+            _ => return Ok(()),
+        };
+
+        self.timeline_subscribers
+            .lock()
+            .unwrap()
+            .retain(|sender| sender.send(timeline_event.clone()).is_ok());
+
+        if let Ok(queue) = lua.fetch_one::<SchedulerQueue>() {
+            let queue = queue.borrow();
+            match timeline_event {
+                TimelineEvent::Beat(_, beat) => {
+                    let props = rlua_serde::to_value(lua, &beat)?;
+                    queue.broadcast(lua, BEAT_EVENT, (event_instance, props))?;
+                }
+                TimelineEvent::Marker(_, marker) => {
+                    let props = rlua_serde::to_value(lua, &marker)?;
+                    queue.broadcast(lua, MARKER_EVENT, (event_instance, props.clone()))?;
+                    queue.broadcast(
+                        lua,
+                        format!("{}:{}", MARKER_EVENT, marker.name),
+                        (event_instance, props),
+                    )?;
+                }
+            }
+        }
+
         Ok(())
     }
 
@@ -351,8 +498,32 @@ impl Fmod {
     /// is deferred by sending their parameters into a queue in the `Fmod` object
     /// and then flushing the queue with this method and calling all the relevant
     /// Lua closures.
+    ///
+    /// Timeline beats and markers from callbacks made with
+    /// [`callback_forwarder`](Fmod::callback_forwarder), with or without a Lua function,
+    /// are additionally broadcast through the space's `SchedulerQueue` as
+    /// [`BEAT_EVENT`] and [`MARKER_EVENT`], and sent to any subscribers from
+    /// [`subscribe_timeline`](Fmod::subscribe_timeline). Per-marker handlers from
+    /// [`marker_forwarder`](Fmod::marker_forwarder) only call their function.
     pub fn flush_callbacks<'lua>(&self, lua: LuaContext<'lua>) -> Result<()> {
-        for (key, event_instance, event_info) in self.cq_recv.try_iter() {
+        for (forward, event_instance, event_info) in self.cq_recv.try_iter() {
+            let key = match forward {
+                Forward::Timeline => {
+                    self.broadcast_timeline(lua, event_instance, &event_info)?;
+                    continue;
+                }
+                Forward::Callback(key) => {
+                    self.broadcast_timeline(lua, event_instance, &event_info)?;
+                    key
+                }
+                Forward::Marker(key) => {
+                    if let EventCallbackInfo::TimelineMarker(marker) = &event_info {
+                        let cb = lua.registry_value::<LuaFunction>(&key)?;
+                        cb.call((event_instance, rlua_serde::to_value(lua, marker)?))?;
+                    }
+                    continue;
+                }
+            };
             let cb = lua.registry_value::<LuaFunction>(&key)?;
 
             use EventCallbackInfo::*;
@@ -391,6 +562,20 @@ impl Fmod {
         Ok(())
     }
 
+    /// Stop every instance played through the [`InstancePool`], drop any callbacks which
+    /// haven't been flushed along with the Lua functions they'd call, and update the
+    /// system so that FMOD releases the stopped instances. This is run as a
+    /// [finalizer](sludge::shutdown) when a space which owns the `Fmod` is shut down.
+    pub fn release_instances(&self) -> Result<()> {
+        self.pool.lock().unwrap().stop_all(StopMode::Immediate)?;
+        self.cq_recv.try_iter().for_each(drop);
+        self.timeline_subscribers.lock().unwrap().clear();
+        unsafe {
+            FMOD_Studio_System_Update(self.ptr).check_err()?;
+        }
+        Ok(())
+    }
+
     /// Load a bank file from a path, relative to your current directory. Banks will not be
     /// unloaded by dropping the `Bank` object, and must be manually released if desired either
     /// through `Bank::unload` or `Fmod::unloadAll`.
@@ -472,6 +657,17 @@ impl Fmod {
         }
     }
 
+    /// Get a bus by its path (for example `bus:/` for the master bus) or ID string. The
+    /// bank containing the bus must be loaded.
+    pub fn get_bus<T: AsRef<[u8]> + ?Sized>(&self, path: &T) -> Result<Bus> {
+        let c_string = CString::new(path.as_ref())?;
+        let mut ptr = ptr::null_mut();
+        unsafe {
+            FMOD_Studio_System_GetBus(self.ptr, c_string.as_ptr(), &mut ptr).check_err()?;
+            Ok(Bus::from_ptr(ptr))
+        }
+    }
+
     /// Get a loaded event by its path or ID string (GUID in its string format; see [`Guid`][Guid]).
     pub fn get_event<T: AsRef<[u8]> + ?Sized>(&self, path: &T) -> Result<EventDescription> {
         let c_string = CString::new(path.as_ref())?;
@@ -495,10 +691,11 @@ impl Fmod {
 
 impl Drop for Fmod {
     fn drop(&mut self) {
-        unsafe {
-            FMOD_Studio_System_Release(self.ptr)
-                .check_err()
-                .expect("error dropping FMOD system");
+        // There's nothing to be done about a failed release, and panicking here would
+        // abort the process if we're dropped while unwinding.
+        let result = unsafe { FMOD_Studio_System_Release(self.ptr).check_err() };
+        if let Err(err) = result {
+            log::error!("error releasing FMOD system: {:?}", err);
         }
     }
 }
@@ -529,7 +726,20 @@ fn load<'lua>(lua: LuaContext<'lua>) -> Result<LuaValue<'lua>> {
                 Ok(event)
             })?,
         ),
+        ("play_one_shot", lua.create_function(pool::play_one_shot)?),
+        ("set_polyphony", lua.create_function(pool::set_polyphony)?),
+        ("pool_stats", lua.create_function(pool::pool_stats)?),
+        (
+            "get_bus",
+            lua.create_function(|lua, path: LuaString| {
+                let resources = lua.resources();
+                let fmod = resources.fetch_one::<Fmod>()?;
+                let bus = fmod.borrow().get_bus(path.as_bytes()).to_lua_err()?;
+                Ok(bus)
+            })?,
+        ),
     ])?;
+    table.set("music", music::load(lua)?)?;
 
     Ok(LuaValue::Table(table))
 }
@@ -538,6 +748,17 @@ inventory::submit! {
     Module::parse("fmod", load)
 }
 
+inventory::submit! {
+    Finalizer::new("fmod", ShutdownStage::ReleaseInstances, |_lua, resources| {
+        // A global `Fmod` is shared with other spaces, and is released by
+        // `shutdown::finalize_global` once they're all gone.
+        if let Ok(fmod) = resources.local.fetch_one::<Fmod>() {
+            fmod.borrow().release_instances()?;
+        }
+        Ok(())
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,396 @@
+//! Looping music with crossfades, intensity layers and beat-quantized stingers.
+//!
+//! A [`MusicManager`] plays one named track at a time. Tracks are FMOD events, defined
+//! once with [`MusicManager::define`]; switching tracks fades the old one out while the
+//! new one fades in, and the old instance is stopped and released once it's silent.
+//! Layers within a track are left to FMOD Studio: if a track has an intensity parameter,
+//! [`MusicManager::set_intensity`] sets it on whichever track is playing, and carries
+//! over to tracks started afterwards. Stingers are one-shot events which are held back
+//! until the playing track's next timeline beat, so they land on the music.
+//!
+//! The manager is driven by the [`MusicSystem`], which measures fades in real time, so
+//! the music keeps going while the game is paused. Beats are counted from FMOD's own
+//! callbacks, and stingers go out on the first update after a beat. From Lua:
+//!
+//! ```lua
+//! fmod.music.define("boss", { event = "event:/music/boss", intensity = "intensity" })
+//! fmod.music.play("boss", { fade = 2.0 })
+//! fmod.music.set_intensity(0.8)
+//! fmod.music.stinger("event:/music/stinger_phase")
+//! fmod.music.stop({ fade = 4.0 })
+//! ```
+
+use crate::{EventCallbackInfo, EventCallbackMask, EventInstance, Fmod, StopMode};
+use {
+    sludge::prelude::*,
+    std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::Instant,
+    },
+};
+
+/// A piece of music the [`MusicManager`] can play by name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MusicTrack {
+    /// The path of the track's event.
+    pub event: String,
+    /// The parameter driving the track's intensity layers, if it has any.
+    pub intensity_parameter: Option<String>,
+    /// How long the track fades in and out over when no fade is given, in seconds.
+    pub fade: f32,
+}
+
+impl MusicTrack {
+    pub fn new(event: impl Into<String>) -> Self {
+        Self {
+            event: event.into(),
+            intensity_parameter: None,
+            fade: 1.,
+        }
+    }
+}
+
+/// An instance of a track, fading towards a target volume.
+#[derive(Debug)]
+struct Playing {
+    name: String,
+    instance: EventInstance,
+    beats: Arc<AtomicU64>,
+    seen_beats: u64,
+    volume: f32,
+    target: f32,
+    /// How fast the volume moves towards its target, in volume per second.
+    rate: f32,
+}
+
+impl Playing {
+    fn fade_to(&mut self, target: f32, fade: f32) {
+        self.target = target;
+        self.rate = if fade > 0. { 1. / fade } else { f32::INFINITY };
+    }
+
+    /// Move the volume towards its target, returning whether it's reached it.
+    fn step(&mut self, dt: f32) -> Result<bool> {
+        let delta = self.rate * dt;
+        self.volume = if self.volume < self.target {
+            (self.volume + delta).min(self.target)
+        } else {
+            (self.volume - delta).max(self.target)
+        };
+        self.instance.set_volume(self.volume)?;
+        Ok(self.volume == self.target)
+    }
+
+    fn beat(&mut self) -> bool {
+        let beats = self.beats.load(Ordering::Relaxed);
+        let beat = beats != self.seen_beats;
+        self.seen_beats = beats;
+        beat
+    }
+
+    fn finish(self) -> Result<()> {
+        self.instance.stop(StopMode::Immediate)?;
+        self.instance.unset_callback()?;
+        self.instance.release()
+    }
+}
+
+/// Plays named [`MusicTrack`]s. See the [module documentation](self) for details.
+#[derive(Debug, Default)]
+pub struct MusicManager {
+    tracks: HashMap<String, MusicTrack>,
+    current: Option<Playing>,
+    fading: Vec<Playing>,
+    intensity: f32,
+    stingers: Vec<String>,
+    last_update: Option<Instant>,
+}
+
+impl MusicManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Define a track, replacing any other track with the same name. Changing a track
+    /// which is already playing doesn't affect it until it's played again.
+    pub fn define(&mut self, name: impl Into<String>, track: MusicTrack) {
+        self.tracks.insert(name.into(), track);
+    }
+
+    pub fn track(&self, name: &str) -> Option<&MusicTrack> {
+        self.tracks.get(name)
+    }
+
+    /// The name of the track which is playing or fading in, if any.
+    pub fn current(&self) -> Option<&str> {
+        self.current.as_ref().map(|playing| playing.name.as_str())
+    }
+
+    pub fn intensity(&self) -> f32 {
+        self.intensity
+    }
+
+    /// Crossfade to the named track over `fade` seconds, or the track's own fade if
+    /// `None`. Does nothing if the track is already playing.
+    pub fn play(&mut self, fmod: &Fmod, name: &str, fade: Option<f32>) -> Result<()> {
+        if self.current() == Some(name) {
+            return Ok(());
+        }
+
+        let track = self
+            .tracks
+            .get(name)
+            .ok_or_else(|| anyhow!("no music track named `{}`", name))?;
+        let fade = fade.unwrap_or(track.fade);
+
+        let instance = fmod.get_event(&track.event)?.create_instance()?;
+        let beats = Arc::new(AtomicU64::new(0));
+        let counter = beats.clone();
+        instance.add_callback(
+            move |_, info| {
+                if let EventCallbackInfo::TimelineBeat(_) = info {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(())
+            },
+            EventCallbackMask::TIMELINE_BEAT,
+        )?;
+        if let Some(parameter) = &track.intensity_parameter {
+            instance.set_parameter_by_name(parameter, self.intensity, false)?;
+        }
+        instance.set_volume(0.)?;
+        instance.start()?;
+
+        let mut playing = Playing {
+            name: name.to_owned(),
+            instance,
+            beats,
+            seen_beats: 0,
+            volume: 0.,
+            target: 1.,
+            rate: 0.,
+        };
+        playing.fade_to(1., fade);
+
+        self.fade_out_current(fade);
+        self.current = Some(playing);
+
+        Ok(())
+    }
+
+    /// Fade out whatever's playing over `fade` seconds, or its track's own fade if
+    /// `None`. Stingers which haven't gone out yet are dropped.
+    pub fn stop(&mut self, fade: Option<f32>) {
+        let fade = fade.unwrap_or_else(|| {
+            self.current()
+                .and_then(|name| self.tracks.get(name))
+                .map_or(0., |track| track.fade)
+        });
+        self.fade_out_current(fade);
+        self.stingers.clear();
+    }
+
+    fn fade_out_current(&mut self, fade: f32) {
+        if let Some(mut playing) = self.current.take() {
+            playing.fade_to(0., fade);
+            self.fading.push(playing);
+        }
+    }
+
+    /// Set the intensity of the playing track, and of any tracks played after it.
+    pub fn set_intensity(&mut self, intensity: f32) -> Result<()> {
+        self.intensity = intensity;
+
+        if let Some(playing) = &self.current {
+            let parameter = self
+                .tracks
+                .get(&playing.name)
+                .and_then(|track| track.intensity_parameter.as_ref());
+            if let Some(parameter) = parameter {
+                playing
+                    .instance
+                    .set_parameter_by_name(parameter, intensity, false)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Play a one-shot event on the playing track's next beat, or right away if nothing
+    /// is playing.
+    pub fn stinger(&mut self, fmod: &Fmod, event: impl Into<String>) -> Result<()> {
+        let event = event.into();
+        match &self.current {
+            Some(_) => self.stingers.push(event),
+            None => {
+                fmod.play_one_shot(&event, &[])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Advance fades by the real time since the last update, and send out any stingers
+    /// if there's been a beat.
+    pub fn update(&mut self, fmod: &Fmod) -> Result<()> {
+        let now = Instant::now();
+        let dt = self
+            .last_update
+            .map_or(0., |last| now.duration_since(last).as_secs_f32());
+        self.last_update = Some(now);
+        self.step(fmod, dt)
+    }
+
+    /// Advance fades by `dt` seconds, and send out any stingers if there's been a beat.
+    pub fn step(&mut self, fmod: &Fmod, dt: f32) -> Result<()> {
+        let mut finished = Vec::new();
+        for (i, playing) in self.fading.iter_mut().enumerate() {
+            if playing.step(dt)? {
+                finished.push(i);
+            }
+        }
+        for i in finished.into_iter().rev() {
+            self.fading.swap_remove(i).finish()?;
+        }
+
+        let beat = match &mut self.current {
+            Some(playing) => {
+                playing.step(dt)?;
+                playing.beat()
+            }
+            None => false,
+        };
+
+        if beat {
+            for stinger in self.stingers.drain(..) {
+                fmod.play_one_shot(&stinger, &[])?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Drives the [`MusicManager`], inserting one into the space's local resources if it
+/// can't find one. It's best registered in the frame stage:
+///
+/// ```ignore
+/// space.register_in(MusicSystem, "Music", &[], Stage::Frame)?;
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MusicSystem;
+
+impl System for MusicSystem {
+    fn init(
+        &self,
+        _lua: LuaContext,
+        local: &mut OwnedResources,
+        global: Option<&SharedResources>,
+    ) -> Result<()> {
+        let in_global = global.map_or(false, |global| global.borrow().has_value::<MusicManager>());
+        if !local.has_value::<MusicManager>() && !in_global {
+            local.insert(MusicManager::new());
+        }
+
+        Ok(())
+    }
+
+    fn update(&self, _lua: LuaContext, resources: &UnifiedResources) -> Result<()> {
+        let (fmod, music) = resources.fetch::<(Fmod, MusicManager)>()?;
+        let result = music.borrow_mut().update(&fmod.borrow());
+        result
+    }
+}
+
+fn fade_option(options: Option<LuaTable>) -> LuaResult<Option<f32>> {
+    match options {
+        Some(options) => options.get("fade"),
+        None => Ok(None),
+    }
+}
+
+pub(crate) fn load<'lua>(lua: LuaContext<'lua>) -> LuaResult<LuaTable<'lua>> {
+    let table = lua.create_table()?;
+
+    table.set(
+        "define",
+        lua.create_function(|lua, (name, options): (String, LuaTable)| {
+            let mut track = MusicTrack::new(options.get::<_, String>("event")?);
+            track.intensity_parameter = options.get("intensity")?;
+            if let Some(fade) = options.get("fade")? {
+                track.fade = fade;
+            }
+            lua.fetch_one::<MusicManager>()?
+                .borrow_mut()
+                .define(name, track);
+            Ok(())
+        })?,
+    )?;
+
+    table.set(
+        "play",
+        lua.create_function(|lua, (name, options): (String, Option<LuaTable>)| {
+            let fade = fade_option(options)?;
+            let (fmod, music) = lua.fetch::<(Fmod, MusicManager)>()?;
+            let result = music.borrow_mut().play(&fmod.borrow(), &name, fade);
+            result.to_lua_err()
+        })?,
+    )?;
+
+    table.set(
+        "stop",
+        lua.create_function(|lua, options: Option<LuaTable>| {
+            let fade = fade_option(options)?;
+            lua.fetch_one::<MusicManager>()?.borrow_mut().stop(fade);
+            Ok(())
+        })?,
+    )?;
+
+    table.set(
+        "set_intensity",
+        lua.create_function(|lua, intensity: f32| {
+            lua.fetch_one::<MusicManager>()?
+                .borrow_mut()
+                .set_intensity(intensity)
+                .to_lua_err()
+        })?,
+    )?;
+
+    table.set(
+        "stinger",
+        lua.create_function(|lua, event: String| {
+            let (fmod, music) = lua.fetch::<(Fmod, MusicManager)>()?;
+            let result = music.borrow_mut().stinger(&fmod.borrow(), event);
+            result.to_lua_err()
+        })?,
+    )?;
+
+    table.set(
+        "current",
+        lua.create_function(|lua, ()| {
+            let music = lua.fetch_one::<MusicManager>()?;
+            let current = music.borrow().current().map(str::to_owned);
+            Ok(current)
+        })?,
+    )?;
+
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defined_tracks() {
+        let mut music = MusicManager::new();
+        music.define("boss", MusicTrack::new("event:/music/boss"));
+        assert_eq!(music.track("boss").unwrap().fade, 1.);
+        assert!(music.track("title").is_none());
+        assert_eq!(music.current(), None);
+    }
+}
@@ -0,0 +1,449 @@
+//! Pooling of fire-and-forget event instances, with a cap on how many instances of
+//! each event can play at once.
+//!
+//! Sound effects which fire constantly (gunshots, bullet impacts, footsteps) would
+//! otherwise create and release a fresh instance every time, and can pile up well past
+//! the point where more of them can be heard. [`Fmod::play_one_shot`] goes through an
+//! [`InstancePool`] instead, which tracks the instances it started per event and, once
+//! an event hits its [`Polyphony::max_instances`], either steals an existing instance
+//! or refuses to start a new one.
+//!
+//! ```lua
+//! fmod.set_polyphony("event:/sfx/shot", 4, "oldest")
+//! fmod.play_one_shot("event:/sfx/shot", { pitch_variation = 0.3 })
+//! ```
+//!
+//! The pool counts how many instances of each event are playing, stolen and rejected;
+//! see [`PoolStats`]. [`Fmod::total_pool_stats`] adds them up across events, and
+//! formats as a single row for sludge-2d's profiler overlay:
+//!
+//! ```ignore
+//! let audio = format!("one-shots: {}", fmod.total_pool_stats());
+//! profile_overlay.draw(&gfx_stats, &scheduler.stats(), None, &[audio], ui);
+//! ```
+
+use crate::{EventInstance, Fmod, Guid, PlaybackState, StopMode};
+use {
+    sludge::prelude::*,
+    std::{
+        collections::{HashMap, VecDeque},
+        fmt,
+    },
+};
+
+/// What to do when an event is already playing as many instances as it's allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StealPolicy {
+    /// Stop the instance which was started first.
+    Oldest,
+    /// Stop the instance with the lowest final volume.
+    Quietest,
+    /// Don't start the new instance.
+    None,
+}
+
+impl<'lua> FromLua<'lua> for StealPolicy {
+    fn from_lua(lua_value: LuaValue<'lua>, lua: LuaContext<'lua>) -> LuaResult<Self> {
+        let lua_str = <LuaString>::from_lua(lua_value, lua).to_lua_err()?;
+        match lua_str.to_str()? {
+            "oldest" => Ok(StealPolicy::Oldest),
+            "quietest" => Ok(StealPolicy::Quietest),
+            "none" => Ok(StealPolicy::None),
+            s => Err(anyhow!(
+                "bad StealPolicy {} \
+                (expected \"oldest\", \"quietest\" or \"none\")",
+                s
+            ))
+            .to_lua_err(),
+        }
+    }
+}
+
+/// The polyphony limit of an event played through an [`InstancePool`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Polyphony {
+    pub max_instances: usize,
+    pub steal: StealPolicy,
+}
+
+impl Default for Polyphony {
+    fn default() -> Self {
+        Self {
+            max_instances: 8,
+            steal: StealPolicy::Oldest,
+        }
+    }
+}
+
+/// Instance counts for a single event in an [`InstancePool`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// How many instances are currently playing.
+    pub active: usize,
+    /// The most instances which have been playing at once.
+    pub peak: usize,
+    /// How many instances have been started in total.
+    pub started: u64,
+    /// How many playing instances were stopped to make room for new ones.
+    pub stolen: u64,
+    /// How many instances weren't started because the event was at its limit.
+    pub rejected: u64,
+}
+
+impl PoolStats {
+    /// Add up the counts of several events. The peak is the sum of each event's peak,
+    /// and so is the most instances which could have been playing at once.
+    pub fn total(stats: impl IntoIterator<Item = PoolStats>) -> Self {
+        stats
+            .into_iter()
+            .fold(Self::default(), |total, stats| Self {
+                active: total.active + stats.active,
+                peak: total.peak + stats.peak,
+                started: total.started + stats.started,
+                stolen: total.stolen + stats.stolen,
+                rejected: total.rejected + stats.rejected,
+            })
+    }
+}
+
+impl fmt::Display for PoolStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} playing (peak {}), {} started, {} stolen, {} rejected",
+            self.active, self.peak, self.started, self.stolen, self.rejected
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct PooledEvent {
+    polyphony: Option<Polyphony>,
+    playing: VecDeque<EventInstance>,
+    stats: PoolStats,
+}
+
+/// Tracks the one-shot instances of every event played through it. See the
+/// [module documentation](self) for details.
+#[derive(Debug, Default)]
+pub struct InstancePool {
+    /// The limit for events which haven't been given one of their own.
+    pub default_polyphony: Polyphony,
+    events: HashMap<Guid, PooledEvent>,
+}
+
+impl InstancePool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the polyphony limit of a single event.
+    pub fn set_polyphony(&mut self, guid: Guid, polyphony: Polyphony) {
+        self.events.entry(guid).or_default().polyphony = Some(polyphony);
+    }
+
+    pub fn polyphony(&self, guid: &Guid) -> Polyphony {
+        self.events
+            .get(guid)
+            .and_then(|event| event.polyphony)
+            .unwrap_or(self.default_polyphony)
+    }
+
+    /// Instance counts of a single event, if it's ever been played through the pool.
+    pub fn stats(&self, guid: &Guid) -> Option<PoolStats> {
+        self.events.get(guid).map(|event| event.stats)
+    }
+
+    /// Instance counts of every event which has been played through the pool.
+    pub fn all_stats(&self) -> impl Iterator<Item = (Guid, PoolStats)> + '_ {
+        self.events.iter().map(|(guid, event)| (*guid, event.stats))
+    }
+
+    /// Instance counts of every event in the pool, added up.
+    pub fn total_stats(&self) -> PoolStats {
+        PoolStats::total(self.events.values().map(|event| event.stats))
+    }
+
+    /// Forget about instances which have finished playing. Instances are released as
+    /// soon as they're started, so FMOD destroys them on its own once they stop.
+    pub fn prune(&mut self) -> Result<()> {
+        for event in self.events.values_mut() {
+            let mut result = Ok(());
+            event.playing.retain(|instance| {
+                if !instance.is_valid() {
+                    return false;
+                }
+
+                match instance.get_playback_state() {
+                    Ok(state) => state != PlaybackState::Stopped,
+                    Err(err) => {
+                        result = Err(err);
+                        false
+                    }
+                }
+            });
+            result?;
+            event.stats.active = event.playing.len();
+        }
+
+        Ok(())
+    }
+
+    /// Stop every instance which was started through the pool and forget about them.
+    pub fn stop_all(&mut self, stop_mode: StopMode) -> Result<()> {
+        for event in self.events.values_mut() {
+            for instance in event.playing.drain(..) {
+                if instance.is_valid() {
+                    instance.stop(stop_mode)?;
+                }
+            }
+            event.stats.active = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Make room for another instance of an event, returning whether there is room.
+    fn reserve(&mut self, guid: Guid) -> Result<bool> {
+        let polyphony = self.polyphony(&guid);
+        let event = self.events.entry(guid).or_default();
+
+        if event.playing.len() < polyphony.max_instances {
+            return Ok(true);
+        }
+
+        let victim = match polyphony.steal {
+            StealPolicy::None => None,
+            StealPolicy::Oldest => Some(0),
+            StealPolicy::Quietest => {
+                let mut quietest = None;
+                for (i, instance) in event.playing.iter().enumerate() {
+                    let volume = instance.get_volume()?.final_value;
+                    if quietest.map_or(true, |(_, v)| volume < v) {
+                        quietest = Some((i, volume));
+                    }
+                }
+                quietest.map(|(i, _)| i)
+            }
+        };
+
+        match victim.and_then(|i| event.playing.remove(i)) {
+            Some(instance) => {
+                instance.stop(StopMode::Immediate)?;
+                event.stats.stolen += 1;
+                Ok(true)
+            }
+            None => {
+                event.stats.rejected += 1;
+                Ok(false)
+            }
+        }
+    }
+
+    fn push(&mut self, guid: Guid, instance: EventInstance) {
+        let event = self.events.entry(guid).or_default();
+        event.playing.push_back(instance);
+        event.stats.started += 1;
+        event.stats.active = event.playing.len();
+        event.stats.peak = event.stats.peak.max(event.stats.active);
+    }
+}
+
+impl Fmod {
+    /// Start a fire-and-forget instance of an event, with the given parameters set,
+    /// through the [`InstancePool`]. Returns `None` if the event is at its polyphony
+    /// limit and doesn't steal instances.
+    ///
+    /// The instance is released as soon as it starts, and is destroyed by FMOD once it
+    /// stops.
+    pub fn play_one_shot<T: AsRef<[u8]> + ?Sized>(
+        &self,
+        path: &T,
+        params: &[(&str, f32)],
+    ) -> Result<Option<EventInstance>> {
+        let description = self.get_event(path)?;
+        let guid = description.get_id()?;
+
+        let mut pool = self.pool.lock().unwrap();
+        pool.prune()?;
+        if !pool.reserve(guid)? {
+            return Ok(None);
+        }
+
+        let instance = description.create_instance()?;
+        for (name, value) in params {
+            instance.set_parameter_by_name(name, *value, false)?;
+        }
+        instance.start()?;
+        instance.release()?;
+        pool.push(guid, instance);
+
+        Ok(Some(instance))
+    }
+
+    /// Set the polyphony limit for one-shots of an event.
+    pub fn set_polyphony<T: AsRef<[u8]> + ?Sized>(
+        &self,
+        path: &T,
+        polyphony: Polyphony,
+    ) -> Result<()> {
+        let guid = self.get_event(path)?.get_id()?;
+        self.pool.lock().unwrap().set_polyphony(guid, polyphony);
+        Ok(())
+    }
+
+    /// Instance counts for one-shots of every event which has been played through
+    /// [`Fmod::play_one_shot`].
+    pub fn pool_stats(&self) -> Vec<(Guid, PoolStats)> {
+        self.pool.lock().unwrap().all_stats().collect()
+    }
+
+    /// Instance counts for one-shots of every event, added up; for the profiler.
+    pub fn total_pool_stats(&self) -> PoolStats {
+        self.pool.lock().unwrap().total_stats()
+    }
+
+    /// Run a closure with the one-shot instance pool.
+    pub fn with_pool<R>(&self, f: impl FnOnce(&mut InstancePool) -> R) -> R {
+        f(&mut self.pool.lock().unwrap())
+    }
+}
+
+pub(crate) fn play_one_shot<'lua>(
+    lua: LuaContext<'lua>,
+    (path, params): (LuaString<'lua>, Option<LuaTable<'lua>>),
+) -> LuaResult<Option<EventInstance>> {
+    let mut owned = Vec::new();
+    if let Some(params) = params {
+        for pair in params.pairs::<String, f32>() {
+            owned.push(pair?);
+        }
+    }
+    let params = owned
+        .iter()
+        .map(|(name, value)| (name.as_str(), *value))
+        .collect::<Vec<_>>();
+
+    let resources = lua.resources();
+    let fmod = resources.fetch_one::<Fmod>()?;
+    let instance = fmod
+        .borrow()
+        .play_one_shot(path.as_bytes(), &params)
+        .to_lua_err()?;
+    Ok(instance)
+}
+
+pub(crate) fn set_polyphony<'lua>(
+    lua: LuaContext<'lua>,
+    (path, max_instances, steal): (LuaString<'lua>, usize, Option<StealPolicy>),
+) -> LuaResult<()> {
+    let polyphony = Polyphony {
+        max_instances,
+        steal: steal.unwrap_or(StealPolicy::Oldest),
+    };
+
+    let resources = lua.resources();
+    let fmod = resources.fetch_one::<Fmod>()?;
+    let result = fmod.borrow().set_polyphony(path.as_bytes(), polyphony);
+    result.to_lua_err()
+}
+
+/// Returns a table of instance counts, keyed by event path where FMOD knows it.
+pub(crate) fn pool_stats<'lua>(lua: LuaContext<'lua>, (): ()) -> LuaResult<LuaTable<'lua>> {
+    let resources = lua.resources();
+    let fmod = resources.fetch_one::<Fmod>()?;
+    let fmod = fmod.borrow();
+
+    let table = lua.create_table()?;
+    for (guid, stats) in fmod.pool_stats() {
+        let key = match fmod.get_event_by_id(&guid).and_then(|e| e.get_path()) {
+            Ok(path) => path,
+            Err(_) => format!("{:?}", guid),
+        };
+
+        table.set(
+            key,
+            lua.create_table_from(vec![
+                ("active", stats.active as u64),
+                ("peak", stats.peak as u64),
+                ("started", stats.started),
+                ("stolen", stats.stolen),
+                ("rejected", stats.rejected),
+            ])?,
+        )?;
+    }
+
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_event_polyphony() {
+        let guid = Guid {
+            data1: 1,
+            data2: 2,
+            data3: 3,
+            data4: [4; 8],
+        };
+
+        let mut pool = InstancePool::new();
+        assert_eq!(pool.polyphony(&guid), Polyphony::default());
+
+        let quiet = Polyphony {
+            max_instances: 1,
+            steal: StealPolicy::None,
+        };
+        pool.set_polyphony(guid, quiet);
+        assert_eq!(pool.polyphony(&guid), quiet);
+        assert_eq!(pool.stats(&guid), Some(PoolStats::default()));
+    }
+
+    #[test]
+    fn stats_add_up_across_events() {
+        let guid = |n| Guid {
+            data1: n,
+            data2: 0,
+            data3: 0,
+            data4: [0; 8],
+        };
+
+        let mut pool = InstancePool::new();
+        assert_eq!(pool.total_stats(), PoolStats::default());
+
+        pool.set_polyphony(guid(1), Polyphony::default());
+        pool.set_polyphony(guid(2), Polyphony::default());
+        pool.events.get_mut(&guid(1)).unwrap().stats = PoolStats {
+            active: 2,
+            peak: 4,
+            started: 10,
+            stolen: 3,
+            rejected: 0,
+        };
+        pool.events.get_mut(&guid(2)).unwrap().stats = PoolStats {
+            active: 1,
+            peak: 1,
+            started: 5,
+            stolen: 0,
+            rejected: 2,
+        };
+
+        let total = pool.total_stats();
+        assert_eq!(
+            total,
+            PoolStats {
+                active: 3,
+                peak: 5,
+                started: 15,
+                stolen: 3,
+                rejected: 2,
+            }
+        );
+        assert_eq!(
+            total.to_string(),
+            "3 playing (peak 5), 15 started, 3 stolen, 2 rejected"
+        );
+    }
+}
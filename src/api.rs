@@ -1,7 +1,6 @@
 use crate::{
-    ecs::{Component, Entity, EntityBuilder, World},
-    filesystem::Filesystem,
-    Resources, SimpleComponent, SludgeLuaContextExt, SludgeResultExt,
+    ecs::{Component, Disabled, Entity, EntityBuilder, World},
+    hierarchy, Resources, SimpleComponent, SludgeLuaContextExt,
 };
 use {
     anyhow::*,
@@ -10,14 +9,22 @@ use {
     rlua::prelude::*,
     std::{
         any::TypeId,
-        io::Read,
         sync::{Arc, Mutex},
     },
 };
 
 mod log;
-mod math;
+pub mod math;
+pub mod package;
 mod thread;
+mod world;
+
+pub use package::{require, DEFAULT_PACKAGE_PATH, PACKAGE_REGISTRY_KEY};
+pub(crate) use thread::{per_thread_table, thread_name};
+pub use world::{
+    component_event_name, dispatch_component_events, ComponentEventKind, WorldEventSubscriptions,
+};
+pub(crate) use world::{playback_subscriptions, record_subscriptions};
 
 pub const SCHEDULER_QUEUE_REGISTRY_KEY: &'static str = "sludge.queue";
 pub const SERIALIZER_THUNK_REGISTRY_KEY: &'static str = "sludge.serialize";
@@ -26,14 +33,16 @@ pub const WORLD_TABLE_REGISTRY_KEY: &'static str = "sludge.world_table";
 pub const PERMANENTS_SER_TABLE_REGISTRY_KEY: &'static str = "sludge.permanents_ser";
 pub const PERMANENTS_DE_TABLE_REGISTRY_KEY: &'static str = "sludge.permanents_de";
 pub const PLAYBACK_THUNK_REGISTRY_KEY: &'static str = "sludge.playback_thunk";
-pub const PACKAGE_REGISTRY_KEY: &'static str = "sludge.package";
-pub const DEFAULT_PACKAGE_PATH: &'static str =
-    "/?.lua:/?/init.lua:/scripts/?.lua:/scripts/?/init.lua";
+pub const THREAD_LOCALS_REGISTRY_KEY: &'static str = "sludge.thread.locals";
+pub const THREAD_NAMES_REGISTRY_KEY: &'static str = "sludge.thread.names";
+pub const LAZY_MODULES_REGISTRY_KEY: &'static str = "sludge.lazy_modules";
 
 pub struct EntityUserDataRegistry {
     archetypes: Mutex<HashMap<Vec<TypeId>, Vec<(&'static str, LuaComponent)>>>,
     registered: HashMap<TypeId, LuaComponent>,
     named: HashMap<String, LuaComponent>,
+    bundles: HashMap<&'static str, LuaBundle>,
+    namespaces: HashMap<&'static str, HashMap<&'static str, EntityMethod>>,
 }
 
 impl EntityUserDataRegistry {
@@ -52,13 +61,51 @@ impl EntityUserDataRegistry {
             );
         }
 
+        let mut bundles = HashMap::new();
+        for bundle in inventory::iter::<LuaBundle> {
+            assert!(
+                !named.contains_key(bundle.name)
+                    && bundles.insert(bundle.name, bundle.clone()).is_none(),
+                "bundle already registered with name `{}`",
+                bundle.name
+            );
+        }
+
+        let mut namespaces = HashMap::<_, HashMap<_, _>>::new();
+        for method in inventory::iter::<EntityMethod> {
+            assert!(
+                namespaces
+                    .entry(method.namespace)
+                    .or_default()
+                    .insert(method.name, method.clone())
+                    .is_none(),
+                "entity method already registered with path `{}.{}`",
+                method.namespace,
+                method.name
+            );
+        }
+
         Self {
             archetypes: Mutex::new(HashMap::new()),
             registered,
             named,
+            bundles,
+            namespaces,
         }
     }
 
+    /// Look up the bundler for a component or bundle by the name it was registered with.
+    pub fn bundler(&self, name: &str) -> Option<BundlerConstructor> {
+        match self.named.get(name) {
+            Some(component) => Some(component.bundler.clone()),
+            None => self.bundles.get(name).map(|bundle| bundle.bundler.clone()),
+        }
+    }
+
+    fn get_method(&self, namespace: &str, name: &str) -> Option<&EntityMethod> {
+        self.namespaces.get(namespace)?.get(name)
+    }
+
     pub fn get_archetype<'lua>(
         &self,
         lua: LuaContext<'lua>,
@@ -138,6 +185,26 @@ impl LuaComponent {
         }
     }
 
+    /// Register a zero-sized marker component, which has no data to access from Lua.
+    ///
+    /// Tags appear on an entity's component table as `true`. When spawning or inserting,
+    /// a tag's value should be `true` (or any other truthy value); a value of `false`
+    /// simply doesn't add the tag.
+    pub fn tag<T: Component + Default>(type_name: &'static str) -> Self {
+        Self {
+            type_name,
+            type_id: TypeId::of::<T>(),
+            accessor: Arc::new(|_lua, _entity| Ok(LuaValue::Boolean(true))),
+            bundler: Arc::new(|_lua, args, builder| {
+                if !matches!(args, LuaValue::Nil | LuaValue::Boolean(false)) {
+                    builder.add(T::default());
+                }
+                Ok(())
+            }),
+            remover: Self::do_remove::<T>,
+        }
+    }
+
     fn do_remove<T: Component>(world: &mut World, entity: Entity) -> LuaResult<()> {
         world.remove_one::<T>(entity).to_lua_err()?;
         Ok(())
@@ -146,16 +213,310 @@ impl LuaComponent {
 
 inventory::collect!(LuaComponent);
 
+/// A bundle of components which can be spawned or inserted from Lua under a single name,
+/// as if it were a component itself. Usually implemented with `#[derive(LuaBundle)]`.
+pub trait LuaBundleInterface: Send + Sync + 'static {
+    /// The keys the bundle reads from its table. Any other keys are bundled as
+    /// components.
+    const FIELDS: &'static [&'static str];
+
+    /// Add the bundle's components to the builder. `table` is `None` if the bundle was
+    /// spawned with a non-table value, such as `true`.
+    fn bundler<'lua>(
+        lua: LuaContext<'lua>,
+        table: Option<LuaTable<'lua>>,
+        builder: &mut EntityBuilder,
+    ) -> LuaResult<()>;
+}
+
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub struct LuaBundle {
+    name: &'static str,
+
+    #[derivative(Debug = "ignore")]
+    bundler: BundlerConstructor,
+}
+
+impl LuaBundle {
+    pub fn new<T: LuaBundleInterface>(name: &'static str) -> Self {
+        Self {
+            name,
+            bundler: Arc::new(|lua, args, builder| {
+                let table = match args {
+                    LuaValue::Table(table) => Some(table),
+                    _ => None,
+                };
+
+                T::bundler(lua, table.clone(), builder)?;
+
+                if let Some(table) = table {
+                    for pair in table.pairs::<LuaString, LuaValue>() {
+                        let (k, v) = pair?;
+                        let s = k.to_str()?;
+                        if T::FIELDS.contains(&s) {
+                            continue;
+                        }
+
+                        let bundler = lua
+                            .fetch_one::<EntityUserDataRegistry>()?
+                            .borrow()
+                            .bundler(s)
+                            .ok_or_else(|| anyhow!("unknown component {}", s))
+                            .to_lua_err()?;
+                        bundler(lua, v, builder)?;
+                    }
+                }
+
+                Ok(())
+            }),
+        }
+    }
+}
+
+inventory::collect!(LuaBundle);
+
+pub type EntityMethodFn = Arc<
+    dyn for<'lua> Fn(
+            LuaContext<'lua>,
+            Entity,
+            LuaMultiValue<'lua>,
+        ) -> LuaResult<LuaMultiValue<'lua>>
+        + Send
+        + Sync,
+>;
+
+pub type EntityGetterFn =
+    Arc<dyn for<'lua> Fn(LuaContext<'lua>, Entity) -> LuaResult<LuaValue<'lua>> + Send + Sync>;
+
+pub type EntitySetterFn =
+    Arc<dyn for<'lua> Fn(LuaContext<'lua>, Entity, LuaValue<'lua>) -> LuaResult<()> + Send + Sync>;
+
+#[derive(Clone)]
+enum EntityMethodKind {
+    Method(EntityMethodFn),
+    Property(EntityGetterFn, Option<EntitySetterFn>),
+}
+
+/// A method or property added to the Lua entity userdata by a downstream crate, registered
+/// through `inventory::submit!`.
+///
+/// Every method lives in a namespace, which is accessed as a field of the entity; a method
+/// registered with the path `"danmaku.fire_pattern"` is called from Lua as
+/// `entity.danmaku:fire_pattern(...)`, and a property `"danmaku.bullet_count"` is read as
+/// `entity.danmaku.bullet_count`. Component names take precedence over namespaces.
+///
+/// ```ignore
+/// inventory::submit! {
+///     EntityMethod::parse("danmaku.fire_pattern", |lua, entity, args| {
+///         let pattern = Pattern::from_lua_multi(args, lua)?;
+///         // ...
+///         Ok(LuaMultiValue::new())
+///     })
+/// }
+/// ```
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub struct EntityMethod {
+    namespace: &'static str,
+    name: &'static str,
+
+    #[derivative(Debug = "ignore")]
+    kind: EntityMethodKind,
+}
+
+impl EntityMethod {
+    fn split_path(path: &'static str) -> (&'static str, &'static str) {
+        match path.rfind('.') {
+            Some(i) if i > 0 && i + 1 < path.len() => (&path[..i], &path[i + 1..]),
+            _ => panic!(
+                "entity method path `{}` must be of the form `namespace.name`",
+                path
+            ),
+        }
+    }
+
+    /// Register a method, called with the entity and whatever arguments follow `self`.
+    pub fn parse<F>(path: &'static str, method: F) -> Self
+    where
+        F: for<'lua> Fn(
+                LuaContext<'lua>,
+                Entity,
+                LuaMultiValue<'lua>,
+            ) -> LuaResult<LuaMultiValue<'lua>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let (namespace, name) = Self::split_path(path);
+        Self {
+            namespace,
+            name,
+            kind: EntityMethodKind::Method(Arc::new(method)),
+        }
+    }
+
+    /// Register a read-only property.
+    pub fn property<F>(path: &'static str, getter: F) -> Self
+    where
+        F: for<'lua> Fn(LuaContext<'lua>, Entity) -> LuaResult<LuaValue<'lua>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let (namespace, name) = Self::split_path(path);
+        Self {
+            namespace,
+            name,
+            kind: EntityMethodKind::Property(Arc::new(getter), None),
+        }
+    }
+
+    /// Make a property writable. Panics if this is a method rather than a property.
+    pub fn with_setter<F>(self, setter: F) -> Self
+    where
+        F: for<'lua> Fn(LuaContext<'lua>, Entity, LuaValue<'lua>) -> LuaResult<()>
+            + Send
+            + Sync
+            + 'static,
+    {
+        match self.kind {
+            EntityMethodKind::Property(getter, _) => Self {
+                kind: EntityMethodKind::Property(getter, Some(Arc::new(setter))),
+                ..self
+            },
+            EntityMethodKind::Method(_) => panic!(
+                "`{}.{}` is a method and can't have a setter",
+                self.namespace, self.name
+            ),
+        }
+    }
+}
+
+inventory::collect!(EntityMethod);
+
+/// A namespace of registered [`EntityMethod`]s, bound to a specific entity.
+#[derive(Debug, Clone)]
+struct EntityNamespace {
+    entity: u64,
+    namespace: &'static str,
+}
+
+impl LuaUserData for EntityNamespace {
+    fn add_methods<'lua, T: LuaUserDataMethods<'lua, Self>>(methods: &mut T) {
+        methods.add_meta_method(LuaMetaMethod::Index, |lua, this, key: LuaString| {
+            let registry = lua.fetch_one::<EntityUserDataRegistry>()?;
+            let kind = registry
+                .borrow()
+                .get_method(this.namespace, key.to_str()?)
+                .map(|method| method.kind.clone());
+            let entity = Entity::from_bits(this.entity);
+
+            match kind {
+                Some(EntityMethodKind::Method(method)) => lua
+                    .create_function(move |lua, (_this, args): (LuaValue, LuaMultiValue)| {
+                        method(lua, entity, args)
+                    })?
+                    .to_lua(lua),
+                Some(EntityMethodKind::Property(getter, _)) => getter(lua, entity),
+                None => Ok(LuaValue::Nil),
+            }
+        });
+
+        methods.add_meta_method(
+            LuaMetaMethod::NewIndex,
+            |lua, this, (key, value): (LuaString, LuaValue)| {
+                let registry = lua.fetch_one::<EntityUserDataRegistry>()?;
+                let key = key.to_str()?;
+                let kind = registry
+                    .borrow()
+                    .get_method(this.namespace, key)
+                    .map(|method| method.kind.clone());
+
+                match kind {
+                    Some(EntityMethodKind::Property(_, Some(setter))) => {
+                        setter(lua, Entity::from_bits(this.entity), value)
+                    }
+                    _ => {
+                        Err(anyhow!("no writable property {}.{}", this.namespace, key)).to_lua_err()
+                    }
+                }
+            },
+        );
+    }
+}
+
+/// The userdata behind a [`LuaEntity`]. Handles are weak unless they're made strong
+/// with `:strong()`: using a component or namespace of a weak handle to a despawned
+/// entity gives `nil`, while a strong handle raises an error instead. Whether the entity
+/// is still there is checked against the generation stored in the handle, so despawning
+/// an entity invalidates every handle to it without having to track them.
 #[derive(Debug, Clone, Copy)]
-struct LuaEntityUserData(u64);
+struct LuaEntityUserData {
+    bits: u64,
+    strong: bool,
+}
+
+impl LuaEntityUserData {
+    fn entity(&self) -> Entity {
+        Entity::from_bits(self.bits)
+    }
+
+    /// Whether the entity is still alive; if it isn't, strong handles are an error.
+    fn check_alive(&self, lua: LuaContext) -> LuaResult<bool> {
+        let alive = lua.fetch_one::<World>()?.borrow().contains(self.entity());
+        if !alive && self.strong {
+            return Err(anyhow!("entity {:?} has been despawned", self.entity())).to_lua_err();
+        }
+        Ok(alive)
+    }
+
+    fn to_lua_handle<'lua>(self, lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+        let registry = lua.fetch_one::<EntityUserDataRegistry>()?;
+        let entity = self.entity();
+        let alive = lua.fetch_one::<World>()?.borrow().contains(entity);
+        let fields = if alive {
+            registry.borrow().get_archetype(lua, entity)?
+        } else {
+            lua.create_table()?
+        };
+
+        let ud = lua.create_userdata(self)?;
+        ud.set_user_value(fields)?;
+        ud.to_lua(lua)
+    }
+}
 
 impl LuaUserData for LuaEntityUserData {
     fn add_methods<'lua, T: LuaUserDataMethods<'lua, Self>>(methods: &mut T) {
         methods.add_meta_function(
             LuaMetaMethod::Index,
-            |_lua, (ud, key): (LuaAnyUserData, LuaString)| {
+            |lua, (ud, key): (LuaAnyUserData, LuaString)| {
+                if !ud.borrow::<Self>()?.check_alive(lua)? {
+                    return Ok(LuaValue::Nil);
+                }
+
                 let table = ud.get_user_value::<LuaTable>()?;
-                table.get::<_, LuaValue>(key)
+                let value = table.get::<_, LuaValue>(key.clone())?;
+                if !matches!(value, LuaValue::Nil) {
+                    return Ok(value);
+                }
+
+                let registry = lua.fetch_one::<EntityUserDataRegistry>()?;
+                let namespace = registry
+                    .borrow()
+                    .namespaces
+                    .get_key_value(key.to_str()?)
+                    .map(|(&namespace, _)| namespace);
+
+                match namespace {
+                    Some(namespace) => EntityNamespace {
+                        entity: ud.borrow::<Self>()?.bits,
+                        namespace,
+                    }
+                    .to_lua(lua),
+                    None => Ok(LuaValue::Nil),
+                }
             },
         );
 
@@ -173,20 +534,18 @@ impl LuaUserData for LuaEntityUserData {
                         .map(|comp| comp.remover)
                         .ok_or_else(|| anyhow!("unknown component {}", s))
                         .to_lua_err()?;
-                    remover(&mut world.borrow_mut(), Entity::from_bits(this.0))?;
+                    remover(&mut world.borrow_mut(), this.entity())?;
                 } else {
                     let mut builder = EntityBuilder::new();
                     let bundler = registry
                         .borrow()
-                        .named
-                        .get(s)
-                        .map(|comp| comp.bundler.clone())
+                        .bundler(s)
                         .ok_or_else(|| anyhow!("unknown component {}", s))
                         .to_lua_err()?;
                     bundler(lua, v, &mut builder)?;
                     world
                         .borrow_mut()
-                        .insert(Entity::from_bits(this.0), builder.build())
+                        .insert(this.entity(), builder.build())
                         .to_lua_err()?;
                 }
 
@@ -202,15 +561,90 @@ impl LuaUserData for LuaEntityUserData {
             Ok(())
         });
 
+        // Copies the entity's components, and with `deep` set, its children as well. See
+        // `World::clone_entity` for which components are copied.
+        methods.add_method("clone", |lua, this, deep: Option<bool>| {
+            let tmp = lua.fetch_one::<World>()?;
+            let mut world = tmp.borrow_mut();
+            let entity = Entity::from(*this);
+            let copy = if deep.unwrap_or(false) {
+                world.clone_hierarchy(entity)
+            } else {
+                world.clone_entity(entity)
+            };
+            Ok(LuaEntity::from(copy.to_lua_err()?))
+        });
+
+        // Attaches the entity to `parent`, or detaches it with `nil`. Unless
+        // `keep_world_transform` is `false`, the entity stays where it is in the world.
+        methods.add_method(
+            "set_parent",
+            |lua, this, (parent, keep_world_transform): (Option<LuaEntity>, Option<bool>)| {
+                let entity = Entity::from(*this);
+                let parent = parent.map(Entity::from);
+                let old_parent = hierarchy::set_parent(
+                    &mut lua.fetch_one::<World>()?.borrow_mut(),
+                    entity,
+                    parent,
+                    keep_world_transform.unwrap_or(true),
+                )
+                .to_lua_err()?;
+
+                if old_parent != parent {
+                    lua.broadcast(
+                        hierarchy::PARENT_CHANGED_EVENT,
+                        (
+                            LuaEntity::from(entity),
+                            parent.map(LuaEntity::from),
+                            old_parent.map(LuaEntity::from),
+                        ),
+                    )?;
+                }
+
+                Ok(())
+            },
+        );
+
+        methods.add_method("parent", |lua, this, ()| {
+            let world = lua.fetch_one::<World>()?;
+            let parent = hierarchy::parent_of(&world.borrow(), Entity::from(*this));
+            Ok(parent.map(LuaEntity::from))
+        });
+
+        methods.add_method("children", |lua, this, ()| {
+            let world = lua.fetch_one::<World>()?;
+            let children = hierarchy::children_of(&world.borrow(), Entity::from(*this));
+            lua.create_sequence_from(children.into_iter().map(LuaEntity::from))
+        });
+
         methods.add_meta_method(LuaMetaMethod::ToString, |_lua, this, ()| {
-            Ok(format!("{:?}", Entity::from_bits(this.0)))
+            Ok(format!("{:?}", this.entity()))
+        });
+
+        methods.add_method("valid", |lua, this, ()| {
+            Ok(lua.fetch_one::<World>()?.borrow().contains(this.entity()))
         });
 
         methods.add_method("is_alive", |lua, this, ()| {
-            Ok(lua
-                .fetch_one::<World>()?
-                .borrow()
-                .contains(Entity::from(*this)))
+            Ok(lua.fetch_one::<World>()?.borrow().contains(this.entity()))
+        });
+
+        methods.add_method("is_strong", |_lua, this, ()| Ok(this.strong));
+
+        methods.add_method("strong", |lua, this, ()| {
+            LuaEntityUserData {
+                strong: true,
+                ..*this
+            }
+            .to_lua_handle(lua)
+        });
+
+        methods.add_method("weak", |lua, this, ()| {
+            LuaEntityUserData {
+                strong: false,
+                ..*this
+            }
+            .to_lua_handle(lua)
         });
 
         methods.add_meta_function(
@@ -227,9 +661,7 @@ impl LuaUserData for LuaEntityUserData {
                 // Temporary here to pacify borrow checker.
                 let t = (this.borrow::<Self>(), other.borrow::<Self>());
                 match t {
-                    (Ok(this), Ok(other)) => {
-                        Ok(Entity::from_bits(this.0) == Entity::from_bits(other.0))
-                    }
+                    (Ok(this), Ok(other)) => Ok(this.entity() == other.entity()),
                     _ => Ok(false),
                 }
             },
@@ -249,9 +681,7 @@ impl LuaUserData for LuaEntityUserData {
                 // Temporary here to pacify borrow checker.
                 let t = (this.borrow::<Self>(), other.borrow::<Self>());
                 match t {
-                    (Ok(this), Ok(other)) => {
-                        Ok(Entity::from_bits(this.0) < Entity::from_bits(other.0))
-                    }
+                    (Ok(this), Ok(other)) => Ok(this.entity() < other.entity()),
                     _ => Ok(false),
                 }
             },
@@ -271,9 +701,7 @@ impl LuaUserData for LuaEntityUserData {
                 // Temporary here to pacify borrow checker.
                 let t = (this.borrow::<Self>(), other.borrow::<Self>());
                 match t {
-                    (Ok(this), Ok(other)) => {
-                        Ok(Entity::from_bits(this.0) <= Entity::from_bits(other.0))
-                    }
+                    (Ok(this), Ok(other)) => Ok(this.entity() <= other.entity()),
                     _ => Ok(false),
                 }
             },
@@ -287,8 +715,9 @@ impl LuaUserData for LuaEntityUserData {
                 let world_table =
                     lua.named_registry_value::<_, LuaTable>(WORLD_TABLE_REGISTRY_KEY)?;
                 lookup_thunk.call((
-                    LuaLightUserData(Entity::from_bits(this.0).id() as *mut _),
+                    LuaLightUserData(this.entity().id() as *mut _),
                     world_table,
+                    this.strong,
                 ))
             },
         );
@@ -297,13 +726,21 @@ impl LuaUserData for LuaEntityUserData {
 
 impl From<LuaEntityUserData> for Entity {
     fn from(leud: LuaEntityUserData) -> Entity {
-        Entity::from_bits(leud.0)
+        leud.entity()
     }
 }
 
 /// An [`Entity`] wrapped for use with Lua and provided with a metatable that
 /// allows for Lua operations on it, for components which support such.
 ///
+/// # Weak and strong handles
+///
+/// Entities are handed to Lua as weak handles, which are safe to keep around in
+/// long-lived tables: once the entity is despawned, `handle:valid()` returns `false` and
+/// its components read as `nil`. A strong handle, made with `handle:strong()` or
+/// [`LuaEntity::to_lua_strong`], raises an error when it's used after its entity is
+/// gone instead, for code which should never see a stale entity.
+///
 /// # Persistence
 ///
 /// Once passed to `Lua`, a `LuaEntity` becomes a userdata object which is
@@ -324,30 +761,48 @@ impl From<LuaEntity> for Entity {
     }
 }
 
+impl LuaEntity {
+    /// Hand the entity to Lua as a strong handle rather than a weak one.
+    pub fn to_lua_strong<'lua>(self, lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+        LuaEntityUserData {
+            bits: self.0,
+            strong: true,
+        }
+        .to_lua_handle(lua)
+    }
+}
+
 impl<'lua> ToLua<'lua> for LuaEntity {
     fn to_lua(self, lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
-        let registry = lua.fetch_one::<EntityUserDataRegistry>()?;
-
-        let ud = lua.create_userdata(LuaEntityUserData(self.0))?;
-        let entity = Entity::from_bits(self.0);
-        let fields = registry.borrow().get_archetype(lua, entity)?;
-
-        ud.set_user_value(fields)?;
-        ud.to_lua(lua)
+        LuaEntityUserData {
+            bits: self.0,
+            strong: false,
+        }
+        .to_lua_handle(lua)
     }
 }
 
 impl<'lua> FromLua<'lua> for LuaEntity {
     fn from_lua(lua_value: LuaValue<'lua>, lua: LuaContext<'lua>) -> LuaResult<Self> {
-        LuaEntityUserData::from_lua(lua_value, lua).map(|ud| LuaEntity(ud.0))
+        LuaEntityUserData::from_lua(lua_value, lua).map(|ud| LuaEntity(ud.bits))
     }
 }
 
 pub type ModuleLoader = Box<dyn for<'lua> Fn(LuaContext<'lua>) -> Result<LuaValue<'lua>> + 'static>;
 
+/// A built-in Lua module, registered with `inventory::submit!` and installed into the
+/// globals of every space at its path.
+///
+/// Paths are hierarchical and separated by dots, so `"fmod.studio"` ends up as the
+/// `studio` field of the global `fmod` table. Modules are installed when a space is
+/// built unless they're marked [lazy](Module::lazy), in which case they're installed
+/// the first time they're `require`d; a lazy module makes everything under it lazy too.
+/// Spaces can also make modules lazy or leave them out entirely with
+/// [`ModuleOptions`].
 pub struct Module {
     path: Vec<&'static str>,
     load: ModuleLoader,
+    lazy: bool,
 }
 
 impl Module {
@@ -358,6 +813,7 @@ impl Module {
         Self {
             path: path.to_owned(),
             load: Box::new(load),
+            lazy: false,
         }
     }
 
@@ -368,8 +824,69 @@ impl Module {
         Self {
             path: path.split(".").collect(),
             load: Box::new(load),
+            lazy: false,
         }
     }
+
+    /// Don't install this module until it's `require`d.
+    pub fn lazy(self) -> Self {
+        Self { lazy: true, ..self }
+    }
+
+    pub fn path(&self) -> String {
+        self.path.join(".")
+    }
+}
+
+/// Per-space settings for which built-in [`Module`]s are loaded, and when.
+///
+/// Each path covers the module at that path and every module under it, so excluding
+/// `"fmod"` also excludes `"fmod.LoadBankFlags"`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModuleOptions {
+    /// Modules which aren't loaded at all.
+    pub excluded: Vec<String>,
+    /// Modules which are only loaded once they're `require`d.
+    pub lazy: Vec<String>,
+}
+
+impl ModuleOptions {
+    /// Modules which the sludge API and prelude can't do without, and so can't be left
+    /// out or made lazy.
+    pub const REQUIRED: &'static [&'static str] = &["sludge", "sludge.thread"];
+
+    fn covers(paths: &[String], path: &str) -> bool {
+        paths.iter().any(|prefix| {
+            path.strip_prefix(prefix.as_str())
+                .map_or(false, |rest| rest.is_empty() || rest.starts_with('.'))
+        })
+    }
+
+    pub fn is_excluded(&self, path: &str) -> bool {
+        Self::covers(&self.excluded, path)
+    }
+
+    pub fn is_lazy(&self, path: &str) -> bool {
+        Self::covers(&self.lazy, path)
+    }
+
+    /// Check that none of the [required](Self::REQUIRED) modules are left out or lazy.
+    pub fn validate(&self) -> Result<()> {
+        for &path in Self::REQUIRED {
+            ensure!(
+                !self.is_excluded(path),
+                "the `{}` module is required by the sludge Lua API and can't be left out",
+                path
+            );
+            ensure!(
+                !self.is_lazy(path),
+                "the `{}` module is required by the sludge Lua API and can't be lazy",
+                path
+            );
+        }
+
+        Ok(())
+    }
 }
 
 inventory::collect!(Module);
@@ -469,9 +986,7 @@ pub fn spawn<'lua>(lua: LuaContext<'lua>, table: LuaTable<'lua>) -> LuaResult<Lu
         let s = k.to_str()?;
         let bundler = registry
             .borrow()
-            .named
-            .get(s)
-            .map(|comp| comp.bundler.clone())
+            .bundler(s)
             .ok_or_else(|| anyhow!("unknown component {}", s))
             .to_lua_err()?;
         bundler(lua, v, &mut builder)?;
@@ -493,9 +1008,7 @@ pub fn insert<'lua>(
         let s = k.to_str()?;
         let bundler = registry
             .borrow()
-            .named
-            .get(s)
-            .map(|comp| comp.bundler.clone())
+            .bundler(s)
             .ok_or_else(|| format_err!("unknown component {}", s))
             .to_lua_err()?;
         bundler(lua, v, &mut builder)?;
@@ -523,78 +1036,155 @@ pub fn clear<'lua>(lua: LuaContext<'lua>, _: ()) -> LuaResult<()> {
     Ok(())
 }
 
-inventory::submit! {
-    Module::parse("sludge", |lua| {
-        let table = lua.create_table_from(vec![
-            ("spawn", lua.create_function(spawn)?),
-            ("insert", lua.create_function(insert)?),
-            ("despawn", lua.create_function(despawn)?),
-            ("clear", lua.create_function(clear)?),
-        ])?;
-
-        Ok(LuaValue::Table(table))
-    })
-}
-
-/// Lua-exposed function for loading a module from sludge's `Filesystem`.
+/// A query over the entities in the world, filtered by which components they have,
+/// exposed to Lua through `sludge.query`.
 ///
-/// Similar to Lua's built-in `require`, this will search along paths found in
-/// `sludge.package.path`, which is expected to be a colon-separated list of
-/// paths to search, where any `?` characters found are replaced by the module
-/// path being searched for. The default value of `sludge.package.path` is "/?.lua",
-/// which will simply search for any Lua files found in the VFS.
+/// ```lua
+/// for boss in sludge.query("Boss"):without("Dead"):iter() do
+///     -- ...
+/// end
+/// ```
 ///
-/// The limitations of opening files through this `require` are the same as opening
-/// any file through the `Filesystem`.
-pub fn require<'lua>(lua: LuaContext<'lua>, module: String) -> LuaResult<LuaValue> {
-    let package = lua.named_registry_value::<_, LuaTable>(PACKAGE_REGISTRY_KEY)?;
-    let loaded_modules = package.get::<_, LuaTable>("modules")?;
-    if let Some(module) = loaded_modules.get::<_, Option<LuaValue>>(module.as_str())? {
-        Ok(module)
-    } else {
-        let fs = lua.fetch_one::<Filesystem>()?;
-        let package_path = package.get::<_, LuaString>("path")?;
-        let segments = package_path.to_str()?.split(":");
-        let module_replaced = module.replace(".", "/");
-
-        for segment in segments {
-            let path = segment.replace('?', &module_replaced);
-            let mut file = match fs.borrow_mut().open(&path) {
-                Ok(file) => file,
-                Err(_) => continue,
-            };
-            let mut buf = String::new();
-            file.read_to_string(&mut buf)
-                .log_error_err(module_path!())
-                .to_lua_err()?;
-            let loaded = lua
-                .load(&buf)
-                .set_name(&module)?
-                .into_function()?
-                .call::<_, LuaValue>(())?;
-            loaded_modules.set(path.as_str(), loaded.clone())?;
-            return Ok(loaded);
+/// Queries are immutable; `with` and `without` return new queries. Matching entities
+/// are collected when `iter`, `entities` or `count` is called, so it's safe to spawn
+/// or despawn entities while iterating.
+///
+/// Entities marked [`Disabled`] are skipped unless the query is made with
+/// `including_disabled` or asks for `"Disabled"` explicitly.
+#[derive(Debug, Clone, Default)]
+pub struct LuaQuery {
+    with: Vec<TypeId>,
+    without: Vec<TypeId>,
+    include_disabled: bool,
+}
+
+impl LuaQuery {
+    fn type_id_of<'lua>(lua: LuaContext<'lua>, name: &str) -> LuaResult<TypeId> {
+        lua.fetch_one::<EntityUserDataRegistry>()?
+            .borrow()
+            .named
+            .get(name)
+            .map(|comp| comp.type_id)
+            .ok_or_else(|| anyhow!("unknown component {}", name))
+            .to_lua_err()
+    }
+
+    /// Whether an entity with the given set of components matches this query.
+    fn matches(&self, component_types: impl Iterator<Item = TypeId>) -> bool {
+        let disabled = TypeId::of::<Disabled>();
+        let skip_disabled = !self.include_disabled && !self.with.contains(&disabled);
+        let mut with_count = 0;
+        for type_id in component_types {
+            if self.without.contains(&type_id) || (type_id == disabled && skip_disabled) {
+                return false;
+            } else if self.with.contains(&type_id) {
+                with_count += 1;
+            }
         }
+        with_count == self.with.len()
+    }
+
+    /// Collect all entities in the world matching this query. Every entity in an
+    /// archetype has the same components, so only the first entity of each archetype is
+    /// checked against the query.
+    pub fn matching(&self, world: &World) -> Vec<Entity> {
+        let mut entities = Vec::new();
+        for archetype in world.archetypes() {
+            let ids = archetype.ids();
+            if ids.is_empty() {
+                continue;
+            }
 
-        // FIXME: better error reporting here; collect errors from individual module attempts
-        // and log them?
-        Err(anyhow!("module {} not found!", module)).to_lua_err()
+            // Safety: the IDs of an archetype are all IDs of live entities.
+            let first = unsafe { world.find_entity_from_id(ids[0]) };
+            let matches = world.entity(first).map_or(false, |entity_ref| {
+                self.matches(entity_ref.component_types())
+            });
+
+            if matches {
+                entities.extend(
+                    ids.iter()
+                        .map(|&id| unsafe { world.find_entity_from_id(id) }),
+                );
+            }
+        }
+        entities
     }
 }
 
-inventory::submit! {
-    Module::parse("sludge.package", |lua| {
-        let table = lua.create_table()?;
-        table.set("path", DEFAULT_PACKAGE_PATH)?;
+impl LuaUserData for LuaQuery {
+    fn add_methods<'lua, T: LuaUserDataMethods<'lua, Self>>(methods: &mut T) {
+        methods.add_method("with", |lua, this, names: LuaMultiValue| {
+            let mut query = this.clone();
+            for name in names {
+                let type_id = Self::type_id_of(lua, &String::from_lua(name, lua)?)?;
+                if !query.with.contains(&type_id) {
+                    query.with.push(type_id);
+                }
+            }
+            Ok(query)
+        });
+
+        methods.add_method("without", |lua, this, names: LuaMultiValue| {
+            let mut query = this.clone();
+            for name in names {
+                let type_id = Self::type_id_of(lua, &String::from_lua(name, lua)?)?;
+                if !query.without.contains(&type_id) {
+                    query.without.push(type_id);
+                }
+            }
+            Ok(query)
+        });
+
+        methods.add_method("including_disabled", |_lua, this, ()| {
+            let mut query = this.clone();
+            query.include_disabled = true;
+            Ok(query)
+        });
+
+        methods.add_method("iter", |lua, this, ()| {
+            let entities = this.matching(&lua.fetch_one::<World>()?.borrow());
+            let iter = Mutex::new(entities.into_iter());
+            lua.create_function(move |_lua, ()| {
+                Ok(iter.lock().unwrap().next().map(LuaEntity::from))
+            })
+        });
+
+        methods.add_method("entities", |lua, this, ()| {
+            let entities = this.matching(&lua.fetch_one::<World>()?.borrow());
+            lua.create_sequence_from(entities.into_iter().map(LuaEntity::from))
+        });
+
+        methods.add_method("count", |lua, this, ()| {
+            Ok(this.matching(&lua.fetch_one::<World>()?.borrow()).len())
+        });
+    }
+}
 
-        let req_fn = lua.create_function(require)?;
-        table.set("require", req_fn.clone())?;
-        lua.globals().set("require", req_fn)?;
+pub fn query<'lua>(lua: LuaContext<'lua>, names: LuaMultiValue<'lua>) -> LuaResult<LuaQuery> {
+    let mut query = LuaQuery::default();
+    for name in names {
+        let type_id = LuaQuery::type_id_of(lua, &String::from_lua(name, lua)?)?;
+        query.with.push(type_id);
+    }
+    Ok(query)
+}
 
-        let modules = lua.create_table()?;
-        table.set("modules", modules.clone())?;
+inventory::submit! {
+    Module::parse("sludge", |lua| {
+        let table = lua.create_table_from(vec![
+            ("spawn", lua.create_function(spawn)?),
+            ("insert", lua.create_function(insert)?),
+            ("despawn", lua.create_function(despawn)?),
+            ("clear", lua.create_function(clear)?),
+            ("query", lua.create_function(query)?),
+            ("pause", lua.create_function(crate::pause::api::pause)?),
+            ("resume", lua.create_function(crate::pause::api::resume)?),
+            ("is_paused", lua.create_function(crate::pause::api::is_paused)?),
+        ])?;
 
-        lua.set_named_registry_value(PACKAGE_REGISTRY_KEY, table.clone())?;
+        table.set("PAUSED_EVENT", crate::pause::PAUSED_EVENT)?;
+        table.set("RESUMED_EVENT", crate::pause::RESUMED_EVENT)?;
 
         Ok(LuaValue::Table(table))
     })
@@ -640,7 +1230,97 @@ impl<'lua> SludgeApiLuaContextExt<'lua> for LuaContext<'lua> {
     }
 }
 
-pub fn load<'lua>(lua: LuaContext<'lua>) -> Result<()> {
+/// Install a module into the globals at its path, creating any missing parent tables,
+/// and register everything in it as a permanent for persistence.
+fn install<'lua>(lua: LuaContext<'lua>, module: &Module) -> Result<LuaValue<'lua>> {
+    let mut t = lua.globals();
+    let (&last, rest) = module
+        .path
+        .split_last()
+        .ok_or_else(|| anyhow!("empty module path!"))?;
+
+    let mut path = String::new();
+    for &ident in rest.iter() {
+        t = match t.get::<_, Option<LuaTable<'lua>>>(ident)? {
+            Some(subtable) => subtable,
+            None => {
+                let subtable = lua.create_table()?;
+                t.set(ident, subtable.clone())?;
+                subtable
+            }
+        };
+
+        if !path.is_empty() {
+            path.push('.');
+        }
+        path.push_str(ident);
+        lua.register_permanents(&path, t.clone())?;
+    }
+
+    ensure!(
+        !t.contains_key(last)?,
+        "name collision while loading modules: two modules have the same path `{}`",
+        module.path()
+    );
+    let table = (module.load)(lua)?;
+    lua.register_permanents(&module.path(), table.clone())?;
+    t.set(last, table.clone())?;
+
+    Ok(table)
+}
+
+/// Install a lazy module which hasn't been loaded yet, along with any lazy modules
+/// above it. Returns `None` if there's no such module waiting to be loaded.
+///
+/// Lazy modules loaded this way are also added to `require`'s cache of loaded modules.
+pub fn load_lazy<'lua>(lua: LuaContext<'lua>, path: &str) -> Result<Option<LuaValue<'lua>>> {
+    let pending = lua.named_registry_value::<_, LuaTable>(LAZY_MODULES_REGISTRY_KEY)?;
+    if !pending.contains_key(path)? {
+        return Ok(None);
+    }
+
+    for (end, _) in path.match_indices('.') {
+        load_lazy(lua, &path[..end])?;
+    }
+
+    let module = inventory::iter::<Module>
+        .into_iter()
+        .find(|module| module.path() == path)
+        .ok_or_else(|| anyhow!("no module registered with the path `{}`", path))?;
+    pending.set(path, LuaValue::Nil)?;
+    let value = install(lua, module)?;
+
+    if let Some(package) = lua.named_registry_value::<_, Option<LuaTable>>(PACKAGE_REGISTRY_KEY)? {
+        package
+            .get::<_, LuaTable>("modules")?
+            .set(path, value.clone())?;
+    }
+
+    Ok(Some(value))
+}
+
+/// Install every lazy module which hasn't been loaded yet.
+pub fn load_all_lazy<'lua>(lua: LuaContext<'lua>) -> Result<()> {
+    let pending = lua.named_registry_value::<_, LuaTable>(LAZY_MODULES_REGISTRY_KEY)?;
+    let mut paths = pending
+        .pairs::<String, bool>()
+        .map(|pair| pair.map(|(path, _)| path))
+        .collect::<LuaResult<Vec<_>>>()?;
+    paths.sort_unstable();
+
+    for path in paths {
+        load_lazy(lua, &path)?;
+    }
+
+    Ok(())
+}
+
+/// Load the sludge Lua API into a fresh Lua state, installing every built-in module
+/// which `options` doesn't exclude or make lazy.
+pub fn load<'lua>(lua: LuaContext<'lua>, options: &ModuleOptions) -> Result<()> {
+    package::stash_raw_functions(lua)?;
+    crate::profile::stash_collectgarbage(lua)?;
+
     [
         "dofile",
         "load",
@@ -671,40 +1351,25 @@ pub fn load<'lua>(lua: LuaContext<'lua>) -> Result<()> {
     let mut modules = inventory::iter::<Module>.into_iter().collect::<Vec<_>>();
     modules.sort_unstable_by_key(|m| &m.path);
 
+    let pending = lua.create_table()?;
+    let mut lazy = options.lazy.clone();
     for module in modules.iter() {
-        let mut t = lua.globals();
-        let (&last, rest) = module
-            .path
-            .split_last()
-            .ok_or_else(|| anyhow!("empty module path!"))?;
-
-        let mut path = String::new();
-        for &ident in rest.iter() {
-            t = match t.get::<_, Option<LuaTable<'lua>>>(ident)? {
-                Some(subtable) => subtable,
-                None => {
-                    let subtable = lua.create_table()?;
-                    t.set(ident, subtable.clone())?;
-                    subtable
-                }
-            };
+        let path = module.path();
+        if options.is_excluded(&path) {
+            continue;
+        }
 
-            if !path.is_empty() {
-                path.push('.');
-            }
-            path.push_str(ident);
-            lua.register_permanents(&path, t.clone())?;
+        // Anything under a lazy module has to wait for it, since loading it first would
+        // create the lazy module's table and make it look like a duplicate.
+        if module.lazy || ModuleOptions::covers(&lazy, &path) {
+            pending.set(path.as_str(), true)?;
+            lazy.push(path);
+            continue;
         }
 
-        ensure!(
-            !t.contains_key(last)?,
-            "name collision while loading modules: two modules have the same path `{}`",
-            module.path.join(".")
-        );
-        let table = (module.load)(lua)?;
-        lua.register_permanents(&module.path.join("."), table.clone())?;
-        t.set(last, table)?;
+        install(lua, module)?;
     }
+    lua.set_named_registry_value(LAZY_MODULES_REGISTRY_KEY, pending)?;
 
     lua.set_named_registry_value(
         SERIALIZER_THUNK_REGISTRY_KEY,
@@ -733,3 +1398,110 @@ pub fn load<'lua>(lua: LuaContext<'lua>) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::{Name, Parent};
+
+    #[test]
+    fn queries_match_whole_archetypes() {
+        let mut world = World::new();
+        let named = (0..3)
+            .map(|i| world.spawn((Name(i.to_string()),)))
+            .collect::<Vec<_>>();
+        let parent = world.spawn((Name("parent".to_owned()), Parent::new(named[0])));
+        let disabled = world.spawn((Name("disabled".to_owned()), Disabled));
+        world.spawn((Parent::new(named[1]),));
+
+        let name = TypeId::of::<Name>();
+        let sorted = |query: &LuaQuery| {
+            let mut entities = query.matching(&world);
+            entities.sort();
+            entities
+        };
+
+        let query = LuaQuery {
+            with: vec![name],
+            ..LuaQuery::default()
+        };
+        let mut expected = named.clone();
+        expected.push(parent);
+        expected.sort();
+        assert_eq!(sorted(&query), expected);
+
+        let query = LuaQuery {
+            with: vec![name],
+            without: vec![TypeId::of::<Parent>()],
+            include_disabled: true,
+        };
+        let mut expected = named.clone();
+        expected.push(disabled);
+        expected.sort();
+        assert_eq!(sorted(&query), expected);
+
+        let query = LuaQuery {
+            with: vec![TypeId::of::<Disabled>()],
+            ..LuaQuery::default()
+        };
+        assert_eq!(sorted(&query), vec![disabled]);
+    }
+
+    #[test]
+    fn despawned_handles_are_nil_when_weak_and_errors_when_strong() -> Result<()> {
+        use crate::{lifetime::Lifetime, Space};
+
+        let space = Space::new()?;
+        let entity = space.world()?.borrow_mut().spawn((Lifetime(10.),));
+
+        space.lua().context(|lua| -> Result<()> {
+            let globals = lua.globals();
+            globals.set("weak", LuaEntity::from(entity))?;
+            globals.set("strong", LuaEntity::from(entity).to_lua_strong(lua)?)?;
+
+            let (weak_strong, strong_strong, round_trip) = lua
+                .load(
+                    "return weak:is_strong(), strong:is_strong(), weak:strong():weak():is_strong()",
+                )
+                .eval::<(bool, bool, bool)>()?;
+            assert!(!weak_strong);
+            assert!(strong_strong);
+            assert!(!round_trip);
+
+            let (valid, has_lifetime) = lua
+                .load("return weak:valid(), weak.Lifetime ~= nil and strong.Lifetime ~= nil")
+                .eval::<(bool, bool)>()?;
+            assert!(valid);
+            assert!(has_lifetime);
+            Ok(())
+        })?;
+
+        space.world()?.borrow_mut().despawn(entity)?;
+
+        space.lua().context(|lua| -> Result<()> {
+            let (weak_valid, strong_valid, alive, lifetime) = lua
+                .load("return weak:valid(), strong:valid(), strong:is_alive(), weak.Lifetime")
+                .eval::<(bool, bool, bool, LuaValue)>()?;
+            assert!(!weak_valid);
+            assert!(!strong_valid);
+            assert!(!alive);
+            assert!(matches!(lifetime, LuaValue::Nil));
+
+            let err = lua
+                .load("return strong.Lifetime")
+                .eval::<LuaValue>()
+                .unwrap_err();
+            assert!(format!("{:?}", err).contains("has been despawned"));
+
+            // Making a weak handle strong doesn't bring its entity back either.
+            let err = lua
+                .load("return weak:strong().Lifetime")
+                .eval::<LuaValue>()
+                .unwrap_err();
+            assert!(format!("{:?}", err).contains("has been despawned"));
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+}
@@ -1,32 +1,90 @@
+//! `sludge.log`: logging from Lua through the `log` crate.
+//!
+//! ```lua
+//! sludge.log.info("something happened")
+//! sludge.log.warn("game.ai", "enemy %d lost its target", id)
+//! sludge.log.log({ level = "debug", target = "game.ai" }, "thinking")
+//! ```
+//!
+//! With a single argument, the message is logged under a default target. With two or
+//! more, the first is the target and the second the message, which is formatted with
+//! `string.format` if there are any arguments after it. Messages are logged with the
+//! chunk name and line of the script which logged them as their file and line.
+
 use {
     anyhow::Result,
-    log::{log, Level},
+    log::{Level, Record},
     rlua::prelude::*,
 };
 
+use crate::{
+    log_buffer::{LogBuffer, LogFilter},
+    SludgeLuaContextExt,
+};
+
+/// The target of messages logged without one.
+const DEFAULT_TARGET: &'static str = "unknown lua script";
+
+fn parse_level(level: &str) -> LuaResult<Level> {
+    match level {
+        l if l.eq_ignore_ascii_case("error") => Ok(Level::Error),
+        l if l.eq_ignore_ascii_case("warn") => Ok(Level::Warn),
+        l if l.eq_ignore_ascii_case("info") => Ok(Level::Info),
+        l if l.eq_ignore_ascii_case("debug") => Ok(Level::Debug),
+        l if l.eq_ignore_ascii_case("trace") => Ok(Level::Trace),
+        _ => Err(LuaError::FromLuaConversionError {
+            from: "string",
+            to: "log level",
+            message: Some(format!(
+                "expected one of 'error', 'warn', 'info', 'debug', or 'trace'; found '{}'",
+                level
+            )),
+        }),
+    }
+}
+
+/// The chunk name and line of the script which called into `sludge.log`, found by
+/// having Lua's `error` describe where it was called from so that the `debug` library
+/// isn't needed.
+fn caller_location(lua: LuaContext) -> Option<(String, Option<u32>)> {
+    let globals = lua.globals();
+    let pcall = globals.get::<_, LuaFunction>("pcall").ok()?;
+    let error = globals.get::<_, LuaFunction>("error").ok()?;
+    // Levels count from `error`: `pcall` is the first, the Rust function logging the
+    // message is the second, and the script which called it is the third.
+    let (_, location) = pcall.call::<_, (bool, String)>((error, "", 3)).ok()?;
+    let location = location.strip_suffix(": ")?;
+    match location.rfind(':') {
+        Some(i) => Some((location[..i].to_owned(), location[i + 1..].parse().ok())),
+        None if !location.is_empty() => Some((location.to_owned(), None)),
+        None => None,
+    }
+}
+
 pub fn log_message(
-    _lua: LuaContext,
+    lua: LuaContext,
     (level, target, message): (&str, Option<&str>, &str),
 ) -> LuaResult<()> {
-    let level = match level {
-        l if l.eq_ignore_ascii_case("error") => Level::Error,
-        l if l.eq_ignore_ascii_case("warn") => Level::Warn,
-        l if l.eq_ignore_ascii_case("info") => Level::Info,
-        l if l.eq_ignore_ascii_case("debug") => Level::Debug,
-        l if l.eq_ignore_ascii_case("trace") => Level::Trace,
-        _ => {
-            return Err(LuaError::FromLuaConversionError {
-                from: "string",
-                to: "log level",
-                message: Some(format!(
-                    "expected one of 'error', 'warn', 'info', 'debug', or 'trace'; found '{}'",
-                    level
-                )),
-            })
-        }
+    let level = parse_level(level)?;
+    if level > log::max_level() {
+        return Ok(());
+    }
+
+    let location = caller_location(lua);
+    let (file, line) = match &location {
+        Some((file, line)) => (Some(file.as_str()), *line),
+        None => (None, None),
     };
 
-    log!(target: target.unwrap_or("unknown lua script"), level, "{}", message);
+    log::logger().log(
+        &Record::builder()
+            .args(format_args!("{}", message))
+            .level(level)
+            .target(target.unwrap_or(DEFAULT_TARGET))
+            .file(file)
+            .line(line)
+            .build(),
+    );
 
     Ok(())
 }
@@ -56,53 +114,95 @@ pub fn log<'lua>(
     log_message(lua, (&level, target.as_deref(), message.to_str()?))
 }
 
+/// Split the arguments to one of the fixed level functions into a target, if there is
+/// one, and a message formatted with `string.format` if there are arguments to format.
+fn target_and_message<'lua>(
+    lua: LuaContext<'lua>,
+    (first, fmt, args): (String, Option<LuaString<'lua>>, LuaMultiValue<'lua>),
+) -> LuaResult<(Option<String>, String)> {
+    let fmt = match fmt {
+        Some(fmt) => fmt,
+        None => return Ok((None, first)),
+    };
+
+    if args.is_empty() {
+        return Ok((Some(first), fmt.to_str()?.to_owned()));
+    }
+
+    let message = lua
+        .globals()
+        .get::<_, LuaTable>("string")?
+        .get::<_, LuaFunction>("format")?
+        .call::<_, String>((fmt, args))?;
+    Ok((Some(first), message))
+}
+
+/// Log at a fixed level, with an optional target and format arguments.
+fn log_at<'lua>(
+    lua: LuaContext<'lua>,
+    level: &str,
+    args: (String, Option<LuaString<'lua>>, LuaMultiValue<'lua>),
+) -> LuaResult<()> {
+    let (target, message) = target_and_message(lua, args)?;
+    log_message(lua, (level, target.as_deref(), &message))
+}
+
 pub fn trace<'lua>(
     lua: LuaContext<'lua>,
-    (first, last): (String, Option<String>),
+    args: (String, Option<LuaString<'lua>>, LuaMultiValue<'lua>),
 ) -> LuaResult<()> {
-    if let Some(message) = last {
-        log_message(lua, ("trace", Some(&first), &message))
-    } else {
-        log_message(lua, ("trace", None, &first))
-    }
+    log_at(lua, "trace", args)
 }
 
 pub fn debug<'lua>(
     lua: LuaContext<'lua>,
-    (first, last): (String, Option<String>),
+    args: (String, Option<LuaString<'lua>>, LuaMultiValue<'lua>),
 ) -> LuaResult<()> {
-    if let Some(message) = last {
-        log_message(lua, ("debug", Some(&first), &message))
-    } else {
-        log_message(lua, ("debug", None, &first))
-    }
+    log_at(lua, "debug", args)
 }
 
-pub fn info<'lua>(lua: LuaContext<'lua>, (first, last): (String, Option<String>)) -> LuaResult<()> {
-    if let Some(message) = last {
-        log_message(lua, ("info", Some(&first), &message))
-    } else {
-        log_message(lua, ("info", None, &first))
-    }
+pub fn info<'lua>(
+    lua: LuaContext<'lua>,
+    args: (String, Option<LuaString<'lua>>, LuaMultiValue<'lua>),
+) -> LuaResult<()> {
+    log_at(lua, "info", args)
 }
 
-pub fn warn<'lua>(lua: LuaContext<'lua>, (first, last): (String, Option<String>)) -> LuaResult<()> {
-    if let Some(message) = last {
-        log_message(lua, ("warn", Some(&first), &message))
-    } else {
-        log_message(lua, ("warn", None, &first))
-    }
+pub fn warn<'lua>(
+    lua: LuaContext<'lua>,
+    args: (String, Option<LuaString<'lua>>, LuaMultiValue<'lua>),
+) -> LuaResult<()> {
+    log_at(lua, "warn", args)
 }
 
 pub fn error<'lua>(
     lua: LuaContext<'lua>,
-    (first, last): (String, Option<String>),
+    args: (String, Option<LuaString<'lua>>, LuaMultiValue<'lua>),
 ) -> LuaResult<()> {
-    if let Some(message) = last {
-        log_message(lua, ("error", Some(&first), &message))
-    } else {
-        log_message(lua, ("error", None, &first))
-    }
+    log_at(lua, "error", args)
+}
+
+/// `sludge.log.recent(n, level, target)`: the last `n` messages (all of them by
+/// default) in the [`LogBuffer`] at `level` or more severe, with targets starting with
+/// `target`, oldest first. Returns an empty list if there's no `LogBuffer`.
+pub fn recent<'lua>(
+    lua: LuaContext<'lua>,
+    (count, level, target): (Option<usize>, Option<String>, Option<String>),
+) -> LuaResult<LuaValue<'lua>> {
+    let buffer = match lua.fetch_one::<LogBuffer>() {
+        Ok(buffer) => buffer,
+        Err(_) => return lua.create_table()?.to_lua(lua),
+    };
+
+    let filter = LogFilter {
+        level: match level {
+            Some(level) => parse_level(&level)?.to_level_filter(),
+            None => log::LevelFilter::Trace,
+        },
+        target,
+    };
+    let entries = buffer.borrow().recent(&filter, count.unwrap_or(usize::MAX));
+    entries.to_lua(lua)
 }
 
 pub fn load<'lua>(lua: LuaContext<'lua>) -> Result<LuaValue<'lua>> {
@@ -113,6 +213,7 @@ pub fn load<'lua>(lua: LuaContext<'lua>) -> Result<LuaValue<'lua>> {
         ("info", lua.create_function(info)?),
         ("debug", lua.create_function(debug)?),
         ("trace", lua.create_function(trace)?),
+        ("recent", lua.create_function(recent)?),
     ])?;
 
     Ok(LuaValue::Table(table))
@@ -121,3 +222,51 @@ pub fn load<'lua>(lua: LuaContext<'lua>) -> Result<LuaValue<'lua>> {
 inventory::submit! {
     crate::api::Module::parse("sludge.log", load)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caller_location_finds_the_script() -> Result<()> {
+        let lua = rlua::Lua::new();
+        lua.context(|lua| -> Result<()> {
+            let locate = lua.create_function(|lua, ()| {
+                Ok(caller_location(lua).map(|(file, line)| (file, line.unwrap_or(0))))
+            })?;
+            lua.globals().set("locate", locate)?;
+
+            let (file, line) = lua
+                .load("local x = 1\nreturn locate()")
+                .set_name("=scripts/enemy.lua")?
+                .eval::<(String, u32)>()?;
+            assert_eq!(file, "scripts/enemy.lua");
+            assert_eq!(line, 2);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn messages_are_formatted_with_arguments() -> Result<()> {
+        let lua = rlua::Lua::new();
+        lua.context(|lua| -> Result<()> {
+            let split = lua.create_function(|lua, args| target_and_message(lua, args))?;
+            lua.globals().set("split", split)?;
+
+            let split =
+                |src: &str| -> Result<(Option<String>, String)> { Ok(lua.load(src).eval()?) };
+            assert_eq!(split("return split('hello')")?, (None, "hello".to_owned()));
+            assert_eq!(
+                split("return split('game.ai', '50%')")?,
+                (Some("game.ai".to_owned()), "50%".to_owned())
+            );
+            assert_eq!(
+                split("return split('game.ai', 'enemy %d at %.1f', 3, 0.5)")?,
+                (Some("game.ai".to_owned()), "enemy 3 at 0.5".to_owned())
+            );
+
+            Ok(())
+        })
+    }
+}
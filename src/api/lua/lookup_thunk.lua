@@ -1,6 +1,11 @@
 -- Defer looking up an entity in a persisted entity ID table to preserve uniqueness.
-return function(entity_id, world_table)
+-- Strong handles come back strong; handles to entities which are gone come back `nil`.
+return function(entity_id, world_table, strong)
     return function()
-        return world_table[entity_id]
+        local entity = world_table[entity_id]
+        if entity and strong then
+            return entity:strong()
+        end
+        return entity
     end
-end
\ No newline at end of file
+end
@@ -7,8 +7,13 @@ return function(orig_world_table)
 
     return function()
         local spawn = sludge.spawn
+        local restore = sludge.persist.restore
         for _,v in ipairs(world_table) do
-            world_table[v.id] = (v.deserialize or spawn)(v.components)
+            local entity = (v.deserialize or spawn)(v.components)
+            if v.custom then
+                restore(entity, v.custom)
+            end
+            world_table[v.id] = entity
         end
 
         return world_table
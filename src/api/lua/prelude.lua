@@ -15,13 +15,55 @@ end
 
 yield = sludge.thread.yield
 local status = sludge.thread.status
+local running = sludge.thread.running
+local locals_of = sludge.thread.locals_of
+local name_of = sludge.thread.name_of
+local set_name_of = sludge.thread.set_name_of
 
+-- Task-local storage, private to the currently running thread.
+function sludge.thread.set_local(key, value)
+    locals_of(running())[key] = value
+end
+
+function sludge.thread.get_local(key)
+    return locals_of(running())[key]
+end
+
+-- Debug names show up in scheduler error logs and snapshots. `thread` defaults to the
+-- currently running thread.
+function sludge.thread.set_name(name, thread)
+    set_name_of(thread or running(), name)
+end
+
+function sludge.thread.name(thread)
+    return name_of(thread or running())
+end
+
+-- Sleep until the next tick. Unlike `yield(1)`, this says what it means, and it reads
+-- differently from waiting on an event.
+function sludge.thread.yield_frame()
+    return yield(1)
+end
+
+yield_frame = sludge.thread.yield_frame
+
+-- Sleep until `predicate` returns a truthy value. The scheduler checks the predicates
+-- of all waiting threads once per tick, before running anything, so a waiting thread
+-- isn't resumed until its condition actually holds. Yielding a function alongside
+-- event names, as in `yield(predicate, "event")`, wakes on whichever comes first.
 function sludge.thread.wait_until(predicate)
-    while not predicate() do
-        yield(1)
+    if not predicate() then
+        yield(predicate)
     end
 end
 
+-- Sleep until a chunked task has finished, then return its result. Raises an error if
+-- the task failed or was cancelled.
+function sludge.task.await(promise)
+    sludge.thread.wait_until(function() return promise:is_done() end)
+    return promise:result()
+end
+
 function sludge.thread.join(...)
     repeat
         for i = 1, select("#", ...) do
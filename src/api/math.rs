@@ -1,4 +1,33 @@
-use {anyhow::Result, nalgebra as na, rlua::prelude::*};
+//! `sludge.math`: vectors, isometries, boxes and transforms for Lua.
+//!
+//! ```lua
+//! local smath = sludge.math
+//! local v = smath.Vec2(3, 4)
+//! print(v:len(), (v * 2).x, v + smath.Vec2(1, 1))
+//!
+//! -- Isometries convert to and from the same tables as `Position`.
+//! local pos = smath.Isometry2(entity.Position:to_table())
+//! local muzzle = pos * smath.Vec2(8, 0)
+//!
+//! local bounds = smath.Box2(0, 0, 320, 240)
+//! if not bounds:contains(muzzle) then ... end
+//! ```
+//!
+//! Curves are built with `Bezier(p0, p1, p2, p3)` or `CatmullRom(points, closed)`; see
+//! [`curve`](crate::math::curve) for how they're followed. Their methods which take a
+//! distance or a fraction of their length raise an error if it isn't finite.
+//! `ease(name, t)` applies one of the [`Easing`]s by name.
+//!
+//! Functions which take a point as their last argument accept a `Vec2`, a `{ x, y }`
+//! table or two numbers, through [`Coords`].
+
+use {
+    crate::math::{curve::Curve, tween::Easing, Box2, Isometry2, Point2, UnitComplex, Vector2},
+    anyhow::Result,
+    nalgebra as na,
+    rlua::prelude::*,
+    std::sync::Arc,
+};
 
 #[derive(Debug, Clone, Copy)]
 pub struct Transform(pub na::Transform2<f32>);
@@ -20,22 +49,19 @@ impl LuaUserData for Transform {
             Ok(this.0.try_inverse().map(Transform))
         });
 
-        methods.add_method(
-            "inverse_transform_point",
-            |ctx, this, (x, y): (f32, f32)| {
-                let maybe_proj: Option<na::Projective2<f32>> = na::try_convert_ref(&this.0);
-                match maybe_proj {
-                    Some(proj) => {
-                        let p = proj.inverse_transform_point(&na::Point2::new(x, y));
-                        Ok((Some(p.x), LuaValue::Number(p.y as LuaNumber)))
-                    }
-                    None => Ok((
-                        None,
-                        LuaValue::String(ctx.create_string("non-invertible matrix")?),
-                    )),
+        methods.add_method("inverse_transform_point", |ctx, this, coords: Coords| {
+            let maybe_proj: Option<na::Projective2<f32>> = na::try_convert_ref(&this.0);
+            match maybe_proj {
+                Some(proj) => {
+                    let p = proj.inverse_transform_point(&coords.point());
+                    Ok((Some(p.x), LuaValue::Number(p.y as LuaNumber)))
                 }
-            },
-        );
+                None => Ok((
+                    None,
+                    LuaValue::String(ctx.create_string("non-invertible matrix")?),
+                )),
+            }
+        });
 
         methods.add_method("is_affine_transform", |_ctx, this, ()| {
             Ok(na::is_convertible::<_, na::Affine2<f32>>(&this.0))
@@ -109,13 +135,13 @@ impl LuaUserData for Transform {
             Ok(())
         });
 
-        methods.add_method_mut("transform_point", |_ctx, this, (x, y): (f32, f32)| {
-            let p = this.0.transform_point(&na::Point2::new(x, y));
+        methods.add_method_mut("transform_point", |_ctx, this, coords: Coords| {
+            let p = this.0.transform_point(&coords.point());
             Ok((p.x, p.y))
         });
 
-        methods.add_method_mut("translate", |_ctx, this, (x, y): (f32, f32)| {
-            this.0 *= na::Translation2::new(x, y);
+        methods.add_method_mut("translate", |_ctx, this, Coords(v)| {
+            this.0 *= na::Translation2::from(v);
             Ok(())
         });
 
@@ -126,6 +152,579 @@ impl LuaUserData for Transform {
     }
 }
 
+fn number_field(table: &LuaTable, key: &str) -> LuaResult<f32> {
+    Ok(table.get::<_, Option<f32>>(key)?.unwrap_or(0.))
+}
+
+/// A 2D vector, exposed to Lua as `sludge.math.Vec2`.
+///
+/// Converts from a `Vec2` or a table with `x` and `y` fields.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LuaVec2(pub Vector2<f32>);
+
+impl LuaVec2 {
+    pub fn new(x: f32, y: f32) -> Self {
+        Self(Vector2::new(x, y))
+    }
+}
+
+impl From<Vector2<f32>> for LuaVec2 {
+    fn from(v: Vector2<f32>) -> Self {
+        Self(v)
+    }
+}
+
+impl From<Point2<f32>> for LuaVec2 {
+    fn from(p: Point2<f32>) -> Self {
+        Self(p.coords)
+    }
+}
+
+impl<'lua> FromLua<'lua> for LuaVec2 {
+    fn from_lua(lua_value: LuaValue<'lua>, _lua: LuaContext<'lua>) -> LuaResult<Self> {
+        match lua_value {
+            LuaValue::UserData(ud) => Ok(*ud.borrow::<Self>()?),
+            LuaValue::Table(t) => Ok(Self::new(number_field(&t, "x")?, number_field(&t, "y")?)),
+            other => Err(LuaError::FromLuaConversionError {
+                from: other.type_name(),
+                to: "Vec2",
+                message: Some("expected a Vec2 or a table with x and y fields".to_owned()),
+            }),
+        }
+    }
+}
+
+impl LuaUserData for LuaVec2 {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_method(LuaMetaMethod::Index, |lua, this, key: LuaString| match key
+            .to_str()?
+        {
+            "x" => this.0.x.to_lua(lua),
+            "y" => this.0.y.to_lua(lua),
+            _ => Ok(LuaValue::Nil),
+        });
+
+        methods.add_meta_method_mut(
+            LuaMetaMethod::NewIndex,
+            |_lua, this, (key, value): (LuaString, f32)| {
+                match key.to_str()? {
+                    "x" => this.0.x = value,
+                    "y" => this.0.y = value,
+                    other => {
+                        return Err(LuaError::external(anyhow::anyhow!(
+                            "no such field {} for Vec2",
+                            other
+                        )))
+                    }
+                }
+                Ok(())
+            },
+        );
+
+        methods.add_meta_function(LuaMetaMethod::Add, |_lua, (a, b): (LuaVec2, LuaVec2)| {
+            Ok(LuaVec2(a.0 + b.0))
+        });
+
+        methods.add_meta_function(LuaMetaMethod::Sub, |_lua, (a, b): (LuaVec2, LuaVec2)| {
+            Ok(LuaVec2(a.0 - b.0))
+        });
+
+        // Either operand can be the scalar.
+        methods.add_meta_function(
+            LuaMetaMethod::Mul,
+            |lua, (a, b): (LuaValue, LuaValue)| match (a, b) {
+                (LuaValue::Number(n), v) | (v, LuaValue::Number(n)) => {
+                    Ok(LuaVec2(LuaVec2::from_lua(v, lua)?.0 * n as f32))
+                }
+                (LuaValue::Integer(n), v) | (v, LuaValue::Integer(n)) => {
+                    Ok(LuaVec2(LuaVec2::from_lua(v, lua)?.0 * n as f32))
+                }
+                (a, b) => Ok(LuaVec2(
+                    LuaVec2::from_lua(a, lua)?
+                        .0
+                        .component_mul(&LuaVec2::from_lua(b, lua)?.0),
+                )),
+            },
+        );
+
+        methods.add_meta_function(LuaMetaMethod::Div, |_lua, (a, n): (LuaVec2, f32)| {
+            Ok(LuaVec2(a.0 / n))
+        });
+
+        methods.add_meta_method(LuaMetaMethod::Unm, |_lua, this, ()| Ok(LuaVec2(-this.0)));
+
+        methods.add_meta_function(LuaMetaMethod::Eq, |_lua, (a, b): (LuaVec2, LuaVec2)| {
+            Ok(a == b)
+        });
+
+        methods.add_meta_method(LuaMetaMethod::ToString, |_lua, this, ()| {
+            Ok(format!("Vec2({}, {})", this.0.x, this.0.y))
+        });
+
+        methods.add_method("unpack", |_lua, this, ()| Ok((this.0.x, this.0.y)));
+        methods.add_method("clone", |_lua, &this, ()| Ok(this));
+        methods.add_method("len", |_lua, this, ()| Ok(this.0.norm()));
+        methods.add_method("len2", |_lua, this, ()| Ok(this.0.norm_squared()));
+        methods.add_method("angle", |_lua, this, ()| Ok(this.0.y.atan2(this.0.x)));
+
+        methods.add_method("normalized", |_lua, this, ()| {
+            Ok(LuaVec2(this.0.try_normalize(0.).unwrap_or_else(na::zero)))
+        });
+
+        methods.add_method("dot", |_lua, this, other: LuaVec2| Ok(this.0.dot(&other.0)));
+
+        methods.add_method("cross", |_lua, this, other: LuaVec2| {
+            Ok(this.0.perp(&other.0))
+        });
+
+        methods.add_method("perpendicular", |_lua, this, ()| {
+            Ok(LuaVec2::new(-this.0.y, this.0.x))
+        });
+
+        methods.add_method("rotated", |_lua, this, angle: f32| {
+            Ok(LuaVec2(UnitComplex::new(angle) * this.0))
+        });
+
+        methods.add_method("dist", |_lua, this, other: LuaVec2| {
+            Ok((this.0 - other.0).norm())
+        });
+
+        methods.add_method("lerp", |_lua, this, (other, t): (LuaVec2, f32)| {
+            Ok(LuaVec2(this.0.lerp(&other.0, t)))
+        });
+
+        methods.add_method("to_table", |lua, this, ()| {
+            lua.create_table_from(vec![("x", this.0.x), ("y", this.0.y)])
+        });
+    }
+}
+
+/// A rigid transformation, exposed to Lua as `sludge.math.Isometry2`.
+///
+/// Converts from an `Isometry2` or a table with `x`, `y` and `angle` fields, the same
+/// tables the `Position` component converts to and from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LuaIsometry2(pub Isometry2<f32>);
+
+impl LuaIsometry2 {
+    pub fn new(x: f32, y: f32, angle: f32) -> Self {
+        Self(Isometry2::new(Vector2::new(x, y), angle))
+    }
+}
+
+impl From<Isometry2<f32>> for LuaIsometry2 {
+    fn from(iso: Isometry2<f32>) -> Self {
+        Self(iso)
+    }
+}
+
+impl<'lua> FromLua<'lua> for LuaIsometry2 {
+    fn from_lua(lua_value: LuaValue<'lua>, _lua: LuaContext<'lua>) -> LuaResult<Self> {
+        match lua_value {
+            LuaValue::UserData(ud) => Ok(*ud.borrow::<Self>()?),
+            LuaValue::Table(t) => Ok(Self::new(
+                number_field(&t, "x")?,
+                number_field(&t, "y")?,
+                number_field(&t, "angle")?,
+            )),
+            other => Err(LuaError::FromLuaConversionError {
+                from: other.type_name(),
+                to: "Isometry2",
+                message: Some(
+                    "expected an Isometry2 or a table with x, y and angle fields".to_owned(),
+                ),
+            }),
+        }
+    }
+}
+
+impl LuaUserData for LuaIsometry2 {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_method(LuaMetaMethod::Index, |lua, this, key: LuaString| match key
+            .to_str()?
+        {
+            "x" => this.0.translation.vector.x.to_lua(lua),
+            "y" => this.0.translation.vector.y.to_lua(lua),
+            "angle" => this.0.rotation.angle().to_lua(lua),
+            _ => Ok(LuaValue::Nil),
+        });
+
+        methods.add_meta_method_mut(
+            LuaMetaMethod::NewIndex,
+            |_lua, this, (key, value): (LuaString, f32)| {
+                match key.to_str()? {
+                    "x" => this.0.translation.vector.x = value,
+                    "y" => this.0.translation.vector.y = value,
+                    "angle" => this.0.rotation = UnitComplex::new(value),
+                    other => {
+                        return Err(LuaError::external(anyhow::anyhow!(
+                            "no such field {} for Isometry2",
+                            other
+                        )))
+                    }
+                }
+                Ok(())
+            },
+        );
+
+        // Composes with another isometry, or transforms a point.
+        methods.add_meta_function(
+            LuaMetaMethod::Mul,
+            |lua, (a, b): (LuaIsometry2, LuaValue)| {
+                if let LuaValue::UserData(ud) = &b {
+                    if let Ok(v) = ud.borrow::<LuaVec2>() {
+                        return LuaVec2::from(a.0 * Point2::from(v.0)).to_lua(lua);
+                    }
+                }
+                LuaIsometry2(a.0 * LuaIsometry2::from_lua(b, lua)?.0).to_lua(lua)
+            },
+        );
+
+        methods.add_meta_function(
+            LuaMetaMethod::Eq,
+            |_lua, (a, b): (LuaIsometry2, LuaIsometry2)| Ok(a == b),
+        );
+
+        methods.add_meta_method(LuaMetaMethod::ToString, |_lua, this, ()| {
+            let v = this.0.translation.vector;
+            Ok(format!(
+                "Isometry2({}, {}, {})",
+                v.x,
+                v.y,
+                this.0.rotation.angle()
+            ))
+        });
+
+        methods.add_method("unpack", |_lua, this, ()| {
+            let v = this.0.translation.vector;
+            Ok((v.x, v.y, this.0.rotation.angle()))
+        });
+
+        methods.add_method("clone", |_lua, &this, ()| Ok(this));
+
+        methods.add_method("translation", |_lua, this, ()| {
+            Ok(LuaVec2(this.0.translation.vector))
+        });
+
+        methods.add_method("inverse", |_lua, this, ()| {
+            Ok(LuaIsometry2(this.0.inverse()))
+        });
+
+        methods.add_method("transform_point", |_lua, this, Coords(p)| {
+            Ok(LuaVec2::from(this.0 * Point2::from(p)))
+        });
+
+        methods.add_method("inverse_transform_point", |_lua, this, Coords(p)| {
+            Ok(LuaVec2::from(
+                this.0.inverse_transform_point(&Point2::from(p)),
+            ))
+        });
+
+        methods.add_method("transform_vector", |_lua, this, Coords(v)| {
+            Ok(LuaVec2(this.0 * v))
+        });
+
+        methods.add_method("lerp", |_lua, this, (other, t): (LuaIsometry2, f32)| {
+            Ok(LuaIsometry2(this.0.lerp_slerp(&other.0, t)))
+        });
+
+        methods.add_method("to_table", |lua, this, ()| {
+            let v = this.0.translation.vector;
+            lua.create_table_from(vec![
+                ("x", v.x),
+                ("y", v.y),
+                ("angle", this.0.rotation.angle()),
+            ])
+        });
+    }
+}
+
+/// An axis-aligned box, exposed to Lua as `sludge.math.Box2`.
+///
+/// Converts from a `Box2` or a table with `x`, `y`, `w` and `h` fields.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LuaBox2(pub Box2<f32>);
+
+impl From<Box2<f32>> for LuaBox2 {
+    fn from(b: Box2<f32>) -> Self {
+        Self(b)
+    }
+}
+
+impl<'lua> FromLua<'lua> for LuaBox2 {
+    fn from_lua(lua_value: LuaValue<'lua>, lua: LuaContext<'lua>) -> LuaResult<Self> {
+        match lua_value {
+            LuaValue::UserData(ud) => Ok(*ud.borrow::<Self>()?),
+            other => Ok(Self(Box2::from_lua(other, lua)?)),
+        }
+    }
+}
+
+impl LuaUserData for LuaBox2 {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_method(LuaMetaMethod::Index, |lua, this, key: LuaString| {
+            let extents = this.0.extents();
+            match key.to_str()? {
+                "x" => this.0.mins.x.to_lua(lua),
+                "y" => this.0.mins.y.to_lua(lua),
+                "w" => extents.x.to_lua(lua),
+                "h" => extents.y.to_lua(lua),
+                _ => Ok(LuaValue::Nil),
+            }
+        });
+
+        methods.add_meta_function(LuaMetaMethod::Eq, |_lua, (a, b): (LuaBox2, LuaBox2)| {
+            Ok(a == b)
+        });
+
+        methods.add_meta_method(LuaMetaMethod::ToString, |_lua, this, ()| {
+            let extents = this.0.extents();
+            Ok(format!(
+                "Box2({}, {}, {}, {})",
+                this.0.mins.x, this.0.mins.y, extents.x, extents.y
+            ))
+        });
+
+        methods.add_method("unpack", |_lua, this, ()| {
+            let extents = this.0.extents();
+            Ok((this.0.mins.x, this.0.mins.y, extents.x, extents.y))
+        });
+
+        methods.add_method("clone", |_lua, &this, ()| Ok(this));
+        methods.add_method("mins", |_lua, this, ()| Ok(LuaVec2::from(this.0.mins)));
+        methods.add_method("maxs", |_lua, this, ()| Ok(LuaVec2::from(this.0.maxs)));
+        methods.add_method("center", |_lua, this, ()| {
+            Ok(LuaVec2::from(this.0.center()))
+        });
+        methods.add_method("extents", |_lua, this, ()| Ok(LuaVec2(this.0.extents())));
+
+        // Accepts either another box or a point.
+        methods.add_method("contains", |lua, this, other: LuaValue| {
+            let is_box = match &other {
+                LuaValue::UserData(ud) => ud.borrow::<LuaBox2>().is_ok(),
+                LuaValue::Table(t) => t.contains_key("w")?,
+                _ => false,
+            };
+
+            if is_box {
+                Ok(this.0.contains(&LuaBox2::from_lua(other, lua)?.0))
+            } else {
+                let p = LuaVec2::from_lua(other, lua)?.0;
+                Ok(p.x >= this.0.mins.x
+                    && p.y >= this.0.mins.y
+                    && p.x <= this.0.maxs.x
+                    && p.y <= this.0.maxs.y)
+            }
+        });
+
+        methods.add_method("intersects", |_lua, this, other: LuaBox2| {
+            Ok(this.0.intersects(&other.0))
+        });
+
+        methods.add_method("merged", |_lua, this, other: LuaBox2| {
+            Ok(LuaBox2(this.0.merged(&other.0)))
+        });
+
+        methods.add_method("loosened", |_lua, this, margin: f32| {
+            Ok(LuaBox2(this.0.loosened(margin)))
+        });
+
+        methods.add_method("to_table", |lua, this, ()| this.0.to_lua(lua));
+    }
+}
+
+/// A [`Curve`], exposed to Lua through `sludge.math.Bezier` and
+/// `sludge.math.CatmullRom`.
+///
+/// Converts from a curve or a table describing its shape, as serialized by
+/// [`CurveShape`](crate::math::curve::CurveShape). Curves are immutable, and cheap to
+/// clone since their points are shared.
+#[derive(Debug, Clone)]
+pub struct LuaCurve(pub Arc<Curve>);
+
+impl From<Curve> for LuaCurve {
+    fn from(curve: Curve) -> Self {
+        Self(Arc::new(curve))
+    }
+}
+
+impl<'lua> FromLua<'lua> for LuaCurve {
+    fn from_lua(lua_value: LuaValue<'lua>, _lua: LuaContext<'lua>) -> LuaResult<Self> {
+        match lua_value {
+            LuaValue::UserData(ud) => Ok(ud.borrow::<Self>()?.clone()),
+            other => Ok(Self::from(rlua_serde::from_value::<Curve>(other)?)),
+        }
+    }
+}
+
+impl LuaUserData for LuaCurve {
+    fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("point", |_lua, this, t: f32| {
+            Ok(LuaVec2::from(this.0.point(t)))
+        });
+        methods.add_method("tangent", |_lua, this, t: f32| {
+            Ok(LuaVec2(this.0.tangent(t)))
+        });
+        methods.add_method("length", |_lua, this, ()| Ok(this.0.length()));
+        methods.add_method("point_at_distance", |_lua, this, distance: f32| {
+            let distance = finite("distance", distance)?;
+            Ok(LuaVec2::from(this.0.point_at_distance(distance)))
+        });
+        methods.add_method("point_uniform", |_lua, this, u: f32| {
+            let u = finite("fraction", u)?;
+            Ok(LuaVec2::from(this.0.point_uniform(u)))
+        });
+        methods.add_method("tangent_uniform", |_lua, this, u: f32| {
+            let u = finite("fraction", u)?;
+            Ok(LuaVec2(this.0.tangent_uniform(u)))
+        });
+        methods.add_method("resample", |_lua, this, count: usize| {
+            Ok(this
+                .0
+                .resample(count)
+                .into_iter()
+                .map(LuaVec2::from)
+                .collect::<Vec<_>>())
+        });
+        methods.add_method("to_table", |lua, this, ()| {
+            rlua_serde::to_value(lua, this.0.shape())
+        });
+    }
+}
+
+fn finite(what: &str, x: f32) -> LuaResult<f32> {
+    if x.is_finite() {
+        Ok(x)
+    } else {
+        Err(LuaError::external(anyhow::anyhow!(
+            "expected a finite {} along the curve, got {}",
+            what,
+            x
+        )))
+    }
+}
+
+/// A point or vector passed as the last argument of a function: either a `Vec2`, a
+/// table with `x` and `y` fields, or two numbers. Use it in place of an `(f32, f32)`
+/// at the end of a function's arguments.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coords(pub Vector2<f32>);
+
+impl Coords {
+    pub fn point(self) -> Point2<f32> {
+        Point2::from(self.0)
+    }
+}
+
+impl<'lua> FromLuaMulti<'lua> for Coords {
+    fn from_lua_multi(values: LuaMultiValue<'lua>, lua: LuaContext<'lua>) -> LuaResult<Self> {
+        let mut values = values.into_iter();
+        match values.next().unwrap_or(LuaValue::Nil) {
+            v @ LuaValue::UserData(_) | v @ LuaValue::Table(_) => {
+                Ok(Coords(LuaVec2::from_lua(v, lua)?.0))
+            }
+            x => {
+                let x = f32::from_lua(x, lua)?;
+                let y = f32::from_lua(values.next().unwrap_or(LuaValue::Nil), lua)?;
+                Ok(Coords(Vector2::new(x, y)))
+            }
+        }
+    }
+}
+
+pub fn new_vec2<'lua>(
+    ctx: LuaContext<'lua>,
+    (x, y): (Option<LuaValue<'lua>>, Option<f32>),
+) -> LuaResult<LuaVec2> {
+    match (x, y) {
+        (None, _) => Ok(LuaVec2::new(0., 0.)),
+        (Some(LuaValue::Number(x)), y) => Ok(LuaVec2::new(x as f32, y.unwrap_or(0.))),
+        (Some(LuaValue::Integer(x)), y) => Ok(LuaVec2::new(x as f32, y.unwrap_or(0.))),
+        (Some(v), _) => LuaVec2::from_lua(v, ctx),
+    }
+}
+
+pub fn vec2_from_angle(_ctx: LuaContext, (angle, len): (f32, Option<f32>)) -> LuaResult<LuaVec2> {
+    let len = len.unwrap_or(1.);
+    Ok(LuaVec2::new(angle.cos() * len, angle.sin() * len))
+}
+
+pub fn new_isometry2<'lua>(
+    ctx: LuaContext<'lua>,
+    (x, y, angle): (Option<LuaValue<'lua>>, Option<f32>, Option<f32>),
+) -> LuaResult<LuaIsometry2> {
+    match x {
+        None => Ok(LuaIsometry2(Isometry2::identity())),
+        Some(LuaValue::Number(x)) => Ok(LuaIsometry2::new(
+            x as f32,
+            y.unwrap_or(0.),
+            angle.unwrap_or(0.),
+        )),
+        Some(LuaValue::Integer(x)) => Ok(LuaIsometry2::new(
+            x as f32,
+            y.unwrap_or(0.),
+            angle.unwrap_or(0.),
+        )),
+        Some(v) => LuaIsometry2::from_lua(v, ctx),
+    }
+}
+
+pub fn new_box2<'lua>(
+    ctx: LuaContext<'lua>,
+    (x, y, w, h): (LuaValue<'lua>, Option<f32>, Option<f32>, Option<f32>),
+) -> LuaResult<LuaBox2> {
+    match x {
+        LuaValue::Number(_) | LuaValue::Integer(_) => Ok(LuaBox2(Box2::new(
+            f32::from_lua(x, ctx)?,
+            y.unwrap_or(0.),
+            w.unwrap_or(0.),
+            h.unwrap_or(0.),
+        ))),
+        v => LuaBox2::from_lua(v, ctx),
+    }
+}
+
+pub fn box2_from_corners(_ctx: LuaContext, (mins, maxs): (LuaVec2, LuaVec2)) -> LuaResult<LuaBox2> {
+    Ok(LuaBox2(Box2::from_corners(
+        Point2::from(mins.0),
+        Point2::from(maxs.0),
+    )))
+}
+
+pub fn new_bezier(
+    _ctx: LuaContext,
+    (p0, p1, p2, p3): (LuaVec2, LuaVec2, LuaVec2, LuaVec2),
+) -> LuaResult<LuaCurve> {
+    Ok(LuaCurve::from(Curve::bezier(
+        Point2::from(p0.0),
+        Point2::from(p1.0),
+        Point2::from(p2.0),
+        Point2::from(p3.0),
+    )))
+}
+
+pub fn new_catmull_rom(
+    _ctx: LuaContext,
+    (points, closed): (Vec<LuaVec2>, Option<bool>),
+) -> LuaResult<LuaCurve> {
+    if points.len() < 2 {
+        return Err(LuaError::external(anyhow::anyhow!(
+            "a Catmull-Rom spline needs at least two points"
+        )));
+    }
+
+    let points = points.into_iter().map(|p| Point2::from(p.0)).collect();
+    Ok(LuaCurve::from(Curve::catmull_rom(
+        points,
+        closed.unwrap_or(false),
+    )))
+}
+
+pub fn ease(_ctx: LuaContext, (easing, t): (LuaString, f32)) -> LuaResult<f32> {
+    let easing = easing.to_str()?.parse::<Easing>().to_lua_err()?;
+    Ok(easing.apply(t))
+}
+
 pub fn new_transform(_ctx: LuaContext, _: ()) -> LuaResult<Transform> {
     Ok(Transform(na::Transform2::identity()))
 }
@@ -133,6 +732,14 @@ pub fn new_transform(_ctx: LuaContext, _: ()) -> LuaResult<Transform> {
 pub fn load<'lua>(lua: LuaContext<'lua>) -> Result<LuaValue<'lua>> {
     let table = lua.create_table_from(vec![
         ("Transform", lua.create_function(new_transform)?),
+        ("Vec2", lua.create_function(new_vec2)?),
+        ("vec2_from_angle", lua.create_function(vec2_from_angle)?),
+        ("Isometry2", lua.create_function(new_isometry2)?),
+        ("Box2", lua.create_function(new_box2)?),
+        ("box2_from_corners", lua.create_function(box2_from_corners)?),
+        ("Bezier", lua.create_function(new_bezier)?),
+        ("CatmullRom", lua.create_function(new_catmull_rom)?),
+        ("ease", lua.create_function(ease)?),
         ("sinh", lua.create_function(|_lua, f: f32| Ok(f.sinh()))?),
         ("cosh", lua.create_function(|_lua, f: f32| Ok(f.cosh()))?),
         ("tanh", lua.create_function(|_lua, f: f32| Ok(f.tanh()))?),
@@ -144,3 +751,107 @@ pub fn load<'lua>(lua: LuaContext<'lua>) -> Result<LuaValue<'lua>> {
 inventory::submit! {
     crate::api::Module::parse("sludge.math", load)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(source: &str) {
+        let lua = Lua::new();
+        lua.context(|lua| {
+            lua.globals().set("smath", load(lua).unwrap()).unwrap();
+            lua.load(source).exec().unwrap();
+        });
+    }
+
+    #[test]
+    fn vectors_do_arithmetic() {
+        run(r#"
+            local Vec2 = smath.Vec2
+            local v = Vec2(3, 4)
+            assert(v:len() == 5 and v:len2() == 25)
+            assert(v + Vec2(1, 1) == Vec2(4, 5))
+            assert(v - { x = 1, y = 1 } == Vec2(2, 3))
+            assert(v * 2 == Vec2(6, 8) and 2 * v == Vec2(6, 8))
+            assert(v * Vec2(2, 0.5) == Vec2(6, 2))
+            assert(v / 2 == Vec2(1.5, 2) and -v == Vec2(-3, -4))
+            assert(v:dot(Vec2(1, 0)) == 3 and Vec2(1, 0):cross(Vec2(0, 1)) == 1)
+            assert(math.abs(v:normalized():len() - 1) < 1e-6)
+            assert(Vec2() == Vec2(0, 0) and Vec2({ x = 1, y = 2 }) == Vec2(1, 2))
+            assert(tostring(Vec2(1, 2)) == "Vec2(1, 2)")
+
+            local w = v:clone()
+            w.x = 10
+            assert(v.x == 3 and w.x == 10)
+            local x, y = w:unpack()
+            assert(x == 10 and y == 4)
+            local t = w:to_table()
+            assert(t.x == 10 and t.y == 4)
+        "#);
+    }
+
+    #[test]
+    fn isometries_compose_and_transform_points() {
+        run(r#"
+            local Vec2, Isometry2 = smath.Vec2, smath.Isometry2
+            local iso = Isometry2(10, 0, math.pi / 2)
+            local p = iso * Vec2(1, 0)
+            assert(math.abs(p.x - 10) < 1e-5 and math.abs(p.y - 1) < 1e-5)
+
+            local back = iso:inverse_transform_point(p)
+            assert(math.abs(back.x - 1) < 1e-5 and math.abs(back.y) < 1e-5)
+
+            local composed = iso * Isometry2(0, 0, math.pi / 2)
+            assert(math.abs(math.abs(composed.angle) - math.pi) < 1e-5)
+            assert(composed.x == 10)
+
+            local from_table = Isometry2({ x = 1, y = 2, angle = 0.5 })
+            local t = from_table:to_table()
+            assert(t.x == 1 and t.y == 2 and math.abs(t.angle - 0.5) < 1e-6)
+            assert(Isometry2() == Isometry2(0, 0, 0))
+        "#);
+    }
+
+    #[test]
+    fn boxes_test_containment() {
+        run(r#"
+            local Vec2, Box2 = smath.Vec2, smath.Box2
+            local bounds = Box2(0, 0, 320, 240)
+            assert(bounds.w == 320 and bounds.h == 240)
+            assert(bounds:contains(Vec2(10, 10)))
+            assert(not bounds:contains({ x = -1, y = 10 }))
+            assert(bounds:contains(Box2(10, 10, 20, 20)))
+            assert(bounds:contains({ x = 10, y = 10, w = 20, h = 20 }))
+            assert(not bounds:contains(Box2(310, 10, 20, 20)))
+            assert(bounds:intersects(Box2(310, 10, 20, 20)))
+            assert(bounds:center() == Vec2(160, 120))
+
+            local merged = bounds:merged(Box2(-10, -10, 5, 5))
+            assert(merged == smath.box2_from_corners(Vec2(-10, -10), Vec2(320, 240)))
+            local x, y, w, h = bounds:loosened(1):unpack()
+            assert(x == -1 and y == -1 and w == 322 and h == 242)
+        "#);
+    }
+
+    #[test]
+    fn coords_accept_vectors_tables_and_numbers() {
+        let lua = Lua::new();
+        lua.context(|lua| {
+            lua.globals().set("smath", load(lua).unwrap()).unwrap();
+            let f = lua
+                .create_function(|_lua, (scale, Coords(v)): (f32, Coords)| Ok(v.x * scale + v.y))
+                .unwrap();
+            lua.globals().set("f", f).unwrap();
+            lua.load(
+                r#"
+                assert(f(10, smath.Vec2(1, 2)) == 12)
+                assert(f(10, { x = 1, y = 2 }) == 12)
+                assert(f(10, 1, 2) == 12)
+                assert(not pcall(f, 10, 1))
+                "#,
+            )
+            .exec()
+            .unwrap();
+        });
+    }
+}
@@ -0,0 +1,304 @@
+//! Module loading for Lua scripts, through sludge's [`Filesystem`].
+//!
+//! `require` resolves modules against the colon-separated templates in
+//! `sludge.package.path`, and keeps two caches in the Lua registry:
+//! - `sludge.package.modules`, mapping module names to the values their chunks
+//!   returned when first loaded;
+//! - `sludge.package.chunks`, mapping resolved filesystem paths to compiled
+//!   chunks, so that a module which has had its value invalidated can be re-run
+//!   without being recompiled.
+//!
+//! Both caches can be cleared per-file with [`invalidate`] (for use by anything
+//! watching files for hot reloading) or all at once with [`invalidate_all`].
+//!
+//! # Precompiled bytecode
+//!
+//! When `sludge.package.precompiled` is set to `true`, every candidate path ending
+//! in `.lua` is first searched for with a `.luac` extension instead, and files which
+//! start with the Lua bytecode signature are loaded as binary chunks no matter what
+//! they're named. Otherwise, only source is loaded; bytecode isn't verified by Lua, so
+//! loading it from an untrusted file is a way out of any sandbox. Spaces with a
+//! [`Sandbox`](crate::sandbox::Sandbox) never load bytecode, even with
+//! `sludge.package.precompiled` set. [`precompile`] and [`write_precompiled`] can be
+//! used to generate precompiled files ahead of time for release builds.
+
+use crate::{filesystem::Filesystem, Resources, SludgeResultExt};
+use {
+    anyhow::*,
+    rlua::prelude::*,
+    std::io::{Read, Write},
+};
+
+pub const PACKAGE_REGISTRY_KEY: &'static str = "sludge.package";
+pub const RAW_LOAD_REGISTRY_KEY: &'static str = "sludge.package.raw_load";
+pub const RAW_DUMP_REGISTRY_KEY: &'static str = "sludge.package.raw_dump";
+/// Set in the registry to refuse binary chunks whatever `sludge.package.precompiled` is
+/// set to.
+pub const NO_BYTECODE_REGISTRY_KEY: &'static str = "sludge.package.no_bytecode";
+pub const DEFAULT_PACKAGE_PATH: &'static str =
+    "/?.lua:/?/init.lua:/scripts/?.lua:/scripts/?/init.lua";
+
+/// The first bytes of any Lua 5.x binary chunk.
+const BYTECODE_SIGNATURE: &'static [u8] = b"\x1bLua";
+
+/// Stash the raw Lua `load` and `string.dump` functions in the registry. This needs to be
+/// called before the `load` global is removed, since the module loader needs it to load
+/// binary chunks.
+pub(crate) fn stash_raw_functions<'lua>(lua: LuaContext<'lua>) -> Result<()> {
+    let raw_load = lua.globals().get::<_, LuaFunction>("load")?;
+    let raw_dump = lua
+        .globals()
+        .get::<_, LuaTable>("string")?
+        .get::<_, LuaFunction>("dump")?;
+    lua.set_named_registry_value(RAW_LOAD_REGISTRY_KEY, raw_load)?;
+    lua.set_named_registry_value(RAW_DUMP_REGISTRY_KEY, raw_dump)?;
+    Ok(())
+}
+
+/// Compile a chunk of Lua source into a function. If `precompiled` is `true`, chunks of
+/// bytecode are accepted as well, unless bytecode has been refused in the registry under
+/// [`NO_BYTECODE_REGISTRY_KEY`].
+pub fn compile<'lua>(
+    lua: LuaContext<'lua>,
+    bytes: &[u8],
+    name: &str,
+    precompiled: bool,
+) -> Result<LuaFunction<'lua>> {
+    let is_bytecode = bytes.starts_with(BYTECODE_SIGNATURE);
+    let refused = lua
+        .named_registry_value::<_, Option<bool>>(NO_BYTECODE_REGISTRY_KEY)?
+        .unwrap_or(false);
+
+    if is_bytecode && (!precompiled || refused) {
+        bail!(
+            "refusing to load `{}`: it's precompiled bytecode, which isn't allowed here",
+            name
+        );
+    }
+
+    let raw_load = lua.named_registry_value::<_, LuaFunction>(RAW_LOAD_REGISTRY_KEY)?;
+    let mode = if is_bytecode { "b" } else { "t" };
+    let (maybe_chunk, maybe_err) = raw_load.call::<_, (Option<LuaFunction>, Option<String>)>((
+        lua.create_string(bytes)?,
+        name,
+        mode,
+    ))?;
+    maybe_chunk.ok_or_else(|| {
+        anyhow!(
+            "error loading `{}`: {}",
+            name,
+            maybe_err.unwrap_or_default()
+        )
+    })
+}
+
+/// Compile a chunk of Lua source and dump it to bytecode, stripping debug information
+/// if `strip` is `true`.
+pub fn precompile<'lua>(
+    lua: LuaContext<'lua>,
+    source: &[u8],
+    name: &str,
+    strip: bool,
+) -> Result<Vec<u8>> {
+    let chunk = compile(lua, source, name, false)?;
+    dump(lua, chunk, strip)
+}
+
+fn dump<'lua>(lua: LuaContext<'lua>, chunk: LuaFunction<'lua>, strip: bool) -> Result<Vec<u8>> {
+    let raw_dump = lua.named_registry_value::<_, LuaFunction>(RAW_DUMP_REGISTRY_KEY)?;
+    let bytecode = raw_dump.call::<_, LuaString>((chunk, strip))?;
+    Ok(bytecode.as_bytes().to_owned())
+}
+
+fn precompiled_path(path: &str) -> Option<String> {
+    path.strip_suffix(".lua")
+        .map(|stem| format!("{}.luac", stem))
+}
+
+/// Write every chunk currently in the chunk cache back out to the filesystem as
+/// precompiled bytecode, next to its source file and with a `.luac` extension.
+///
+/// Chunks which were themselves loaded from precompiled files are skipped.
+pub fn write_precompiled<'lua>(
+    lua: LuaContext<'lua>,
+    fs: &mut Filesystem,
+    strip: bool,
+) -> Result<()> {
+    let package = lua.named_registry_value::<_, LuaTable>(PACKAGE_REGISTRY_KEY)?;
+    let chunks = package.get::<_, LuaTable>("chunks")?;
+
+    for pair in chunks.pairs::<String, LuaFunction>() {
+        let (path, chunk) = pair?;
+        let out_path = match precompiled_path(&path) {
+            Some(out_path) => out_path,
+            None => continue,
+        };
+
+        let bytecode = dump(lua, chunk, strip)?;
+        fs.create(&out_path)?.write_all(&bytecode)?;
+        log::info!("wrote precompiled chunk `{}`", out_path);
+    }
+
+    Ok(())
+}
+
+/// Remove the compiled chunk for the file at `path` from the cache, along with the
+/// loaded value of any module which was loaded from it. The next `require` of that
+/// module will reload it from the filesystem.
+///
+/// Returns `true` if anything was removed.
+pub fn invalidate<'lua>(lua: LuaContext<'lua>, path: &str) -> LuaResult<bool> {
+    let package = lua.named_registry_value::<_, LuaTable>(PACKAGE_REGISTRY_KEY)?;
+    let chunks = package.get::<_, LuaTable>("chunks")?;
+    let modules = package.get::<_, LuaTable>("modules")?;
+    let sources = package.get::<_, LuaTable>("sources")?;
+
+    let had_chunk = chunks.contains_key(path)?;
+    chunks.set(path, LuaValue::Nil)?;
+
+    let had_module = match sources.get::<_, Option<String>>(path)? {
+        Some(module) => {
+            modules.set(module, LuaValue::Nil)?;
+            sources.set(path, LuaValue::Nil)?;
+            true
+        }
+        None => false,
+    };
+
+    Ok(had_chunk || had_module)
+}
+
+/// Clear all compiled chunks and loaded module values.
+pub fn invalidate_all<'lua>(lua: LuaContext<'lua>) -> LuaResult<()> {
+    let package = lua.named_registry_value::<_, LuaTable>(PACKAGE_REGISTRY_KEY)?;
+    for cache in &["chunks", "modules", "sources"] {
+        package.set(*cache, lua.create_table()?)?;
+    }
+
+    Ok(())
+}
+
+/// Lua-exposed function for loading a module from sludge's `Filesystem`.
+///
+/// Similar to Lua's built-in `require`, this will search along paths found in
+/// `sludge.package.path`, which is expected to be a colon-separated list of
+/// paths to search, where any `?` characters found are replaced by the module
+/// path being searched for. The default value of `sludge.package.path` is
+/// [`DEFAULT_PACKAGE_PATH`].
+///
+/// The limitations of opening files through this `require` are the same as opening
+/// any file through the `Filesystem`.
+///
+/// Built-in modules which were marked lazy are installed by the first `require` of
+/// their path, before the filesystem is searched.
+pub fn require<'lua>(lua: LuaContext<'lua>, module: String) -> LuaResult<LuaValue> {
+    let package = lua.named_registry_value::<_, LuaTable>(PACKAGE_REGISTRY_KEY)?;
+    let loaded_modules = package.get::<_, LuaTable>("modules")?;
+    if let Some(module) = loaded_modules.get::<_, Option<LuaValue>>(module.as_str())? {
+        return Ok(module);
+    }
+
+    if let Some(module) = crate::api::load_lazy(lua, &module).to_lua_err()? {
+        return Ok(module);
+    }
+
+    let chunks = package.get::<_, LuaTable>("chunks")?;
+    let sources = package.get::<_, LuaTable>("sources")?;
+    let precompiled = package
+        .get::<_, Option<bool>>("precompiled")?
+        .unwrap_or(false);
+    let package_path = package.get::<_, LuaString>("path")?;
+    let segments = package_path.to_str()?.split(":");
+    let module_replaced = module.replace(".", "/");
+    let fs = lua.fetch_one::<Filesystem>()?;
+
+    for segment in segments {
+        let source_path = segment.replace('?', &module_replaced);
+        let candidates = precompiled
+            .then(|| precompiled_path(&source_path))
+            .flatten()
+            .into_iter()
+            .chain(Some(source_path));
+
+        for path in candidates {
+            let chunk = match chunks.get::<_, Option<LuaFunction>>(path.as_str())? {
+                Some(chunk) => chunk,
+                None => {
+                    let mut file = match fs.borrow_mut().open(&path) {
+                        Ok(file) => file,
+                        Err(_) => continue,
+                    };
+                    let mut buf = Vec::new();
+                    file.read_to_end(&mut buf)
+                        .log_error_err(module_path!())
+                        .to_lua_err()?;
+                    let chunk = compile(lua, &buf, &module, precompiled).to_lua_err()?;
+                    chunks.set(path.as_str(), chunk.clone())?;
+                    chunk
+                }
+            };
+
+            let loaded = chunk.call::<_, LuaValue>(())?;
+            loaded_modules.set(module.as_str(), loaded.clone())?;
+            sources.set(path.as_str(), module.as_str())?;
+            return Ok(loaded);
+        }
+    }
+
+    // FIXME: better error reporting here; collect errors from individual module attempts
+    // and log them?
+    Err(anyhow!("module {} not found!", module)).to_lua_err()
+}
+
+pub fn load<'lua>(lua: LuaContext<'lua>) -> Result<LuaValue<'lua>> {
+    let table = lua.create_table()?;
+    table.set("path", DEFAULT_PACKAGE_PATH)?;
+    table.set("precompiled", false)?;
+
+    let req_fn = lua.create_function(require)?;
+    table.set("require", req_fn.clone())?;
+    lua.globals().set("require", req_fn)?;
+
+    table.set(
+        "invalidate",
+        lua.create_function(|lua, path: String| invalidate(lua, &path))?,
+    )?;
+    table.set(
+        "invalidate_all",
+        lua.create_function(|lua, ()| invalidate_all(lua))?,
+    )?;
+
+    table.set("modules", lua.create_table()?)?;
+    table.set("chunks", lua.create_table()?)?;
+    table.set("sources", lua.create_table()?)?;
+
+    lua.set_named_registry_value(PACKAGE_REGISTRY_KEY, table.clone())?;
+
+    Ok(LuaValue::Table(table))
+}
+
+inventory::submit! {
+    crate::api::Module::parse("sludge.package", load)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytecode_needs_precompiled() {
+        Lua::new().context(|lua| {
+            stash_raw_functions(lua).unwrap();
+            let bytecode = precompile(lua, b"return 1", "one", false).unwrap();
+
+            assert!(compile(lua, &bytecode, "one", false).is_err());
+            let chunk = compile(lua, &bytecode, "one", true).unwrap();
+            assert_eq!(chunk.call::<_, i32>(()).unwrap(), 1);
+
+            lua.set_named_registry_value(NO_BYTECODE_REGISTRY_KEY, true)
+                .unwrap();
+            assert!(compile(lua, &bytecode, "one", true).is_err());
+            assert!(compile(lua, b"return 1", "one", false).is_ok());
+        });
+    }
+}
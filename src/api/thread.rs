@@ -1,6 +1,7 @@
 use crate::{
-    api::SCHEDULER_QUEUE_REGISTRY_KEY, resources::Resources, Scheduler, SchedulerQueue,
-    SludgeLuaContextExt,
+    api::{SCHEDULER_QUEUE_REGISTRY_KEY, THREAD_LOCALS_REGISTRY_KEY, THREAD_NAMES_REGISTRY_KEY},
+    resources::Resources,
+    Scheduler, SchedulerQueue, SludgeLuaContextExt,
 };
 use {anyhow::*, rlua::prelude::*, thiserror::*};
 
@@ -8,6 +9,36 @@ use {anyhow::*, rlua::prelude::*, thiserror::*};
 #[error("a Lua thread made a graceful premature exit after being killed")]
 pub struct GracefulExit;
 
+/// Fetch one of the registry tables holding per-thread data, creating it if it doesn't
+/// exist yet. The tables are weakly keyed by thread, so that they don't keep finished
+/// threads alive.
+pub(crate) fn per_thread_table<'lua>(
+    lua: LuaContext<'lua>,
+    key: &str,
+) -> LuaResult<LuaTable<'lua>> {
+    if let Some(table) = lua.named_registry_value::<_, Option<LuaTable>>(key)? {
+        return Ok(table);
+    }
+
+    let table = lua.create_table()?;
+    let metatable = lua.create_table()?;
+    metatable.set("__mode", "k")?;
+    table.set_metatable(Some(metatable));
+    lua.set_named_registry_value(key, table.clone())?;
+    Ok(table)
+}
+
+/// The debug name given to a thread with `sludge.thread.set_name`, if it has one.
+pub(crate) fn thread_name<'lua>(
+    lua: LuaContext<'lua>,
+    thread: &LuaThread<'lua>,
+) -> LuaResult<Option<String>> {
+    match lua.named_registry_value::<_, Option<LuaTable>>(THREAD_NAMES_REGISTRY_KEY)? {
+        Some(names) => names.get(thread.clone()),
+        None => Ok(None),
+    }
+}
+
 pub fn load<'lua>(lua: LuaContext<'lua>) -> Result<LuaValue<'lua>> {
     // Steal coroutine then get rid of it from the global table so that
     // all coroutine manipulation goes through Space.
@@ -25,6 +56,18 @@ pub fn load<'lua>(lua: LuaContext<'lua>) -> Result<LuaValue<'lua>> {
         ctx.notify(target, args)
     })?;
 
+    let broadcast_in = lua.create_function(
+        |ctx, (ticks, string, args): (u64, LuaString, LuaMultiValue)| {
+            ctx.broadcast_in(ticks, string.to_str()?, args)
+        },
+    )?;
+
+    let notify_in = lua.create_function(
+        |ctx, (ticks, target, args): (u64, LuaThread, LuaMultiValue)| {
+            ctx.notify_in(ticks, target, args)
+        },
+    )?;
+
     let kill = lua.create_function(|ctx, (target, args): (LuaThread, LuaMultiValue)| {
         ctx.kill(target, args)
     })?;
@@ -42,6 +85,41 @@ pub fn load<'lua>(lua: LuaContext<'lua>) -> Result<LuaValue<'lua>> {
         Ok((*lua.fetch_one::<SchedulerQueue>()?.borrow()).clone())
     })?;
 
+    let stats =
+        lua.create_function(|lua, _: ()| Ok(lua.fetch_one::<SchedulerQueue>()?.borrow().stats()))?;
+
+    let set_profiling = lua.create_function(|lua, enabled: bool| {
+        let queue = lua.fetch_one::<SchedulerQueue>()?;
+        let queue = queue.borrow();
+        queue.profiler().lock().unwrap().set_enabled(enabled);
+        Ok(())
+    })?;
+
+    let reset_profile = lua.create_function(|lua, _: ()| {
+        let queue = lua.fetch_one::<SchedulerQueue>()?;
+        let queue = queue.borrow();
+        queue.profiler().lock().unwrap().reset();
+        Ok(())
+    })?;
+
+    let locals_of = lua.create_function(|lua, thread: LuaThread| {
+        let locals = per_thread_table(lua, THREAD_LOCALS_REGISTRY_KEY)?;
+        match locals.get::<_, Option<LuaTable>>(thread.clone())? {
+            Some(table) => Ok(table),
+            None => {
+                let table = lua.create_table()?;
+                locals.set(thread, table.clone())?;
+                Ok(table)
+            }
+        }
+    })?;
+
+    let name_of = lua.create_function(|lua, thread: LuaThread| thread_name(lua, &thread))?;
+
+    let set_name_of = lua.create_function(|lua, (thread, name): (LuaThread, Option<String>)| {
+        per_thread_table(lua, THREAD_NAMES_REGISTRY_KEY)?.set(thread, name)
+    })?;
+
     let yield_ = coroutine.get::<_, LuaFunction>("yield")?;
     let create = coroutine.get::<_, LuaFunction>("create")?;
     let wrap = coroutine.get::<_, LuaFunction>("wrap")?;
@@ -53,6 +131,8 @@ pub fn load<'lua>(lua: LuaContext<'lua>) -> Result<LuaValue<'lua>> {
         ("spawn", spawn),
         ("broadcast", broadcast),
         ("notify", notify),
+        ("broadcast_in", broadcast_in),
+        ("notify_in", notify_in),
         ("kill", kill),
         ("rawyield", yield_),
         ("graceful_exit", graceful_exit),
@@ -64,6 +144,12 @@ pub fn load<'lua>(lua: LuaContext<'lua>) -> Result<LuaValue<'lua>> {
         ("new_scheduler", new_scheduler),
         ("current_scheduler", current_scheduler),
         ("global_scheduler", global_scheduler),
+        ("stats", stats),
+        ("set_profiling", set_profiling),
+        ("reset_profile", reset_profile),
+        ("locals_of", locals_of),
+        ("name_of", name_of),
+        ("set_name_of", set_name_of),
     ])?))
 }
 
@@ -0,0 +1,598 @@
+//! The `sludge.world` module, which moves chunks of world state in and out of Lua as
+//! plain data, through the [`ReflectionRegistry`].
+//!
+//! `sludge.world.export` takes a query, an entity or a list of entities, and returns a
+//! list of tables mapping component names to their values. `sludge.world.import` takes
+//! a list in the same format, spawns an entity for each entry and returns the spawned
+//! entities. Together they let levels and encounters be written as Lua data files:
+//!
+//! ```lua
+//! -- encounters/ambush.lua
+//! return {
+//!     { Name = "sniper", Position = { x = 32, y = 16 } },
+//!     { Name = "grunt", Position = { x = 96, y = 40 }, Velocity = { x = -1, y = 0 } },
+//! }
+//! ```
+//!
+//! ```lua
+//! local spawned = sludge.world.import(require("encounters/ambush"))
+//! local saved = sludge.world.export(sludge.query("Position"))
+//! ```
+//!
+//! Only components registered for reflection are exported or imported. Values are
+//! converted through serde, so each component's table looks exactly like its
+//! serialized form. If any entry fails to import, every entity spawned by that call is
+//! despawned again before the error is raised.
+//!
+//! Scripts can also react to components being added, modified or removed, without
+//! polling every frame. `sludge.world.on` takes the name of a component, one of
+//! `"added"`, `"modified"` or `"removed"`, and a handler, and returns an id which can
+//! be passed to `sludge.world.off` to unsubscribe. Events are batched up, and once per
+//! tick, while the space is maintained, each handler is called with the list of every
+//! entity the event happened to since the last tick. Without a handler, the list is
+//! broadcast instead, and `on` returns the name of the broadcast to wait for:
+//!
+//! ```lua
+//! sludge.world.on("Health", "removed", function(entities)
+//!     for _, entity in ipairs(entities) do
+//!         print("lost track of", entity)
+//!     end
+//! end)
+//!
+//! local _, _, moved = yield(sludge.world.on("Position", "modified"))
+//! ```
+//!
+//! Modifications are only seen for components which track them, such as those deriving
+//! `TrackedComponent`.
+
+use {
+    anyhow::*,
+    hashbrown::HashMap,
+    rlua::prelude::*,
+    serde_json::{Map, Value},
+    std::{any::TypeId, mem},
+};
+
+use crate::{
+    api::{EntityUserDataRegistry, LuaEntity, LuaQuery},
+    ecs::{ComponentEvent, Entity, ReaderId, World},
+    reflect::ReflectionRegistry,
+    SludgeLuaContextExt,
+};
+
+/// Find the entities named by a query, a single entity or a list of entities.
+fn entities_of<'lua>(
+    lua: LuaContext<'lua>,
+    world: &World,
+    filter: LuaValue<'lua>,
+) -> LuaResult<Vec<Entity>> {
+    match filter {
+        LuaValue::UserData(ud) => {
+            if let Ok(query) = ud.borrow::<LuaQuery>() {
+                return Ok(query.matching(world));
+            }
+            Ok(vec![
+                LuaEntity::from_lua(LuaValue::UserData(ud), lua)?.into()
+            ])
+        }
+        LuaValue::Table(table) => table
+            .sequence_values::<LuaEntity>()
+            .map(|entity| entity.map(Entity::from))
+            .collect(),
+        other => Err(anyhow!(
+            "expected a query, an entity or a list of entities, got {}",
+            other.type_name()
+        ))
+        .to_lua_err(),
+    }
+}
+
+pub fn export<'lua>(lua: LuaContext<'lua>, filter: LuaValue<'lua>) -> LuaResult<LuaTable<'lua>> {
+    let registry = ReflectionRegistry::new();
+    let world = lua.fetch_one::<World>()?;
+    let world = world.borrow();
+
+    let exported = lua.create_table()?;
+    for entity in entities_of(lua, &world, filter)? {
+        let components = registry.to_json(&world, entity).to_lua_err()?;
+        let table = lua.create_table()?;
+        for (name, value) in components {
+            table.set(name, rlua_serde::to_value(lua, &value)?)?;
+        }
+        exported.set(exported.len()? + 1, table)?;
+    }
+
+    Ok(exported)
+}
+
+pub fn import<'lua>(lua: LuaContext<'lua>, entries: LuaTable<'lua>) -> LuaResult<LuaTable<'lua>> {
+    let registry = ReflectionRegistry::new();
+
+    let mut decoded = Vec::new();
+    for entry in entries.sequence_values::<LuaTable>() {
+        let mut components = Map::new();
+        for pair in entry?.pairs::<String, LuaValue>() {
+            let (name, value) = pair?;
+            components.insert(name, rlua_serde::from_value::<Value>(value)?);
+        }
+        decoded.push(components);
+    }
+
+    let spawned = {
+        let world = lua.fetch_one::<World>()?;
+        let mut world = world.borrow_mut();
+        let mut spawned = Vec::with_capacity(decoded.len());
+        for (i, components) in decoded.into_iter().enumerate() {
+            match registry.spawn_json(&mut world, components) {
+                Ok(entity) => spawned.push(entity),
+                Err(err) => {
+                    for entity in spawned {
+                        let _ = world.despawn(entity);
+                    }
+                    return Err(err.context(format!("error importing entry {}", i + 1)))
+                        .to_lua_err();
+                }
+            }
+        }
+        spawned
+    };
+
+    lua.create_sequence_from(spawned.into_iter().map(LuaEntity::from))
+}
+
+/// The kinds of component events which can be subscribed to from Lua.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ComponentEventKind {
+    Added,
+    Modified,
+    Removed,
+}
+
+impl ComponentEventKind {
+    const ALL: [Self; 3] = [Self::Added, Self::Modified, Self::Removed];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    /// The name of the event kind, as used from Lua.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Added => "added",
+            Self::Modified => "modified",
+            Self::Removed => "removed",
+        }
+    }
+}
+
+impl<'lua> FromLua<'lua> for ComponentEventKind {
+    fn from_lua(lua_value: LuaValue<'lua>, lua: LuaContext<'lua>) -> LuaResult<Self> {
+        let name = LuaString::from_lua(lua_value, lua)?;
+        match name.to_str()? {
+            "added" => Ok(Self::Added),
+            "modified" => Ok(Self::Modified),
+            "removed" => Ok(Self::Removed),
+            other => Err(anyhow!(
+                "unknown component event `{}`; expected `added`, `modified` or `removed`",
+                other
+            ))
+            .to_lua_err(),
+        }
+    }
+}
+
+/// The name of the broadcast sent for a kind of event on a component, like
+/// `"world.Position.modified"`.
+pub fn component_event_name(component: &str, kind: ComponentEventKind) -> String {
+    format!("world.{}.{}", component, kind.as_str())
+}
+
+#[derive(Debug)]
+struct Handler {
+    id: u64,
+    kind: ComponentEventKind,
+    function: LuaRegistryKey,
+}
+
+#[derive(Debug)]
+struct Tracked {
+    name: String,
+    reader: ReaderId<ComponentEvent>,
+    handlers: Vec<Handler>,
+    /// Which kinds of event are broadcast, indexed by `ComponentEventKind::index`.
+    broadcast: [bool; 3],
+}
+
+/// Every subscription made from Lua with `sludge.world.on`. Inserted by the
+/// [`WorldEventSystem`](crate::systems::WorldEventSystem), which dispatches the
+/// subscribed events once per tick with [`dispatch_component_events`].
+#[derive(Debug, Default)]
+pub struct WorldEventSubscriptions {
+    tracked: HashMap<TypeId, Tracked>,
+    next_id: u64,
+}
+
+impl WorldEventSubscriptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remove a handler, returning whether it existed. A component with nothing left
+    /// listening to it stops being tracked.
+    pub fn remove(&mut self, id: u64) -> bool {
+        let mut removed = false;
+        self.tracked.retain(|_, tracked| {
+            let before = tracked.handlers.len();
+            tracked.handlers.retain(|handler| handler.id != id);
+            removed |= tracked.handlers.len() != before;
+            !tracked.handlers.is_empty() || tracked.broadcast.iter().any(|&b| b)
+        });
+        removed
+    }
+
+    /// Subscribe a handler, along with its id, to a kind of event on a named component,
+    /// or broadcast the event if there's no handler.
+    fn subscribe(
+        &mut self,
+        lua: LuaContext,
+        world: &mut World,
+        component: &str,
+        kind: ComponentEventKind,
+        handler: Option<(u64, LuaRegistryKey)>,
+    ) -> LuaResult<()> {
+        let type_id = lua
+            .fetch_one::<EntityUserDataRegistry>()?
+            .borrow()
+            .named
+            .get(component)
+            .map(|registered| registered.type_id)
+            .ok_or_else(|| anyhow!("unknown component {}", component))
+            .to_lua_err()?;
+
+        let tracked = self.tracked.entry(type_id).or_insert_with(|| Tracked {
+            name: component.to_owned(),
+            reader: world.track_dynamic(type_id),
+            handlers: Vec::new(),
+            broadcast: [false; 3],
+        });
+
+        match handler {
+            Some((id, function)) => tracked.handlers.push(Handler { id, kind, function }),
+            None => tracked.broadcast[kind.index()] = true,
+        }
+
+        Ok(())
+    }
+}
+
+/// Subscribe to a kind of event on a named component, returning the handler's id, or
+/// the name of the broadcast if there's no handler.
+fn on<'lua>(
+    lua: LuaContext<'lua>,
+    (component, kind, handler): (String, ComponentEventKind, Option<LuaFunction<'lua>>),
+) -> LuaResult<LuaValue<'lua>> {
+    let (subscriptions, world) = lua.fetch::<(WorldEventSubscriptions, World)>()?;
+    let mut subscriptions = subscriptions.borrow_mut();
+    let mut world = world.borrow_mut();
+
+    match handler {
+        Some(function) => {
+            let id = subscriptions.next_id;
+            let function = lua.create_registry_value(function)?;
+            subscriptions.subscribe(lua, &mut world, &component, kind, Some((id, function)))?;
+            subscriptions.next_id += 1;
+            id.to_lua(lua)
+        }
+        None => {
+            subscriptions.subscribe(lua, &mut world, &component, kind, None)?;
+            component_event_name(&component, kind).to_lua(lua)
+        }
+    }
+}
+
+/// Record every subscription made with `sludge.world.on`, handlers and all, for
+/// persistence.
+pub(crate) fn record_subscriptions<'lua>(lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+    let subscriptions = match lua.fetch_one::<WorldEventSubscriptions>() {
+        Ok(subscriptions) => subscriptions,
+        Err(_) => return Ok(LuaValue::Nil),
+    };
+    let subscriptions = subscriptions.borrow();
+
+    let recorded = lua.create_table()?;
+    for tracked in subscriptions.tracked.values() {
+        for handler in tracked.handlers.iter() {
+            let entry = lua.create_table()?;
+            entry.set("component", tracked.name.as_str())?;
+            entry.set("kind", handler.kind.as_str())?;
+            entry.set("id", handler.id)?;
+            entry.set(
+                "handler",
+                lua.registry_value::<LuaFunction>(&handler.function)?,
+            )?;
+            recorded.set(recorded.raw_len() + 1, entry)?;
+        }
+
+        for &kind in &ComponentEventKind::ALL {
+            if tracked.broadcast[kind.index()] {
+                let entry = lua.create_table()?;
+                entry.set("component", tracked.name.as_str())?;
+                entry.set("kind", kind.as_str())?;
+                recorded.set(recorded.raw_len() + 1, entry)?;
+            }
+        }
+    }
+
+    let table = lua.create_table()?;
+    table.set("next_id", subscriptions.next_id)?;
+    table.set("subscriptions", recorded)?;
+    Ok(LuaValue::Table(table))
+}
+
+/// Replace the space's subscriptions with those recorded by [`record_subscriptions`].
+pub(crate) fn playback_subscriptions<'lua>(
+    lua: LuaContext<'lua>,
+    value: LuaValue<'lua>,
+) -> Result<()> {
+    let table = match value {
+        LuaValue::Nil => return Ok(()),
+        other => LuaTable::from_lua(other, lua)?,
+    };
+
+    let (subscriptions, world) = lua.fetch::<(WorldEventSubscriptions, World)>()?;
+    let mut restored = WorldEventSubscriptions {
+        tracked: HashMap::new(),
+        next_id: table.get("next_id")?,
+    };
+    for entry in table
+        .get::<_, LuaTable>("subscriptions")?
+        .sequence_values::<LuaTable>()
+    {
+        let entry = entry?;
+        let component = entry.get::<_, String>("component")?;
+        let kind = entry.get::<_, ComponentEventKind>("kind")?;
+        let handler = match entry.get::<_, Option<LuaFunction>>("handler")? {
+            Some(function) => Some((entry.get("id")?, lua.create_registry_value(function)?)),
+            None => None,
+        };
+        restored.subscribe(lua, &mut world.borrow_mut(), &component, kind, handler)?;
+    }
+
+    *subscriptions.borrow_mut() = restored;
+    Ok(())
+}
+
+/// Call the handlers of, and broadcast, every component event subscribed to from Lua
+/// which has happened since the last call. Errors in handlers are logged, rather than
+/// stopping the other handlers from running.
+pub fn dispatch_component_events(lua: LuaContext) -> Result<()> {
+    let subscriptions = match lua.fetch_one::<WorldEventSubscriptions>() {
+        Ok(subscriptions) => subscriptions,
+        Err(_) => return Ok(()),
+    };
+
+    // Handlers are free to touch the world and subscribe or unsubscribe, so neither is
+    // borrowed while they run.
+    let mut batches = Vec::new();
+    {
+        let world = lua.fetch_one::<World>()?;
+        let world = world.borrow();
+        let mut subscriptions = subscriptions.borrow_mut();
+
+        for (&type_id, tracked) in subscriptions.tracked.iter_mut() {
+            let mut entities: [Vec<Entity>; 3] = Default::default();
+            for event in world.poll_dynamic(type_id, &mut tracked.reader) {
+                let (kind, entity) = match *event {
+                    ComponentEvent::Inserted(entity) => (ComponentEventKind::Added, entity),
+                    ComponentEvent::Modified(entity) => (ComponentEventKind::Modified, entity),
+                    ComponentEvent::Removed(entity) => (ComponentEventKind::Removed, entity),
+                };
+                entities[kind.index()].push(entity);
+            }
+
+            for &kind in &ComponentEventKind::ALL {
+                let entities = mem::take(&mut entities[kind.index()]);
+                if entities.is_empty() {
+                    continue;
+                }
+
+                let handlers = tracked
+                    .handlers
+                    .iter()
+                    .filter(|handler| handler.kind == kind)
+                    .map(|handler| lua.registry_value::<LuaFunction>(&handler.function))
+                    .collect::<LuaResult<Vec<_>>>()?;
+                let broadcast = if tracked.broadcast[kind.index()] {
+                    Some(component_event_name(&tracked.name, kind))
+                } else {
+                    None
+                };
+                batches.push((handlers, broadcast, entities));
+            }
+        }
+    }
+
+    for (handlers, broadcast, entities) in batches {
+        let entities = lua.create_sequence_from(entities.into_iter().map(LuaEntity::from))?;
+        for handler in handlers {
+            if let Err(err) = handler.call::<_, ()>(entities.clone()) {
+                log::error!("error in world event handler: {}", err);
+            }
+        }
+
+        if let Some(event_name) = broadcast {
+            lua.broadcast(event_name, entities)?;
+        }
+    }
+
+    Ok(())
+}
+
+inventory::submit! {
+    crate::api::Module::parse("sludge.world", |lua| {
+        let table = lua.create_table_from(vec![
+            ("export", lua.create_function(export)?),
+            ("import", lua.create_function(import)?),
+            ("on", lua.create_function(on)?),
+            (
+                "off",
+                lua.create_function(|lua, id: u64| {
+                    Ok(lua
+                        .fetch_one::<WorldEventSubscriptions>()?
+                        .borrow_mut()
+                        .remove(id))
+                })?,
+            ),
+        ])?;
+
+        Ok(LuaValue::Table(table))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{components::Name, lifetime::Lifetime, Space};
+
+    const SUBSCRIBE: &'static str = r#"
+        handler_id = sludge.world.on("Lifetime", "added", function(entities)
+            added = (added or 0) + #entities
+        end)
+        return handler_id, sludge.world.on("Lifetime", "removed")
+    "#;
+
+    fn added(space: &Space) -> anyhow::Result<u32> {
+        Ok(space
+            .lua()
+            .context(|lua| lua.globals().get::<_, Option<u32>>("added"))?
+            .unwrap_or(0))
+    }
+
+    #[test]
+    fn exported_entities_import_as_copies() -> anyhow::Result<()> {
+        let space = Space::new()?;
+        space.lua().context(|lua| {
+            lua.load(
+                r#"
+                local sniper = sludge.spawn { Name = "sniper", Lifetime = 2 }
+                sludge.spawn { Name = "grunt" }
+
+                local exported = sludge.world.export({ sniper })
+                assert(#exported == 1)
+                assert(exported[1].Name == "sniper" and exported[1].Lifetime == 2)
+                assert(#sludge.world.export(sludge.query("Name")) == 2)
+
+                local spawned = sludge.world.import(exported)
+                assert(#spawned == 1 and spawned[1] ~= sniper)
+                local copy = sludge.world.export(spawned[1])[1]
+                assert(copy.Name == "sniper" and copy.Lifetime == 2)
+                "#,
+            )
+            .exec()
+        })?;
+
+        let world = space.world()?;
+        let world = world.borrow();
+        let mut names = world
+            .query::<&Name>()
+            .iter()
+            .map(|(_, name)| name.0.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["grunt", "sniper", "sniper"]);
+        assert_eq!(world.query::<&Lifetime>().iter().count(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn failed_imports_spawn_nothing() -> anyhow::Result<()> {
+        let space = Space::new()?;
+        let err = space
+            .lua()
+            .context(|lua| {
+                lua.load(
+                    r#"
+                    sludge.world.import {
+                        { Name = "fine" },
+                        { Name = "also fine", Lifetime = 1 },
+                        { Name = "broken", Lifetime = "forever" },
+                    }
+                    "#,
+                )
+                .exec()
+            })
+            .unwrap_err();
+        assert!(format!("{:?}", err).contains("entry 3"), "{:?}", err);
+        assert_eq!(space.world()?.borrow().query::<&Name>().iter().count(), 0);
+
+        let err = space
+            .lua()
+            .context(|lua| lua.load("sludge.world.export(5)").exec())
+            .unwrap_err();
+        assert!(
+            format!("{:?}", err).contains("expected a query"),
+            "{:?}",
+            err
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn handlers_are_unsubscribed_with_off() -> anyhow::Result<()> {
+        let mut space = Space::new()?;
+        let (id, broadcast) = space
+            .lua()
+            .context(|lua| lua.load(SUBSCRIBE).eval::<(u64, String)>())?;
+        assert_eq!(broadcast, "world.Lifetime.removed");
+
+        space.world()?.borrow_mut().spawn((Lifetime(10.),));
+        space.fixed_update()?;
+        assert_eq!(added(&space)?, 1);
+
+        let removed = space.lua().context(|lua| {
+            lua.load("return sludge.world.off(...), sludge.world.off(...)")
+                .call::<_, (bool, bool)>((id, id))
+        })?;
+        assert_eq!(removed, (true, false));
+
+        space.world()?.borrow_mut().spawn((Lifetime(10.),));
+        space.fixed_update()?;
+        assert_eq!(added(&space)?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn subscriptions_survive_saving_and_loading() -> anyhow::Result<()> {
+        let space = Space::new()?;
+        let (id, _) = space
+            .lua()
+            .context(|lua| lua.load(SUBSCRIBE).eval::<(u64, String)>())?;
+        let mut bytes = Vec::new();
+        space.save(&mut bytes)?;
+
+        let mut loaded = Space::new()?;
+        loaded.load(&mut &bytes[..])?;
+        loaded.world()?.borrow_mut().spawn((Lifetime(10.),));
+        loaded.fixed_update()?;
+        assert_eq!(added(&loaded)?, 1);
+
+        let (next, removed) = loaded.lua().context(|lua| {
+            lua.load(
+                r#"
+                local id = ...
+                return sludge.world.on("Name", "added", function() end), sludge.world.off(id)
+                "#,
+            )
+            .call::<_, (u64, bool)>(id)
+        })?;
+        assert_eq!((next, removed), (id + 1, true));
+
+        loaded.world()?.borrow_mut().spawn((Lifetime(10.),));
+        loaded.fixed_update()?;
+        assert_eq!(added(&loaded)?, 1);
+
+        Ok(())
+    }
+}
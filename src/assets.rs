@@ -13,9 +13,12 @@ use {
         borrow::Cow,
         fmt,
         marker::PhantomData,
-        ops,
+        mem, ops,
         path::{Path, PathBuf},
-        sync::{Arc, Condvar, Mutex},
+        sync::{
+            atomic::{AtomicU64, AtomicUsize, Ordering},
+            Arc, Condvar, Mutex,
+        },
         thread::{self, ThreadId},
     },
 };
@@ -60,6 +63,13 @@ pub trait Asset: Send + Sync + 'static + Sized {
         cache: &Cache<'a, R>,
         resources: &R,
     ) -> Result<Loaded<Self>>;
+
+    /// An approximate count of the bytes of memory this asset takes up, counted against
+    /// the cache's budget. Defaults to the size of the type itself; assets which own
+    /// heap or GPU memory should override this.
+    fn size_hint(&self) -> usize {
+        mem::size_of::<Self>()
+    }
 }
 
 #[derive(Debug)]
@@ -126,29 +136,67 @@ impl fmt::Display for StructuredKey {
 }
 
 impl StructuredKey {
+    fn new<T: Serialize>(structured: &T) -> Result<Self> {
+        Ok(Self {
+            inner: serde_hashkey::to_key_with_ordered_float(structured)?,
+        })
+    }
+
     pub fn to_rust<T: DeserializeOwned>(&self) -> Result<T> {
         Ok(serde_hashkey::from_key(&self.inner)?)
     }
 }
 
+/// The namespace a [`Key`] belongs to. Keys in different namespaces never collide, even
+/// when they're made from the same path or the same value.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Namespace {
+    /// Paths in the filesystem.
+    Path,
+    /// Structured keys made from values of the type with the given name.
+    Structured(&'static str),
+    /// Keys for assets generated at runtime, under a name chosen by whatever generates
+    /// them.
+    Generated(Cow<'static, str>),
+}
+
+impl fmt::Display for Namespace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Path => write!(f, "path"),
+            Self::Structured(type_name) => write!(f, "structured:{}", type_name),
+            Self::Generated(name) => write!(f, "generated:{}", name),
+        }
+    }
+}
+
+/// The key an asset is cached under. Keys are made with [`Key::path`] for assets loaded
+/// from files, [`Key::structured`] for assets described by a value (such as a font at a
+/// given size), and [`Key::generated`] for assets which are built at runtime and
+/// [inserted](Cache::insert) into the cache. Each kind of key lives in its own
+/// [`Namespace`].
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Key<'a> {
     Path(Cow<'a, Path>),
-    Structured(StructuredKey),
+    /// A structured key, along with the name of the type it was made from.
+    Structured(&'static str, StructuredKey),
+    /// A generated key, along with the name of its namespace.
+    Generated(Cow<'static, str>, StructuredKey),
 }
 
 impl<'a> fmt::Display for Key<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Self::Path(path) => fmt::Display::fmt(&path.display(), f),
-            Self::Structured(key) => fmt::Display::fmt(key, f),
+            Self::Structured(type_name, key) => write!(f, "{} {}", type_name, key),
+            Self::Generated(name, key) => write!(f, "{} {}", name, key),
         }
     }
 }
 
 impl<'a> From<&'a Path> for Key<'a> {
     fn from(path: &'a Path) -> Self {
-        Self::from_path(path)
+        Self::path(path)
     }
 }
 
@@ -159,54 +207,112 @@ impl<'a> From<PathBuf> for Key<'a> {
 }
 
 impl<'a> Key<'a> {
-    pub fn from_path<P: AsRef<Path> + ?Sized>(path: &'a P) -> Self {
+    /// A key for an asset loaded from a file.
+    pub fn path<P: AsRef<Path> + ?Sized>(path: &'a P) -> Self {
         Self::Path(Cow::Borrowed(path.as_ref()))
     }
 
-    pub fn from_structured<T: Serialize>(structured: &T) -> Result<Self> {
-        Ok(Self::Structured(StructuredKey {
-            inner: serde_hashkey::to_key_with_ordered_float(structured)?,
-        }))
+    /// A key made from a value, in the namespace of the value's type, so values of
+    /// different types never make the same key even if they serialize the same way.
+    pub fn structured<T: Serialize>(structured: &T) -> Result<Self> {
+        Ok(Self::Structured(
+            any::type_name::<T>(),
+            StructuredKey::new(structured)?,
+        ))
+    }
+
+    /// A key for an asset generated at runtime, identified by `id` within the
+    /// `namespace`. Each generated namespace belongs to a single type of asset; see
+    /// [`Cache::get`].
+    pub fn generated<T: Serialize>(
+        namespace: impl Into<Cow<'static, str>>,
+        id: &T,
+    ) -> Result<Self> {
+        Ok(Self::Generated(namespace.into(), StructuredKey::new(id)?))
+    }
+
+    pub fn namespace(&self) -> Namespace {
+        match self {
+            Key::Path(_) => Namespace::Path,
+            Key::Structured(type_name, _) => Namespace::Structured(*type_name),
+            Key::Generated(name, _) => Namespace::Generated(name.clone()),
+        }
     }
 
     pub fn clone_static(&self) -> Key<'static> {
         match self {
             Key::Path(cow_path) => Key::Path(Cow::Owned(cow_path.clone().into_owned())),
-            Key::Structured(structured) => Key::Structured(structured.clone()),
+            Key::Structured(type_name, structured) => {
+                Key::Structured(*type_name, structured.clone())
+            }
+            Key::Generated(name, id) => Key::Generated(name.clone(), id.clone()),
         }
     }
 
     pub fn to_path(&self) -> Result<&Path> {
         match self {
             Key::Path(path) => Ok(path),
-            Key::Structured(key) => bail!("expected path but found structured key: {}", key),
+            other => bail!(
+                "expected path but found {} key: {}",
+                other.namespace(),
+                other
+            ),
         }
     }
 
+    /// Deserialize a structured key back into the value it was made from, or a generated
+    /// key into its ID. Structured keys can only be deserialized into the type they were
+    /// made from.
     pub fn to_rust<T: DeserializeOwned>(&self) -> Result<T> {
-        match self {
+        let structured = match self {
             Key::Path(path) => bail!(
                 "expected structured key deserializable to type {} but found path: {}",
                 any::type_name::<T>(),
                 path.display()
             ),
-            Key::Structured(structured) => structured.to_rust().with_context(|| {
-                anyhow!(
-                    "error parsing structured key {} into type {}",
-                    structured,
-                    any::type_name::<T>()
-                )
-            }),
-        }
+            Key::Structured(type_name, _) if *type_name != any::type_name::<T>() => bail!(
+                "expected structured key of type {} but found one of type {}",
+                any::type_name::<T>(),
+                type_name
+            ),
+            Key::Structured(_, structured) | Key::Generated(_, structured) => structured,
+        };
+
+        structured.to_rust().with_context(|| {
+            anyhow!(
+                "error parsing structured key {} into type {}",
+                structured,
+                any::type_name::<T>()
+            )
+        })
     }
 }
 
 #[derive(Debug)]
 enum ResourceState {
-    Done(Arc<dyn Any + Send + Sync>),
+    Done(LoadedEntry),
     Loading(ThreadId, Arc<Condvar>),
 }
 
+#[derive(Debug)]
+struct LoadedEntry {
+    value: Arc<dyn Any + Send + Sync>,
+    size: usize,
+    last_used: AtomicU64,
+}
+
+/// A snapshot of how much memory a [`Cache`] is using.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheUsage {
+    /// The approximate size in bytes of every loaded asset, as reported by
+    /// [`Asset::size_hint`].
+    pub bytes: usize,
+    /// The number of loaded assets.
+    pub assets: usize,
+    /// The budget set with [`Cache::set_budget`], if any.
+    pub budget: Option<usize>,
+}
+
 #[derive(Default)]
 struct KeyEntry {
     types: HashMap<TypeId, ResourceState>,
@@ -216,6 +322,14 @@ pub struct Cache<'a, R: Resources<'a>> {
     resources: R,
     entries: Mutex<HashMap<Key<'static>, KeyEntry>>,
     dependencies: Mutex<HashMap<Key<'static>, HashSet<Key<'static>>>>,
+    /// The keys currently being loaded by each thread, innermost last, so that assets
+    /// fetched from inside `Asset::load` can be recorded as dependencies.
+    loading: Mutex<HashMap<ThreadId, Vec<Key<'static>>>>,
+    reloaders: Mutex<HashMap<TypeId, fn(&Self, &Key) -> Result<()>>>,
+    /// The type of asset each generated namespace belongs to, and its name.
+    generated: Mutex<HashMap<Cow<'static, str>, (TypeId, &'static str)>>,
+    frame: AtomicU64,
+    budget: AtomicUsize,
     _marker: PhantomData<&'a ()>,
 }
 
@@ -225,13 +339,443 @@ impl<'a, R: Resources<'a>> Cache<'a, R> {
             resources,
             entries: Mutex::new(HashMap::new()),
             dependencies: Mutex::new(HashMap::new()),
+            loading: Mutex::new(HashMap::new()),
+            reloaders: Mutex::new(HashMap::new()),
+            generated: Mutex::new(HashMap::new()),
+            frame: AtomicU64::new(0),
+            budget: AtomicUsize::new(usize::MAX),
             _marker: PhantomData,
         }
     }
 
+    /// The keys which the assets loaded from `key` depend on. Dependencies are
+    /// recorded both from [`Loaded::with_deps`] and whenever an asset's
+    /// [`Asset::load`] fetches another asset from the cache.
+    pub fn dependencies(&self, key: &Key) -> Vec<Key<'static>> {
+        self.dependencies
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|deps| deps.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The keys of assets which directly depend on `key`.
+    pub fn dependents(&self, key: &Key) -> Vec<Key<'static>> {
+        self.dependencies
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, deps)| deps.contains(key))
+            .map(|(dependent, _)| dependent.clone())
+            .collect()
+    }
+
+    /// Reload every asset loaded from `key`, and then every asset which depends on it,
+    /// directly or transitively, so that dependents are rebuilt from the new data.
+    /// Assets are reloaded in dependency order, so every asset is reloaded after
+    /// everything it depends on, and replaced in place, so existing [`Cached`] handles
+    /// see the new values. Call this when the file behind a key changes. Returns every
+    /// key which was reloaded, in the order they were reloaded.
+    ///
+    /// If an asset fails to reload, reloading stops there; the asset keeps its old value
+    /// and its old dependencies, so it's reloaded again when they change.
+    pub fn reload(&self, key: &Key) -> Result<Vec<Key<'static>>> {
+        let order = self.reload_order(key);
+
+        for key in &order {
+            let type_ids = match self.entries.lock().unwrap().get(key) {
+                Some(entry) => entry.types.keys().copied().collect::<Vec<_>>(),
+                None => continue,
+            };
+
+            let old_deps = self.dependencies.lock().unwrap().remove(key);
+            let result = type_ids.into_iter().try_for_each(|type_id| {
+                let reloader = self.reloaders.lock().unwrap().get(&type_id).copied();
+                match reloader {
+                    Some(reload) => reload(self, key),
+                    None => Ok(()),
+                }
+            });
+
+            if let Err(err) = result {
+                let mut dependencies = self.dependencies.lock().unwrap();
+                match old_deps {
+                    Some(deps) => dependencies.insert(key.clone(), deps),
+                    None => dependencies.remove(key),
+                };
+                return Err(err);
+            }
+        }
+
+        Ok(order)
+    }
+
+    /// `key` and everything which transitively depends on it, sorted so that every key
+    /// comes after all of the keys it depends on.
+    fn reload_order(&self, key: &Key) -> Vec<Key<'static>> {
+        let mut affected = vec![key.clone_static()];
+        let mut visited = HashSet::new();
+        visited.insert(key.clone_static());
+
+        let mut i = 0;
+        while i < affected.len() {
+            for dependent in self.dependents(&affected[i]) {
+                if visited.insert(dependent.clone()) {
+                    affected.push(dependent);
+                }
+            }
+            i += 1;
+        }
+
+        // Kahn's algorithm, counting only the dependencies which are themselves being
+        // reloaded. `key` is always first, since nothing it depends on is reloaded.
+        let dependencies = self.dependencies.lock().unwrap();
+        let mut pending = affected
+            .iter()
+            .map(|k| {
+                let count = dependencies.get(k).map_or(0, |deps| {
+                    deps.iter()
+                        .filter(|&dep| dep != k && visited.contains(dep))
+                        .count()
+                });
+                (k.clone(), count)
+            })
+            .collect::<HashMap<_, _>>();
+        pending.insert(key.clone_static(), 0);
+        drop(dependencies);
+
+        let mut order = Vec::with_capacity(affected.len());
+        let mut ready = vec![key.clone_static()];
+        while let Some(next) = ready.pop() {
+            pending.remove(&next);
+            for dependent in self.dependents(&next) {
+                if let Some(count) = pending.get_mut(&dependent) {
+                    *count -= 1;
+                    if *count == 0 {
+                        ready.push(dependent);
+                    }
+                }
+            }
+            order.push(next);
+        }
+
+        // Cycles shouldn't be possible, but if one sneaks in, reload what's left anyway.
+        order.extend(affected.into_iter().filter(|k| pending.contains_key(k)));
+        order
+    }
+
+    fn reload_asset<T: Asset>(&self, key: &Key) -> Result<()> {
+        let loaded = self.load_tracked::<T>(key).with_context(|| {
+            anyhow!(
+                "error reloading asset of type {} for key {}",
+                any::type_name::<T>(),
+                key
+            )
+        })?;
+        let size = loaded.value.size_hint();
+
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(ResourceState::Done(entry)) = entries
+            .get_mut(&key.clone_static())
+            .and_then(|e| e.types.get_mut(&TypeId::of::<T>()))
+        {
+            let swap = entry.value.clone().downcast::<ArcSwap<T>>().unwrap();
+            swap.store(Arc::new(loaded.value));
+            entry.size = size;
+        }
+
+        Ok(())
+    }
+
+    /// If this thread is in the middle of loading an asset, record that it depends on
+    /// `key`.
+    fn record_dependency(&self, key: &Key) {
+        let parent = self
+            .loading
+            .lock()
+            .unwrap()
+            .get(&thread::current().id())
+            .and_then(|stack| stack.last().cloned());
+
+        if let Some(parent) = parent {
+            if parent != *key {
+                self.dependencies
+                    .lock()
+                    .unwrap()
+                    .entry(parent)
+                    .or_default()
+                    .insert(key.clone_static());
+            }
+        }
+    }
+
+    /// Load an asset, keeping track of which key this thread is loading while it runs.
+    fn load_tracked<T: Asset>(&self, key: &Key) -> Result<Loaded<T>> {
+        let thread_id = thread::current().id();
+        self.loading
+            .lock()
+            .unwrap()
+            .entry(thread_id)
+            .or_default()
+            .push(key.clone_static());
+
+        let result = T::load(key, self, &self.resources);
+
+        let mut loading = self.loading.lock().unwrap();
+        let stack = loading.get_mut(&thread_id).unwrap();
+        stack.pop();
+        if stack.is_empty() {
+            loading.remove(&thread_id);
+        }
+        drop(loading);
+
+        let loaded = result?;
+        if !loaded.deps.is_empty() {
+            self.dependencies
+                .lock()
+                .unwrap()
+                .entry(key.clone_static())
+                .or_default()
+                .extend(loaded.deps.iter().cloned());
+        }
+
+        Ok(loaded)
+    }
+
+    /// Advance the cache's frame counter, which is used to track when each asset was
+    /// last fetched with [`Cache::get`]. Should be called once per frame.
+    pub fn next_frame(&self) {
+        self.frame.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Set the approximate number of bytes the cache should try to keep its assets
+    /// under. Nothing is evicted until [`Cache::collect`] is called.
+    pub fn set_budget(&self, bytes: impl Into<Option<usize>>) {
+        self.budget
+            .store(bytes.into().unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+
+    pub fn budget(&self) -> Option<usize> {
+        match self.budget.load(Ordering::Relaxed) {
+            usize::MAX => None,
+            bytes => Some(bytes),
+        }
+    }
+
+    /// Report how much memory the cache's assets are taking up.
+    pub fn usage(&self) -> CacheUsage {
+        let entries = self.entries.lock().unwrap();
+        let (bytes, assets) = entries
+            .values()
+            .flat_map(|entry| entry.types.values())
+            .fold((0, 0), |(bytes, assets), state| match state {
+                ResourceState::Done(loaded) => (bytes + loaded.size, assets + 1),
+                ResourceState::Loading(..) => (bytes, assets),
+            });
+
+        CacheUsage {
+            bytes,
+            assets,
+            budget: self.budget(),
+        }
+    }
+
+    /// If the cache is over budget, evict assets until it isn't, least recently used
+    /// first. Only assets which aren't referenced by any [`Cached`] handle outside the
+    /// cache are evicted, so the cache may stay over budget if everything in it is in
+    /// use. Returns the number of bytes freed.
+    pub fn collect(&self) -> usize {
+        let budget = self.budget.load(Ordering::Relaxed);
+        let mut entries = self.entries.lock().unwrap();
+
+        let mut usage = 0;
+        let mut candidates = Vec::new();
+        for (key, entry) in entries.iter() {
+            for (&type_id, state) in entry.types.iter() {
+                if let ResourceState::Done(loaded) = state {
+                    usage += loaded.size;
+                    if Arc::strong_count(&loaded.value) == 1 {
+                        candidates.push((
+                            loaded.last_used.load(Ordering::Relaxed),
+                            loaded.size,
+                            key.clone(),
+                            type_id,
+                        ));
+                    }
+                }
+            }
+        }
+
+        if usage <= budget {
+            return 0;
+        }
+
+        candidates.sort_by_key(|&(last_used, ..)| last_used);
+
+        let mut freed = 0;
+        let mut dependencies = self.dependencies.lock().unwrap();
+        for (_, size, key, type_id) in candidates {
+            if usage - freed <= budget {
+                break;
+            }
+
+            let entry = entries.get_mut(&key).unwrap();
+            entry.types.remove(&type_id);
+            if entry.types.is_empty() {
+                entries.remove(&key);
+                dependencies.remove(&key);
+            }
+            freed += size;
+        }
+
+        freed
+    }
+
+    /// Every namespace with an asset loaded in it.
+    pub fn namespaces(&self) -> Vec<Namespace> {
+        let entries = self.entries.lock().unwrap();
+        let namespaces = entries.keys().map(Key::namespace).collect::<HashSet<_>>();
+        namespaces.into_iter().collect()
+    }
+
+    /// The keys of every asset loaded in `namespace`, along with the approximate size of
+    /// each in bytes and whether anything outside the cache is holding onto it.
+    pub fn keys_in(&self, namespace: &Namespace) -> Vec<(Key<'static>, usize, bool)> {
+        let entries = self.entries.lock().unwrap();
+        let mut keys = Vec::new();
+        for (key, entry) in entries.iter() {
+            if key.namespace() != *namespace {
+                continue;
+            }
+
+            for state in entry.types.values() {
+                if let ResourceState::Done(loaded) = state {
+                    let in_use = Arc::strong_count(&loaded.value) > 1;
+                    keys.push((key.clone(), loaded.size, in_use));
+                }
+            }
+        }
+        keys
+    }
+
+    /// Evict every asset in `namespace` which isn't referenced by any [`Cached`] handle
+    /// outside the cache, regardless of the budget. Returns the number of bytes freed.
+    pub fn evict_namespace(&self, namespace: &Namespace) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let mut dependencies = self.dependencies.lock().unwrap();
+
+        let mut freed = 0;
+        entries.retain(|key, entry| {
+            if key.namespace() != *namespace {
+                return true;
+            }
+
+            entry.types.retain(|_, state| match state {
+                ResourceState::Done(loaded) if Arc::strong_count(&loaded.value) == 1 => {
+                    freed += loaded.size;
+                    false
+                }
+                _ => true,
+            });
+
+            if entry.types.is_empty() {
+                dependencies.remove(key);
+                false
+            } else {
+                true
+            }
+        });
+
+        freed
+    }
+
+    /// Let go of every loaded asset, regardless of the budget. Assets which are still
+    /// referenced by a [`Cached`] handle outside the cache live on until it's dropped.
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| {
+            entry.types.retain(|_, state| match state {
+                ResourceState::Done(_) => false,
+                ResourceState::Loading(..) => true,
+            });
+            !entry.types.is_empty()
+        });
+        self.dependencies.lock().unwrap().clear();
+    }
+
+    /// Make sure that a generated key's namespace belongs to assets of type `T`, claiming
+    /// it if nothing has yet.
+    fn claim_generated<T: Asset>(&self, key: &Key) -> Result<()> {
+        if let Key::Generated(name, _) = key {
+            let mut generated = self.generated.lock().unwrap();
+            let &mut (owner, owner_name) = generated
+                .entry(name.clone())
+                .or_insert((TypeId::of::<T>(), any::type_name::<T>()));
+            ensure!(
+                owner == TypeId::of::<T>(),
+                "generated namespace `{}` belongs to assets of type {}, not {}",
+                name,
+                owner_name,
+                any::type_name::<T>()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Put an asset which was generated at runtime into the cache, under a
+    /// [generated](Key::generated) key. If there's already an asset of the same type
+    /// under the key, it's replaced in place, so existing [`Cached`] handles see the new
+    /// value.
+    pub fn insert<T: Asset>(&self, key: &Key, value: T) -> Result<Cached<T>> {
+        ensure!(
+            matches!(key, Key::Generated(..)),
+            "only generated keys can be inserted into the cache, but got {} key: {}",
+            key.namespace(),
+            key
+        );
+        self.claim_generated::<T>(key)?;
+
+        let frame = self.frame.load(Ordering::Relaxed);
+        let size = value.size_hint();
+        let mut entries = self.entries.lock().unwrap();
+        let types = &mut entries.entry(key.clone_static()).or_default().types;
+
+        if let Some(ResourceState::Done(entry)) = types.get_mut(&TypeId::of::<T>()) {
+            let swap = entry.value.clone().downcast::<ArcSwap<T>>().unwrap();
+            swap.store(Arc::new(value));
+            entry.size = size;
+            entry.last_used.store(frame, Ordering::Relaxed);
+            return Ok(Cached(arc_swap::Cache::new(swap)));
+        }
+
+        ensure!(
+            !types.contains_key(&TypeId::of::<T>()),
+            "asset with key {} is still being loaded",
+            key
+        );
+
+        let wrapped = Arc::new(ArcSwap::from_pointee(value));
+        types.insert(
+            TypeId::of::<T>(),
+            ResourceState::Done(LoadedEntry {
+                value: wrapped.clone() as Arc<dyn Any + Send + Sync>,
+                size,
+                last_used: AtomicU64::new(frame),
+            }),
+        );
+
+        Ok(Cached(arc_swap::Cache::new(wrapped)))
+    }
+
     /// Load a resource, inserting it into the cache if unloaded and returning a reference
     /// to the cached value if already loaded.
     ///
+    /// Generated keys are loaded with [`Asset::load`] like any others, but each generated
+    /// namespace belongs to the first type of asset fetched or inserted under it, and
+    /// fetching any other type of asset from it is an error.
+    ///
     /// # Concurrency
     ///
     /// This method has several caveats with respect to concurrency. The first is that with
@@ -267,14 +811,19 @@ impl<'a, R: Resources<'a>> Cache<'a, R> {
         //     containing the current thread (so we can detect bad recursion/re-calls on the
         //     same thread) and return the created condvar so we can signal to any other
         //     threads waiting on our loading resource when we are done (or fail.)
+        self.claim_generated::<T>(key)?;
+        self.record_dependency(key);
+
+        let frame = self.frame.load(Ordering::Relaxed);
         let signal_loaded = {
             let mut entries = self.entries.lock().unwrap();
             match entries
                 .get(key)
                 .and_then(|e| e.types.get(&TypeId::of::<T>()))
             {
-                Some(ResourceState::Done(value)) => {
-                    let downcast = value.clone().downcast::<ArcSwap<T>>().unwrap();
+                Some(ResourceState::Done(loaded)) => {
+                    loaded.last_used.store(frame, Ordering::Relaxed);
+                    let downcast = loaded.value.clone().downcast::<ArcSwap<T>>().unwrap();
                     return Ok(Cached(arc_swap::Cache::new(downcast)));
                 }
                 Some(ResourceState::Loading(thread_id, loaded))
@@ -285,8 +834,9 @@ impl<'a, R: Resources<'a>> Cache<'a, R> {
                     let entry = entries
                         .get(key)
                         .and_then(|e| e.types.get(&TypeId::of::<T>()));
-                    if let Some(ResourceState::Done(value)) = entry {
-                        let downcast = value.clone().downcast::<ArcSwap<T>>().unwrap();
+                    if let Some(ResourceState::Done(loaded)) = entry {
+                        loaded.last_used.store(frame, Ordering::Relaxed);
+                        let downcast = loaded.value.clone().downcast::<ArcSwap<T>>().unwrap();
                         return Ok(Cached(arc_swap::Cache::new(downcast)));
                     } else {
                         bail!("an error occurred while waiting for another thread to load a resource with the key {}", key);
@@ -306,7 +856,7 @@ impl<'a, R: Resources<'a>> Cache<'a, R> {
             }
         };
 
-        let loaded = match T::load(key, self, &self.resources) {
+        let loaded = match self.load_tracked::<T>(key) {
             Ok(t) => t,
             Err(err) => {
                 // On an error, we unblock the other threads waiting for this resource
@@ -320,20 +870,23 @@ impl<'a, R: Resources<'a>> Cache<'a, R> {
             }
         };
 
-        if !loaded.deps.is_empty() {
-            let mut dependencies = self.dependencies.lock().unwrap();
-            dependencies
-                .entry(key.clone_static())
-                .or_default()
-                .extend(loaded.deps);
-        }
+        self.reloaders
+            .lock()
+            .unwrap()
+            .insert(TypeId::of::<T>(), Self::reload_asset::<T>);
+
+        let size = loaded.value.size_hint();
         let wrapped = Arc::new(ArcSwap::from_pointee(loaded.value));
 
         {
             let mut entries = self.entries.lock().unwrap();
             entries.entry(key.clone_static()).or_default().types.insert(
                 TypeId::of::<T>(),
-                ResourceState::Done(wrapped.clone() as Arc<dyn Any + Send + Sync>),
+                ResourceState::Done(LoadedEntry {
+                    value: wrapped.clone() as Arc<dyn Any + Send + Sync>,
+                    size,
+                    last_used: AtomicU64::new(frame),
+                }),
             );
         }
         signal_loaded.notify_all();
@@ -341,3 +894,296 @@ impl<'a, R: Resources<'a>> Cache<'a, R> {
         Ok(Cached(arc_swap::Cache::new(wrapped)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resources::SharedResources;
+
+    /// The text behind each path; `None` makes loading it fail.
+    struct Sources(Mutex<HashMap<String, Option<String>>>);
+
+    impl Sources {
+        fn set(&self, path: &str, source: Option<&str>) {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(path.to_owned(), source.map(str::to_owned));
+        }
+    }
+
+    /// Either plain text, or with a leading `=`, the concatenation of the nodes at a
+    /// comma-separated list of paths.
+    struct Node(String);
+
+    impl Asset for Node {
+        fn load<'a, R: Resources<'a>>(
+            key: &Key,
+            cache: &Cache<'a, R>,
+            resources: &R,
+        ) -> Result<Loaded<Self>> {
+            let path = key.to_path()?.to_str().unwrap().to_owned();
+            let source = resources
+                .fetch_one::<Sources>()?
+                .borrow()
+                .0
+                .lock()
+                .unwrap()
+                .get(&path)
+                .cloned()
+                .flatten()
+                .ok_or_else(|| anyhow!("no source for {}", path))?;
+
+            match source.strip_prefix('=') {
+                Some(paths) => {
+                    let mut joined = String::new();
+                    for path in paths.split(',') {
+                        joined.push_str(&cache.get::<Node>(&Key::from(Path::new(path)))?.load().0);
+                    }
+                    Ok(Loaded::new(Node(joined)))
+                }
+                None => Ok(Loaded::new(Node(source))),
+            }
+        }
+    }
+
+    fn cache(sources: &[(&str, &str)]) -> Cache<'static, SharedResources<'static>> {
+        let resources = SharedResources::new();
+        let map = sources
+            .iter()
+            .map(|&(path, source)| (path.to_owned(), Some(source.to_owned())))
+            .collect();
+        resources.borrow_mut().insert(Sources(Mutex::new(map)));
+        Cache::new(resources)
+    }
+
+    fn set_source(
+        cache: &Cache<'static, SharedResources<'static>>,
+        path: &str,
+        source: Option<&str>,
+    ) {
+        cache
+            .resources
+            .fetch_one::<Sources>()
+            .unwrap()
+            .borrow()
+            .set(path, source);
+    }
+
+    fn key(path: &str) -> Key<'static> {
+        Key::from(PathBuf::from(path))
+    }
+
+    #[test]
+    fn dependents_reload_after_their_dependencies() -> Result<()> {
+        let cache = cache(&[("/x", "x"), ("/b", "=/x"), ("/a", "=/x,/b")]);
+        let a = cache.get::<Node>(&key("/a"))?;
+        assert_eq!(a.load().0, "xx");
+
+        set_source(&cache, "/x", Some("y"));
+        let order = cache.reload(&key("/x"))?;
+        assert_eq!(order, vec![key("/x"), key("/b"), key("/a")]);
+        assert_eq!(a.load().0, "yy");
+
+        Ok(())
+    }
+
+    #[test]
+    fn failed_reloads_keep_their_dependencies() -> Result<()> {
+        let cache = cache(&[("/x", "x"), ("/b", "=/x")]);
+        let b = cache.get::<Node>(&key("/b"))?;
+
+        set_source(&cache, "/b", None);
+        assert!(cache.reload(&key("/b")).is_err());
+        assert_eq!(b.load().0, "x");
+        assert_eq!(cache.dependencies(&key("/b")), vec![key("/x")]);
+
+        set_source(&cache, "/b", Some("=/x,/x"));
+        set_source(&cache, "/x", Some("y"));
+        assert_eq!(cache.reload(&key("/x"))?, vec![key("/x"), key("/b")]);
+        assert_eq!(b.load().0, "yy");
+
+        Ok(())
+    }
+
+    #[test]
+    fn collect_evicts_least_recently_used_assets_first() -> Result<()> {
+        let cache = cache(&[("/a", "a"), ("/b", "b"), ("/c", "c")]);
+        let size = Node(String::new()).size_hint();
+
+        for path in &["/a", "/b", "/c", "/a"] {
+            cache.get::<Node>(&key(path))?;
+            cache.next_frame();
+        }
+
+        assert_eq!(
+            cache.usage(),
+            CacheUsage {
+                bytes: size * 3,
+                assets: 3,
+                budget: None,
+            }
+        );
+        assert_eq!(cache.collect(), 0, "there's no budget to be over");
+
+        cache.set_budget(size * 2);
+        assert_eq!(cache.collect(), size);
+        assert_eq!(cache.usage().assets, 2);
+        assert_eq!(cache.collect(), 0, "the cache is within its budget");
+
+        // `/b` was used least recently, so it's the one which was evicted.
+        for path in &["/a", "/b", "/c"] {
+            set_source(&cache, path, None);
+        }
+        assert!(cache.get::<Node>(&key("/a")).is_ok());
+        assert!(cache.get::<Node>(&key("/b")).is_err());
+        assert!(cache.get::<Node>(&key("/c")).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn collect_keeps_assets_in_use() -> Result<()> {
+        let cache = cache(&[("/a", "a"), ("/b", "b")]);
+        let size = Node(String::new()).size_hint();
+
+        let a = cache.get::<Node>(&key("/a"))?;
+        cache.next_frame();
+        cache.get::<Node>(&key("/b"))?;
+
+        cache.set_budget(0);
+        assert_eq!(cache.budget(), Some(0));
+        assert_eq!(cache.collect(), size);
+        assert_eq!(
+            cache.usage(),
+            CacheUsage {
+                bytes: size,
+                assets: 1,
+                budget: Some(0),
+            },
+            "`/a` is still held, so the cache stays over budget"
+        );
+
+        drop(a);
+        assert_eq!(cache.collect(), size);
+        assert_eq!(cache.usage().assets, 0);
+
+        cache.set_budget(None);
+        assert_eq!(cache.budget(), None);
+
+        Ok(())
+    }
+
+    /// An asset which can only be inserted into the cache, never loaded.
+    struct Count(u32);
+
+    impl Asset for Count {
+        fn load<'a, R: Resources<'a>>(
+            key: &Key,
+            _cache: &Cache<'a, R>,
+            _resources: &R,
+        ) -> Result<Loaded<Self>> {
+            bail!("counts can't be loaded: {}", key)
+        }
+    }
+
+    #[test]
+    fn keys_in_different_namespaces_never_collide() -> Result<()> {
+        let path = Key::path("minimap");
+        let structured = Key::structured(&"minimap")?;
+        let generated = Key::generated("minimap", &"minimap")?;
+        let other_type = Key::structured(&String::from("minimap"))?;
+
+        assert_eq!(path.namespace(), Namespace::Path);
+        assert_eq!(
+            structured.namespace(),
+            Namespace::Structured(any::type_name::<&str>())
+        );
+        assert_eq!(
+            generated.namespace(),
+            Namespace::Generated(Cow::Borrowed("minimap"))
+        );
+        assert_ne!(structured, generated);
+        assert_ne!(structured, other_type);
+
+        assert_eq!(other_type.to_rust::<String>()?, "minimap");
+        assert!(structured.to_rust::<String>().is_err());
+        assert_eq!(generated.to_rust::<String>()?, "minimap");
+        assert!(path.to_rust::<String>().is_err());
+        assert!(generated.to_path().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn generated_assets_are_inserted_and_replaced_in_place() -> Result<()> {
+        let cache = cache(&[("/a", "a")]);
+        let nodes = Key::generated("nodes", &1u32)?;
+
+        let node = cache.insert(&nodes, Node("first".to_owned()))?;
+        assert_eq!(cache.get::<Node>(&nodes)?.load().0, "first");
+
+        cache.insert(&nodes, Node("second".to_owned()))?;
+        assert_eq!(node.load().0, "second");
+
+        let err = cache.insert(&key("/a"), Node(String::new())).unwrap_err();
+        assert!(err.to_string().contains("only generated keys"), "{}", err);
+
+        // The `nodes` namespace belongs to `Node`s now.
+        let other = Key::generated("nodes", &2u32)?;
+        let err = cache.insert(&other, Count(2)).unwrap_err();
+        assert!(
+            err.to_string().contains("belongs to assets of type"),
+            "{}",
+            err
+        );
+        assert!(cache.get::<Count>(&other).is_err());
+
+        let count = Key::generated("counts", &1u32)?;
+        assert_eq!(cache.insert(&count, Count(1))?.load().0, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn namespaces_are_listed_and_evicted_separately() -> Result<()> {
+        let cache = cache(&[("/a", "a"), ("/b", "b")]);
+        let size = Node(String::new()).size_hint();
+
+        let a = cache.get::<Node>(&key("/a"))?;
+        cache.get::<Node>(&key("/b"))?;
+        let generated = Key::generated("nodes", &0u32)?;
+        cache.insert(&generated, Node("generated".to_owned()))?;
+
+        let mut namespaces = cache.namespaces();
+        namespaces.sort_by_key(|namespace| namespace.to_string());
+        assert_eq!(
+            namespaces,
+            vec![
+                Namespace::Generated(Cow::Borrowed("nodes")),
+                Namespace::Path,
+            ]
+        );
+
+        let mut paths = cache.keys_in(&Namespace::Path);
+        paths.sort_by_key(|(key, _, _)| key.to_string());
+        assert_eq!(
+            paths,
+            vec![(key("/a"), size, true), (key("/b"), size, false)]
+        );
+
+        assert_eq!(cache.evict_namespace(&Namespace::Path), size);
+        assert_eq!(cache.keys_in(&Namespace::Path).len(), 1);
+        assert_eq!(
+            cache.keys_in(&generated.namespace()),
+            vec![(generated.clone(), size, false)]
+        );
+
+        drop(a);
+        assert_eq!(cache.evict_namespace(&Namespace::Path), size);
+        assert!(cache.keys_in(&Namespace::Path).is_empty());
+        assert_eq!(cache.namespaces(), vec![generated.namespace()]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+//! Messaging between [`Space`](crate::Space)s which don't share a Lua state.
+//!
+//! A [`SpaceBus`] is intended to live in the global resources shared by several
+//! spaces. Messages are published to string-named channels with a serde-encoded
+//! payload, and every space which has subscribed to a channel receives a copy.
+//!
+//! From Lua, `sludge.bus.subscribe(channel, event_name)` asks for messages on
+//! `channel` to be broadcast on the space's scheduler as `event_name` (which
+//! defaults to the channel name), with the decoded payload as the event's only
+//! argument. `sludge.bus.publish(channel, value)` encodes `value` and publishes it.
+//! Delivery into the space's scheduler happens when the space is maintained, so a
+//! message published on one update is seen by other spaces on their next update.
+
+use {
+    anyhow::*,
+    crossbeam_channel::{Receiver, Sender},
+    hashbrown::HashMap,
+    rlua::prelude::*,
+    serde::{de::DeserializeOwned, Deserialize, Serialize},
+    std::sync::{Arc, Mutex},
+};
+
+use crate::{OwnedResources, Resources, SchedulerQueue, SharedResources, UnifiedResources};
+
+/// A message sent over a [`SpaceBus`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BusMessage {
+    /// The name of the channel the message was published on.
+    pub channel: String,
+    /// The serde-encoded payload of the message.
+    pub payload: serde_json::Value,
+}
+
+impl BusMessage {
+    /// Decode the payload of the message into some concrete type.
+    pub fn decode<T: DeserializeOwned>(&self) -> Result<T> {
+        Ok(serde_json::from_value(self.payload.clone())?)
+    }
+}
+
+/// A shared publish/subscribe message bus, for communicating between spaces.
+/// Cloning a `SpaceBus` gives another handle to the same bus.
+#[derive(Debug, Clone, Default)]
+pub struct SpaceBus {
+    channels: Arc<Mutex<HashMap<String, Vec<Sender<BusMessage>>>>>,
+}
+
+impl SpaceBus {
+    /// Create a new bus with no subscribers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribe to a channel, returning a receiver which will get a copy of every
+    /// message published on the channel from now on. Dropping the receiver
+    /// unsubscribes.
+    pub fn subscribe(&self, channel: &str) -> Receiver<BusMessage> {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        self.channels
+            .lock()
+            .unwrap()
+            .entry(channel.to_owned())
+            .or_default()
+            .push(sender);
+        receiver
+    }
+
+    /// Encode a payload and publish it on a channel.
+    pub fn publish<T: Serialize + ?Sized>(&self, channel: &str, payload: &T) -> Result<()> {
+        self.publish_value(channel, serde_json::to_value(payload)?);
+        Ok(())
+    }
+
+    /// Publish an already encoded payload on a channel.
+    pub fn publish_value(&self, channel: &str, payload: serde_json::Value) {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(subscribers) = channels.get_mut(channel) {
+            let message = BusMessage {
+                channel: channel.to_owned(),
+                payload,
+            };
+
+            // Any subscriber whose receiver has been dropped is removed here.
+            subscribers.retain(|sender| sender.send(message.clone()).is_ok());
+        }
+    }
+}
+
+/// The subscriptions of a single space to a [`SpaceBus`], mapping incoming messages
+/// to scheduler events.
+#[derive(Debug, Default)]
+pub struct BusSubscriptions {
+    subscriptions: Vec<Subscription>,
+}
+
+#[derive(Debug)]
+struct Subscription {
+    channel: String,
+    event_name: String,
+    receiver: Receiver<BusMessage>,
+}
+
+impl BusSubscriptions {
+    /// Create an empty set of subscriptions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribe to `channel` on `bus`, broadcasting received messages as `event_name`.
+    pub fn subscribe(&mut self, bus: &SpaceBus, channel: &str, event_name: &str) {
+        self.subscriptions.push(Subscription {
+            channel: channel.to_owned(),
+            event_name: event_name.to_owned(),
+            receiver: bus.subscribe(channel),
+        });
+    }
+
+    /// Drop all subscriptions to `channel`. Messages which were received but not yet
+    /// delivered are discarded.
+    pub fn unsubscribe(&mut self, channel: &str) {
+        self.subscriptions.retain(|sub| sub.channel != channel);
+    }
+
+    /// Broadcast all received messages into a scheduler queue.
+    pub fn deliver<'lua>(&self, lua: LuaContext<'lua>, queue: &SchedulerQueue) -> Result<()> {
+        for sub in &self.subscriptions {
+            for message in sub.receiver.try_iter() {
+                let payload = rlua_serde::to_value(lua, &message.payload)?;
+                queue.broadcast(lua, &sub.event_name, payload)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A system which delivers messages received from the [`SpaceBus`] into the space's
+/// scheduler. It does nothing if the space has no `BusSubscriptions`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpaceBusSystem;
+
+impl crate::System for SpaceBusSystem {
+    fn init(
+        &self,
+        _lua: LuaContext,
+        resources: &mut OwnedResources,
+        _: Option<&SharedResources>,
+    ) -> Result<()> {
+        if !resources.has_value::<BusSubscriptions>() {
+            resources.insert(BusSubscriptions::new());
+        }
+        Ok(())
+    }
+
+    fn update(&self, lua: LuaContext, resources: &UnifiedResources) -> Result<()> {
+        if let Ok(subscriptions) = resources.fetch_one::<BusSubscriptions>() {
+            let queue = resources.fetch_one::<SchedulerQueue>()?;
+            subscriptions.borrow().deliver(lua, &queue.borrow())?;
+        }
+
+        Ok(())
+    }
+}
+
+fn publish<'lua>(
+    lua: LuaContext<'lua>,
+    (channel, value): (String, LuaValue<'lua>),
+) -> LuaResult<()> {
+    let payload = rlua_serde::from_value::<serde_json::Value>(value)?;
+    lua.fetch_one::<SpaceBus>()?
+        .borrow()
+        .publish_value(&channel, payload);
+    Ok(())
+}
+
+fn subscribe<'lua>(
+    lua: LuaContext<'lua>,
+    (channel, event_name): (String, Option<String>),
+) -> LuaResult<()> {
+    let (bus, subscriptions) = lua.fetch::<(SpaceBus, BusSubscriptions)>()?;
+    subscriptions.borrow_mut().subscribe(
+        &bus.borrow(),
+        &channel,
+        event_name.as_deref().unwrap_or(&channel),
+    );
+    Ok(())
+}
+
+fn unsubscribe<'lua>(lua: LuaContext<'lua>, channel: String) -> LuaResult<()> {
+    lua.fetch_one::<BusSubscriptions>()?
+        .borrow_mut()
+        .unsubscribe(&channel);
+    Ok(())
+}
+
+inventory::submit! {
+    crate::api::Module::parse("sludge.bus", |lua| {
+        let table = lua.create_table_from(vec![
+            ("publish", lua.create_function(publish)?),
+            ("subscribe", lua.create_function(subscribe)?),
+            ("unsubscribe", lua.create_function(unsubscribe)?),
+        ])?;
+
+        Ok(LuaValue::Table(table))
+    })
+}
@@ -1,4 +1,9 @@
-use {rlua::prelude::*, serde::*, sludge_macros::SimpleComponent};
+use {
+    serde::*,
+    sludge_macros::{LuaAccessor, SimpleComponent},
+};
+
+use crate::reflect::ReflectedComponent;
 
 pub use crate::{
     api::*,
@@ -7,11 +12,11 @@ pub use crate::{
     math::*,
     resources::Resources,
     sprite::{SpriteFrame, SpriteName, SpriteTag},
-    transform::Transform,
+    transform::{Placement2, Transform, Transform2d},
     SludgeLuaContextExt,
 };
 
-#[derive(Debug, Clone, Serialize, Deserialize, SimpleComponent)]
+#[derive(Debug, Clone, Serialize, Deserialize, SimpleComponent, LuaAccessor)]
 pub struct Name(pub String);
 
 impl Name {
@@ -20,68 +25,234 @@ impl Name {
     }
 }
 
-pub struct NameAccessor(Entity);
-
-impl LuaUserData for NameAccessor {
-    fn add_methods<'lua, T: LuaUserDataMethods<'lua, Self>>(methods: &mut T) {
-        methods.add_method("get", |lua, this, ()| {
-            let tmp = lua.fetch_one::<World>()?;
-            let world = tmp.borrow();
-            let name = world.get::<Name>(this.0).to_lua_err()?;
-            name.0.as_str().to_lua(lua)
-        });
-
-        methods.add_method("to_table", |lua, this, ()| {
-            let tmp = lua.fetch_one::<World>()?;
-            let world = tmp.borrow();
-            let name = world.get::<Name>(this.0).to_lua_err()?;
-            rlua_serde::to_value(lua, &*name)
-        });
-    }
+inventory::submit! {
+    LuaComponent::new::<Name>("Name")
 }
 
-impl LuaComponentInterface for Name {
-    fn accessor<'lua>(lua: LuaContext<'lua>, entity: Entity) -> LuaResult<LuaValue<'lua>> {
-        NameAccessor(entity).to_lua(lua)
-    }
+inventory::submit! {
+    ReflectedComponent::new::<Name>("Name")
+}
 
-    fn bundler<'lua>(
-        lua: LuaContext<'lua>,
-        args: LuaValue<'lua>,
-        builder: &mut EntityBuilder,
-    ) -> LuaResult<()> {
-        let name = String::from_lua(args, lua)?;
-        builder.add(Name(name));
-        Ok(())
-    }
+/// A tag marking an entity which should be saved when its `Space` is persisted.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, SimpleComponent)]
+pub struct Persistent;
+
+inventory::submit! {
+    LuaComponent::tag::<Persistent>("Persistent")
 }
 
 inventory::submit! {
-    LuaComponent::new::<Name>("Name")
+    ReflectedComponent::new::<Persistent>("Persistent")
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, SimpleComponent)]
-pub struct Persistent;
+inventory::submit! {
+    LuaComponent::tag::<Disabled>("Disabled")
+}
+
+inventory::submit! {
+    ReflectedComponent::new::<Disabled>("Disabled")
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::Space, anyhow::Result, rlua::prelude::*, sludge_macros::LuaBundle};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, SimpleComponent, LuaAccessor)]
+    struct Stats {
+        hp: i32,
+        #[lua(readonly)]
+        max_hp: i32,
+        #[lua(rename = "label")]
+        name: String,
+        #[lua(skip)]
+        secret: u32,
+    }
+
+    inventory::submit! {
+        LuaComponent::new::<Stats>("TestStats")
+    }
 
-pub struct PersistentAccessor(Entity);
+    fn mook_stats() -> Stats {
+        Stats {
+            hp: 1,
+            max_hp: 1,
+            name: "mook".to_owned(),
+            secret: 0,
+        }
+    }
+
+    #[allow(dead_code)]
+    #[derive(LuaBundle)]
+    #[lua(name = "TestMook")]
+    struct Mook {
+        #[lua(rename = "MookStats", default = "mook_stats")]
+        stats: Stats,
+        #[lua(optional)]
+        name: Name,
+    }
+
+    fn with_accessor<T>(
+        source: &str,
+        f: impl for<'lua> FnOnce(LuaContext<'lua>, &Space, Entity) -> Result<T>,
+    ) -> Result<T> {
+        let space = Space::new()?;
+        space.lua().context(|lua| {
+            let entity = Entity::from(lua.load(source).eval::<LuaEntity>()?);
+            lua.globals()
+                .set("accessor", Stats::accessor(lua, entity)?)?;
+            f(lua, &space, entity)
+        })
+    }
+
+    #[test]
+    fn derived_accessors_read_and_write_fields() -> Result<()> {
+        with_accessor(
+            r#"return sludge.spawn {
+                TestStats = { hp = 3, max_hp = 10, name = "slime", secret = 7 },
+            }"#,
+            |lua, space, entity| {
+                lua.load(
+                    r#"
+                    assert(accessor.hp == 3)
+                    assert(accessor.max_hp == 10)
+                    assert(accessor.label == "slime")
+                    assert(accessor.name == nil)
+                    assert(accessor.secret == nil)
+                    accessor.hp = accessor.hp - 1
+                    accessor.label = "big slime"
+                    "#,
+                )
+                .exec()?;
+
+                let stats = space.world()?.borrow().get::<Stats>(entity)?.clone();
+                assert_eq!(
+                    stats,
+                    Stats {
+                        hp: 2,
+                        max_hp: 10,
+                        name: "big slime".to_owned(),
+                        secret: 7,
+                    }
+                );
 
-impl LuaUserData for PersistentAccessor {}
+                let err = lua.load("accessor.max_hp = 20").exec().unwrap_err();
+                assert!(format!("{:?}", err).contains("read-only"), "{:?}", err);
+                let err = lua.load("accessor.secret = 0").exec().unwrap_err();
+                assert!(format!("{:?}", err).contains("no such field"), "{:?}", err);
+                assert_eq!(space.world()?.borrow().get::<Stats>(entity)?.max_hp, 10);
 
-impl LuaComponentInterface for Persistent {
-    fn accessor<'lua>(lua: LuaContext<'lua>, entity: Entity) -> LuaResult<LuaValue<'lua>> {
-        PersistentAccessor(entity).to_lua(lua)
+                Ok(())
+            },
+        )
     }
 
-    fn bundler<'lua>(
-        _lua: LuaContext<'lua>,
-        _args: LuaValue<'lua>,
-        builder: &mut EntityBuilder,
-    ) -> LuaResult<()> {
-        builder.add(Persistent);
+    #[test]
+    fn derived_accessors_round_trip_through_tables() -> Result<()> {
+        with_accessor(
+            r#"return sludge.spawn {
+                TestStats = { hp = 5, max_hp = 5, name = "bat", secret = 1 },
+            }"#,
+            |lua, space, entity| {
+                let copy = lua
+                    .load("return sludge.spawn { TestStats = accessor:to_table() }")
+                    .eval::<LuaEntity>()?;
+
+                let world = space.world()?;
+                let world = world.borrow();
+                assert_eq!(
+                    *world.get::<Stats>(copy.into())?,
+                    *world.get::<Stats>(entity)?
+                );
+
+                Ok(())
+            },
+        )
+    }
+
+    #[test]
+    fn newtype_accessors_use_get_and_set() -> Result<()> {
+        let space = Space::new()?;
+        space.lua().context(|lua| -> Result<()> {
+            let entity = Entity::from(
+                lua.load(r#"return sludge.spawn { Name = "player" }"#)
+                    .eval::<LuaEntity>()?,
+            );
+            lua.globals().set("name", Name::accessor(lua, entity)?)?;
+            lua.load(
+                r#"
+                assert(name:get() == "player")
+                name:set("hero")
+                assert(name:to_table() == "hero")
+                "#,
+            )
+            .exec()?;
+            assert_eq!(space.world()?.borrow().get::<Name>(entity)?.0, "hero");
+
+            Ok(())
+        })
+    }
+
+    fn spawn_mook(space: &Space, source: &str) -> Result<Entity> {
+        let entity = space
+            .lua()
+            .context(|lua| lua.load(source).eval::<LuaEntity>())?;
+        Ok(entity.into())
+    }
+
+    #[test]
+    fn bundles_fill_in_defaults() -> Result<()> {
+        let space = Space::new()?;
+        for source in &[
+            "return sludge.spawn { TestMook = {} }",
+            "return sludge.spawn { TestMook = true }",
+        ] {
+            let mook = spawn_mook(&space, source)?;
+            let world = space.world()?;
+            let world = world.borrow();
+            assert_eq!(*world.get::<Stats>(mook)?, mook_stats());
+            assert!(world.get::<Name>(mook).is_err());
+        }
+
         Ok(())
     }
-}
 
-inventory::submit! {
-    LuaComponent::new::<Persistent>("Persistent")
+    #[test]
+    fn bundles_read_fields_and_extra_components() -> Result<()> {
+        let space = Space::new()?;
+        let boss = spawn_mook(
+            &space,
+            r#"return sludge.spawn {
+                TestMook = {
+                    MookStats = { hp = 50, max_hp = 50, name = "boss", secret = 3 },
+                    Name = "Big Mook",
+                    Persistent = true,
+                },
+            }"#,
+        )?;
+
+        {
+            let world = space.world()?;
+            let world = world.borrow();
+            assert_eq!(world.get::<Stats>(boss)?.hp, 50);
+            assert_eq!(world.get::<Name>(boss)?.0, "Big Mook");
+            assert!(world.get::<Persistent>(boss).is_ok());
+        }
+
+        let err =
+            spawn_mook(&space, "return sludge.spawn { TestMook = { Bogus = 1 } }").unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("unknown component Bogus"),
+            "{:#}",
+            err
+        );
+
+        let err = spawn_mook(
+            &space,
+            "return sludge.spawn { TestMook = { MookStats = 5 } }",
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("TestMook.stats"), "{:#}", err);
+
+        Ok(())
+    }
 }
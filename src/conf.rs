@@ -1,10 +1,16 @@
 use serde::{Deserialize, Serialize};
 
+/// Settings used to create the window. These are only consulted at startup; to change
+/// the window while the game is running, see the window methods on `Graphics`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Conf {
     pub window_title: String,
     pub window_width: u32,
     pub window_height: u32,
+    pub fullscreen: bool,
+    pub high_dpi: bool,
+    pub vsync: bool,
 }
 
 impl Default for Conf {
@@ -13,6 +19,9 @@ impl Default for Conf {
             window_title: "SLUDGE \\m/".to_string(),
             window_width: 800,
             window_height: 680,
+            fullscreen: false,
+            high_dpi: false,
+            vsync: true,
         }
     }
 }
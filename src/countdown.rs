@@ -0,0 +1,335 @@
+//! Named countdowns and stopwatches which survive saving and loading. Not to be confused
+//! with [`timer`](crate::timer), which keeps track of frame timing.
+//!
+//! The scheduler's clock counts ticks, which is what you want for gameplay scripted
+//! with `yield(n)`, but some things are better measured in real time: a speedrun clock,
+//! a ten-second bomb, the time left on a level. A [`Timers`] resource holds any number
+//! of named [`Timer`]s, each either a countdown or a stopwatch, advanced by [`update`]
+//! with however much time passed since the last frame.
+//!
+//! Every [`Space`](crate::Space) has a `Timers` resource. Its state is saved along with
+//! the space by [`Space::save`](crate::Space::save) and restored by
+//! [`Space::load`](crate::Space::load). Nothing advances it on its own; call [`update`]
+//! once per frame from the game loop:
+//!
+//! ```ignore
+//! space.lua().context(|lua| sludge::countdown::update(lua, dt))?;
+//! ```
+//!
+//! Timers stand still while their [pause channel](Timers::set_pause_channel) is paused,
+//! which is [`pause::GAMEPLAY`] by default. When a countdown runs out, [`update`]
+//! broadcasts [`FINISHED_EVENT`] with the countdown's name. A finished countdown stays
+//! at zero until it's restarted or stopped.
+//!
+//! From Lua, timers are accessed through the `sludge.timer` module:
+//!
+//! ```lua
+//! sludge.timer.start("bomb", 10)
+//! sludge.timer.stopwatch("speedrun")
+//!
+//! sludge.thread.spawn(function()
+//!     repeat
+//!         local _, _, name = yield(sludge.timer.FINISHED_EVENT)
+//!     until name == "bomb"
+//!     explode()
+//! end)
+//!
+//! print(sludge.timer.remaining("bomb"), sludge.timer.elapsed("speedrun"))
+//! ```
+
+use {
+    anyhow::*,
+    rlua::prelude::*,
+    serde::{Deserialize, Serialize},
+    std::collections::BTreeMap,
+};
+
+use crate::{pause, SludgeLuaContextExt};
+
+/// Broadcast with a countdown's name when it runs out.
+pub const FINISHED_EVENT: &'static str = "sludge.timer.finished";
+
+/// Whether a [`Timer`] counts down to zero or up from it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TimerKind {
+    Countdown { duration: f32 },
+    Stopwatch,
+}
+
+/// A single countdown or stopwatch.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Timer {
+    pub kind: TimerKind,
+    /// How much time has passed while the timer was running.
+    pub elapsed: f32,
+    pub paused: bool,
+}
+
+impl Timer {
+    pub fn countdown(duration: f32) -> Self {
+        Self {
+            kind: TimerKind::Countdown { duration },
+            elapsed: 0.,
+            paused: false,
+        }
+    }
+
+    pub fn stopwatch() -> Self {
+        Self {
+            kind: TimerKind::Stopwatch,
+            elapsed: 0.,
+            paused: false,
+        }
+    }
+
+    /// How much time a countdown has left, or `None` for a stopwatch.
+    pub fn remaining(&self) -> Option<f32> {
+        match self.kind {
+            TimerKind::Countdown { duration } => Some((duration - self.elapsed).max(0.)),
+            TimerKind::Stopwatch => None,
+        }
+    }
+
+    /// Whether this is a countdown which has run out.
+    pub fn is_finished(&self) -> bool {
+        self.remaining() == Some(0.)
+    }
+}
+
+/// A collection of named timers. See the [module documentation](self) for details.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Timers {
+    timers: BTreeMap<String, Timer>,
+    pause_channel: Option<String>,
+}
+
+impl Default for Timers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Self {
+            timers: BTreeMap::new(),
+            pause_channel: Some(pause::GAMEPLAY.to_owned()),
+        }
+    }
+
+    /// The pause channel which stops every timer. Defaults to [`pause::GAMEPLAY`].
+    pub fn pause_channel(&self) -> Option<&str> {
+        self.pause_channel.as_deref()
+    }
+
+    /// Set the pause channel which stops every timer, or `None` to keep them running no
+    /// matter what's paused.
+    pub fn set_pause_channel(&mut self, channel: Option<&str>) {
+        self.pause_channel = channel.map(str::to_owned);
+    }
+
+    /// Start a countdown, replacing any timer with the same name.
+    pub fn start(&mut self, name: &str, duration: f32) {
+        self.timers
+            .insert(name.to_owned(), Timer::countdown(duration));
+    }
+
+    /// Start a stopwatch, replacing any timer with the same name.
+    pub fn start_stopwatch(&mut self, name: &str) {
+        self.timers.insert(name.to_owned(), Timer::stopwatch());
+    }
+
+    /// Remove a timer. Returns `true` if it existed.
+    pub fn stop(&mut self, name: &str) -> bool {
+        self.timers.remove(name).is_some()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Timer> {
+        self.timers.get(name)
+    }
+
+    /// How much time a countdown has left. `None` if there's no such countdown.
+    pub fn remaining(&self, name: &str) -> Option<f32> {
+        self.get(name).and_then(Timer::remaining)
+    }
+
+    /// How much time a timer has been running for. `None` if there's no such timer.
+    pub fn elapsed(&self, name: &str) -> Option<f32> {
+        self.get(name).map(|timer| timer.elapsed)
+    }
+
+    /// Pause or resume a single timer. Returns `true` if its state changed.
+    pub fn set_paused(&mut self, name: &str, paused: bool) -> bool {
+        match self.timers.get_mut(name) {
+            Some(timer) if timer.paused != paused => {
+                timer.paused = paused;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Every timer, in order of name.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Timer)> + '_ {
+        self.timers
+            .iter()
+            .map(|(name, timer)| (name.as_str(), timer))
+    }
+
+    /// Advance every running timer by `dt`, returning the names of the countdowns which
+    /// ran out, in order of name. This ignores the pause channel; see [`update`].
+    pub fn tick(&mut self, dt: f32) -> Vec<String> {
+        let mut finished = Vec::new();
+
+        for (name, timer) in self.timers.iter_mut() {
+            if timer.paused || timer.is_finished() {
+                continue;
+            }
+
+            timer.elapsed += dt;
+            if timer.is_finished() {
+                finished.push(name.clone());
+            }
+        }
+
+        finished
+    }
+}
+
+/// Advance the space's [`Timers`] by `dt`, unless their pause channel is paused, and
+/// broadcast [`FINISHED_EVENT`] for every countdown which ran out.
+pub fn update(lua: LuaContext, dt: f32) -> Result<()> {
+    let finished = {
+        let timers = lua.fetch_one::<Timers>()?;
+        let mut timers = timers.borrow_mut();
+        let paused = timers
+            .pause_channel()
+            .map_or(false, |channel| pause::is_paused(&lua, channel));
+
+        if paused {
+            return Ok(());
+        }
+
+        timers.tick(dt)
+    };
+
+    for name in finished {
+        lua.broadcast(FINISHED_EVENT, name)?;
+    }
+
+    Ok(())
+}
+
+/// Record the state of the space's `Timers` for persistence, if it has one.
+pub(crate) fn record<'lua>(lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+    match lua.fetch_one::<Timers>() {
+        Ok(timers) => rlua_serde::to_value(lua, &*timers.borrow()),
+        Err(_) => Ok(LuaValue::Nil),
+    }
+}
+
+/// Restore the state recorded by [`record`] into the space's `Timers`.
+pub(crate) fn playback<'lua>(lua: LuaContext<'lua>, value: LuaValue<'lua>) -> Result<()> {
+    if let LuaValue::Nil = value {
+        return Ok(());
+    }
+
+    let restored = rlua_serde::from_value::<Timers>(value)?;
+    *lua.fetch_one::<Timers>()?.borrow_mut() = restored;
+    Ok(())
+}
+
+inventory::submit! {
+    crate::api::Module::parse("sludge.timer", |lua| {
+        let table = lua.create_table()?;
+
+        table.set(
+            "start",
+            lua.create_function(|lua, (name, duration): (String, f32)| {
+                lua.fetch_one::<Timers>()?.borrow_mut().start(&name, duration);
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "stopwatch",
+            lua.create_function(|lua, name: String| {
+                lua.fetch_one::<Timers>()?.borrow_mut().start_stopwatch(&name);
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "stop",
+            lua.create_function(|lua, name: String| {
+                Ok(lua.fetch_one::<Timers>()?.borrow_mut().stop(&name))
+            })?,
+        )?;
+
+        table.set(
+            "remaining",
+            lua.create_function(|lua, name: String| {
+                Ok(lua.fetch_one::<Timers>()?.borrow().remaining(&name))
+            })?,
+        )?;
+
+        table.set(
+            "elapsed",
+            lua.create_function(|lua, name: String| {
+                Ok(lua.fetch_one::<Timers>()?.borrow().elapsed(&name))
+            })?,
+        )?;
+
+        table.set(
+            "is_finished",
+            lua.create_function(|lua, name: String| {
+                let timers = lua.fetch_one::<Timers>()?;
+                let finished = timers.borrow().get(&name).map_or(false, Timer::is_finished);
+                Ok(finished)
+            })?,
+        )?;
+
+        table.set(
+            "pause",
+            lua.create_function(|lua, name: String| {
+                Ok(lua.fetch_one::<Timers>()?.borrow_mut().set_paused(&name, true))
+            })?,
+        )?;
+
+        table.set(
+            "resume",
+            lua.create_function(|lua, name: String| {
+                Ok(lua.fetch_one::<Timers>()?.borrow_mut().set_paused(&name, false))
+            })?,
+        )?;
+
+        table.set("FINISHED_EVENT", FINISHED_EVENT)?;
+
+        Ok(LuaValue::Table(table))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn countdowns_finish_once() {
+        let mut timers = Timers::new();
+        timers.start("bomb", 1.);
+        timers.start_stopwatch("clock");
+
+        assert!(timers.tick(0.5).is_empty());
+        assert!(timers.set_paused("clock", true));
+        assert_eq!(timers.tick(0.75), vec!["bomb".to_owned()]);
+        assert!(timers.tick(1.).is_empty());
+
+        assert_eq!(timers.remaining("bomb"), Some(0.));
+        assert_eq!(timers.elapsed("clock"), Some(0.5));
+        assert_eq!(timers.remaining("clock"), None);
+
+        let restored =
+            serde_json::from_str::<Timers>(&serde_json::to_string(&timers).unwrap()).unwrap();
+        assert_eq!(restored, timers);
+    }
+}
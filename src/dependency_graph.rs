@@ -1,5 +1,23 @@
 use crate::Atom;
-use {anyhow::*, hashbrown::HashMap, petgraph::prelude::*, std::borrow::Borrow};
+use {
+    anyhow::*,
+    hashbrown::HashMap,
+    petgraph::prelude::*,
+    std::{borrow::Borrow, collections::VecDeque, fmt::Write},
+};
+
+/// The resolved shape of a [`DependencyGraph`], for diagnostics.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResolvedGraph {
+    /// Every node, in the order they run.
+    pub order: Vec<String>,
+    /// Every dependency between two nodes, as `(dependency, dependent)`; the dependency
+    /// always runs first.
+    pub edges: Vec<(String, String)>,
+    /// Dependencies on nodes which don't exist, as `(dependency, dependent)`. These are
+    /// ignored when ordering the graph.
+    pub missing: Vec<(String, String)>,
+}
 
 #[derive(Debug, Clone)]
 pub struct Node {
@@ -64,13 +82,19 @@ impl<T> DependencyGraph<T> {
             }
         }
 
-        self.sorted = petgraph::algo::toposort(&self.graph, None).map_err(|cycle| {
-            let node = &self.graph[cycle.node_id()].0;
+        let sorted = petgraph::algo::toposort(&self.graph, None);
+        self.sorted = sorted.map_err(|cycle| {
+            let path = self
+                .find_cycle(cycle.node_id())
+                .into_iter()
+                .map(|index| format!("`{}`", self.graph[index].0))
+                .collect::<Vec<_>>()
+                .join(" -> ");
             anyhow!(
-                "A cycle was found which includes the node `{}`, \
+                "A dependency cycle was found ({}), \
                 but the dependency graph must be acyclic to allow \
                 a proper ordering of dependencies!",
-                node
+                path
             )
         })?;
         self.changed = false;
@@ -78,6 +102,86 @@ impl<T> DependencyGraph<T> {
         Ok(true)
     }
 
+    /// Find a path of edges leading from `start` back around to itself. Returns just
+    /// `start` if there isn't one.
+    fn find_cycle(&self, start: NodeIndex) -> Vec<NodeIndex> {
+        let mut parents = HashMap::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(start);
+
+        while let Some(node) = queue.pop_front() {
+            for next in self.graph.neighbors_directed(node, Outgoing) {
+                if next == start {
+                    let mut path = vec![node];
+                    let mut current = node;
+                    while current != start {
+                        current = parents[&current];
+                        path.push(current);
+                    }
+                    path.reverse();
+                    path.push(start);
+                    return path;
+                }
+
+                if !parents.contains_key(&next) {
+                    parents.insert(next, node);
+                    queue.push_back(next);
+                }
+            }
+        }
+
+        vec![start]
+    }
+
+    /// The order nodes run in along with their dependencies. Panics if the graph has
+    /// been changed without being updated.
+    pub fn resolved(&self) -> ResolvedGraph {
+        let mut resolved = ResolvedGraph {
+            order: self.sorted().map(|(name, _)| name.to_owned()).collect(),
+            ..ResolvedGraph::default()
+        };
+
+        for (name, node) in self.indices.iter() {
+            for dep in node.deps.iter() {
+                let edge = (dep.to_string(), name.to_string());
+                if self.indices.contains_key(dep) {
+                    resolved.edges.push(edge);
+                } else {
+                    resolved.missing.push(edge);
+                }
+            }
+        }
+
+        resolved.edges.sort();
+        resolved.missing.sort();
+        resolved
+    }
+
+    /// Render the resolved graph in Graphviz's dot format. Nodes are labeled with
+    /// their position in the order, and missing dependencies are drawn dashed.
+    pub fn graphviz(&self) -> String {
+        let resolved = self.resolved();
+        let quote = |s: &str| format!("\"{}\"", s.replace('"', "\\\""));
+        let mut out = String::from("digraph dependencies {\n");
+
+        for (i, name) in resolved.order.iter().enumerate() {
+            let label = quote(&format!("{}: {}", i, name));
+            writeln!(out, "    {} [label={}];", quote(name), label).unwrap();
+        }
+
+        for (dep, name) in resolved.edges.iter() {
+            writeln!(out, "    {} -> {};", quote(dep), quote(name)).unwrap();
+        }
+
+        for (dep, name) in resolved.missing.iter() {
+            writeln!(out, "    {} [style=dashed];", quote(dep)).unwrap();
+            writeln!(out, "    {} -> {} [style=dashed];", quote(dep), quote(name)).unwrap();
+        }
+
+        out.push_str("}\n");
+        out
+    }
+
     pub fn sorted(&self) -> impl Iterator<Item = (&str, &T)> {
         assert!(!self.changed);
         self.sorted.iter().copied().map(move |index| {
@@ -86,3 +190,43 @@ impl<T> DependencyGraph<T> {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cycle_error_names_path() {
+        let mut graph = DependencyGraph::new();
+        graph.insert((), "a", vec!["c"]).unwrap();
+        graph.insert((), "b", vec!["a"]).unwrap();
+        graph.insert((), "c", vec!["b"]).unwrap();
+        graph.insert((), "d", Vec::<&str>::new()).unwrap();
+
+        let message = graph.update().unwrap_err().to_string();
+        assert!(
+            [
+                "`a` -> `b` -> `c` -> `a`",
+                "`b` -> `c` -> `a` -> `b`",
+                "`c` -> `a` -> `b` -> `c`"
+            ]
+            .iter()
+            .any(|path| message.contains(path)),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn resolved_order_and_edges() {
+        let mut graph = DependencyGraph::new();
+        graph.insert((), "b", vec!["a", "z"]).unwrap();
+        graph.insert((), "a", Vec::<&str>::new()).unwrap();
+        graph.update().unwrap();
+
+        let resolved = graph.resolved();
+        assert_eq!(resolved.order, vec!["a", "b"]);
+        assert_eq!(resolved.edges, vec![("a".to_owned(), "b".to_owned())]);
+        assert_eq!(resolved.missing, vec![("z".to_owned(), "b".to_owned())]);
+    }
+}
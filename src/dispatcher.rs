@@ -1,20 +1,66 @@
 use crate::{
-    dependency_graph::DependencyGraph, OwnedResources, SharedResources, System, UnifiedResources,
+    dependency_graph::{DependencyGraph, ResolvedGraph},
+    OwnedResources, SharedResources, System, UnifiedResources,
 };
-use {anyhow::*, rlua::prelude::*};
+use {anyhow::*, hashbrown::HashMap, rlua::prelude::*};
+
+/// Which part of the game loop a system runs in.
+///
+/// Systems in the fixed stage run once per fixed tick, which is where anything that
+/// steps the simulation (physics, danmaku, lifetimes) belongs; systems in the frame stage
+/// run once per rendered frame, for things like interpolation and UI which should keep
+/// up with the display however many ticks a frame takes. Ordering between systems is
+/// the same in every stage; a system's dependencies only constrain its order in the
+/// stages they share with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stage {
+    Fixed,
+    Frame,
+    Both,
+}
+
+impl Default for Stage {
+    fn default() -> Self {
+        Stage::Fixed
+    }
+}
+
+impl Stage {
+    /// Whether a system tagged with this stage runs when `stage` is run.
+    pub fn includes(self, stage: Stage) -> bool {
+        self == Stage::Both || stage == Stage::Both || self == stage
+    }
+}
 
 pub struct Dispatcher<'a> {
     dependency_graph: DependencyGraph<Box<dyn System + 'a>>,
+    stages: HashMap<String, Stage>,
 }
 
 impl<'a> Dispatcher<'a> {
     pub fn new() -> Self {
         Self {
             dependency_graph: DependencyGraph::new(),
+            stages: HashMap::new(),
         }
     }
 
+    /// Register a system in the [fixed stage](Stage::Fixed).
     pub fn register<S>(&mut self, system: S, name: &str, deps: &[&str]) -> Result<()>
+    where
+        S: System + 'a,
+    {
+        self.register_in(system, name, deps, Stage::Fixed)
+    }
+
+    /// Register a system which runs in the given stage.
+    pub fn register_in<S>(
+        &mut self,
+        system: S,
+        name: &str,
+        deps: &[&str],
+        stage: Stage,
+    ) -> Result<()>
     where
         S: System + 'a,
     {
@@ -24,10 +70,16 @@ impl<'a> Dispatcher<'a> {
                 .is_none(),
             "system already exists!"
         );
+        self.stages.insert(name.to_owned(), stage);
 
         Ok(())
     }
 
+    /// The stage a system was registered in, if there's a system with that name.
+    pub fn stage(&self, name: &str) -> Option<Stage> {
+        self.stages.get(name).copied()
+    }
+
     pub fn refresh<'lua>(
         &mut self,
         lua: LuaContext<'lua>,
@@ -44,20 +96,133 @@ impl<'a> Dispatcher<'a> {
         Ok(())
     }
 
+    /// Initialize every system again, into a different set of resources. The dispatcher
+    /// must have been refreshed since it was last modified.
+    pub fn init_into<'lua>(
+        &self,
+        lua: LuaContext<'lua>,
+        local_resources: &mut OwnedResources,
+        global_resources: Option<&SharedResources>,
+    ) -> Result<()> {
+        ensure!(
+            !self.dependency_graph.is_dirty(),
+            "dispatcher has been modified but not refreshed!"
+        );
+
+        for (_, sys) in self.dependency_graph.sorted() {
+            sys.init(lua, local_resources, global_resources)?;
+        }
+
+        Ok(())
+    }
+
+    /// The order systems run in and the dependencies between them. The dispatcher must
+    /// have been refreshed since it was last modified.
+    pub fn resolved(&self) -> Result<ResolvedGraph> {
+        ensure!(
+            !self.dependency_graph.is_dirty(),
+            "dispatcher has been modified but not refreshed!"
+        );
+
+        Ok(self.dependency_graph.resolved())
+    }
+
+    /// Render the system ordering in Graphviz's dot format, for debugging. The
+    /// dispatcher must have been refreshed since it was last modified.
+    pub fn graphviz(&self) -> Result<String> {
+        ensure!(
+            !self.dependency_graph.is_dirty(),
+            "dispatcher has been modified but not refreshed!"
+        );
+
+        Ok(self.dependency_graph.graphviz())
+    }
+
+    /// Run every system once, whatever stage it's in.
     pub fn update<'lua>(
         &mut self,
         lua: LuaContext<'lua>,
         resources: &UnifiedResources,
+    ) -> Result<()> {
+        self.update_stage(lua, resources, Stage::Both)
+    }
+
+    /// Run the systems in `stage`. Passing [`Stage::Both`] runs every system.
+    pub fn update_stage<'lua>(
+        &mut self,
+        lua: LuaContext<'lua>,
+        resources: &UnifiedResources,
+        stage: Stage,
     ) -> Result<()> {
         ensure!(
             !self.dependency_graph.is_dirty(),
             "dispatcher has been modified but not refreshed!"
         );
 
-        for (_, sys) in self.dependency_graph.sorted() {
-            sys.update(lua, resources)?;
+        for (name, sys) in self.dependency_graph.sorted() {
+            if self.stages[name].includes(stage) {
+                sys.update(lua, resources)?;
+            }
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::Space,
+        std::{cell::RefCell, rc::Rc},
+    };
+
+    struct Log(&'static str, Rc<RefCell<Vec<&'static str>>>);
+
+    impl System for Log {
+        fn update(&self, _lua: LuaContext, _resources: &UnifiedResources) -> Result<()> {
+            self.1.borrow_mut().push(self.0);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn systems_run_in_their_stages() -> Result<()> {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut space = Space::new()?;
+        space.register_in(Log("fixed", log.clone()), "fixed", &[], Stage::Fixed)?;
+        space.register_in(Log("frame", log.clone()), "frame", &["fixed"], Stage::Frame)?;
+        space.register_in(Log("both", log.clone()), "both", &["frame"], Stage::Both)?;
+
+        space.fixed_update()?;
+        assert_eq!(log.replace(Vec::new()), ["fixed", "both"]);
+        space.frame_update()?;
+        assert_eq!(log.replace(Vec::new()), ["frame", "both"]);
+        space.maintain()?;
+        assert_eq!(log.replace(Vec::new()), ["fixed", "frame", "both"]);
+
+        assert_eq!(space.maintainers.stage("frame"), Some(Stage::Frame));
+        assert_eq!(space.maintainers.stage("missing"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn default_systems_have_one_stage_for_world_events() -> Result<()> {
+        let space = Space::new()?;
+        assert_eq!(space.maintainers.stage("WorldEvent"), Some(Stage::Fixed));
+        assert_eq!(space.maintainers.stage("Hierarchy"), Some(Stage::Both));
+        assert_eq!(space.maintainers.stage("Lifetime"), Some(Stage::Fixed));
+
+        Ok(())
+    }
+
+    #[test]
+    fn stages_include_both() {
+        assert!(Stage::Fixed.includes(Stage::Fixed));
+        assert!(!Stage::Fixed.includes(Stage::Frame));
+        assert!(!Stage::Frame.includes(Stage::Fixed));
+        assert!(Stage::Both.includes(Stage::Frame));
+        assert!(Stage::Frame.includes(Stage::Both));
+    }
+}
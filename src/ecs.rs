@@ -19,30 +19,46 @@
 //! can be used to derive a `SmartComponent` implementation which is un-flagged,
 //! and `#[derive(TrackedComponent)]` will generate an implementation which flags
 //! changes on mutable borrow and registers the component type with the ECS.
+//!
+//! Entities can be temporarily switched off without removing any of their components
+//! by inserting the [`Disabled`] marker. [`World::query`] still sees disabled entities,
+//! so that bookkeeping like persistence and the hierarchy keeps working, but
+//! [`World::query_enabled`], [`PreparedQuery`] and Lua's `sludge.query` skip them unless
+//! told otherwise, and the built-in systems leave them alone until they're re-enabled.
+//!
+//! Entities can be copied with [`World::clone_entity`], which copies every component
+//! registered with a [`CloneComponent`]. Components deriving `SimpleComponent` or
+//! `TrackedComponent` are registered automatically if they implement `Clone`; anything
+//! else has to be registered by hand, and components which can't be copied as-is, like
+//! handles into some other structure, can register a hook or opt out entirely.
 
 use {
     anyhow::*,
     derivative::*,
     hashbrown::HashMap,
     hibitset::*,
+    serde::{Deserialize, Serialize},
     shrev::{EventChannel, EventIterator},
+    sludge_macros::SimpleComponent,
     std::{
         any::{Any, TypeId},
         fmt,
         marker::PhantomData,
         pin::Pin,
-        sync::{Mutex, RwLock, RwLockReadGuard},
+        sync::{Arc, Mutex, RwLock, RwLockReadGuard},
     },
 };
 
 pub use hecs::{
     Archetype, ArchetypesGeneration, Bundle, Component, ComponentError, DynamicBundle, Entity,
     EntityBuilder, EntityRef, Iter, NoSuchEntity, Query, QueryBorrow, QueryOne, Ref, RefMut,
-    SmartComponent, SpawnBatchIter,
+    SmartComponent, SpawnBatchIter, Without,
 };
 
 pub use shrev::ReaderId;
 
+use crate::hierarchy::{self, Parent};
+
 #[doc(hidden)]
 pub type ScContext<'a> = &'a HashMap<TypeId, EventEmitter>;
 
@@ -58,6 +74,156 @@ impl FlaggedComponent {
 
 inventory::collect!(FlaggedComponent);
 
+type Cloner = Arc<dyn Fn(&World, Entity, &mut EntityBuilder) + Send + Sync>;
+
+/// How a component is copied by [`World::clone_entity`], registered with
+/// `inventory::submit!`:
+///
+/// ```ignore
+/// inventory::submit! {
+///     CloneComponent::of::<Position>()
+/// }
+///
+/// // Sprite ids index into a sprite batch, so a copy would point at the same sprite.
+/// inventory::submit! {
+///     CloneComponent::skip::<SpriteId>()
+/// }
+/// ```
+///
+/// Components deriving `SimpleComponent` or `TrackedComponent` are registered with
+/// [`CloneComponent::of`] automatically if they're `Clone`, unless they're generic. A
+/// registration made by hand takes precedence over the automatic one, so those can be
+/// overridden with a hook or skipped, too. Components with no registration at all are
+/// left off of copies.
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub struct CloneComponent {
+    type_id: TypeId,
+    type_name: &'static str,
+    #[derivative(Debug = "ignore")]
+    cloner: Option<Cloner>,
+    automatic: bool,
+}
+
+impl CloneComponent {
+    /// Copy the component with its `Clone` implementation.
+    pub fn of<T: Component + Clone>() -> Self {
+        Self::with::<T, _>(|component| Some(component.clone()))
+    }
+
+    /// Copy the component with a hook, which can also decide to leave it off of the copy
+    /// by returning `None`.
+    pub fn with<T, F>(hook: F) -> Self
+    where
+        T: Component,
+        F: Fn(&T) -> Option<T> + Send + Sync + 'static,
+    {
+        Self {
+            type_id: TypeId::of::<T>(),
+            type_name: std::any::type_name::<T>(),
+            cloner: Some(Arc::new(move |world, entity, builder| {
+                let copy = world.get_raw::<T>(entity).ok().and_then(|c| hook(&c));
+                if let Some(copy) = copy {
+                    builder.add(copy);
+                }
+            })),
+            automatic: false,
+        }
+    }
+
+    /// Never copy the component.
+    pub fn skip<T: Component>() -> Self {
+        Self {
+            type_id: TypeId::of::<T>(),
+            type_name: std::any::type_name::<T>(),
+            cloner: None,
+            automatic: false,
+        }
+    }
+
+    /// The type ID of the registered component.
+    pub fn type_id(&self) -> TypeId {
+        self.type_id
+    }
+
+    /// The Rust type name of the registered component.
+    pub fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+
+    /// Whether copies of an entity get this component.
+    pub fn is_cloned(&self) -> bool {
+        self.cloner.is_some()
+    }
+
+    fn automatic(self) -> Self {
+        Self {
+            automatic: true,
+            ..self
+        }
+    }
+}
+
+inventory::collect!(CloneComponent);
+
+/// Used by the component derives to register `Clone` components, and only those, with
+/// [`CloneComponent`]: `(&CloneProbe::<T>(PhantomData)).clone_component()` picks
+/// [`ProbeClone`] if `T` is `Clone`, and falls back to [`ProbeNoClone`] if it isn't.
+/// Method lookup tries the receiver as it is before borrowing it again, so the impl for
+/// `CloneProbe<T>` wins over the one for `&CloneProbe<T>` whenever its bound holds.
+#[doc(hidden)]
+pub struct CloneProbe<T>(pub PhantomData<T>);
+
+#[doc(hidden)]
+pub trait ProbeClone {
+    #[doc(hidden)]
+    fn clone_component(&self) -> CloneComponent;
+}
+
+impl<T: Component + Clone> ProbeClone for CloneProbe<T> {
+    fn clone_component(&self) -> CloneComponent {
+        CloneComponent::of::<T>().automatic()
+    }
+}
+
+#[doc(hidden)]
+pub trait ProbeNoClone {
+    #[doc(hidden)]
+    fn clone_component(&self) -> CloneComponent;
+}
+
+impl<T: Component> ProbeNoClone for &CloneProbe<T> {
+    fn clone_component(&self) -> CloneComponent {
+        CloneComponent::skip::<T>().automatic()
+    }
+}
+
+/// Collect every [`CloneComponent`], letting registrations made by hand override the
+/// automatic ones.
+fn clone_registry() -> HashMap<TypeId, CloneComponent> {
+    let mut registry = HashMap::<TypeId, CloneComponent>::new();
+    for component in inventory::iter::<CloneComponent> {
+        match registry.get(&component.type_id) {
+            Some(existing) if !existing.automatic && !component.automatic => panic!(
+                "component `{}` already registered for cloning",
+                component.type_name
+            ),
+            Some(existing) if !existing.automatic || component.automatic => {}
+            _ => {
+                registry.insert(component.type_id, component.clone());
+            }
+        }
+    }
+    registry
+}
+
+/// A marker for an entity which is temporarily switched off, like an enemy playing its
+/// death animation or a pooled object waiting to be reused. Disabled entities keep all
+/// of their components, but are skipped by [`World::query_enabled`], [`PreparedQuery`]
+/// and the built-in systems. Removing the marker switches the entity back on.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, SimpleComponent)]
+pub struct Disabled;
+
 enum Command {
     Spawn(EntityBuilder),
     Insert(Entity, EntityBuilder),
@@ -387,6 +553,8 @@ pub struct World {
     buffers: Mutex<Vec<CommandBuffer>>,
     queued: Mutex<Vec<CommandBuffer>>,
     channels: HashMap<TypeId, EventEmitter>,
+    cloners: HashMap<TypeId, CloneComponent>,
+    structure_generation: u64,
 }
 
 impl World {
@@ -400,6 +568,8 @@ impl World {
                 .into_iter()
                 .map(|fc| (fc.0, EventEmitter::default()))
                 .collect(),
+            cloners: clone_registry(),
+            structure_generation: 0,
         }
     }
 
@@ -420,11 +590,19 @@ impl World {
         self.ecs.archetypes_generation()
     }
 
+    /// A counter which is incremented every time an entity is spawned or despawned, or
+    /// has components added or removed; that is, whenever the set of entities matching
+    /// some query might have changed.
+    pub fn structure_generation(&self) -> u64 {
+        self.structure_generation
+    }
+
     /// Spawn an entity with a bundle of components.
     ///
     /// If you're spawning lots of entities at once with the same component types, you probably
     /// want to use [`World::spawn_batch`](World::spawn_batch) instead.
     pub fn spawn(&mut self, components: impl DynamicBundle) -> Entity {
+        self.structure_generation += 1;
         Self::do_spawn(&mut self.channels, &mut self.ecs, components)
     }
 
@@ -452,6 +630,7 @@ impl World {
         I: IntoIterator,
         I::Item: Bundle,
     {
+        self.structure_generation += 1;
         let batched = self.ecs.spawn_batch(iter).collect::<Vec<_>>();
         I::Item::with_static_ids(|ids| {
             for typeid in ids {
@@ -472,6 +651,7 @@ impl World {
         I: IntoIterator,
         I::Item: Bundle,
     {
+        self.structure_generation += 1;
         let start = buf.len();
         buf.extend(self.ecs.spawn_batch(iter));
         I::Item::with_static_ids(|ids| {
@@ -485,6 +665,7 @@ impl World {
 
     /// Despawn an entity, removing it from the world and dropping all its components.
     pub fn despawn(&mut self, entity: Entity) -> Result<(), NoSuchEntity> {
+        self.structure_generation += 1;
         Self::do_despawn(&mut self.channels, &mut self.ecs, entity)
     }
 
@@ -530,6 +711,19 @@ impl World {
         self.ecs.query_with_context(&self.channels)
     }
 
+    /// Like [`World::query`], but skips entities marked [`Disabled`].
+    pub fn query_enabled<'w, Q>(&'w self) -> QueryBorrow<'w, Without<Disabled, Q>, ScContext<'w>>
+    where
+        Q: Query<'w, ScContext<'w>>,
+    {
+        self.query::<Q>().without::<Disabled>()
+    }
+
+    /// Returns true if `entity` exists and isn't marked [`Disabled`].
+    pub fn is_enabled(&self, entity: Entity) -> bool {
+        self.contains(entity) && self.get_raw::<Disabled>(entity).is_err()
+    }
+
     /// Query the world for all the given components of a single entity. This
     /// is more efficient than [`World::get`] when you want multiple components
     /// of an entity.
@@ -607,12 +801,48 @@ impl World {
         self.ecs.iter()
     }
 
+    /// Spawn a copy of an entity, with a copy of each of its components which is
+    /// registered with a [`CloneComponent`]. See the [module documentation](self) for
+    /// details.
+    ///
+    /// Only the entity itself is copied; if it has a [`Parent`], the copy is a sibling
+    /// of the original. Use [`World::clone_hierarchy`] to copy its children as well.
+    pub fn clone_entity(&mut self, entity: Entity) -> Result<Entity, NoSuchEntity> {
+        let type_ids = self
+            .ecs
+            .entity(entity)?
+            .component_types()
+            .collect::<Vec<_>>();
+        let mut builder = EntityBuilder::new();
+        for type_id in type_ids {
+            if let Some(cloner) = self.cloners.get(&type_id).and_then(|c| c.cloner.as_ref()) {
+                cloner(self, entity, &mut builder);
+            }
+        }
+
+        Ok(self.spawn(builder.build()))
+    }
+
+    /// Spawn a copy of an entity along with copies of all of its children, and their
+    /// children, and so on. The copied children are parented to the copy of the entity
+    /// rather than to the original.
+    pub fn clone_hierarchy(&mut self, entity: Entity) -> Result<Entity, NoSuchEntity> {
+        let copy = self.clone_entity(entity)?;
+        for child in hierarchy::children_of(self, entity) {
+            let child_copy = self.clone_hierarchy(child)?;
+            self.insert_one(child_copy, Parent::new(copy))?;
+        }
+
+        Ok(copy)
+    }
+
     /// Insert a bundle of components onto an entity.
     pub fn insert(
         &mut self,
         entity: Entity,
         bundle: impl DynamicBundle,
     ) -> Result<(), NoSuchEntity> {
+        self.structure_generation += 1;
         Self::do_insert(&mut self.channels, &mut self.ecs, entity, bundle)
     }
 
@@ -640,6 +870,7 @@ impl World {
         entity: Entity,
         component: C,
     ) -> Result<(), NoSuchEntity> {
+        self.structure_generation += 1;
         let typeid = TypeId::of::<C>();
 
         if let Some(channel) = self.channels.get_mut(&typeid) {
@@ -652,6 +883,7 @@ impl World {
     /// Remove multiple components from an entity. If the components are found on the entity
     /// they will be returned; otherwise, a `ComponentError` will be returned.
     pub fn remove<T: Bundle>(&mut self, entity: Entity) -> Result<T, ComponentError> {
+        self.structure_generation += 1;
         Self::do_remove::<T>(&mut self.channels, &mut self.ecs, entity)
     }
 
@@ -673,6 +905,7 @@ impl World {
 
     /// Remove a single component from the entity, returning it if it's found.
     pub fn remove_one<T: Component>(&mut self, entity: Entity) -> Result<T, ComponentError> {
+        self.structure_generation += 1;
         if let Some(channel) = self.channels.get_mut(&TypeId::of::<T>()) {
             channel.emit_removed(entity);
         }
@@ -682,6 +915,7 @@ impl World {
 
     /// Clear all entities from the world, dropping their components.
     pub fn clear(&mut self) {
+        self.structure_generation += 1;
         for (id, e) in self.ecs.iter() {
             for typeid in e.component_types() {
                 if let Some(channel) = self.channels.get_mut(&typeid) {
@@ -706,17 +940,24 @@ impl World {
     pub fn poll<'a, T: Component>(
         &'a self,
         subscriber: &'a mut ComponentSubscriber<T>,
+    ) -> ComponentEventIterator<'a> {
+        self.poll_dynamic(TypeId::of::<T>(), &mut subscriber.reader_id)
+    }
+
+    /// Like [`World::poll`](World::poll), for a reader returned by
+    /// [`World::track_dynamic`](World::track_dynamic).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the component type has never been tracked.
+    pub fn poll_dynamic<'a>(
+        &'a self,
+        type_id: TypeId,
+        reader_id: &'a mut ReaderId<ComponentEvent>,
     ) -> ComponentEventIterator<'a> {
         ComponentEventIterator::new(
-            Pin::new(
-                self.channels
-                    .get(&TypeId::of::<T>())
-                    .unwrap()
-                    .channel
-                    .read()
-                    .unwrap(),
-            ),
-            &mut subscriber.reader_id,
+            Pin::new(self.channels.get(&type_id).unwrap().channel.read().unwrap()),
+            reader_id,
         )
     }
 
@@ -726,19 +967,22 @@ impl World {
     /// that for `track`, you need mutable access to the `World`, but `poll` only
     /// needs immutable access.
     pub fn track<T: Component>(&mut self) -> ComponentSubscriber<T> {
-        let reader_id = self
-            .channels
-            .entry(TypeId::of::<T>())
+        ComponentSubscriber {
+            _marker: PhantomData,
+            reader_id: self.track_dynamic(TypeId::of::<T>()),
+        }
+    }
+
+    /// Like [`World::track`](World::track), for a component type which is only known
+    /// by its `TypeId`, such as one named from Lua.
+    pub fn track_dynamic(&mut self, type_id: TypeId) -> ReaderId<ComponentEvent> {
+        self.channels
+            .entry(type_id)
             .or_default()
             .channel
             .get_mut()
             .unwrap()
-            .register_reader();
-
-        ComponentSubscriber {
-            _marker: PhantomData,
-            reader_id,
-        }
+            .register_reader()
     }
 
     /// Retrieve a command buffer from the `World`'s internal pool. Buffers queued
@@ -769,6 +1013,7 @@ impl World {
 
         let nonempty_count = queued.iter().filter(|buf| !buf.is_empty()).count();
         if nonempty_count > 0 {
+            self.structure_generation += 1;
             log::info!(
                 "flushing {} nonempty queued command buffers",
                 nonempty_count,
@@ -824,3 +1069,209 @@ impl World {
         }
     }
 }
+
+/// A query which caches which archetypes it matches between uses, so that it can be
+/// stored in a system or resource and reused from frame to frame without checking every
+/// archetype in the world against the query each time it's run.
+///
+/// Which archetypes match only changes when archetypes are added to the world, so the
+/// cache is only thrown away when the world's
+/// [`archetypes_generation`](World::archetypes_generation) changes; spawning and
+/// despawning entities of existing archetypes leaves it alone. Whether an archetype
+/// matches is found out the first time it's seen with an entity in it, by running the
+/// query on that entity.
+///
+/// Entities marked [`Disabled`] are skipped unless the query is built with
+/// [`PreparedQuery::including_disabled`].
+pub struct PreparedQuery<Q> {
+    generation: Option<ArchetypesGeneration>,
+    /// Whether each of the world's archetypes matches, in the order the world lists
+    /// them, or `None` for archetypes which have been empty every time they were seen.
+    matches: Vec<Option<bool>>,
+    entities: Vec<Entity>,
+    include_disabled: bool,
+    _marker: PhantomData<fn() -> Q>,
+}
+
+impl<Q> Default for PreparedQuery<Q> {
+    fn default() -> Self {
+        Self {
+            generation: None,
+            matches: Vec::new(),
+            entities: Vec::new(),
+            include_disabled: false,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<Q> fmt::Debug for PreparedQuery<Q> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PreparedQuery")
+            .field("generation", &self.generation)
+            .field("matches", &self.matches)
+            .field("include_disabled", &self.include_disabled)
+            .finish()
+    }
+}
+
+impl<Q> PreparedQuery<Q> {
+    /// Create a new prepared query. Its cache is filled the first time it's used.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Match entities marked [`Disabled`] too.
+    pub fn including_disabled(mut self) -> Self {
+        self.include_disabled = true;
+        self.invalidate();
+        self
+    }
+
+    /// Force the cache to be rebuilt the next time the query is used.
+    pub fn invalidate(&mut self) {
+        self.generation = None;
+    }
+
+    /// The entities matching the query, checking any archetypes which are new or haven't
+    /// had entities in them before.
+    pub fn entities<'w>(&mut self, world: &'w World) -> &[Entity]
+    where
+        Q: Query<'w>,
+    {
+        let generation = world.archetypes_generation();
+        if self.generation != Some(generation) {
+            self.matches.clear();
+            self.generation = Some(generation);
+        }
+        self.matches.resize(world.archetypes().len(), None);
+
+        let include_disabled = self.include_disabled;
+        self.entities.clear();
+        for (archetype, matches) in world.archetypes().zip(&mut self.matches) {
+            let ids = archetype.ids();
+            if ids.is_empty() {
+                continue;
+            }
+
+            // Safety: the IDs of an archetype are all IDs of live entities.
+            let matches = *matches.get_or_insert_with(|| {
+                (include_disabled || !archetype.has::<Disabled>())
+                    && world
+                        .query_one_raw::<Q>(unsafe { world.find_entity_from_id(ids[0]) })
+                        .map_or(false, |mut query| query.get().is_some())
+            });
+
+            if matches {
+                self.entities.extend(
+                    ids.iter()
+                        .map(|&id| unsafe { world.find_entity_from_id(id) }),
+                );
+            }
+        }
+
+        &self.entities
+    }
+
+    /// Run `f` on every entity matching the query, along with a [`QueryOne`] for
+    /// fetching its components.
+    pub fn for_each<'w, F>(&mut self, world: &'w World, mut f: F)
+    where
+        Q: Query<'w> + Query<'w, ScContext<'w>>,
+        F: FnMut(Entity, &mut QueryOne<'w, Q, ScContext<'w>>),
+    {
+        for &entity in self.entities(world) {
+            if let Ok(mut query) = world.query_one::<Q>(entity) {
+                f(entity, &mut query);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::Name;
+
+    #[test]
+    fn clone_hierarchy_reparents_children() {
+        let mut world = World::new();
+        let root = world.spawn((Name("root".to_owned()),));
+        let child = world.spawn((Name("child".to_owned()), Parent::new(root)));
+
+        let copy = world.clone_hierarchy(root).unwrap();
+        assert_ne!(copy, root);
+        assert_eq!(world.get_raw::<Name>(copy).unwrap().0, "root");
+
+        let children = world
+            .query_raw::<(&Name, &Parent)>()
+            .iter()
+            .filter(|(_, (_, parent))| parent.parent_entity == copy)
+            .map(|(entity, (name, _))| (entity, name.0.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(children.len(), 1);
+        assert_ne!(children[0].0, child);
+        assert_eq!(children[0].1, "child");
+    }
+
+    /// Derives a component without implementing `Clone`.
+    #[derive(SimpleComponent)]
+    struct Unclonable;
+
+    #[test]
+    fn derived_components_are_cloned_only_if_clone() {
+        let registry = clone_registry();
+        assert!(registry[&TypeId::of::<Disabled>()].is_cloned());
+        assert!(!registry[&TypeId::of::<Unclonable>()].is_cloned());
+
+        let mut world = World::new();
+        let original = world.spawn((Disabled, Unclonable));
+        let copy = world.clone_entity(original).unwrap();
+        assert!(world.get_raw::<Disabled>(copy).is_ok());
+        assert!(world.get_raw::<Unclonable>(copy).is_err());
+    }
+
+    #[test]
+    fn prepared_query_follows_spawns_and_despawns() {
+        let mut world = World::new();
+        let mut query = PreparedQuery::<&Name>::new();
+        assert!(query.entities(&world).is_empty());
+
+        let a = world.spawn((Name("a".to_owned()),));
+        let b = world.spawn((Name("b".to_owned()), Parent::new(a)));
+        world.spawn((Parent::new(a),));
+        assert_eq!(query.entities(&world), &[a, b]);
+
+        // Spawning into an archetype which already exists doesn't change the archetypes
+        // generation, but the new entity is still found.
+        let generation = world.archetypes_generation();
+        let c = world.spawn((Name("c".to_owned()),));
+        assert_eq!(world.archetypes_generation(), generation);
+        let mut found = query.entities(&world).to_vec();
+        found.sort();
+        let mut expected = vec![a, b, c];
+        expected.sort();
+        assert_eq!(found, expected);
+
+        world.despawn(a).unwrap();
+        assert!(!query.entities(&world).contains(&a));
+        assert_eq!(query.entities(&world).len(), 2);
+    }
+
+    #[test]
+    fn prepared_query_skips_disabled() {
+        let mut world = World::new();
+        let enabled = world.spawn((Name("enabled".to_owned()),));
+        let disabled = world.spawn((Name("disabled".to_owned()), Disabled));
+
+        let mut query = PreparedQuery::<&Name>::new();
+        assert_eq!(query.entities(&world), &[enabled]);
+
+        let mut query = PreparedQuery::<&Name>::new().including_disabled();
+        let mut found = query.entities(&world).to_vec();
+        found.sort();
+        let mut expected = vec![enabled, disabled];
+        expected.sort();
+        assert_eq!(found, expected);
+    }
+}
@@ -1,11 +1,17 @@
 use crate::{
     conf::Conf,
-    graphics::Graphics,
-    input::{KeyCode, KeyMods, MouseButton},
-    SludgeResultExt,
+    graphics::{FrameRecorder, Graphics},
+    input::{KeyCode, KeyMods, MouseButton, TextInput, TouchPhase, Touches},
+    math::*,
+    SludgeLuaContextExt, SludgeResultExt, Space,
 };
 use {anyhow::*, miniquad as mq};
 
+/// The name of the event broadcast to the scheduler of the handler's
+/// [`space`](EventHandler::space) when the window is resized, with the new width and
+/// height as arguments.
+pub const WINDOW_RESIZED_EVENT: &'static str = "window.resized";
+
 pub trait EventHandler: Sized + 'static {
     type Args;
 
@@ -13,12 +19,86 @@ pub trait EventHandler: Sized + 'static {
     fn update(&mut self) -> Result<()>;
     fn draw(&mut self) -> Result<()>;
 
-    fn key_down_event(&mut self, _keycode: KeyCode, _keymods: KeyMods, _repeat: bool) {}
+    /// Called when a key is pressed. By default, the key is passed on to the
+    /// [`TextInput`] of the handler's [`space`](EventHandler::space), if it has one, so
+    /// that the focused text field gets its editing keys, and to its [`FrameRecorder`].
+    fn key_down_event(&mut self, keycode: KeyCode, keymods: KeyMods, repeat: bool) {
+        if let Some(text_input) = self.space().and_then(|s| s.fetch_one::<TextInput>().ok()) {
+            text_input.borrow_mut().key_down_event(keycode, keymods);
+        }
+        if let Some(recorder) = self
+            .space()
+            .and_then(|s| s.fetch_one::<FrameRecorder>().ok())
+        {
+            if !repeat {
+                recorder.borrow_mut().key_down(keycode);
+            }
+        }
+    }
     fn key_up_event(&mut self, _keycode: KeyCode, _keymods: KeyMods) {}
+    /// Called with each character of typed text, after keyboard layouts, dead keys and
+    /// any IME have had their say. By default, the character is passed on to the
+    /// [`TextInput`] of the handler's [`space`](EventHandler::space), if it has one.
+    fn text_input_event(&mut self, character: char, _keymods: KeyMods, _repeat: bool) {
+        if let Some(text_input) = self.space().and_then(|s| s.fetch_one::<TextInput>().ok()) {
+            text_input.borrow_mut().char_event(character);
+        }
+    }
     fn mouse_motion_event(&mut self, _x: f32, _y: f32) {}
     fn mouse_wheel_event(&mut self, _x: f32, _y: f32) {}
-    fn mouse_button_down_event(&mut self, _button: MouseButton, _x: f32, _y: f32) {}
+    /// Called when a mouse button is pressed. By default, the button is passed on to the
+    /// [`FrameRecorder`] of the handler's [`space`](EventHandler::space), if it has one.
+    fn mouse_button_down_event(&mut self, button: MouseButton, _x: f32, _y: f32) {
+        if let Some(recorder) = self
+            .space()
+            .and_then(|s| s.fetch_one::<FrameRecorder>().ok())
+        {
+            recorder.borrow_mut().mouse_button_down(button);
+        }
+    }
     fn mouse_button_up_event(&mut self, _button: MouseButton, _x: f32, _y: f32) {}
+    /// Called for every touch on a touch screen. By default, touches are passed on to
+    /// the [`Touches`] of the handler's [`space`](EventHandler::space), if it has one, and
+    /// then turned into clicks of the left mouse button with [`emulate_mouse`].
+    fn touch_event(&mut self, phase: TouchPhase, id: u64, x: f32, y: f32) {
+        if let Some(touches) = self.space().and_then(|s| s.fetch_one::<Touches>().ok()) {
+            touches
+                .borrow_mut()
+                .touch_event(phase, id, Point2::new(x, y));
+        }
+        emulate_mouse(self, phase, x, y);
+    }
+    /// Called when the window is resized. By default, this broadcasts
+    /// [`WINDOW_RESIZED_EVENT`] to the handler's [`space`](EventHandler::space), if it has
+    /// one.
+    fn resize_event(&mut self, width: f32, height: f32) {
+        if let Some(space) = self.space() {
+            let _ = space
+                .lua()
+                .context(|lua| lua.broadcast(WINDOW_RESIZED_EVENT, (width, height)))
+                .log_warn_err(module_path!());
+        }
+    }
+
+    /// The space which receives input by default, if there is one. Handlers which run a
+    /// space should return it, so that the default event handlers can feed its input
+    /// resources.
+    fn space(&self) -> Option<&Space> {
+        None
+    }
+}
+
+/// Turn a touch into the mouse events it would be if the finger were a mouse with only a
+/// left button. Every touch moves the same cursor, so this only makes sense for one
+/// finger at a time.
+pub fn emulate_mouse<H: EventHandler>(handler: &mut H, phase: TouchPhase, x: f32, y: f32) {
+    match phase {
+        TouchPhase::Started => handler.mouse_button_down_event(MouseButton::Left, x, y),
+        TouchPhase::Moved => handler.mouse_motion_event(x, y),
+        TouchPhase::Ended | TouchPhase::Cancelled => {
+            handler.mouse_button_up_event(MouseButton::Left, x, y)
+        }
+    }
 }
 
 pub struct MqHandler<H: EventHandler> {
@@ -27,9 +107,16 @@ pub struct MqHandler<H: EventHandler> {
 
 impl<H: EventHandler> MqHandler<H> {
     pub fn new(ctx: mq::Context, args: H::Args) -> Self {
-        let context = Graphics::new(ctx)
+        Self::with_conf(ctx, &Conf::default(), args)
+    }
+
+    /// Create a handler for a window which was created from `conf`, so that the window
+    /// state reported by [`Graphics`] matches it.
+    pub fn with_conf(ctx: mq::Context, conf: &Conf, args: H::Args) -> Self {
+        let mut context = Graphics::new(ctx)
             .log_error_err(module_path!())
             .expect("error creating miniquad context");
+        context.apply_conf(conf);
         Self {
             handler: H::init(context, args)
                 .log_error_err(module_path!())
@@ -47,7 +134,9 @@ impl<H: EventHandler> mq::EventHandlerFree for MqHandler<H> {
         self.handler.draw().unwrap();
     }
 
-    fn resize_event(&mut self, _width: f32, _height: f32) {}
+    fn resize_event(&mut self, width: f32, height: f32) {
+        self.handler.resize_event(width, height);
+    }
 
     fn mouse_motion_event(&mut self, x: f32, y: f32) {
         self.handler.mouse_motion_event(x, y);
@@ -67,7 +156,10 @@ impl<H: EventHandler> mq::EventHandlerFree for MqHandler<H> {
             .mouse_button_up_event(MouseButton::from(button), x, y);
     }
 
-    fn char_event(&mut self, _character: char, _keymods: mq::KeyMods, _repeat: bool) {}
+    fn char_event(&mut self, character: char, keymods: mq::KeyMods, repeat: bool) {
+        self.handler
+            .text_input_event(character, KeyMods::from(keymods), repeat);
+    }
 
     fn key_down_event(&mut self, keycode: mq::KeyCode, keymods: mq::KeyMods, repeat: bool) {
         self.handler
@@ -79,19 +171,8 @@ impl<H: EventHandler> mq::EventHandlerFree for MqHandler<H> {
             .key_up_event(KeyCode::from(keycode), KeyMods::from(keymods));
     }
 
-    /// Default implementation emulates mouse clicks
-    fn touch_event(&mut self, phase: mq::TouchPhase, _id: u64, x: f32, y: f32) {
-        if phase == mq::TouchPhase::Started {
-            self.mouse_button_down_event(mq::MouseButton::Left, x, y);
-        }
-
-        if phase == mq::TouchPhase::Ended {
-            self.mouse_button_up_event(mq::MouseButton::Left, x, y);
-        }
-
-        if phase == mq::TouchPhase::Moved {
-            self.mouse_motion_event(x, y);
-        }
+    fn touch_event(&mut self, phase: mq::TouchPhase, id: u64, x: f32, y: f32) {
+        self.handler.touch_event(TouchPhase::from(phase), id, x, y);
     }
 
     /// Represents raw hardware mouse motion event
@@ -109,13 +190,19 @@ impl<H: EventHandler> mq::EventHandlerFree for MqHandler<H> {
 
 pub fn run<T: EventHandler>(conf: Conf, args: T::Args) {
     let mq_conf = mq::conf::Conf {
-        window_title: conf.window_title,
+        window_title: conf.window_title.clone(),
         window_width: conf.window_width as i32,
         window_height: conf.window_height as i32,
+        fullscreen: conf.fullscreen,
+        high_dpi: conf.high_dpi,
+        platform: mq::conf::Platform {
+            swap_interval: Some(if conf.vsync { 1 } else { 0 }),
+            ..mq::conf::Platform::default()
+        },
         ..mq::conf::Conf::default()
     };
 
     mq::start(mq_conf, |ctx| {
-        mq::UserData::free(MqHandler::<T>::new(ctx, args))
+        mq::UserData::free(MqHandler::<T>::with_conf(ctx, &conf, args))
     });
 }
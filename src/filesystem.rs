@@ -217,6 +217,19 @@ impl Filesystem {
         self.vfs.create(path.as_ref()).map(|f| File::VfsFile(f))
     }
 
+    /// Resolve a path in the writable user directory to its location on disk, for
+    /// writing to from threads which can't share the `Filesystem`.
+    pub fn user_path<P: AsRef<path::Path>>(&self, path: P) -> Result<path::PathBuf> {
+        let safe_path = vfs::sanitize_path(path.as_ref()).ok_or_else(|| {
+            anyhow!(
+                "Path {:?} is not valid: must be an absolute path with no \
+                 references to parent directories",
+                path.as_ref()
+            )
+        })?;
+        Ok(self.user_config_path.join(safe_path))
+    }
+
     /// Create an empty directory in the user dir
     /// with the given name.  Any parents to that directory
     /// that do not exist will be created.
@@ -0,0 +1,924 @@
+//! Hierarchical state machines for entities, described by Lua tables.
+//!
+//! An entity is given a state machine with an [`Fsm`] component, either by spawning it
+//! with an `Fsm` field or with `sludge.fsm.start(entity, definition)`:
+//!
+//! ```lua
+//! sludge.fsm.start(guard, {
+//!     initial = "patrol",
+//!     states = {
+//!         patrol = {
+//!             run = function(entity, state) ... end,
+//!             transitions = {
+//!                 { on = "noise", to = "alert" },
+//!                 { when = function(entity) return can_see_player(entity) end, to = "chase" },
+//!             },
+//!         },
+//!         hostile = {
+//!             initial = "alert",
+//!             on_exit = function(entity, state) ... end,
+//!             states = {
+//!                 alert = { on_enter = function(entity, state) ... end },
+//!                 chase = { on_update = function(entity, state) ... end },
+//!             },
+//!             transitions = {
+//!                 { on = "lost_player", to = "patrol" },
+//!             },
+//!         },
+//!     },
+//! })
+//! ```
+//!
+//! States can have substates, in which case they must name the substate they start
+//! in with `initial`. The machine is always in a single innermost state, along with
+//! all of its ancestors. State names have to be unique across the whole machine, since
+//! transitions refer to their targets by name alone.
+//!
+//! `on_enter`, `on_update` and `on_exit` are called directly, and so can't yield;
+//! `run` is spawned on the scheduler when the state is entered and killed when it's
+//! exited, for anything which needs to wait. All of them are passed the entity and the
+//! state's table.
+//!
+//! Transitions with an `on` field fire when that event is sent to the machine with
+//! `sludge.fsm.send(entity, event, arg)` or `sludge.fsm.broadcast(event, arg)`, as long
+//! as their `when` guard (if any) passes when called with the entity and the event's
+//! argument. Transitions with only a `when` field are checked once per update. Inner
+//! states' transitions take priority over their ancestors'. A transition to the state
+//! the machine is already in exits and re-enters it.
+//!
+//! Machines are advanced by the [`FsmSystem`], which enters the initial state on its
+//! first update and broadcasts [`TRANSITION_EVENT`] on every transition. The current
+//! state and the threads of running states are saved along with the definition when
+//! the space is persisted; events which haven't been handled yet are not.
+
+use {
+    anyhow::*, hashbrown::HashMap, rlua::prelude::*, sludge_macros::SimpleComponent,
+    std::collections::VecDeque,
+};
+
+use crate::{
+    api::{LuaComponent, LuaComponentInterface, LuaEntity},
+    ecs::*,
+    pause,
+    resources::{OwnedResources, Resources, SharedResources, UnifiedResources},
+    SludgeLuaContextExt, System,
+};
+
+/// Broadcast after a state machine changes state, with the entity, the innermost state
+/// it left (`nil` when it first starts) and the innermost state it entered.
+pub const TRANSITION_EVENT: &'static str = "sludge.fsm.transition";
+
+#[derive(Debug)]
+enum Pending {
+    Event(String, Option<LuaRegistryKey>),
+    SetState(String),
+}
+
+/// A hierarchical state machine. See the [module documentation](self) for details.
+#[derive(Debug, SimpleComponent)]
+pub struct Fsm {
+    definition: LuaRegistryKey,
+    /// The path from the root of the machine to every state, by name.
+    paths: HashMap<String, Vec<String>>,
+    /// The currently active states, outermost first.
+    active: Vec<String>,
+    threads: HashMap<String, LuaRegistryKey>,
+    pending: VecDeque<Pending>,
+}
+
+impl Fsm {
+    pub fn new<'lua>(lua: LuaContext<'lua>, definition: LuaTable<'lua>) -> Result<Self> {
+        let mut paths = HashMap::new();
+        let states = definition
+            .get::<_, Option<LuaTable>>("states")?
+            .ok_or_else(|| anyhow!("a state machine must have at least one state"))?;
+        index_states(states, &mut Vec::new(), &mut paths)?;
+
+        let initial = definition
+            .get::<_, Option<String>>("initial")?
+            .ok_or_else(|| anyhow!("a state machine must have an initial state"))?;
+        ensure!(
+            paths.get(&initial).map_or(false, |path| path.len() == 1),
+            "initial state `{}` is not a top-level state",
+            initial
+        );
+
+        for path in paths.values() {
+            let state = state_table(&definition, path)?;
+            if let Some(transitions) = state.get::<_, Option<LuaTable>>("transitions")? {
+                for transition in transitions.sequence_values::<LuaTable>() {
+                    let transition = transition?;
+                    let to = transition.get::<_, String>("to")?;
+                    ensure!(
+                        paths.contains_key(&to),
+                        "transition from `{}` to nonexistent state `{}`",
+                        path.last().unwrap(),
+                        to
+                    );
+                    ensure!(
+                        transition.get::<_, Option<LuaValue>>("on")?.is_some()
+                            || transition.get::<_, Option<LuaValue>>("when")?.is_some(),
+                        "transition from `{}` to `{}` has neither an event nor a guard",
+                        path.last().unwrap(),
+                        to
+                    );
+                }
+            }
+        }
+
+        Ok(Self {
+            definition: lua.create_registry_value(definition)?,
+            paths,
+            active: Vec::new(),
+            threads: HashMap::new(),
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// The innermost active state, if the machine has started.
+    pub fn state(&self) -> Option<&str> {
+        self.active.last().map(String::as_str)
+    }
+
+    /// Whether the given state or one of its substates is active.
+    pub fn is_in(&self, state: &str) -> bool {
+        self.active.iter().any(|active| active == state)
+    }
+
+    /// Every active state, outermost first.
+    pub fn active(&self) -> impl Iterator<Item = &str> + '_ {
+        self.active.iter().map(String::as_str)
+    }
+
+    /// Queue an event to be handled on the machine's next update.
+    pub fn send<'lua>(
+        &mut self,
+        lua: LuaContext<'lua>,
+        event: &str,
+        arg: LuaValue<'lua>,
+    ) -> LuaResult<()> {
+        let arg = match arg {
+            LuaValue::Nil => None,
+            other => Some(lua.create_registry_value(other)?),
+        };
+        self.pending
+            .push_back(Pending::Event(event.to_owned(), arg));
+        Ok(())
+    }
+
+    /// Queue an unconditional transition to the given state on the machine's next
+    /// update.
+    pub fn set_state(&mut self, state: &str) -> Result<()> {
+        ensure!(self.paths.contains_key(state), "no state named `{}`", state);
+        self.pending.push_back(Pending::SetState(state.to_owned()));
+        Ok(())
+    }
+
+    /// Put a freshly created machine back into a saved state, without entering it.
+    fn restore<'lua>(
+        &mut self,
+        lua: LuaContext<'lua>,
+        state: &str,
+        threads: Option<LuaTable<'lua>>,
+    ) -> Result<()> {
+        self.active = self
+            .paths
+            .get(state)
+            .cloned()
+            .ok_or_else(|| anyhow!("no state named `{}`", state))?;
+
+        if let Some(threads) = threads {
+            for pair in threads.pairs::<String, LuaThread>() {
+                let (name, thread) = pair?;
+                self.threads
+                    .insert(name, lua.create_registry_value(thread)?);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The full path of states entered by transitioning to `target`, descending into
+    /// initial substates, along with the length of the path to `target` itself.
+    fn resolve(&self, definition: &LuaTable, target: &str) -> Result<(Vec<String>, usize)> {
+        let mut path = self
+            .paths
+            .get(target)
+            .cloned()
+            .ok_or_else(|| anyhow!("no state named `{}`", target))?;
+        let depth = path.len();
+
+        while let Some(initial) =
+            state_table(definition, &path)?.get::<_, Option<String>>("initial")?
+        {
+            path.push(initial);
+        }
+
+        Ok((path, depth))
+    }
+}
+
+/// Record the path to every state in `states` and their substates, checking that
+/// every compound state has a valid initial state.
+fn index_states(
+    states: LuaTable,
+    prefix: &mut Vec<String>,
+    paths: &mut HashMap<String, Vec<String>>,
+) -> Result<()> {
+    for pair in states.pairs::<String, LuaTable>() {
+        let (name, state) = pair?;
+        prefix.push(name.clone());
+        ensure!(
+            paths.insert(name.clone(), prefix.clone()).is_none(),
+            "duplicate state name `{}`",
+            name
+        );
+
+        let initial = state.get::<_, Option<String>>("initial")?;
+        match state.get::<_, Option<LuaTable>>("states")? {
+            Some(substates) => {
+                let initial = initial.ok_or_else(|| {
+                    anyhow!("state `{}` has substates but no initial state", name)
+                })?;
+                ensure!(
+                    substates
+                        .get::<_, Option<LuaTable>>(initial.as_str())?
+                        .is_some(),
+                    "initial state `{}` is not a substate of `{}`",
+                    initial,
+                    name
+                );
+                index_states(substates, prefix, paths)?;
+            }
+            None => ensure!(
+                initial.is_none(),
+                "state `{}` has an initial state but no substates",
+                name
+            ),
+        }
+
+        prefix.pop();
+    }
+
+    Ok(())
+}
+
+fn state_table<'lua>(definition: &LuaTable<'lua>, path: &[String]) -> LuaResult<LuaTable<'lua>> {
+    let mut table = definition.clone();
+    for name in path {
+        table = table
+            .get::<_, LuaTable>("states")?
+            .get::<_, LuaTable>(name.as_str())?;
+    }
+    Ok(table)
+}
+
+/// Run `f` on the entity's state machine, or return `None` if it no longer has one,
+/// which can happen if a callback despawns the entity or removes its `Fsm`.
+fn with_fsm<'lua, R>(
+    lua: LuaContext<'lua>,
+    entity: Entity,
+    f: impl FnOnce(&mut Fsm) -> R,
+) -> Result<Option<R>> {
+    let world = lua.fetch_one::<World>()?;
+    let world = world.borrow();
+    let result = world
+        .get_mut::<Fsm>(entity)
+        .ok()
+        .map(|mut fsm| f(&mut *fsm));
+    Ok(result)
+}
+
+fn call_state<'lua>(state: &LuaTable<'lua>, callback: &str, entity: Entity) -> Result<()> {
+    if let Some(f) = state.get::<_, Option<LuaFunction>>(callback)? {
+        f.call::<_, ()>((LuaEntity::from(entity), state.clone()))
+            .with_context(|| anyhow!("error in state machine callback `{}`", callback))?;
+    }
+    Ok(())
+}
+
+/// Exit states up to the nearest common ancestor of the current state and `target`,
+/// and then enter states down to `target` and its initial substates.
+fn transition<'lua>(lua: LuaContext<'lua>, entity: Entity, target: &str) -> Result<()> {
+    let resolved = with_fsm(lua, entity, |fsm| -> Result<_> {
+        let definition = lua.registry_value::<LuaTable>(&fsm.definition)?;
+        let (path, depth) = fsm.resolve(&definition, target)?;
+        let common = fsm
+            .active
+            .iter()
+            .zip(&path)
+            .take_while(|(a, b)| a == b)
+            .count()
+            .min(depth - 1);
+        Ok((definition, fsm.active.clone(), path, common))
+    })?;
+
+    let (definition, old, new, common) = match resolved {
+        Some(resolved) => resolved?,
+        None => return Ok(()),
+    };
+
+    for i in (common..old.len()).rev() {
+        let thread = match with_fsm(lua, entity, |fsm| {
+            fsm.active.truncate(i);
+            fsm.threads.remove(&old[i])
+        })? {
+            Some(thread) => thread,
+            None => return Ok(()),
+        };
+
+        if let Some(key) = thread {
+            let thread = lua.registry_value::<LuaThread>(&key)?;
+            lua.remove_registry_value(key)?;
+            if thread.status() != LuaThreadStatus::Unresumable {
+                lua.kill(thread, ())?;
+            }
+        }
+
+        call_state(&state_table(&definition, &old[..=i])?, "on_exit", entity)?;
+    }
+
+    for i in common..new.len() {
+        if with_fsm(lua, entity, |fsm| fsm.active.push(new[i].clone()))?.is_none() {
+            return Ok(());
+        }
+
+        let state = state_table(&definition, &new[..=i])?;
+        call_state(&state, "on_enter", entity)?;
+
+        if let Some(run) = state.get::<_, Option<LuaFunction>>("run")? {
+            let thread = lua.spawn(run, (LuaEntity::from(entity), state))?;
+            let key = lua.create_registry_value(thread)?;
+            if let Some(Some(old_key)) =
+                with_fsm(lua, entity, |fsm| fsm.threads.insert(new[i].clone(), key))?
+            {
+                lua.remove_registry_value(old_key)?;
+            }
+        }
+    }
+
+    lua.broadcast(
+        TRANSITION_EVENT,
+        (
+            LuaEntity::from(entity),
+            old.last().cloned(),
+            new.last().cloned(),
+        ),
+    )?;
+
+    Ok(())
+}
+
+/// Find the first transition out of the active states which fires on `event` (or on
+/// its guard alone, if `event` is `None`), innermost states first.
+fn find_transition<'lua>(
+    lua: LuaContext<'lua>,
+    definition: &LuaTable<'lua>,
+    entity: Entity,
+    event: Option<&str>,
+    arg: LuaValue<'lua>,
+) -> Result<Option<String>> {
+    let active = match with_fsm(lua, entity, |fsm| fsm.active.clone())? {
+        Some(active) => active,
+        None => return Ok(None),
+    };
+
+    for depth in (1..=active.len()).rev() {
+        let state = state_table(definition, &active[..depth])?;
+        let transitions = match state.get::<_, Option<LuaTable>>("transitions")? {
+            Some(transitions) => transitions,
+            None => continue,
+        };
+
+        for transition in transitions.sequence_values::<LuaTable>() {
+            let transition = transition?;
+            if transition.get::<_, Option<String>>("on")?.as_deref() != event {
+                continue;
+            }
+
+            let fires = match transition.get::<_, Option<LuaFunction>>("when")? {
+                Some(when) => when
+                    .call::<_, bool>((LuaEntity::from(entity), arg.clone()))
+                    .context("error in state machine guard")?,
+                None => true,
+            };
+
+            if fires {
+                return Ok(Some(transition.get("to")?));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+fn update_fsm<'lua>(lua: LuaContext<'lua>, entity: Entity) -> Result<()> {
+    let state = with_fsm(lua, entity, |fsm| -> LuaResult<_> {
+        Ok((
+            lua.registry_value::<LuaTable>(&fsm.definition)?,
+            fsm.active.is_empty(),
+            fsm.pending.drain(..).collect::<Vec<_>>(),
+        ))
+    })?;
+
+    let (definition, starting, pending) = match state {
+        Some(state) => state?,
+        None => return Ok(()),
+    };
+
+    if starting {
+        let initial = definition.get::<_, String>("initial")?;
+        transition(lua, entity, &initial)?;
+    }
+
+    for pending in pending {
+        let target = match pending {
+            Pending::SetState(target) => Some(target),
+            Pending::Event(event, arg) => {
+                let arg = match arg {
+                    Some(key) => {
+                        let value = lua.registry_value::<LuaValue>(&key)?;
+                        lua.remove_registry_value(key)?;
+                        value
+                    }
+                    None => LuaValue::Nil,
+                };
+                find_transition(lua, &definition, entity, Some(&event), arg)?
+            }
+        };
+
+        if let Some(target) = target {
+            transition(lua, entity, &target)?;
+        }
+    }
+
+    if let Some(target) = find_transition(lua, &definition, entity, None, LuaValue::Nil)? {
+        transition(lua, entity, &target)?;
+    }
+
+    let active = match with_fsm(lua, entity, |fsm| fsm.active.clone())? {
+        Some(active) => active,
+        None => return Ok(()),
+    };
+
+    for depth in 1..=active.len() {
+        call_state(
+            &state_table(&definition, &active[..depth])?,
+            "on_update",
+            entity,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Advances every [`Fsm`] component: starting new machines, handling queued events,
+/// checking guard-only transitions and calling `on_update` for each active state.
+/// Does nothing while the gameplay channel is [paused](crate::pause).
+pub struct FsmSystem;
+
+impl System for FsmSystem {
+    fn init(
+        &self,
+        _lua: LuaContext,
+        _local: &mut OwnedResources,
+        _global: Option<&SharedResources>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn update(&self, lua: LuaContext, resources: &UnifiedResources) -> Result<()> {
+        if pause::is_paused(resources, pause::GAMEPLAY) {
+            return Ok(());
+        }
+
+        let entities = resources
+            .fetch_one::<World>()?
+            .borrow()
+            .query::<&Fsm>()
+            .iter()
+            .map(|(entity, _)| entity)
+            .collect::<Vec<_>>();
+
+        for entity in entities {
+            update_fsm(lua, entity)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FsmAccessor(Entity);
+
+impl LuaUserData for FsmAccessor {
+    fn add_methods<'lua, T: LuaUserDataMethods<'lua, Self>>(methods: &mut T) {
+        methods.add_method("state", |lua, this, ()| {
+            api::with_fsm(lua, this.0.into(), |fsm| fsm.state().map(str::to_owned))
+        });
+
+        methods.add_method("is_in", |lua, this, state: String| {
+            api::with_fsm(lua, this.0.into(), |fsm| fsm.is_in(&state))
+        });
+
+        methods.add_method("send", |lua, this, (event, arg): (String, LuaValue)| {
+            api::with_fsm(lua, this.0.into(), |fsm| fsm.send(lua, &event, arg))?
+        });
+
+        methods.add_method("to_table", |lua, this, ()| {
+            let tmp = lua.fetch_one::<World>()?;
+            let world = tmp.borrow();
+            let fsm = world.get::<Fsm>(this.0).to_lua_err()?;
+
+            let table = lua.create_table()?;
+            table.set(
+                "definition",
+                lua.registry_value::<LuaTable>(&fsm.definition)?,
+            )?;
+            table.set("state", fsm.state())?;
+
+            let threads = lua.create_table()?;
+            for (name, key) in &fsm.threads {
+                threads.set(name.as_str(), lua.registry_value::<LuaThread>(key)?)?;
+            }
+            table.set("threads", threads)?;
+
+            Ok(table)
+        });
+    }
+}
+
+impl LuaComponentInterface for Fsm {
+    fn accessor<'lua>(lua: LuaContext<'lua>, entity: Entity) -> LuaResult<LuaValue<'lua>> {
+        FsmAccessor(entity).to_lua(lua)
+    }
+
+    /// Accepts either a definition, or a table with a `definition` and optionally the
+    /// `state` and `threads` fields produced by the accessor's `to_table`.
+    fn bundler<'lua>(
+        lua: LuaContext<'lua>,
+        args: LuaValue<'lua>,
+        builder: &mut EntityBuilder,
+    ) -> LuaResult<()> {
+        let table = LuaTable::from_lua(args, lua)?;
+        let fsm = match table.get::<_, Option<LuaTable>>("definition")? {
+            Some(definition) => {
+                let mut fsm = Fsm::new(lua, definition).to_lua_err()?;
+                if let Some(state) = table.get::<_, Option<String>>("state")? {
+                    fsm.restore(lua, &state, table.get("threads")?)
+                        .to_lua_err()?;
+                }
+                fsm
+            }
+            None => Fsm::new(lua, table).to_lua_err()?,
+        };
+
+        builder.add(fsm);
+        Ok(())
+    }
+}
+
+inventory::submit! {
+    LuaComponent::new::<Fsm>("Fsm")
+}
+
+pub(crate) mod api {
+    use super::*;
+
+    pub fn with_fsm<'lua, R>(
+        lua: LuaContext<'lua>,
+        entity: LuaEntity,
+        f: impl FnOnce(&mut Fsm) -> R,
+    ) -> LuaResult<R> {
+        let world = lua.fetch_one::<World>()?;
+        let world = world.borrow();
+        let mut fsm = world.get_mut::<Fsm>(entity.into()).to_lua_err()?;
+        Ok(f(&mut *fsm))
+    }
+
+    pub fn start<'lua>(
+        lua: LuaContext<'lua>,
+        (entity, definition): (LuaEntity, LuaTable<'lua>),
+    ) -> LuaResult<()> {
+        let fsm = Fsm::new(lua, definition).to_lua_err()?;
+        lua.fetch_one::<World>()?
+            .borrow_mut()
+            .insert_one(entity.into(), fsm)
+            .to_lua_err()?;
+        Ok(())
+    }
+
+    pub fn send<'lua>(
+        lua: LuaContext<'lua>,
+        (entity, event, arg): (LuaEntity, String, LuaValue<'lua>),
+    ) -> LuaResult<()> {
+        with_fsm(lua, entity, |fsm| fsm.send(lua, &event, arg))?
+    }
+
+    pub fn broadcast<'lua>(
+        lua: LuaContext<'lua>,
+        (event, arg): (String, LuaValue<'lua>),
+    ) -> LuaResult<()> {
+        let world = lua.fetch_one::<World>()?;
+        for (_, mut fsm) in world.borrow().query::<&mut Fsm>().iter() {
+            fsm.send(lua, &event, arg.clone())?;
+        }
+        Ok(())
+    }
+
+    pub fn set_state<'lua>(
+        lua: LuaContext<'lua>,
+        (entity, state): (LuaEntity, String),
+    ) -> LuaResult<()> {
+        with_fsm(lua, entity, |fsm| fsm.set_state(&state))?.to_lua_err()
+    }
+
+    pub fn state<'lua>(lua: LuaContext<'lua>, entity: LuaEntity) -> LuaResult<Option<String>> {
+        with_fsm(lua, entity, |fsm| fsm.state().map(str::to_owned))
+    }
+
+    pub fn is_in<'lua>(
+        lua: LuaContext<'lua>,
+        (entity, state): (LuaEntity, String),
+    ) -> LuaResult<bool> {
+        with_fsm(lua, entity, |fsm| fsm.is_in(&state))
+    }
+}
+
+inventory::submit! {
+    crate::api::Module::parse("sludge.fsm", |lua| {
+        let table = lua.create_table_from(vec![
+            ("start", lua.create_function(api::start)?),
+            ("send", lua.create_function(api::send)?),
+            ("broadcast", lua.create_function(api::broadcast)?),
+            ("set_state", lua.create_function(api::set_state)?),
+            ("state", lua.create_function(api::state)?),
+            ("is_in", lua.create_function(api::is_in)?),
+        ])?;
+        table.set("TRANSITION_EVENT", TRANSITION_EVENT)?;
+
+        Ok(LuaValue::Table(table))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{components::Persistent, Space};
+
+    fn space() -> Result<Space> {
+        let mut space = Space::new()?;
+        space.register(FsmSystem, "Fsm", &[])?;
+        Ok(space)
+    }
+
+    fn spawn(space: &Space, source: &str) -> Result<Entity> {
+        let entity = space
+            .lua()
+            .context(|lua| lua.load(source).eval::<LuaEntity>())?;
+        Ok(entity.into())
+    }
+
+    fn send(space: &mut Space, entity: Entity, event: &str, arg: i64) -> Result<()> {
+        space.lua().context(|lua| {
+            let world = lua.fetch_one::<World>()?;
+            let world = world.borrow();
+            let mut fsm = world.get_mut::<Fsm>(entity)?;
+            fsm.send(lua, event, LuaValue::Integer(arg))?;
+            Ok::<_, Error>(())
+        })?;
+        space.fixed_update()
+    }
+
+    fn state(space: &Space, entity: Entity) -> Result<Option<String>> {
+        let world = space.world()?;
+        let world = world.borrow();
+        let fsm = world.get::<Fsm>(entity)?;
+        Ok(fsm.state().map(str::to_owned))
+    }
+
+    fn take_log(space: &Space) -> Result<Vec<String>> {
+        Ok(space.lua().context(|lua| {
+            let log = lua.globals().get::<_, Vec<String>>("log")?;
+            lua.globals().set("log", lua.create_table()?)?;
+            Ok::<_, LuaError>(log)
+        })?)
+    }
+
+    #[test]
+    fn transitions_exit_and_enter_in_order() -> Result<()> {
+        let mut space = space()?;
+        let guard = spawn(
+            &space,
+            r#"
+            log = {}
+            local function logged(name, state)
+                state.on_enter = function() table.insert(log, "enter " .. name) end
+                state.on_exit = function() table.insert(log, "exit " .. name) end
+                return state
+            end
+
+            return sludge.spawn {
+                Fsm = {
+                    initial = "patrol",
+                    states = {
+                        patrol = logged("patrol", {
+                            transitions = { { on = "noise", to = "alert" } },
+                        }),
+                        hostile = logged("hostile", {
+                            initial = "alert",
+                            states = {
+                                alert = logged("alert", {
+                                    transitions = { { on = "noise", to = "chase" } },
+                                }),
+                                chase = logged("chase", {}),
+                            },
+                            transitions = {
+                                { on = "noise", to = "patrol" },
+                                { on = "calm", to = "patrol" },
+                            },
+                        }),
+                    },
+                },
+            }
+            "#,
+        )?;
+
+        assert_eq!(state(&space, guard)?, None);
+        space.fixed_update()?;
+        assert_eq!(state(&space, guard)?.as_deref(), Some("patrol"));
+        assert_eq!(take_log(&space)?, ["enter patrol"]);
+
+        send(&mut space, guard, "noise", 0)?;
+        assert_eq!(state(&space, guard)?.as_deref(), Some("alert"));
+        assert_eq!(
+            take_log(&space)?,
+            ["exit patrol", "enter hostile", "enter alert"]
+        );
+
+        // The inner state's transition on `noise` takes priority over its parent's.
+        send(&mut space, guard, "noise", 0)?;
+        assert_eq!(state(&space, guard)?.as_deref(), Some("chase"));
+        assert_eq!(take_log(&space)?, ["exit alert", "enter chase"]);
+        {
+            let world = space.world()?;
+            let world = world.borrow();
+            let fsm = world.get::<Fsm>(guard)?;
+            assert!(fsm.is_in("hostile"));
+            assert_eq!(fsm.active().collect::<Vec<_>>(), ["hostile", "chase"]);
+        }
+
+        send(&mut space, guard, "calm", 0)?;
+        assert_eq!(state(&space, guard)?.as_deref(), Some("patrol"));
+        assert_eq!(
+            take_log(&space)?,
+            ["exit chase", "exit hostile", "enter patrol"]
+        );
+
+        send(&mut space, guard, "unknown", 0)?;
+        assert_eq!(state(&space, guard)?.as_deref(), Some("patrol"));
+        assert!(take_log(&space)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn guards_gate_transitions() -> Result<()> {
+        let mut space = space()?;
+        let entity = spawn(
+            &space,
+            r#"
+            go_home = false
+            return sludge.spawn {
+                Fsm = {
+                    initial = "idle",
+                    states = {
+                        idle = {
+                            transitions = {
+                                {
+                                    on = "hit",
+                                    when = function(entity, damage) return damage >= 3 end,
+                                    to = "hurt",
+                                },
+                                { when = function(entity) return go_home end, to = "home" },
+                            },
+                        },
+                        hurt = {},
+                        home = {},
+                    },
+                },
+            }
+            "#,
+        )?;
+
+        space.fixed_update()?;
+        send(&mut space, entity, "hit", 1)?;
+        assert_eq!(state(&space, entity)?.as_deref(), Some("idle"));
+        send(&mut space, entity, "hit", 5)?;
+        assert_eq!(state(&space, entity)?.as_deref(), Some("hurt"));
+
+        // Guard-only transitions are checked after queued state changes, on the same
+        // update.
+        space
+            .lua()
+            .context(|lua| lua.globals().set("go_home", true))?;
+        space
+            .world()?
+            .borrow()
+            .get_mut::<Fsm>(entity)?
+            .set_state("idle")?;
+        space.fixed_update()?;
+        assert_eq!(state(&space, entity)?.as_deref(), Some("home"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_definitions_are_rejected() {
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let check = |source: &str, message: &str| {
+                let definition = lua.load(source).eval::<LuaTable>().unwrap();
+                let err = Fsm::new(lua, definition).unwrap_err();
+                assert!(err.to_string().contains(message), "{}", err);
+            };
+
+            check("{ initial = 'a' }", "at least one state");
+            check("{ states = { a = {} } }", "initial state");
+            check(
+                "{ initial = 'a', states = { a = { transitions = { { on = 'x', to = 'b' } } } } }",
+                "nonexistent state `b`",
+            );
+            check(
+                "{ initial = 'a', states = { a = { transitions = { { to = 'a' } } } } }",
+                "neither an event nor a guard",
+            );
+            check(
+                "{ initial = 'a', states = { a = { states = { b = {} } } } }",
+                "no initial state",
+            );
+            check(
+                "{ initial = 'b', states = { a = { initial = 'b', states = { b = {} } } } }",
+                "not a top-level state",
+            );
+        });
+    }
+
+    #[test]
+    fn machines_persist_their_state_and_threads() -> Result<()> {
+        let mut space = space()?;
+        let entity = spawn(
+            &space,
+            r#"
+            return sludge.spawn {
+                Persistent = true,
+                Fsm = {
+                    initial = "asleep",
+                    states = {
+                        asleep = { transitions = { { on = "wake", to = "awake" } } },
+                        awake = {
+                            run = function(entity, state)
+                                yield("never")
+                            end,
+                            transitions = { { on = "sleep", to = "asleep" } },
+                        },
+                    },
+                },
+            }
+            "#,
+        )?;
+        space.fixed_update()?;
+        send(&mut space, entity, "wake", 0)?;
+        assert_eq!(state(&space, entity)?.as_deref(), Some("awake"));
+
+        let mut bytes = Vec::<u8>::new();
+        space.save(&mut bytes)?;
+        let mut loaded = self::space()?;
+        loaded.load(&mut &bytes[..])?;
+
+        let entity = {
+            let world = loaded.world()?;
+            let world = world.borrow();
+            let mut query = world.query::<&Fsm>().with::<Persistent>();
+            let (entity, fsm) = query.iter().next().expect("no state machine was loaded");
+            assert_eq!(fsm.state(), Some("awake"));
+            assert!(fsm.threads.contains_key("awake"));
+            entity
+        };
+
+        // The restored machine picks up where it left off, rather than starting over
+        // from its initial state.
+        loaded.fixed_update()?;
+        assert_eq!(state(&loaded, entity)?.as_deref(), Some("awake"));
+        send(&mut loaded, entity, "sleep", 0)?;
+        assert_eq!(state(&loaded, entity)?.as_deref(), Some("asleep"));
+        assert!(loaded
+            .world()?
+            .borrow()
+            .get::<Fsm>(entity)?
+            .threads
+            .is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,357 @@
+//! Keeping Lua's garbage collector from causing hitches.
+//!
+//! Left to itself, Lua's incremental collector does its work whenever allocation
+//! happens to trigger it, which means in the middle of whatever script is unlucky enough
+//! to be running at the time. When a lot of garbage is made every frame, that shows up
+//! as frames which take noticeably longer than their neighbours.
+//!
+//! Instead, a [`Scheduler`](crate::Scheduler) can be given a [`GcConfig`] with a time
+//! budget. At the end of every [update](crate::Scheduler::update), the scheduler runs
+//! incremental collection steps of [`GcConfig::step_kbytes`] until the budget is spent
+//! or a collection cycle is finished. With [`GcConfig::manual`] set, Lua's own collector
+//! is stopped entirely and the budgeted steps are the only collection which happens; the
+//! budget then has to be large enough to keep up with the garbage being made, or the
+//! heap will grow without bound. [`GcStats::heap`] is the thing to watch.
+//!
+//! For loading screens and other places where a hitch won't be noticed,
+//! [`Space::gc_full`](crate::Space::gc_full) runs a full collection on the spot.
+//!
+//! From Lua, the same controls are available from the `sludge.gc` module:
+//!
+//! ```lua
+//! sludge.gc.configure { manual = true, step_kbytes = 32, budget = 0.001 }
+//! sludge.gc.full()
+//! print(sludge.gc.stats().heap)
+//! ```
+
+use {
+    anyhow::*,
+    rlua::prelude::*,
+    std::{
+        collections::VecDeque,
+        sync::Mutex,
+        time::{Duration, Instant},
+    },
+};
+
+use crate::{profile, SchedulerQueue, SludgeLuaContextExt};
+
+/// How a scheduler drives the Lua garbage collector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GcConfig {
+    /// Whether Lua's own collector is stopped, leaving the budgeted steps to do all of
+    /// the collection.
+    pub manual: bool,
+    /// How many kilobytes of allocation each incremental step is worth, which decides
+    /// how much work a single step does. Zero runs the smallest step Lua can take.
+    pub step_kbytes: u32,
+    /// How long to spend stepping the collector at the end of every scheduler update.
+    /// Zero turns budgeted collection off.
+    pub budget: Duration,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            manual: false,
+            step_kbytes: 0,
+            budget: Duration::from_secs(0),
+        }
+    }
+}
+
+impl<'lua> FromLua<'lua> for GcConfig {
+    fn from_lua(lua_value: LuaValue<'lua>, _lua: LuaContext<'lua>) -> LuaResult<Self> {
+        let table = match lua_value {
+            LuaValue::Table(table) => table,
+            other => {
+                return Err(LuaError::FromLuaConversionError {
+                    from: other.type_name(),
+                    to: "GcConfig",
+                    message: Some("expected a table".to_owned()),
+                })
+            }
+        };
+
+        let default = Self::default();
+        Ok(Self {
+            manual: table
+                .get::<_, Option<_>>("manual")?
+                .unwrap_or(default.manual),
+            step_kbytes: table
+                .get::<_, Option<_>>("step_kbytes")?
+                .unwrap_or(default.step_kbytes),
+            budget: table
+                .get::<_, Option<f64>>("budget")?
+                .map_or(default.budget, |secs| Duration::from_secs_f64(secs.max(0.))),
+        })
+    }
+}
+
+/// What the collector has been up to. Converted to Lua as a table with the same fields;
+/// times are in seconds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcStats {
+    /// The size of the Lua heap after the most recent budgeted update or full
+    /// collection, in bytes.
+    pub heap: usize,
+    /// How many collection cycles have been finished by budgeted steps and full
+    /// collections. Cycles finished by Lua's own collector aren't counted.
+    pub cycles: u64,
+    /// How many of those cycles were finished in the last second.
+    pub cycles_per_second: u32,
+    /// How many incremental steps were run at the end of the most recent update.
+    pub steps: u32,
+    /// How long those steps took.
+    pub time: Duration,
+}
+
+impl<'lua> ToLua<'lua> for GcStats {
+    fn to_lua(self, lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+        let table = lua.create_table()?;
+        table.set("heap", self.heap)?;
+        table.set("cycles", self.cycles)?;
+        table.set("cycles_per_second", self.cycles_per_second)?;
+        table.set("steps", self.steps)?;
+        table.set("time", self.time.as_secs_f64())?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// A scheduler's collector settings and statistics. Shared between a scheduler and its
+/// [`SchedulerQueue`], so that it can be configured from Lua.
+#[derive(Debug, Default)]
+pub struct GcControl {
+    config: GcConfig,
+    /// Whether the collector was last stopped or restarted by us, if it's been touched at
+    /// all; Lua's collector is only told about changes to [`GcConfig::manual`].
+    stopped: Option<bool>,
+    stats: GcStats,
+    /// When each cycle in the last second was finished.
+    recent: VecDeque<Instant>,
+}
+
+impl GcControl {
+    pub fn config(&self) -> GcConfig {
+        self.config
+    }
+
+    /// Change how the collector is driven. Takes effect at the end of the next scheduler
+    /// update.
+    pub fn set_config(&mut self, config: GcConfig) {
+        self.config = config;
+    }
+
+    pub fn stats(&self) -> GcStats {
+        let mut stats = self.stats.clone();
+        stats.cycles_per_second = self.recent.len() as u32;
+        stats
+    }
+
+    fn record_cycle(&mut self, now: Instant) {
+        self.stats.cycles += 1;
+        self.recent.push_back(now);
+    }
+
+    fn forget_old_cycles(&mut self, now: Instant) {
+        let second = Duration::from_secs(1);
+        while matches!(self.recent.front(), Some(&then) if now - then > second) {
+            self.recent.pop_front();
+        }
+    }
+}
+
+/// The `collectgarbage` stashed in the registry, or the global one if it was never
+/// stashed.
+fn collectgarbage(lua: LuaContext) -> Result<LuaFunction> {
+    match lua
+        .named_registry_value::<_, Option<LuaFunction>>(profile::COLLECTGARBAGE_REGISTRY_KEY)?
+    {
+        Some(collectgarbage) => Ok(collectgarbage),
+        None => lua
+            .globals()
+            .get::<_, Option<LuaFunction>>("collectgarbage")?
+            .ok_or_else(|| anyhow!("`collectgarbage` is neither stashed nor global")),
+    }
+}
+
+/// Run budgeted collection steps, as configured in `control`. The lock is only held
+/// between calls into Lua, so finalizers are free to look at the collector's stats.
+///
+/// With a zero budget and nothing to tell Lua's collector, this doesn't touch Lua at
+/// all, so the default configuration costs nothing.
+pub(crate) fn step(lua: LuaContext, control: &Mutex<GcControl>) -> Result<()> {
+    let (config, stopped) = {
+        let control = control.lock().unwrap();
+        (control.config, control.stopped)
+    };
+
+    let toggle = stopped != Some(config.manual) && (config.manual || stopped.is_some());
+    if config.budget == Duration::from_secs(0) && !toggle {
+        return Ok(());
+    }
+
+    let collectgarbage = collectgarbage(lua)?;
+    if toggle {
+        collectgarbage.call::<_, ()>(if config.manual { "stop" } else { "restart" })?;
+        control.lock().unwrap().stopped = Some(config.manual);
+    }
+
+    let start = Instant::now();
+    let mut steps = 0;
+    let mut finished = false;
+    while !finished && start.elapsed() < config.budget {
+        finished = collectgarbage.call::<_, bool>(("step", config.step_kbytes))?;
+        steps += 1;
+    }
+
+    let now = Instant::now();
+    let heap = profile::heap_size(lua).unwrap_or(0) as usize;
+    let mut control = control.lock().unwrap();
+    if finished {
+        control.record_cycle(now);
+    }
+    control.forget_old_cycles(now);
+    control.stats.steps = steps;
+    control.stats.time = now - start;
+    control.stats.heap = heap;
+
+    Ok(())
+}
+
+/// Run a full collection cycle right away, whatever the configuration.
+pub(crate) fn full(lua: LuaContext, control: &Mutex<GcControl>) -> Result<()> {
+    collectgarbage(lua)?.call::<_, ()>("collect")?;
+
+    let now = Instant::now();
+    let heap = profile::heap_size(lua).unwrap_or(0) as usize;
+    let mut control = control.lock().unwrap();
+    control.record_cycle(now);
+    control.forget_old_cycles(now);
+    control.stats.heap = heap;
+
+    Ok(())
+}
+
+inventory::submit! {
+    crate::api::Module::parse("sludge.gc", |lua| {
+        let configure = lua.create_function(|lua, config: GcConfig| {
+            let queue = lua.fetch_one::<SchedulerQueue>()?;
+            queue.borrow().gc().lock().unwrap().set_config(config);
+            Ok(())
+        })?;
+
+        let full = lua.create_function(|lua, ()| {
+            let queue = lua.fetch_one::<SchedulerQueue>()?.borrow().clone();
+            full(lua, queue.gc()).to_lua_err()
+        })?;
+
+        let stats = lua.create_function(|lua, ()| {
+            let queue = lua.fetch_one::<SchedulerQueue>()?;
+            let stats = queue.borrow().gc().lock().unwrap().stats();
+            Ok(stats)
+        })?;
+
+        let table = lua.create_table_from(vec![
+            ("configure", configure),
+            ("full", full),
+            ("stats", stats),
+        ])?;
+
+        Ok(LuaValue::Table(table))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cycles_per_second_only_counts_the_last_second() {
+        let mut control = GcControl::default();
+        let start = Instant::now();
+        control.record_cycle(start);
+        control.record_cycle(start + Duration::from_millis(600));
+        control.record_cycle(start + Duration::from_millis(1200));
+        control.forget_old_cycles(start + Duration::from_millis(1200));
+
+        let stats = control.stats();
+        assert_eq!(stats.cycles, 3);
+        assert_eq!(stats.cycles_per_second, 2);
+    }
+
+    fn control(config: GcConfig) -> Mutex<GcControl> {
+        let mut control = GcControl::default();
+        control.set_config(config);
+        Mutex::new(control)
+    }
+
+    #[test]
+    fn default_config_leaves_lua_alone() -> Result<()> {
+        Lua::new().context(|lua| {
+            // Without a `collectgarbage` to call, anything but an early return is an error.
+            lua.globals().set("collectgarbage", LuaValue::Nil)?;
+            let control = control(GcConfig::default());
+            step(lua, &control)?;
+            assert_eq!(control.lock().unwrap().stats(), GcStats::default());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn budgeted_steps_are_counted() -> Result<()> {
+        Lua::new().context(|lua| {
+            profile::stash_collectgarbage(lua)?;
+            lua.load("garbage = {} for i = 1, 10000 do garbage[i] = {} end garbage = nil")
+                .exec()?;
+
+            let control = control(GcConfig {
+                budget: Duration::from_millis(50),
+                ..GcConfig::default()
+            });
+            step(lua, &control)?;
+
+            let stats = control.lock().unwrap().stats();
+            assert!(stats.steps > 0);
+            assert!(stats.heap > 0);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn manual_mode_stops_and_restarts_the_collector() -> Result<()> {
+        Lua::new().context(|lua| {
+            profile::stash_collectgarbage(lua)?;
+            let is_running =
+                || -> Result<bool> { Ok(lua.load("return collectgarbage('isrunning')").eval()?) };
+
+            let control = control(GcConfig {
+                manual: true,
+                ..GcConfig::default()
+            });
+            step(lua, &control)?;
+            assert!(!is_running()?);
+
+            control.lock().unwrap().set_config(GcConfig::default());
+            step(lua, &control)?;
+            assert!(is_running()?);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn full_collections_count_as_cycles() -> Result<()> {
+        Lua::new().context(|lua| {
+            profile::stash_collectgarbage(lua)?;
+            let control = control(GcConfig::default());
+            full(lua, &control)?;
+            full(lua, &control)?;
+
+            let stats = control.lock().unwrap().stats();
+            assert_eq!(stats.cycles, 2);
+            assert_eq!(stats.cycles_per_second, 2);
+            assert!(stats.heap > 0);
+            Ok(())
+        })
+    }
+}
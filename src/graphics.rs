@@ -1,12 +1,17 @@
 use crate::{
-    assets::{Asset, Cache, Cached, Key, Loaded},
-    ecs::{ScContext, SmartComponent},
+    assets::{Asset, Cache, Cached, DefaultCache, Key, Loaded},
+    conf::Conf,
+    dispatcher::Stage,
+    ecs::{CloneComponent, ScContext, SmartComponent},
     filesystem::Filesystem,
     math::*,
-    resources::Resources,
+    resources::{OwnedResources, Resources, SharedResources, UnifiedResources},
+    transform::Transform2d,
+    DefaultSystem, System,
 };
 use {
     anyhow::*,
+    crossbeam_channel::{Receiver, Sender},
     derivative::*,
     lyon::{
         math::*,
@@ -23,10 +28,13 @@ use {
         io::Read,
         marker::PhantomData,
         mem, ops,
+        path::Path,
         sync::{
             atomic::{self, AtomicBool},
             Arc, RwLock,
         },
+        thread::{self, JoinHandle},
+        time::Instant,
     },
     thunderdome::{self, Arena, Index},
 };
@@ -46,6 +54,84 @@ pub mod shader {
         }
     }
 
+    /// Shaders for [`SpriteBatch`]es which draw from more than one page. They take the
+    /// same inputs as the basic shaders, plus the page of each instance.
+    pub const PAGED_VERTEX: &'static str = include_str!("graphics/paged_es300.glslv");
+    pub const PAGED_FRAGMENT: &'static str = include_str!("graphics/paged_es300.glslf");
+
+    pub fn paged_meta() -> mq::ShaderMeta {
+        mq::ShaderMeta {
+            images: (0..SpriteBatch::MAX_PAGES)
+                .map(|i| format!("t_Page{}", i))
+                .collect(),
+            uniforms: mq::UniformBlockLayout {
+                uniforms: vec![mq::UniformDesc::new("u_MVP", mq::UniformType::Mat4)],
+            },
+        }
+    }
+
+    /// The parameters every built-in pipeline is created with, given how it blends.
+    pub fn pipeline_params(blend: BlendMode) -> mq::PipelineParams {
+        mq::PipelineParams {
+            color_blend: Some(blend.into()),
+            depth_test: mq::Comparison::LessOrEqual,
+            depth_write: true,
+            ..mq::PipelineParams::default()
+        }
+    }
+
+    /// Create the basic pipeline once for every [`BlendPreset`], in the order of
+    /// [`BlendPreset::ALL`]. miniquad can't delete pipelines, so this is done once by
+    /// the [`Graphics`] context.
+    pub(crate) fn basic_pipelines(mq: &mut mq::Context) -> Result<Vec<mq::Pipeline>> {
+        let shader = mq::Shader::new(mq, BASIC_VERTEX, BASIC_FRAGMENT, meta())?;
+        Ok(BlendPreset::ALL
+            .iter()
+            .map(|preset| {
+                mq::Pipeline::with_params(
+                    mq,
+                    &buffer_layouts(),
+                    &vertex_attributes(),
+                    shader,
+                    pipeline_params(preset.mode()),
+                )
+            })
+            .collect())
+    }
+
+    /// Create the pipelines shared by every multi-page [`SpriteBatch`], one for every
+    /// [`BlendPreset`] in the order of [`BlendPreset::ALL`].
+    pub(crate) fn paged_pipelines(mq: &mut mq::Context) -> Result<Vec<mq::Pipeline>> {
+        let shader = mq::Shader::new(mq, PAGED_VERTEX, PAGED_FRAGMENT, paged_meta())?;
+        let per_instance = mq::BufferLayout {
+            step_func: mq::VertexStep::PerInstance,
+            ..mq::BufferLayout::default()
+        };
+        let mut attributes = vertex_attributes().to_vec();
+        attributes.push(mq::VertexAttribute::with_buffer(
+            "a_Page",
+            mq::VertexFormat::Float1,
+            2,
+        ));
+
+        Ok(BlendPreset::ALL
+            .iter()
+            .map(|preset| {
+                mq::Pipeline::with_params(
+                    mq,
+                    &[
+                        mq::BufferLayout::default(),
+                        per_instance.clone(),
+                        per_instance.clone(),
+                    ],
+                    &attributes,
+                    shader,
+                    pipeline_params(preset.mode()),
+                )
+            })
+            .collect())
+    }
+
     #[repr(C)]
     pub struct Uniforms {
         pub mvp: Matrix4<f32>,
@@ -59,34 +145,125 @@ pub mod shader {
         pub color: LinearColor,
     }
 
+    /// The layouts of the two vertex buffers used by the basic shader: one of
+    /// [`Vertex`]es, and one of [`InstanceProperties`] stepped per instance.
+    pub fn buffer_layouts() -> [mq::BufferLayout; 2] {
+        [
+            mq::BufferLayout::default(),
+            mq::BufferLayout {
+                step_func: mq::VertexStep::PerInstance,
+                ..mq::BufferLayout::default()
+            },
+        ]
+    }
+
+    pub fn vertex_attributes() -> [mq::VertexAttribute; 7] {
+        [
+            mq::VertexAttribute::with_buffer("a_Pos", mq::VertexFormat::Float3, 0),
+            mq::VertexAttribute::with_buffer("a_Uv", mq::VertexFormat::Float2, 0),
+            mq::VertexAttribute::with_buffer("a_VertColor", mq::VertexFormat::Float4, 0),
+            mq::VertexAttribute::with_buffer("a_Src", mq::VertexFormat::Float4, 1),
+            mq::VertexAttribute::with_buffer("a_Tx", mq::VertexFormat::Mat4, 1),
+            mq::VertexAttribute::with_buffer("a_Color", mq::VertexFormat::Float4, 1),
+            mq::VertexAttribute::with_buffer("a_User", mq::VertexFormat::Float4, 1),
+        ]
+    }
+
     #[derive(Debug, Clone, Copy)]
     #[repr(C)]
     pub struct InstanceProperties {
         pub src: Vector4<f32>,
         pub tx: Matrix4<f32>,
         pub color: LinearColor,
+        /// Per-instance data for custom shaders, passed to the fragment shader as
+        /// `v_User`. Unused by the basic shader.
+        pub user: Vector4<f32>,
     }
 }
 
 pub use shader::{InstanceProperties, Uniforms, Vertex};
 
-// FIXME(sleffy): we aren't actually using `OwnedBuffer` and `Buffer` anywhere
+pub mod effects;
+pub mod grading;
+pub mod headless;
+pub mod lines;
+pub mod queue;
+pub mod recorder;
+
+pub use effects::{Effects, Outline, Shadow, Silhouette, Styled};
+pub use grading::{ColorGrading, Flash};
+pub use headless::NullGraphics;
+pub use lines::{Line, LineId, LineRenderer};
+pub use queue::{DrawKey, MaterialId, PassId, RenderQueue};
+pub use recorder::{Clip, ClipFormat, FrameRecorder, RecorderButton, RecorderConfig};
+
+/// A GPU resource waiting to be deleted.
+#[derive(Debug)]
+pub enum GpuResource {
+    Texture(mq::Texture),
+    Buffer(mq::Buffer),
+}
+
+impl GpuResource {
+    fn delete(self) {
+        match self {
+            Self::Texture(texture) => texture.delete(),
+            Self::Buffer(buffer) => buffer.delete(),
+        }
+    }
+}
+
+/// A handle to the queue of GPU resources waiting to be deleted by a [`Graphics`]
+/// context.
+///
+/// GPU handles can only be safely deleted on the thread which owns the context, and
+/// only while the context still exists. Types which own GPU resources, like
+/// [`Texture`], [`OwnedBuffer`], [`Mesh`] and [`SpriteBatch`], hold onto one of these
+/// and push their handles into it when dropped, which is safe from any thread; the
+/// handles are actually deleted the next time the context commits a frame. If the
+/// context has already been dropped, the handles are simply forgotten.
+///
+/// Pipelines aren't covered, since miniquad has no way to delete them.
+#[derive(Debug, Clone)]
+pub struct DeletionQueue {
+    sender: Sender<GpuResource>,
+}
+
+impl DeletionQueue {
+    fn new() -> (Self, Receiver<GpuResource>) {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        (Self { sender }, receiver)
+    }
+
+    /// Queue a resource for deletion.
+    pub fn push(&self, resource: GpuResource) {
+        // If the receiving end is gone, so is the context, and there's nothing left to
+        // delete the resource from.
+        let _ = self.sender.send(resource);
+    }
+}
+
 /// An `OwnedBuffer` represents either a VertexBuffer or an IndexBuffer, and
-/// can be used in a binding to a render pipeline
+/// can be used in a binding to a render pipeline. The buffer is queued for
+/// deletion when this is dropped.
 #[derive(Debug)]
 pub struct OwnedBuffer {
     pub buffer: mq::Buffer,
+    queue: DeletionQueue,
 }
 
-impl From<mq::Buffer> for OwnedBuffer {
-    fn from(buffer: mq::Buffer) -> Self {
-        Self { buffer }
+impl OwnedBuffer {
+    pub fn new(ctx: &Graphics, buffer: mq::Buffer) -> Self {
+        Self {
+            buffer,
+            queue: ctx.deletion_queue(),
+        }
     }
 }
 
 impl Drop for OwnedBuffer {
     fn drop(&mut self) {
-        self.buffer.delete();
+        self.queue.push(GpuResource::Buffer(self.buffer));
     }
 }
 
@@ -105,10 +282,10 @@ impl ops::Deref for Buffer {
     }
 }
 
-impl From<mq::Buffer> for Buffer {
-    fn from(buffer: mq::Buffer) -> Self {
+impl Buffer {
+    pub fn new(ctx: &Graphics, buffer: mq::Buffer) -> Self {
         Self {
-            shared: Arc::new(OwnedBuffer::from(buffer)),
+            shared: Arc::new(OwnedBuffer::new(ctx, buffer)),
         }
     }
 }
@@ -119,11 +296,12 @@ pub enum FilterMode {
     Linear,
 }
 
-/// A `Texture` is a safe type used to obtain asynchronous references to an
-/// `OwnedTexture`
+/// A `Texture` owns a GPU texture, which is queued for deletion when it's dropped.
+/// See [`DeletionQueue`] for details.
 #[derive(Debug)]
 pub struct Texture {
     pub handle: mq::Texture,
+    queue: DeletionQueue,
 }
 
 impl Texture {
@@ -131,7 +309,7 @@ impl Texture {
     pub fn from_rgba8(ctx: &mut Graphics, width: u16, height: u16, bytes: &[u8]) -> Self {
         let tex = mq::Texture::from_rgba8(&mut ctx.mq, width, height, bytes);
         tex.set_filter(&mut ctx.mq, mq::FilterMode::Nearest);
-        Self::from_inner(tex)
+        Self::from_inner(ctx, tex)
     }
 
     /// Parse a buffer containing the raw contents of an image file such as a PNG, GIF, etc.
@@ -152,8 +330,12 @@ impl Texture {
         Self::from_memory(ctx, &buf)
     }
 
-    pub fn from_inner(handle: mq::Texture) -> Self {
-        Self { handle }
+    /// Take ownership of a raw miniquad texture.
+    pub fn from_inner(ctx: &Graphics, handle: mq::Texture) -> Self {
+        Self {
+            handle,
+            queue: ctx.deletion_queue(),
+        }
     }
 
     pub fn set_filter_mode(&self, ctx: &mut Graphics, filter_mode: FilterMode) {
@@ -177,14 +359,14 @@ impl Texture {
 
 impl Drop for Texture {
     fn drop(&mut self) {
-        self.handle.delete();
+        self.queue.push(GpuResource::Texture(self.handle));
     }
 }
 
 impl Drawable for Texture {
     fn draw(&self, ctx: &mut Graphics, param: InstanceParam) {
-        ctx.quad_bindings.vertex_buffers[1].update(
-            &mut ctx.mq,
+        ctx.update_buffer(
+            ctx.quad_bindings.vertex_buffers[1],
             &[param
                 .scale2(Vector2::new(self.width() as f32, self.height() as f32))
                 .scale2(param.src.extents())
@@ -192,7 +374,7 @@ impl Drawable for Texture {
         );
         ctx.quad_bindings.images[0] = self.handle;
         ctx.mq.apply_bindings(&ctx.quad_bindings);
-        ctx.mq.draw(0, 6, 1);
+        ctx.draw_elements(6, 1);
     }
 }
 
@@ -202,6 +384,62 @@ pub struct Pipeline {
     pub mq: mq::Pipeline,
 }
 
+impl Pipeline {
+    /// Create a pipeline for a custom material, which draws everything the basic
+    /// pipeline can. The shaders take the same vertex attributes, uniforms and
+    /// texture as [`shader::BASIC_VERTEX`] and [`shader::BASIC_FRAGMENT`], so most
+    /// materials only need their own fragment shader, reading the instance's
+    /// [`InstanceParam::user`] data from `v_User`:
+    ///
+    /// ```glsl
+    /// #version 300 es
+    ///
+    /// uniform mediump sampler2D t_Texture;
+    /// in mediump vec2 v_Uv;
+    /// in mediump vec4 v_Color;
+    /// in mediump vec4 v_User;
+    /// out mediump vec4 Target0;
+    ///
+    /// void main() {
+    ///     mediump vec4 texel = texture(t_Texture, v_Uv) * v_Color;
+    ///     // Flash towards white by the amount in the first user component.
+    ///     Target0 = vec4(mix(texel.rgb, vec3(1.0), v_User.x), texel.a);
+    /// }
+    /// ```
+    pub fn new(
+        ctx: &mut Graphics,
+        vertex: &str,
+        fragment: &str,
+        blend: Option<BlendMode>,
+    ) -> Result<Self> {
+        let shader = mq::Shader::new(&mut ctx.mq, vertex, fragment, shader::meta())?;
+        let pipeline = mq::Pipeline::with_params(
+            &mut ctx.mq,
+            &shader::buffer_layouts(),
+            &shader::vertex_attributes(),
+            shader,
+            mq::PipelineParams {
+                color_blend: blend.map(mq::BlendState::from),
+                depth_test: mq::Comparison::LessOrEqual,
+                depth_write: true,
+                ..mq::PipelineParams::default()
+            },
+        );
+
+        Ok(Self { mq: pipeline })
+    }
+
+    /// Create a pipeline for a custom material using the basic vertex shader.
+    pub fn with_fragment(ctx: &mut Graphics, fragment: &str) -> Result<Self> {
+        Self::new(
+            ctx,
+            shader::BASIC_VERTEX,
+            fragment,
+            Some(BlendMode::default()),
+        )
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RenderPass {
     pub shared: Arc<mq::RenderPass>,
@@ -629,6 +867,100 @@ impl From<BlendMode> for mq::BlendState {
     }
 }
 
+/// The blend modes which the basic pipeline is built with ahead of time, so that they
+/// can be chosen per draw instead of by changing global state with
+/// [`Graphics::set_blend`].
+///
+/// A preset is chosen for a whole [`SpriteBatch`] with [`SpriteBatch::set_blend`], for
+/// a queued draw with [`DrawKey::blend`], or for everything drawn with the default
+/// pipeline with [`Graphics::set_blend_preset`]. From Lua, presets are given by their
+/// names: `"alpha"`, `"additive"`, `"multiply"` and `"premultiplied"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendPreset {
+    /// Ordinary alpha blending, which is what everything uses by default.
+    Alpha,
+    /// Adds the source color to what's underneath, weighted by its alpha; for glows,
+    /// sparks and anything else which should brighten.
+    Additive,
+    /// Multiplies what's underneath by the source color, ignoring alpha; for shadows
+    /// and tinting. Parts of the source which shouldn't change anything must be white.
+    Multiply,
+    /// Alpha blending for textures whose colors are already multiplied by their alpha.
+    Premultiplied,
+}
+
+impl Default for BlendPreset {
+    fn default() -> Self {
+        Self::Alpha
+    }
+}
+
+impl BlendPreset {
+    /// Every preset, in order.
+    pub const ALL: [BlendPreset; 4] = [
+        Self::Alpha,
+        Self::Additive,
+        Self::Multiply,
+        Self::Premultiplied,
+    ];
+
+    /// Where this preset is in [`BlendPreset::ALL`].
+    #[inline]
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Alpha => "alpha",
+            Self::Additive => "additive",
+            Self::Multiply => "multiply",
+            Self::Premultiplied => "premultiplied",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|preset| preset.name() == name)
+    }
+
+    pub fn mode(self) -> BlendMode {
+        use {BlendEquation::Add, BlendFactor::*};
+
+        match self {
+            Self::Alpha => BlendMode::default(),
+            Self::Additive => BlendMode::new(Add, SourceAlpha, One),
+            Self::Multiply => BlendMode::new(Add, DestinationColor, Zero),
+            Self::Premultiplied => BlendMode::new(Add, One, OneMinusSourceAlpha),
+        }
+    }
+}
+
+impl From<BlendPreset> for BlendMode {
+    fn from(preset: BlendPreset) -> Self {
+        preset.mode()
+    }
+}
+
+impl<'lua> ToLua<'lua> for BlendPreset {
+    fn to_lua(self, lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+        self.name().to_lua(lua)
+    }
+}
+
+impl<'lua> FromLua<'lua> for BlendPreset {
+    fn from_lua(value: LuaValue<'lua>, lua: LuaContext<'lua>) -> LuaResult<Self> {
+        let name = LuaString::from_lua(value, lua)?;
+        let name = name.to_str()?;
+        Self::from_name(name)
+            .ok_or_else(|| anyhow!("unknown blend preset `{}`", name))
+            .to_lua_err()
+    }
+}
+
 /// Specifies whether a mesh should be drawn
 /// filled or as an outline.
 #[derive(Debug, Copy, Clone)]
@@ -751,6 +1083,71 @@ impl TransformStack {
     }
 }
 
+/// Counts of the work done by a [`Graphics`] context over a single frame, for spotting
+/// batching problems: a frame with thousands of draw calls and pipeline switches is
+/// usually one where something which should have been batched wasn't.
+///
+/// The counts only cover work which goes through the context's own methods, which is
+/// everything drawn by the engine itself. Anything done directly with the raw miniquad
+/// context in [`Graphics::mq`] goes uncounted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GfxStats {
+    /// Calls to the GPU to draw something, each of which may draw many instances.
+    pub draw_calls: u32,
+    /// Instances drawn across every draw call.
+    pub instances: u32,
+    /// Writes of new data into vertex and index buffers.
+    pub buffer_uploads: u32,
+    /// Times a pipeline was applied, whether or not it was already the current one.
+    pub pipeline_switches: u32,
+    /// Render passes begun, including the default pass.
+    pub passes: u32,
+}
+
+impl<'lua> ToLua<'lua> for GfxStats {
+    fn to_lua(self, lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+        let table = lua.create_table()?;
+        table.set("draw_calls", self.draw_calls)?;
+        table.set("instances", self.instances)?;
+        table.set("buffer_uploads", self.buffer_uploads)?;
+        table.set("pipeline_switches", self.pipeline_switches)?;
+        table.set("passes", self.passes)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// Keeps a [`GfxStats`] resource in every space up to date with the
+/// [stats](Graphics::stats) of the last frame committed by the [`Graphics`] context in
+/// the space's resources, once per frame. Spaces without a `Graphics` context are left
+/// with empty stats.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GfxStatsSystem;
+
+inventory::submit! {
+    DefaultSystem::new("GfxStats", &[], Stage::Frame, || Box::new(GfxStatsSystem))
+}
+
+impl System for GfxStatsSystem {
+    fn init(
+        &self,
+        _lua: LuaContext,
+        local: &mut OwnedResources,
+        _global: Option<&SharedResources>,
+    ) -> Result<()> {
+        if !local.has_value::<GfxStats>() {
+            local.insert(GfxStats::default());
+        }
+        Ok(())
+    }
+
+    fn update(&self, _lua: LuaContext, resources: &UnifiedResources) -> Result<()> {
+        if let Ok(gfx) = resources.fetch_one::<Graphics>() {
+            *resources.fetch_one::<GfxStats>()?.borrow_mut() = gfx.borrow().stats();
+        }
+        Ok(())
+    }
+}
+
 /// The main graphics struct combines a bunch of mq types and the
 /// model view matrix to represent a basic context that can be drawn into
 #[derive(Derivative)]
@@ -758,55 +1155,67 @@ impl TransformStack {
 pub struct Graphics {
     #[derivative(Debug = "ignore")]
     pub mq: mq::Context,
+    /// The basic pipeline, with alpha blending.
     pub pipeline: mq::Pipeline,
+    /// The basic pipeline built with each [`BlendPreset`], indexed by
+    /// [`BlendPreset::index`].
+    pub blend_pipelines: Vec<mq::Pipeline>,
+    /// The preset the default pipeline blends with.
+    blend: BlendPreset,
+    /// The pipeline shared by every [`LineRenderer`].
+    pub line_pipeline: mq::Pipeline,
+    /// The pipelines shared by every [`SpriteBatch`] with more than one page, indexed by
+    /// [`BlendPreset::index`].
+    pub paged_pipelines: Vec<mq::Pipeline>,
+    /// The pipeline used to draw every [`Silhouette`].
+    pub silhouette_pipeline: mq::Pipeline,
+    /// The pipeline used to draw the frame through its [`ColorGrading`].
+    pub grading_pipeline: mq::Pipeline,
     pub null_texture: Cached<Texture>,
     pub projection: Matrix4<f32>,
     pub modelview: TransformStack,
     pub quad_bindings: mq::Bindings,
     pub render_passes: Vec<RenderPass>,
+    pub screenshot_requests: Vec<String>,
+    /// Draws waiting to be sorted and submitted; see the [`queue`] module.
+    pub queue: RenderQueue,
+    /// Adjustments made to the finished frame; see the [`grading`] module.
+    pub grading: ColorGrading,
+    /// The canvas the default pass is drawn into while the frame is being graded.
+    grading_target: Option<Canvas>,
+    /// Whether anything has been drawn into the grading canvas this frame.
+    graded_frame: bool,
+    /// When the last frame was committed, for fading out flashes.
+    last_graded: Option<Instant>,
+    pub(crate) fullscreen: bool,
+    pub(crate) vsync: bool,
+    pub(crate) window_title: String,
+    deletion_queue: DeletionQueue,
+    deleted: Receiver<GpuResource>,
+    /// Counts for the frame in progress.
+    stats: GfxStats,
+    /// Counts for the last committed frame.
+    last_stats: GfxStats,
+    /// Whether to read back the next committed frame; see [`Graphics::request_capture`].
+    capture_requested: bool,
+    captured: Option<Screenshot>,
 }
 
 impl Graphics {
     pub fn new(mut mq: mq::Context) -> Result<Self> {
-        let shader = mq::Shader::new(
-            &mut mq,
-            shader::BASIC_VERTEX,
-            shader::BASIC_FRAGMENT,
-            shader::meta(),
-        )?;
-
-        let pipeline = mq::Pipeline::with_params(
-            &mut mq,
-            &[
-                mq::BufferLayout::default(),
-                mq::BufferLayout {
-                    step_func: mq::VertexStep::PerInstance,
-                    ..mq::BufferLayout::default()
-                },
-            ],
-            &[
-                mq::VertexAttribute::with_buffer("a_Pos", mq::VertexFormat::Float3, 0),
-                mq::VertexAttribute::with_buffer("a_Uv", mq::VertexFormat::Float2, 0),
-                mq::VertexAttribute::with_buffer("a_VertColor", mq::VertexFormat::Float4, 0),
-                mq::VertexAttribute::with_buffer("a_Src", mq::VertexFormat::Float4, 1),
-                mq::VertexAttribute::with_buffer("a_Tx", mq::VertexFormat::Mat4, 1),
-                mq::VertexAttribute::with_buffer("a_Color", mq::VertexFormat::Float4, 1),
-            ],
-            shader,
-            mq::PipelineParams {
-                color_blend: Some(BlendMode::default().into()),
-                depth_test: mq::Comparison::LessOrEqual,
-                depth_write: true,
-                ..mq::PipelineParams::default()
-            },
-        );
-
-        let null_texture = Texture::from_inner(mq::Texture::from_rgba8(
-            &mut mq,
-            1,
-            1,
-            &[255, 255, 255, 255],
-        ));
+        let blend_pipelines = shader::basic_pipelines(&mut mq)?;
+        let pipeline = blend_pipelines[BlendPreset::Alpha.index()];
+        let line_pipeline = lines::pipeline(&mut mq)?;
+        let paged_pipelines = shader::paged_pipelines(&mut mq)?;
+        let silhouette_pipeline = effects::pipeline(&mut mq)?;
+        let grading_pipeline = grading::pipeline(&mut mq)?;
+
+        let (deletion_queue, deleted) = DeletionQueue::new();
+
+        let null_texture = Texture {
+            handle: mq::Texture::from_rgba8(&mut mq, 1, 1, &[255, 255, 255, 255]),
+            queue: deletion_queue.clone(),
+        };
 
         let quad_vertices =
             mq::Buffer::immutable(&mut mq, mq::BufferType::VertexBuffer, &quad_vertices());
@@ -828,14 +1237,49 @@ impl Graphics {
         Ok(Self {
             mq,
             pipeline,
+            blend_pipelines,
+            blend: BlendPreset::Alpha,
+            line_pipeline,
+            paged_pipelines,
+            silhouette_pipeline,
+            grading_pipeline,
             null_texture: null_texture.into(),
             projection: Matrix4::identity(),
             modelview: TransformStack::new(),
             quad_bindings,
             render_passes: Vec::new(),
+            screenshot_requests: Vec::new(),
+            queue: RenderQueue::new(),
+            grading: ColorGrading::new(),
+            grading_target: None,
+            graded_frame: false,
+            last_graded: None,
+            fullscreen: false,
+            vsync: true,
+            window_title: String::new(),
+            deletion_queue,
+            deleted,
+            stats: GfxStats::default(),
+            last_stats: GfxStats::default(),
+            capture_requested: false,
+            captured: None,
         })
     }
 
+    /// A handle to this context's queue of GPU resources waiting to be deleted.
+    #[inline]
+    pub fn deletion_queue(&self) -> DeletionQueue {
+        self.deletion_queue.clone()
+    }
+
+    /// Delete every GPU resource which has been dropped since the last call.
+    #[inline]
+    pub(crate) fn expire_gpu_resources(&mut self) {
+        for resource in self.deleted.try_iter() {
+            resource.delete();
+        }
+    }
+
     #[inline]
     pub(crate) fn register_render_pass(&mut self, pass: RenderPass) {
         self.render_passes.push(pass);
@@ -843,12 +1287,14 @@ impl Graphics {
 
     #[inline]
     pub(crate) fn expire_render_passes(&mut self) {
-        for pass in self
-            .render_passes
-            .drain_filter(|rp| Arc::strong_count(&rp.shared) == 1)
-        {
-            pass.shared.delete(&mut self.mq);
-        }
+        let mq = &mut self.mq;
+        self.render_passes.retain(|rp| {
+            let expired = Arc::strong_count(&rp.shared) == 1;
+            if expired {
+                rp.shared.delete(mq);
+            }
+            !expired
+        });
     }
 
     #[inline]
@@ -891,29 +1337,111 @@ impl Graphics {
         self.projection = projection.into();
     }
 
+    /// Apply the basic pipeline, blending with the current [`BlendPreset`].
     #[inline]
     pub fn apply_default_pipeline(&mut self) {
-        self.mq.apply_pipeline(&self.pipeline);
+        self.apply_mq_pipeline(self.blend_pipelines[self.blend.index()]);
+    }
+
+    /// The preset the default pipeline blends with.
+    #[inline]
+    pub fn blend_preset(&self) -> BlendPreset {
+        self.blend
+    }
+
+    /// Change the preset the default pipeline blends with, and apply the default
+    /// pipeline. Unlike [`Graphics::set_blend`], this survives switching pipelines:
+    /// drawables which restore the default pipeline when they're done restore this
+    /// preset along with it.
+    #[inline]
+    pub fn set_blend_preset(&mut self, preset: BlendPreset) {
+        self.blend = preset;
+        self.apply_default_pipeline();
     }
 
     #[inline]
     pub fn apply_pipeline(&mut self, pipeline: &Pipeline) {
-        self.mq.apply_pipeline(&pipeline.mq);
+        self.apply_mq_pipeline(pipeline.mq);
+    }
+
+    /// Apply a raw miniquad pipeline, counting the switch.
+    #[inline]
+    pub(crate) fn apply_mq_pipeline(&mut self, pipeline: mq::Pipeline) {
+        self.stats.pipeline_switches += 1;
+        self.mq.apply_pipeline(&pipeline);
+    }
+
+    /// Write `data` into a buffer, counting the upload.
+    #[inline]
+    pub(crate) fn update_buffer<T>(&mut self, buffer: mq::Buffer, data: &[T]) {
+        self.stats.buffer_uploads += 1;
+        buffer.update(&mut self.mq, data);
+    }
+
+    /// Apply `bindings` and draw `instances` instances of `num_elements` elements with
+    /// them, counting the draw call.
+    #[inline]
+    pub(crate) fn draw_bindings(
+        &mut self,
+        bindings: &mq::Bindings,
+        num_elements: i32,
+        instances: i32,
+    ) {
+        self.mq.apply_bindings(bindings);
+        self.draw_elements(num_elements, instances);
+    }
+
+    /// Draw with whatever bindings are already applied, counting the draw call.
+    #[inline]
+    pub(crate) fn draw_elements(&mut self, num_elements: i32, instances: i32) {
+        self.stats.draw_calls += 1;
+        self.stats.instances += instances.max(0) as u32;
+        self.mq.draw(0, num_elements, instances);
     }
 
+    /// Counts of the work done over the last committed frame.
+    #[inline]
+    pub fn stats(&self) -> GfxStats {
+        self.last_stats
+    }
+
+    /// Counts of the work done so far in the frame in progress.
+    #[inline]
+    pub fn frame_stats(&self) -> GfxStats {
+        self.stats
+    }
+
+    /// Finish the frame, first drawing anything left on the [`RenderQueue`] and then
+    /// applying the [`ColorGrading`]. The frame's [`GfxStats`] become available through
+    /// [`Graphics::stats`], and counting starts over for the next one.
     #[inline]
     pub fn commit_frame(&mut self) {
+        self.flush_queue();
+        self.finish_grading();
+        if mem::take(&mut self.capture_requested) {
+            self.captured = Some(self.screenshot());
+        }
         self.mq.commit_frame();
         self.expire_render_passes();
+        self.expire_gpu_resources();
+        self.last_stats = mem::take(&mut self.stats);
     }
 
+    /// Begin a pass on the screen, or on the grading canvas if there's any
+    /// [`ColorGrading`] in effect.
     #[inline]
     pub fn begin_default_pass(&mut self, action: PassAction) {
+        if self.begin_graded_pass(action) {
+            return;
+        }
+
+        self.stats.passes += 1;
         self.mq.begin_default_pass(action.into());
     }
 
     #[inline]
     pub fn begin_pass(&mut self, pass: &impl AsRef<RenderPass>, action: PassAction) {
+        self.stats.passes += 1;
         self.mq
             .begin_pass(**pass.as_ref(), mq::PassAction::from(action));
     }
@@ -932,6 +1460,9 @@ impl Graphics {
         drawable.draw(self, param.into().unwrap_or_default());
     }
 
+    /// Change the blending of whichever pipeline is applied, until another pipeline is
+    /// applied. For anything which lasts longer than a single draw, prefer a
+    /// [`BlendPreset`].
     #[inline]
     pub fn set_blend(&mut self, blend: Option<BlendMode>) {
         self.mq.set_blend(blend.map(mq::BlendState::from), None);
@@ -941,6 +1472,189 @@ impl Graphics {
     pub fn get_screen_size(&self) -> (f32, f32) {
         self.mq.screen_size()
     }
+
+    /// Record the window state the window was created with, so that the getters below
+    /// agree with it before anything has been changed at runtime.
+    pub(crate) fn apply_conf(&mut self, conf: &Conf) {
+        self.fullscreen = conf.fullscreen;
+        self.vsync = conf.vsync;
+        self.window_title = conf.window_title.clone();
+    }
+
+    /// Switch the window in or out of fullscreen mode.
+    pub fn set_fullscreen(&mut self, fullscreen: bool) {
+        self.mq.set_fullscreen(fullscreen);
+        self.fullscreen = fullscreen;
+    }
+
+    pub fn is_fullscreen(&self) -> bool {
+        self.fullscreen
+    }
+
+    pub fn toggle_fullscreen(&mut self) {
+        self.set_fullscreen(!self.fullscreen);
+    }
+
+    /// Resize the window. This has no effect while in fullscreen mode.
+    pub fn set_window_size(&mut self, width: u32, height: u32) {
+        self.mq.set_window_size(width, height);
+    }
+
+    /// The ratio of physical pixels to logical pixels on the window's display.
+    pub fn dpi_scale(&self) -> f32 {
+        self.mq.dpi_scale()
+    }
+
+    /// Turn vertical sync on or off.
+    pub fn set_vsync(&mut self, vsync: bool) {
+        self.mq.set_swap_interval(if vsync { 1 } else { 0 });
+        self.vsync = vsync;
+    }
+
+    pub fn vsync(&self) -> bool {
+        self.vsync
+    }
+
+    pub fn set_window_title(&mut self, title: &str) {
+        self.mq.set_window_title(title);
+        self.window_title = title.to_owned();
+    }
+
+    pub fn window_title(&self) -> &str {
+        &self.window_title
+    }
+
+    /// The resolutions of the connected monitors, in physical pixels. The monitor the
+    /// window is on comes first.
+    pub fn monitor_sizes(&self) -> Vec<(u32, u32)> {
+        self.mq.monitor_sizes()
+    }
+
+    /// Read back the contents of the default framebuffer. This should be called after
+    /// everything has been drawn for the frame, but before `commit_frame`. While the
+    /// frame is being graded, this reads the ungraded frame from the grading canvas.
+    pub fn screenshot(&mut self) -> Screenshot {
+        if let Some(target) = self.grading_target.as_ref().filter(|_| self.graded_frame) {
+            return target.read_pixels();
+        }
+
+        let (width, height) = self.mq.screen_size();
+        let (width, height) = (width as u32, height as u32);
+        let mut pixels = vec![0; width as usize * height as usize * 4];
+
+        unsafe {
+            use mq::sapp::*;
+
+            let mut bound_fbo: i32 = 0;
+            glGetIntegerv(GL_FRAMEBUFFER_BINDING, &mut bound_fbo);
+            glBindFramebuffer(GL_FRAMEBUFFER, 0);
+            glReadPixels(
+                0,
+                0,
+                width as i32,
+                height as i32,
+                GL_RGBA,
+                GL_UNSIGNED_BYTE,
+                pixels.as_mut_ptr() as *mut _,
+            );
+            glBindFramebuffer(GL_FRAMEBUFFER, bound_fbo as u32);
+        }
+
+        Screenshot::from_bottom_up(width, height, pixels)
+    }
+
+    /// Ask for a screenshot to be saved to `path` in the user directory the next time
+    /// [`Graphics::save_requested_screenshots`] is called. This is how Lua takes
+    /// screenshots, since scripts may run at any point in the frame.
+    pub fn request_screenshot(&mut self, path: impl Into<String>) {
+        self.screenshot_requests.push(path.into());
+    }
+
+    /// Read back the next frame when it's committed, after grading, to be picked up with
+    /// [`Graphics::take_capture`]. This is for code which can't run between drawing and
+    /// `commit_frame`, like the [`FrameRecorder`].
+    pub fn request_capture(&mut self) {
+        self.capture_requested = true;
+    }
+
+    /// Take the frame captured by the last [`Graphics::request_capture`], if it's been
+    /// committed since.
+    pub fn take_capture(&mut self) -> Option<Screenshot> {
+        self.captured.take()
+    }
+
+    /// Take a screenshot if any have been requested, and save it to every requested
+    /// path in the background. Like [`Graphics::screenshot`], this should be called
+    /// after everything has been drawn for the frame.
+    pub fn save_requested_screenshots(&mut self, fs: &Filesystem) -> Result<()> {
+        if self.screenshot_requests.is_empty() {
+            return Ok(());
+        }
+
+        let screenshot = Arc::new(self.screenshot());
+        for path in self.screenshot_requests.drain(..) {
+            let path = fs.user_path(&path)?;
+            let screenshot = screenshot.clone();
+            thread::spawn(move || {
+                if let Err(err) = screenshot.save_png(&path) {
+                    log::error!("error saving screenshot to {:?}: {:#}", path, err);
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// RGBA pixels read back from the GPU, with the top row first.
+#[derive(Debug, Clone)]
+pub struct Screenshot {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Screenshot {
+    /// OpenGL reads pixels starting from the bottom row, so they have to be flipped.
+    fn from_bottom_up(width: u32, height: u32, mut pixels: Vec<u8>) -> Self {
+        let stride = width as usize * 4;
+        for row in 0..height as usize / 2 {
+            let (top, bottom) = pixels.split_at_mut((height as usize - row - 1) * stride);
+            top[row * stride..(row + 1) * stride].swap_with_slice(&mut bottom[..stride]);
+        }
+
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    /// Encode the pixels as a PNG and write them to a path on disk, creating any
+    /// missing parent directories. To write into the user directory, use
+    /// [`Filesystem::user_path`] to find the path to write to.
+    pub fn save_png(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        image::save_buffer(
+            path,
+            &self.pixels,
+            self.width,
+            self.height,
+            image::ColorType::RGBA(8),
+        )?;
+
+        Ok(())
+    }
+
+    /// Encode and save the screenshot on a background thread.
+    pub fn save_png_async(self, path: impl AsRef<Path>) -> JoinHandle<Result<()>> {
+        let path = path.as_ref().to_owned();
+        thread::spawn(move || self.save_png(path))
+    }
 }
 
 #[derive(Debug)]
@@ -952,13 +1666,26 @@ pub struct Mesh {
     pub bindings: mq::Bindings,
     pub len: i32,
     pub aabb: Box2<f32>,
+    queue: DeletionQueue,
+}
+
+impl Drop for Mesh {
+    fn drop(&mut self) {
+        for &buffer in &self.bindings.vertex_buffers {
+            self.queue.push(GpuResource::Buffer(buffer));
+        }
+        self.queue
+            .push(GpuResource::Buffer(self.bindings.index_buffer));
+    }
 }
 
 impl Drawable for Mesh {
     fn draw(&self, ctx: &mut Graphics, param: InstanceParam) {
-        self.bindings.vertex_buffers[1].update(&mut ctx.mq, &[param.to_instance_properties()]);
-        ctx.mq.apply_bindings(&self.bindings);
-        ctx.mq.draw(0, self.len, 1);
+        ctx.update_buffer(
+            self.bindings.vertex_buffers[1],
+            &[param.to_instance_properties()],
+        );
+        ctx.draw_bindings(&self.bindings, self.len, 1);
     }
 }
 
@@ -1185,6 +1912,7 @@ impl MeshBuilder {
             },
             len: self.buffer.indices.len() as i32,
             aabb,
+            queue: ctx.deletion_queue(),
         }
     }
 }
@@ -1195,6 +1923,8 @@ pub struct InstanceParam {
     pub src: Box2<f32>,
     pub tx: Transform3<f32>,
     pub color: Color,
+    /// Extra per-instance data for custom materials; see [`Pipeline::new`].
+    pub user: Vector4<f32>,
 }
 
 impl Default for InstanceParam {
@@ -1203,7 +1933,55 @@ impl Default for InstanceParam {
             src: Box2::new(0., 0., 1., 1.),
             tx: Transform3::identity(),
             color: Color::WHITE,
+            user: Vector4::zeros(),
+        }
+    }
+}
+
+/// Instance parameters are read from Lua tables with the optional fields `x`, `y`,
+/// `rotation`, `sx`, `sy`, `ox`, `oy`, `src` (a box in texture coordinates), `color`
+/// and `user` (a list of up to four numbers for custom materials). The sprite is shifted
+/// so that its origin `(ox, oy)` is at `(0, 0)`, then scaled, then rotated, then
+/// translated.
+impl<'lua> FromLua<'lua> for InstanceParam {
+    fn from_lua(value: LuaValue<'lua>, lua: LuaContext<'lua>) -> LuaResult<Self> {
+        let table = LuaTable::from_lua(value, lua)?;
+        let mut param = Self::new().translate2(Vector2::new(
+            table.get::<_, Option<f32>>("x")?.unwrap_or(0.),
+            table.get::<_, Option<f32>>("y")?.unwrap_or(0.),
+        ));
+
+        if let Some(rotation) = table.get::<_, Option<f32>>("rotation")? {
+            param = param.rotate2(rotation);
+        }
+
+        param = param.scale2(Vector2::new(
+            table.get::<_, Option<f32>>("sx")?.unwrap_or(1.),
+            table.get::<_, Option<f32>>("sy")?.unwrap_or(1.),
+        ));
+
+        param = param.origin2(Vector2::new(
+            table.get::<_, Option<f32>>("ox")?.unwrap_or(0.),
+            table.get::<_, Option<f32>>("oy")?.unwrap_or(0.),
+        ));
+
+        if let Some(src) = table.get::<_, Option<Box2<f32>>>("src")? {
+            param = param.src(src);
         }
+
+        if let Some(color) = table.get::<_, Option<Color>>("color")? {
+            param = param.color(color);
+        }
+
+        if let Some(user) = table.get::<_, Option<Vec<f32>>>("user")? {
+            let mut v = Vector4::zeros();
+            for (dst, src) in v.iter_mut().zip(user) {
+                *dst = src;
+            }
+            param = param.user(v);
+        }
+
+        Ok(param)
     }
 }
 
@@ -1223,6 +2001,12 @@ impl InstanceParam {
         Self { color, ..self }
     }
 
+    /// Set the per-instance data passed to custom materials as `v_User`.
+    #[inline]
+    pub fn user(self, user: Vector4<f32>) -> Self {
+        Self { user, ..self }
+    }
+
     #[inline]
     pub fn rotate2(self, angle: f32) -> Self {
         Self {
@@ -1251,6 +2035,22 @@ impl InstanceParam {
         }
     }
 
+    /// Shift the sprite so that `origin`, in the sprite's own coordinates, lands on the
+    /// current position. Apply this after translating, rotating and scaling, so that
+    /// the sprite rotates and scales around its origin.
+    #[inline]
+    pub fn origin2(self, origin: Vector2<f32>) -> Self {
+        self.translate2(-origin)
+    }
+
+    /// Draw a frame of a [`SpriteSheet`](crate::sprite::SpriteSheet), positioned and
+    /// rotated around the frame's pivot. Like [`InstanceParam::origin2`], apply this
+    /// after any other transformations.
+    #[inline]
+    pub fn frame(self, frame: &crate::sprite::Frame) -> Self {
+        self.translate2(frame.offset).src(frame.uvs)
+    }
+
     #[inline]
     pub fn translate3(self, v: Vector3<f32>) -> Self {
         Self {
@@ -1267,14 +2067,22 @@ impl InstanceParam {
         }
     }
 
+    /// Prepend the global placement of a [`Transform2d`], converting it into a
+    /// matrix with its z-order as the z translation.
     #[inline]
-    pub fn to_instance_properties(&self) -> InstanceProperties {
+    pub fn prepend_transform2d(self, tx: &Transform2d) -> Self {
+        self.prepend_transform(&tx.global().to_transform3())
+    }
+
+    #[inline]
+    pub fn to_instance_properties(&self) -> InstanceProperties {
         let mins = self.src.mins;
         let extents = self.src.extents();
         InstanceProperties {
             src: Vector4::new(mins.x, mins.y, extents.x, extents.y),
             tx: *self.tx.matrix(),
             color: LinearColor::from(self.color),
+            user: self.user,
         }
     }
 
@@ -1319,27 +2127,62 @@ pub struct SpriteId(Index);
 
 impl<'a> SmartComponent<ScContext<'a>> for SpriteId {}
 
+// A copy would index the same sprite in the same batch.
+inventory::submit! {
+    CloneComponent::skip::<SpriteId>()
+}
+
+impl LuaUserData for SpriteId {}
+
 pub struct SpriteBatchIter<'a> {
-    iter: thunderdome::Iter<'a, InstanceParam>,
+    iter: thunderdome::Iter<'a, BatchedSprite>,
 }
 
 impl<'a> Iterator for SpriteBatchIter<'a> {
     type Item = (SpriteId, &'a InstanceParam);
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next().map(|(i, v)| (SpriteId(i), v))
+        self.iter.next().map(|(i, v)| (SpriteId(i), &v.param))
     }
 }
 
 pub struct SpriteBatchIterMut<'a> {
-    iter: thunderdome::IterMut<'a, InstanceParam>,
+    iter: thunderdome::IterMut<'a, BatchedSprite>,
 }
 
 impl<'a> Iterator for SpriteBatchIterMut<'a> {
     type Item = (SpriteId, &'a mut InstanceParam);
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next().map(|(i, v)| (SpriteId(i), v))
+        self.iter.next().map(|(i, v)| (SpriteId(i), &mut v.param))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BatchedSprite {
+    param: InstanceParam,
+    page: u32,
+}
+
+/// Fill `instances` and `pages` with the instance properties and page of every sprite,
+/// scaling each sprite up to the size of its source rectangle on its page.
+fn write_instances(
+    sprites: &Arena<BatchedSprite>,
+    page_sizes: &[Vector2<f32>],
+    instances: &mut Vec<InstanceProperties>,
+    pages: &mut Vec<f32>,
+) {
+    instances.clear();
+    pages.clear();
+    for (_, sprite) in sprites.iter() {
+        let param = &sprite.param;
+        instances.push(
+            param
+                .scale2(param.src.extents())
+                .scale2(page_sizes[sprite.page as usize])
+                .to_instance_properties(),
+        );
+        pages.push(sprite.page as f32);
     }
 }
 
@@ -1347,17 +2190,45 @@ impl<'a> Iterator for SpriteBatchIterMut<'a> {
 struct SpriteBatchInner {
     // Used to store the result of converting InstanceParams to InstanceProperties
     instances: Vec<InstanceProperties>,
+    /// The page of each instance, for batches with more than one page.
+    pages: Vec<f32>,
     /// Capacity is used to store the length of the buffers inside of mq::Bindings
     capacity: usize,
     bindings: mq::Bindings,
 }
 
+/// A batch of sprites drawn in a single draw call.
+///
+/// A batch draws from one texture by default, but atlases which don't fit on a single
+/// texture can be split across up to [`SpriteBatch::MAX_PAGES`] pages, added with
+/// [`SpriteBatch::add_page`]. Every sprite is drawn from one page, chosen when it's
+/// inserted with [`SpriteBatch::insert_on_page`], and its source rectangle is relative
+/// to that page. A batch with more than one page draws with the paged pipeline
+/// instead of whichever one is applied, and leaves the default pipeline applied when
+/// it's done.
+///
+/// A batch given a [`BlendPreset`] with [`SpriteBatch::set_blend`] likewise draws with
+/// the basic or paged pipeline for that preset, whichever pipeline is applied, and
+/// leaves the default pipeline applied when it's done.
 #[derive(Debug)]
 pub struct SpriteBatch {
-    sprites: Arena<InstanceParam>,
+    sprites: Arena<BatchedSprite>,
     inner: RwLock<SpriteBatchInner>,
     dirty: AtomicBool,
-    texture: Cached<Texture>,
+    blend: Option<BlendPreset>,
+    textures: Vec<Cached<Texture>>,
+    queue: DeletionQueue,
+}
+
+impl Drop for SpriteBatch {
+    fn drop(&mut self) {
+        // Only the instance and page buffers belong to the batch; the quad's vertex and
+        // index buffers are shared with the context.
+        let inner = self.inner.get_mut().unwrap();
+        for &buffer in &inner.bindings.vertex_buffers[1..] {
+            self.queue.push(GpuResource::Buffer(buffer));
+        }
+    }
 }
 
 impl ops::Index<SpriteId> for SpriteBatch {
@@ -1365,7 +2236,7 @@ impl ops::Index<SpriteId> for SpriteBatch {
 
     #[inline]
     fn index(&self, index: SpriteId) -> &Self::Output {
-        &self.sprites[index.0]
+        &self.sprites[index.0].param
     }
 }
 
@@ -1373,11 +2244,14 @@ impl ops::IndexMut<SpriteId> for SpriteBatch {
     #[inline]
     fn index_mut(&mut self, index: SpriteId) -> &mut Self::Output {
         self.dirty = AtomicBool::new(true);
-        &mut self.sprites[index.0]
+        &mut self.sprites[index.0].param
     }
 }
 
 impl SpriteBatch {
+    /// The most pages a single batch can draw from.
+    pub const MAX_PAGES: usize = 4;
+
     pub fn new<T>(ctx: &mut Graphics, texture: T) -> Self
     where
         T: Into<Cached<Texture>>,
@@ -1397,30 +2271,67 @@ impl SpriteBatch {
             mq::BufferType::VertexBuffer,
             capacity * mem::size_of::<InstanceProperties>(),
         );
+        let pages = mq::Buffer::stream(
+            &mut ctx.mq,
+            mq::BufferType::VertexBuffer,
+            capacity * mem::size_of::<f32>(),
+        );
 
         let bindings = mq::Bindings {
-            vertex_buffers: vec![ctx.quad_bindings.vertex_buffers[0], instances],
+            vertex_buffers: vec![ctx.quad_bindings.vertex_buffers[0], instances, pages],
             index_buffer: ctx.quad_bindings.index_buffer,
-            images: vec![texture.load_cached().handle],
+            images: vec![texture.load_cached().handle; Self::MAX_PAGES],
         };
 
         Self {
             sprites: Arena::new(),
             inner: SpriteBatchInner {
                 instances: Vec::new(),
+                pages: Vec::new(),
                 capacity,
                 bindings,
             }
             .into(),
             dirty: AtomicBool::new(true),
-            texture,
+            blend: None,
+            textures: vec![texture],
+            queue: ctx.deletion_queue(),
+        }
+    }
+
+    /// Create a batch which draws from several pages at once.
+    pub fn with_pages<I>(ctx: &mut Graphics, pages: I, capacity: usize) -> Result<Self>
+    where
+        I: IntoIterator,
+        I::Item: Into<Cached<Texture>>,
+    {
+        let mut pages = pages.into_iter();
+        let first = pages
+            .next()
+            .ok_or_else(|| anyhow!("a sprite batch needs at least one page"))?;
+        let mut batch = Self::with_capacity(ctx, first, capacity);
+        for page in pages {
+            batch.add_page(page)?;
         }
+        Ok(batch)
     }
 
     #[inline]
     pub fn insert(&mut self, param: InstanceParam) -> SpriteId {
         *self.dirty.get_mut() = true;
-        SpriteId(self.sprites.insert(param))
+        SpriteId(self.sprites.insert(BatchedSprite { param, page: 0 }))
+    }
+
+    /// Insert a sprite drawn from the given page.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the batch doesn't have that many pages.
+    #[inline]
+    pub fn insert_on_page(&mut self, page: u32, param: InstanceParam) -> SpriteId {
+        assert!((page as usize) < self.textures.len(), "no page {}", page);
+        *self.dirty.get_mut() = true;
+        SpriteId(self.sprites.insert(BatchedSprite { param, page }))
     }
 
     #[inline]
@@ -1429,21 +2340,113 @@ impl SpriteBatch {
         self.sprites.remove(index.0);
     }
 
+    #[inline]
+    pub fn get(&self, index: SpriteId) -> Option<&InstanceParam> {
+        self.sprites.get(index.0).map(|sprite| &sprite.param)
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, index: SpriteId) -> Option<&mut InstanceParam> {
+        *self.dirty.get_mut() = true;
+        self.sprites
+            .get_mut(index.0)
+            .map(|sprite| &mut sprite.param)
+    }
+
+    /// The page a sprite is drawn from.
+    #[inline]
+    pub fn page_of(&self, index: SpriteId) -> Option<u32> {
+        self.sprites.get(index.0).map(|sprite| sprite.page)
+    }
+
+    /// Move a sprite to another page. Returns `false` if there's no such sprite or no
+    /// such page.
+    #[inline]
+    pub fn set_page_of(&mut self, index: SpriteId, page: u32) -> bool {
+        if page as usize >= self.textures.len() {
+            return false;
+        }
+
+        match self.sprites.get_mut(index.0) {
+            Some(sprite) => {
+                *self.dirty.get_mut() = true;
+                sprite.page = page;
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.sprites.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.sprites.is_empty()
+    }
+
     #[inline]
     pub fn clear(&mut self) {
         *self.dirty.get_mut() = true;
         self.sprites.clear();
     }
 
+    /// The texture of the first page.
     #[inline]
     pub fn texture(&self) -> &Cached<Texture> {
-        &self.texture
+        &self.textures[0]
     }
 
+    /// Replace the texture of the first page.
     #[inline]
     pub fn set_texture(&mut self, texture: impl Into<Cached<Texture>>) {
         *self.dirty.get_mut() = true;
-        self.texture = texture.into();
+        self.textures[0] = texture.into();
+    }
+
+    /// The texture of every page, in order.
+    #[inline]
+    pub fn pages(&self) -> &[Cached<Texture>] {
+        &self.textures
+    }
+
+    /// Add a page, returning its index.
+    pub fn add_page(&mut self, texture: impl Into<Cached<Texture>>) -> Result<u32> {
+        ensure!(
+            self.textures.len() < Self::MAX_PAGES,
+            "a sprite batch can't have more than {} pages",
+            Self::MAX_PAGES
+        );
+
+        *self.dirty.get_mut() = true;
+        self.textures.push(texture.into());
+        Ok(self.textures.len() as u32 - 1)
+    }
+
+    /// The blend preset the batch draws with, if it has its own.
+    #[inline]
+    pub fn blend(&self) -> Option<BlendPreset> {
+        self.blend
+    }
+
+    /// Draw the batch with a blend preset of its own, or with `None`, blend however the
+    /// pipeline it's drawn with does.
+    #[inline]
+    pub fn set_blend(&mut self, blend: impl Into<Option<BlendPreset>>) {
+        self.blend = blend.into();
+    }
+
+    /// Replace the texture of a page.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the batch doesn't have that many pages.
+    #[inline]
+    pub fn set_page(&mut self, page: u32, texture: impl Into<Cached<Texture>>) {
+        *self.dirty.get_mut() = true;
+        self.textures[page as usize] = texture.into();
     }
 
     pub fn flush(&self, ctx: &mut Graphics) {
@@ -1452,37 +2455,52 @@ impl SpriteBatch {
         }
 
         let inner = &mut *self.inner.write().unwrap();
-        let texture = self.texture.load();
-
-        inner.instances.clear();
-        inner
-            .instances
-            .extend(self.sprites.iter().map(|(_, param)| {
-                param
-                    .scale2(param.src.extents())
-                    .scale2(Vector2::new(
-                        texture.width() as f32,
-                        texture.height() as f32,
-                    ))
-                    .to_instance_properties()
-            }));
-
-        if inner.instances.len() > inner.capacity {
-            let new_capacity = inner.instances.len().checked_next_power_of_two().unwrap();
-            let new_buffer = mq::Buffer::stream(
+        let textures = self
+            .textures
+            .iter()
+            .map(|texture| texture.load())
+            .collect::<Vec<_>>();
+        let page_sizes = textures
+            .iter()
+            .map(|texture| Vector2::new(texture.width() as f32, texture.height() as f32))
+            .collect::<Vec<_>>();
+
+        write_instances(
+            &self.sprites,
+            &page_sizes,
+            &mut inner.instances,
+            &mut inner.pages,
+        );
+
+        if let Some(new_capacity) = grown_capacity(inner.instances.len(), inner.capacity) {
+            let new_instances = mq::Buffer::stream(
                 &mut ctx.mq,
                 mq::BufferType::VertexBuffer,
                 new_capacity * mem::size_of::<InstanceProperties>(),
             );
+            let new_pages = mq::Buffer::stream(
+                &mut ctx.mq,
+                mq::BufferType::VertexBuffer,
+                new_capacity * mem::size_of::<f32>(),
+            );
 
-            let old_buffer = mem::replace(&mut inner.bindings.vertex_buffers[1], new_buffer);
-            old_buffer.delete();
+            let old_instances = mem::replace(&mut inner.bindings.vertex_buffers[1], new_instances);
+            let old_pages = mem::replace(&mut inner.bindings.vertex_buffers[2], new_pages);
+            self.queue.push(GpuResource::Buffer(old_instances));
+            self.queue.push(GpuResource::Buffer(old_pages));
 
             inner.capacity = new_capacity;
         }
 
-        inner.bindings.vertex_buffers[1].update(&mut ctx.mq, &inner.instances);
-        inner.bindings.images[0] = texture.handle;
+        ctx.update_buffer(inner.bindings.vertex_buffers[1], &inner.instances);
+        if textures.len() > 1 {
+            ctx.update_buffer(inner.bindings.vertex_buffers[2], &inner.pages);
+        }
+
+        // Unused samplers still need something bound, so they get the first page.
+        for (i, image) in inner.bindings.images.iter_mut().enumerate() {
+            *image = textures.get(i).unwrap_or(&textures[0]).handle;
+        }
 
         self.dirty.store(false, atomic::Ordering::Relaxed);
     }
@@ -1507,17 +2525,404 @@ impl Drawable for SpriteBatch {
     fn draw(&self, ctx: &mut Graphics, instance: InstanceParam) {
         self.flush(ctx);
         let inner = self.inner.read().unwrap();
+        let paged = self.textures.len() > 1;
+        let blend = self.blend.unwrap_or(ctx.blend);
 
         ctx.push_multiplied_transform(instance.tx.to_homogeneous());
+        if paged {
+            ctx.apply_mq_pipeline(ctx.paged_pipelines[blend.index()]);
+        } else if self.blend.is_some() {
+            ctx.apply_mq_pipeline(ctx.blend_pipelines[blend.index()]);
+        }
         ctx.mq.apply_bindings(&inner.bindings);
         ctx.apply_transforms();
         // 6 here because a quad is 6 vertices
-        ctx.mq.draw(0, 6, inner.instances.len() as i32);
+        ctx.draw_elements(6, inner.instances.len() as i32);
+        if paged || self.blend.is_some() {
+            ctx.apply_default_pipeline();
+        }
+        ctx.pop_transform();
+        ctx.apply_transforms();
+    }
+}
+
+/// Represents the index of an instance within a `MeshBatch`
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct MeshInstanceId(Index);
+
+pub struct MeshBatchIter<'a> {
+    iter: thunderdome::Iter<'a, InstanceParam>,
+}
+
+impl<'a> Iterator for MeshBatchIter<'a> {
+    type Item = (MeshInstanceId, &'a InstanceParam);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|(i, v)| (MeshInstanceId(i), v))
+    }
+}
+
+pub struct MeshBatchIterMut<'a> {
+    iter: thunderdome::IterMut<'a, InstanceParam>,
+}
+
+impl<'a> Iterator for MeshBatchIterMut<'a> {
+    type Item = (MeshInstanceId, &'a mut InstanceParam);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|(i, v)| (MeshInstanceId(i), v))
+    }
+}
+
+/// Fill `instances` with the instance properties of every instance of a mesh batch.
+fn write_mesh_instances(params: &Arena<InstanceParam>, instances: &mut Vec<InstanceProperties>) {
+    instances.clear();
+    instances.extend(
+        params
+            .iter()
+            .map(|(_, param)| param.to_instance_properties()),
+    );
+}
+
+/// The capacity an instance buffer with room for `capacity` instances has to grow to in
+/// order to hold `len` of them, if it has to grow at all.
+fn grown_capacity(len: usize, capacity: usize) -> Option<usize> {
+    if len > capacity {
+        Some(len.checked_next_power_of_two().unwrap())
+    } else {
+        None
+    }
+}
+
+#[derive(Debug)]
+struct MeshBatchInner {
+    // Used to store the result of converting InstanceParams to InstanceProperties
+    instances: Vec<InstanceProperties>,
+    /// Capacity is used to store the length of the instance buffer inside of mq::Bindings
+    capacity: usize,
+    bindings: mq::Bindings,
+}
+
+/// Many copies of the same [`Mesh`] drawn in a single draw call, for geometry which is
+/// repeated all over the screen, like glowing bullets or hexagonal tiles.
+///
+/// This works like a [`SpriteBatch`], except that every instance is a copy of the
+/// batch's mesh instead of a textured quad. Instances are positioned with the `tx` and
+/// tinted with the `color` of their [`InstanceParam`]s; since the mesh's vertices
+/// already have texture coordinates, `src` is passed along to the shader as is, and
+/// should usually be left as the whole texture.
+#[derive(Debug)]
+pub struct MeshBatch {
+    mesh: Mesh,
+    instances: Arena<InstanceParam>,
+    inner: RwLock<MeshBatchInner>,
+    dirty: AtomicBool,
+    queue: DeletionQueue,
+}
+
+impl Drop for MeshBatch {
+    fn drop(&mut self) {
+        // Only the instance buffer belongs to the batch; the mesh cleans up after itself.
+        let inner = self.inner.get_mut().unwrap();
+        self.queue
+            .push(GpuResource::Buffer(inner.bindings.vertex_buffers[1]));
+    }
+}
+
+impl ops::Index<MeshInstanceId> for MeshBatch {
+    type Output = InstanceParam;
+
+    #[inline]
+    fn index(&self, index: MeshInstanceId) -> &Self::Output {
+        &self.instances[index.0]
+    }
+}
+
+impl ops::IndexMut<MeshInstanceId> for MeshBatch {
+    #[inline]
+    fn index_mut(&mut self, index: MeshInstanceId) -> &mut Self::Output {
+        *self.dirty.get_mut() = true;
+        &mut self.instances[index.0]
+    }
+}
+
+impl MeshBatch {
+    pub fn new(ctx: &mut Graphics, mesh: Mesh) -> Self {
+        const DEFAULT_MESHBATCH_CAPACITY: usize = 64;
+        Self::with_capacity(ctx, mesh, DEFAULT_MESHBATCH_CAPACITY)
+    }
+
+    pub fn with_capacity(ctx: &mut Graphics, mesh: Mesh, capacity: usize) -> Self {
+        let instances = mq::Buffer::stream(
+            &mut ctx.mq,
+            mq::BufferType::VertexBuffer,
+            capacity * mem::size_of::<InstanceProperties>(),
+        );
+
+        let bindings = mq::Bindings {
+            vertex_buffers: vec![mesh.bindings.vertex_buffers[0], instances],
+            index_buffer: mesh.bindings.index_buffer,
+            images: mesh.bindings.images.clone(),
+        };
+
+        Self {
+            mesh,
+            instances: Arena::new(),
+            inner: MeshBatchInner {
+                instances: Vec::new(),
+                capacity,
+                bindings,
+            }
+            .into(),
+            dirty: AtomicBool::new(true),
+            queue: ctx.deletion_queue(),
+        }
+    }
+
+    /// The mesh every instance is a copy of.
+    #[inline]
+    pub fn mesh(&self) -> &Mesh {
+        &self.mesh
+    }
+
+    #[inline]
+    pub fn insert(&mut self, param: InstanceParam) -> MeshInstanceId {
+        *self.dirty.get_mut() = true;
+        MeshInstanceId(self.instances.insert(param))
+    }
+
+    #[inline]
+    pub fn remove(&mut self, index: MeshInstanceId) {
+        *self.dirty.get_mut() = true;
+        self.instances.remove(index.0);
+    }
+
+    #[inline]
+    pub fn get(&self, index: MeshInstanceId) -> Option<&InstanceParam> {
+        self.instances.get(index.0)
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, index: MeshInstanceId) -> Option<&mut InstanceParam> {
+        *self.dirty.get_mut() = true;
+        self.instances.get_mut(index.0)
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        *self.dirty.get_mut() = true;
+        self.instances.clear();
+    }
+
+    /// Upload the instances to the GPU if they've changed since the last flush, growing
+    /// the instance buffer if it's too small to hold them all.
+    pub fn flush(&self, ctx: &mut Graphics) {
+        if !self.dirty.load(atomic::Ordering::Relaxed) {
+            return;
+        }
+
+        let inner = &mut *self.inner.write().unwrap();
+        write_mesh_instances(&self.instances, &mut inner.instances);
+
+        if let Some(new_capacity) = grown_capacity(inner.instances.len(), inner.capacity) {
+            let new_instances = mq::Buffer::stream(
+                &mut ctx.mq,
+                mq::BufferType::VertexBuffer,
+                new_capacity * mem::size_of::<InstanceProperties>(),
+            );
+
+            let old_instances = mem::replace(&mut inner.bindings.vertex_buffers[1], new_instances);
+            self.queue.push(GpuResource::Buffer(old_instances));
+
+            inner.capacity = new_capacity;
+        }
+
+        ctx.update_buffer(inner.bindings.vertex_buffers[1], &inner.instances);
+        self.dirty.store(false, atomic::Ordering::Relaxed);
+    }
+
+    pub fn iter(&self) -> MeshBatchIter<'_> {
+        MeshBatchIter {
+            iter: self.instances.iter(),
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> MeshBatchIterMut<'_> {
+        *self.dirty.get_mut() = true;
+        MeshBatchIterMut {
+            iter: self.instances.iter_mut(),
+        }
+    }
+}
+
+/// Like the `SpriteBatch` implementation, this only uses the `tx` of the
+/// `InstanceParam`, which transforms every instance in the batch at once.
+impl Drawable for MeshBatch {
+    fn draw(&self, ctx: &mut Graphics, instance: InstanceParam) {
+        self.flush(ctx);
+        let inner = self.inner.read().unwrap();
+
+        ctx.push_multiplied_transform(instance.tx.to_homogeneous());
+        ctx.mq.apply_bindings(&inner.bindings);
+        ctx.apply_transforms();
+        ctx.draw_elements(self.mesh.len, inner.instances.len() as i32);
         ctx.pop_transform();
         ctx.apply_transforms();
     }
 }
 
+/// A `SpriteBatch` shared with Lua, created with `sludge.graphics.sprite_batch(path,
+/// capacity)`. Sprites are described to Lua as tables of instance parameters; see the
+/// `FromLua` implementation of [`InstanceParam`].
+///
+/// There's no Lua-side render queue, so batches are drawn from Lua with
+/// `batch:draw(params, effects)` while the space's `Graphics` is available, or from
+/// Rust by drawing the shared batch directly. `effects` is an optional shadow and
+/// outline; see the `FromLua` implementation of [`Effects`].
+///
+/// Passing a list of paths instead of a single one creates a batch with several pages.
+/// Pages are numbered from 1 on the Lua side, so `batch:insert(params, 2)` draws a
+/// sprite from the second page.
+///
+/// `batch:set_blend("additive")` gives the batch a [`BlendPreset`] of its own, and
+/// `batch:set_blend(nil)` takes it away again.
+#[derive(Debug, Clone)]
+pub struct LuaSpriteBatch {
+    pub shared: Arc<RwLock<SpriteBatch>>,
+}
+
+impl LuaSpriteBatch {
+    fn load_texture(lua: LuaContext, path: &str) -> LuaResult<Cached<Texture>> {
+        use crate::SludgeLuaContextExt;
+        lua.fetch_one::<DefaultCache>()?
+            .borrow()
+            .get::<Texture>(&Key::path(path))
+            .to_lua_err()
+    }
+
+    /// Convert a page number from Lua, counting from 1, into a page index.
+    fn page_index(batch: &SpriteBatch, page: u32) -> LuaResult<u32> {
+        match page.checked_sub(1) {
+            Some(index) if (index as usize) < batch.pages().len() => Ok(index),
+            _ => Err(anyhow!(
+                "no page {} in a sprite batch with {} pages",
+                page,
+                batch.pages().len()
+            ))
+            .to_lua_err(),
+        }
+    }
+}
+
+impl LuaUserData for LuaSpriteBatch {
+    fn add_methods<'lua, T: LuaUserDataMethods<'lua, Self>>(methods: &mut T) {
+        use crate::SludgeLuaContextExt;
+
+        methods.add_method(
+            "insert",
+            |_lua, this, (param, page): (InstanceParam, Option<u32>)| {
+                let mut batch = this.shared.write().unwrap();
+                let page = Self::page_index(&batch, page.unwrap_or(1))?;
+                Ok(batch.insert_on_page(page, param))
+            },
+        );
+
+        methods.add_method("add_page", |lua, this, path: String| {
+            let texture = Self::load_texture(lua, &path)?;
+            let page = this
+                .shared
+                .write()
+                .unwrap()
+                .add_page(texture)
+                .to_lua_err()?;
+            Ok(page + 1)
+        });
+
+        methods.add_method("page_count", |_lua, this, ()| {
+            Ok(this.shared.read().unwrap().pages().len())
+        });
+
+        methods.add_method("page_of", |_lua, this, id: SpriteId| {
+            Ok(this.shared.read().unwrap().page_of(id).map(|page| page + 1))
+        });
+
+        methods.add_method("set_page_of", |_lua, this, (id, page): (SpriteId, u32)| {
+            let mut batch = this.shared.write().unwrap();
+            let page = Self::page_index(&batch, page)?;
+            Ok(batch.set_page_of(id, page))
+        });
+
+        methods.add_method(
+            "set",
+            |_lua, this, (id, param): (SpriteId, InstanceParam)| match this
+                .shared
+                .write()
+                .unwrap()
+                .get_mut(id)
+            {
+                Some(slot) => {
+                    *slot = param;
+                    Ok(())
+                }
+                None => Err(anyhow!("no sprite with id {:?} in this batch", id)).to_lua_err(),
+            },
+        );
+
+        methods.add_method("remove", |_lua, this, id: SpriteId| {
+            this.shared.write().unwrap().remove(id);
+            Ok(())
+        });
+
+        methods.add_method("clear", |_lua, this, ()| {
+            this.shared.write().unwrap().clear();
+            Ok(())
+        });
+
+        methods.add_method("len", |_lua, this, ()| {
+            Ok(this.shared.read().unwrap().len())
+        });
+
+        methods.add_method("blend", |_lua, this, ()| {
+            Ok(this.shared.read().unwrap().blend())
+        });
+
+        methods.add_method("set_blend", |_lua, this, blend: Option<BlendPreset>| {
+            this.shared.write().unwrap().set_blend(blend);
+            Ok(())
+        });
+
+        methods.add_method("set_texture", |lua, this, path: String| {
+            let texture = Self::load_texture(lua, &path)?;
+            this.shared.write().unwrap().set_texture(texture);
+            Ok(())
+        });
+
+        methods.add_method(
+            "draw",
+            |lua, this, (param, effects): (Option<InstanceParam>, Option<Effects>)| {
+                let gfx = lua.fetch_one::<Graphics>()?;
+                let batch = this.shared.read().unwrap();
+                effects.unwrap_or_default().draw(
+                    &mut gfx.borrow_mut(),
+                    &*batch,
+                    param.unwrap_or_default(),
+                );
+                Ok(())
+            },
+        );
+    }
+}
+
 #[derive(Debug)]
 pub struct Canvas {
     pub render_pass: RenderPass,
@@ -1533,7 +2938,7 @@ impl AsRef<RenderPass> for Canvas {
 
 impl Canvas {
     pub fn new(ctx: &mut Graphics, width: u32, height: u32) -> Self {
-        let color_img = Texture::from_inner(mq::Texture::new_render_texture(
+        let color_img = mq::Texture::new_render_texture(
             &mut ctx.mq,
             mq::TextureParams {
                 width,
@@ -1542,9 +2947,10 @@ impl Canvas {
                 filter: mq::FilterMode::Nearest,
                 ..Default::default()
             },
-        ));
+        );
+        let color_img = Texture::from_inner(ctx, color_img);
 
-        let depth_img = Texture::from_inner(mq::Texture::new_render_texture(
+        let depth_img = mq::Texture::new_render_texture(
             &mut ctx.mq,
             mq::TextureParams {
                 width,
@@ -1553,7 +2959,8 @@ impl Canvas {
                 filter: mq::FilterMode::Nearest,
                 ..Default::default()
             },
-        ));
+        );
+        let depth_img = Texture::from_inner(ctx, depth_img);
 
         let render_pass = RenderPass::from_parts(ctx, color_img.handle, Some(depth_img.handle));
 
@@ -1563,6 +2970,14 @@ impl Canvas {
             depth_buffer: depth_img,
         }
     }
+
+    /// Read back the contents of the canvas's color buffer.
+    pub fn read_pixels(&self) -> Screenshot {
+        let (width, height) = (self.color_buffer.width(), self.color_buffer.height());
+        let mut pixels = vec![0; width as usize * height as usize * 4];
+        self.color_buffer.handle.read_pixels(&mut pixels);
+        Screenshot::from_bottom_up(width, height, pixels)
+    }
 }
 
 impl Drawable for Canvas {
@@ -1783,12 +3198,17 @@ impl Asset for Texture {
         let path = key
             .to_path()
             .with_context(|| anyhow!("bad key for Texture"))?;
-        let (fs, gfx) = resources.fetch::<(Filesystem, Graphics)>()?;
-        let mut file = fs.borrow_mut().open(path)?;
-        let texture = Texture::from_reader(&mut gfx.borrow_mut(), &mut file)
+        let fs = resources.fetch_one::<Filesystem>()?;
+        let mut buf = Vec::new();
+        fs.borrow_mut().open(path)?.read_to_end(&mut buf)?;
+        let texture = Texture::from_memory_with_resources(resources, &buf)
             .with_context(|| anyhow!("failed to create a texture using {:?}", path))?;
         Ok(Loaded::new(texture))
     }
+
+    fn size_hint(&self) -> usize {
+        self.width() as usize * self.height() as usize * 4
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -1869,3 +3289,293 @@ impl<'lua, C: 'static> FromLua<'lua> for ErasedDrawableId<C> {
         Ok(ErasedDrawableId::new(ldiu.drawable_id))
     }
 }
+
+inventory::submit! {
+    crate::api::Module::parse("sludge.graphics", |lua| {
+        use crate::SludgeLuaContextExt;
+
+        let table = lua.create_table()?;
+
+        table.set(
+            "set_fullscreen",
+            lua.create_function(|lua, fullscreen: bool| {
+                lua.fetch_one::<Graphics>()?
+                    .borrow_mut()
+                    .set_fullscreen(fullscreen);
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "is_fullscreen",
+            lua.create_function(|lua, ()| {
+                Ok(lua.fetch_one::<Graphics>()?.borrow().is_fullscreen())
+            })?,
+        )?;
+
+        table.set(
+            "toggle_fullscreen",
+            lua.create_function(|lua, ()| {
+                lua.fetch_one::<Graphics>()?.borrow_mut().toggle_fullscreen();
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "set_window_size",
+            lua.create_function(|lua, (width, height): (u32, u32)| {
+                lua.fetch_one::<Graphics>()?
+                    .borrow_mut()
+                    .set_window_size(width, height);
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "window_size",
+            lua.create_function(|lua, ()| {
+                Ok(lua.fetch_one::<Graphics>()?.borrow().get_screen_size())
+            })?,
+        )?;
+
+        table.set(
+            "set_vsync",
+            lua.create_function(|lua, vsync: bool| {
+                lua.fetch_one::<Graphics>()?.borrow_mut().set_vsync(vsync);
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "vsync",
+            lua.create_function(|lua, ()| Ok(lua.fetch_one::<Graphics>()?.borrow().vsync()))?,
+        )?;
+
+        table.set(
+            "set_window_title",
+            lua.create_function(|lua, title: String| {
+                lua.fetch_one::<Graphics>()?
+                    .borrow_mut()
+                    .set_window_title(&title);
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "window_title",
+            lua.create_function(|lua, ()| {
+                Ok(lua
+                    .fetch_one::<Graphics>()?
+                    .borrow()
+                    .window_title()
+                    .to_owned())
+            })?,
+        )?;
+
+        table.set(
+            "monitors",
+            lua.create_function(|lua, ()| {
+                let sizes = lua.fetch_one::<Graphics>()?.borrow().monitor_sizes();
+                lua.create_sequence_from(sizes.into_iter().map(|(width, height)| {
+                    let monitor = lua.create_table()?;
+                    monitor.set("width", width)?;
+                    monitor.set("height", height)?;
+                    Ok(monitor)
+                }).collect::<LuaResult<Vec<_>>>()?)
+            })?,
+        )?;
+
+        table.set(
+            "sprite_batch",
+            lua.create_function(|lua, (paths, capacity): (LuaValue, Option<usize>)| {
+                let paths = match paths {
+                    LuaValue::Table(table) => table
+                        .sequence_values::<String>()
+                        .collect::<LuaResult<Vec<_>>>()?,
+                    other => vec![String::from_lua(other, lua)?],
+                };
+                let pages = paths
+                    .iter()
+                    .map(|path| LuaSpriteBatch::load_texture(lua, path))
+                    .collect::<LuaResult<Vec<_>>>()?;
+                let gfx = lua.fetch_one::<Graphics>()?;
+                let batch = SpriteBatch::with_pages(
+                    &mut gfx.borrow_mut(),
+                    pages,
+                    capacity.unwrap_or(64),
+                )
+                .to_lua_err()?;
+                Ok(LuaSpriteBatch {
+                    shared: Arc::new(RwLock::new(batch)),
+                })
+            })?,
+        )?;
+
+        table.set(
+            "stats",
+            lua.create_function(|lua, ()| Ok(lua.fetch_one::<Graphics>()?.borrow().stats()))?,
+        )?;
+
+        table.set(
+            "screenshot",
+            lua.create_function(|lua, path: String| {
+                lua.fetch_one::<Graphics>()?
+                    .borrow_mut()
+                    .request_screenshot(path);
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "clip",
+            lua.create_function(|lua, (path, format): (String, Option<ClipFormat>)| {
+                let format = format.unwrap_or_else(|| ClipFormat::from_path(&path));
+                lua.fetch_one::<FrameRecorder>()?
+                    .borrow_mut()
+                    .request_clip(path, format);
+                Ok(())
+            })?,
+        )?;
+
+        Ok(LuaValue::Table(table))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paged_instances_are_scaled_by_their_page() {
+        let mut sprites = Arena::new();
+        sprites.insert(BatchedSprite {
+            param: InstanceParam::new(),
+            page: 0,
+        });
+        sprites.insert(BatchedSprite {
+            param: InstanceParam::new().src(Box2::new(0.5, 0., 0.5, 1.)),
+            page: 1,
+        });
+
+        let (mut instances, mut pages) = (Vec::new(), vec![7.]);
+        write_instances(
+            &sprites,
+            &[Vector2::new(16., 16.), Vector2::new(32., 8.)],
+            &mut instances,
+            &mut pages,
+        );
+
+        assert_eq!(pages, [0., 1.]);
+        assert_eq!(instances.len(), 2);
+        let scales = instances
+            .iter()
+            .map(|instance| (instance.tx[(0, 0)], instance.tx[(1, 1)]))
+            .collect::<Vec<_>>();
+        assert_eq!(scales, [(16., 16.), (16., 8.)]);
+        assert_eq!(instances[1].src, Vector4::new(0.5, 0., 0.5, 1.));
+    }
+
+    #[test]
+    fn mesh_instances_follow_the_batch() {
+        let mut params = Arena::new();
+        let first = params.insert(InstanceParam::new().translate2(Vector2::new(1., 2.)));
+        let removed = params.insert(InstanceParam::new());
+        params.insert(InstanceParam::new().color(Color::RED));
+        params.remove(removed);
+
+        let mut instances = vec![InstanceParam::new().to_instance_properties(); 5];
+        write_mesh_instances(&params, &mut instances);
+
+        assert_eq!(instances.len(), 2);
+        assert_eq!(
+            instances[0].tx,
+            params[first].to_instance_properties().tx,
+            "instances are positioned by their transforms"
+        );
+        assert_eq!(instances[1].color, LinearColor::from(Color::RED));
+    }
+
+    #[test]
+    fn instance_buffers_grow_to_powers_of_two() {
+        assert_eq!(grown_capacity(0, 64), None);
+        assert_eq!(grown_capacity(64, 64), None);
+        assert_eq!(grown_capacity(65, 64), Some(128));
+        assert_eq!(grown_capacity(1000, 64), Some(1024));
+        assert_eq!(grown_capacity(1, 0), Some(1));
+    }
+
+    #[test]
+    fn paged_shaders_have_a_sampler_per_page() {
+        let meta = shader::paged_meta();
+        assert_eq!(meta.images.len(), SpriteBatch::MAX_PAGES);
+        for image in &meta.images {
+            assert!(shader::PAGED_FRAGMENT.contains(&format!("sampler2D {};", image)));
+        }
+    }
+
+    #[test]
+    fn spaces_keep_gfx_stats_every_frame() -> Result<()> {
+        let mut space = crate::Space::new()?;
+        assert_eq!(space.maintainers.stage("GfxStats"), Some(Stage::Frame));
+
+        space.frame_update()?;
+        assert_eq!(
+            *space.fetch_one::<GfxStats>()?.borrow(),
+            GfxStats::default()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn blend_presets_go_to_and_from_lua_by_name() -> Result<()> {
+        for (i, preset) in BlendPreset::ALL.iter().copied().enumerate() {
+            assert_eq!(preset.index(), i);
+            assert_eq!(BlendPreset::from_name(preset.name()), Some(preset));
+        }
+        assert_eq!(BlendPreset::from_name("subtract"), None);
+
+        Lua::new().context(|lua| -> Result<()> {
+            let preset = lua.load(r#"return "additive""#).eval::<BlendPreset>()?;
+            assert_eq!(preset, BlendPreset::Additive);
+
+            lua.globals().set("preset", BlendPreset::Premultiplied)?;
+            let name = lua.load("return preset").eval::<String>()?;
+            assert_eq!(name, "premultiplied");
+
+            let err = lua.load(r#"return "subtract""#).eval::<BlendPreset>();
+            assert!(format!("{:?}", err.unwrap_err()).contains("unknown blend preset"));
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn blend_presets_blend_as_named() {
+        use {BlendEquation as Equation, BlendFactor as Factor};
+
+        let factors = |preset: BlendPreset| {
+            let mode = preset.mode();
+            assert!(matches!(mode.eq, Equation::Add));
+            (mode.src, mode.dst)
+        };
+
+        assert!(matches!(
+            factors(BlendPreset::Alpha),
+            (Factor::SourceAlpha, Factor::OneMinusSourceAlpha)
+        ));
+        assert!(matches!(
+            factors(BlendPreset::Additive),
+            (Factor::SourceAlpha, Factor::One)
+        ));
+        assert!(matches!(
+            factors(BlendPreset::Multiply),
+            (Factor::DestinationColor, Factor::Zero)
+        ));
+        assert!(matches!(
+            factors(BlendPreset::Premultiplied),
+            (Factor::One, Factor::OneMinusSourceAlpha)
+        ));
+    }
+}
@@ -0,0 +1,374 @@
+//! Drop shadows and outlines, drawn from a drawable's silhouette.
+//!
+//! Drawing a shadow or an outline by hand means drawing the same thing several times
+//! with different offsets and colors, which is awkward when the thing has its own
+//! colors baked in, like the glyphs of a piece of text. Anything implementing
+//! [`Silhouette`] can instead be drawn as a flat-colored shape which keeps only its
+//! alpha, and an [`Effects`] uses that to draw a shadow and an outline underneath it:
+//!
+//! ```ignore
+//! let effects = Effects::new()
+//!     .shadow(Vector2::new(2., 2.), Color::from_rgba(0, 0, 0, 128))
+//!     .outline(1., Color::BLACK);
+//! effects.draw(&mut gfx, &score_text, InstanceParam::new().translate2(position));
+//! ```
+//!
+//! Effects are chosen per draw, so the same drawable can be drawn with and without
+//! them. [`Styled`] pairs a drawable with its effects for places which need a single
+//! [`Drawable`], like the [`RenderQueue`].
+//!
+//! Shadow offsets and outline thicknesses are measured in the space the drawable is
+//! drawn into, so they don't rotate or scale along with it. Outlines are made of eight
+//! copies of the silhouette shifted outwards, which suits pixel art and bitmap fonts;
+//! thick outlines on smooth text will need a signed distance field font mode, which
+//! the text renderer doesn't have yet.
+
+use super::*;
+
+pub const SILHOUETTE_FRAGMENT: &'static str = include_str!("silhouette_es300.glslf");
+
+pub fn meta() -> mq::ShaderMeta {
+    mq::ShaderMeta {
+        images: vec!["t_Texture".to_string()],
+        uniforms: mq::UniformBlockLayout {
+            uniforms: vec![
+                mq::UniformDesc::new("u_MVP", mq::UniformType::Mat4),
+                mq::UniformDesc::new("u_Silhouette", mq::UniformType::Float4),
+            ],
+        },
+    }
+}
+
+#[repr(C)]
+pub struct SilhouetteUniforms {
+    pub mvp: Matrix4<f32>,
+    /// The color every drawn pixel is replaced with, multiplied by its alpha.
+    pub color: LinearColor,
+}
+
+/// Create the pipeline used to draw every [`Silhouette`]. miniquad can't delete
+/// pipelines, so this is done once by the [`Graphics`] context.
+pub(crate) fn pipeline(mq: &mut mq::Context) -> Result<mq::Pipeline> {
+    let shader = mq::Shader::new(mq, shader::BASIC_VERTEX, SILHOUETTE_FRAGMENT, meta())?;
+
+    Ok(mq::Pipeline::with_params(
+        mq,
+        &shader::buffer_layouts(),
+        &shader::vertex_attributes(),
+        shader,
+        mq::PipelineParams {
+            color_blend: Some(BlendMode::default().into()),
+            depth_test: mq::Comparison::LessOrEqual,
+            depth_write: true,
+            ..mq::PipelineParams::default()
+        },
+    ))
+}
+
+/// Apply the silhouette pipeline with the given local transform and color, run `draw`,
+/// and then go back to the default pipeline.
+fn with_silhouette_pipeline(
+    ctx: &mut Graphics,
+    tx: &Matrix4<f32>,
+    color: Color,
+    draw: impl FnOnce(&mut Graphics),
+) {
+    let uniforms = SilhouetteUniforms {
+        mvp: ctx.projection * ctx.modelview.top() * tx,
+        color: LinearColor::from(color),
+    };
+
+    ctx.apply_mq_pipeline(ctx.silhouette_pipeline);
+    ctx.mq.apply_uniforms(&uniforms);
+    draw(ctx);
+    ctx.apply_default_pipeline();
+    ctx.apply_transforms();
+}
+
+/// Drawables which can be drawn as a flat-colored shape, for [`Effects`].
+pub trait Silhouette: Drawable {
+    /// Draw the shape of this drawable in a single color, keeping only its alpha.
+    fn draw_silhouette(&self, ctx: &mut Graphics, instance: InstanceParam, color: Color);
+}
+
+impl Silhouette for Texture {
+    fn draw_silhouette(&self, ctx: &mut Graphics, instance: InstanceParam, color: Color) {
+        with_silhouette_pipeline(ctx, &Matrix4::identity(), color, |ctx| {
+            self.draw(ctx, instance)
+        });
+    }
+}
+
+impl Silhouette for Sprite {
+    fn draw_silhouette(&self, ctx: &mut Graphics, instance: InstanceParam, color: Color) {
+        let params = InstanceParam {
+            tx: instance.tx * self.params.tx,
+            ..self.params
+        };
+        self.texture.load().draw_silhouette(ctx, params, color);
+    }
+}
+
+/// Batches with more than one page have no silhouette, since the silhouette shader
+/// only samples a single texture; they're drawn without effects.
+impl Silhouette for SpriteBatch {
+    fn draw_silhouette(&self, ctx: &mut Graphics, instance: InstanceParam, color: Color) {
+        if self.textures.len() > 1 {
+            return;
+        }
+
+        self.flush(ctx);
+        let inner = self.inner.read().unwrap();
+        with_silhouette_pipeline(ctx, &instance.tx.to_homogeneous(), color, |ctx| {
+            // 6 here because a quad is 6 vertices
+            ctx.draw_bindings(&inner.bindings, 6, inner.instances.len() as i32);
+        });
+    }
+}
+
+/// A drop shadow: the silhouette of a drawable, offset and drawn underneath it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Shadow {
+    pub offset: Vector2<f32>,
+    pub color: Color,
+}
+
+/// An outline of a given thickness around the silhouette of a drawable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Outline {
+    pub thickness: f32,
+    pub color: Color,
+}
+
+impl Outline {
+    /// The offsets the silhouette is drawn at to make up the outline.
+    fn offsets(&self) -> [Vector2<f32>; 8] {
+        let t = self.thickness;
+        [
+            Vector2::new(-t, -t),
+            Vector2::new(0., -t),
+            Vector2::new(t, -t),
+            Vector2::new(-t, 0.),
+            Vector2::new(t, 0.),
+            Vector2::new(-t, t),
+            Vector2::new(0., t),
+            Vector2::new(t, t),
+        ]
+    }
+}
+
+/// A shadow and an outline to draw underneath a [`Silhouette`]. See the [module
+/// documentation](self) for details.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Effects {
+    pub shadow: Option<Shadow>,
+    pub outline: Option<Outline>,
+}
+
+/// Effects are read from Lua tables with the optional fields `shadow`, a table with the
+/// fields `x`, `y` and `color`, and `outline`, a table with the fields `thickness` and
+/// `color`. Colors default to black, and the outline's thickness to `1`.
+impl<'lua> FromLua<'lua> for Effects {
+    fn from_lua(value: LuaValue<'lua>, lua: LuaContext<'lua>) -> LuaResult<Self> {
+        let table = LuaTable::from_lua(value, lua)?;
+        let mut effects = Self::new();
+
+        if let Some(shadow) = table.get::<_, Option<LuaTable>>("shadow")? {
+            effects = effects.shadow(
+                Vector2::new(
+                    shadow.get::<_, Option<f32>>("x")?.unwrap_or(0.),
+                    shadow.get::<_, Option<f32>>("y")?.unwrap_or(0.),
+                ),
+                shadow
+                    .get::<_, Option<Color>>("color")?
+                    .unwrap_or(Color::BLACK),
+            );
+        }
+
+        if let Some(outline) = table.get::<_, Option<LuaTable>>("outline")? {
+            effects = effects.outline(
+                outline.get::<_, Option<f32>>("thickness")?.unwrap_or(1.),
+                outline
+                    .get::<_, Option<Color>>("color")?
+                    .unwrap_or(Color::BLACK),
+            );
+        }
+
+        Ok(effects)
+    }
+}
+
+impl Effects {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn shadow(self, offset: Vector2<f32>, color: Color) -> Self {
+        Self {
+            shadow: Some(Shadow { offset, color }),
+            ..self
+        }
+    }
+
+    #[inline]
+    pub fn outline(self, thickness: f32, color: Color) -> Self {
+        Self {
+            outline: Some(Outline { thickness, color }),
+            ..self
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.shadow.is_none() && self.outline.is_none()
+    }
+
+    /// Grow the bounding box of a drawable to cover its shadow and outline.
+    pub fn grow(&self, aabb: &Box2<f32>) -> Box2<f32> {
+        let mut grown = match self.outline {
+            Some(outline) => aabb.loosened(outline.thickness),
+            None => *aabb,
+        };
+
+        if let Some(shadow) = self.shadow {
+            let shifted =
+                Box2::from_corners(grown.mins + shadow.offset, grown.maxs + shadow.offset);
+            grown.merge(&shifted);
+        }
+
+        grown
+    }
+
+    /// Draw the shadow and the outline, and then the drawable itself on top.
+    ///
+    /// The shadow is cast by the outlined shape, so outlined text gets a shadow as
+    /// thick as its outline.
+    pub fn draw<D>(&self, ctx: &mut Graphics, drawable: &D, instance: InstanceParam)
+    where
+        D: Silhouette + ?Sized,
+    {
+        let outline_offsets = self.outline.as_ref().map(Outline::offsets);
+
+        if let Some(shadow) = self.shadow {
+            draw_shifted(ctx, drawable, instance, shadow.offset, shadow.color);
+            for &offset in outline_offsets.iter().flatten() {
+                draw_shifted(
+                    ctx,
+                    drawable,
+                    instance,
+                    shadow.offset + offset,
+                    shadow.color,
+                );
+            }
+        }
+
+        if let Some(outline) = self.outline {
+            for &offset in outline_offsets.iter().flatten() {
+                draw_shifted(ctx, drawable, instance, offset, outline.color);
+            }
+        }
+
+        drawable.draw(ctx, instance);
+    }
+}
+
+/// Draw the silhouette of `drawable` shifted by `offset` in the space it's drawn into.
+fn draw_shifted<D>(
+    ctx: &mut Graphics,
+    drawable: &D,
+    instance: InstanceParam,
+    offset: Vector2<f32>,
+    color: Color,
+) where
+    D: Silhouette + ?Sized,
+{
+    let shift = Matrix4::new_translation(&offset.push(0.));
+    let instance = InstanceParam {
+        tx: Transform3::from_matrix_unchecked(shift * instance.tx.matrix()),
+        ..instance
+    };
+    drawable.draw_silhouette(ctx, instance, color);
+}
+
+/// A drawable paired with the [`Effects`] it's always drawn with.
+#[derive(Debug, Clone)]
+pub struct Styled<D> {
+    pub drawable: D,
+    pub effects: Effects,
+}
+
+impl<D> Styled<D> {
+    pub fn new(drawable: D, effects: Effects) -> Self {
+        Self { drawable, effects }
+    }
+}
+
+impl<D: Silhouette> Drawable for Styled<D> {
+    fn draw(&self, ctx: &mut Graphics, instance: InstanceParam) {
+        self.effects.draw(ctx, &self.drawable, instance);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn effects_grow_bounds_by_outline_then_shadow() {
+        let aabb = Box2::new(0., 0., 10., 10.);
+        assert_eq!(Effects::new().grow(&aabb), aabb);
+        assert!(Effects::new().is_empty());
+
+        let outlined = Effects::new().outline(2., Color::BLACK);
+        assert!(!outlined.is_empty());
+        assert_eq!(outlined.grow(&aabb), Box2::new(-2., -2., 14., 14.));
+
+        let shadowed = outlined.shadow(Vector2::new(3., -1.), Color::BLACK);
+        assert_eq!(shadowed.grow(&aabb), Box2::new(-2., -3., 17., 15.));
+    }
+
+    #[test]
+    fn outlines_surround_the_silhouette() {
+        let outline = Outline {
+            thickness: 1.5,
+            color: Color::BLACK,
+        };
+        let offsets = outline.offsets();
+
+        assert!(!offsets.contains(&Vector2::zeros()));
+        for offset in offsets.iter() {
+            assert_eq!(offset.x.abs().max(offset.y.abs()), 1.5);
+        }
+        for (i, a) in offsets.iter().enumerate() {
+            assert!(offsets[i + 1..].iter().all(|b| a != b));
+        }
+    }
+
+    #[test]
+    fn effects_are_read_from_lua_tables() {
+        let lua = Lua::new();
+        lua.context(|lua| {
+            let effects = lua
+                .load(
+                    r#"{
+                        shadow = { x = 2, y = 3, color = { r = 1, g = 0, b = 0, a = 0.5 } },
+                        outline = {},
+                    }"#,
+                )
+                .eval::<Effects>()
+                .unwrap();
+            assert_eq!(
+                effects,
+                Effects::new()
+                    .shadow(Vector2::new(2., 3.), Color::new(1., 0., 0., 0.5))
+                    .outline(1., Color::BLACK)
+            );
+
+            let effects = lua.load("{}").eval::<Effects>().unwrap();
+            assert!(effects.is_empty());
+            assert!(lua.load("5").eval::<Effects>().is_err());
+        });
+    }
+}
@@ -0,0 +1,392 @@
+//! Whole-screen color grading: tints, flashes, brightness, contrast, saturation and
+//! lookup tables, applied to the finished frame.
+//!
+//! Tinting every sprite by hand to flash the screen when the player is hit, or to turn
+//! a stage sepia, means every drawable has to know about it. The [`ColorGrading`] on
+//! the [`Graphics`] context is applied to the whole frame at once instead:
+//!
+//! ```ignore
+//! gfx.grading.saturation = 0.5;
+//! gfx.grading.flash(Color::WHITE, 0.25);
+//! ```
+//!
+//! While any grading is in effect, the default pass is drawn into an offscreen canvas
+//! the size of the screen instead of straight to the screen, and
+//! [`Graphics::commit_frame`] draws that canvas onto the screen through the grading
+//! shader, after the [`RenderQueue`] has been flushed. When grading is back to neutral
+//! the canvas is dropped and frames are drawn directly again, so there's no cost when
+//! it isn't used. Screenshots taken while grading is in effect are read from the canvas,
+//! and so are ungraded.
+//!
+//! The adjustments are made in order: brightness, contrast, saturation, the lookup
+//! table, the tint, and finally the flash. Lookup tables are 2D textures holding a 3D
+//! table as a horizontal strip of square slices, one per level of blue, from none on
+//! the left to full on the right; a 16-level table is a 256 by 16 image. The size of
+//! the table is taken from the height of the texture.
+//!
+//! Flashes fade out linearly over their duration, measured in real time between
+//! calls to `commit_frame`. From Lua, all of this is available through `sludge.screen`:
+//!
+//! ```lua
+//! sludge.screen.flash({ r = 1, g = 0, b = 0, a = 0.6 }, 0.2)
+//! sludge.screen.set_saturation(0)
+//! sludge.screen.set_lut("/luts/dusk.png")
+//! ```
+
+use {super::*, std::time::Instant};
+
+pub const GRADING_FRAGMENT: &'static str = include_str!("grading_es300.glslf");
+
+pub fn meta() -> mq::ShaderMeta {
+    mq::ShaderMeta {
+        images: vec!["t_Texture".to_string(), "t_Lut".to_string()],
+        uniforms: mq::UniformBlockLayout {
+            uniforms: vec![
+                mq::UniformDesc::new("u_MVP", mq::UniformType::Mat4),
+                mq::UniformDesc::new("u_Tint", mq::UniformType::Float4),
+                mq::UniformDesc::new("u_Flash", mq::UniformType::Float4),
+                mq::UniformDesc::new("u_Grade", mq::UniformType::Float4),
+            ],
+        },
+    }
+}
+
+#[repr(C)]
+pub struct GradingUniforms {
+    pub mvp: Matrix4<f32>,
+    /// The color the frame is multiplied by, blended in by its alpha.
+    pub tint: LinearColor,
+    /// The color the frame is blended towards, by its alpha.
+    pub flash: LinearColor,
+    /// Brightness, contrast, saturation and the size of the lookup table, or zero if
+    /// there isn't one.
+    pub grade: Vector4<f32>,
+}
+
+/// Create the pipeline used to draw the graded frame. miniquad can't delete pipelines,
+/// so this is done once by the [`Graphics`] context.
+pub(crate) fn pipeline(mq: &mut mq::Context) -> Result<mq::Pipeline> {
+    let shader = mq::Shader::new(mq, shader::BASIC_VERTEX, GRADING_FRAGMENT, meta())?;
+
+    Ok(mq::Pipeline::with_params(
+        mq,
+        &shader::buffer_layouts(),
+        &shader::vertex_attributes(),
+        shader,
+        mq::PipelineParams {
+            color_blend: None,
+            depth_test: mq::Comparison::Always,
+            depth_write: false,
+            ..mq::PipelineParams::default()
+        },
+    ))
+}
+
+/// A flash of color which fades out over its duration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Flash {
+    pub color: Color,
+    /// How long the flash lasts in total, in seconds.
+    pub duration: f32,
+    /// How long the flash has left, in seconds.
+    pub remaining: f32,
+}
+
+impl Flash {
+    /// The flash's color, with its alpha faded by how much of the flash is left.
+    pub fn current(&self) -> Color {
+        let fade = if self.duration > 0. {
+            (self.remaining / self.duration).max(0.).min(1.)
+        } else {
+            0.
+        };
+
+        Color {
+            a: self.color.a * fade,
+            ..self.color
+        }
+    }
+}
+
+/// Adjustments made to every finished frame. See the [module documentation](self) for
+/// details.
+#[derive(Debug, Clone)]
+pub struct ColorGrading {
+    /// Multiplied into the frame, blended in by its alpha. White is neutral.
+    pub tint: Color,
+    /// Added to every channel. Zero is neutral.
+    pub brightness: f32,
+    /// How far colors are pushed away from middle gray. One is neutral.
+    pub contrast: f32,
+    /// How far colors are pushed away from gray of the same brightness; zero is
+    /// grayscale. One is neutral.
+    pub saturation: f32,
+    /// A lookup table to remap colors with, set with [`Graphics::set_lut`].
+    pub(crate) lut: Option<Cached<Texture>>,
+    pub flash: Option<Flash>,
+}
+
+impl Default for ColorGrading {
+    fn default() -> Self {
+        Self {
+            tint: Color::WHITE,
+            brightness: 0.,
+            contrast: 1.,
+            saturation: 1.,
+            lut: None,
+            flash: None,
+        }
+    }
+}
+
+impl ColorGrading {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Flash the screen with `color`, fading out over `duration` seconds. This replaces
+    /// any flash already in progress.
+    pub fn flash(&mut self, color: Color, duration: f32) {
+        self.flash = Some(Flash {
+            color,
+            duration,
+            remaining: duration,
+        });
+    }
+
+    pub fn lut(&self) -> Option<&Cached<Texture>> {
+        self.lut.as_ref()
+    }
+
+    /// Put everything back to neutral, including any flash in progress.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Whether any adjustment would change the frame.
+    pub fn is_active(&self) -> bool {
+        self.tint != Color::WHITE
+            || self.brightness != 0.
+            || self.contrast != 1.
+            || self.saturation != 1.
+            || self.lut.is_some()
+            || self.flash.is_some()
+    }
+
+    /// Fade the flash by `dt` seconds, ending it once it's run its course.
+    pub fn update(&mut self, dt: f32) {
+        if let Some(flash) = &mut self.flash {
+            flash.remaining -= dt;
+            if flash.remaining <= 0. {
+                self.flash = None;
+            }
+        }
+    }
+
+    fn uniforms(&self, mvp: Matrix4<f32>, lut_size: f32) -> GradingUniforms {
+        let flash = self
+            .flash
+            .map(|flash| flash.current())
+            .unwrap_or(Color::ZEROS);
+
+        GradingUniforms {
+            mvp,
+            tint: LinearColor::from(self.tint),
+            flash: LinearColor::from(flash),
+            grade: Vector4::new(self.brightness, self.contrast, self.saturation, lut_size),
+        }
+    }
+}
+
+impl Graphics {
+    /// Set the lookup table colors are remapped with, or remove it with `None`. The
+    /// texture is switched to linear filtering.
+    pub fn set_lut(&mut self, lut: Option<Cached<Texture>>) {
+        if let Some(lut) = &lut {
+            lut.load().set_filter_mode(self, FilterMode::Linear);
+        }
+
+        self.grading.lut = lut;
+    }
+
+    /// Begin a pass on the canvas the frame is drawn into while it's being graded,
+    /// making a new one if the screen has changed size. Returns `false` without
+    /// beginning a pass if there's no grading to do.
+    pub(crate) fn begin_graded_pass(&mut self, action: PassAction) -> bool {
+        if !self.grading.is_active() {
+            return false;
+        }
+
+        let (width, height) = self.mq.screen_size();
+        let (width, height) = (width as u32, height as u32);
+        let target = match self.grading_target.take() {
+            Some(canvas)
+                if canvas.color_buffer.width() == width
+                    && canvas.color_buffer.height() == height =>
+            {
+                canvas
+            }
+            _ => Canvas::new(self, width, height),
+        };
+
+        self.begin_pass(&target, action);
+        self.grading_target = Some(target);
+        self.graded_frame = true;
+        true
+    }
+
+    /// Draw the graded frame onto the screen, if the frame was drawn into the grading
+    /// canvas, and fade out the flash.
+    pub(crate) fn finish_grading(&mut self) {
+        let now = Instant::now();
+        let dt = self
+            .last_graded
+            .map_or(0., |last| now.duration_since(last).as_secs_f32());
+        self.last_graded = Some(now);
+
+        let target = match self.grading_target.as_ref() {
+            Some(target) if self.graded_frame => target.color_buffer.handle,
+            _ => {
+                self.grading.update(dt);
+                if !self.grading.is_active() {
+                    self.grading_target = None;
+                }
+                return;
+            }
+        };
+
+        let (lut, lut_size) = match self.grading.lut() {
+            Some(lut) => {
+                let lut = lut.load();
+                (lut.handle, lut.height() as f32)
+            }
+            None => (self.null_texture.load().handle, 0.),
+        };
+
+        let mvp = Orthographic3::new(0., 1., 0., 1., -1., 1.).to_homogeneous();
+        let uniforms = self.grading.uniforms(mvp, lut_size);
+        let bindings = mq::Bindings {
+            images: vec![target, lut],
+            ..self.quad_bindings.clone()
+        };
+
+        self.stats.passes += 1;
+        self.mq.begin_default_pass(PassAction::Nothing.into());
+        self.apply_mq_pipeline(self.grading_pipeline);
+        self.mq.apply_uniforms(&uniforms);
+        self.update_buffer(
+            bindings.vertex_buffers[1],
+            &[InstanceParam::new().to_instance_properties()],
+        );
+        self.draw_bindings(&bindings, 6, 1);
+        self.end_pass();
+        self.apply_default_pipeline();
+
+        self.graded_frame = false;
+        self.grading.update(dt);
+        if !self.grading.is_active() {
+            self.grading_target = None;
+        }
+    }
+}
+
+inventory::submit! {
+    crate::api::Module::parse("sludge.screen", |lua| {
+        use crate::SludgeLuaContextExt;
+
+        let table = lua.create_table()?;
+
+        table.set(
+            "flash",
+            lua.create_function(|lua, (color, duration): (Color, f32)| {
+                lua.fetch_one::<Graphics>()?
+                    .borrow_mut()
+                    .grading
+                    .flash(color, duration);
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "set_tint",
+            lua.create_function(|lua, tint: Option<Color>| {
+                lua.fetch_one::<Graphics>()?.borrow_mut().grading.tint =
+                    tint.unwrap_or(Color::WHITE);
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "set_brightness",
+            lua.create_function(|lua, brightness: f32| {
+                lua.fetch_one::<Graphics>()?.borrow_mut().grading.brightness = brightness;
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "set_contrast",
+            lua.create_function(|lua, contrast: f32| {
+                lua.fetch_one::<Graphics>()?.borrow_mut().grading.contrast = contrast;
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "set_saturation",
+            lua.create_function(|lua, saturation: f32| {
+                lua.fetch_one::<Graphics>()?.borrow_mut().grading.saturation = saturation;
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "set_lut",
+            lua.create_function(|lua, path: Option<String>| {
+                let lut = match path {
+                    Some(path) => Some(
+                        lua.fetch_one::<DefaultCache>()?
+                            .borrow()
+                            .get::<Texture>(&Key::path(&path))
+                            .to_lua_err()?,
+                    ),
+                    None => None,
+                };
+                lua.fetch_one::<Graphics>()?.borrow_mut().set_lut(lut);
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "reset",
+            lua.create_function(|lua, ()| {
+                let gfx = lua.fetch_one::<Graphics>()?;
+                let mut gfx = gfx.borrow_mut();
+                gfx.grading.reset();
+                Ok(())
+            })?,
+        )?;
+
+        Ok(LuaValue::Table(table))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flash_fades_out_and_ends() {
+        let mut grading = ColorGrading::new();
+        assert!(!grading.is_active());
+
+        grading.flash(Color::WHITE, 0.5);
+        grading.update(0.25);
+        let flash = grading.flash.expect("the flash should still be going");
+        assert!((flash.current().a - 0.5).abs() < 1e-6);
+
+        grading.update(0.25);
+        assert!(grading.flash.is_none());
+        assert!(!grading.is_active());
+    }
+}
@@ -0,0 +1,101 @@
+//! Running without a graphics context.
+//!
+//! A [`Graphics`] context needs a window, which CI machines and dedicated servers
+//! don't have. Inserting a [`NullGraphics`] into the global resources in its place lets
+//! a [`Space`](crate::Space) load its assets anyway: anything loaded through a
+//! [`Cache`] which would create a [`Texture`] gets a stub texture instead, which knows
+//! its dimensions but has nothing on the GPU. That's enough for sprite sheets, tilesets
+//! and fonts to load and be used for layout, so the dispatcher, systems and Lua can all
+//! run as usual.
+//!
+//! ```rust
+//! use sludge::{graphics::NullGraphics, prelude::*, resources::OwnedResources};
+//! # fn main() -> Result<()> {
+//! let mut global = OwnedResources::new();
+//! global.insert(NullGraphics::new(320., 240.));
+//! let space = Space::with_global_resources(SharedResources::from(global))?;
+//! # let _ = space;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Anything which actually draws still needs a real `Graphics`, and will fail to find
+//! one.
+
+use super::*;
+
+/// A stand-in for [`Graphics`] for running headless. See the [module
+/// documentation](self) for details.
+#[derive(Debug)]
+pub struct NullGraphics {
+    /// The size of the pretend screen, in pixels.
+    pub screen_size: (f32, f32),
+    deletion_queue: DeletionQueue,
+}
+
+impl NullGraphics {
+    pub fn new(width: f32, height: f32) -> Self {
+        // Stub textures have nothing to delete, so nothing ever receives from the queue.
+        let (deletion_queue, _) = DeletionQueue::new();
+
+        Self {
+            screen_size: (width, height),
+            deletion_queue,
+        }
+    }
+
+    pub fn get_screen_size(&self) -> (f32, f32) {
+        self.screen_size
+    }
+
+    /// Create a stub texture with the given dimensions, which doesn't exist on the GPU.
+    pub fn texture(&self, width: u16, height: u16) -> Texture {
+        let mut handle = mq::Texture::empty();
+        handle.width = width as u32;
+        handle.height = height as u32;
+
+        Texture {
+            handle,
+            queue: self.deletion_queue.clone(),
+        }
+    }
+}
+
+impl Texture {
+    /// Create a texture using whichever graphics backend is available: a [`Graphics`]
+    /// context if there is one, or a stub texture from a [`NullGraphics`] otherwise.
+    pub fn from_resources<'a, R: Resources<'a>>(
+        resources: &R,
+        width: u16,
+        height: u16,
+        bytes: &[u8],
+    ) -> Result<Self> {
+        match resources.fetch_one::<Graphics>() {
+            Ok(gfx) => Ok(Self::from_rgba8(
+                &mut gfx.borrow_mut(),
+                width,
+                height,
+                bytes,
+            )),
+            Err(not_found) => match resources.fetch_one::<NullGraphics>() {
+                Ok(null) => Ok(null.borrow().texture(width, height)),
+                Err(_) => Err(not_found.into()),
+            },
+        }
+    }
+
+    /// Parse the raw contents of an image file into a texture, using whichever graphics
+    /// backend is available. See [`Texture::from_resources`].
+    pub fn from_memory_with_resources<'a, R: Resources<'a>>(
+        resources: &R,
+        buffer: &[u8],
+    ) -> Result<Self> {
+        let rgba_image = image::load_from_memory(buffer)?.to_rgba();
+        Self::from_resources(
+            resources,
+            rgba_image.width() as u16,
+            rgba_image.height() as u16,
+            &rgba_image.to_vec(),
+        )
+    }
+}
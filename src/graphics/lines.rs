@@ -0,0 +1,512 @@
+//! Retained lines whose thickness is measured in pixels.
+//!
+//! Tessellating strokes with a [`MeshBuilder`] bakes their width into the geometry, so
+//! a one-pixel debug line becomes a ten-pixel one as soon as the camera zooms in. A
+//! [`LineRenderer`] instead stores only the points of each line, and pushes the
+//! vertices out sideways in the vertex shader once the points have been transformed
+//! onto the screen. Thickness, dash lengths and arrowhead sizes are all in pixels,
+//! while the points themselves are in whatever space the renderer is drawn in.
+//!
+//! Segments of a polyline are drawn as separate quads, without any joins, which is
+//! unnoticeable for the thin lines this is meant for.
+
+use super::*;
+
+pub const LINE_VERTEX: &'static str = include_str!("line_es300.glslv");
+pub const LINE_FRAGMENT: &'static str = include_str!("line_es300.glslf");
+
+pub fn meta() -> mq::ShaderMeta {
+    mq::ShaderMeta {
+        images: vec![],
+        uniforms: mq::UniformBlockLayout {
+            uniforms: vec![
+                mq::UniformDesc::new("u_MVP", mq::UniformType::Mat4),
+                mq::UniformDesc::new("u_Viewport", mq::UniformType::Float2),
+            ],
+        },
+    }
+}
+
+#[repr(C)]
+pub struct LineUniforms {
+    pub mvp: Matrix4<f32>,
+    /// The size of the render target, in pixels.
+    pub viewport: Vector2<f32>,
+}
+
+/// A corner of a segment or arrowhead. Both endpoints of the segment are carried by
+/// every vertex so that the shader can find the segment's direction on screen.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct LineVertex {
+    pub start: Vector2<f32>,
+    pub end: Vector2<f32>,
+    /// `0` to place the vertex at `start`, or `1` to place it at `end`.
+    pub anchor: f32,
+    /// Offset from the anchor in pixels, along the segment and then along its normal.
+    pub offset: Vector2<f32>,
+    /// Distance along the line at the anchor, in local units.
+    pub distance: f32,
+    /// Dash and gap lengths in pixels. A gap of zero draws a solid line.
+    pub dash: Vector2<f32>,
+    pub color: LinearColor,
+}
+
+/// Create the pipeline shared by every [`LineRenderer`]. miniquad can't delete
+/// pipelines, so this is done once by the [`Graphics`] context.
+pub(crate) fn pipeline(mq: &mut mq::Context) -> Result<mq::Pipeline> {
+    let shader = mq::Shader::new(mq, LINE_VERTEX, LINE_FRAGMENT, meta())?;
+
+    Ok(mq::Pipeline::with_params(
+        mq,
+        &[mq::BufferLayout::default()],
+        &[
+            mq::VertexAttribute::with_buffer("a_Start", mq::VertexFormat::Float2, 0),
+            mq::VertexAttribute::with_buffer("a_End", mq::VertexFormat::Float2, 0),
+            mq::VertexAttribute::with_buffer("a_Anchor", mq::VertexFormat::Float1, 0),
+            mq::VertexAttribute::with_buffer("a_Offset", mq::VertexFormat::Float2, 0),
+            mq::VertexAttribute::with_buffer("a_Distance", mq::VertexFormat::Float1, 0),
+            mq::VertexAttribute::with_buffer("a_Dash", mq::VertexFormat::Float2, 0),
+            mq::VertexAttribute::with_buffer("a_Color", mq::VertexFormat::Float4, 0),
+        ],
+        shader,
+        mq::PipelineParams {
+            color_blend: Some(BlendMode::default().into()),
+            depth_test: mq::Comparison::LessOrEqual,
+            depth_write: true,
+            ..mq::PipelineParams::default()
+        },
+    ))
+}
+
+/// An arrowhead at the end of a [`Line`], in pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Arrowhead {
+    pub length: f32,
+    pub width: f32,
+}
+
+/// A single polyline held by a [`LineRenderer`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub points: Vec<Point2<f32>>,
+    /// Thickness in pixels.
+    pub thickness: f32,
+    pub color: Color,
+    /// Dash and gap lengths in pixels, if the line is dashed. The pattern carries on
+    /// across the line's corners.
+    pub dash: Option<(f32, f32)>,
+    pub arrowhead: Option<Arrowhead>,
+    /// Whether the last point connects back up to the first.
+    pub closed: bool,
+}
+
+impl Line {
+    pub fn new(points: impl IntoIterator<Item = Point2<f32>>) -> Self {
+        Self {
+            points: points.into_iter().collect(),
+            thickness: 1.,
+            color: Color::WHITE,
+            dash: None,
+            arrowhead: None,
+            closed: false,
+        }
+    }
+
+    pub fn segment(from: Point2<f32>, to: Point2<f32>) -> Self {
+        Self::new(vec![from, to])
+    }
+
+    pub fn thickness(self, thickness: f32) -> Self {
+        Self { thickness, ..self }
+    }
+
+    pub fn color(self, color: Color) -> Self {
+        Self { color, ..self }
+    }
+
+    pub fn dashed(self, dash: f32, gap: f32) -> Self {
+        Self {
+            dash: Some((dash, gap)),
+            ..self
+        }
+    }
+
+    /// Put an arrowhead at the last point, pointing along the last segment.
+    pub fn arrowhead(self, length: f32, width: f32) -> Self {
+        Self {
+            arrowhead: Some(Arrowhead { length, width }),
+            ..self
+        }
+    }
+
+    pub fn closed(self) -> Self {
+        Self {
+            closed: true,
+            ..self
+        }
+    }
+
+    fn segments(&self) -> impl Iterator<Item = (Point2<f32>, Point2<f32>)> + '_ {
+        let closing = match self.points.as_slice() {
+            [first, .., last] if self.closed => Some((*last, *first)),
+            _ => None,
+        };
+
+        self.points
+            .windows(2)
+            .map(|pair| (pair[0], pair[1]))
+            .chain(closing)
+    }
+
+    /// Append this line's vertices and indices to the given buffers.
+    fn tessellate(&self, vertices: &mut Vec<LineVertex>, indices: &mut Vec<u16>) {
+        let color = LinearColor::from(self.color);
+        let dash = self
+            .dash
+            .map_or(Vector2::zeros(), |(dash, gap)| Vector2::new(dash, gap));
+        let half = self.thickness / 2.;
+        let segment_count = self.segments().count();
+
+        let mut distance = 0.;
+        for (i, (start, end)) in self.segments().enumerate() {
+            let length = (end - start).norm();
+            // Pull the end of the last segment back so it doesn't poke out through the
+            // tip of the arrowhead.
+            let pullback = match self.arrowhead {
+                Some(head) if i + 1 == segment_count => -head.length,
+                _ => 0.,
+            };
+
+            let vertex = |anchor: f32, along: f32, across: f32| LineVertex {
+                start: start.coords,
+                end: end.coords,
+                anchor,
+                offset: Vector2::new(along, across),
+                distance: distance + anchor * length,
+                dash,
+                color,
+            };
+
+            push_polygon(
+                vertices,
+                indices,
+                &[
+                    vertex(0., 0., -half),
+                    vertex(0., 0., half),
+                    vertex(1., pullback, half),
+                    vertex(1., pullback, -half),
+                ],
+            );
+
+            if let Some(head) = self.arrowhead.filter(|_| i + 1 == segment_count) {
+                let solid = |v: LineVertex| LineVertex {
+                    dash: Vector2::zeros(),
+                    ..v
+                };
+
+                push_polygon(
+                    vertices,
+                    indices,
+                    &[
+                        solid(vertex(1., 0., 0.)),
+                        solid(vertex(1., -head.length, head.width / 2.)),
+                        solid(vertex(1., -head.length, -head.width / 2.)),
+                    ],
+                );
+            }
+
+            distance += length;
+        }
+    }
+}
+
+/// Push a convex polygon as a triangle fan.
+fn push_polygon(vertices: &mut Vec<LineVertex>, indices: &mut Vec<u16>, polygon: &[LineVertex]) {
+    let base = vertices.len() as u16;
+    vertices.extend_from_slice(polygon);
+    for i in 1..polygon.len() as u16 - 1 {
+        indices.extend_from_slice(&[base, base + i, base + i + 1]);
+    }
+}
+
+/// Represents the index of a [`Line`] within a [`LineRenderer`].
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct LineId(Index);
+
+#[derive(Debug)]
+struct LineRendererInner {
+    vertices: Vec<LineVertex>,
+    indices: Vec<u16>,
+    /// The number of vertices and indices which fit in the buffers inside `bindings`.
+    capacity: (usize, usize),
+    bindings: mq::Bindings,
+}
+
+/// A retained collection of screen-space [`Line`]s, drawn in a single draw call.
+///
+/// Lines are re-tessellated whenever the collection changes, which only involves
+/// generating a handful of vertices per segment. Indices are 16 bits, so a single
+/// renderer holds up to a little over sixteen thousand segments; anything past that
+/// is left out with a warning. The color and `src` of the [`InstanceParam`] it's drawn
+/// with are ignored.
+#[derive(Debug)]
+pub struct LineRenderer {
+    lines: Arena<Line>,
+    inner: RwLock<LineRendererInner>,
+    dirty: AtomicBool,
+    queue: DeletionQueue,
+}
+
+impl Drop for LineRenderer {
+    fn drop(&mut self) {
+        let bindings = &self.inner.get_mut().unwrap().bindings;
+        self.queue
+            .push(GpuResource::Buffer(bindings.vertex_buffers[0]));
+        self.queue.push(GpuResource::Buffer(bindings.index_buffer));
+    }
+}
+
+impl ops::Index<LineId> for LineRenderer {
+    type Output = Line;
+
+    #[inline]
+    fn index(&self, index: LineId) -> &Self::Output {
+        &self.lines[index.0]
+    }
+}
+
+impl ops::IndexMut<LineId> for LineRenderer {
+    #[inline]
+    fn index_mut(&mut self, index: LineId) -> &mut Self::Output {
+        *self.dirty.get_mut() = true;
+        &mut self.lines[index.0]
+    }
+}
+
+impl LineRenderer {
+    pub fn new(ctx: &mut Graphics) -> Self {
+        const DEFAULT_LINE_RENDERER_CAPACITY: usize = 64;
+        Self::with_capacity(ctx, DEFAULT_LINE_RENDERER_CAPACITY)
+    }
+
+    /// Create a renderer with room for `capacity` segments before its buffers have to
+    /// be reallocated.
+    pub fn with_capacity(ctx: &mut Graphics, capacity: usize) -> Self {
+        let capacity = (capacity * 4, capacity * 6);
+        let bindings = mq::Bindings {
+            vertex_buffers: vec![mq::Buffer::stream(
+                &mut ctx.mq,
+                mq::BufferType::VertexBuffer,
+                capacity.0 * mem::size_of::<LineVertex>(),
+            )],
+            index_buffer: mq::Buffer::stream(
+                &mut ctx.mq,
+                mq::BufferType::IndexBuffer,
+                capacity.1 * mem::size_of::<u16>(),
+            ),
+            images: vec![],
+        };
+
+        Self {
+            lines: Arena::new(),
+            inner: LineRendererInner {
+                vertices: Vec::new(),
+                indices: Vec::new(),
+                capacity,
+                bindings,
+            }
+            .into(),
+            dirty: AtomicBool::new(true),
+            queue: ctx.deletion_queue(),
+        }
+    }
+
+    #[inline]
+    pub fn insert(&mut self, line: Line) -> LineId {
+        *self.dirty.get_mut() = true;
+        LineId(self.lines.insert(line))
+    }
+
+    /// Insert a solid polyline.
+    pub fn polyline(
+        &mut self,
+        points: impl IntoIterator<Item = Point2<f32>>,
+        thickness: f32,
+        color: Color,
+    ) -> LineId {
+        self.insert(Line::new(points).thickness(thickness).color(color))
+    }
+
+    /// Insert a dashed polyline.
+    pub fn dashed(
+        &mut self,
+        points: impl IntoIterator<Item = Point2<f32>>,
+        thickness: f32,
+        dash: f32,
+        gap: f32,
+        color: Color,
+    ) -> LineId {
+        self.insert(
+            Line::new(points)
+                .thickness(thickness)
+                .color(color)
+                .dashed(dash, gap),
+        )
+    }
+
+    /// Insert a single arrow from `from` to `to`, with an arrowhead sized relative to
+    /// its thickness.
+    pub fn arrow(
+        &mut self,
+        from: Point2<f32>,
+        to: Point2<f32>,
+        thickness: f32,
+        color: Color,
+    ) -> LineId {
+        let head = (thickness * 4.).max(8.);
+        self.insert(
+            Line::segment(from, to)
+                .thickness(thickness)
+                .color(color)
+                .arrowhead(head, head * 0.75),
+        )
+    }
+
+    #[inline]
+    pub fn remove(&mut self, index: LineId) -> Option<Line> {
+        *self.dirty.get_mut() = true;
+        self.lines.remove(index.0)
+    }
+
+    #[inline]
+    pub fn get(&self, index: LineId) -> Option<&Line> {
+        self.lines.get(index.0)
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self, index: LineId) -> Option<&mut Line> {
+        *self.dirty.get_mut() = true;
+        self.lines.get_mut(index.0)
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        *self.dirty.get_mut() = true;
+        self.lines.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (LineId, &Line)> + '_ {
+        self.lines.iter().map(|(i, line)| (LineId(i), line))
+    }
+
+    pub fn flush(&self, ctx: &mut Graphics) {
+        if !self.dirty.load(atomic::Ordering::Relaxed) {
+            return;
+        }
+
+        let inner = &mut *self.inner.write().unwrap();
+        inner.vertices.clear();
+        inner.indices.clear();
+
+        for (n, (_, line)) in self.lines.iter().enumerate() {
+            let (vertex_count, index_count) = (inner.vertices.len(), inner.indices.len());
+            line.tessellate(&mut inner.vertices, &mut inner.indices);
+
+            if inner.vertices.len() > u16::MAX as usize {
+                log::warn!(
+                    "too many segments in line renderer; skipping {} lines",
+                    self.lines.len() - n
+                );
+                inner.vertices.truncate(vertex_count);
+                inner.indices.truncate(index_count);
+                break;
+            }
+        }
+
+        if inner.vertices.len() > inner.capacity.0 {
+            let new_capacity = inner.vertices.len().checked_next_power_of_two().unwrap();
+            let new_buffer = mq::Buffer::stream(
+                &mut ctx.mq,
+                mq::BufferType::VertexBuffer,
+                new_capacity * mem::size_of::<LineVertex>(),
+            );
+
+            let old_buffer = mem::replace(&mut inner.bindings.vertex_buffers[0], new_buffer);
+            self.queue.push(GpuResource::Buffer(old_buffer));
+            inner.capacity.0 = new_capacity;
+        }
+
+        if inner.indices.len() > inner.capacity.1 {
+            let new_capacity = inner.indices.len().checked_next_power_of_two().unwrap();
+            let new_buffer = mq::Buffer::stream(
+                &mut ctx.mq,
+                mq::BufferType::IndexBuffer,
+                new_capacity * mem::size_of::<u16>(),
+            );
+
+            let old_buffer = mem::replace(&mut inner.bindings.index_buffer, new_buffer);
+            self.queue.push(GpuResource::Buffer(old_buffer));
+            inner.capacity.1 = new_capacity;
+        }
+
+        ctx.update_buffer(inner.bindings.vertex_buffers[0], &inner.vertices);
+        ctx.update_buffer(inner.bindings.index_buffer, &inner.indices);
+
+        self.dirty.store(false, atomic::Ordering::Relaxed);
+    }
+}
+
+impl Drawable for LineRenderer {
+    fn draw(&self, ctx: &mut Graphics, instance: InstanceParam) {
+        self.flush(ctx);
+        let inner = self.inner.read().unwrap();
+        if inner.indices.is_empty() {
+            return;
+        }
+
+        let (width, height) = ctx.get_screen_size();
+        let uniforms = LineUniforms {
+            mvp: ctx.projection * ctx.modelview.top() * instance.tx.to_homogeneous(),
+            viewport: Vector2::new(width, height),
+        };
+
+        ctx.apply_mq_pipeline(ctx.line_pipeline);
+        ctx.mq.apply_bindings(&inner.bindings);
+        ctx.mq.apply_uniforms(&uniforms);
+        ctx.draw_elements(inner.indices.len() as i32, 1);
+        ctx.apply_default_pipeline();
+        ctx.apply_transforms();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arrowheads_are_solid_and_pull_back_the_line() {
+        let line = Line::new(vec![Point2::origin(), Point2::new(10., 0.)])
+            .dashed(2., 2.)
+            .arrowhead(6., 4.);
+        let (mut vertices, mut indices) = (Vec::new(), Vec::new());
+        line.tessellate(&mut vertices, &mut indices);
+
+        // One quad for the segment, one triangle for the head.
+        assert_eq!(vertices.len(), 7);
+        assert_eq!(indices.len(), 9);
+        assert_eq!(vertices[2].offset.x, -6.);
+        assert_eq!(vertices[2].distance, 10.);
+        assert!(vertices[4..].iter().all(|v| v.dash == Vector2::zeros()));
+    }
+}
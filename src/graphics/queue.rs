@@ -0,0 +1,378 @@
+//! Deferred drawing, sorted by pass, layer and depth and batched by material.
+//!
+//! Drawing through [`Graphics::draw`] happens immediately, so the caller is responsible
+//! for beginning the right pass and applying the right pipeline before every draw, and
+//! for drawing things in the right order. The [`RenderQueue`] is an alternative which
+//! records draw commands instead, each with a [`DrawKey`] saying which pass and
+//! material it uses and where it goes in the draw order. Queued commands are sorted and
+//! submitted all at once by [`Graphics::flush_queue`], which [`Graphics::commit_frame`]
+//! calls if it hasn't been called already.
+//!
+//! ```ignore
+//! let glow = gfx.queue.add_material(Pipeline::with_fragment(&mut gfx, GLOW_FRAGMENT)?);
+//!
+//! gfx.queue_draw(DrawKey::new().layer(1), hud_text.clone(), InstanceParam::new());
+//! gfx.queue_draw(DrawKey::new().material(glow), bullets.clone(), InstanceParam::new());
+//! gfx.queue_draw(DrawKey::new().z(player_y), player_sprite.clone(), InstanceParam::new());
+//!
+//! gfx.commit_frame(); // Draws the bullets, then the player, then the text on top.
+//! ```
+//!
+//! Layers can also be given by name with [`DrawKey::sorting_layer`], which looks them up
+//! in the [`SortingLayers`] resource.
+//!
+//! A draw can pick a [`BlendPreset`] with [`DrawKey::blend`], so that glowing bullets
+//! can be drawn additively without touching the blend state of anything else:
+//!
+//! ```ignore
+//! let additive = DrawKey::new().blend(BlendPreset::Additive);
+//! gfx.queue_draw(additive, glow.clone(), InstanceParam::new());
+//! ```
+//!
+//! With the default material, the preset picks which of the basic pipelines is applied.
+//! With a custom material, it overrides the blending the material's pipeline was created
+//! with; without one, the material blends however its pipeline does. The preset the
+//! default pipeline was using before the queue was flushed is put back afterwards.
+//!
+//! Commands are sorted by pass, then by layer, then by z, lowest first. Commands which
+//! tie are grouped by material and then by blend preset so that switching pipelines
+//! happens as rarely as possible, and otherwise keep the order they were queued in.
+//! Each command is drawn with the projection and transform stack which were current
+//! when it was queued.
+//!
+//! Offscreen passes are drawn in the order they were added with
+//! [`RenderQueue::add_pass`], and the default pass is always drawn last, so that it can
+//! composite the results of the offscreen passes. The default pass doesn't clear the
+//! screen unless [`RenderQueue::set_pass_action`] says so, so anything drawn
+//! immediately before the queue is flushed stays underneath the queued draws.
+//!
+//! Drawables which switch pipelines themselves, like the [`LineRenderer`], leave the
+//! default pipeline applied when they're done, so they should use the default material.
+
+use {super::*, crate::layers::SortingLayers, std::cmp::Ordering};
+
+/// A pass added to a [`RenderQueue`]. [`PassId::DEFAULT`] is the default framebuffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PassId(u32);
+
+impl PassId {
+    pub const DEFAULT: PassId = PassId(0);
+
+    /// Where this pass is drawn relative to others; the default pass goes last.
+    fn rank(self) -> u32 {
+        match self {
+            Self::DEFAULT => u32::MAX,
+            PassId(n) => n,
+        }
+    }
+}
+
+/// A material added to a [`RenderQueue`]. [`MaterialId::DEFAULT`] is the basic
+/// pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MaterialId(u32);
+
+impl MaterialId {
+    pub const DEFAULT: MaterialId = MaterialId(0);
+}
+
+/// Where a queued draw goes: which pass it's drawn in, with which material, and where
+/// it goes in the draw order within the pass.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DrawKey {
+    pub pass: PassId,
+    pub material: MaterialId,
+    pub blend: Option<BlendPreset>,
+    pub layer: i32,
+    pub z: f32,
+}
+
+impl Default for DrawKey {
+    fn default() -> Self {
+        Self {
+            pass: PassId::DEFAULT,
+            material: MaterialId::DEFAULT,
+            blend: None,
+            layer: 0,
+            z: 0.,
+        }
+    }
+}
+
+impl DrawKey {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn pass(self, pass: PassId) -> Self {
+        Self { pass, ..self }
+    }
+
+    #[inline]
+    pub fn material(self, material: MaterialId) -> Self {
+        Self { material, ..self }
+    }
+
+    #[inline]
+    pub fn blend(self, blend: impl Into<Option<BlendPreset>>) -> Self {
+        Self {
+            blend: blend.into(),
+            ..self
+        }
+    }
+
+    #[inline]
+    pub fn layer(self, layer: i32) -> Self {
+        Self { layer, ..self }
+    }
+
+    /// Put the draw on a layer declared in the [`SortingLayers`], failing if there's no
+    /// such layer.
+    pub fn sorting_layer(self, layers: &SortingLayers, name: &str) -> Result<Self> {
+        Ok(self.layer(layers.id(name)?))
+    }
+
+    #[inline]
+    pub fn z(self, z: f32) -> Self {
+        Self { z, ..self }
+    }
+
+    /// How two draws are ordered when the queue is flushed; see the [module
+    /// documentation](self).
+    fn draw_order(&self, other: &Self) -> Ordering {
+        self.pass
+            .rank()
+            .cmp(&other.pass.rank())
+            .then(self.layer.cmp(&other.layer))
+            .then(self.z.partial_cmp(&other.z).unwrap_or(Ordering::Equal))
+            .then(self.material.cmp(&other.material))
+            .then(self.blend.cmp(&other.blend))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct QueuedPass {
+    target: Option<RenderPass>,
+    action: PassAction,
+}
+
+#[derive(Derivative)]
+#[derivative(Debug)]
+struct DrawCommand {
+    key: DrawKey,
+    projection: Matrix4<f32>,
+    modelview: Matrix4<f32>,
+    #[derivative(Debug = "ignore")]
+    drawable: Arc<dyn Drawable + Send + Sync>,
+    param: InstanceParam,
+}
+
+/// Draw commands waiting to be submitted. See the [module documentation](self) for
+/// details.
+#[derive(Debug)]
+pub struct RenderQueue {
+    passes: Vec<QueuedPass>,
+    materials: Vec<Option<Pipeline>>,
+    commands: Vec<DrawCommand>,
+}
+
+impl Default for RenderQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RenderQueue {
+    pub fn new() -> Self {
+        Self {
+            passes: vec![QueuedPass {
+                target: None,
+                action: PassAction::Nothing,
+            }],
+            materials: vec![None],
+            commands: Vec::new(),
+        }
+    }
+
+    /// Add an offscreen pass, begun with `action` every time the queue is flushed.
+    pub fn add_pass(&mut self, target: RenderPass, action: PassAction) -> PassId {
+        self.passes.push(QueuedPass {
+            target: Some(target),
+            action,
+        });
+        PassId(self.passes.len() as u32 - 1)
+    }
+
+    /// Change what happens to a pass's framebuffer when it's begun.
+    pub fn set_pass_action(&mut self, pass: PassId, action: PassAction) {
+        self.passes[pass.0 as usize].action = action;
+    }
+
+    /// Add a material, which draws with a custom pipeline.
+    pub fn add_material(&mut self, pipeline: Pipeline) -> MaterialId {
+        self.materials.push(Some(pipeline));
+        MaterialId(self.materials.len() as u32 - 1)
+    }
+
+    /// Queue a draw with an explicit projection and transform.
+    pub fn push(
+        &mut self,
+        key: DrawKey,
+        projection: Matrix4<f32>,
+        modelview: Matrix4<f32>,
+        drawable: Arc<dyn Drawable + Send + Sync>,
+        param: InstanceParam,
+    ) {
+        self.commands.push(DrawCommand {
+            key,
+            projection,
+            modelview,
+            drawable,
+            param,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Throw away every queued command without drawing it.
+    pub fn clear(&mut self) {
+        self.commands.clear();
+    }
+}
+
+impl Graphics {
+    /// Queue a draw on the [`RenderQueue`], with the current projection and transform.
+    pub fn queue_draw(
+        &mut self,
+        key: DrawKey,
+        drawable: Arc<dyn Drawable + Send + Sync>,
+        param: InstanceParam,
+    ) {
+        let (projection, modelview) = (self.projection, *self.modelview.top());
+        self.queue.push(key, projection, modelview, drawable, param);
+    }
+
+    /// Sort and draw everything on the [`RenderQueue`], leaving it empty. This must be
+    /// called outside of any pass, and before taking a screenshot if the screenshot
+    /// should include the queued draws.
+    pub fn flush_queue(&mut self) {
+        if self.queue.is_empty() {
+            return;
+        }
+
+        let mut commands = mem::take(&mut self.queue.commands);
+        commands.sort_by(|a, b| a.key.draw_order(&b.key));
+
+        let saved_projection = self.projection;
+        let saved_blend = self.blend;
+        let mut current_pass = None;
+        let mut current_material = None;
+        let mut current_mvp = None;
+
+        for command in commands.drain(..) {
+            if current_pass != Some(command.key.pass) {
+                if current_pass.is_some() {
+                    self.end_pass();
+                }
+
+                let pass = self.queue.passes[command.key.pass.0 as usize].clone();
+                match &pass.target {
+                    Some(target) => self.begin_pass(target, pass.action),
+                    None => self.begin_default_pass(pass.action),
+                }
+
+                current_pass = Some(command.key.pass);
+                current_material = None;
+            }
+
+            let material = (command.key.material, command.key.blend);
+            if current_material != Some(material) {
+                match self.queue.materials[command.key.material.0 as usize].clone() {
+                    Some(pipeline) => {
+                        self.blend = saved_blend;
+                        self.apply_pipeline(&pipeline);
+                        if let Some(preset) = command.key.blend {
+                            self.set_blend(Some(preset.mode()));
+                        }
+                    }
+                    None => {
+                        self.blend = command.key.blend.unwrap_or(saved_blend);
+                        self.apply_default_pipeline();
+                    }
+                }
+
+                current_material = Some(material);
+                // Uniforms don't survive switching pipelines.
+                current_mvp = None;
+            }
+
+            self.projection = command.projection;
+            self.modelview.push(command.modelview);
+            let mvp = command.projection * command.modelview;
+            if current_mvp != Some(mvp) {
+                self.apply_transforms();
+                current_mvp = Some(mvp);
+            }
+
+            command.drawable.draw(self, command.param);
+            self.modelview.pop();
+        }
+
+        if current_pass.is_some() {
+            self.end_pass();
+        }
+
+        self.projection = saved_projection;
+        self.blend = saved_blend;
+        // Hang onto the allocation for next frame.
+        self.queue.commands = commands;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(keys: &[DrawKey]) -> Vec<usize> {
+        let mut order = (0..keys.len()).collect::<Vec<_>>();
+        order.sort_by(|&a, &b| keys[a].draw_order(&keys[b]));
+        order
+    }
+
+    #[test]
+    fn ties_are_grouped_by_material_then_blend() {
+        let custom = MaterialId(1);
+        let keys = [
+            DrawKey::new().blend(BlendPreset::Additive),
+            DrawKey::new().material(custom),
+            DrawKey::new(),
+            DrawKey::new().material(custom).blend(BlendPreset::Multiply),
+            DrawKey::new().blend(BlendPreset::Additive),
+            DrawKey::new().blend(BlendPreset::Alpha),
+        ];
+
+        // Draws without a preset of their own come first, and draws which tie keep the
+        // order they were queued in.
+        assert_eq!(sorted(&keys), [2, 5, 0, 4, 1, 3]);
+    }
+
+    #[test]
+    fn blend_presets_dont_reorder_layers_or_passes() {
+        let keys = [
+            DrawKey::new().blend(BlendPreset::Additive).layer(1),
+            DrawKey::new().layer(2),
+            DrawKey::new().blend(BlendPreset::Multiply).pass(PassId(1)),
+            DrawKey::new().blend(BlendPreset::Premultiplied).z(-1.),
+        ];
+
+        assert_eq!(sorted(&keys), [2, 3, 0, 1]);
+    }
+}
@@ -0,0 +1,484 @@
+//! Recording the last few seconds of gameplay, for bug reports and sharing.
+//!
+//! A [`FrameRecorder`] keeps a ring buffer of downscaled [`Screenshot`]s of the last
+//! few seconds of frames. Nothing is encoded until a clip is asked for, at which point
+//! the frames in the buffer are handed to the [`TaskPool`] and written out as a GIF or
+//! as a directory of numbered PNGs, so saving a clip never stalls the game.
+//!
+//! The recorder is a resource. A space whose global resources hold the [`Graphics`]
+//! gets one in its global resources when it's built, if there isn't one already, and
+//! [`Space::frame_update`](crate::Space::frame_update) feeds it through [`update`]: each
+//! frame it picks up the frame captured at the last `commit_frame` and asks for the next
+//! one when it's due, then saves any requested clips. Recording starts out enabled in
+//! debug builds only.
+//!
+//! Clips can be asked for from Rust with [`FrameRecorder::request_clip`], by pressing a
+//! key or mouse button bound to [`RecorderButton::SaveClip`] in the recorder's
+//! [bindings](FrameRecorder::bindings), which the default
+//! [`EventHandler`](crate::event::EventHandler) input events pass on to the recorder of
+//! the handler's space, or from Lua:
+//!
+//! ```lua
+//! sludge.graphics.clip("/clips/boss.gif")
+//! sludge.graphics.clip("/clips/boss", "png")
+//! ```
+//!
+//! Capturing reads the frame back from the GPU, which isn't free, so frames are only
+//! captured at [`RecorderConfig::fps`] rather than every frame.
+
+use {
+    super::*,
+    crate::{
+        input::{InputBinding, InputEffect, KeyCode, MouseButton},
+        task::TaskPool,
+        SludgeLuaContextExt,
+    },
+    std::{
+        collections::VecDeque,
+        fs::File,
+        io::BufWriter,
+        time::{Duration, Instant},
+    },
+};
+
+/// How a clip is written out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipFormat {
+    /// A single animated GIF.
+    Gif,
+    /// A directory of PNGs named `frame_0000.png`, `frame_0001.png`, and so on.
+    Png,
+}
+
+impl ClipFormat {
+    /// GIF if the path ends in `.gif`, and an image sequence otherwise.
+    pub fn from_path(path: impl AsRef<Path>) -> Self {
+        match path.as_ref().extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("gif") => Self::Gif,
+            _ => Self::Png,
+        }
+    }
+}
+
+impl<'lua> FromLua<'lua> for ClipFormat {
+    fn from_lua(lua_value: LuaValue<'lua>, lua: LuaContext<'lua>) -> LuaResult<Self> {
+        match String::from_lua(lua_value, lua)?.as_str() {
+            "gif" => Ok(Self::Gif),
+            "png" => Ok(Self::Png),
+            other => Err(LuaError::FromLuaConversionError {
+                from: "string",
+                to: "clip format",
+                message: Some(format!("expected 'gif' or 'png', found '{}'", other)),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecorderConfig {
+    /// How many seconds of frames to keep.
+    pub seconds: f32,
+    /// How many frames to capture per second.
+    pub fps: f32,
+    /// How many times smaller than the screen captured frames are, along each axis.
+    pub downscale: u32,
+}
+
+impl Default for RecorderConfig {
+    fn default() -> Self {
+        Self {
+            seconds: 5.,
+            fps: 15.,
+            downscale: 2,
+        }
+    }
+}
+
+/// A captured frame, and when it was captured.
+#[derive(Debug)]
+struct RecordedFrame {
+    screenshot: Screenshot,
+    captured: Instant,
+}
+
+/// The frames of a clip, ready to be encoded.
+#[derive(Debug, Clone)]
+pub struct Clip {
+    frames: Vec<Arc<RecordedFrame>>,
+    /// How long the last frame is shown for, since there's no frame after it to say.
+    last_delay: Duration,
+}
+
+impl Clip {
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// How long each frame is shown for, going by when the next frame was captured.
+    fn delays(&self) -> impl Iterator<Item = Duration> + '_ {
+        let last_delay = self.last_delay;
+        self.frames
+            .iter()
+            .zip(self.frames.iter().skip(1).map(Some).chain(Some(None)))
+            .map(move |(frame, next)| match next {
+                Some(next) => next.captured - frame.captured,
+                None => last_delay,
+            })
+    }
+
+    /// Encode the clip as an animated GIF and write it to a path on disk, creating any
+    /// missing parent directories. Frames which aren't the size of the first, because
+    /// the window was resized while recording, are left out.
+    pub fn save_gif(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let first = match self.frames.first() {
+            Some(first) => &first.screenshot,
+            None => bail!("can't save an empty clip"),
+        };
+
+        let mut encoder = image::gif::Encoder::new(BufWriter::new(File::create(path)?));
+        for (frame, delay) in self.frames.iter().zip(self.delays()) {
+            let screenshot = &frame.screenshot;
+            if (screenshot.width, screenshot.height) != (first.width, first.height) {
+                continue;
+            }
+
+            let buffer = image::RgbaImage::from_raw(
+                screenshot.width,
+                screenshot.height,
+                screenshot.pixels.clone(),
+            )
+            .ok_or_else(|| anyhow!("frame pixels don't match the frame's size"))?;
+            let millis = delay.as_millis().min(u16::MAX as u128) as u16;
+            encoder.encode(&image::Frame::from_parts(
+                buffer,
+                0,
+                0,
+                num_rational::Ratio::from_integer(millis),
+            ))?;
+        }
+
+        Ok(())
+    }
+
+    /// Write each frame of the clip as a numbered PNG into a directory on disk,
+    /// creating it if it's missing. Like [`Clip::save_gif`], frames which aren't the size
+    /// of the first are left out, so the sequence can be put back together as a video.
+    pub fn save_images(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        let first = match self.frames.first() {
+            Some(first) => &first.screenshot,
+            None => bail!("can't save an empty clip"),
+        };
+
+        let same_size = self.frames.iter().filter(|frame| {
+            (frame.screenshot.width, frame.screenshot.height) == (first.width, first.height)
+        });
+        for (i, frame) in same_size.enumerate() {
+            frame
+                .screenshot
+                .save_png(dir.join(format!("frame_{:04}.png", i)))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>, format: ClipFormat) -> Result<()> {
+        match format {
+            ClipFormat::Gif => self.save_gif(path),
+            ClipFormat::Png => self.save_images(path),
+        }
+    }
+}
+
+/// The buttons the recorder can be bound to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RecorderButton {
+    /// Save the frames in the buffer as a GIF in the `clips` directory of the user
+    /// directory, named after when it was saved.
+    SaveClip,
+}
+
+/// Keeps the last few seconds of frames. See the [module documentation](self) for
+/// details.
+#[derive(Debug)]
+pub struct FrameRecorder {
+    pub config: RecorderConfig,
+    /// Whether frames are being captured. Frames already in the buffer are kept while
+    /// recording is paused.
+    pub enabled: bool,
+    /// What presses which keys and mouse buttons do, as passed to
+    /// [`FrameRecorder::key_down`] and [`FrameRecorder::mouse_button_down`]. By default,
+    /// F9 saves a clip.
+    pub bindings: InputBinding<(), RecorderButton>,
+    frames: VecDeque<Arc<RecordedFrame>>,
+    last_capture: Option<Instant>,
+    requests: Vec<(String, ClipFormat)>,
+}
+
+impl Default for FrameRecorder {
+    fn default() -> Self {
+        Self::new(RecorderConfig::default())
+    }
+}
+
+impl FrameRecorder {
+    pub fn new(config: RecorderConfig) -> Self {
+        Self {
+            config,
+            enabled: cfg!(debug_assertions),
+            bindings: InputBinding::new().bind_key_to_button(KeyCode::F9, RecorderButton::SaveClip),
+            frames: VecDeque::new(),
+            last_capture: None,
+            requests: Vec::new(),
+        }
+    }
+
+    fn frame_interval(&self) -> Duration {
+        Duration::from_secs_f32(1. / self.config.fps.max(1.))
+    }
+
+    /// Whether it's been long enough since the last capture to take another.
+    fn is_due(&self, now: Instant) -> bool {
+        self.enabled
+            && !matches!(self.last_capture, Some(last) if now - last < self.frame_interval())
+    }
+
+    /// Add a frame to the buffer, and drop any frames which are now too old to keep.
+    fn push(&mut self, screenshot: Screenshot, now: Instant) {
+        self.frames.push_back(Arc::new(RecordedFrame {
+            screenshot: downscale(screenshot, self.config.downscale),
+            captured: now,
+        }));
+
+        let keep = Duration::from_secs_f32(self.config.seconds.max(0.));
+        while matches!(self.frames.front(), Some(oldest) if now - oldest.captured > keep) {
+            self.frames.pop_front();
+        }
+    }
+
+    /// Capture the current frame if it's been long enough since the last one. This
+    /// should be called after everything has been drawn for the frame, but before
+    /// `commit_frame`. Use [`FrameRecorder::update`] from anywhere else in the frame.
+    pub fn capture(&mut self, gfx: &mut Graphics) {
+        let now = Instant::now();
+        if self.is_due(now) {
+            self.last_capture = Some(now);
+            self.push(gfx.screenshot(), now);
+        }
+    }
+
+    /// Pick up the frame captured when the last frame was committed, if one was asked
+    /// for, and ask for the next frame to be captured if it's due. This can be called
+    /// at any point in the frame.
+    pub fn update(&mut self, gfx: &mut Graphics) {
+        let now = Instant::now();
+        if let Some(screenshot) = gfx.take_capture() {
+            self.push(screenshot, now);
+        }
+
+        if self.is_due(now) {
+            self.last_capture = Some(now);
+            gfx.request_capture();
+        }
+    }
+
+    /// Throw away every captured frame.
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.last_capture = None;
+    }
+
+    /// The frames currently in the buffer. This is cheap; the frames themselves are
+    /// shared rather than copied.
+    pub fn clip(&self) -> Clip {
+        Clip {
+            frames: self.frames.iter().cloned().collect(),
+            last_delay: self.frame_interval(),
+        }
+    }
+
+    /// Ask for the frames in the buffer to be saved to `path` in the user directory the
+    /// next time [`FrameRecorder::save_requested_clips`] is called.
+    pub fn request_clip(&mut self, path: impl Into<String>, format: ClipFormat) {
+        self.requests.push((path.into(), format));
+    }
+
+    /// Do whatever `keycode` is bound to. Returns whether it was bound to anything.
+    pub fn key_down(&mut self, keycode: KeyCode) -> bool {
+        let effect = self.bindings.resolve_keycode(keycode);
+        self.apply(effect)
+    }
+
+    /// Do whatever `button` is bound to. Returns whether it was bound to anything.
+    pub fn mouse_button_down(&mut self, button: MouseButton) -> bool {
+        let effect = self.bindings.resolve_mouse_button(button, Point2::origin());
+        self.apply(effect)
+    }
+
+    fn apply(&mut self, effect: Option<InputEffect<(), RecorderButton>>) -> bool {
+        match effect {
+            Some(InputEffect::Button(RecorderButton::SaveClip, _)) => {
+                let name = chrono::Local::now().format("%Y-%m-%d_%H-%M-%S");
+                self.request_clip(format!("/clips/{}.gif", name), ClipFormat::Gif);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Encode and save every requested clip on the task pool. Errors while encoding are
+    /// logged, since they happen long after this returns.
+    pub fn save_requested_clips(&mut self, fs: &Filesystem, pool: &TaskPool) -> Result<()> {
+        if self.requests.is_empty() {
+            return Ok(());
+        }
+
+        let clip = self.clip();
+        for (path, format) in self.requests.drain(..) {
+            let path = fs.user_path(&path)?;
+            let clip = clip.clone();
+            pool.execute(move || match clip.save(&path, format) {
+                Ok(()) => log::info!("saved {} frame clip to {:?}", clip.len(), path),
+                Err(err) => log::error!("error saving clip to {:?}: {:#}", path, err),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Feed the [`FrameRecorder`] from Lua's resources, if there is one, and save any
+/// requested clips. Does nothing without a recorder or [`Graphics`], and clips are only
+/// saved once there's a [`Filesystem`] and a [`TaskPool`] to save them with.
+pub fn update(lua: LuaContext) -> Result<()> {
+    let (recorder, gfx) = match (
+        lua.fetch_one::<FrameRecorder>(),
+        lua.fetch_one::<Graphics>(),
+    ) {
+        (Ok(recorder), Ok(gfx)) => (recorder, gfx),
+        _ => return Ok(()),
+    };
+
+    let mut recorder = recorder.borrow_mut();
+    recorder.update(&mut gfx.borrow_mut());
+
+    if !recorder.requests.is_empty() {
+        if let (Ok(fs), Ok(pool)) = (lua.fetch_one::<Filesystem>(), lua.fetch_one::<TaskPool>()) {
+            recorder.save_requested_clips(&fs.borrow(), &pool.borrow())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Shrink a screenshot by a whole number factor, averaging each square of pixels.
+fn downscale(screenshot: Screenshot, factor: u32) -> Screenshot {
+    if factor <= 1 {
+        return screenshot;
+    }
+
+    let width = (screenshot.width / factor).max(1);
+    let height = (screenshot.height / factor).max(1);
+    let mut pixels = Vec::with_capacity(width as usize * height as usize * 4);
+
+    for y in 0..height {
+        let rows = y * factor..((y + 1) * factor).min(screenshot.height);
+        for x in 0..width {
+            let columns = x * factor..((x + 1) * factor).min(screenshot.width);
+            let mut sum = [0u32; 4];
+            let mut count = 0;
+            for sy in rows.clone() {
+                for sx in columns.clone() {
+                    let i = (sy as usize * screenshot.width as usize + sx as usize) * 4;
+                    for (total, &channel) in sum.iter_mut().zip(&screenshot.pixels[i..i + 4]) {
+                        *total += channel as u32;
+                    }
+                    count += 1;
+                }
+            }
+            pixels.extend(sum.iter().map(|&total| (total / count.max(1)) as u8));
+        }
+    }
+
+    Screenshot {
+        width,
+        height,
+        pixels,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn downscale_averages_blocks() {
+        let pixels = [10u8, 20, 30, 40, 50, 60]
+            .iter()
+            .flat_map(|&v| vec![v, v, v, 255])
+            .collect();
+        let screenshot = Screenshot {
+            width: 3,
+            height: 2,
+            pixels,
+        };
+
+        let small = downscale(screenshot, 2);
+        assert_eq!((small.width, small.height), (1, 1));
+        assert_eq!(small.pixels, vec![30, 30, 30, 255]);
+    }
+
+    fn frame(width: u32, height: u32) -> Arc<RecordedFrame> {
+        Arc::new(RecordedFrame {
+            screenshot: Screenshot {
+                width,
+                height,
+                pixels: vec![255; width as usize * height as usize * 4],
+            },
+            captured: Instant::now(),
+        })
+    }
+
+    #[test]
+    fn bound_inputs_request_clips() {
+        let mut recorder = FrameRecorder::default();
+        assert!(!recorder.key_down(KeyCode::F8));
+        assert!(recorder.key_down(KeyCode::F9));
+        assert_eq!(recorder.requests.len(), 1);
+        assert_eq!(recorder.requests[0].1, ClipFormat::Gif);
+
+        recorder.bindings =
+            InputBinding::new().bind_mouse_to_button(MouseButton::Middle, RecorderButton::SaveClip);
+        assert!(!recorder.key_down(KeyCode::F9));
+        assert!(recorder.mouse_button_down(MouseButton::Middle));
+        assert_eq!(recorder.requests.len(), 2);
+    }
+
+    #[test]
+    fn image_sequences_skip_resized_frames() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("sludge-recorder-{}", std::process::id()));
+        let clip = Clip {
+            frames: vec![frame(4, 2), frame(8, 4), frame(4, 2)],
+            last_delay: Duration::from_millis(50),
+        };
+
+        clip.save_images(&dir)?;
+        let mut saved = std::fs::read_dir(&dir)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect::<Result<Vec<_>>>()?;
+        saved.sort();
+        std::fs::remove_dir_all(&dir)?;
+
+        assert_eq!(saved, vec!["frame_0000.png", "frame_0001.png"]);
+        Ok(())
+    }
+}
@@ -8,10 +8,18 @@ use {
 };
 
 use crate::{
-    ecs::{ComponentEvent, ComponentSubscriber, Entity, FlaggedComponent, ScContext, World},
+    ecs::{
+        CloneComponent, ComponentEvent, ComponentSubscriber, Entity, FlaggedComponent, ScContext,
+        World,
+    },
+    transform::{Placement2, Transform, Transform2d},
     Resources,
 };
 
+/// Broadcast when an entity's parent is changed from Lua with `entity:set_parent`, with
+/// the entity, its new parent and its old parent. Either parent may be `nil`.
+pub const PARENT_CHANGED_EVENT: &'static str = "sludge.hierarchy.parent_changed";
+
 #[derive(Debug, Clone, Copy)]
 pub struct Parent {
     pub parent_entity: Entity,
@@ -33,6 +41,10 @@ inventory::submit! {
     FlaggedComponent::of::<Parent>()
 }
 
+inventory::submit! {
+    CloneComponent::of::<Parent>()
+}
+
 impl ParentComponent for Parent {
     fn parent_entity(&self) -> Entity {
         self.parent_entity
@@ -43,6 +55,98 @@ pub trait ParentComponent: for<'a> SmartComponent<ScContext<'a>> {
     fn parent_entity(&self) -> Entity;
 }
 
+/// Get the parent of an entity straight from its [`Parent`] component, without waiting
+/// for the [`HierarchyManager`] to catch up.
+pub fn parent_of(world: &World, entity: Entity) -> Option<Entity> {
+    world
+        .get::<Parent>(entity)
+        .ok()
+        .map(|parent| parent.parent_entity)
+}
+
+/// Get the immediate children of an entity straight from the world's [`Parent`]
+/// components. This scans every parented entity; prefer [`HierarchyManager::children`]
+/// where its view of the hierarchy is recent enough.
+pub fn children_of(world: &World, entity: Entity) -> Vec<Entity> {
+    world
+        .query_raw::<&Parent>()
+        .iter()
+        .filter(|(_, parent)| parent.parent_entity == entity)
+        .map(|(child, _)| child)
+        .collect()
+}
+
+/// Attach an entity to a new parent, or detach it from its parent with `None`, returning
+/// its old parent.
+///
+/// With `keep_world_transform` set, the entity's local [`Transform`] and [`Transform2d`]
+/// are recomputed against the new parent's global transform so that it doesn't move;
+/// otherwise the local transform is kept, and the entity jumps to wherever it puts it
+/// relative to the new parent. Either way, the global transform is only brought up to
+/// date by the next update of the transform managers. Making an entity its own ancestor
+/// is an error.
+pub fn set_parent(
+    world: &mut World,
+    entity: Entity,
+    parent: Option<Entity>,
+    keep_world_transform: bool,
+) -> Result<Option<Entity>> {
+    ensure!(world.contains(entity), "no such entity {:?}", entity);
+
+    if let Some(parent) = parent {
+        ensure!(world.contains(parent), "no such parent entity {:?}", parent);
+
+        let mut ancestor = Some(parent);
+        while let Some(current) = ancestor {
+            ensure!(
+                current != entity,
+                "cannot parent {:?} to {:?}, which is its own descendant",
+                entity,
+                parent
+            );
+            ancestor = parent_of(world, current);
+        }
+    }
+
+    let old_parent = parent_of(world, entity);
+    if old_parent == parent {
+        return Ok(old_parent);
+    }
+
+    if keep_world_transform {
+        let parent_global_2d = parent.map(|p| {
+            world
+                .get::<Transform2d>(p)
+                .map(|tx| *tx.global())
+                .unwrap_or_else(|_| Placement2::identity())
+        });
+        if let Ok(mut tx) = world.get_mut::<Transform2d>(entity) {
+            tx.local = match parent_global_2d {
+                Some(parent_global) => tx.global.relative_to(&parent_global),
+                None => tx.global,
+            };
+        }
+
+        let parent_global = parent.and_then(|p| world.get::<Transform>(p).ok().map(|tx| tx.global));
+        if let Ok(mut tx) = world.get_mut::<Transform>(entity) {
+            tx.local = match parent_global.and_then(|global| global.try_inverse()) {
+                Some(inverse) => inverse * tx.global,
+                None => tx.global,
+            };
+        }
+    }
+
+    match (parent, old_parent) {
+        (Some(parent), Some(_)) => world.get_mut::<Parent>(entity)?.parent_entity = parent,
+        (Some(parent), None) => world.insert_one(entity, Parent::new(parent))?,
+        (None, _) => {
+            world.remove_one::<Parent>(entity)?;
+        }
+    }
+
+    Ok(old_parent)
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum HierarchyEvent {
     ModifiedOrCreated(Entity),
@@ -45,7 +45,23 @@
 // TODO: Handle mice, game pads, joysticks
 
 use crate::math::*;
-use {hashbrown::HashMap, std::hash::Hash};
+use {
+    hashbrown::HashMap,
+    serde::{Deserialize, Serialize},
+    std::hash::Hash,
+};
+
+mod text;
+mod touch;
+
+pub use text::{
+    broadcast as broadcast_text, TextEvent, TextInput, TEXT_CANCELLED_EVENT, TEXT_EVENT,
+    TEXT_SUBMITTED_EVENT,
+};
+pub use touch::{
+    broadcast as broadcast_touches, Touch, TouchEvent, TouchPhase, Touches, TOUCH_ENDED_EVENT,
+    TOUCH_MOVED_EVENT, TOUCH_STARTED_EVENT,
+};
 
 // Okay, but how does it actually work?
 // Basically we have to bind input events to buttons and axes.
@@ -193,6 +209,7 @@ pub enum KeyCode {
     Unknown,
 }
 
+#[cfg(feature = "miniquad")]
 impl From<miniquad::KeyCode> for KeyCode {
     fn from(kc: miniquad::KeyCode) -> Self {
         use miniquad::KeyCode as MqKc;
@@ -332,6 +349,7 @@ pub struct KeyMods {
     pub logo: bool,
 }
 
+#[cfg(feature = "miniquad")]
 impl From<miniquad::KeyMods> for KeyMods {
     fn from(km: miniquad::KeyMods) -> Self {
         Self {
@@ -350,6 +368,7 @@ pub enum MouseButton {
     Middle,
 }
 
+#[cfg(feature = "miniquad")]
 impl From<miniquad::MouseButton> for MouseButton {
     fn from(mq: miniquad::MouseButton) -> Self {
         use miniquad::MouseButton as MqMb;
@@ -370,7 +389,7 @@ enum InputType {
     MouseButtonEvent(MouseButton),
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub enum InputEffect<Axes, Buttons>
 where
     Axes: Eq + Hash + Clone,
@@ -451,6 +470,7 @@ struct ButtonState {
 /// A struct that contains a mapping from physical input events
 /// (currently just `KeyCode`s) to whatever your logical Axis/Button
 /// types are.
+#[derive(Debug)]
 pub struct InputBinding<Axes, Buttons>
 where
     Axes: Hash + Eq + Clone,
@@ -530,6 +550,8 @@ where
     buttons: HashMap<Buttons, ButtonState>,
     // Input state for the mouse cursor
     mouse: CursorState,
+    // Every effect applied since recording started, if we're recording.
+    recording: Option<InputRecording<Axes, Buttons>>,
 }
 
 impl<Axes, Buttons> InputState<Axes, Buttons>
@@ -542,6 +564,7 @@ where
             axes: HashMap::new(),
             buttons: HashMap::new(),
             mouse: CursorState::default(),
+            recording: None,
         }
     }
 
@@ -586,6 +609,10 @@ where
 
         self.mouse.delta = self.mouse.position - self.mouse.last_position;
         self.mouse.last_position = self.mouse.position;
+
+        if let Some(recording) = self.recording.as_mut() {
+            recording.advance();
+        }
     }
 
     /// This method should get called by your key_down_event handler.
@@ -614,6 +641,10 @@ where
 
     /// Takes an InputEffect and actually applies it.
     pub fn update_effect(&mut self, effect: InputEffect<Axes, Buttons>, started: bool) {
+        if let Some(recording) = self.recording.as_mut() {
+            recording.push(effect.clone(), started);
+        }
+
         match effect {
             InputEffect::Axis(axis, positive) => {
                 let f = || AxisState::default();
@@ -692,6 +723,39 @@ where
         self.mouse.delta
     }
 
+    /// Every button which is currently held down.
+    pub fn buttons_down(&self) -> impl Iterator<Item = &Buttons> + '_ {
+        self.buttons
+            .iter()
+            .filter(|(_, status)| status.pressed)
+            .map(|(button, _)| button)
+    }
+
+    /// Every axis which is currently being pushed or hasn't yet fallen back to zero,
+    /// along with its position.
+    pub fn axes_active(&self) -> impl Iterator<Item = (&Axes, f32)> + '_ {
+        self.axes
+            .iter()
+            .filter(|(_, status)| status.direction != 0. || status.position != 0.)
+            .map(|(axis, status)| (axis, status.position))
+    }
+
+    /// Start recording every input effect applied to this state, discarding any
+    /// recording already in progress.
+    pub fn start_recording(&mut self) {
+        self.recording = Some(InputRecording::new());
+    }
+
+    /// Stop recording and return everything recorded since
+    /// [`start_recording`](InputState::start_recording) was called.
+    pub fn stop_recording(&mut self) -> Option<InputRecording<Axes, Buttons>> {
+        self.recording.take()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
     pub fn reset_input_state(&mut self) {
         for (_axis, axis_status) in self.axes.iter_mut() {
             axis_status.position = 0.0;
@@ -709,6 +773,133 @@ where
     }
 }
 
+/// A single input effect captured by an [`InputRecording`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedInput<Axes, Buttons>
+where
+    Axes: Eq + Hash + Clone,
+    Buttons: Eq + Hash + Clone,
+{
+    /// The tick the effect was applied on, counting calls to [`InputState::update`]
+    /// since the recording started.
+    pub tick: u32,
+    pub effect: InputEffect<Axes, Buttons>,
+    pub started: bool,
+}
+
+/// A tick-by-tick recording of the effects applied to an [`InputState`].
+///
+/// Only changes in input are stored, and cursor movements within a single tick are
+/// collapsed into the last one, so a recording stays small even over long sessions.
+/// Recordings are serializable, and are meant to be saved alongside the seed of the
+/// space's [`RngResource`](crate::rng::RngResource) to replay a session exactly.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InputRecording<Axes, Buttons>
+where
+    Axes: Eq + Hash + Clone,
+    Buttons: Eq + Hash + Clone,
+{
+    ticks: u32,
+    events: Vec<RecordedInput<Axes, Buttons>>,
+}
+
+impl<Axes, Buttons> InputRecording<Axes, Buttons>
+where
+    Axes: Eq + Hash + Clone,
+    Buttons: Eq + Hash + Clone,
+{
+    pub fn new() -> Self {
+        Self {
+            ticks: 0,
+            events: Vec::new(),
+        }
+    }
+
+    /// The number of ticks recorded.
+    pub fn ticks(&self) -> u32 {
+        self.ticks
+    }
+
+    pub fn events(&self) -> &[RecordedInput<Axes, Buttons>] {
+        &self.events
+    }
+
+    /// Record an effect on the current tick.
+    pub fn push(&mut self, effect: InputEffect<Axes, Buttons>, started: bool) {
+        if let InputEffect::Cursor(_) = effect {
+            if let Some(last) = self.events.last_mut() {
+                if last.tick == self.ticks && matches!(last.effect, InputEffect::Cursor(_)) {
+                    last.effect = effect;
+                    return;
+                }
+            }
+        }
+
+        self.events.push(RecordedInput {
+            tick: self.ticks,
+            effect,
+            started,
+        });
+    }
+
+    /// Move on to the next tick.
+    pub fn advance(&mut self) {
+        self.ticks += 1;
+    }
+}
+
+/// Plays an [`InputRecording`] back into an [`InputState`].
+#[derive(Debug, Clone)]
+pub struct InputPlayback<Axes, Buttons>
+where
+    Axes: Eq + Hash + Clone,
+    Buttons: Eq + Hash + Clone,
+{
+    recording: InputRecording<Axes, Buttons>,
+    tick: u32,
+    next: usize,
+}
+
+impl<Axes, Buttons> InputPlayback<Axes, Buttons>
+where
+    Axes: Eq + Hash + Clone,
+    Buttons: Eq + Hash + Clone,
+{
+    pub fn new(recording: InputRecording<Axes, Buttons>) -> Self {
+        Self {
+            recording,
+            tick: 0,
+            next: 0,
+        }
+    }
+
+    /// The tick which will be played back by the next call to
+    /// [`step`](InputPlayback::step).
+    pub fn tick(&self) -> u32 {
+        self.tick
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.tick >= self.recording.ticks && self.next >= self.recording.events.len()
+    }
+
+    /// Apply every effect recorded on the current tick to `state` and move on to the
+    /// next tick. This should be called once per tick, before [`InputState::update`],
+    /// in place of feeding the state real input events.
+    pub fn step(&mut self, state: &mut InputState<Axes, Buttons>) {
+        while let Some(event) = self.recording.events.get(self.next) {
+            if event.tick > self.tick {
+                break;
+            }
+
+            state.update_effect(event.effect.clone(), event.started);
+            self.next += 1;
+        }
+
+        self.tick += 1;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -833,6 +1024,36 @@ mod tests {
         assert_eq!(im.get_axis_raw(Axes::Vert), 0.0);
     }
 
+    #[test]
+    fn test_record_and_playback() {
+        let mut im: InputState<Axes, Buttons> = InputState::new();
+        im.start_recording();
+        im.update_button_down(Buttons::A);
+        im.update_mouse_position(Point2::new(1., 1.));
+        im.update_mouse_position(Point2::new(2., 2.));
+        im.update(0.1);
+        im.update_axis_start(Axes::Horz, true);
+        im.update(0.1);
+        im.update_button_up(Buttons::A);
+        im.update(0.1);
+        let recording = im.stop_recording().unwrap();
+        assert_eq!(recording.ticks(), 3);
+        assert_eq!(recording.events().len(), 4);
+
+        let mut replayed: InputState<Axes, Buttons> = InputState::new();
+        let mut playback = InputPlayback::new(recording);
+        playback.step(&mut replayed);
+        assert!(replayed.get_button_down(Buttons::A));
+        assert_eq!(replayed.mouse_position(), Point2::new(2., 2.));
+        replayed.update(0.1);
+        playback.step(&mut replayed);
+        assert!(replayed.get_axis_raw(Axes::Horz) > 0.);
+        replayed.update(0.1);
+        playback.step(&mut replayed);
+        assert!(replayed.get_button_up(Buttons::A));
+        assert!(playback.is_finished());
+    }
+
     #[test]
     fn test_button_edge_transitions() {
         let mut im: InputState<Axes, Buttons> = InputState::new();
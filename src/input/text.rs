@@ -0,0 +1,300 @@
+//! Typed text, for consoles, chat boxes and name-entry screens.
+//!
+//! Key events say which keys are down, not what was typed; the character a key produces
+//! depends on the keyboard layout, the modifiers held and, for many languages, an input
+//! method editor (IME) which composes several keystrokes into a single character. The
+//! platform does all of that work and reports the result as character events, which
+//! the [`EventHandler`](crate::event::EventHandler) receives through
+//! [`text_input_event`](crate::event::EventHandler::text_input_event).
+//!
+//! The [`TextInput`] resource decides where those characters go. At most one text field
+//! has focus at a time, named by whoever focused it, and only the focused field gets
+//! the text. While something has focus, [`TextInput::key_down_event`] consumes every
+//! key, handling the usual editing keys itself. The default `key_down_event` and
+//! `text_input_event` of an `EventHandler` feed the `TextInput` of its
+//! [`space`](crate::event::EventHandler::space); handlers which override them to bind
+//! keys should keep doing so, and skip their bindings for consumed keys, so that typing
+//! a name doesn't also walk the player around:
+//!
+//! ```ignore
+//! fn key_down_event(&mut self, keycode: KeyCode, keymods: KeyMods, _repeat: bool) {
+//!     let text_input = self.space.fetch_one::<TextInput>().unwrap();
+//!     if !text_input.borrow_mut().key_down_event(keycode, keymods) {
+//!         if let Some(effect) = self.bindings.resolve_keycode(keycode) {
+//!             self.input_state.update_effect(effect, true);
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! Every space has its own `TextInput`. Changes to it are queued up as [`TextEvent`]s,
+//! and [`broadcast`] sends them on to Lua as [`TEXT_EVENT`], [`TEXT_SUBMITTED_EVENT`]
+//! and [`TEXT_CANCELLED_EVENT`], each with the name of the focused field first;
+//! [`Space::fixed_update`](crate::Space::fixed_update) calls it before the scheduler
+//! runs. From Lua, text fields are focused with `sludge.input.focus(name, text)`:
+//!
+//! ```lua
+//! sludge.input.focus("console")
+//! local _, _, field, line = yield("input.text_submitted")
+//! ```
+//!
+//! Composition is left to the platform: miniquad only reports committed characters, so
+//! there's no way to show text which an IME is still composing.
+
+use {anyhow::*, rlua::prelude::*};
+
+use crate::{
+    input::{KeyCode, KeyMods},
+    SludgeLuaContextExt,
+};
+
+/// Broadcast for every character typed into the focused field, with the field's name,
+/// the character and the field's new text.
+pub const TEXT_EVENT: &'static str = "input.text";
+
+/// Broadcast when Enter is pressed in the focused field, with the field's name and its
+/// text. The field stays focused, with its text cleared.
+pub const TEXT_SUBMITTED_EVENT: &'static str = "input.text_submitted";
+
+/// Broadcast when Escape is pressed in the focused field, with the field's name. The
+/// field loses focus.
+pub const TEXT_CANCELLED_EVENT: &'static str = "input.text_cancelled";
+
+/// A change to the focused text field, waiting to be handled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TextEvent {
+    /// A character was typed into the field, leaving it with the given text.
+    Typed {
+        focus: String,
+        character: char,
+        text: String,
+    },
+    /// The field's text was submitted with Enter.
+    Submitted { focus: String, text: String },
+    /// The field was left with Escape.
+    Cancelled { focus: String },
+}
+
+/// Where typed text goes. See the [module documentation](self) for details.
+#[derive(Debug, Clone, Default)]
+pub struct TextInput {
+    focus: Option<String>,
+    text: String,
+    /// The cursor, as a byte offset into `text` which always lies on a char boundary.
+    cursor: usize,
+    events: Vec<TextEvent>,
+}
+
+impl TextInput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give a text field focus, starting it off with `text` and the cursor at the end.
+    /// Whatever had focus before loses it, without any events.
+    pub fn focus(&mut self, name: impl Into<String>, text: impl Into<String>) {
+        self.focus = Some(name.into());
+        self.text = text.into();
+        self.cursor = self.text.len();
+    }
+
+    /// Take focus away from whichever text field has it.
+    pub fn blur(&mut self) {
+        self.focus = None;
+        self.text.clear();
+        self.cursor = 0;
+    }
+
+    /// The name of the focused text field, if there is one.
+    pub fn focused(&self) -> Option<&str> {
+        self.focus.as_deref()
+    }
+
+    pub fn is_focused(&self, name: &str) -> bool {
+        self.focus.as_deref() == Some(name)
+    }
+
+    /// The text of the focused field.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The position of the cursor in the focused field, in bytes.
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Type a character into the focused field. Control characters are ignored, since
+    /// the keys which produce them are handled by [`TextInput::key_down_event`].
+    pub fn char_event(&mut self, character: char) {
+        let focus = match &self.focus {
+            Some(focus) if !character.is_control() => focus.clone(),
+            _ => return,
+        };
+
+        self.text.insert(self.cursor, character);
+        self.cursor += character.len_utf8();
+        self.events.push(TextEvent::Typed {
+            focus,
+            character,
+            text: self.text.clone(),
+        });
+    }
+
+    /// Handle a key press, returning whether it was consumed. Every key is consumed
+    /// while a field has focus.
+    pub fn key_down_event(&mut self, keycode: KeyCode, _keymods: KeyMods) -> bool {
+        let focus = match &self.focus {
+            Some(focus) => focus.clone(),
+            None => return false,
+        };
+
+        match keycode {
+            KeyCode::Backspace => {
+                if let Some(c) = self.text[..self.cursor].chars().next_back() {
+                    self.cursor -= c.len_utf8();
+                    self.text.remove(self.cursor);
+                }
+            }
+            KeyCode::Delete => {
+                if self.cursor < self.text.len() {
+                    self.text.remove(self.cursor);
+                }
+            }
+            KeyCode::Left => {
+                if let Some(c) = self.text[..self.cursor].chars().next_back() {
+                    self.cursor -= c.len_utf8();
+                }
+            }
+            KeyCode::Right => {
+                if let Some(c) = self.text[self.cursor..].chars().next() {
+                    self.cursor += c.len_utf8();
+                }
+            }
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.text.len(),
+            KeyCode::Enter | KeyCode::KpEnter => {
+                let text = std::mem::take(&mut self.text);
+                self.cursor = 0;
+                self.events.push(TextEvent::Submitted { focus, text });
+            }
+            KeyCode::Escape => {
+                self.blur();
+                self.events.push(TextEvent::Cancelled { focus });
+            }
+            _ => {}
+        }
+
+        true
+    }
+
+    /// Take every event queued up since the last drain.
+    pub fn drain_events(&mut self) -> impl Iterator<Item = TextEvent> + '_ {
+        self.events.drain(..)
+    }
+}
+
+/// Broadcast every queued [`TextEvent`] in the space's [`TextInput`] to Lua. Does
+/// nothing if the space doesn't have a `TextInput`.
+pub fn broadcast(lua: LuaContext) -> Result<()> {
+    let events = match lua.fetch_one::<TextInput>() {
+        Ok(text_input) => text_input.borrow_mut().drain_events().collect::<Vec<_>>(),
+        Err(_) => return Ok(()),
+    };
+
+    for event in events {
+        match event {
+            TextEvent::Typed {
+                focus,
+                character,
+                text,
+            } => lua.broadcast(TEXT_EVENT, (focus, character.to_string(), text))?,
+            TextEvent::Submitted { focus, text } => {
+                lua.broadcast(TEXT_SUBMITTED_EVENT, (focus, text))?
+            }
+            TextEvent::Cancelled { focus } => lua.broadcast(TEXT_CANCELLED_EVENT, focus)?,
+        }
+    }
+
+    Ok(())
+}
+
+inventory::submit! {
+    crate::api::Module::parse("sludge.input", |lua| {
+        let table = lua.create_table()?;
+
+        table.set(
+            "focus",
+            lua.create_function(|lua, (name, text): (String, Option<String>)| {
+                lua.fetch_one::<TextInput>()?
+                    .borrow_mut()
+                    .focus(name, text.unwrap_or_default());
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "blur",
+            lua.create_function(|lua, ()| {
+                lua.fetch_one::<TextInput>()?.borrow_mut().blur();
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "focused",
+            lua.create_function(|lua, ()| {
+                Ok(lua
+                    .fetch_one::<TextInput>()?
+                    .borrow()
+                    .focused()
+                    .map(str::to_owned))
+            })?,
+        )?;
+
+        table.set(
+            "text",
+            lua.create_function(|lua, ()| {
+                Ok(lua.fetch_one::<TextInput>()?.borrow().text().to_owned())
+            })?,
+        )?;
+
+        table.set("TEXT_EVENT", TEXT_EVENT)?;
+        table.set("TEXT_SUBMITTED_EVENT", TEXT_SUBMITTED_EVENT)?;
+        table.set("TEXT_CANCELLED_EVENT", TEXT_CANCELLED_EVENT)?;
+
+        Ok(LuaValue::Table(table))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn editing_keys_move_by_chars() {
+        let mut input = TextInput::new();
+        input.char_event('x');
+        assert!(input.drain_events().next().is_none());
+
+        input.focus("name", "né");
+        input.key_down_event(KeyCode::Left, KeyMods::default());
+        input.char_event('o');
+        assert_eq!(input.text(), "noé");
+        input.key_down_event(KeyCode::End, KeyMods::default());
+        input.key_down_event(KeyCode::Backspace, KeyMods::default());
+        assert_eq!(input.text(), "no");
+
+        assert!(input.key_down_event(KeyCode::Enter, KeyMods::default()));
+        let events = input.drain_events().collect::<Vec<_>>();
+        assert_eq!(
+            events.last(),
+            Some(&TextEvent::Submitted {
+                focus: "name".to_owned(),
+                text: "no".to_owned(),
+            })
+        );
+        assert_eq!(input.text(), "");
+        assert!(input.is_focused("name"));
+    }
+}
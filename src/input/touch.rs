@@ -0,0 +1,268 @@
+//! Multi-touch input, for phones and tablets.
+//!
+//! miniquad reports every touch with an ID, a phase and a position. By default the
+//! [`EventHandler`](crate::event::EventHandler) passes each touch on to the [`Touches`]
+//! resource of its [`space`](crate::event::EventHandler::space), and also turns it into
+//! clicks of the left mouse button with [`emulate_mouse`](crate::event::emulate_mouse),
+//! which is enough for menus:
+//!
+//! ```ignore
+//! impl EventHandler for Game {
+//!     fn space(&self) -> Option<&Space> {
+//!         Some(&self.space)
+//!     }
+//!
+//!     // ...
+//! }
+//! ```
+//!
+//! `Touches` keeps track of every finger which is currently down, in the order they
+//! touched the screen. Every space has its own. Like the [`TextInput`](super::TextInput),
+//! changes are queued up as [`TouchEvent`]s and [`broadcast`] sends them on to Lua as
+//! [`TOUCH_STARTED_EVENT`], [`TOUCH_MOVED_EVENT`] and [`TOUCH_ENDED_EVENT`], each with
+//! the ID and position of the touch. [`Space::fixed_update`](crate::Space::fixed_update)
+//! calls it right before the scheduler runs. From Lua, the touches which are down can
+//! also be polled:
+//!
+//! ```lua
+//! for _, touch in ipairs(sludge.input.touch.list()) do
+//!     print(touch.id, touch.x, touch.y)
+//! end
+//! ```
+
+use {anyhow::*, rlua::prelude::*};
+
+use crate::{math::*, SludgeLuaContextExt};
+
+/// Broadcast when a finger touches the screen, with the touch's ID and position.
+pub const TOUCH_STARTED_EVENT: &'static str = "input.touch_started";
+
+/// Broadcast when a touch moves, with the touch's ID and new position.
+pub const TOUCH_MOVED_EVENT: &'static str = "input.touch_moved";
+
+/// Broadcast when a finger leaves the screen, with the touch's ID, its last position and
+/// whether the touch was cancelled by the platform rather than lifted.
+pub const TOUCH_ENDED_EVENT: &'static str = "input.touch_ended";
+
+/// Where a touch is in its life.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TouchPhase {
+    Started,
+    Moved,
+    Ended,
+    /// The platform took the touch away, for example because a system gesture began.
+    Cancelled,
+}
+
+#[cfg(feature = "miniquad")]
+impl From<miniquad::TouchPhase> for TouchPhase {
+    fn from(phase: miniquad::TouchPhase) -> Self {
+        use miniquad::TouchPhase as MqTp;
+
+        match phase {
+            MqTp::Started => Self::Started,
+            MqTp::Moved => Self::Moved,
+            MqTp::Ended => Self::Ended,
+            MqTp::Cancelled => Self::Cancelled,
+        }
+    }
+}
+
+/// A finger which is down on the screen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Touch {
+    pub id: u64,
+    pub position: Point2<f32>,
+    /// Where the touch started.
+    pub start: Point2<f32>,
+}
+
+impl<'lua> ToLua<'lua> for Touch {
+    fn to_lua(self, lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+        let table = lua.create_table()?;
+        table.set("id", self.id)?;
+        table.set("x", self.position.x)?;
+        table.set("y", self.position.y)?;
+        table.set("start_x", self.start.x)?;
+        table.set("start_y", self.start.y)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// A change to a touch, waiting to be handled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TouchEvent {
+    pub id: u64,
+    pub phase: TouchPhase,
+    pub position: Point2<f32>,
+}
+
+/// Every touch which is currently down. See the [module documentation](self) for
+/// details.
+#[derive(Debug, Clone, Default)]
+pub struct Touches {
+    active: Vec<Touch>,
+    events: Vec<TouchEvent>,
+}
+
+impl Touches {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle a touch event from the platform. Moves of touches which never started are
+    /// treated as starting them, since the start may have been delivered before the
+    /// `Touches` existed.
+    pub fn touch_event(&mut self, phase: TouchPhase, id: u64, position: Point2<f32>) {
+        let index = self.active.iter().position(|touch| touch.id == id);
+        let phase = match (phase, index) {
+            (TouchPhase::Started, Some(i)) | (TouchPhase::Moved, Some(i)) => {
+                self.active[i].position = position;
+                TouchPhase::Moved
+            }
+            (TouchPhase::Started, None) | (TouchPhase::Moved, None) => {
+                self.active.push(Touch {
+                    id,
+                    position,
+                    start: position,
+                });
+                TouchPhase::Started
+            }
+            (TouchPhase::Ended, Some(i)) | (TouchPhase::Cancelled, Some(i)) => {
+                self.active.remove(i);
+                phase
+            }
+            (TouchPhase::Ended, None) | (TouchPhase::Cancelled, None) => return,
+        };
+
+        self.events.push(TouchEvent {
+            id,
+            phase,
+            position,
+        });
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Touch> {
+        self.active.iter().find(|touch| touch.id == id)
+    }
+
+    /// Every touch which is down, in the order they started.
+    pub fn iter(&self) -> impl Iterator<Item = &Touch> + '_ {
+        self.active.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.active.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.active.is_empty()
+    }
+
+    /// Forget every touch without any events, for when the window loses focus and the
+    /// ends of the touches might never arrive.
+    pub fn clear(&mut self) {
+        self.active.clear();
+    }
+
+    /// Take every event queued up since the last drain.
+    pub fn drain_events(&mut self) -> impl Iterator<Item = TouchEvent> + '_ {
+        self.events.drain(..)
+    }
+}
+
+/// Broadcast every queued [`TouchEvent`] in the space's [`Touches`] to Lua. Does nothing
+/// if the space doesn't have a `Touches`.
+pub fn broadcast(lua: LuaContext) -> Result<()> {
+    let events = match lua.fetch_one::<Touches>() {
+        Ok(touches) => touches.borrow_mut().drain_events().collect::<Vec<_>>(),
+        Err(_) => return Ok(()),
+    };
+
+    for TouchEvent {
+        id,
+        phase,
+        position,
+    } in events
+    {
+        let (x, y) = (position.x, position.y);
+        match phase {
+            TouchPhase::Started => lua.broadcast(TOUCH_STARTED_EVENT, (id, x, y))?,
+            TouchPhase::Moved => lua.broadcast(TOUCH_MOVED_EVENT, (id, x, y))?,
+            TouchPhase::Ended => lua.broadcast(TOUCH_ENDED_EVENT, (id, x, y, false))?,
+            TouchPhase::Cancelled => lua.broadcast(TOUCH_ENDED_EVENT, (id, x, y, true))?,
+        }
+    }
+
+    Ok(())
+}
+
+inventory::submit! {
+    crate::api::Module::parse("sludge.input.touch", |lua| {
+        let table = lua.create_table()?;
+
+        table.set(
+            "list",
+            lua.create_function(|lua, ()| {
+                let touches = lua.fetch_one::<Touches>()?;
+                let list = touches.borrow().iter().copied().collect::<Vec<_>>();
+                Ok(list)
+            })?,
+        )?;
+
+        table.set(
+            "get",
+            lua.create_function(|lua, id: u64| {
+                Ok(lua.fetch_one::<Touches>()?.borrow().get(id).copied())
+            })?,
+        )?;
+
+        table.set(
+            "count",
+            lua.create_function(|lua, ()| Ok(lua.fetch_one::<Touches>()?.borrow().len()))?,
+        )?;
+
+        table.set("TOUCH_STARTED_EVENT", TOUCH_STARTED_EVENT)?;
+        table.set("TOUCH_MOVED_EVENT", TOUCH_MOVED_EVENT)?;
+        table.set("TOUCH_ENDED_EVENT", TOUCH_ENDED_EVENT)?;
+
+        Ok(LuaValue::Table(table))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn touches_track_fingers() {
+        let mut touches = Touches::new();
+        touches.touch_event(TouchPhase::Started, 1, Point2::new(1., 1.));
+        touches.touch_event(TouchPhase::Moved, 2, Point2::new(5., 5.));
+        touches.touch_event(TouchPhase::Moved, 1, Point2::new(2., 3.));
+        assert_eq!(touches.len(), 2);
+        assert_eq!(touches.get(1).unwrap().start, Point2::new(1., 1.));
+        assert_eq!(touches.get(1).unwrap().position, Point2::new(2., 3.));
+
+        touches.touch_event(TouchPhase::Cancelled, 1, Point2::new(2., 3.));
+        touches.touch_event(TouchPhase::Ended, 3, Point2::origin());
+        assert_eq!(
+            touches.iter().map(|touch| touch.id).collect::<Vec<_>>(),
+            vec![2]
+        );
+
+        let phases = touches
+            .drain_events()
+            .map(|event| (event.id, event.phase))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            phases,
+            vec![
+                (1, TouchPhase::Started),
+                (2, TouchPhase::Started),
+                (1, TouchPhase::Moved),
+                (1, TouchPhase::Cancelled),
+            ]
+        );
+    }
+}
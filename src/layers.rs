@@ -0,0 +1,271 @@
+//! Named sorting layers, declared once and shared between Rust and Lua.
+//!
+//! Draw order is decided by plain integers in several places: the `layer` of a
+//! `DrawKey` in the render queue, of a node in a drawable graph, and the [`Layer`]
+//! component which decides which entities are on top. Rather than repeating the same numbers in Rust
+//! and in scripts, declare each layer once in the [`SortingLayers`] resource and look
+//! it up by name wherever a layer is needed. A layer's ID is its order, so it can be used
+//! anywhere an integer layer is expected; higher layers go on top.
+//!
+//! Layers can be declared in code, or in the `layers` section of the
+//! [`Settings`](crate::settings::Settings) file, which a space loads them from when it's
+//! built:
+//!
+//! ```toml
+//! [layers]
+//! background = -10
+//! enemies = 0
+//! bullets = 10
+//! hud = 100
+//! ```
+//!
+//! Looking up a layer which was never declared is an error, which catches misspelled
+//! names as soon as something is spawned with them instead of letting it quietly land on
+//! layer zero. From Lua:
+//!
+//! ```lua
+//! sludge.layers.declare("effects", 20)
+//! local bullets = sludge.layers.id("bullets")
+//! sludge.spawn { SpriteAnimation = { path = "/bullet.json", layer = "bullets" } }
+//! ```
+
+use {
+    anyhow::*,
+    rlua::prelude::*,
+    serde::{Deserialize, Serialize},
+    std::collections::{BTreeMap, HashMap},
+};
+
+use crate::{
+    api::{LuaComponent, LuaComponentInterface},
+    ecs::*,
+    reflect::ReflectedComponent,
+    settings::Settings,
+    SludgeLuaContextExt,
+};
+
+/// The section of the settings file layers are loaded from.
+pub const SETTINGS_SECTION: &'static str = "layers";
+
+/// A registry of named sorting layers. See the [module documentation](self) for details.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SortingLayers {
+    layers: HashMap<String, i32>,
+}
+
+impl SortingLayers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the layers declared in the `layers` section of the settings.
+    pub fn from_settings(settings: &Settings) -> Self {
+        let mut layers = Self::new();
+        layers.extend(settings.section::<BTreeMap<String, i32>>(SETTINGS_SECTION));
+        layers
+    }
+
+    /// Declare a layer, or move an existing layer to a new order.
+    pub fn declare(&mut self, name: impl Into<String>, order: i32) {
+        self.layers.insert(name.into(), order);
+    }
+
+    /// Declare several layers at once.
+    pub fn extend<S: Into<String>>(&mut self, layers: impl IntoIterator<Item = (S, i32)>) {
+        for (name, order) in layers {
+            self.declare(name, order);
+        }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.layers.contains_key(name)
+    }
+
+    /// The ID of a layer, if it's been declared.
+    pub fn get(&self, name: &str) -> Option<i32> {
+        self.layers.get(name).copied()
+    }
+
+    /// The ID of a layer, or an error naming the layer if it hasn't been declared.
+    pub fn id(&self, name: &str) -> Result<i32> {
+        self.get(name)
+            .ok_or_else(|| anyhow!("unknown sorting layer `{}`", name))
+    }
+
+    /// The name of the first layer with the given ID, if there is one.
+    pub fn name(&self, id: i32) -> Option<&str> {
+        self.iter()
+            .find(|&(_, order)| order == id)
+            .map(|(name, _)| name)
+    }
+
+    /// Every layer, from the bottom to the top. Layers with the same order are sorted by
+    /// name.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, i32)> + '_ {
+        let mut layers = self
+            .layers
+            .iter()
+            .map(|(name, &order)| (name.as_str(), order))
+            .collect::<Vec<_>>();
+        layers.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(b.0)));
+        layers.into_iter()
+    }
+}
+
+/// Look up a layer given either its name or its ID, as Lua code is allowed to do when
+/// it's given a layer. Names are checked against the space's [`SortingLayers`]; IDs
+/// are taken as they are.
+pub fn from_lua_layer<'lua>(lua: LuaContext<'lua>, value: LuaValue<'lua>) -> LuaResult<i32> {
+    match value {
+        LuaValue::String(name) => {
+            let layers = lua.fetch_one::<SortingLayers>()?;
+            let id = layers.borrow().id(name.to_str()?);
+            id.to_lua_err()
+        }
+        other => i32::from_lua(other, lua),
+    }
+}
+
+/// Which sorting layer an entity is on, for anything which orders entities, like picking
+/// in `sludge-2d`. Entities without one are on layer zero. Sprites can be given a layer
+/// with the `layer` field of their `SpriteAnimation` table, too.
+///
+/// From Lua, the layer can be given either as a number or as the name of one of the
+/// [`SortingLayers`], in which case spawning the entity fails if there's no layer by
+/// that name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Layer(pub i32);
+
+impl<'a> SmartComponent<ScContext<'a>> for Layer {}
+
+inventory::submit! {
+    CloneComponent::of::<Layer>()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LayerAccessor(Entity);
+
+impl LuaUserData for LayerAccessor {
+    fn add_methods<'lua, T: LuaUserDataMethods<'lua, Self>>(methods: &mut T) {
+        methods.add_method("get", |lua, this, ()| {
+            let world = lua.fetch_one::<World>()?;
+            let layer = *world.borrow().get::<Layer>(this.0).to_lua_err()?;
+            Ok(layer.0)
+        });
+
+        methods.add_method("set", |lua, this, layer: LuaValue| {
+            let layer = from_lua_layer(lua, layer)?;
+            let world = lua.fetch_one::<World>()?;
+            *world.borrow().get_mut::<Layer>(this.0).to_lua_err()? = Layer(layer);
+            Ok(())
+        });
+
+        methods.add_method("to_table", |lua, this, ()| {
+            let world = lua.fetch_one::<World>()?;
+            let layer = *world.borrow().get::<Layer>(this.0).to_lua_err()?;
+            Ok(layer.0)
+        });
+    }
+}
+
+impl LuaComponentInterface for Layer {
+    fn accessor<'lua>(lua: LuaContext<'lua>, entity: Entity) -> LuaResult<LuaValue<'lua>> {
+        LayerAccessor(entity).to_lua(lua)
+    }
+
+    fn bundler<'lua>(
+        lua: LuaContext<'lua>,
+        args: LuaValue<'lua>,
+        builder: &mut EntityBuilder,
+    ) -> LuaResult<()> {
+        builder.add(Layer(from_lua_layer(lua, args)?));
+        Ok(())
+    }
+}
+
+inventory::submit! {
+    LuaComponent::new::<Layer>("Layer")
+}
+
+inventory::submit! {
+    ReflectedComponent::new::<Layer>("Layer")
+}
+
+inventory::submit! {
+    crate::api::Module::parse("sludge.layers", |lua| {
+        let table = lua.create_table()?;
+
+        table.set(
+            "id",
+            lua.create_function(|lua, name: LuaString| {
+                from_lua_layer(lua, LuaValue::String(name))
+            })?,
+        )?;
+
+        table.set(
+            "get",
+            lua.create_function(|lua, name: LuaString| {
+                let layers = lua.fetch_one::<SortingLayers>()?;
+                let id = layers.borrow().get(name.to_str()?);
+                Ok(id)
+            })?,
+        )?;
+
+        table.set(
+            "declare",
+            lua.create_function(|lua, (name, order): (String, i32)| {
+                lua.fetch_one::<SortingLayers>()?
+                    .borrow_mut()
+                    .declare(name, order);
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "names",
+            lua.create_function(|lua, ()| {
+                let layers = lua.fetch_one::<SortingLayers>()?;
+                let names = layers
+                    .borrow()
+                    .iter()
+                    .map(|(name, _)| name.to_owned())
+                    .collect::<Vec<_>>();
+                Ok(names)
+            })?,
+        )?;
+
+        Ok(LuaValue::Table(table))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_layers_are_errors() {
+        let mut layers = SortingLayers::new();
+        layers.extend(vec![("hud", 100), ("bullets", 10), ("background", -10)]);
+
+        assert_eq!(layers.id("bullets").unwrap(), 10);
+        assert!(layers.id("bulets").is_err());
+        assert_eq!(
+            layers.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+            vec!["background", "bullets", "hud"]
+        );
+        assert_eq!(layers.name(100), Some("hud"));
+    }
+
+    #[test]
+    fn layers_load_from_settings() -> Result<()> {
+        let mut settings = Settings::new();
+        settings.set("layers.hud", 100)?;
+        settings.set("layers.background", -10)?;
+
+        let layers = SortingLayers::from_settings(&settings);
+        assert_eq!(layers.id("hud")?, 100);
+        assert_eq!(layers.id("background")?, -10);
+        Ok(())
+    }
+}
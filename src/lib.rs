@@ -1,17 +1,10 @@
-#![feature(
-    drain_filter,
-    exact_size_is_empty,
-    option_expect_none,
-    duration_zero,
-    clamp
-)]
-#![deny(broken_intra_doc_links)]
+#![deny(rustdoc::broken_intra_doc_links)]
 
 use {
     anyhow::*,
-    crossbeam_channel::{Receiver, Sender},
+    crossbeam_channel::{Receiver, Sender, TrySendError},
     derivative::*,
-    hashbrown::HashMap,
+    hashbrown::{HashMap, HashSet},
     nalgebra as na,
     rlua::prelude::*,
     serde::{Deserialize, Serialize},
@@ -19,11 +12,15 @@ use {
     std::{
         any::Any,
         cmp::Ordering,
-        collections::BinaryHeap,
+        collections::{BTreeMap, BinaryHeap, VecDeque},
         error::Error as StdError,
         fmt,
         io::{Read, Write},
-        iter,
+        iter, mem,
+        sync::{
+            atomic::{self, AtomicU64, AtomicUsize},
+            Arc, Mutex,
+        },
     },
     string_cache::DefaultAtom,
     thunderdome::{Arena, Index},
@@ -33,29 +30,55 @@ pub type Atom = DefaultAtom;
 
 pub mod api;
 pub mod assets;
+pub mod bus;
 pub mod chunked_grid;
 pub mod components;
 pub mod conf;
+pub mod countdown;
 pub mod dependency_graph;
 pub mod dispatcher;
 pub mod ecs;
+#[cfg(all(feature = "graphics", feature = "input"))]
 pub mod event;
 pub mod filesystem;
+pub mod fsm;
+pub mod gc;
+#[cfg(feature = "graphics")]
 pub mod graphics;
 pub mod hierarchy;
+#[cfg(feature = "input")]
 pub mod input;
+pub mod layers;
+pub mod lifetime;
+pub mod log_buffer;
 pub mod math;
+pub mod memory;
 pub mod path_clean;
+pub mod pause;
 pub mod persist;
+pub mod profile;
+pub mod reflect;
 pub mod resources;
+pub mod rng;
+pub mod sandbox;
+#[cfg(feature = "graphics")]
 pub mod scene;
+pub mod script;
+pub mod settings;
+pub mod shutdown;
 pub mod sprite;
 pub mod systems;
+pub mod task;
+#[cfg(feature = "tiled")]
 pub mod tiled;
 pub mod timer;
+pub mod timestep;
 pub mod transform;
 pub mod vfs;
+pub mod worlds;
 
+/// The core of sludge: the ECS, resources, the scheduler and the Lua API. None of this
+/// depends on any optional features, so it's available however sludge is built.
 pub mod prelude {
     pub use anyhow::*;
     pub use inventory;
@@ -79,8 +102,16 @@ pub use {anyhow, inventory, nalgebra, ncollide2d, rlua, rlua_serde, serde, sludg
 pub mod sludge {
     #[doc(hidden)]
     pub use {
-        crate::ecs::{Entity, FlaggedComponent, ScContext, SmartComponent},
-        inventory,
+        crate::{
+            api::{LuaBundle, LuaBundleInterface, LuaComponentInterface},
+            ecs::{
+                CloneComponent, CloneProbe, Entity, EntityBuilder, FlaggedComponent, ProbeClone,
+                ProbeNoClone, ScContext, SmartComponent, World,
+            },
+            memory::ComponentLayout,
+            SludgeLuaContextExt,
+        },
+        anyhow, inventory, rlua, rlua_serde,
         std::any::TypeId,
     };
 }
@@ -88,7 +119,13 @@ pub mod sludge {
 #[doc(hidden)]
 pub use crate::sludge::*;
 
-use crate::{api::EntityUserDataRegistry, dispatcher::Dispatcher, ecs::World, resources::*};
+use crate::{
+    api::EntityUserDataRegistry,
+    dispatcher::{Dispatcher, Stage},
+    ecs::World,
+    resources::*,
+    timer::TimeContext,
+};
 
 pub trait SludgeResultExt: Sized {
     type Ok;
@@ -149,6 +186,15 @@ pub trait SludgeLuaContextExt<'lua>: Sized {
     where
         T: ToLuaMulti<'lua>;
 
+    fn broadcast_in<S, T>(self, ticks: u64, event_name: S, args: T) -> LuaResult<()>
+    where
+        S: AsRef<str>,
+        T: ToLuaMulti<'lua>;
+
+    fn notify_in<T>(self, ticks: u64, thread: LuaThread<'lua>, args: T) -> LuaResult<()>
+    where
+        T: ToLuaMulti<'lua>;
+
     fn kill<T>(self, thread: LuaThread<'lua>, args: T) -> LuaResult<()>
     where
         T: ToLuaMulti<'lua>;
@@ -187,6 +233,28 @@ impl<'lua> SludgeLuaContextExt<'lua> for LuaContext<'lua> {
             .notify(self, thread, args)
     }
 
+    fn broadcast_in<S: AsRef<str>, T: ToLuaMulti<'lua>>(
+        self,
+        ticks: u64,
+        event_name: S,
+        args: T,
+    ) -> LuaResult<()> {
+        self.fetch_one::<SchedulerQueue>()?
+            .borrow()
+            .broadcast_in(self, ticks, event_name, args)
+    }
+
+    fn notify_in<T: ToLuaMulti<'lua>>(
+        self,
+        ticks: u64,
+        thread: LuaThread<'lua>,
+        args: T,
+    ) -> LuaResult<()> {
+        self.fetch_one::<SchedulerQueue>()?
+            .borrow()
+            .notify_in(self, ticks, thread, args)
+    }
+
     fn kill<T: ToLuaMulti<'lua>>(self, thread: LuaThread<'lua>, args: T) -> LuaResult<()> {
         self.fetch_one::<SchedulerQueue>()?
             .borrow()
@@ -225,6 +293,59 @@ pub trait System {
     fn update(&self, lua: LuaContext, resources: &UnifiedResources) -> Result<()>;
 }
 
+impl<S: System + ?Sized> System for Box<S> {
+    fn init(
+        &self,
+        lua: LuaContext,
+        local: &mut OwnedResources,
+        global: Option<&SharedResources>,
+    ) -> Result<()> {
+        (**self).init(lua, local, global)
+    }
+
+    fn update(&self, lua: LuaContext, resources: &UnifiedResources) -> Result<()> {
+        (**self).update(lua, resources)
+    }
+}
+
+/// A maintenance system which a crate built on sludge registers with
+/// `inventory::submit!`, so that every space gets it along with the built-in default
+/// systems. These are registered after the built-in ones, and are left out with
+/// [`DefaultSystems::plugins`] or one at a time with
+/// [`SpaceBuilder::without_default_system`].
+pub struct DefaultSystem {
+    name: &'static str,
+    deps: &'static [&'static str],
+    stage: Stage,
+    make: fn() -> Box<dyn System>,
+}
+
+impl DefaultSystem {
+    pub fn new(
+        name: &'static str,
+        deps: &'static [&'static str],
+        stage: Stage,
+        make: fn() -> Box<dyn System>,
+    ) -> Self {
+        Self {
+            name,
+            deps,
+            stage,
+            make,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+inventory::collect!(DefaultSystem);
+
+/// How many fixed ticks [`Space::update`] runs per second, unless the space was built
+/// with a different [`FixedTimestep`](timestep::FixedTimestep).
+pub const FIXED_FPS: u32 = 60;
+
 #[derive(Derivative)]
 #[derivative(Debug)]
 pub struct Space {
@@ -238,58 +359,385 @@ pub struct Space {
     maintainers: Dispatcher<'static>,
 }
 
-impl Space {
-    pub fn new() -> Result<Self> {
-        Self::with_global_resources(SharedResources::new())
+/// Which of the default maintenance systems a [`SpaceBuilder`] registers. All of them
+/// are enabled by default.
+///
+/// Note that the `Hierarchy` system depends on `WorldEvent`, and both transform systems
+/// depend on `Hierarchy` and `WorldEvent`; disabling a system which another enabled
+/// system depends on will cause building the space to fail.
+///
+/// `WorldEvent` runs in the [fixed stage](Stage::Fixed) only, so component events are
+/// dispatched to Lua once per tick; the hierarchy and transform systems run in both
+/// stages, so that anything moved during a frame is propagated before it's drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DefaultSystems {
+    pub world_event: bool,
+    pub space_bus: bool,
+    pub hierarchy: bool,
+    pub transform: bool,
+    pub transform2d: bool,
+    pub lifetime: bool,
+    /// Every [`DefaultSystem`] registered by other crates.
+    pub plugins: bool,
+}
+
+impl Default for DefaultSystems {
+    fn default() -> Self {
+        Self {
+            world_event: true,
+            space_bus: true,
+            hierarchy: true,
+            transform: true,
+            transform2d: true,
+            lifetime: true,
+            plugins: true,
+        }
     }
+}
 
-    pub fn with_global_resources(global: SharedResources<'static>) -> Result<Self> {
+impl DefaultSystems {
+    /// Disable all of the default systems.
+    pub fn none() -> Self {
+        Self {
+            world_event: false,
+            space_bus: false,
+            hierarchy: false,
+            transform: false,
+            transform2d: false,
+            lifetime: false,
+            plugins: false,
+        }
+    }
+}
+
+/// A builder for configuring a [`Space`] before its Lua state is initialized.
+pub struct SpaceBuilder {
+    global: SharedResources<'static>,
+    std_lib: rlua::StdLib,
+    sandbox: Option<sandbox::Sandbox>,
+    channel_bound: usize,
+    overflow: OverflowPolicy,
+    timestep: timestep::FixedTimestep,
+    default_systems: DefaultSystems,
+    excluded_systems: Vec<String>,
+    modules: api::ModuleOptions,
+    preload: Vec<String>,
+    initial_resources: Vec<Box<dyn FnOnce(&mut OwnedResources<'static>)>>,
+}
+
+impl Default for SpaceBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpaceBuilder {
+    /// The Lua standard libraries loaded into a space by default.
+    pub fn default_std_lib() -> rlua::StdLib {
         use rlua::StdLib;
-        let lua = Lua::new_with(
-            StdLib::BASE
-                | StdLib::COROUTINE
-                | StdLib::TABLE
-                | StdLib::STRING
-                | StdLib::UTF8
-                | StdLib::MATH
-                | StdLib::ERIS,
-        );
+        StdLib::BASE
+            | StdLib::COROUTINE
+            | StdLib::TABLE
+            | StdLib::STRING
+            | StdLib::UTF8
+            | StdLib::MATH
+            | StdLib::ERIS
+    }
+
+    pub fn new() -> Self {
+        Self {
+            global: SharedResources::new(),
+            std_lib: Self::default_std_lib(),
+            sandbox: None,
+            channel_bound: Scheduler::CHANNEL_BOUND,
+            overflow: OverflowPolicy::default(),
+            timestep: timestep::FixedTimestep::default(),
+            default_systems: DefaultSystems::default(),
+            excluded_systems: Vec::new(),
+            modules: api::ModuleOptions::default(),
+            preload: Vec::new(),
+            initial_resources: Vec::new(),
+        }
+    }
+
+    /// Use the given resources as the space's global resources.
+    pub fn with_global_resources(mut self, global: SharedResources<'static>) -> Self {
+        self.global = global;
+        self
+    }
+
+    /// Set which Lua standard libraries are loaded. `StdLib::BASE`, `StdLib::COROUTINE`
+    /// and `StdLib::STRING` are used by the sludge API and prelude, and building a space
+    /// without them is an error. `StdLib::ERIS` is required for saving and loading the
+    /// space.
+    pub fn with_std_lib(mut self, std_lib: rlua::StdLib) -> Self {
+        self.std_lib = std_lib;
+        self
+    }
+
+    /// Run the space's fixed ticks `fps` times per second instead of [`FIXED_FPS`]. The
+    /// [`FixedTimestep`](timestep::FixedTimestep) is inserted into the space's local
+    /// resources for systems to step by. Panics if `fps` is zero.
+    pub fn with_fixed_fps(mut self, fps: u32) -> Self {
+        self.timestep = timestep::FixedTimestep::new(fps);
+        self
+    }
+
+    /// Restrict the space's Lua state with a [`Sandbox`](sandbox::Sandbox). The sandbox
+    /// is also inserted into the space's local resources.
+    pub fn with_sandbox(mut self, sandbox: sandbox::Sandbox) -> Self {
+        self.sandbox = Some(sandbox);
+        self
+    }
+
+    /// Set the bound of the scheduler's spawn and event channels.
+    pub fn with_channel_bound(mut self, bound: usize) -> Self {
+        self.channel_bound = bound;
+        self
+    }
+
+    /// Set what the scheduler's queue does when one of its channels is full.
+    pub fn with_overflow_policy(mut self, overflow: OverflowPolicy) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Set which of the default maintenance systems are registered.
+    pub fn with_default_systems(mut self, default_systems: DefaultSystems) -> Self {
+        self.default_systems = default_systems;
+        self
+    }
+
+    /// Leave out the [`DefaultSystem`] registered under `name` by another crate.
+    pub fn without_default_system(mut self, name: impl Into<String>) -> Self {
+        self.excluded_systems.push(name.into());
+        self
+    }
+
+    /// Leave out the built-in Lua module at `path`, along with every module under it. A
+    /// space which only draws UI might leave out `"fmod"` and `"danmaku"`, for example.
+    /// The `"sludge"` and `"sludge.thread"` modules are used by the prelude, and building
+    /// a space without them fails.
+    pub fn without_module(mut self, path: impl Into<String>) -> Self {
+        self.modules.excluded.push(path.into());
+        self
+    }
+
+    /// Don't install the built-in Lua module at `path`, or any module under it, until
+    /// it's `require`d. Like with [`without_module`](Self::without_module), the
+    /// `"sludge"` and `"sludge.thread"` modules can't be made lazy.
+    pub fn with_lazy_module(mut self, path: impl Into<String>) -> Self {
+        self.modules.lazy.push(path.into());
+        self
+    }
+
+    /// `require` a Lua module once the space is initialized. Modules are loaded in the
+    /// order they're added, after the default systems have run for the first time.
+    pub fn with_preloaded_module(mut self, module: impl Into<String>) -> Self {
+        self.preload.push(module.into());
+        self
+    }
+
+    /// Insert a resource into the space's local resources before the sludge Lua API is
+    /// loaded and before any systems are initialized.
+    pub fn with_resource<T: Fetchable + 'static>(mut self, resource: T) -> Self {
+        self.initial_resources
+            .push(Box::new(move |local| local.insert(resource)));
+        self
+    }
+
+    pub fn build(self) -> Result<Space> {
+        let Self {
+            global,
+            std_lib,
+            sandbox,
+            channel_bound,
+            overflow,
+            timestep,
+            default_systems,
+            excluded_systems,
+            modules,
+            preload,
+            initial_resources,
+        } = self;
+
+        {
+            use rlua::StdLib;
+            for &(lib, name) in &[
+                (StdLib::BASE, "BASE"),
+                (StdLib::COROUTINE, "COROUTINE"),
+                (StdLib::STRING, "STRING"),
+            ] {
+                ensure!(
+                    std_lib & lib == lib,
+                    "the sludge Lua API requires `StdLib::{}`, which was left out of the \
+                     space's standard libraries",
+                    name
+                );
+            }
+        }
+        modules.validate()?;
+
+        let lua = Lua::new_with(std_lib);
         let mut local = OwnedResources::new();
 
-        local.insert(World::new());
-        let scheduler = lua.context(Scheduler::new)?;
+        for insert in initial_resources {
+            insert(&mut local);
+        }
+
+        if !local.has_value::<World>() {
+            local.insert(World::new());
+        }
+        if !local.has_value::<rng::RngResource>() {
+            local.insert(rng::RngResource::from_entropy());
+        }
+        if !local.has_value::<pause::PauseState>() {
+            local.insert(pause::PauseState::new());
+        }
+        if !local.has_value::<countdown::Timers>() {
+            local.insert(countdown::Timers::new());
+        }
+        if !local.has_value::<task::ChunkedTasks>() {
+            local.insert(task::ChunkedTasks::new());
+        }
+        if !local.has_value::<task::Completions>() {
+            local.insert(task::Completions::new());
+        }
+        if !local.has_value::<worlds::Worlds>() {
+            local.insert(worlds::Worlds::new());
+        }
+        // The player's settings are loaded as soon as possible, so that everything set up
+        // after this can be configured by them.
+        if !local.has_value::<settings::Settings>()
+            && !global.borrow().has_value::<settings::Settings>()
+        {
+            let path = match global.borrow().fetch_one::<filesystem::Filesystem>() {
+                Ok(fs) => Some(fs.borrow().user_path(settings::SETTINGS_PATH)?),
+                Err(_) => None,
+            };
+            if let Some(path) = path {
+                let loaded = settings::Settings::load(&path).unwrap_or_else(|err| {
+                    log::error!("error loading settings, using defaults: {:?}", err);
+                    settings::Settings::new()
+                });
+                global.borrow_mut().insert(loaded);
+            }
+        }
+        if !local.has_value::<layers::SortingLayers>()
+            && !global.borrow().has_value::<layers::SortingLayers>()
+        {
+            let sorting_layers = match global.borrow().fetch_one::<settings::Settings>() {
+                Ok(settings) => layers::SortingLayers::from_settings(&settings.borrow()),
+                Err(_) => layers::SortingLayers::new(),
+            };
+            local.insert(sorting_layers);
+        }
+        // Recording follows the graphics context, so it's only set up alongside it.
+        #[cfg(feature = "graphics")]
+        if global.borrow().has_value::<graphics::Graphics>()
+            && !local.has_value::<graphics::FrameRecorder>()
+            && !global.borrow().has_value::<graphics::FrameRecorder>()
+        {
+            global
+                .borrow_mut()
+                .insert(graphics::FrameRecorder::default());
+        }
+        #[cfg(feature = "input")]
+        if !local.has_value::<input::TextInput>() {
+            local.insert(input::TextInput::new());
+        }
+        #[cfg(feature = "input")]
+        if !local.has_value::<input::Touches>() {
+            local.insert(input::Touches::new());
+        }
+        let scheduler =
+            lua.context(|lua| Scheduler::with_channels(lua, channel_bound, overflow))?;
         let queue_handle = scheduler.queue().clone();
         local.insert(scheduler);
         local.insert(queue_handle);
         local.insert(EntityUserDataRegistry::new());
+        local.insert(persist::PersistPolicies::new()?);
+        local.insert(timestep);
+        if let Some(sandbox) = &sandbox {
+            local.insert(sandbox.clone());
+        }
 
         let local = SharedResources::from(local);
         let resources = UnifiedResources { local, global };
 
         lua.context(|lua_ctx| -> Result<_> {
             lua_ctx.set_named_registry_value(RESOURCES_REGISTRY_KEY, resources.clone())?;
-            crate::api::load(lua_ctx)?;
+            crate::api::load(lua_ctx, &modules)?;
 
             Ok(())
         })?;
 
-        let mut this = Self {
+        if let Some(sandbox) = &sandbox {
+            sandbox.apply(&lua)?;
+        }
+
+        let mut this = Space {
             lua,
             resources,
             maintainers: Dispatcher::new(),
         };
 
-        this.register(crate::systems::WorldEventSystem, "WorldEvent", &[])?;
-        this.register(
-            crate::systems::DefaultHierarchySystem::new(),
-            "Hierarchy",
-            &["WorldEvent"],
-        )?;
-        this.register(
-            crate::systems::DefaultTransformSystem::new(),
-            "Transform",
-            &["WorldEvent", "Hierarchy"],
-        )?;
+        if default_systems.world_event {
+            this.register_in(
+                crate::systems::WorldEventSystem,
+                "WorldEvent",
+                &[],
+                Stage::Fixed,
+            )?;
+        }
+
+        if default_systems.space_bus {
+            this.register(crate::bus::SpaceBusSystem, "SpaceBus", &[])?;
+        }
+
+        if default_systems.hierarchy {
+            this.register_in(
+                crate::systems::DefaultHierarchySystem::new(),
+                "Hierarchy",
+                &["WorldEvent"],
+                Stage::Both,
+            )?;
+        }
+
+        if default_systems.transform {
+            this.register_in(
+                crate::systems::DefaultTransformSystem::new(),
+                "Transform",
+                &["WorldEvent", "Hierarchy"],
+                Stage::Both,
+            )?;
+        }
+
+        if default_systems.transform2d {
+            this.register_in(
+                crate::systems::DefaultTransform2dSystem::new(),
+                "Transform2d",
+                &["WorldEvent", "Hierarchy"],
+                Stage::Both,
+            )?;
+        }
+
+        if default_systems.lifetime {
+            this.register(crate::lifetime::LifetimeSystem, "Lifetime", &[])?;
+        }
+
+        if default_systems.plugins {
+            for system in inventory::iter::<DefaultSystem> {
+                if excluded_systems.iter().any(|name| name == system.name) {
+                    continue;
+                }
+
+                this.register_in((system.make)(), system.name, system.deps, system.stage)
+                    .with_context(|| {
+                        anyhow!("error registering default system `{}`", system.name)
+                    })?;
+            }
+        }
 
         let resources = &this.resources;
         let maintainers = &mut this.maintainers;
@@ -302,9 +750,47 @@ impl Space {
         })?;
         this.maintain()?;
 
+        this.lua.context(|lua| -> Result<_> {
+            for module in &preload {
+                crate::api::require(lua, module.clone())
+                    .with_context(|| anyhow!("error preloading Lua module `{}`", module))?;
+            }
+
+            Ok(())
+        })?;
+
         Ok(this)
     }
+}
+
+impl Space {
+    pub fn new() -> Result<Self> {
+        Self::with_global_resources(SharedResources::new())
+    }
+
+    pub fn with_global_resources(global: SharedResources<'static>) -> Result<Self> {
+        Self::with_sandbox(global, None)
+    }
+
+    /// Create a space with its Lua state restricted by a [`Sandbox`](sandbox::Sandbox).
+    /// If a sandbox is provided, it's also inserted into the space's local resources.
+    pub fn with_sandbox(
+        global: SharedResources<'static>,
+        sandbox: Option<sandbox::Sandbox>,
+    ) -> Result<Self> {
+        let builder = SpaceBuilder::new().with_global_resources(global);
+        match sandbox {
+            Some(sandbox) => builder.with_sandbox(sandbox).build(),
+            None => builder.build(),
+        }
+    }
+
+    /// Start building a space with non-default configuration.
+    pub fn builder() -> SpaceBuilder {
+        SpaceBuilder::new()
+    }
 
+    /// Register a maintenance system in the [fixed stage](Stage::Fixed).
     pub fn register<S>(&mut self, system: S, name: &str, deps: &[&str]) -> Result<()>
     where
         S: System + 'static,
@@ -312,14 +798,127 @@ impl Space {
         self.maintainers.register(system, name, deps)
     }
 
+    /// Register a maintenance system which runs in the given stage.
+    pub fn register_in<S>(
+        &mut self,
+        system: S,
+        name: &str,
+        deps: &[&str],
+        stage: Stage,
+    ) -> Result<()>
+    where
+        S: System + 'static,
+    {
+        self.maintainers.register_in(system, name, deps, stage)
+    }
+
+    /// Run every maintenance system once, whatever stage it's in.
     pub fn maintain(&mut self) -> Result<()> {
+        self.maintain_stage(Stage::Both)
+    }
+
+    /// Run the maintenance systems in `stage`, initializing any which have been
+    /// registered since the last time the space was maintained.
+    pub fn maintain_stage(&mut self, stage: Stage) -> Result<()> {
         let Self {
             lua,
             maintainers,
             resources,
         } = self;
 
-        lua.context(|lua| maintainers.update(lua, resources))
+        lua.context(|lua| {
+            maintainers.refresh(
+                lua,
+                &mut resources.local.borrow_mut(),
+                Some(&resources.global),
+            )?;
+            maintainers.update_stage(lua, resources, stage)
+        })
+    }
+
+    /// Run a single fixed tick: broadcast queued input events, update the scheduler by
+    /// one tick, step the space's [chunked tasks](task) and finish its pooled jobs,
+    /// broadcast and save changed [settings](settings), then run the maintenance
+    /// systems in the [fixed stage](Stage::Fixed).
+    pub fn fixed_update(&mut self) -> Result<()> {
+        let scheduler = self.scheduler()?;
+        self.lua.context(|lua| -> Result<()> {
+            #[cfg(feature = "input")]
+            {
+                input::broadcast_touches(lua)?;
+                input::broadcast_text(lua)?;
+            }
+            scheduler.borrow_mut().update(lua, 1.0)?;
+            task::update(lua)?;
+            settings::update(lua)
+        })?;
+        self.maintain_stage(Stage::Fixed)
+    }
+
+    /// Feed the [frame recorder](graphics::recorder), if there is one, then run the
+    /// maintenance systems in the [frame stage](Stage::Frame).
+    pub fn frame_update(&mut self) -> Result<()> {
+        #[cfg(feature = "graphics")]
+        self.lua.context(graphics::recorder::update)?;
+        self.maintain_stage(Stage::Frame)
+    }
+
+    /// Drive the space through a rendered frame: run as many
+    /// [fixed ticks](Space::fixed_update) as `timer` says are due at the space's
+    /// [`FixedTimestep`](timestep::FixedTimestep), then the
+    /// [frame stage](Space::frame_update) once. `timer` should be
+    /// [ticked](TimeContext::tick) once per frame before this is called.
+    pub fn update(&mut self, timer: &mut TimeContext) -> Result<()> {
+        let fps = timestep::fixed_timestep(&self.resources).fps();
+        while timer.check_update_time(fps) {
+            self.fixed_update()?;
+        }
+
+        self.frame_update()
+    }
+
+    /// Create a named secondary world, initializing the maintenance systems into its
+    /// scope. See the [`worlds`] module for details.
+    pub fn create_world(&mut self, name: &str) -> Result<()> {
+        let mut scoped = OwnedResources::new();
+        scoped.insert(World::new());
+
+        let Self {
+            lua,
+            maintainers,
+            resources,
+        } = self;
+
+        lua.context(|lua| {
+            maintainers.refresh(
+                lua,
+                &mut resources.local.borrow_mut(),
+                Some(&resources.global),
+            )?;
+            maintainers.init_into(lua, &mut scoped, Some(&resources.global))
+        })?;
+
+        self.fetch_one::<worlds::Worlds>()?
+            .borrow_mut()
+            .insert(name, scoped)
+    }
+
+    /// Remove a secondary world, returning whether there was one by that name.
+    pub fn remove_world(&mut self, name: &str) -> Result<bool> {
+        self.fetch_one::<worlds::Worlds>()?
+            .borrow_mut()
+            .remove(name)
+    }
+
+    /// Call `f` with the named secondary world standing in for the default one. See the
+    /// [`worlds`] module for details.
+    pub fn with_world<T>(
+        &mut self,
+        name: &str,
+        f: impl FnOnce(&mut Space) -> Result<T>,
+    ) -> Result<T> {
+        let resources = self.resources.clone();
+        worlds::with_world(&resources, name, || f(self))?
     }
 
     pub fn fetch<T: FetchAll<'static>>(&self) -> Result<T::Fetched, NotFound> {
@@ -350,6 +949,12 @@ impl Space {
             .context(|lua| dispatcher.update(lua, &self.resources))
     }
 
+    /// Run the systems in `stage` of a dispatcher.
+    pub fn dispatch_stage(&self, dispatcher: &mut Dispatcher, stage: Stage) -> Result<()> {
+        self.lua
+            .context(|lua| dispatcher.update_stage(lua, &self.resources, stage))
+    }
+
     #[inline]
     pub fn world(&self) -> Result<Shared<'static, World>, NotFound> {
         self.fetch_one()
@@ -360,13 +965,115 @@ impl Space {
         self.fetch_one()
     }
 
+    /// Change how the space's scheduler drives the Lua garbage collector. See the [`gc`]
+    /// module.
+    pub fn set_gc_config(&self, config: gc::GcConfig) -> Result<()> {
+        self.scheduler()?.borrow_mut().set_gc_config(config);
+        Ok(())
+    }
+
+    pub fn gc_stats(&self) -> Result<gc::GcStats> {
+        Ok(self.scheduler()?.borrow().stats().gc)
+    }
+
+    /// Run a full garbage collection cycle right away. This can take a while with a big
+    /// heap, so it's best saved for loading screens and the like.
+    pub fn gc_full(&self) -> Result<()> {
+        let queue = self.scheduler()?.borrow().queue().clone();
+        self.lua.context(|lua| gc::full(lua, queue.gc()))
+    }
+
     pub fn save<W: Write>(&self, writer: W) -> Result<()> {
-        self.lua.context(|lua| persist::persist(lua, self, writer))
+        self.save_as(writer, persist::SaveFormat::Eris)
+    }
+
+    /// Save the space in the given format. Only [`persist::SaveFormat::Eris`] saves can
+    /// be loaded back in with [`Space::load`]; the other formats write a human-readable
+    /// [`persist::Snapshot`] for debugging.
+    ///
+    /// Eris saves are wrapped in a [container](persist::container) which guards against
+    /// corruption, and compressed if the `compression` feature is enabled.
+    pub fn save_as<W: Write>(&self, writer: W, format: persist::SaveFormat) -> Result<()> {
+        self.lua.context(|lua| match format {
+            persist::SaveFormat::Eris => {
+                persist::persist_container(lua, self, writer, persist::Compression::default())
+            }
+            _ => persist::persist_snapshot(lua, self, writer, format),
+        })
+    }
+
+    /// Save the space as an Eris save, with a choice of how it's compressed.
+    pub fn save_compressed<W: Write>(
+        &self,
+        writer: W,
+        compression: persist::Compression,
+    ) -> Result<()> {
+        self.lua
+            .context(|lua| persist::persist_container(lua, self, writer, compression))
+    }
+
+    /// Start saving the space to a file on disk, returning once its state has been
+    /// captured and leaving the file IO to the background. Eris saves are still
+    /// serialized before this returns. Returns a registry key for the save's
+    /// [promise](task::Promise). Requires a [`task::TaskPool`]; see
+    /// [`persist::save_async`] for details.
+    pub fn save_async(
+        &self,
+        path: impl Into<std::path::PathBuf>,
+        format: persist::SaveFormat,
+    ) -> Result<LuaRegistryKey> {
+        self.lua.context(|lua| {
+            let promise = persist::save_async(lua, self, path, format)?;
+            Ok(lua.create_registry_value(promise)?)
+        })
+    }
+
+    /// Take a human-readable [`persist::Snapshot`] of the space's persistent state.
+    pub fn snapshot(&self) -> Result<persist::Snapshot> {
+        self.lua.context(|lua| persist::snapshot(lua, self))
     }
 
+    /// Load a save written by [`Space::save`]. The save is checked before anything is
+    /// loaded, and a save which isn't a [container](persist::container), is corrupt, or
+    /// is from another version of the save format fails with a [`persist::SaveError`]
+    /// saying which. Saves from before there was a container fail with
+    /// [`SaveError::NotASave`](persist::SaveError::NotASave), and can be loaded with
+    /// [`Space::load_legacy`].
     pub fn load<R: Read>(&self, reader: R) -> Result<()> {
         self.lua
-            .context(|lua| persist::unpersist(lua, self, reader))
+            .context(|lua| persist::unpersist_container(lua, self, reader))
+    }
+
+    /// Load a save from before the [container](persist::container) format, which is a
+    /// raw Eris dump. Nothing in a raw dump can be checked before it's handed to Lua, so
+    /// only use this on files known to be old saves, such as when [`Space::load`] has
+    /// already refused one as [`NotASave`](persist::SaveError::NotASave).
+    pub fn load_legacy<R: Read>(&self, reader: R) -> Result<()> {
+        self.lua
+            .context(|lua| persist::unpersist_legacy(lua, self, reader))
+    }
+
+    /// Tear the space down in a fixed order rather than leaving it to drop order: every
+    /// registered [finalizer](shutdown::Finalizer) is run, stage by stage, and then the
+    /// maintenance systems, the Lua state and finally the space's own resources are
+    /// dropped, in that order. See the [`shutdown`] module for details.
+    ///
+    /// The space is torn down even if a finalizer fails, in which case the first error
+    /// is returned afterwards.
+    pub fn shutdown(self) -> Result<()> {
+        let Self {
+            lua,
+            resources,
+            maintainers,
+        } = self;
+
+        let result = lua.context(|lua| shutdown::finalize(lua, &resources));
+        drop(maintainers);
+        // The Lua registry holds onto the resources too, so nothing in them is actually
+        // dropped until the Lua state is.
+        drop(lua);
+        drop(resources);
+        result
     }
 }
 
@@ -499,6 +1206,22 @@ impl Ord for Wakeup {
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct EventName(Atom);
 
+impl EventName {
+    pub fn new(name: &str) -> Self {
+        Self(Atom::from(name))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for EventName {
+    fn from(name: &str) -> Self {
+        Self::new(name)
+    }
+}
+
 pub type EventArgs = SmallVec<[LuaRegistryKey; 3]>;
 
 /// The type of an event to be sent into a scheduler's queue.
@@ -524,28 +1247,219 @@ pub enum Event {
         thread: LuaRegistryKey,
         args: Option<EventArgs>,
     },
+    /// Another event, which the scheduler holds on to until `ticks` ticks after it
+    /// receives it. See [`SchedulerQueue::broadcast_in`].
+    Delayed { ticks: u64, event: Box<Event> },
+}
+
+/// What a [`SchedulerQueue`] does when one of its channels is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait for the scheduler to make room. Channels are only drained when the scheduler
+    /// is updated, so this is only safe for queues which are pushed to from threads other
+    /// than the one updating the scheduler; otherwise, a full channel blocks forever.
+    Block,
+    /// Throw away the oldest pending message to make room for the new one.
+    DropOldest,
+    /// Refuse the new message with a [`QueueFull`] error, which Lua sees as an error
+    /// raised by whatever tried to push it. This is the default.
+    Error,
+}
+
+impl Default for OverflowPolicy {
+    fn default() -> Self {
+        OverflowPolicy::Error
+    }
+}
+
+/// The error returned when a message is pushed into a full scheduler channel under
+/// [`OverflowPolicy::Error`].
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("the scheduler's {channel} channel is full ({capacity} messages pending)")]
+pub struct QueueFull {
+    pub channel: &'static str,
+    pub capacity: usize,
+}
+
+/// Counters for one of a scheduler's channels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChannelStats {
+    pub capacity: usize,
+    /// How many messages are waiting for the scheduler's next update.
+    pub depth: usize,
+    /// The most messages which have ever been waiting at once.
+    pub high_water: usize,
+    /// How many messages were thrown away under [`OverflowPolicy::DropOldest`].
+    pub dropped: u64,
+    /// How many messages were refused under [`OverflowPolicy::Error`].
+    pub rejected: u64,
+}
+
+impl<'lua> ToLua<'lua> for ChannelStats {
+    fn to_lua(self, lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+        let table = lua.create_table()?;
+        table.set("capacity", self.capacity)?;
+        table.set("depth", self.depth)?;
+        table.set("high_water", self.high_water)?;
+        table.set("dropped", self.dropped)?;
+        table.set("rejected", self.rejected)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// Counters for both of a scheduler's channels, the threads which have taken the most
+/// time while [profiling](profile) was turned on, and what the [garbage collector](gc)
+/// has been doing. Converted to Lua as a table with the fields `events`, `spawns`,
+/// `scripts` and `gc`, `scripts` being a sequence.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SchedulerStats {
+    pub events: ChannelStats,
+    pub spawns: ChannelStats,
+    /// At most [`profile::TOP_THREADS`] thread profiles, worst first.
+    pub scripts: Vec<profile::ThreadProfile>,
+    pub gc: gc::GcStats,
+}
+
+impl<'lua> ToLua<'lua> for SchedulerStats {
+    fn to_lua(self, lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+        let table = lua.create_table()?;
+        table.set("events", self.events)?;
+        table.set("spawns", self.spawns)?;
+        table.set("scripts", lua.create_sequence_from(self.scripts)?)?;
+        table.set("gc", self.gc)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// One of a scheduler's bounded channels, along with its counters. The channel keeps a
+/// receiver of its own, to measure its depth and to make room under
+/// [`OverflowPolicy::DropOldest`].
+#[derive(Debug)]
+struct Channel<T> {
+    name: &'static str,
+    sender: Sender<T>,
+    receiver: Receiver<T>,
+    high_water: AtomicUsize,
+    dropped: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl<T> Channel<T> {
+    fn new(name: &'static str, bound: usize) -> Self {
+        // A zero-capacity channel never has room, so it couldn't hold anything at all.
+        let (sender, receiver) = crossbeam_channel::bounded(bound.max(1));
+        Self {
+            name,
+            sender,
+            receiver,
+            high_water: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    fn push(&self, mut message: T, overflow: OverflowPolicy) -> Result<(), QueueFull> {
+        loop {
+            match self.sender.try_send(message) {
+                Ok(()) => break,
+                Err(TrySendError::Full(returned)) => match overflow {
+                    OverflowPolicy::Block => {
+                        self.sender
+                            .send(returned)
+                            .expect("the channel holds its own receiver");
+                        break;
+                    }
+                    OverflowPolicy::DropOldest => {
+                        // The scheduler may have drained the channel in the meantime, in
+                        // which case there's nothing to drop and the send is just retried.
+                        if self.receiver.try_recv().is_ok() {
+                            self.dropped.fetch_add(1, atomic::Ordering::Relaxed);
+                        }
+                        message = returned;
+                    }
+                    OverflowPolicy::Error => {
+                        self.rejected.fetch_add(1, atomic::Ordering::Relaxed);
+                        return Err(QueueFull {
+                            channel: self.name,
+                            capacity: self.capacity(),
+                        });
+                    }
+                },
+                Err(TrySendError::Disconnected(_)) => {
+                    unreachable!("the channel holds its own receiver")
+                }
+            }
+        }
+
+        self.high_water
+            .fetch_max(self.receiver.len(), atomic::Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.sender.capacity().unwrap_or(usize::MAX)
+    }
+
+    fn stats(&self) -> ChannelStats {
+        ChannelStats {
+            capacity: self.capacity(),
+            depth: self.receiver.len(),
+            high_water: self.high_water.load(atomic::Ordering::Relaxed),
+            dropped: self.dropped.load(atomic::Ordering::Relaxed),
+            rejected: self.rejected.load(atomic::Ordering::Relaxed),
+        }
+    }
 }
 
 /// The `SchedulerQueue` is one half of a concurrent MPSC queue corresponding to
 /// a specific `Scheduler`. It can be cheaply cloned and send to other threads
 /// or into the Lua state for use inside userdata.
+///
+/// The queue's channels are bounded; what happens when one of them fills up is decided
+/// by its [`OverflowPolicy`].
 #[derive(Debug, Clone)]
 pub struct SchedulerQueue {
-    spawn: Sender<LuaRegistryKey>,
-    event: Sender<Event>,
+    spawn: Arc<Channel<LuaRegistryKey>>,
+    event: Arc<Channel<Event>>,
+    overflow: OverflowPolicy,
+    profiler: Arc<Mutex<profile::ScriptProfiler>>,
+    gc: Arc<Mutex<gc::GcControl>>,
 }
 
 impl SchedulerQueue {
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.overflow
+    }
+
+    /// The current depth and lifetime counters of the queue's channels, along with the
+    /// scheduler's slowest threads.
+    pub fn stats(&self) -> SchedulerStats {
+        SchedulerStats {
+            events: self.event.stats(),
+            spawns: self.spawn.stats(),
+            scripts: self.profiler.lock().unwrap().top(profile::TOP_THREADS),
+            gc: self.gc.lock().unwrap().stats(),
+        }
+    }
+
+    /// The per-thread samples collected by the scheduler. See the [`profile`] module.
+    pub fn profiler(&self) -> &Mutex<profile::ScriptProfiler> {
+        &self.profiler
+    }
+
+    /// The scheduler's garbage collector settings. See the [`gc`] module.
+    pub fn gc(&self) -> &Mutex<gc::GcControl> {
+        &self.gc
+    }
+
     /// Push an already encoded `Event` into the event queue.
     ///
     /// If you don't have an `Event` at hand for some reason or another,
     /// you can use [`broadcast`](SchedulerQueue::broadcast) or
     /// [`notify`](SchedulerQueue::notify) for a simpler and more convenient
     /// API.
-    pub fn push_event(&self, event: Event) {
-        self.event
-            .try_send(event)
-            .expect("unbounded channel should never fail to send");
+    pub fn push_event(&self, event: Event) -> Result<(), QueueFull> {
+        self.event.push(event, self.overflow)
     }
 
     /// Push a Lua thread which is already encoded into a registry key into
@@ -554,10 +1468,8 @@ impl SchedulerQueue {
     /// If you don't have a registry key handy or you're working in a Lua
     /// context, there's the more convenient [`spawn`](SchedulerQueue::spawn)
     /// method. Most of the time that's probably what you'll want.
-    pub fn push_spawn(&self, spawn: LuaRegistryKey) {
-        self.spawn
-            .try_send(spawn)
-            .expect("unbounded channel should never fail to send");
+    pub fn push_spawn(&self, spawn: LuaRegistryKey) -> Result<(), QueueFull> {
+        self.spawn.push(spawn, self.overflow)
     }
 
     /// Spawn a Lua thread, pushing it into the scheduler's queue.
@@ -592,7 +1504,7 @@ impl SchedulerQueue {
         };
 
         let key = lua.create_registry_value(thread.clone())?;
-        self.push_spawn(key);
+        self.push_spawn(key).map_err(LuaError::external)?;
         self.call(lua, thread.clone(), args)?;
 
         Ok(thread)
@@ -616,8 +1528,38 @@ impl SchedulerQueue {
         event_name: S,
         args: T,
     ) -> LuaResult<()> {
+        let event = Self::broadcast_event(lua, event_name, args)?;
+        self.push_event(event).map_err(LuaError::external)
+    }
+
+    /// Broadcast an event `ticks` ticks from now, as though a thread had slept for that
+    /// long and then broadcast it.
+    ///
+    /// The arguments are stored into the Lua registry right away, so they're the same
+    /// values, not copies of them, by the time the event goes out. Delayed events are
+    /// held by the scheduler, and count down with its clock; while the scheduler's
+    /// pause channel is paused, they wait.
+    pub fn broadcast_in<'lua, S: AsRef<str>, T: ToLuaMulti<'lua>>(
+        &self,
+        lua: LuaContext<'lua>,
+        ticks: u64,
+        event_name: S,
+        args: T,
+    ) -> LuaResult<()> {
+        let event = Event::Delayed {
+            ticks,
+            event: Box::new(Self::broadcast_event(lua, event_name, args)?),
+        };
+        self.push_event(event).map_err(LuaError::external)
+    }
+
+    fn broadcast_event<'lua, S: AsRef<str>, T: ToLuaMulti<'lua>>(
+        lua: LuaContext<'lua>,
+        event_name: S,
+        args: T,
+    ) -> LuaResult<Event> {
         let args = args.to_lua_multi(lua)?;
-        let event = Event::Broadcast {
+        Ok(Event::Broadcast {
             name: EventName(Atom::from(event_name.as_ref())),
             args: if args.is_empty() {
                 None
@@ -628,11 +1570,7 @@ impl SchedulerQueue {
                         .collect::<LuaResult<_>>()?,
                 )
             },
-        };
-
-        self.push_event(event);
-
-        Ok(())
+        })
     }
 
     /// Notify a single specific thread to continue execution the next
@@ -654,9 +1592,36 @@ impl SchedulerQueue {
         thread: LuaThread<'lua>,
         args: T,
     ) -> LuaResult<()> {
+        let event = Self::notify_event(lua, thread, args)?;
+        self.push_event(event).map_err(LuaError::external)
+    }
+
+    /// Notify a single specific thread `ticks` ticks from now. Like
+    /// [`broadcast_in`](SchedulerQueue::broadcast_in), the notification waits while
+    /// the scheduler's clock is stopped. If the thread has died in the meantime,
+    /// nothing happens.
+    pub fn notify_in<'lua, T: ToLuaMulti<'lua>>(
+        &self,
+        lua: LuaContext<'lua>,
+        ticks: u64,
+        thread: LuaThread<'lua>,
+        args: T,
+    ) -> LuaResult<()> {
+        let event = Event::Delayed {
+            ticks,
+            event: Box::new(Self::notify_event(lua, thread, args)?),
+        };
+        self.push_event(event).map_err(LuaError::external)
+    }
+
+    fn notify_event<'lua, T: ToLuaMulti<'lua>>(
+        lua: LuaContext<'lua>,
+        thread: LuaThread<'lua>,
+        args: T,
+    ) -> LuaResult<Event> {
         let args = args.to_lua_multi(lua)?;
         let thread = lua.create_registry_value(thread)?;
-        let event = Event::Notify {
+        Ok(Event::Notify {
             thread,
             args: if args.is_empty() {
                 None
@@ -667,11 +1632,7 @@ impl SchedulerQueue {
                         .collect::<LuaResult<_>>()?,
                 )
             },
-        };
-
-        self.push_event(event);
-
-        Ok(())
+        })
     }
 
     fn call<'lua, T: ToLuaMulti<'lua>>(
@@ -695,9 +1656,7 @@ impl SchedulerQueue {
             },
         };
 
-        self.push_event(event);
-
-        Ok(())
+        self.push_event(event).map_err(LuaError::external)
     }
 
     /// Send a "kill" signal to a thread, allowing it to resume once more
@@ -723,10 +1682,79 @@ impl SchedulerQueue {
             },
         };
 
-        self.push_event(event);
+        self.push_event(event).map_err(LuaError::external)
+    }
+}
 
-        Ok(())
+/// The name of the event broadcast when a Lua thread dies with an error. It's broadcast
+/// with a single table holding the dead `thread`, its debug `name` (if it was given
+/// one with `sludge.thread.set_name`), the error `message` and a `traceback`.
+pub const SCRIPT_ERROR_EVENT: &'static str = "script.error";
+
+/// A Lua thread dying with an error, as reported by the [`Scheduler`].
+#[derive(Debug, Clone)]
+pub struct ScriptError {
+    pub thread: Index,
+    pub name: Option<String>,
+    pub message: String,
+    /// The Lua traceback of the error, if one could be found; otherwise, the message
+    /// again. Full tracebacks of errors raised from Lua itself need the `debug` library
+    /// to be loaded.
+    pub traceback: String,
+}
+
+/// What a [`Scheduler`] does when a Lua thread dies with an error. The thread is
+/// always killed; by default, the error is also logged and broadcast as a
+/// [`SCRIPT_ERROR_EVENT`].
+#[derive(Clone)]
+pub struct ErrorPolicy {
+    pub log: bool,
+    pub broadcast: bool,
+    /// Called with every error, after it's logged and before it's broadcast.
+    pub hook: Option<Arc<dyn Fn(&ScriptError) + Send + Sync>>,
+}
+
+impl Default for ErrorPolicy {
+    fn default() -> Self {
+        Self {
+            log: true,
+            broadcast: true,
+            hook: None,
+        }
+    }
+}
+
+impl fmt::Debug for ErrorPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ErrorPolicy")
+            .field("log", &self.log)
+            .field("broadcast", &self.broadcast)
+            .field("hook", &self.hook.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+impl ErrorPolicy {
+    pub fn with_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&ScriptError) + Send + Sync + 'static,
+    {
+        self.hook = Some(Arc::new(hook));
+        self
+    }
+}
+
+/// Find the best traceback available for an error which killed a thread.
+fn traceback<'lua>(lua: LuaContext<'lua>, thread: &LuaThread<'lua>, error: &LuaError) -> String {
+    if let LuaError::CallbackError { traceback, .. } = error {
+        return traceback.clone();
     }
+
+    lua.globals()
+        .get::<_, LuaTable>("debug")
+        .and_then(|debug| debug.get::<_, LuaFunction>("traceback"))
+        .and_then(|traceback| traceback.call::<_, String>((thread.clone(), error.to_string())))
+        .unwrap_or_else(|_| error.to_string())
 }
 
 /// The scheduler controls the execution of Lua "threads", under a cooperative
@@ -798,6 +1826,23 @@ pub struct Scheduler {
     /// and added to the queue with `wakeup == 0`.
     waiting: HashMap<EventName, Vec<Index>>,
 
+    /// Events pushed with a delay, keyed by the tick they're due on and then by the
+    /// order they were received in, so that events due on the same tick go out in
+    /// order.
+    delayed_events: BTreeMap<(u64, u64), Event>,
+
+    /// How many delayed events have ever been received, used to order them.
+    delayed_count: u64,
+
+    /// The names of the events broadcast during the most recent update, for systems
+    /// which act on events without running a thread to wait for them.
+    broadcasts: HashSet<EventName>,
+
+    /// Threads waiting for a condition, each with the registry key of the predicate
+    /// which decides when it's woken. Predicates are checked once per tick, before
+    /// any threads are run on that tick.
+    conditions: Vec<(Index, LuaRegistryKey)>,
+
     /// The generational arena allows us to ensure that threads that
     /// are waiting for multiple events and also possibly a timer don't
     /// get woken up multiple times.
@@ -835,6 +1880,16 @@ pub struct Scheduler {
     /// to discrete time on update, used to measure how many ticks
     /// to run per a given update)
     continuous: f32,
+
+    /// What to do when a thread dies with an error.
+    error_policy: ErrorPolicy,
+
+    /// The pause channel which stops the scheduler's clock, if any.
+    pause_channel: Option<Atom>,
+
+    /// Whether the clock was stopped for the current update. While it is, only
+    /// untimed wakeups are considered ready.
+    paused: bool,
 }
 
 impl Scheduler {
@@ -844,17 +1899,39 @@ impl Scheduler {
     /// to a given Lua state and cannot be moved from one to another; they store
     /// a significant amount of state in the registry of their bound Lua state.
     pub fn new(lua: LuaContext) -> Result<Self> {
-        let (spawn_sender, spawn_channel) = crossbeam_channel::bounded(Self::CHANNEL_BOUND);
-        let (event_sender, event_channel) = crossbeam_channel::bounded(Self::CHANNEL_BOUND);
+        Self::with_channel_bound(lua, Self::CHANNEL_BOUND)
+    }
+
+    /// Construct a new scheduler whose spawn and event channels can hold at most
+    /// `bound` pending messages each.
+    pub fn with_channel_bound(lua: LuaContext, bound: usize) -> Result<Self> {
+        Self::with_channels(lua, bound, OverflowPolicy::default())
+    }
+
+    /// Construct a new scheduler whose spawn and event channels can hold at most
+    /// `bound` pending messages each, and which handles messages pushed into a full
+    /// channel according to `overflow`.
+    pub fn with_channels(lua: LuaContext, bound: usize, overflow: OverflowPolicy) -> Result<Self> {
+        let spawn = Channel::new("spawn", bound);
+        let event = Channel::new("event", bound);
+        let spawn_channel = spawn.receiver.clone();
+        let event_channel = event.receiver.clone();
         let senders = SchedulerQueue {
-            spawn: spawn_sender,
-            event: event_sender,
+            spawn: Arc::new(spawn),
+            event: Arc::new(event),
+            overflow,
+            profiler: Arc::default(),
+            gc: Arc::default(),
         };
         let slots = lua.create_registry_value(lua.create_table()?)?;
 
         Ok(Self {
             queue: BinaryHeap::new(),
             waiting: HashMap::new(),
+            delayed_events: BTreeMap::new(),
+            delayed_count: 0,
+            broadcasts: HashSet::new(),
+            conditions: Vec::new(),
 
             threads: Arena::new(),
             slots,
@@ -866,6 +1943,11 @@ impl Scheduler {
 
             discrete: 0,
             continuous: 0.,
+
+            error_policy: ErrorPolicy::default(),
+
+            pause_channel: Some(Atom::from(pause::GAMEPLAY)),
+            paused: false,
         })
     }
 
@@ -874,12 +1956,132 @@ impl Scheduler {
     ///
     /// The scheduler is considered idle only if no events are waiting to be resumed
     /// on the current step and there are no events or threads to be spawned waiting in
-    /// its queue.
+    /// its queue, or delayed events due on the current step.
     pub fn is_idle(&self) -> bool {
         let nothing_in_queue =
-            self.queue.is_empty() || self.queue.peek().unwrap().scheduled_for() > self.discrete;
+            self.queue.is_empty() || self.queue.peek().unwrap().scheduled_for() > self.now();
         let no_pending_events = self.spawn_receiver.is_empty() && self.event_receiver.is_empty();
-        nothing_in_queue && no_pending_events
+        let no_due_events = self
+            .delayed_events
+            .keys()
+            .next()
+            .map_or(true, |&(due, _)| due > self.now());
+        nothing_in_queue && no_pending_events && no_due_events
+    }
+
+    /// Drop every thread, wakeup and pending event, stopping every script running in
+    /// the scheduler. The clock is left as it is.
+    pub fn clear(&mut self, lua: LuaContext) -> Result<()> {
+        self.queue.clear();
+        self.waiting.clear();
+        self.delayed_events.clear();
+        self.broadcasts.clear();
+        self.conditions.clear();
+        self.threads = Arena::new();
+        self.event_args = Arena::new();
+        self.slots = lua.create_registry_value(lua.create_table()?)?;
+        self.spawn_receiver.try_iter().for_each(drop);
+        self.event_receiver.try_iter().for_each(drop);
+        Ok(())
+    }
+
+    /// How many delayed events are waiting to go out.
+    pub fn delayed_events(&self) -> usize {
+        self.delayed_events.len()
+    }
+
+    /// The names of the events broadcast during the most recent call to
+    /// [`Scheduler::update`], whether or not any threads were waiting on them.
+    pub fn broadcasts(&self) -> &HashSet<EventName> {
+        &self.broadcasts
+    }
+
+    /// Whether an event named `name` was broadcast during the most recent update.
+    pub fn was_broadcast(&self, name: &EventName) -> bool {
+        self.broadcasts.contains(name)
+    }
+
+    pub fn error_policy(&self) -> &ErrorPolicy {
+        &self.error_policy
+    }
+
+    pub fn set_error_policy(&mut self, policy: ErrorPolicy) {
+        self.error_policy = policy;
+    }
+
+    /// The pause channel which stops the scheduler's clock. Defaults to
+    /// [`pause::GAMEPLAY`].
+    pub fn pause_channel(&self) -> Option<&str> {
+        self.pause_channel.as_ref().map(|channel| channel.as_ref())
+    }
+
+    /// Set the pause channel which stops the scheduler's clock, or `None` to keep the
+    /// clock running no matter what's paused.
+    ///
+    /// While the channel is paused, timed wakeups are held back and no time passes,
+    /// but threads woken by events, notifications, kills and spawns still run. That
+    /// way scripts driving a pause menu keep working while everything sleeping on
+    /// the clock stays frozen.
+    pub fn set_pause_channel(&mut self, channel: Option<&str>) {
+        self.pause_channel = channel.map(Atom::from);
+    }
+
+    /// The latest tick whose wakeups are ready to run. While paused, only untimed
+    /// wakeups (which are always scheduled for tick 0) are ready.
+    fn now(&self) -> u64 {
+        if self.paused {
+            0
+        } else {
+            self.discrete
+        }
+    }
+
+    /// Report a thread which died with an error according to the error policy.
+    fn report_error<'lua>(
+        &self,
+        lua: LuaContext<'lua>,
+        index: Index,
+        thread: LuaThread<'lua>,
+        lua_error: &LuaError,
+    ) -> Result<()> {
+        let name = api::thread_name(lua, &thread).ok().flatten();
+        let message = match lua_error.source() {
+            Some(src) => src.to_string(),
+            None => lua_error.to_string(),
+        };
+        let error = ScriptError {
+            thread: index,
+            traceback: traceback(lua, &thread, lua_error),
+            name,
+            message,
+        };
+
+        if self.error_policy.log {
+            match &error.name {
+                Some(name) => log::error!(
+                    "fatal error in Lua thread {:?} `{}`: {}",
+                    index,
+                    name,
+                    error.message
+                ),
+                None => log::error!("fatal error in Lua thread {:?}: {}", index, error.message),
+            }
+        }
+
+        if let Some(hook) = &self.error_policy.hook {
+            hook(&error);
+        }
+
+        if self.error_policy.broadcast {
+            let table = lua.create_table()?;
+            table.set("thread", thread)?;
+            table.set("name", error.name)?;
+            table.set("message", error.message)?;
+            table.set("traceback", error.traceback)?;
+            self.senders.broadcast(lua, SCRIPT_ERROR_EVENT, table)?;
+        }
+
+        Ok(())
     }
 
     /// Returns a reference to the scheduler's queue handle, for spawning threads and
@@ -888,6 +2090,36 @@ impl Scheduler {
         &self.senders
     }
 
+    /// The current depth and lifetime counters of the scheduler's channels, and the
+    /// threads which have taken the most time while profiling was turned on.
+    pub fn stats(&self) -> SchedulerStats {
+        self.senders.stats()
+    }
+
+    pub fn is_profiling(&self) -> bool {
+        self.senders.profiler.lock().unwrap().is_enabled()
+    }
+
+    /// Turn on timing of every thread resume, which is reported in
+    /// [`Scheduler::stats`]. See the [`profile`] module for details.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.senders.profiler.lock().unwrap().set_enabled(enabled);
+    }
+
+    pub fn gc_config(&self) -> gc::GcConfig {
+        self.senders.gc.lock().unwrap().config()
+    }
+
+    /// Change how the scheduler drives the Lua garbage collector. See the [`gc`] module.
+    pub fn set_gc_config(&mut self, config: gc::GcConfig) {
+        self.senders.gc.lock().unwrap().set_config(config);
+    }
+
+    /// Run a full garbage collection cycle right away.
+    pub fn gc_full(&self, lua: LuaContext) -> Result<()> {
+        gc::full(lua, &self.senders.gc)
+    }
+
     /// Drains the spawn channel, pushing new threads onto the scheduler's heap with a wakeup
     /// time of 0 (so that they're immediately resumed on the next run through the queue)
     /// and inserting them into the reverse-lookup table (slots).
@@ -917,24 +2149,45 @@ impl Scheduler {
         Ok(())
     }
 
-    /// Drains the event channel and adds relevant `Wakeup`s to the queue.
+    /// Drains the event channel and any delayed events which have come due, and adds
+    /// relevant `Wakeup`s to the queue.
     pub(crate) fn poll_events_and_queue_all_notified<'lua>(
         &mut self,
         lua: LuaContext<'lua>,
         slots: &LuaTable<'lua>,
     ) -> Result<()> {
+        let now = self.now();
+        let discrete = self.discrete;
         let Self {
             queue,
             threads,
             waiting,
             event_args,
             event_receiver: event_channel,
+            delayed_events,
+            delayed_count,
+            broadcasts,
             ..
         } = self;
 
-        for event in event_channel.try_iter() {
+        // Delayed events which have come due were sent before anything still in the
+        // channel, so they go first.
+        let not_due = delayed_events.split_off(&(now + 1, 0));
+        let mut events = mem::replace(delayed_events, not_due)
+            .into_iter()
+            .map(|(_, event)| event)
+            .collect::<VecDeque<_>>();
+        events.extend(event_channel.try_iter());
+
+        while let Some(event) = events.pop_front() {
             match event {
+                Event::Delayed { ticks: 0, event } => events.push_back(*event),
+                Event::Delayed { ticks, event } => {
+                    delayed_events.insert((discrete + ticks, *delayed_count), *event);
+                    *delayed_count += 1;
+                }
                 Event::Broadcast { name, args } => {
+                    broadcasts.insert(name.clone());
                     let event_index = args.map(|args| event_args.insert(args));
                     if let Some(running_threads) = waiting.get_mut(&name) {
                         for index in running_threads.drain(..) {
@@ -1009,7 +2262,7 @@ impl Scheduler {
         while let Some(top) = self.queue.peek() {
             // If this thread isn't ready to wake up on this tick, then
             // none of the other threads in this queue are.
-            if top.scheduled_for() > self.discrete {
+            if top.scheduled_for() > self.now() {
                 break;
             }
 
@@ -1017,6 +2270,8 @@ impl Scheduler {
             if let Some(key) = self.threads.get(sleeping.thread()) {
                 let thread = lua.registry_value::<LuaThread>(key)?;
 
+                let budget_guard = sandbox::ResumeGuard::begin(lua)?;
+                let sample = profile::Sample::begin(lua, &self.senders.profiler);
                 let resumed = match &sleeping {
                     Wakeup::Call {
                         args: Some(args), ..
@@ -1070,6 +2325,10 @@ impl Scheduler {
                         name, args: None, ..
                     } => thread.resume::<_, LuaMultiValue>((true, name.0.as_ref())),
                 };
+                drop(budget_guard);
+                if let Some(sample) = sample {
+                    sample.finish(lua, &thread, &self.senders.profiler);
+                }
 
                 let status = thread.status();
                 match resumed {
@@ -1123,6 +2382,12 @@ impl Scheduler {
                                         }
                                     }
                                 }
+                                // If we see a function, then treat it as a condition which the
+                                // thread wants to wait for; it's checked once per tick.
+                                LuaValue::Function(predicate) => {
+                                    let key = lua.create_registry_value(predicate)?;
+                                    self.conditions.push((new_index, key));
+                                }
                                 other => {
                                     log::error!("unknown yield return value {:?}", other);
                                 }
@@ -1143,21 +2408,18 @@ impl Scheduler {
                         self.threads.remove(sleeping.thread());
                     }
                     Err(lua_error) => {
-                        slots.set(thread, LuaValue::Nil)?;
+                        slots.set(thread.clone(), LuaValue::Nil)?;
                         self.threads.remove(sleeping.thread());
 
-                        match lua_error.source() {
-                            Some(src) => log::error!(
-                                "fatal error in Lua thread {:?}: {}",
-                                sleeping.thread(),
-                                src
-                            ),
-                            None => log::error!(
-                                "fatal error in Lua thread {:?}: {}",
-                                sleeping.thread(),
-                                lua_error
-                            ),
+                        if let Some(violation) = sandbox::Violation::find(&lua_error) {
+                            self.senders.broadcast(
+                                lua,
+                                sandbox::VIOLATION_EVENT,
+                                (thread.clone(), violation.kind(), violation.to_string()),
+                            )?;
                         }
+
+                        self.report_error(lua, sleeping.thread(), thread, &lua_error)?;
                     }
                 }
             }
@@ -1166,6 +2428,77 @@ impl Scheduler {
         Ok(())
     }
 
+    /// Check the predicate of every thread waiting for a condition, queueing the threads
+    /// whose predicates hold to be woken on the current tick.
+    ///
+    /// Conditions of threads which have since been woken some other way are thrown out
+    /// without being checked. A thread whose predicate fails with an error is killed.
+    fn poll_conditions<'lua>(&mut self, lua: LuaContext<'lua>) -> Result<()> {
+        let conditions = mem::take(&mut self.conditions);
+
+        for (index, key) in conditions {
+            if self.threads.get(index).is_none() {
+                continue;
+            }
+
+            let predicate = lua.registry_value::<LuaFunction>(&key)?;
+            let budget_guard = sandbox::ResumeGuard::begin(lua)?;
+            let result = predicate.call::<_, LuaValue>(());
+            drop(budget_guard);
+
+            // Like any condition in Lua, only `nil` and `false` are falsy.
+            match result {
+                Ok(LuaValue::Nil) | Ok(LuaValue::Boolean(false)) => {
+                    self.conditions.push((index, key))
+                }
+                Ok(_) => self.queue.push(Wakeup::Timed {
+                    thread: self.threads.invalidate(index).unwrap(),
+                    scheduled_for: self.discrete,
+                }),
+                Err(lua_error) => {
+                    log::error!(
+                        "error in condition of Lua thread {:?}: {}",
+                        index,
+                        lua_error
+                    );
+                    self.queue.push(Wakeup::Kill {
+                        thread: self.threads.invalidate(index).unwrap(),
+                        args: None,
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run every thread which is ready on the current tick.
+    fn step<'lua>(&mut self, lua: LuaContext<'lua>, slots: &LuaTable<'lua>) -> Result<()> {
+        // Our core update step consists of two steps:
+        // 1. Run all threads scheduled to run on or before the current tick.
+        // 2. Check for threads spawned/woken by newly run threads. If there are new
+        //    threads to be run immediately, go to step 1.
+        //
+        // `LOOP_CAP` is our limit on how many times we go to step 1 in a given
+        // tick. This stops us from hitting an infinitely spawning loop.
+        const LOOP_CAP: usize = 8;
+
+        for i in 0..LOOP_CAP {
+            self.run_all_queued(lua, slots)?;
+            self.event_args.clear();
+            self.queue_all_spawned(lua, slots)?;
+            self.poll_events_and_queue_all_notified(lua, slots)?;
+
+            if self.is_idle() {
+                break;
+            } else if i == LOOP_CAP - 1 {
+                log::warn!("trampoline loop cap exceeded");
+            }
+        }
+
+        Ok(())
+    }
+
     /// Run the scheduler for `dt` steps.
     ///
     /// The scheduler contains a very simple internal timestep which simply waits
@@ -1182,37 +2515,37 @@ impl Scheduler {
     /// cap was exceeded, it's a good idea to look back at your code and check to
     /// ensure there's nothing infinitely spawning/waking itself. The loop cap is
     /// currently hardcoded to 8, but may be made parameterizable in the future.
+    ///
+    /// If the scheduler's [pause channel](Scheduler::set_pause_channel) is paused, `dt`
+    /// is ignored and only threads with untimed wakeups are run.
+    ///
+    /// Once the threads have been run, the Lua garbage collector is stepped for as long
+    /// as the scheduler's [`GcConfig`](gc::GcConfig) allows. See the [`gc`] module.
     pub fn update(&mut self, lua: LuaContext, dt: f32) -> Result<()> {
         let old_queue =
             lua.named_registry_value::<_, Option<LuaValue>>(api::SCHEDULER_QUEUE_REGISTRY_KEY)?;
         lua.set_named_registry_value(api::SCHEDULER_QUEUE_REGISTRY_KEY, self.senders.clone())?;
 
+        self.paused = self
+            .pause_channel
+            .as_ref()
+            .map_or(false, |channel| pause::is_paused(&lua, channel));
+        self.broadcasts.clear();
+        let gc = self.senders.gc.clone();
+
         let mut block = move || -> Result<()> {
-            self.continuous += dt;
             let slots = lua.registry_value(&self.slots)?;
-            while self.continuous > 0. {
-                // Our core update step consists of two steps:
-                // 1. Run all threads scheduled to run on or before the current tick.
-                // 2. Check for threads spawned/woken by newly run threads. If there are new
-                //    threads to be run immediately, go to step 1.
-                //
-                // `LOOP_CAP` is our limit on how many times we go to step 1 in a given
-                // tick. This stops us from hitting an infinitely spawning loop.
-                const LOOP_CAP: usize = 8;
-
-                for i in 0..LOOP_CAP {
-                    self.run_all_queued(lua, &slots)?;
-                    self.event_args.clear();
-                    self.queue_all_spawned(lua, &slots)?;
-                    self.poll_events_and_queue_all_notified(lua, &slots)?;
-
-                    if self.is_idle() {
-                        break;
-                    } else if i == LOOP_CAP - 1 {
-                        log::warn!("trampoline loop cap exceeded");
-                    }
-                }
 
+            // While paused, the clock stands still; we run through the untimed wakeups
+            // once and leave the accumulated time alone.
+            if self.paused {
+                return self.step(lua, &slots);
+            }
+
+            self.continuous += dt;
+            while self.continuous > 0. {
+                self.poll_conditions(lua)?;
+                self.step(lua, &slots)?;
                 self.continuous -= 1.;
                 self.discrete += 1;
             }
@@ -1222,6 +2555,7 @@ impl Scheduler {
 
         let result = block();
         lua.expire_registry_values();
+        let result = result.and_then(|()| gc::step(lua, &gc));
         lua.set_named_registry_value(api::SCHEDULER_QUEUE_REGISTRY_KEY, old_queue)?;
 
         result
@@ -1253,6 +2587,24 @@ impl LuaUserData for Scheduler {
             },
         );
 
+        methods.add_method(
+            "broadcast_in",
+            |lua, this, (ticks, event_name, args): (u64, LuaString, LuaMultiValue)| {
+                this.queue()
+                    .broadcast_in(lua, ticks, event_name.to_str()?, args)
+                    .to_lua_err()
+            },
+        );
+
+        methods.add_method(
+            "notify_in",
+            |lua, this, (ticks, thread, args): (u64, LuaThread, LuaMultiValue)| {
+                this.queue()
+                    .notify_in(lua, ticks, thread, args)
+                    .to_lua_err()
+            },
+        );
+
         methods.add_method(
             "kill",
             |lua, this, (thread, args): (LuaThread, LuaMultiValue)| {
@@ -1262,6 +2614,7 @@ impl LuaUserData for Scheduler {
 
         methods.add_method_mut("update", |lua, this, ()| this.update(lua, 1.).to_lua_err());
         methods.add_method("queue", |_lua, this, ()| Ok(this.queue().clone()));
+        methods.add_method("stats", |_lua, this, ()| Ok(this.stats()));
     }
 }
 
@@ -1288,11 +2641,28 @@ impl LuaUserData for SchedulerQueue {
             },
         );
 
+        methods.add_method(
+            "broadcast_in",
+            |lua, this, (ticks, event_name, args): (u64, LuaString, LuaMultiValue)| {
+                this.broadcast_in(lua, ticks, event_name.to_str()?, args)
+                    .to_lua_err()
+            },
+        );
+
+        methods.add_method(
+            "notify_in",
+            |lua, this, (ticks, thread, args): (u64, LuaThread, LuaMultiValue)| {
+                this.notify_in(lua, ticks, thread, args).to_lua_err()
+            },
+        );
+
         methods.add_method(
             "kill",
             |lua, this, (thread, args): (LuaThread, LuaMultiValue)| {
                 this.kill(lua, thread, args).to_lua_err()
             },
         );
+
+        methods.add_method("stats", |_lua, this, ()| Ok(this.stats()));
     }
 }
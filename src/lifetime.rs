@@ -0,0 +1,273 @@
+//! Components for entities which despawn themselves, either after a while or when an
+//! event is broadcast.
+//!
+//! An entity with a [`Lifetime`] is despawned once its lifetime runs out, and an entity
+//! with a [`DespawnOnEvent`] is despawned when its event is broadcast on the space's
+//! [`Scheduler`]. Both are handled by the [`LifetimeSystem`], which is one of the
+//! default systems registered by a [`SpaceBuilder`](crate::SpaceBuilder):
+//!
+//! ```lua
+//! sludge.spawn {
+//!     Sprite = "explosion",
+//!     Lifetime = 0.5,
+//! }
+//!
+//! sludge.spawn {
+//!     Sprite = "warning",
+//!     DespawnOnEvent = "boss.defeated",
+//! }
+//! ```
+//!
+//! Lifetimes are in seconds and count down by the space's
+//! [fixed timestep](crate::timestep) each time the system is updated, so they line up
+//! with the scheduler's ticks; they stop counting while the gameplay channel is
+//! [paused](crate::pause). Entities waiting on an event are
+//! despawned if the event was broadcast during the scheduler's most recent update,
+//! paused or not. [`Disabled`] entities are left alone either way.
+
+use {
+    anyhow::*,
+    rlua::prelude::*,
+    serde::{Deserialize, Serialize},
+};
+
+use crate::{
+    api::{LuaComponent, LuaComponentInterface},
+    ecs::*,
+    pause,
+    reflect::ReflectedComponent,
+    timestep, EventName, OwnedResources, Resources, Scheduler, SharedResources,
+    SludgeLuaContextExt, SludgeResultExt, UnifiedResources,
+};
+
+/// How many seconds an entity has left before it's despawned.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Lifetime(pub f32);
+
+impl<'a> SmartComponent<ScContext<'a>> for Lifetime {}
+
+inventory::submit! {
+    CloneComponent::of::<Lifetime>()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LifetimeAccessor(Entity);
+
+impl LuaUserData for LifetimeAccessor {
+    fn add_methods<'lua, T: LuaUserDataMethods<'lua, Self>>(methods: &mut T) {
+        methods.add_method("get", |lua, this, ()| {
+            let world = lua.fetch_one::<World>()?;
+            let lifetime = *world.borrow().get::<Lifetime>(this.0).to_lua_err()?;
+            Ok(lifetime.0)
+        });
+
+        methods.add_method("set", |lua, this, seconds: f32| {
+            let world = lua.fetch_one::<World>()?;
+            *world.borrow().get_mut::<Lifetime>(this.0).to_lua_err()? = Lifetime(seconds);
+            Ok(())
+        });
+
+        methods.add_method("to_table", |lua, this, ()| {
+            let world = lua.fetch_one::<World>()?;
+            let lifetime = *world.borrow().get::<Lifetime>(this.0).to_lua_err()?;
+            Ok(lifetime.0)
+        });
+    }
+}
+
+impl LuaComponentInterface for Lifetime {
+    fn accessor<'lua>(lua: LuaContext<'lua>, entity: Entity) -> LuaResult<LuaValue<'lua>> {
+        LifetimeAccessor(entity).to_lua(lua)
+    }
+
+    fn bundler<'lua>(
+        lua: LuaContext<'lua>,
+        args: LuaValue<'lua>,
+        builder: &mut EntityBuilder,
+    ) -> LuaResult<()> {
+        builder.add(Lifetime(f32::from_lua(args, lua)?));
+        Ok(())
+    }
+}
+
+inventory::submit! {
+    LuaComponent::new::<Lifetime>("Lifetime")
+}
+
+inventory::submit! {
+    ReflectedComponent::new::<Lifetime>("Lifetime")
+}
+
+/// Despawns an entity when the named event is broadcast.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct DespawnOnEvent(pub EventName);
+
+impl DespawnOnEvent {
+    pub fn new(event: &str) -> Self {
+        Self(EventName::new(event))
+    }
+}
+
+impl<'a> SmartComponent<ScContext<'a>> for DespawnOnEvent {}
+
+inventory::submit! {
+    CloneComponent::of::<DespawnOnEvent>()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DespawnOnEventAccessor(Entity);
+
+impl LuaUserData for DespawnOnEventAccessor {
+    fn add_methods<'lua, T: LuaUserDataMethods<'lua, Self>>(methods: &mut T) {
+        methods.add_method("get", |lua, this, ()| {
+            let world = lua.fetch_one::<World>()?;
+            let world = world.borrow();
+            let despawn = world.get::<DespawnOnEvent>(this.0).to_lua_err()?;
+            Ok(despawn.0.as_str().to_owned())
+        });
+
+        methods.add_method("set", |lua, this, event: LuaString| {
+            let world = lua.fetch_one::<World>()?;
+            *world
+                .borrow()
+                .get_mut::<DespawnOnEvent>(this.0)
+                .to_lua_err()? = DespawnOnEvent::new(event.to_str()?);
+            Ok(())
+        });
+
+        methods.add_method("to_table", |lua, this, ()| {
+            let world = lua.fetch_one::<World>()?;
+            let world = world.borrow();
+            let despawn = world.get::<DespawnOnEvent>(this.0).to_lua_err()?;
+            Ok(despawn.0.as_str().to_owned())
+        });
+    }
+}
+
+impl LuaComponentInterface for DespawnOnEvent {
+    fn accessor<'lua>(lua: LuaContext<'lua>, entity: Entity) -> LuaResult<LuaValue<'lua>> {
+        DespawnOnEventAccessor(entity).to_lua(lua)
+    }
+
+    fn bundler<'lua>(
+        lua: LuaContext<'lua>,
+        args: LuaValue<'lua>,
+        builder: &mut EntityBuilder,
+    ) -> LuaResult<()> {
+        let event = LuaString::from_lua(args, lua)?;
+        builder.add(DespawnOnEvent::new(event.to_str()?));
+        Ok(())
+    }
+}
+
+inventory::submit! {
+    LuaComponent::new::<DespawnOnEvent>("DespawnOnEvent")
+}
+
+inventory::submit! {
+    ReflectedComponent::new::<DespawnOnEvent>("DespawnOnEvent")
+}
+
+/// Count down every enabled [`Lifetime`] by `dt` seconds, and collect the entities whose
+/// lifetimes have run out.
+pub fn expire(world: &World, dt: f32) -> Vec<Entity> {
+    let mut expired = Vec::new();
+    for (entity, mut lifetime) in world.query_enabled::<&mut Lifetime>().iter() {
+        lifetime.0 -= dt;
+        if lifetime.0 <= 0. {
+            expired.push(entity);
+        }
+    }
+    expired
+}
+
+/// Collect the enabled entities with a [`DespawnOnEvent`] whose event was broadcast
+/// during the scheduler's most recent update.
+pub fn triggered(world: &World, scheduler: &Scheduler) -> Vec<Entity> {
+    if scheduler.broadcasts().is_empty() {
+        return Vec::new();
+    }
+
+    world
+        .query_enabled::<&DespawnOnEvent>()
+        .iter()
+        .filter(|(_, despawn)| scheduler.was_broadcast(&despawn.0))
+        .map(|(entity, _)| entity)
+        .collect()
+}
+
+/// Despawns entities whose [`Lifetime`] has run out or whose [`DespawnOnEvent`] event has
+/// been broadcast. See the [module documentation](self) for details.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LifetimeSystem;
+
+impl crate::System for LifetimeSystem {
+    fn init(
+        &self,
+        _lua: LuaContext,
+        local: &mut OwnedResources,
+        _global: Option<&SharedResources>,
+    ) -> Result<()> {
+        if !local.has_value::<World>() {
+            local.insert(World::new());
+        }
+
+        Ok(())
+    }
+
+    fn update(&self, _lua: LuaContext, resources: &UnifiedResources) -> Result<()> {
+        let world = resources.fetch_one::<World>()?;
+        let mut doomed = match resources.fetch_one::<Scheduler>() {
+            Ok(scheduler) => triggered(&world.borrow(), &scheduler.borrow()),
+            Err(_) => Vec::new(),
+        };
+
+        if !pause::is_paused(resources, pause::GAMEPLAY) {
+            doomed.extend(expire(&world.borrow(), timestep::fixed_dt(resources)));
+        }
+
+        let mut world = world.borrow_mut();
+        for entity in doomed {
+            // An entity can both expire and be triggered on the same update.
+            if world.contains(entity) {
+                let _ = world.despawn(entity).log_error_err("sludge::lifetime");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Space;
+
+    #[test]
+    fn lifetimes_expire() {
+        let dt = 0.25;
+        let mut world = World::new();
+        let short = world.spawn((Lifetime(dt * 1.5),));
+        let long = world.spawn((Lifetime(1.),));
+
+        assert!(expire(&world, dt).is_empty());
+        assert_eq!(expire(&world, dt), vec![short]);
+        assert!(world.get::<Lifetime>(long).unwrap().0 < 1.);
+    }
+
+    #[test]
+    fn lifetimes_follow_the_space_timestep() -> Result<()> {
+        let mut space = Space::builder().with_fixed_fps(4).build()?;
+        let entity = space.world()?.borrow_mut().spawn((Lifetime(0.6),));
+
+        space.fixed_update()?;
+        space.fixed_update()?;
+        assert!(space.world()?.borrow().contains(entity));
+
+        space.fixed_update()?;
+        assert!(!space.world()?.borrow().contains(entity));
+        Ok(())
+    }
+}
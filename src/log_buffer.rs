@@ -0,0 +1,260 @@
+//! A ring buffer of recent log messages, for showing the log inside the game.
+//!
+//! A [`LogBuffer`] is a [`log::Log`] which keeps the last few hundred messages logged
+//! through the `log` crate, from Rust and from Lua's `sludge.log` alike. It's a cheap
+//! handle to a shared buffer, so one copy is [installed](LogBuffer::install) alongside
+//! the game's logger and another is inserted into the global resources, where debug
+//! overlays and scripts can read it:
+//!
+//! ```ignore
+//! let log_buffer = LogBuffer::new();
+//! log_buffer.install(fern::Dispatch::new().chain(std::io::stdout()))?;
+//! global_resources.borrow_mut().insert(log_buffer);
+//! ```
+//!
+//! Nothing is thrown away when reading the buffer, so any number of readers can each
+//! show it through their own [`LogFilter`]. From Lua, the most recent messages can be
+//! fetched with `sludge.log.recent(n, level, target)`.
+
+use {
+    anyhow::Result,
+    log::{Level, LevelFilter, Log, Metadata, Record},
+    rlua::prelude::*,
+    std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    },
+};
+
+/// A single message kept by a [`LogBuffer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    /// How many messages the buffer had received before this one, so that readers can
+    /// tell which messages they've already seen.
+    pub index: u64,
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+    /// The source file, or for messages logged from Lua, the chunk name.
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+impl<'lua> ToLua<'lua> for LogEntry {
+    fn to_lua(self, lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+        let table = lua.create_table()?;
+        table.set("index", self.index)?;
+        table.set("level", self.level.as_str().to_ascii_lowercase())?;
+        table.set("target", self.target)?;
+        table.set("message", self.message)?;
+        table.set("file", self.file)?;
+        table.set("line", self.line)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// Which messages to show when reading a [`LogBuffer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFilter {
+    /// The least severe level to show.
+    pub level: LevelFilter,
+    /// If set, only show messages whose target starts with this.
+    pub target: Option<String>,
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        Self {
+            level: LevelFilter::Trace,
+            target: None,
+        }
+    }
+}
+
+impl LogFilter {
+    pub fn matches(&self, entry: &LogEntry) -> bool {
+        entry.level <= self.level
+            && self
+                .target
+                .as_deref()
+                .map_or(true, |target| entry.target.starts_with(target))
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    entries: VecDeque<LogEntry>,
+    capacity: usize,
+    level: LevelFilter,
+    received: u64,
+}
+
+/// A shared ring buffer of log messages. See the [module documentation](self) for
+/// details.
+#[derive(Debug, Clone)]
+pub struct LogBuffer {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Default for LogBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LogBuffer {
+    /// How many messages a buffer made with [`LogBuffer::new`] keeps.
+    pub const DEFAULT_CAPACITY: usize = 512;
+
+    pub fn new() -> Self {
+        Self::with_capacity(Self::DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                entries: VecDeque::with_capacity(capacity),
+                capacity,
+                level: LevelFilter::Trace,
+                received: 0,
+            })),
+        }
+    }
+
+    /// The least severe level of message the buffer keeps. Messages which aren't kept
+    /// don't count towards the indices of those which are.
+    pub fn level(&self) -> LevelFilter {
+        self.inner.lock().unwrap().level
+    }
+
+    pub fn set_level(&self, level: LevelFilter) {
+        self.inner.lock().unwrap().level = level;
+    }
+
+    /// Make `logger` together with the buffer the global logger, so the buffer gets every
+    /// message logged from then on which passes its [level](LogBuffer::level). Messages
+    /// reach the buffer unformatted, whatever `logger` does with its own. This fails if
+    /// a global logger has already been set.
+    pub fn install(&self, logger: fern::Dispatch) -> Result<()> {
+        fern::Dispatch::new()
+            .chain(logger)
+            .chain(
+                fern::Dispatch::new()
+                    .level(self.level())
+                    .chain(Box::new(self.clone()) as Box<dyn Log>),
+            )
+            .apply()?;
+        Ok(())
+    }
+
+    /// Add a message to the buffer, dropping the oldest if it's full.
+    pub fn push(
+        &self,
+        level: Level,
+        target: &str,
+        message: String,
+        file: Option<&str>,
+        line: Option<u32>,
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        if level > inner.level || inner.capacity == 0 {
+            return;
+        }
+
+        if inner.entries.len() == inner.capacity {
+            inner.entries.pop_front();
+        }
+
+        let index = inner.received;
+        inner.received += 1;
+        inner.entries.push_back(LogEntry {
+            index,
+            level,
+            target: target.to_owned(),
+            message,
+            file: file.map(str::to_owned),
+            line,
+        });
+    }
+
+    pub fn clear(&self) {
+        self.inner.lock().unwrap().entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The most recent `count` messages which pass `filter`, oldest first.
+    pub fn recent(&self, filter: &LogFilter, count: usize) -> Vec<LogEntry> {
+        let inner = self.inner.lock().unwrap();
+        let mut entries = inner
+            .entries
+            .iter()
+            .rev()
+            .filter(|entry| filter.matches(entry))
+            .take(count)
+            .cloned()
+            .collect::<Vec<_>>();
+        entries.reverse();
+        entries
+    }
+}
+
+impl Log for LogBuffer {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level()
+    }
+
+    fn log(&self, record: &Record) {
+        self.push(
+            record.level(),
+            record.target(),
+            record.args().to_string(),
+            record.file(),
+            record.line(),
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_most_recent_messages() {
+        let buffer = LogBuffer::with_capacity(3);
+        for i in 0..4 {
+            let target = if i % 2 == 0 { "game::ai" } else { "sludge" };
+            buffer.push(Level::Info, target, format!("{}", i), None, None);
+        }
+        buffer.push(Level::Error, "game::ai", "oops".to_owned(), None, Some(3));
+
+        let all = buffer.recent(&LogFilter::default(), usize::MAX);
+        assert_eq!(
+            all.iter().map(|e| e.index).collect::<Vec<_>>(),
+            vec![2, 3, 4]
+        );
+
+        let filter = LogFilter {
+            level: LevelFilter::Info,
+            target: Some("game".to_owned()),
+        };
+        let messages = buffer
+            .recent(&filter, 1)
+            .into_iter()
+            .map(|e| e.message)
+            .collect::<Vec<_>>();
+        assert_eq!(messages, vec!["oops"]);
+
+        buffer.set_level(LevelFilter::Warn);
+        buffer.push(Level::Debug, "sludge", "ignored".to_owned(), None, None);
+        assert_eq!(buffer.len(), 3);
+    }
+}
@@ -5,6 +5,9 @@ use {
     serde::{de::DeserializeOwned, Deserialize, Serialize},
 };
 
+pub mod curve;
+pub mod tween;
+
 pub use mint;
 
 pub use nalgebra::{
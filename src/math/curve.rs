@@ -0,0 +1,355 @@
+//! Cubic Bezier and Catmull-Rom curves, for anything which needs to move smoothly along
+//! a path: bullets, cameras, cutscenes.
+//!
+//! A [`Curve`] wraps a [`CurveShape`] together with a table of arc lengths, so that it
+//! can be followed either by its raw parameter with [`Curve::point`], which speeds up
+//! and slows down depending on how the control points are spaced, or at a constant
+//! speed with [`Curve::point_at_distance`] and [`Curve::point_uniform`]. Curves
+//! serialize as their shape alone; the arc length table is rebuilt when they're
+//! deserialized.
+//!
+//! From Lua, curves are built with `sludge.math.Bezier` and `sludge.math.CatmullRom`:
+//!
+//! ```lua
+//! local rail = sludge.math.CatmullRom({ { x = 0, y = 0 }, { x = 64, y = 32 }, { x = 128, y = 0 } })
+//! local halfway = rail:point_uniform(0.5)
+//! ```
+
+use {
+    serde::{Deserialize, Serialize},
+    std::cmp,
+};
+
+use crate::math::{Point2, Vector2};
+
+/// How many samples are taken along each segment of a curve to measure its length.
+pub const ARC_LENGTH_SAMPLES: usize = 16;
+
+/// A cubic Bezier curve from `p0` to `p3`, pulled towards `p1` and `p2`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CubicBezier {
+    pub p0: Point2<f32>,
+    pub p1: Point2<f32>,
+    pub p2: Point2<f32>,
+    pub p3: Point2<f32>,
+}
+
+impl CubicBezier {
+    pub fn new(p0: Point2<f32>, p1: Point2<f32>, p2: Point2<f32>, p3: Point2<f32>) -> Self {
+        Self { p0, p1, p2, p3 }
+    }
+
+    /// The point at `t`, from `0` to `1`.
+    pub fn point(&self, t: f32) -> Point2<f32> {
+        let u = 1. - t;
+        let coords = self.p0.coords * (u * u * u)
+            + self.p1.coords * (3. * u * u * t)
+            + self.p2.coords * (3. * u * t * t)
+            + self.p3.coords * (t * t * t);
+        Point2::from(coords)
+    }
+
+    /// The derivative of the curve at `t`, from `0` to `1`.
+    pub fn derivative(&self, t: f32) -> Vector2<f32> {
+        let u = 1. - t;
+        (self.p1 - self.p0) * (3. * u * u)
+            + (self.p2 - self.p1) * (6. * u * t)
+            + (self.p3 - self.p2) * (3. * t * t)
+    }
+}
+
+/// A uniform Catmull-Rom spline, which passes through every one of its points. Open
+/// splines are extended past their ends by mirroring their first and last points; closed
+/// splines loop back around to their first point.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CatmullRom {
+    pub points: Vec<Point2<f32>>,
+    #[serde(default)]
+    pub closed: bool,
+}
+
+impl CatmullRom {
+    pub fn new(points: Vec<Point2<f32>>, closed: bool) -> Self {
+        Self { points, closed }
+    }
+
+    /// How many segments there are between points.
+    pub fn segments(&self) -> usize {
+        match self.points.len() {
+            0 | 1 => 0,
+            n if self.closed => n,
+            n => n - 1,
+        }
+    }
+
+    fn control(&self, i: isize) -> Point2<f32> {
+        let n = self.points.len() as isize;
+        if self.closed {
+            self.points[i.rem_euclid(n) as usize]
+        } else if i < 0 {
+            self.points[0] + (self.points[0] - self.points[1])
+        } else if i >= n {
+            let (last, before) = (self.points[n as usize - 1], self.points[n as usize - 2]);
+            last + (last - before)
+        } else {
+            self.points[i as usize]
+        }
+    }
+
+    /// Find the segment `t` falls in and how far along it `t` is.
+    fn locate(&self, t: f32) -> (isize, f32) {
+        let segments = self.segments();
+        let scaled = t.max(0.).min(1.) * segments as f32;
+        let i = (scaled.floor() as usize).min(segments - 1);
+        (i as isize, scaled - i as f32)
+    }
+
+    fn segment(&self, i: isize) -> [Vector2<f32>; 4] {
+        [
+            self.control(i - 1).coords,
+            self.control(i).coords,
+            self.control(i + 1).coords,
+            self.control(i + 2).coords,
+        ]
+    }
+
+    /// The point at `t`, from `0` to `1` over the whole spline.
+    pub fn point(&self, t: f32) -> Point2<f32> {
+        match self.points.len() {
+            0 => return Point2::origin(),
+            1 => return self.points[0],
+            _ => {}
+        }
+
+        let (i, s) = self.locate(t);
+        let [p0, p1, p2, p3] = self.segment(i);
+        let (s2, s3) = (s * s, s * s * s);
+        let coords = (p1 * 2.
+            + (p2 - p0) * s
+            + (p0 * 2. - p1 * 5. + p2 * 4. - p3) * s2
+            + (p1 * 3. - p0 - p2 * 3. + p3) * s3)
+            * 0.5;
+        Point2::from(coords)
+    }
+
+    /// The derivative of the spline at `t`, from `0` to `1` over the whole spline.
+    pub fn derivative(&self, t: f32) -> Vector2<f32> {
+        if self.points.len() < 2 {
+            return Vector2::zeros();
+        }
+
+        let (i, s) = self.locate(t);
+        let [p0, p1, p2, p3] = self.segment(i);
+        let per_segment = ((p2 - p0)
+            + (p0 * 2. - p1 * 5. + p2 * 4. - p3) * (2. * s)
+            + (p1 * 3. - p0 - p2 * 3. + p3) * (3. * s * s))
+            * 0.5;
+        per_segment * self.segments() as f32
+    }
+}
+
+/// The shape of a [`Curve`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum CurveShape {
+    Bezier(CubicBezier),
+    CatmullRom(CatmullRom),
+}
+
+impl CurveShape {
+    pub fn point(&self, t: f32) -> Point2<f32> {
+        match self {
+            Self::Bezier(bezier) => bezier.point(t),
+            Self::CatmullRom(spline) => spline.point(t),
+        }
+    }
+
+    pub fn derivative(&self, t: f32) -> Vector2<f32> {
+        match self {
+            Self::Bezier(bezier) => bezier.derivative(t),
+            Self::CatmullRom(spline) => spline.derivative(t),
+        }
+    }
+
+    fn segments(&self) -> usize {
+        match self {
+            Self::Bezier(_) => 1,
+            Self::CatmullRom(spline) => spline.segments().max(1),
+        }
+    }
+}
+
+/// A curve which can be followed at a constant speed. See the
+/// [module documentation](self) for details.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "CurveShape", into = "CurveShape")]
+pub struct Curve {
+    shape: CurveShape,
+    /// The length of the curve from its start up to each of `ARC_LENGTH_SAMPLES`
+    /// evenly spaced parameters per segment, starting with zero.
+    lengths: Vec<f32>,
+}
+
+impl From<CurveShape> for Curve {
+    fn from(shape: CurveShape) -> Self {
+        Self::new(shape)
+    }
+}
+
+impl From<Curve> for CurveShape {
+    fn from(curve: Curve) -> Self {
+        curve.shape
+    }
+}
+
+impl Curve {
+    pub fn new(shape: CurveShape) -> Self {
+        let samples = shape.segments() * ARC_LENGTH_SAMPLES;
+        let mut lengths = Vec::with_capacity(samples + 1);
+        let mut length = 0.;
+        let mut last = shape.point(0.);
+        lengths.push(0.);
+        for i in 1..=samples {
+            let point = shape.point(i as f32 / samples as f32);
+            length += (point - last).norm();
+            lengths.push(length);
+            last = point;
+        }
+
+        Self { shape, lengths }
+    }
+
+    pub fn bezier(p0: Point2<f32>, p1: Point2<f32>, p2: Point2<f32>, p3: Point2<f32>) -> Self {
+        Self::new(CurveShape::Bezier(CubicBezier::new(p0, p1, p2, p3)))
+    }
+
+    pub fn catmull_rom(points: Vec<Point2<f32>>, closed: bool) -> Self {
+        Self::new(CurveShape::CatmullRom(CatmullRom::new(points, closed)))
+    }
+
+    pub fn shape(&self) -> &CurveShape {
+        &self.shape
+    }
+
+    /// The point at the raw parameter `t`, from `0` to `1`.
+    pub fn point(&self, t: f32) -> Point2<f32> {
+        self.shape.point(t)
+    }
+
+    /// The direction the curve is heading in at the raw parameter `t`, normalized, or
+    /// zero if the curve isn't moving there.
+    pub fn tangent(&self, t: f32) -> Vector2<f32> {
+        self.shape
+            .derivative(t)
+            .try_normalize(f32::EPSILON)
+            .unwrap_or_else(Vector2::zeros)
+    }
+
+    /// The approximate length of the whole curve.
+    pub fn length(&self) -> f32 {
+        *self.lengths.last().unwrap()
+    }
+
+    /// The raw parameter of the point `distance` along the curve, clamped to its ends.
+    /// A NaN distance is treated as zero.
+    pub fn parameter_at_distance(&self, distance: f32) -> f32 {
+        let last = self.lengths.len() - 1;
+        // Written this way around so that NaN ends up here too.
+        if !(distance > 0.) || last == 0 {
+            return 0.;
+        } else if distance >= self.length() {
+            return 1.;
+        }
+
+        // Lengths are only NaN if the curve's points are, in which case any answer will
+        // do as long as it isn't a panic.
+        let i = match self.lengths.binary_search_by(|length| {
+            length
+                .partial_cmp(&distance)
+                .unwrap_or(cmp::Ordering::Greater)
+        }) {
+            Ok(i) => return i as f32 / last as f32,
+            Err(i) => i - 1,
+        };
+
+        let (before, after) = (self.lengths[i], self.lengths[i + 1]);
+        let frac = if after > before {
+            (distance - before) / (after - before)
+        } else {
+            0.
+        };
+        (i as f32 + frac) / last as f32
+    }
+
+    /// The point `distance` along the curve, clamped to its ends.
+    pub fn point_at_distance(&self, distance: f32) -> Point2<f32> {
+        self.point(self.parameter_at_distance(distance))
+    }
+
+    /// The point a fraction `u` of the way along the curve by length, so that following
+    /// `u` from `0` to `1` at a steady rate moves at a constant speed.
+    pub fn point_uniform(&self, u: f32) -> Point2<f32> {
+        self.point_at_distance(u * self.length())
+    }
+
+    /// The direction the curve is heading in a fraction `u` of the way along it by
+    /// length.
+    pub fn tangent_uniform(&self, u: f32) -> Vector2<f32> {
+        self.tangent(self.parameter_at_distance(u * self.length()))
+    }
+
+    /// Sample `count` points spaced evenly along the curve by length, including both ends.
+    /// At least two points are always returned.
+    pub fn resample(&self, count: usize) -> Vec<Point2<f32>> {
+        let n = count.max(2);
+        (0..n)
+            .map(|i| self.point_uniform(i as f32 / (n - 1) as f32))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uniform_points_are_evenly_spaced() {
+        let line = Curve::bezier(
+            Point2::new(0., 0.),
+            Point2::new(1., 0.),
+            Point2::new(2., 0.),
+            Point2::new(30., 0.),
+        );
+        assert!((line.length() - 30.).abs() < 1e-3);
+        assert!((line.point_uniform(0.5).x - 15.).abs() < 0.5);
+
+        let spline = Curve::catmull_rom(
+            vec![
+                Point2::new(0., 0.),
+                Point2::new(10., 0.),
+                Point2::new(10., 10.),
+            ],
+            false,
+        );
+        assert_eq!(spline.point(0.), Point2::new(0., 0.));
+        assert_eq!(spline.point(0.5), Point2::new(10., 0.));
+        assert_eq!(spline.point(1.), Point2::new(10., 10.));
+
+        let json = serde_json::to_string(&spline).unwrap();
+        assert_eq!(serde_json::from_str::<Curve>(&json).unwrap(), spline);
+    }
+
+    #[test]
+    fn non_finite_distances_clamp() {
+        let line = Curve::bezier(
+            Point2::new(0., 0.),
+            Point2::new(1., 0.),
+            Point2::new(2., 0.),
+            Point2::new(3., 0.),
+        );
+        assert_eq!(line.parameter_at_distance(f32::NAN), 0.);
+        assert_eq!(line.parameter_at_distance(f32::NEG_INFINITY), 0.);
+        assert_eq!(line.parameter_at_distance(f32::INFINITY), 1.);
+        assert_eq!(line.point_uniform(f32::NAN), Point2::new(0., 0.));
+    }
+}
@@ -0,0 +1,195 @@
+//! Easing functions, and [`Tween`]s which use them to move between two values or along
+//! a [`Curve`] over a fixed length of time.
+//!
+//! Easings are named the same way in Rust, in serialized data and in Lua, where
+//! `sludge.math.ease(name, t)` applies one to a progress between `0` and `1`:
+//!
+//! ```lua
+//! local t = sludge.math.ease("cubic_in_out", elapsed / duration)
+//! position:set_coords(rail:point_uniform(t))
+//! ```
+
+use {
+    anyhow::{anyhow, Error, Result},
+    serde::{Deserialize, Serialize},
+    std::{f32::consts::PI, str::FromStr},
+};
+
+use crate::math::{curve::Curve, Point2, Vector2};
+
+/// Maps linear progress between `0` and `1` onto eased progress between `0` and `1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Easing {
+    Linear,
+    QuadIn,
+    QuadOut,
+    QuadInOut,
+    CubicIn,
+    CubicOut,
+    CubicInOut,
+    SineIn,
+    SineOut,
+    SineInOut,
+}
+
+impl Default for Easing {
+    fn default() -> Self {
+        Self::Linear
+    }
+}
+
+impl Easing {
+    /// Ease a progress `t`, which is clamped to `[0, 1]` first. NaN is treated as `0`.
+    pub fn apply(self, t: f32) -> f32 {
+        let t = if t.is_nan() { 0. } else { t.max(0.).min(1.) };
+        match self {
+            Self::Linear => t,
+            Self::QuadIn => t * t,
+            Self::QuadOut => 1. - (1. - t) * (1. - t),
+            Self::QuadInOut if t < 0.5 => 2. * t * t,
+            Self::QuadInOut => 1. - (-2. * t + 2.).powi(2) / 2.,
+            Self::CubicIn => t * t * t,
+            Self::CubicOut => 1. - (1. - t).powi(3),
+            Self::CubicInOut if t < 0.5 => 4. * t * t * t,
+            Self::CubicInOut => 1. - (-2. * t + 2.).powi(3) / 2.,
+            Self::SineIn => 1. - (t * PI / 2.).cos(),
+            Self::SineOut => (t * PI / 2.).sin(),
+            Self::SineInOut => -((t * PI).cos() - 1.) / 2.,
+        }
+    }
+}
+
+impl FromStr for Easing {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let easing = match s {
+            "linear" => Self::Linear,
+            "quad_in" => Self::QuadIn,
+            "quad_out" => Self::QuadOut,
+            "quad_in_out" => Self::QuadInOut,
+            "cubic_in" => Self::CubicIn,
+            "cubic_out" => Self::CubicOut,
+            "cubic_in_out" => Self::CubicInOut,
+            "sine_in" => Self::SineIn,
+            "sine_out" => Self::SineOut,
+            "sine_in_out" => Self::SineInOut,
+            other => return Err(anyhow!("unknown easing `{}`", other)),
+        };
+        Ok(easing)
+    }
+}
+
+/// Progress through an eased transition lasting `duration`, in whatever units it's
+/// advanced in (usually seconds or ticks).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Tween {
+    pub duration: f32,
+    pub elapsed: f32,
+    #[serde(default)]
+    pub easing: Easing,
+}
+
+impl Tween {
+    /// A tween which hasn't started yet. A tween with a zero, negative or NaN duration
+    /// is finished immediately.
+    pub fn new(duration: f32, easing: Easing) -> Self {
+        Self {
+            duration,
+            elapsed: 0.,
+            easing,
+        }
+    }
+
+    /// Advance the tween by `dt` and return its eased progress.
+    pub fn advance(&mut self, dt: f32) -> f32 {
+        if dt.is_finite() {
+            self.elapsed = (self.elapsed + dt).max(0.);
+        }
+        self.progress()
+    }
+
+    /// The eased progress between `0` and `1`.
+    pub fn progress(&self) -> f32 {
+        if !(self.duration > 0.) {
+            return 1.;
+        }
+        self.easing.apply(self.elapsed / self.duration)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        !(self.elapsed < self.duration)
+    }
+
+    pub fn reset(&mut self) {
+        self.elapsed = 0.;
+    }
+
+    /// Interpolate between `from` and `to` by the eased progress.
+    pub fn lerp(&self, from: Vector2<f32>, to: Vector2<f32>) -> Vector2<f32> {
+        from.lerp(&to, self.progress())
+    }
+
+    /// The point along `curve` by the eased progress, measured by length so that a
+    /// linear tween moves at a constant speed.
+    pub fn point_along(&self, curve: &Curve) -> Point2<f32> {
+        curve.point_uniform(self.progress())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn easings_start_and_end_in_place() {
+        let all = [
+            "linear",
+            "quad_in",
+            "quad_out",
+            "quad_in_out",
+            "cubic_in",
+            "cubic_out",
+            "cubic_in_out",
+            "sine_in",
+            "sine_out",
+            "sine_in_out",
+        ];
+
+        for name in all.iter() {
+            let easing = name.parse::<Easing>().unwrap();
+            assert!(easing.apply(0.).abs() < 1e-6, "{}", name);
+            assert!((easing.apply(1.) - 1.).abs() < 1e-6, "{}", name);
+            assert_eq!(easing.apply(f32::NAN), easing.apply(0.), "{}", name);
+            assert_eq!(easing.apply(2.), easing.apply(1.), "{}", name);
+        }
+
+        assert!("bouncy".parse::<Easing>().is_err());
+        assert!(Easing::QuadIn.apply(0.5) < 0.5);
+        assert!(Easing::QuadOut.apply(0.5) > 0.5);
+    }
+
+    #[test]
+    fn tweens_follow_curves() {
+        let line = Curve::bezier(
+            Point2::new(0., 0.),
+            Point2::new(10., 0.),
+            Point2::new(20., 0.),
+            Point2::new(30., 0.),
+        );
+
+        let mut tween = Tween::new(4., Easing::Linear);
+        assert_eq!(tween.point_along(&line), Point2::new(0., 0.));
+        tween.advance(2.);
+        assert!((tween.point_along(&line).x - 15.).abs() < 0.5);
+        assert!(!tween.is_finished());
+        tween.advance(f32::NAN);
+        assert_eq!(tween.elapsed, 2.);
+        tween.advance(5.);
+        assert!(tween.is_finished());
+        assert_eq!(tween.point_along(&line), Point2::new(30., 0.));
+
+        assert_eq!(Tween::new(0., Easing::CubicIn).progress(), 1.);
+    }
+}
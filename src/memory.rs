@@ -0,0 +1,256 @@
+//! Rough accounting of where a space's memory is going.
+//!
+//! Worlds have a habit of growing without anyone noticing: bullets which are never
+//! despawned, sprites which are never removed from their batches, and so on. A
+//! [`MemoryReport`] counts how many entities have each type of component and how many
+//! bytes those components take up inline, and how big each resource is, so that a count
+//! which keeps climbing stands out.
+//!
+//! The numbers are approximate. Component sizes only count the component itself and not
+//! anything it owns on the heap, and only components deriving `SimpleComponent` or
+//! `TrackedComponent` have a known size at all; other components are still counted, but
+//! show up with no name or size. Resources are measured the same way, by the size of
+//! the type which was inserted.
+//!
+//! From Lua, `sludge.memory.report()` returns the same report as a table.
+
+use {
+    hashbrown::HashMap,
+    rlua::prelude::*,
+    std::{any::TypeId, mem},
+};
+
+use crate::{
+    ecs::{Component, World},
+    resources::{OwnedResources, Resources, UnifiedResources},
+    SludgeLuaContextExt,
+};
+
+/// The name and size of a component type, registered with `inventory::submit!` by the
+/// component derives so that [`MemoryReport`]s can describe it.
+#[derive(Debug, Clone, Copy)]
+pub struct ComponentLayout {
+    type_id: TypeId,
+    type_name: &'static str,
+    size: usize,
+}
+
+impl ComponentLayout {
+    pub fn of<T: Component>() -> Self {
+        Self {
+            type_id: TypeId::of::<T>(),
+            type_name: std::any::type_name::<T>(),
+            size: mem::size_of::<T>(),
+        }
+    }
+
+    pub fn type_id(&self) -> TypeId {
+        self.type_id
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+inventory::collect!(ComponentLayout);
+
+/// How much of one type of component a world holds.
+#[derive(Debug, Clone)]
+pub struct ComponentUsage {
+    pub type_id: TypeId,
+    /// The Rust type name of the component, if it has a registered [`ComponentLayout`].
+    pub type_name: Option<&'static str>,
+    /// How many entities have the component.
+    pub entities: usize,
+    /// How many bytes the components take up inline, if their size is known.
+    pub bytes: Option<usize>,
+}
+
+/// How big one resource is.
+#[derive(Debug, Clone)]
+pub struct ResourceUsage {
+    pub type_name: &'static str,
+    /// The size of the resource's type. Resources inserted by reference are counted too,
+    /// even though they aren't owned by the container.
+    pub bytes: usize,
+    /// Whether the resource is in the global resources rather than the space's own.
+    pub global: bool,
+}
+
+/// A snapshot of how much memory a world and its resources take up. See the [module
+/// documentation](self) for details.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryReport {
+    pub entities: usize,
+    pub archetypes: usize,
+    /// Every type of component in the world, largest first.
+    pub components: Vec<ComponentUsage>,
+    /// Every resource with a known size, largest first.
+    pub resources: Vec<ResourceUsage>,
+}
+
+impl MemoryReport {
+    /// Count the components in a world and measure every resource, local and global.
+    pub fn new(world: &World, resources: &UnifiedResources) -> Self {
+        let mut report = Self::of_world(world);
+        report.add_resources(&resources.local.borrow(), false);
+        report.add_resources(&resources.global.borrow(), true);
+        report.resources.sort_by(|a, b| b.bytes.cmp(&a.bytes));
+        report
+    }
+
+    /// Count the components in a world, without looking at any resources.
+    pub fn of_world(world: &World) -> Self {
+        let layouts = inventory::iter::<ComponentLayout>
+            .into_iter()
+            .map(|layout| (layout.type_id, *layout))
+            .collect::<HashMap<_, _>>();
+
+        let mut counts = HashMap::<TypeId, usize>::new();
+        let mut entities = 0;
+        for (_, entity_ref) in world.iter() {
+            entities += 1;
+            for type_id in entity_ref.component_types() {
+                *counts.entry(type_id).or_default() += 1;
+            }
+        }
+
+        let mut components = counts
+            .into_iter()
+            .map(|(type_id, count)| {
+                let layout = layouts.get(&type_id);
+                ComponentUsage {
+                    type_id,
+                    type_name: layout.map(ComponentLayout::type_name),
+                    entities: count,
+                    bytes: layout.map(|layout| layout.size * count),
+                }
+            })
+            .collect::<Vec<_>>();
+        components.sort_by(|a, b| {
+            (b.bytes.unwrap_or(0), b.entities).cmp(&(a.bytes.unwrap_or(0), a.entities))
+        });
+
+        Self {
+            entities,
+            archetypes: world.archetypes().len(),
+            components,
+            resources: Vec::new(),
+        }
+    }
+
+    fn add_resources(&mut self, resources: &OwnedResources, global: bool) {
+        self.resources
+            .extend(resources.layouts().map(|(type_name, bytes)| ResourceUsage {
+                type_name,
+                bytes,
+                global,
+            }));
+    }
+
+    /// The total known size of every component in the world.
+    pub fn component_bytes(&self) -> usize {
+        self.components.iter().filter_map(|c| c.bytes).sum()
+    }
+
+    /// The total size of every resource.
+    pub fn resource_bytes(&self) -> usize {
+        self.resources.iter().map(|r| r.bytes).sum()
+    }
+}
+
+impl<'lua> ToLua<'lua> for MemoryReport {
+    fn to_lua(self, lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+        let components = lua.create_table()?;
+        for (i, usage) in self.components.iter().enumerate() {
+            let table = lua.create_table()?;
+            table.set("name", usage.type_name)?;
+            table.set("entities", usage.entities)?;
+            table.set("bytes", usage.bytes)?;
+            components.set(i + 1, table)?;
+        }
+
+        let resources = lua.create_table()?;
+        for (i, usage) in self.resources.iter().enumerate() {
+            let table = lua.create_table()?;
+            table.set("name", usage.type_name)?;
+            table.set("bytes", usage.bytes)?;
+            table.set("global", usage.global)?;
+            resources.set(i + 1, table)?;
+        }
+
+        let table = lua.create_table()?;
+        table.set("entities", self.entities)?;
+        table.set("archetypes", self.archetypes)?;
+        table.set("component_bytes", self.component_bytes())?;
+        table.set("resource_bytes", self.resource_bytes())?;
+        table.set("components", components)?;
+        table.set("resources", resources)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+inventory::submit! {
+    crate::api::Module::parse("sludge.memory", |lua| {
+        let table = lua.create_table_from(vec![(
+            "report",
+            lua.create_function(|lua, ()| {
+                let world = lua.fetch_one::<World>()?;
+                let resources = lua.resources();
+                let report = MemoryReport::new(&world.borrow(), &resources);
+                Ok(report)
+            })?,
+        )])?;
+
+        Ok(LuaValue::Table(table))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ecs::{Disabled, ScContext, SmartComponent};
+
+    /// Implements `SmartComponent` by hand, so it has no registered layout.
+    struct Unregistered;
+
+    impl<'a> SmartComponent<ScContext<'a>> for Unregistered {}
+
+    #[test]
+    fn counts_components_and_resources() {
+        let mut world = World::new();
+        world.spawn((Disabled, Unregistered));
+        world.spawn((Unregistered,));
+        world.spawn((Unregistered,));
+
+        let resources = UnifiedResources::new();
+        resources.local.borrow_mut().insert([0u8; 64]);
+        resources.global.borrow_mut().insert(0u32);
+
+        let report = MemoryReport::new(&world, &resources);
+        assert_eq!(report.entities, 3);
+
+        let disabled = report
+            .components
+            .iter()
+            .find(|c| c.type_id == TypeId::of::<Disabled>())
+            .unwrap();
+        assert_eq!(disabled.entities, 1);
+        assert_eq!(disabled.bytes, Some(0));
+
+        let unregistered = report
+            .components
+            .iter()
+            .find(|c| c.type_id == TypeId::of::<Unregistered>())
+            .unwrap();
+        assert_eq!((unregistered.entities, unregistered.bytes), (3, None));
+
+        assert_eq!(report.resource_bytes(), 64 + 4);
+        assert!(!report.resources[0].global);
+    }
+}
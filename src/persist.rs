@@ -1,20 +1,234 @@
 use {
     anyhow::*,
+    derivative::*,
     hashbrown::HashMap,
     rlua::prelude::*,
-    std::io::{Read, Write},
+    serde::{Deserialize, Serialize},
+    serde_json::Value,
+    std::{
+        any::TypeId,
+        collections::BTreeMap,
+        fs,
+        io::{Read, Write},
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+    },
+    thunderdome::Index,
 };
 
 use crate::{
-    api::*, components::Persistent, ecs::*, resources::Resources, EventArgs, EventName, Scheduler,
-    Space, Wakeup,
+    api::*,
+    components::Persistent,
+    countdown::Timers,
+    ecs::*,
+    resources::Resources,
+    rng::RngResource,
+    task::{Completions, TaskPool},
+    Event, EventArgs, EventName, Scheduler, SludgeLuaContextExt, Space, Wakeup,
 };
 
+pub mod container;
+
+pub use container::{Compression, SaveError, SAVE_VERSION};
+
+/// Broadcast when a save started with [`save_async`] finishes, with the path it was
+/// written to and an error message if it failed.
+pub const SAVED_EVENT: &'static str = "sludge.persist.saved";
+
+/// The format a [`Space`] is saved in.
+///
+/// [`SaveFormat::Eris`] is the only format which can be loaded back in; the others
+/// write a human-readable [`Snapshot`] of the space instead, for debugging broken
+/// saves and for diffing the state of a space at two different points in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveFormat {
+    /// A complete, loadable save, including the state of every Lua thread.
+    Eris,
+    /// A [`Snapshot`] written as pretty-printed JSON.
+    Json,
+    /// A [`Snapshot`] written as pretty-printed RON.
+    Ron,
+}
+
+impl Default for SaveFormat {
+    fn default() -> Self {
+        Self::Eris
+    }
+}
+
+pub type PersistSaver =
+    Arc<dyn for<'lua> Fn(LuaContext<'lua>, Entity) -> LuaResult<LuaValue<'lua>> + Send + Sync>;
+
+pub type PersistLoader =
+    Arc<dyn for<'lua> Fn(LuaContext<'lua>, Entity, LuaValue<'lua>) -> LuaResult<()> + Send + Sync>;
+
+/// How a component is treated when the world is persisted.
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub enum PersistMode {
+    /// The component is left out of saves entirely, and entities are loaded without it.
+    Skip,
+    /// The component is saved by calling `save` instead of its accessor's `to_table`
+    /// method. Once the entity has been respawned from the rest of its components, `load`
+    /// is called with the saved value to reconstruct it.
+    Custom {
+        #[derivative(Debug = "ignore")]
+        save: PersistSaver,
+        #[derivative(Debug = "ignore")]
+        load: PersistLoader,
+    },
+}
+
+/// A persistence policy for a component registered with Lua, overriding the default of
+/// saving it through its accessor's `to_table` method and loading it through its
+/// bundler. This is for components which can't survive a round trip through a save, like
+/// GPU handles or audio instances, and is registered with `inventory::submit!` under the
+/// same name as the component's [`LuaComponent`]:
+///
+/// ```ignore
+/// inventory::submit! {
+///     PersistPolicy::skip::<SpriteAnimation>("SpriteAnimation")
+/// }
+/// ```
+///
+/// Custom-saved values are stored separately from the rest of an entity's components,
+/// so they are never passed to an [`EntityTable`]'s `serialize` or `deserialize` hooks.
+#[derive(Debug, Clone)]
+pub struct PersistPolicy {
+    type_name: &'static str,
+    type_id: TypeId,
+    mode: PersistMode,
+}
+
+impl PersistPolicy {
+    pub fn skip<T: Component>(type_name: &'static str) -> Self {
+        Self {
+            type_name,
+            type_id: TypeId::of::<T>(),
+            mode: PersistMode::Skip,
+        }
+    }
+
+    pub fn custom<T, S, L>(type_name: &'static str, save: S, load: L) -> Self
+    where
+        T: Component,
+        S: for<'lua> Fn(LuaContext<'lua>, Entity) -> LuaResult<LuaValue<'lua>>
+            + Send
+            + Sync
+            + 'static,
+        L: for<'lua> Fn(LuaContext<'lua>, Entity, LuaValue<'lua>) -> LuaResult<()>
+            + Send
+            + Sync
+            + 'static,
+    {
+        Self {
+            type_name,
+            type_id: TypeId::of::<T>(),
+            mode: PersistMode::Custom {
+                save: Arc::new(save),
+                load: Arc::new(load),
+            },
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+
+    pub fn type_id(&self) -> TypeId {
+        self.type_id
+    }
+
+    pub fn mode(&self) -> &PersistMode {
+        &self.mode
+    }
+}
+
+inventory::collect!(PersistPolicy);
+
+/// Every registered [`PersistPolicy`], by component name. Collected once when a
+/// [`Space`] is built and kept in its local resources.
+#[derive(Debug, Clone)]
+pub struct PersistPolicies {
+    named: HashMap<&'static str, PersistPolicy>,
+}
+
+impl PersistPolicies {
+    /// Collect every registered policy. Registering two policies for the same
+    /// component is an error.
+    pub fn new() -> Result<Self> {
+        let mut named = HashMap::new();
+        for policy in inventory::iter::<PersistPolicy> {
+            ensure!(
+                named.insert(policy.type_name, policy.clone()).is_none(),
+                "persistence policy already registered for component `{}`",
+                policy.type_name
+            );
+        }
+
+        Ok(Self { named })
+    }
+
+    /// Collect policies from a list instead of from the ones registered with
+    /// `inventory`. Duplicates are an error, as with [`PersistPolicies::new`].
+    pub fn from_policies(policies: impl IntoIterator<Item = PersistPolicy>) -> Result<Self> {
+        let mut named = HashMap::new();
+        for policy in policies {
+            let type_name = policy.type_name;
+            ensure!(
+                named.insert(type_name, policy).is_none(),
+                "persistence policy already registered for component `{}`",
+                type_name
+            );
+        }
+
+        Ok(Self { named })
+    }
+
+    pub fn get(&self, type_name: &str) -> Option<&PersistPolicy> {
+        self.named.get(type_name)
+    }
+
+    /// Reconstruct the custom-saved components of a freshly loaded entity from a table
+    /// mapping component names to the values returned by their `save` functions.
+    pub fn restore<'lua>(
+        &self,
+        lua: LuaContext<'lua>,
+        entity: Entity,
+        custom: LuaTable<'lua>,
+    ) -> LuaResult<()> {
+        for pair in custom.pairs::<LuaString, LuaValue>() {
+            let (name, value) = pair?;
+            let name = name.to_str()?;
+            match self.get(name).map(PersistPolicy::mode) {
+                Some(PersistMode::Custom { load, .. }) => load(lua, entity, value)?,
+                _ => {
+                    return Err(anyhow!(
+                        "no custom persistence policy registered for component `{}`",
+                        name
+                    ))
+                    .to_lua_err()
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
 /// Create a new table under the `WORLD_TABLE_REGISTRY_KEY` and fill it with a mapping from
 /// 32-bit transient hecs entity IDs to serializer thunks.
+///
+/// Components with a [`PersistPolicy`] are skipped or saved through their policy's
+/// `save` function, in which case their values are kept in a separate `custom` table.
 pub fn record_world_table<'lua>(lua: LuaContext<'lua>, world: &World) -> LuaResult<LuaTable<'lua>> {
     let tmp = lua.fetch_one::<EntityUserDataRegistry>()?;
     let entity_ud_registry = tmp.borrow();
+    let tmp = lua.fetch_one::<PersistPolicies>()?;
+    let policies = tmp.borrow();
 
     let to_table = lua
         .load(include_str!("api/lua/component_value_thunk.lua"))
@@ -38,15 +252,30 @@ pub fn record_world_table<'lua>(lua: LuaContext<'lua>, world: &World) -> LuaResu
         let id = LuaLightUserData(e.id() as *mut _);
         let archetype = entity_ud_registry.get_archetype(lua, e)?;
         let components = lua.create_table()?;
-        for pair in archetype.pairs::<LuaValue, LuaValue>() {
+        let custom = lua.create_table()?;
+        let mut has_custom = false;
+        for pair in archetype.pairs::<LuaString, LuaValue>() {
             let (k, v) = pair?;
-            let t = to_table.call::<_, LuaValue>(v)?;
-            components.set(k, t)?;
+            match policies.get(k.to_str()?).map(PersistPolicy::mode) {
+                Some(PersistMode::Skip) => {}
+                Some(PersistMode::Custom { save, .. }) => {
+                    custom.set(k, save(lua, e)?)?;
+                    has_custom = true;
+                }
+                None => {
+                    let t = to_table.call::<_, LuaValue>(v)?;
+                    components.set(k, t)?;
+                }
+            }
         }
 
         let persisted_entity = lua.create_table()?;
         persisted_entity.set("id", id)?;
 
+        if has_custom {
+            persisted_entity.set("custom", custom)?;
+        }
+
         if let Some(entity_table_key) = maybe_et {
             let entity_table = lua.registry_value::<LuaTable>(&entity_table_key.key)?;
             if let Some(serialize) = entity_table.get::<_, Option<LuaFunction>>("serialize")? {
@@ -69,7 +298,133 @@ pub fn record_world_table<'lua>(lua: LuaContext<'lua>, world: &World) -> LuaResu
     Ok(world_table)
 }
 
-/// Create a new table under the fill it with entries for queued and waiting threads.
+fn record_event_args<'lua>(
+    lua: LuaContext<'lua>,
+    scheduler: &Scheduler,
+    wakeup_table: &LuaTable<'lua>,
+    args: Option<Index>,
+) -> LuaResult<()> {
+    if let Some(args_i) = args {
+        let tmp = scheduler.event_args[args_i]
+            .iter()
+            .map(|k| lua.registry_value::<LuaValue>(k))
+            .collect::<LuaResult<Vec<_>>>()?;
+        wakeup_table.set("args", tmp)?;
+    }
+
+    Ok(())
+}
+
+fn playback_event_args<'lua>(
+    lua: LuaContext<'lua>,
+    scheduler: &mut Scheduler,
+    wakeup_table: &LuaTable<'lua>,
+) -> LuaResult<Option<Index>> {
+    match wakeup_table.get::<_, Option<Vec<LuaValue>>>("args")? {
+        Some(args) => {
+            let args_registered = args
+                .into_iter()
+                .map(|v| lua.create_registry_value(v))
+                .collect::<LuaResult<EventArgs>>()?;
+            Ok(Some(scheduler.event_args.insert(args_registered)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn record_delayed_args<'lua>(
+    lua: LuaContext<'lua>,
+    event_table: &LuaTable<'lua>,
+    args: &Option<EventArgs>,
+) -> LuaResult<()> {
+    if let Some(args) = args {
+        let tmp = args
+            .iter()
+            .map(|k| lua.registry_value::<LuaValue>(k))
+            .collect::<LuaResult<Vec<_>>>()?;
+        event_table.set("args", tmp)?;
+    }
+
+    Ok(())
+}
+
+fn playback_delayed_args<'lua>(
+    lua: LuaContext<'lua>,
+    event_table: &LuaTable<'lua>,
+) -> LuaResult<Option<EventArgs>> {
+    event_table
+        .get::<_, Option<Vec<LuaValue>>>("args")?
+        .map(|args| {
+            args.into_iter()
+                .map(|v| lua.create_registry_value(v))
+                .collect::<LuaResult<EventArgs>>()
+        })
+        .transpose()
+}
+
+/// Record a delayed event due `ticks` ticks from now. Events delayed inside of delayed
+/// events are flattened into a single delay.
+fn record_delayed_event<'lua>(
+    lua: LuaContext<'lua>,
+    ticks: u64,
+    event: &Event,
+) -> LuaResult<LuaTable<'lua>> {
+    let event_table = lua.create_table()?;
+    event_table.set("ticks", ticks)?;
+
+    let (kind, thread, args) = match event {
+        Event::Delayed {
+            ticks: more,
+            event: inner,
+        } => return record_delayed_event(lua, ticks + more, inner),
+        Event::Broadcast { name, args } => {
+            event_table.set("event", &*name.0)?;
+            ("event", None, args)
+        }
+        Event::Notify { thread, args } => ("notify", Some(thread), args),
+        Event::Kill { thread, args } => ("kill", Some(thread), args),
+        Event::Call { thread, args } => ("call", Some(thread), args),
+    };
+
+    event_table.set("type", kind)?;
+    if let Some(thread) = thread {
+        event_table.set("thread", lua.registry_value::<LuaThread>(thread)?)?;
+    }
+    record_delayed_args(lua, &event_table, args)?;
+
+    Ok(event_table)
+}
+
+fn playback_delayed_event<'lua>(
+    lua: LuaContext<'lua>,
+    event_table: &LuaTable<'lua>,
+) -> Result<Event> {
+    let args = playback_delayed_args(lua, event_table)?;
+    let kind = event_table.get::<_, LuaString>("type")?;
+    let kind = kind.to_str()?;
+    if kind == "event" {
+        let name = EventName(event_table.get::<_, LuaString>("event")?.to_str()?.into());
+        return Ok(Event::Broadcast { name, args });
+    }
+
+    let thread = lua.create_registry_value(event_table.get::<_, LuaThread>("thread")?)?;
+    Ok(match kind {
+        "notify" => Event::Notify { thread, args },
+        "kill" => Event::Kill { thread, args },
+        "call" => Event::Call { thread, args },
+        other => bail!("unknown persisted delayed event type `{}`", other),
+    })
+}
+
+/// Create a new table and fill it with entries for all live threads, as well as the
+/// queued wakeups and event waits which refer to them.
+///
+/// Wakeups and event waits which refer to threads which have since been invalidated
+/// (woken by some other wakeup, or dead) are skipped. Timed wakeups are recorded
+/// relative to the scheduler's current tick, so that they resume the same number of
+/// ticks after being loaded as they would have had the scheduler never been saved.
+/// Delayed events, from `broadcast_in` and `notify_in`, are recorded the same way, in
+/// the order they're due in.
 pub fn record_scheduler_table<'lua>(
     lua: LuaContext<'lua>,
     scheduler: &Scheduler,
@@ -77,11 +432,18 @@ pub fn record_scheduler_table<'lua>(
     let waiting_table = lua.create_table()?;
     let queue_table = lua.create_table()?;
 
+    let locals_table = lua.create_table()?;
+    let names_table = lua.create_table()?;
+    let locals = per_thread_table(lua, THREAD_LOCALS_REGISTRY_KEY)?;
+    let names = per_thread_table(lua, THREAD_NAMES_REGISTRY_KEY)?;
+
     let mut threads = HashMap::new();
     for (i, thread) in scheduler.threads.iter() {
         let thread = lua.registry_value::<LuaThread>(thread)?;
         threads.insert(i, thread.clone());
-        waiting_table.set(thread, lua.create_table()?)?;
+        waiting_table.set(thread.clone(), lua.create_table()?)?;
+        locals_table.set(thread.clone(), locals.get::<_, LuaValue>(thread.clone())?)?;
+        names_table.set(thread.clone(), names.get::<_, LuaValue>(thread)?)?;
     }
 
     for (event_name, waiting_thread) in scheduler
@@ -89,72 +451,43 @@ pub fn record_scheduler_table<'lua>(
         .iter()
         .flat_map(|(ev, ts)| ts.iter().map(move |t| (ev, t)))
     {
-        let thread_entry = waiting_table.get::<_, LuaTable>(threads[waiting_thread].clone())?;
+        let thread = match threads.get(waiting_thread) {
+            Some(thread) => thread.clone(),
+            None => continue,
+        };
+        let thread_entry = waiting_table.get::<_, LuaTable>(thread)?;
         thread_entry.set(thread_entry.len()? + 1, &*event_name.0)?;
     }
 
     for wakeup in scheduler.queue.iter() {
+        let thread = match threads.get(&wakeup.thread()) {
+            Some(thread) => thread.clone(),
+            None => continue,
+        };
+
         let wakeup_table = lua.create_table()?;
+        wakeup_table.set("thread", thread)?;
+
         match wakeup {
-            Wakeup::Call { thread: i, args } => {
+            Wakeup::Call { args, .. } => {
                 wakeup_table.set("type", "call")?;
-                wakeup_table.set("thread", threads[i].clone())?;
-
-                if let Some(args_i) = *args {
-                    let tmp = scheduler.event_args[args_i]
-                        .iter()
-                        .map(|k| lua.registry_value::<LuaValue>(k))
-                        .collect::<LuaResult<Vec<_>>>()?;
-                    wakeup_table.set("args", tmp)?;
-                }
+                record_event_args(lua, scheduler, &wakeup_table, *args)?;
             }
-            Wakeup::Notify { thread: i, args } => {
+            Wakeup::Notify { args, .. } => {
                 wakeup_table.set("type", "notify")?;
-                wakeup_table.set("thread", threads[i].clone())?;
-
-                if let Some(args_i) = *args {
-                    let tmp = scheduler.event_args[args_i]
-                        .iter()
-                        .map(|k| lua.registry_value::<LuaValue>(k))
-                        .collect::<LuaResult<Vec<_>>>()?;
-                    wakeup_table.set("args", tmp)?;
-                }
+                record_event_args(lua, scheduler, &wakeup_table, *args)?;
             }
-            Wakeup::Kill { thread: i, args } => {
+            Wakeup::Kill { args, .. } => {
                 wakeup_table.set("type", "kill")?;
-                wakeup_table.set("thread", threads[i].clone())?;
-
-                if let Some(args_i) = *args {
-                    let tmp = scheduler.event_args[args_i]
-                        .iter()
-                        .map(|k| lua.registry_value::<LuaValue>(k))
-                        .collect::<LuaResult<Vec<_>>>()?;
-                    wakeup_table.set("args", tmp)?;
-                }
+                record_event_args(lua, scheduler, &wakeup_table, *args)?;
             }
-            Wakeup::Broadcast {
-                thread: i,
-                name,
-                args,
-            } => {
+            Wakeup::Broadcast { name, args, .. } => {
                 wakeup_table.set("type", "event")?;
-                wakeup_table.set("thread", threads[i].clone())?;
                 wakeup_table.set("event", &*name.0)?;
-
-                if let Some(args_i) = *args {
-                    let tmp = scheduler.event_args[args_i]
-                        .iter()
-                        .map(|k| lua.registry_value::<LuaValue>(k))
-                        .collect::<LuaResult<Vec<_>>>()?;
-                    wakeup_table.set("args", tmp)?;
-                }
+                record_event_args(lua, scheduler, &wakeup_table, *args)?;
             }
-            Wakeup::Timed {
-                thread: i,
-                scheduled_for,
-            } => {
+            Wakeup::Timed { scheduled_for, .. } => {
                 wakeup_table.set("type", "timed")?;
-                wakeup_table.set("thread", threads[i].clone())?;
                 wakeup_table.set(
                     "scheduled_for",
                     scheduled_for.saturating_sub(scheduler.discrete),
@@ -165,106 +498,149 @@ pub fn record_scheduler_table<'lua>(
         queue_table.set(queue_table.len()? + 1, wakeup_table)?;
     }
 
+    let conditions_table = lua.create_table()?;
+    for (index, predicate) in scheduler.conditions.iter() {
+        let thread = match threads.get(index) {
+            Some(thread) => thread.clone(),
+            None => continue,
+        };
+
+        let condition_table = lua.create_table()?;
+        condition_table.set("thread", thread)?;
+        condition_table.set("predicate", lua.registry_value::<LuaFunction>(predicate)?)?;
+        conditions_table.set(conditions_table.len()? + 1, condition_table)?;
+    }
+
+    let delayed_table = lua.create_table()?;
+    for (&(due, _), event) in scheduler.delayed_events.iter() {
+        let ticks = due.saturating_sub(scheduler.discrete);
+        delayed_table.set(
+            delayed_table.len()? + 1,
+            record_delayed_event(lua, ticks, event)?,
+        )?;
+    }
+
     let scheduler_table = lua.create_table()?;
 
     scheduler_table.set("queue", queue_table)?;
+    scheduler_table.set("delayed", delayed_table)?;
+    scheduler_table.set("conditions", conditions_table)?;
     scheduler_table.set("waiting", waiting_table)?;
+    scheduler_table.set("locals", locals_table)?;
+    scheduler_table.set("names", names_table)?;
 
     Ok(scheduler_table)
 }
 
+/// Reconstruct the state recorded by [`record_scheduler_table`] into a scheduler.
+///
+/// Every recorded thread is re-registered with the scheduler exactly once, regardless
+/// of how many wakeups or event waits refer to it, so that threads which are only
+/// waiting on events or on a `notify` survive the round trip as well as threads
+/// with pending timed wakeups.
 pub fn playback_scheduler_table<'lua>(
     lua: LuaContext<'lua>,
     scheduler_table: LuaTable<'lua>,
     scheduler: &mut Scheduler,
 ) -> Result<()> {
     let queue_table = scheduler_table.get::<_, LuaTable>("queue")?;
-    // FIXME(sleffy): persisting the scheduler table does not yet persist threads which
-    // exist outside the queue
-    let _slots_table = lua.registry_value::<LuaTable>(&scheduler.slots)?;
-    for item in queue_table.sequence_values::<LuaTable>() {
-        let table = item?;
-        let thread = table.get::<_, LuaThread>("thread")?;
+    let waiting_table = scheduler_table.get::<_, LuaTable>("waiting")?;
+    let slots = lua.registry_value::<LuaTable>(&scheduler.slots)?;
+
+    let lookup = |scheduler: &Scheduler, thread: LuaThread<'lua>| -> Result<Index> {
+        let slot = slots
+            .get::<_, Option<u32>>(thread)?
+            .ok_or_else(|| anyhow!("wakeup refers to a thread which was not recorded"))?;
+        scheduler.threads.contains_slot(slot).ok_or_else(|| {
+            anyhow!(
+                "wakeup refers to thread slot {}, which has no thread in the scheduler",
+                slot
+            )
+        })
+    };
+
+    for pair in waiting_table.pairs::<LuaThread, LuaTable>() {
+        let (thread, events) = pair?;
         let key = lua.create_registry_value(thread.clone())?;
-        let i = scheduler.threads.insert(key);
-        match table.get::<_, LuaString>("type")?.to_str()? {
-            "call" => {
-                let event_args =
-                    if let Some(args) = table.get::<_, Option<Vec<LuaValue>>>("args")? {
-                        let args_registered = args
-                            .into_iter()
-                            .map(|v| lua.create_registry_value(v))
-                            .collect::<LuaResult<EventArgs>>()?;
-                        let i = scheduler.event_args.insert(args_registered);
-                        Some(i)
-                    } else {
-                        None
-                    };
-                scheduler.queue.push(Wakeup::Call {
-                    thread: i,
-                    args: event_args,
-                });
-            }
-            "notify" => {
-                let event_args =
-                    if let Some(args) = table.get::<_, Option<Vec<LuaValue>>>("args")? {
-                        let args_registered = args
-                            .into_iter()
-                            .map(|v| lua.create_registry_value(v))
-                            .collect::<LuaResult<EventArgs>>()?;
-                        let i = scheduler.event_args.insert(args_registered);
-                        Some(i)
-                    } else {
-                        None
-                    };
-                scheduler.queue.push(Wakeup::Notify {
-                    thread: i,
-                    args: event_args,
-                });
-            }
-            "kill" => {
-                let event_args =
-                    if let Some(args) = table.get::<_, Option<Vec<LuaValue>>>("args")? {
-                        let args_registered = args
-                            .into_iter()
-                            .map(|v| lua.create_registry_value(v))
-                            .collect::<LuaResult<EventArgs>>()?;
-                        let i = scheduler.event_args.insert(args_registered);
-                        Some(i)
-                    } else {
-                        None
-                    };
-                scheduler.queue.push(Wakeup::Kill {
-                    thread: i,
-                    args: event_args,
-                });
-            }
-            "event" => {
-                let event_name = EventName(table.get::<_, LuaString>("event")?.to_str()?.into());
-                let event_args =
-                    if let Some(args) = table.get::<_, Option<Vec<LuaValue>>>("args")? {
-                        let args_registered = args
-                            .into_iter()
-                            .map(|v| lua.create_registry_value(v))
-                            .collect::<LuaResult<EventArgs>>()?;
-                        let i = scheduler.event_args.insert(args_registered);
-                        Some(i)
-                    } else {
-                        None
-                    };
-                scheduler.queue.push(Wakeup::Broadcast {
-                    thread: i,
-                    name: event_name,
-                    args: event_args,
-                });
+        let index = scheduler.threads.insert(key);
+        slots.set(thread, index.slot())?;
+
+        for event in events.sequence_values::<LuaString>() {
+            let event_name = EventName(event?.to_str()?.into());
+            let waiting = scheduler.waiting.entry(event_name).or_default();
+            if let Err(i) = waiting.binary_search(&index) {
+                waiting.insert(i, index);
             }
-            "timed" => {
-                scheduler.queue.push(Wakeup::Timed {
-                    thread: i,
-                    scheduled_for: scheduler.discrete + table.get::<_, u64>("scheduled_for")?,
-                });
+        }
+    }
+
+    for item in queue_table.sequence_values::<LuaTable>() {
+        let table = item?;
+        let i = lookup(&*scheduler, table.get::<_, LuaThread>("thread")?)?;
+        let wakeup = match table.get::<_, LuaString>("type")?.to_str()? {
+            "call" => Wakeup::Call {
+                thread: i,
+                args: playback_event_args(lua, scheduler, &table)?,
+            },
+            "notify" => Wakeup::Notify {
+                thread: i,
+                args: playback_event_args(lua, scheduler, &table)?,
+            },
+            "kill" => Wakeup::Kill {
+                thread: i,
+                args: playback_event_args(lua, scheduler, &table)?,
+            },
+            "event" => Wakeup::Broadcast {
+                thread: i,
+                name: EventName(table.get::<_, LuaString>("event")?.to_str()?.into()),
+                args: playback_event_args(lua, scheduler, &table)?,
+            },
+            "timed" => Wakeup::Timed {
+                thread: i,
+                scheduled_for: scheduler.discrete + table.get::<_, u64>("scheduled_for")?,
+            },
+            other => bail!("unknown persisted wakeup type `{}`", other),
+        };
+
+        scheduler.queue.push(wakeup);
+    }
+
+    // The weak per-thread tables aren't reachable from the persisted permanents, so their
+    // entries for live threads are carried along with the threads themselves.
+    for (field, key) in &[
+        ("locals", THREAD_LOCALS_REGISTRY_KEY),
+        ("names", THREAD_NAMES_REGISTRY_KEY),
+    ] {
+        if let Some(recorded) = scheduler_table.get::<_, Option<LuaTable>>(*field)? {
+            let table = per_thread_table(lua, key)?;
+            for pair in recorded.pairs::<LuaThread, LuaValue>() {
+                let (thread, value) = pair?;
+                table.set(thread, value)?;
             }
-            _ => unreachable!(),
+        }
+    }
+
+    // Saves from before delayed events were persisted have none.
+    if let Some(delayed_table) = scheduler_table.get::<_, Option<LuaTable>>("delayed")? {
+        for item in delayed_table.sequence_values::<LuaTable>() {
+            let table = item?;
+            let due = scheduler.discrete + table.get::<_, u64>("ticks")?;
+            let event = playback_delayed_event(lua, &table)?;
+            scheduler
+                .delayed_events
+                .insert((due, scheduler.delayed_count), event);
+            scheduler.delayed_count += 1;
+        }
+    }
+
+    // Saves from before `wait_until` was handled by the scheduler have no conditions.
+    if let Some(conditions_table) = scheduler_table.get::<_, Option<LuaTable>>("conditions")? {
+        for item in conditions_table.sequence_values::<LuaTable>() {
+            let table = item?;
+            let i = lookup(&*scheduler, table.get::<_, LuaThread>("thread")?)?;
+            let predicate = table.get::<_, LuaFunction>("predicate")?;
+            let key = lua.create_registry_value(predicate)?;
+            scheduler.conditions.push((i, key));
         }
     }
 
@@ -278,6 +654,9 @@ pub fn persist<'lua, W: Write>(lua: LuaContext<'lua>, space: &Space, writer: W)
 
     let persisted_table =
         lua.create_table_from(vec![("world", world_table), ("scheduler", scheduler_table)])?;
+    persisted_table.set("rng", crate::rng::record(lua)?)?;
+    persisted_table.set("timers", crate::countdown::record(lua)?)?;
+    persisted_table.set("world_events", record_subscriptions(lua)?)?;
 
     lua.set_dump_setting("path", true)?;
     lua.dump_value(writer, permanents, persisted_table)?;
@@ -286,6 +665,9 @@ pub fn persist<'lua, W: Write>(lua: LuaContext<'lua>, space: &Space, writer: W)
 }
 
 pub fn unpersist<'lua, R: Read>(lua: LuaContext<'lua>, space: &Space, reader: R) -> Result<()> {
+    // The save may refer to values from lazy modules which haven't been loaded into
+    // this space yet, and those have to be registered as permanents before undumping.
+    crate::api::load_all_lazy(lua)?;
     let permanents = lua.named_registry_value::<_, LuaTable>(PERMANENTS_DE_TABLE_REGISTRY_KEY)?;
     lua.set_dump_setting("path", true)?;
     let persisted_table = lua.undump_value::<_, _, LuaTable>(reader, permanents)?;
@@ -295,6 +677,487 @@ pub fn unpersist<'lua, R: Read>(lua: LuaContext<'lua>, space: &Space, reader: R)
         persisted_table.get("scheduler")?,
         &mut *space.scheduler()?.borrow_mut(),
     )?;
+    crate::rng::playback(lua, persisted_table.get("rng")?)?;
+    crate::countdown::playback(lua, persisted_table.get("timers")?)?;
+    playback_subscriptions(lua, persisted_table.get("world_events")?)?;
+
+    Ok(())
+}
+
+/// Persist a space and wrap it in a [save container](container), compressed with
+/// `compression`. This is what [`Space::save`] writes.
+pub fn persist_container<'lua, W: Write>(
+    lua: LuaContext<'lua>,
+    space: &Space,
+    writer: W,
+    compression: Compression,
+) -> Result<()> {
+    let mut buf = Vec::new();
+    persist(lua, space, &mut buf)?;
+    container::write_container(writer, &buf, compression)
+}
+
+/// Check a [save container](container) and unpersist the save inside it. This is what
+/// [`Space::load`] reads; if the bytes aren't a container at all, or the container is
+/// damaged or from another version of the save format, the error is a [`SaveError`].
+/// Saves from before the container format have to be loaded with
+/// [`unpersist_legacy`] instead.
+pub fn unpersist_container<'lua, R: Read>(
+    lua: LuaContext<'lua>,
+    space: &Space,
+    reader: R,
+) -> Result<()> {
+    let payload = container::read_container(reader)?;
+    unpersist(lua, space, &payload[..])
+}
+
+/// Unpersist a save from before the container format, which is a raw Eris dump. This is
+/// what [`Space::load_legacy`] reads. There's nothing in a raw dump to check it against,
+/// so this should only be used on files known to be old saves; anything which is a
+/// container is refused, since it should go through [`unpersist_container`].
+pub fn unpersist_legacy<'lua, R: Read>(
+    lua: LuaContext<'lua>,
+    space: &Space,
+    mut reader: R,
+) -> Result<()> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    ensure!(
+        !container::is_container(&bytes),
+        "save has a container header, so it isn't a legacy save; load it with `Space::load`"
+    );
+    unpersist(lua, space, &bytes[..])
+}
+
+/// A persisted entity, as seen in a [`Snapshot`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EntitySnapshot {
+    pub id: u32,
+    pub components: Value,
+}
+
+/// A pending wakeup in the scheduler queue, as seen in a [`Snapshot`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WakeupSnapshot {
+    /// The slot of the thread being woken.
+    pub thread: u32,
+    /// One of `call`, `notify`, `kill`, `event` or `timed`, as in the Eris save.
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event: Option<String>,
+    /// For timed wakeups, how many ticks from now the thread will be woken.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ticks: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<Value>,
+}
+
+/// The scheduler's metadata, as seen in a [`Snapshot`]. Thread states themselves
+/// can't be represented outside of Eris, so threads are only identified by their
+/// slots.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SchedulerSnapshot {
+    pub threads: Vec<u32>,
+    /// Debug names given to threads with `sludge.thread.set_name`, by slot.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub names: BTreeMap<u32, String>,
+    pub waiting: BTreeMap<String, Vec<u32>>,
+    pub queue: Vec<WakeupSnapshot>,
+    /// Threads waiting on a `sludge.thread.wait_until` condition.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<u32>,
+}
+
+/// A human-readable snapshot of the persistent state of a space: every
+/// [`Persistent`] entity's components, exactly as they would be written to an Eris
+/// save, along with the scheduler's metadata and the state of the space's RNG and timers.
+///
+/// Functions, threads and userdata can't be represented, and are left out with a
+/// warning. Snapshots compare equal if the state they record is the same, so they are
+/// handy for checking in tests that two spaces agree.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub world: Vec<EntitySnapshot>,
+    pub scheduler: SchedulerSnapshot,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rng: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timers: Option<Value>,
+}
+
+/// Convert a Lua value into JSON, skipping (and warning about) anything which can't be
+/// represented. Tables whose keys are exactly `1..=n` become arrays; all other tables
+/// become objects with stringified keys.
+fn lua_to_json<'lua>(value: LuaValue<'lua>, path: &str) -> LuaResult<Option<Value>> {
+    let json = match value {
+        LuaValue::Nil => Value::Null,
+        LuaValue::Boolean(b) => Value::Bool(b),
+        LuaValue::Integer(i) => Value::from(i),
+        LuaValue::Number(n) => Value::from(n),
+        LuaValue::String(s) => Value::String(s.to_str()?.to_owned()),
+        LuaValue::Table(table) => {
+            let mut entries = Vec::new();
+            for pair in table.pairs::<LuaValue, LuaValue>() {
+                let (k, v) = pair?;
+                let key = match k {
+                    LuaValue::Integer(i) => i.to_string(),
+                    LuaValue::Number(n) => n.to_string(),
+                    LuaValue::String(s) => s.to_str()?.to_owned(),
+                    other => {
+                        log::warn!(
+                            "skipping {} key in `{}` while snapshotting",
+                            other.type_name(),
+                            path
+                        );
+                        continue;
+                    }
+                };
+
+                if let Some(v) = lua_to_json(v, &format!("{}.{}", path, key))? {
+                    entries.push((key, v));
+                }
+            }
+
+            let n = entries.len();
+            let mut indices = entries
+                .iter()
+                .map(|(k, _)| k.parse::<usize>().ok())
+                .collect::<Option<Vec<_>>>();
+            if let Some(indices) = indices.as_mut() {
+                indices.sort_unstable();
+            }
+
+            if n > 0 && indices.map_or(false, |is| is.into_iter().eq(1..=n)) {
+                entries.sort_by_key(|(k, _)| k.parse::<usize>().unwrap());
+                Value::Array(entries.into_iter().map(|(_, v)| v).collect())
+            } else {
+                Value::Object(entries.into_iter().collect())
+            }
+        }
+        other => {
+            log::warn!(
+                "skipping {} at `{}` while snapshotting",
+                other.type_name(),
+                path
+            );
+            return Ok(None);
+        }
+    };
+
+    Ok(Some(json))
+}
+
+fn snapshot_event_args<'lua>(
+    lua: LuaContext<'lua>,
+    scheduler: &Scheduler,
+    args: Option<Index>,
+    path: &str,
+) -> LuaResult<Vec<Value>> {
+    let mut values = Vec::new();
+    if let Some(args_i) = args {
+        for (i, key) in scheduler.event_args[args_i].iter().enumerate() {
+            let value = lua.registry_value::<LuaValue>(key)?;
+            values.extend(lua_to_json(value, &format!("{}.args.{}", path, i + 1))?);
+        }
+    }
+
+    Ok(values)
+}
+
+/// Record the scheduler's metadata. Like [`record_scheduler_table`], wakeups and
+/// event waits for dead threads are skipped and timed wakeups are recorded relative to
+/// the current tick.
+pub fn snapshot_scheduler<'lua>(
+    lua: LuaContext<'lua>,
+    scheduler: &Scheduler,
+) -> LuaResult<SchedulerSnapshot> {
+    let mut snapshot = SchedulerSnapshot::default();
+    let mut slots = HashMap::new();
+    for (i, key) in scheduler.threads.iter() {
+        slots.insert(i, i.slot());
+        snapshot.threads.push(i.slot());
+
+        let thread = lua.registry_value::<LuaThread>(key)?;
+        if let Some(name) = crate::api::thread_name(lua, &thread)? {
+            snapshot.names.insert(i.slot(), name);
+        }
+    }
+    snapshot.threads.sort_unstable();
+
+    for (event_name, waiting) in scheduler.waiting.iter() {
+        let mut threads = waiting
+            .iter()
+            .filter_map(|t| slots.get(t).copied())
+            .collect::<Vec<_>>();
+        if !threads.is_empty() {
+            threads.sort_unstable();
+            snapshot
+                .waiting
+                .insert((&*event_name.0).to_owned(), threads);
+        }
+    }
+
+    snapshot.conditions = scheduler
+        .conditions
+        .iter()
+        .filter_map(|(t, _)| slots.get(t).copied())
+        .collect();
+    snapshot.conditions.sort_unstable();
+
+    // The queue is a max-heap, so sort it into the order it'll be woken in.
+    let mut queue = scheduler.queue.iter().collect::<Vec<_>>();
+    queue.sort_by(|a, b| b.cmp(a));
+    for wakeup in queue {
+        let thread = match slots.get(&wakeup.thread()) {
+            Some(&slot) => slot,
+            None => continue,
+        };
+
+        let path = format!("scheduler.queue.{}", snapshot.queue.len() + 1);
+        let (kind, event, ticks, args) = match wakeup {
+            Wakeup::Call { args, .. } => ("call", None, None, *args),
+            Wakeup::Notify { args, .. } => ("notify", None, None, *args),
+            Wakeup::Kill { args, .. } => ("kill", None, None, *args),
+            Wakeup::Broadcast { name, args, .. } => {
+                ("event", Some((&*name.0).to_owned()), None, *args)
+            }
+            Wakeup::Timed { scheduled_for, .. } => (
+                "timed",
+                None,
+                Some(scheduled_for.saturating_sub(scheduler.discrete)),
+                None,
+            ),
+        };
+
+        snapshot.queue.push(WakeupSnapshot {
+            thread,
+            kind: kind.to_owned(),
+            event,
+            ticks,
+            args: snapshot_event_args(lua, scheduler, args, &path)?,
+        });
+    }
+
+    Ok(snapshot)
+}
+
+/// Take a [`Snapshot`] of a space.
+pub fn snapshot<'lua>(lua: LuaContext<'lua>, space: &Space) -> Result<Snapshot> {
+    let world_table = record_world_table(lua, &*space.world()?.borrow())?;
+
+    let mut world = Vec::new();
+    for entry in world_table.sequence_values::<LuaTable>() {
+        let entry = entry?;
+        let id = match entry.get::<_, LuaValue>("id")? {
+            LuaValue::LightUserData(ud) => ud.0 as usize as u32,
+            other => bail!("unexpected entity id type `{}`", other.type_name()),
+        };
+        let path = format!("world.{}", id);
+        let components = lua_to_json(entry.get("components")?, &path)?.unwrap_or(Value::Null);
+        if entry
+            .get::<_, Option<LuaFunction>>("deserialize")?
+            .is_some()
+        {
+            log::warn!(
+                "skipping `deserialize` hook of `{}` while snapshotting",
+                path
+            );
+        }
+        world.push(EntitySnapshot { id, components });
+    }
+    world.sort_by_key(|e| e.id);
+
+    let scheduler = snapshot_scheduler(lua, &*space.scheduler()?.borrow())?;
+    let rng = match space.fetch_one::<RngResource>() {
+        Ok(rng) => Some(serde_json::to_value(&*rng.borrow())?),
+        Err(_) => None,
+    };
+    let timers = match space.fetch_one::<Timers>() {
+        Ok(timers) => Some(serde_json::to_value(&*timers.borrow())?),
+        Err(_) => None,
+    };
+
+    Ok(Snapshot {
+        world,
+        scheduler,
+        rng,
+        timers,
+    })
+}
+
+/// Write a [`Snapshot`] of a space in a human-readable format.
+pub fn persist_snapshot<'lua, W: Write>(
+    lua: LuaContext<'lua>,
+    space: &Space,
+    writer: W,
+    format: SaveFormat,
+) -> Result<()> {
+    write_snapshot(&snapshot(lua, space)?, writer, format)
+}
+
+fn write_snapshot<W: Write>(snapshot: &Snapshot, mut writer: W, format: SaveFormat) -> Result<()> {
+    match format {
+        SaveFormat::Json => serde_json::to_writer_pretty(&mut writer, &snapshot)?,
+        SaveFormat::Ron => {
+            let pretty = ron::ser::PrettyConfig::new();
+            writer.write_all(ron::ser::to_string_pretty(&snapshot, pretty)?.as_bytes())?;
+        }
+        SaveFormat::Eris => bail!("Eris saves are written with `persist`, not `persist_snapshot`"),
+    }
 
     Ok(())
 }
+
+/// The state of a space captured for [`save_async`], ready to be written out.
+enum Captured {
+    Eris(Vec<u8>),
+    Snapshot(Snapshot, SaveFormat),
+}
+
+/// Counts temporary files, so that saves running at the same time never share one.
+static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+
+/// Write `bytes` to `path` through a temporary file next to it, so that a save which
+/// fails partway through never clobbers the last good one. Every call gets its own
+/// temporary file, so overlapping saves to the same path can't corrupt each other;
+/// whichever finishes last wins.
+fn write_atomically(path: &Path, bytes: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp = PathBuf::from(tmp);
+
+    fs::write(&tmp, bytes).with_context(|| format!("error writing save to {:?}", tmp))?;
+    fs::rename(&tmp, path).with_context(|| format!("error moving save into {:?}", path))?;
+    Ok(())
+}
+
+/// Save the space to a file on disk, returning a [promise](crate::task::Promise) which
+/// resolves to the path once the file has been written. [`SAVED_EVENT`] is broadcast
+/// when the save finishes, too.
+///
+/// The state of the space is captured before this returns, so the save is consistent
+/// with the tick it was started on no matter what happens to the space afterwards.
+/// For the human-readable formats the capture is a [`Snapshot`], and formatting it
+/// happens on the space's [`TaskPool`] along with the file IO.
+///
+/// For Eris saves, the capture *is* the serialization: Eris dumps straight out of the
+/// Lua state, which can only be used from the thread which owns it, so the whole dump
+/// happens before this returns and costs as much of the frame as [`Space::save`] does.
+/// Only checksumming and compressing it into its [container](container) and writing it
+/// out happen on the pool. The snapshot formats can't be loaded back, so they're for
+/// inspecting saves rather than a way around this.
+///
+/// The `path` is a path on disk rather than in the [`Filesystem`](crate::filesystem::Filesystem),
+/// since the pool can't share it; use
+/// [`Filesystem::user_path`](crate::filesystem::Filesystem::user_path) to find one.
+/// The file is written to a temporary file alongside it first and then moved into
+/// place, so a failed save leaves any previous save at `path` intact.
+pub fn save_async<'lua>(
+    lua: LuaContext<'lua>,
+    space: &Space,
+    path: impl Into<PathBuf>,
+    format: SaveFormat,
+) -> Result<LuaAnyUserData<'lua>> {
+    let path = path.into();
+    let captured = match format {
+        SaveFormat::Eris => {
+            let mut buf = Vec::new();
+            persist(lua, space, &mut buf)?;
+            Captured::Eris(buf)
+        }
+        _ => Captured::Snapshot(snapshot(lua, space)?, format),
+    };
+
+    let promise = crate::task::promise(lua)?;
+    let key = lua.create_registry_value(promise.clone())?;
+
+    let pool = lua.fetch_one::<TaskPool>()?;
+    let completions = lua.fetch_one::<Completions>()?;
+    let job_path = path.clone();
+    pool.borrow().spawn_then(
+        &completions.borrow(),
+        move || {
+            let bytes = match captured {
+                Captured::Eris(bytes) => {
+                    let mut buf = Vec::new();
+                    container::write_container(&mut buf, &bytes, Compression::default())?;
+                    buf
+                }
+                Captured::Snapshot(snapshot, format) => {
+                    let mut buf = Vec::new();
+                    write_snapshot(&snapshot, &mut buf, format)?;
+                    buf
+                }
+            };
+            write_atomically(&job_path, &bytes)
+        },
+        move |lua, result| {
+            let display = path.to_string_lossy().into_owned();
+            let error = result.as_ref().err().map(|err| format!("{:#}", err));
+            let outcome = result.and_then(|()| Ok(display.clone().to_lua(lua)?));
+            crate::task::resolve(lua, key, outcome)?;
+            lua.broadcast(SAVED_EVENT, (display, error))?;
+            Ok(())
+        },
+    );
+
+    Ok(promise)
+}
+
+inventory::submit! {
+    crate::api::Module::parse("sludge.persist", |lua| {
+        let table = lua.create_table()?;
+
+        table.set(
+            "restore",
+            lua.create_function(|lua, (entity, custom): (LuaEntity, LuaTable)| {
+                let tmp = lua.fetch_one::<PersistPolicies>()?;
+                let policies = tmp.borrow();
+                policies.restore(lua, entity.into(), custom)
+            })?,
+        )?;
+
+        table.set("SAVED_EVENT", SAVED_EVENT)?;
+
+        Ok(LuaValue::Table(table))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wakeups_for_missing_threads_are_errors() -> Result<()> {
+        let lua = Lua::new();
+        lua.context(|lua| -> Result<()> {
+            let mut scheduler = Scheduler::new(lua)?;
+            let thread = lua.create_thread(lua.create_function(|_, ()| Ok(()))?)?;
+            lua.registry_value::<LuaTable>(&scheduler.slots)?
+                .set(thread.clone(), 7)?;
+
+            let scheduler_table = lua
+                .load(
+                    r#"
+                    local thread = ...
+                    return { waiting = {}, queue = { { thread = thread, type = "call" } } }
+                    "#,
+                )
+                .call::<_, LuaTable>(thread)?;
+            let err = playback_scheduler_table(lua, scheduler_table, &mut scheduler)
+                .expect_err("the thread was never added to the scheduler");
+            assert!(err.to_string().contains("slot 7"), "{}", err);
+
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,270 @@
+//! The container Eris saves are wrapped in when they're written by [`Space::save`].
+//!
+//! A raw Eris dump has nothing in it to say it's a save at all, so a truncated or
+//! corrupted file only shows up as a confusing error from deep inside the undumper, or
+//! worse, loads into a space which is subtly wrong. The container puts a small header in
+//! front of the dump:
+//!
+//! | bytes | contents |
+//! |-------|----------|
+//! | 8 | the magic bytes `SLUDGESV` |
+//! | 2 | the version of the container format |
+//! | 4 | the version of the save format, [`SAVE_VERSION`] |
+//! | 1 | how the payload is compressed; see [`Compression`] |
+//! | 8 | the length of the payload once it's decompressed |
+//! | 4 | a CRC-32 checksum of the payload as it's stored |
+//!
+//! Every number is little-endian. Opening a save checks all of these before anything
+//! is handed to Lua, and fails with a [`SaveError`] which says whether the save is
+//! corrupt or just from another version, so that the two can be told apart with
+//! `err.downcast_ref::<SaveError>()`. Saves written before there was a container don't
+//! start with the magic bytes, so [`Space::load`] refuses them as
+//! [`SaveError::NotASave`]; they can only be read as raw Eris dumps, explicitly, with
+//! [`Space::load_legacy`].
+//!
+//! Saves are compressed with zstd when the `compression` feature is enabled, which
+//! makes them several times smaller. Compressed saves can still be recognized without
+//! the feature, but not opened.
+//!
+//! [`Space::save`]: crate::Space::save
+//! [`Space::load`]: crate::Space::load
+//! [`Space::load_legacy`]: crate::Space::load_legacy
+
+use {
+    anyhow::*,
+    std::{
+        convert::TryInto,
+        io::{Read, Write},
+    },
+    thiserror::Error,
+};
+
+/// The magic bytes every save container starts with.
+pub const SAVE_MAGIC: &[u8; 8] = b"SLUDGESV";
+
+/// The version of the layout of the container itself.
+pub const CONTAINER_VERSION: u16 = 1;
+
+/// The version of the format of the persisted state inside the container. Bump this
+/// whenever a change to [`persist`](super::persist) means older saves can't be loaded.
+pub const SAVE_VERSION: u32 = 1;
+
+const HEADER_LEN: usize = 8 + 2 + 4 + 1 + 8 + 4;
+
+/// The zstd compression level saves are written with.
+#[cfg(feature = "compression")]
+const ZSTD_LEVEL: i32 = 3;
+
+/// How the payload of a save container is compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// Only available with the `compression` feature.
+    Zstd,
+}
+
+impl Default for Compression {
+    /// Zstd if the `compression` feature is enabled, and no compression otherwise.
+    fn default() -> Self {
+        if cfg!(feature = "compression") {
+            Self::Zstd
+        } else {
+            Self::None
+        }
+    }
+}
+
+impl Compression {
+    fn tag(self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Zstd => 1,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(Self::None),
+            1 => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Why a save container couldn't be opened.
+#[derive(Debug, Error)]
+pub enum SaveError {
+    #[error("not a save file (bad magic bytes)")]
+    NotASave,
+    #[error("save is truncated: expected at least {expected} bytes, found {found}")]
+    Truncated { expected: usize, found: usize },
+    #[error("save container version {found} is not supported (expected {expected})")]
+    ContainerVersion { found: u16, expected: u16 },
+    #[error("save is from version {found} of the save format, but this is version {expected}")]
+    VersionMismatch { found: u32, expected: u32 },
+    #[error("save is corrupt: checksum is {found:#010x}, expected {expected:#010x}")]
+    ChecksumMismatch { found: u32, expected: u32 },
+    #[error("save is corrupt: unknown compression method {0}")]
+    UnknownCompression(u8),
+    #[error("save is compressed with {0:?}, but the `compression` feature is disabled")]
+    CompressionUnavailable(Compression),
+    #[error("save is corrupt: payload should be {expected} bytes, found {found}")]
+    LengthMismatch { expected: u64, found: u64 },
+    #[error("save is corrupt: error decompressing payload")]
+    Decompression(#[source] std::io::Error),
+}
+
+impl SaveError {
+    /// Whether the save is damaged, as opposed to being from a version of the format
+    /// this build can't read.
+    pub fn is_corruption(&self) -> bool {
+        !matches!(
+            self,
+            Self::ContainerVersion { .. }
+                | Self::VersionMismatch { .. }
+                | Self::CompressionUnavailable(_)
+        )
+    }
+}
+
+fn compress(payload: &[u8], compression: Compression) -> Result<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(payload.to_vec()),
+        #[cfg(feature = "compression")]
+        Compression::Zstd => Ok(zstd::stream::encode_all(payload, ZSTD_LEVEL)?),
+        #[cfg(not(feature = "compression"))]
+        Compression::Zstd => bail!(
+            "can't compress a save with {:?} without the `compression` feature",
+            compression
+        ),
+    }
+}
+
+fn decompress(stored: &[u8], compression: Compression) -> std::result::Result<Vec<u8>, SaveError> {
+    match compression {
+        Compression::None => Ok(stored.to_vec()),
+        #[cfg(feature = "compression")]
+        Compression::Zstd => zstd::stream::decode_all(stored).map_err(SaveError::Decompression),
+        #[cfg(not(feature = "compression"))]
+        Compression::Zstd => Err(SaveError::CompressionUnavailable(compression)),
+    }
+}
+
+/// Wrap a raw Eris dump in a save container and write it out.
+pub fn write_container<W: Write>(
+    mut writer: W,
+    payload: &[u8],
+    compression: Compression,
+) -> Result<()> {
+    let stored = compress(payload, compression)?;
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(SAVE_MAGIC);
+    header.extend_from_slice(&CONTAINER_VERSION.to_le_bytes());
+    header.extend_from_slice(&SAVE_VERSION.to_le_bytes());
+    header.push(compression.tag());
+    header.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    header.extend_from_slice(&crc32fast::hash(&stored).to_le_bytes());
+
+    writer.write_all(&header)?;
+    writer.write_all(&stored)?;
+    Ok(())
+}
+
+/// Whether `bytes` start like a save container, rather than a raw Eris dump.
+pub fn is_container(bytes: &[u8]) -> bool {
+    bytes.starts_with(SAVE_MAGIC)
+}
+
+/// Check a save container and return the raw Eris dump inside it.
+pub fn open_container(bytes: &[u8]) -> std::result::Result<Vec<u8>, SaveError> {
+    if !is_container(bytes) {
+        return Err(SaveError::NotASave);
+    } else if bytes.len() < HEADER_LEN {
+        return Err(SaveError::Truncated {
+            expected: HEADER_LEN,
+            found: bytes.len(),
+        });
+    }
+
+    let (header, stored) = bytes.split_at(HEADER_LEN);
+    let container_version = u16::from_le_bytes(header[8..10].try_into().unwrap());
+    let save_version = u32::from_le_bytes(header[10..14].try_into().unwrap());
+    let compression_tag = header[14];
+    let length = u64::from_le_bytes(header[15..23].try_into().unwrap());
+    let checksum = u32::from_le_bytes(header[23..27].try_into().unwrap());
+
+    if container_version != CONTAINER_VERSION {
+        return Err(SaveError::ContainerVersion {
+            found: container_version,
+            expected: CONTAINER_VERSION,
+        });
+    }
+
+    // A corrupt save is more important to report than an old one, since an old save
+    // which is also corrupt won't load once it's been migrated either.
+    let found = crc32fast::hash(stored);
+    if found != checksum {
+        return Err(SaveError::ChecksumMismatch {
+            found,
+            expected: checksum,
+        });
+    }
+
+    if save_version != SAVE_VERSION {
+        return Err(SaveError::VersionMismatch {
+            found: save_version,
+            expected: SAVE_VERSION,
+        });
+    }
+
+    let compression = Compression::from_tag(compression_tag)
+        .ok_or(SaveError::UnknownCompression(compression_tag))?;
+    let payload = decompress(stored, compression)?;
+    if payload.len() as u64 != length {
+        return Err(SaveError::LengthMismatch {
+            expected: length,
+            found: payload.len() as u64,
+        });
+    }
+
+    Ok(payload)
+}
+
+/// Read a whole save container and return the raw Eris dump inside it.
+pub fn read_container<R: Read>(mut reader: R) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    Ok(open_container(&bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corruption_and_version_mismatch_are_told_apart() {
+        let payload = b"not really an eris dump, but close enough".repeat(16);
+        let mut sealed = Vec::new();
+        write_container(&mut sealed, &payload, Compression::default()).unwrap();
+        assert_eq!(open_container(&sealed).unwrap(), payload);
+
+        let mut corrupt = sealed.clone();
+        *corrupt.last_mut().unwrap() ^= 0xff;
+        let err = open_container(&corrupt).unwrap_err();
+        assert!(matches!(err, SaveError::ChecksumMismatch { .. }));
+        assert!(err.is_corruption());
+
+        let mut newer = sealed.clone();
+        newer[10..14].copy_from_slice(&(SAVE_VERSION + 1).to_le_bytes());
+        let err = open_container(&newer).unwrap_err();
+        assert!(matches!(err, SaveError::VersionMismatch { .. }));
+        assert!(!err.is_corruption());
+
+        assert!(matches!(
+            open_container(&sealed[..HEADER_LEN - 1]),
+            Err(SaveError::Truncated { .. })
+        ));
+        assert!(!is_container(&payload));
+        assert!(matches!(open_container(&payload), Err(SaveError::NotASave)));
+    }
+}
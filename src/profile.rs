@@ -0,0 +1,188 @@
+//! Instrumentation for finding slow Lua scripts.
+//!
+//! Time spent in Lua shows up in a Rust profiler as one big lump under the scheduler's
+//! update, with no way to tell which script it belongs to. With profiling turned on
+//! with [`Scheduler::set_profiling`](crate::Scheduler::set_profiling), the scheduler
+//! instead times every resume of every thread, and measures how much the Lua heap grew
+//! while the thread ran. Samples are added up per thread name, as given with
+//! `sludge.thread.set_name`; threads without a name are lumped together.
+//!
+//! The worst offenders are reported in
+//! [`SchedulerStats::scripts`](crate::SchedulerStats::scripts), sorted by total time.
+//! From Lua, profiling is turned on with `sludge.thread.set_profiling(true)`, and the
+//! results read with `sludge.thread.stats().scripts`.
+//!
+//! Profiling costs a couple of calls into Lua per resume, so it's off by default.
+
+use {
+    hashbrown::HashMap,
+    rlua::prelude::*,
+    std::{
+        sync::Mutex,
+        time::{Duration, Instant},
+    },
+};
+
+use crate::api;
+
+pub const COLLECTGARBAGE_REGISTRY_KEY: &'static str = "sludge.profile.collectgarbage";
+
+/// The name samples of threads without a debug name are filed under.
+pub const UNNAMED_THREAD: &'static str = "<unnamed>";
+
+/// How many threads are reported in [`SchedulerStats`](crate::SchedulerStats).
+pub const TOP_THREADS: usize = 8;
+
+/// Stash the raw `collectgarbage` function in the registry, so that the heap can still
+/// be measured once the global has been hidden by a sandbox.
+pub(crate) fn stash_collectgarbage<'lua>(lua: LuaContext<'lua>) -> LuaResult<()> {
+    let collectgarbage = lua.globals().get::<_, LuaValue>("collectgarbage")?;
+    lua.set_named_registry_value(COLLECTGARBAGE_REGISTRY_KEY, collectgarbage)
+}
+
+/// The size of the Lua heap, in bytes, or `None` if it can't be measured.
+pub(crate) fn heap_size(lua: LuaContext) -> Option<i64> {
+    let collectgarbage = lua
+        .named_registry_value::<_, Option<LuaFunction>>(COLLECTGARBAGE_REGISTRY_KEY)
+        .ok()??;
+    let kilobytes = collectgarbage.call::<_, f64>("count").ok()?;
+    Some((kilobytes * 1024.) as i64)
+}
+
+/// Everything measured about the threads sharing a name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ThreadProfile {
+    pub name: String,
+    pub resumes: u64,
+    /// Wall time spent in all resumes, added up.
+    pub time: Duration,
+    /// The longest single resume.
+    pub max_time: Duration,
+    /// How much the Lua heap grew across all resumes, in bytes. Garbage collected
+    /// during a resume is credited to whichever thread happened to be running, so this
+    /// can be negative.
+    pub memory: i64,
+}
+
+/// Converted to a table with the same fields; times are in seconds.
+impl<'lua> ToLua<'lua> for ThreadProfile {
+    fn to_lua(self, lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+        let table = lua.create_table()?;
+        table.set("name", self.name)?;
+        table.set("resumes", self.resumes)?;
+        table.set("time", self.time.as_secs_f64())?;
+        table.set("max_time", self.max_time.as_secs_f64())?;
+        table.set("memory", self.memory)?;
+        Ok(LuaValue::Table(table))
+    }
+}
+
+/// The per-thread samples collected by a scheduler. Shared between a scheduler and its
+/// [`SchedulerQueue`](crate::SchedulerQueue), so that the results can be read while the
+/// scheduler is busy running threads.
+#[derive(Debug, Default)]
+pub struct ScriptProfiler {
+    enabled: bool,
+    threads: HashMap<String, ThreadProfile>,
+}
+
+impl ScriptProfiler {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Turn profiling on or off. Samples collected so far are kept.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Throw away every sample collected so far.
+    pub fn reset(&mut self) {
+        self.threads.clear();
+    }
+
+    /// The `n` thread names with the most time spent, worst first.
+    pub fn top(&self, n: usize) -> Vec<ThreadProfile> {
+        let mut profiles = self.threads.values().cloned().collect::<Vec<_>>();
+        profiles.sort_by(|a, b| b.time.cmp(&a.time).then_with(|| a.name.cmp(&b.name)));
+        profiles.truncate(n);
+        profiles
+    }
+
+    fn record(&mut self, name: String, time: Duration, memory: i64) {
+        let profile = self
+            .threads
+            .entry(name.clone())
+            .or_insert_with(|| ThreadProfile {
+                name,
+                ..ThreadProfile::default()
+            });
+        profile.resumes += 1;
+        profile.time += time;
+        profile.max_time = profile.max_time.max(time);
+        profile.memory += memory;
+    }
+}
+
+/// A single resume being measured.
+#[derive(Debug)]
+pub(crate) struct Sample {
+    start: Instant,
+    heap: Option<i64>,
+}
+
+impl Sample {
+    /// Start measuring a resume, if profiling is turned on.
+    pub fn begin(lua: LuaContext, profiler: &Mutex<ScriptProfiler>) -> Option<Self> {
+        if !profiler.lock().unwrap().enabled {
+            return None;
+        }
+
+        let heap = heap_size(lua);
+        Some(Self {
+            start: Instant::now(),
+            heap,
+        })
+    }
+
+    /// Finish measuring a resume of `thread`, and add it to the thread's profile.
+    pub fn finish<'lua>(
+        self,
+        lua: LuaContext<'lua>,
+        thread: &LuaThread<'lua>,
+        profiler: &Mutex<ScriptProfiler>,
+    ) {
+        let time = self.start.elapsed();
+        let memory = match (self.heap, heap_size(lua)) {
+            (Some(before), Some(after)) => after - before,
+            _ => 0,
+        };
+        let name = api::thread_name(lua, thread)
+            .ok()
+            .flatten()
+            .unwrap_or_else(|| UNNAMED_THREAD.to_owned());
+
+        profiler.lock().unwrap().record(name, time, memory);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn top_sorts_by_total_time() {
+        let mut profiler = ScriptProfiler::default();
+        profiler.record("ai".to_owned(), Duration::from_millis(3), 64);
+        profiler.record("hud".to_owned(), Duration::from_millis(1), 0);
+        profiler.record("ai".to_owned(), Duration::from_millis(2), -32);
+
+        let top = profiler.top(1);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].name, "ai");
+        assert_eq!(top[0].resumes, 2);
+        assert_eq!(top[0].time, Duration::from_millis(5));
+        assert_eq!(top[0].max_time, Duration::from_millis(3));
+        assert_eq!(top[0].memory, 32);
+    }
+}
@@ -0,0 +1,327 @@
+//! Reflection for components, for use by editors and inspectors.
+//!
+//! Any component implementing `Serialize` and `Deserialize` can be registered for
+//! reflection with `inventory::submit!`, usually right next to its
+//! [`LuaComponent`](crate::api::LuaComponent) registration and under the same name:
+//!
+//! ```ignore
+//! inventory::submit! {
+//!     ReflectedComponent::new::<Position>("Position")
+//! }
+//! ```
+//!
+//! The [`ReflectionRegistry`] collects every registered component, and can read any
+//! of them from an entity as a `serde_json::Value` or write them back from one. The
+//! names of a component's fields are found by asking its `Deserialize` implementation
+//! which fields it expects, so components which deserialize through a proxy type
+//! (`#[serde(from = "...")]`) report the proxy's fields.
+
+use {
+    anyhow::*,
+    hashbrown::HashMap,
+    serde::{
+        de::{self, DeserializeOwned, Visitor},
+        forward_to_deserialize_any, Serialize,
+    },
+    serde_json::Value,
+    std::{any::TypeId, fmt},
+};
+
+use crate::ecs::{Component, Entity, EntityBuilder, ScContext, SmartComponent, World};
+
+/// The kind of value held by a field, as seen through serde.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldKind {
+    Null,
+    Bool,
+    Number,
+    String,
+    Array,
+    Object,
+}
+
+impl From<&Value> for FieldKind {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Null => Self::Null,
+            Value::Bool(_) => Self::Bool,
+            Value::Number(_) => Self::Number,
+            Value::String(_) => Self::String,
+            Value::Array(_) => Self::Array,
+            Value::Object(_) => Self::Object,
+        }
+    }
+}
+
+/// A single field of a reflected component's value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldDescriptor {
+    pub name: String,
+    pub kind: FieldKind,
+}
+
+/// A component type registered for reflection.
+#[derive(Clone)]
+pub struct ReflectedComponent {
+    name: &'static str,
+    type_id: TypeId,
+    field_names: fn() -> Option<&'static [&'static str]>,
+    has: fn(&World, Entity) -> bool,
+    get: fn(&World, Entity) -> Result<Value>,
+    set: fn(&mut World, Entity, Value) -> Result<()>,
+    remove: fn(&mut World, Entity) -> Result<()>,
+}
+
+impl fmt::Debug for ReflectedComponent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReflectedComponent")
+            .field("name", &self.name)
+            .field("fields", &(self.field_names)())
+            .finish()
+    }
+}
+
+impl ReflectedComponent {
+    pub fn new<T>(name: &'static str) -> Self
+    where
+        T: Component + Serialize + DeserializeOwned + for<'a> SmartComponent<ScContext<'a>>,
+    {
+        Self {
+            name,
+            type_id: TypeId::of::<T>(),
+            field_names: struct_fields::<T>,
+            has: |world, entity| world.get_raw::<T>(entity).is_ok(),
+            get: |world, entity| Ok(serde_json::to_value(&*world.get_raw::<T>(entity)?)?),
+            set: |world, entity, value| {
+                let component = serde_json::from_value::<T>(value)?;
+                // The existing component's borrow has to end before the world can be
+                // changed, so check for it first rather than matching on `get_mut`.
+                if world.get_raw::<T>(entity).is_ok() {
+                    *world.get_mut::<T>(entity)? = component;
+                } else {
+                    world.insert_one(entity, component)?;
+                }
+                Ok(())
+            },
+            remove: |world, entity| {
+                world.remove_one::<T>(entity)?;
+                Ok(())
+            },
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn type_id(&self) -> TypeId {
+        self.type_id
+    }
+
+    /// The names of the component's fields, if it deserializes from a struct.
+    pub fn field_names(&self) -> Option<&'static [&'static str]> {
+        (self.field_names)()
+    }
+
+    /// Describe the fields of the component on `entity`. Components which don't
+    /// serialize to a map are described as a single field with an empty name.
+    pub fn describe(&self, world: &World, entity: Entity) -> Result<Vec<FieldDescriptor>> {
+        Ok(match self.get(world, entity)? {
+            Value::Object(map) => map
+                .iter()
+                .map(|(name, value)| FieldDescriptor {
+                    name: name.clone(),
+                    kind: FieldKind::from(value),
+                })
+                .collect(),
+            other => vec![FieldDescriptor {
+                name: String::new(),
+                kind: FieldKind::from(&other),
+            }],
+        })
+    }
+
+    pub fn has(&self, world: &World, entity: Entity) -> bool {
+        (self.has)(world, entity)
+    }
+
+    /// Serialize the component on `entity`.
+    pub fn get(&self, world: &World, entity: Entity) -> Result<Value> {
+        (self.get)(world, entity)
+            .with_context(|| anyhow!("error reflecting component `{}`", self.name))
+    }
+
+    /// Deserialize a value and write it to `entity`, replacing the component if it
+    /// already has one and inserting it if it doesn't.
+    pub fn set(&self, world: &mut World, entity: Entity, value: Value) -> Result<()> {
+        (self.set)(world, entity, value)
+            .with_context(|| anyhow!("error setting reflected component `{}`", self.name))
+    }
+
+    pub fn remove(&self, world: &mut World, entity: Entity) -> Result<()> {
+        (self.remove)(world, entity)
+    }
+}
+
+inventory::collect!(ReflectedComponent);
+
+/// Every component registered for reflection, by name.
+#[derive(Debug, Clone)]
+pub struct ReflectionRegistry {
+    named: HashMap<&'static str, ReflectedComponent>,
+}
+
+impl ReflectionRegistry {
+    pub fn new() -> Self {
+        let mut named = HashMap::new();
+        for component in inventory::iter::<ReflectedComponent> {
+            assert!(
+                named.insert(component.name, component.clone()).is_none(),
+                "component already registered for reflection with name `{}`",
+                component.name
+            );
+        }
+
+        Self { named }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ReflectedComponent> {
+        self.named.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ReflectedComponent> + '_ {
+        self.named.values()
+    }
+
+    /// The reflected components present on an entity.
+    pub fn components_of<'a>(
+        &'a self,
+        world: &'a World,
+        entity: Entity,
+    ) -> impl Iterator<Item = &'a ReflectedComponent> + 'a {
+        self.iter().filter(move |c| c.has(world, entity))
+    }
+
+    /// Serialize every reflected component on an entity into a map from component
+    /// names to values.
+    pub fn to_json(&self, world: &World, entity: Entity) -> Result<serde_json::Map<String, Value>> {
+        self.components_of(world, entity)
+            .map(|c| Ok((c.name.to_owned(), c.get(world, entity)?)))
+            .collect()
+    }
+
+    /// Write every component in a map produced by [`ReflectionRegistry::to_json`] to an
+    /// entity.
+    pub fn apply_json(
+        &self,
+        world: &mut World,
+        entity: Entity,
+        components: serde_json::Map<String, Value>,
+    ) -> Result<()> {
+        for (name, value) in components {
+            self.get(&name)
+                .ok_or_else(|| anyhow!("no component registered for reflection as `{}`", name))?
+                .set(world, entity, value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Spawn an entity with every component in a map produced by
+    /// [`ReflectionRegistry::to_json`]. If any of the components can't be written, the
+    /// entity is despawned again before the error is returned.
+    pub fn spawn_json(
+        &self,
+        world: &mut World,
+        components: serde_json::Map<String, Value>,
+    ) -> Result<Entity> {
+        let entity = world.spawn(EntityBuilder::new().build());
+        match self.apply_json(world, entity, components) {
+            Ok(()) => Ok(entity),
+            Err(err) => {
+                let _ = world.despawn(entity);
+                Err(err)
+            }
+        }
+    }
+}
+
+impl Default for ReflectionRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Find the field names a type expects to deserialize, by handing it a deserializer
+/// which records them and then bails out.
+fn struct_fields<T: DeserializeOwned>() -> Option<&'static [&'static str]> {
+    let mut fields = None;
+    let _ = T::deserialize(FieldTracer {
+        fields: &mut fields,
+    });
+    fields
+}
+
+#[derive(Debug)]
+struct TraceError;
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "field tracing finished")
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+impl de::Error for TraceError {
+    fn custom<T: fmt::Display>(_msg: T) -> Self {
+        TraceError
+    }
+}
+
+struct FieldTracer<'a> {
+    fields: &'a mut Option<&'static [&'static str]>,
+}
+
+impl<'de, 'a> de::Deserializer<'de> for FieldTracer<'a> {
+    type Error = TraceError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, TraceError> {
+        Err(TraceError)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        *self.fields = Some(fields);
+        Err(TraceError)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple tuple_struct map enum
+        identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Example {
+        x: f32,
+        y: f32,
+    }
+
+    #[test]
+    fn traces_struct_fields() {
+        assert_eq!(struct_fields::<Example>(), Some(&["x", "y"][..]));
+        assert_eq!(struct_fields::<String>(), None);
+    }
+}
@@ -6,7 +6,7 @@
 //! which allows by-type access to singletons both local to a space
 //! (for example an [ECS world](crate::ecs::World)) and global
 //! context types which are shared between all spaces in your program
-//! (for example the [graphics context](crate::graphics::Graphics)).
+//! (for example the graphics context.)
 //!
 //! There are currently three different containers for these singleton
 //! resources, of which currently only two implement the `Resources`
@@ -377,6 +377,8 @@ fn downcast_send_sync<T: Any>(
 #[derive(Debug)]
 pub struct OwnedResources<'a> {
     map: HashMap<TypeId, Arc<RwLock<StoredResource<'a>>>>,
+    /// The name and size of every type which has been inserted, for memory reports.
+    layouts: HashMap<TypeId, (&'static str, usize)>,
 }
 
 impl<'a> OwnedResources<'a> {
@@ -384,9 +386,25 @@ impl<'a> OwnedResources<'a> {
     pub fn new() -> Self {
         Self {
             map: HashMap::new(),
+            layouts: HashMap::new(),
         }
     }
 
+    fn record_layout<T: Fetchable>(&mut self) {
+        self.layouts.insert(
+            TypeId::of::<T>(),
+            (any::type_name::<T>(), std::mem::size_of::<T>()),
+        );
+    }
+
+    /// The type name and size in bytes of every resource in the container. See
+    /// [`MemoryReport`](crate::memory::MemoryReport).
+    pub fn layouts(&self) -> impl Iterator<Item = (&'static str, usize)> + '_ {
+        self.map
+            .keys()
+            .filter_map(move |type_id| self.layouts.get(type_id).copied())
+    }
+
     /// Check whether or not this map contains a value of some type.
     pub fn has_value<T: Fetchable>(&self) -> bool {
         self.map.contains_key(&TypeId::of::<T>())
@@ -396,6 +414,7 @@ impl<'a> OwnedResources<'a> {
     pub fn insert<T: Fetchable + 'static>(&mut self, res: T) {
         let type_id = TypeId::of::<T>();
         assert!(!self.map.contains_key(&type_id));
+        self.record_layout::<T>();
         let entry = StoredResource::Owned {
             pointer: Box::new(res),
         };
@@ -409,6 +428,7 @@ impl<'a> OwnedResources<'a> {
     pub fn insert_ref<'b: 'a, T: Fetchable>(&mut self, res: &'b T) {
         let type_id = TypeId::of::<T>();
         assert!(!self.map.contains_key(&type_id));
+        self.record_layout::<T>();
         let entry = StoredResource::Immutable {
             pointer: unsafe {
                 NonNull::new_unchecked(res as &'a (dyn Any + Send + Sync) as *const _ as *mut _)
@@ -423,6 +443,7 @@ impl<'a> OwnedResources<'a> {
     pub fn insert_mut<'b: 'a, T: Fetchable>(&mut self, res: &'b mut T) {
         let type_id = TypeId::of::<T>();
         assert!(!self.map.contains_key(&type_id));
+        self.record_layout::<T>();
         let entry = StoredResource::Mutable {
             pointer: unsafe {
                 NonNull::new_unchecked(res as &'a mut (dyn Any + Send + Sync) as *mut _)
@@ -435,6 +456,7 @@ impl<'a> OwnedResources<'a> {
     /// Remove a type from the map. This is rarely useful, but the functionality is still here.
     /// Returns `Some` with the removed value if it's found; otherwise `None`.
     pub fn remove<T: Fetchable>(&mut self) -> Option<T> {
+        self.layouts.remove(&TypeId::of::<T>());
         self.map
             .remove(&TypeId::of::<T>())
             .and_then(|t| Arc::try_unwrap(t).ok())
@@ -444,6 +466,18 @@ impl<'a> OwnedResources<'a> {
             })
     }
 
+    /// Replace the entry for a type, returning the old entry. `None` removes the entry.
+    fn replace_entry(
+        &mut self,
+        type_id: TypeId,
+        entry: Option<Arc<RwLock<StoredResource<'a>>>>,
+    ) -> Option<Arc<RwLock<StoredResource<'a>>>> {
+        match entry {
+            Some(entry) => self.map.insert(type_id, entry),
+            None => self.map.remove(&type_id),
+        }
+    }
+
     /// Fetch a single resource from the container. Will return `Err(NotFound)` if the
     /// map does not contain a value of that type. The `NotFound` error implements a couple
     /// useful traits making it easy to use in sludge's usual use cases; please see its
@@ -605,6 +639,96 @@ pub trait Resources<'a> {
     fn fetch<T: FetchAll<'a>>(&self) -> Result<T::Fetched, NotFound> {
         T::fetch_components(self)
     }
+
+    /// Temporarily shadow a resource with another value of the same type for the duration
+    /// of a closure, for example to swap in a mock `Filesystem` during a test. The value
+    /// doesn't have to replace anything; if there's no resource of that type, it's simply
+    /// there until the closure returns. Either way, the container goes back to the way it
+    /// was afterwards, even if the closure panics, and the value is dropped.
+    ///
+    /// Like `borrow_mut`, this works on the "most-local" `OwnedResources`. On a
+    /// `UnifiedResources`, the value is put in the local layer, and since lookups check the
+    /// local layer first, it shadows a global resource of the same type too. To shadow
+    /// just a global resource, scope it on the `global` field instead; keep in mind that a
+    /// local resource of the same type will still take precedence over it.
+    ///
+    /// Only lookups made inside the closure see the new value. `Shared` handles fetched
+    /// before the scope began keep pointing to the original.
+    ///
+    /// ```rust
+    /// use sludge::resources::{Resources, UnifiedResources};
+    ///
+    /// let resources = UnifiedResources::new();
+    /// resources.global.borrow_mut().insert::<&'static str>("real");
+    ///
+    /// resources.scope_with::<&'static str, _>("mock", || {
+    ///     assert_eq!(*resources.fetch_one::<&'static str>().unwrap().borrow(), "mock");
+    /// });
+    ///
+    /// assert_eq!(*resources.fetch_one::<&'static str>().unwrap().borrow(), "real");
+    /// ```
+    fn scope_with<T: Fetchable, R>(&self, value: T, f: impl FnOnce() -> R) -> R {
+        let type_id = TypeId::of::<T>();
+        let entry = Arc::new(RwLock::new(StoredResource::Owned {
+            pointer: Box::new(value),
+        }));
+        let shadowed = {
+            let mut resources = self.borrow_mut();
+            resources.record_layout::<T>();
+            resources.replace_entry(type_id, Some(entry))
+        };
+
+        let _restore = RestoreOnDrop {
+            resources: self,
+            type_id,
+            shadowed,
+            _marker: PhantomData,
+        };
+
+        f()
+    }
+
+    /// Temporarily shadow every resource in `overlay` for the duration of a closure. This
+    /// works like [`scope_with`](Resources::scope_with), except that the resources are
+    /// shared with `overlay` rather than moved in, so changes made to them inside the
+    /// closure are still there in `overlay` afterwards, ready for the next scope.
+    fn scope_overlay<R>(&self, overlay: &OwnedResources<'a>, f: impl FnOnce() -> R) -> R {
+        let mut restore = Vec::new();
+        for (&type_id, entry) in overlay.map.iter() {
+            let shadowed = {
+                let mut resources = self.borrow_mut();
+                if let Some(&layout) = overlay.layouts.get(&type_id) {
+                    resources.layouts.insert(type_id, layout);
+                }
+                resources.replace_entry(type_id, Some(entry.clone()))
+            };
+            restore.push(RestoreOnDrop {
+                resources: self,
+                type_id,
+                shadowed,
+                _marker: PhantomData,
+            });
+        }
+
+        let _restore = restore;
+        f()
+    }
+}
+
+/// Puts a shadowed resource back when a `Resources::scope_with` scope ends.
+struct RestoreOnDrop<'a, 'r, R: Resources<'a> + ?Sized> {
+    resources: &'r R,
+    type_id: TypeId,
+    shadowed: Option<Arc<RwLock<StoredResource<'a>>>>,
+    _marker: PhantomData<&'a ()>,
+}
+
+impl<'a, 'r, R: Resources<'a> + ?Sized> Drop for RestoreOnDrop<'a, 'r, R> {
+    fn drop(&mut self) {
+        self.resources
+            .borrow_mut()
+            .replace_entry(self.type_id, self.shadowed.take());
+    }
 }
 
 /// A trait marking a type which represents a bundle of resources to be fetched from a resource
@@ -724,4 +848,48 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn scoped_overrides() -> Result<()> {
+        let resources = UnifiedResources::new();
+        resources.global.borrow_mut().insert(1i32);
+        resources.global.borrow_mut().insert(true);
+        resources.local.borrow_mut().insert(true);
+
+        // Shadowing through the unified container shadows the global layer.
+        resources.scope_with(2i32, || -> Result<()> {
+            assert_eq!(*resources.fetch_one::<i32>()?.borrow(), 2);
+            assert_eq!(*resources.global.fetch_one::<i32>()?.borrow(), 1);
+
+            // Scopes nest, and each one restores what was there before it.
+            resources.scope_with(3i32, || -> Result<()> {
+                assert_eq!(*resources.fetch_one::<i32>()?.borrow(), 3);
+                Ok(())
+            })?;
+            assert_eq!(*resources.fetch_one::<i32>()?.borrow(), 2);
+
+            Ok(())
+        })?;
+        assert_eq!(*resources.fetch_one::<i32>()?.borrow(), 1);
+        assert!(!resources.local.borrow().has_value::<i32>());
+
+        // Shadowing the global layer doesn't affect a local resource of the same type.
+        resources.global.scope_with(false, || -> Result<()> {
+            assert_eq!(*resources.fetch_one::<bool>()?.borrow(), true);
+            assert_eq!(*resources.global.fetch_one::<bool>()?.borrow(), false);
+            Ok(())
+        })?;
+
+        // Values which don't shadow anything are simply removed afterwards.
+        resources.scope_with("temporary", || -> Result<()> {
+            assert_eq!(
+                *resources.fetch_one::<&'static str>()?.borrow(),
+                "temporary"
+            );
+            Ok(())
+        })?;
+        assert!(resources.fetch_one::<&'static str>().is_err());
+
+        Ok(())
+    }
 }
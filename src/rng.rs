@@ -0,0 +1,209 @@
+//! Deterministic random numbers which survive saving and loading.
+//!
+//! An [`RngResource`] holds any number of named random number streams. Every stream is
+//! seeded from the resource's seed and the stream's name, so that drawing numbers from
+//! one stream never affects the numbers produced by another: loot drops can't be
+//! manipulated by firing more bullets, and replays stay in sync as long as each stream
+//! is used in the same order.
+//!
+//! Every [`Space`](crate::Space) has an `RngResource`, seeded from entropy unless one
+//! was provided to its builder. Its state is saved along with the space by
+//! [`Space::save`](crate::Space::save) and restored by
+//! [`Space::load`](crate::Space::load).
+//!
+//! From Lua, streams are accessed through the `sludge.rng` module:
+//!
+//! ```lua
+//! local roll = sludge.rng.range("loot", 1, 10) -- integer in [1, 10]
+//! local t = sludge.rng.random("particles") -- float in [0, 1)
+//! ```
+
+use {
+    anyhow::*,
+    hashbrown::HashMap,
+    rand::{distributions::Uniform, Rng, SeedableRng},
+    rand_xorshift::XorShiftRng,
+    rlua::prelude::*,
+    serde::{Deserialize, Serialize},
+};
+
+use crate::SludgeLuaContextExt;
+
+/// A collection of named, independently seeded random number streams.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RngResource {
+    seed: u64,
+    streams: HashMap<String, XorShiftRng>,
+}
+
+impl RngResource {
+    /// Create a set of streams which all derive their seeds from `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            streams: HashMap::new(),
+        }
+    }
+
+    /// Create a set of streams with a random seed. The seed is kept small enough to be
+    /// represented exactly by a Lua number.
+    pub fn from_entropy() -> Self {
+        Self::new(rand::random::<u64>() >> 11)
+    }
+
+    /// The seed which all streams are derived from.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Reset every stream, deriving them from a new seed.
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.streams.clear();
+    }
+
+    /// Get the stream with the given name, creating it if it doesn't exist yet.
+    pub fn stream(&mut self, name: &str) -> &mut XorShiftRng {
+        if !self.streams.contains_key(name) {
+            let rng = XorShiftRng::seed_from_u64(stream_seed(self.seed, name));
+            self.streams.insert(name.to_owned(), rng);
+        }
+
+        self.streams.get_mut(name).unwrap()
+    }
+
+    /// Pick an integer in `[lo, hi]` from the named stream, both ends included.
+    pub fn range(&mut self, name: &str, lo: i64, hi: i64) -> Result<i64> {
+        ensure!(lo <= hi, "empty range [{}, {}]", lo, hi);
+        Ok(self.stream(name).sample(Uniform::new_inclusive(lo, hi)))
+    }
+}
+
+impl Default for RngResource {
+    fn default() -> Self {
+        Self::from_entropy()
+    }
+}
+
+/// Mix a stream name into the base seed with 64-bit FNV-1a. This is written out by hand
+/// rather than using `std`'s hasher, so that stream seeds stay the same across Rust
+/// versions.
+fn stream_seed(seed: u64, name: &str) -> u64 {
+    const FNV_PRIME: u64 = 0x100000001b3;
+    name.bytes().fold(seed ^ 0xcbf29ce484222325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// Record the state of the space's `RngResource` for persistence, if it has one.
+pub(crate) fn record<'lua>(lua: LuaContext<'lua>) -> LuaResult<LuaValue<'lua>> {
+    match lua.fetch_one::<RngResource>() {
+        Ok(rng) => rlua_serde::to_value(lua, &*rng.borrow()),
+        Err(_) => Ok(LuaValue::Nil),
+    }
+}
+
+/// Restore the state recorded by [`record`] into the space's `RngResource`.
+pub(crate) fn playback<'lua>(lua: LuaContext<'lua>, value: LuaValue<'lua>) -> Result<()> {
+    if let LuaValue::Nil = value {
+        return Ok(());
+    }
+
+    let restored = rlua_serde::from_value::<RngResource>(value)?;
+    *lua.fetch_one::<RngResource>()?.borrow_mut() = restored;
+    Ok(())
+}
+
+inventory::submit! {
+    crate::api::Module::parse("sludge.rng", |lua| {
+        let table = lua.create_table()?;
+
+        table.set(
+            "range",
+            lua.create_function(|lua, (stream, lo, hi): (String, i64, i64)| {
+                lua.fetch_one::<RngResource>()?
+                    .borrow_mut()
+                    .range(&stream, lo, hi)
+                    .to_lua_err()
+            })?,
+        )?;
+
+        table.set(
+            "random",
+            lua.create_function(|lua, stream: String| {
+                Ok(lua
+                    .fetch_one::<RngResource>()?
+                    .borrow_mut()
+                    .stream(&stream)
+                    .gen::<f64>())
+            })?,
+        )?;
+
+        table.set(
+            "chance",
+            lua.create_function(|lua, (stream, p): (String, f64)| {
+                Ok(lua
+                    .fetch_one::<RngResource>()?
+                    .borrow_mut()
+                    .stream(&stream)
+                    .gen_bool(p.clamp(0., 1.)))
+            })?,
+        )?;
+
+        table.set(
+            "seed",
+            lua.create_function(|lua, ()| Ok(lua.fetch_one::<RngResource>()?.borrow().seed()))?,
+        )?;
+
+        table.set(
+            "reseed",
+            lua.create_function(|lua, seed: u64| {
+                lua.fetch_one::<RngResource>()?.borrow_mut().reseed(seed);
+                Ok(())
+            })?,
+        )?;
+
+        Ok(LuaValue::Table(table))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streams_are_independent() {
+        let mut a = RngResource::new(1234);
+        let mut b = RngResource::new(1234);
+
+        let _ = a.stream("bullets").gen::<u64>();
+        assert_eq!(a.stream("loot").gen::<u64>(), b.stream("loot").gen::<u64>());
+    }
+
+    #[test]
+    fn ranges_include_both_ends() {
+        let mut rng = RngResource::new(7);
+        assert_eq!(rng.range("loot", 3, 3).unwrap(), 3);
+        assert_eq!(rng.range("loot", i64::MAX, i64::MAX).unwrap(), i64::MAX);
+        assert!(rng.range("loot", i64::MIN, i64::MAX).is_ok());
+        assert!(rng.range("loot", 1, 0).is_err());
+
+        let rolls = (0..100)
+            .map(|_| rng.range("dice", 1, 2).unwrap())
+            .collect::<Vec<_>>();
+        assert!(rolls.contains(&1) && rolls.contains(&2));
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let mut rng = RngResource::new(42);
+        let _ = rng.stream("loot").gen::<u64>();
+
+        let mut restored =
+            serde_json::from_str::<RngResource>(&serde_json::to_string(&rng).unwrap()).unwrap();
+        assert_eq!(
+            rng.stream("loot").gen::<u64>(),
+            restored.stream("loot").gen::<u64>()
+        );
+    }
+}
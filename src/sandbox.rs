@@ -0,0 +1,368 @@
+//! Restrictions for running untrusted Lua code inside a [`Space`](crate::Space).
+//!
+//! A [`Sandbox`] is applied when a space is created with
+//! [`Space::with_sandbox`](crate::Space::with_sandbox). It can restrict which
+//! global Lua APIs are visible, limit how many instructions a scheduled thread
+//! may run on a single resume, and cap the total memory used by the Lua state.
+//! Sandboxed spaces also never load precompiled bytecode through `require`.
+//!
+//! When a thread running on a [`Scheduler`](crate::Scheduler) exceeds the
+//! instruction limit or runs out of memory, only that thread is killed. The
+//! scheduler then broadcasts a `"script.violation"` event with the dead thread,
+//! the kind of violation (`"instructions"` or `"memory"`) and an error message
+//! as arguments.
+//!
+//! Note that the instruction limit is only enforced while a scheduler is resuming
+//! a thread; Lua called directly from Rust is never interrupted.
+
+use {
+    anyhow::*,
+    rlua::{prelude::*, HookTriggers},
+    std::sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thiserror::Error,
+};
+
+pub const INSTRUCTION_BUDGET_REGISTRY_KEY: &'static str = "sludge.sandbox.budget";
+
+/// The name of the event broadcast when a thread is killed for violating the sandbox.
+pub const VIOLATION_EVENT: &'static str = "script.violation";
+
+/// How many instructions pass between checks of the instruction budget.
+const HOOK_GRANULARITY: u32 = 1000;
+
+/// Configuration for restricting what Lua code running in a space can do.
+#[derive(Debug, Clone, Default)]
+pub struct Sandbox {
+    /// If `Some`, only the listed global APIs are left visible to Lua once the space
+    /// is initialized. Entries are dotted paths such as `"math"` or `"sludge.thread"`;
+    /// listing a table keeps everything inside it, and listing a path inside a table
+    /// keeps only that part of the table.
+    ///
+    /// Some parts of sludge's Lua API depend on others; in particular, loading a saved
+    /// space requires `sludge.spawn`, and the `yield` function is defined in terms of
+    /// `sludge.thread`.
+    pub whitelist: Option<Vec<String>>,
+
+    /// The maximum number of instructions a thread may run on a single resume from a
+    /// scheduler before it's killed. This is checked every thousand instructions or so,
+    /// so it's approximate.
+    pub instruction_limit: Option<u64>,
+
+    /// The maximum number of bytes the Lua state may allocate.
+    pub memory_limit: Option<usize>,
+}
+
+impl Sandbox {
+    /// A sandbox which doesn't restrict anything.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only allow the given global APIs to be accessed from Lua.
+    pub fn with_whitelist<I, S>(mut self, whitelist: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.whitelist = Some(whitelist.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Limit the number of instructions a thread may run per resume.
+    pub fn with_instruction_limit(mut self, limit: u64) -> Self {
+        self.instruction_limit = Some(limit);
+        self
+    }
+
+    /// Limit the total memory used by the Lua state.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Apply the sandbox to a freshly initialized Lua state. The whitelist and memory
+    /// limit are applied immediately, so this should be called after the sludge API
+    /// has been loaded.
+    pub(crate) fn apply(&self, lua: &Lua) -> Result<()> {
+        lua.context(|lua| {
+            lua.set_named_registry_value(crate::api::package::NO_BYTECODE_REGISTRY_KEY, true)
+        })?;
+
+        if let Some(whitelist) = &self.whitelist {
+            lua.context(|lua| {
+                let paths = whitelist
+                    .iter()
+                    .map(|s| s.split('.').collect::<Vec<_>>())
+                    .collect::<Vec<_>>();
+                prune(lua.globals(), &paths, 0)
+            })?;
+        }
+
+        if let Some(limit) = self.instruction_limit {
+            let budget = InstructionBudget {
+                limit,
+                used: Arc::new(AtomicU64::new(0)),
+                active: Arc::new(AtomicBool::new(false)),
+            };
+
+            let hook_budget = budget.clone();
+            lua.set_hook(
+                HookTriggers {
+                    every_nth_instruction: Some(HOOK_GRANULARITY),
+                    ..HookTriggers::default()
+                },
+                move |_lua, _debug| hook_budget.charge(),
+            );
+
+            lua.context(|lua| {
+                lua.set_named_registry_value(INSTRUCTION_BUDGET_REGISTRY_KEY, budget)
+            })?;
+        }
+
+        if let Some(bytes) = self.memory_limit {
+            lua.set_memory_limit(Some(bytes));
+        }
+
+        Ok(())
+    }
+}
+
+/// Remove everything from `table` which isn't named by one of `paths`, starting at
+/// path segment `depth`.
+fn prune<'lua>(table: LuaTable<'lua>, paths: &[Vec<&str>], depth: usize) -> LuaResult<()> {
+    let keys = table
+        .clone()
+        .pairs::<LuaValue, LuaValue>()
+        .map(|pair| pair.map(|(k, _)| k))
+        .collect::<LuaResult<Vec<_>>>()?;
+
+    for key in keys {
+        let name = match &key {
+            LuaValue::String(s) => s.to_str()?.to_owned(),
+            _ => {
+                table.set(key, LuaValue::Nil)?;
+                continue;
+            }
+        };
+
+        let matching = paths
+            .iter()
+            .filter(|p| p.len() > depth && p[depth] == name)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if matching.is_empty() {
+            table.set(key, LuaValue::Nil)?;
+        } else if matching.iter().all(|p| p.len() > depth + 1) {
+            match table.get::<_, LuaValue>(key.clone())? {
+                LuaValue::Table(subtable) => prune(subtable, &matching, depth + 1)?,
+                _ => table.set(key, LuaValue::Nil)?,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The error raised inside a Lua thread when it violates the sandbox.
+#[derive(Debug, Clone, Error)]
+pub enum Violation {
+    /// The thread ran too many instructions without yielding.
+    #[error("thread exceeded its instruction limit of {0} instructions per resume")]
+    Instructions(u64),
+    /// The Lua state ran out of memory.
+    #[error("Lua state exceeded its memory limit: {0}")]
+    Memory(String),
+}
+
+impl Violation {
+    /// Find a sandbox violation in the causes of a Lua error, if there is one.
+    pub fn find(err: &LuaError) -> Option<Self> {
+        match err {
+            LuaError::MemoryError(msg) => Some(Self::Memory(msg.clone())),
+            LuaError::CallbackError { cause, .. } => Self::find(cause),
+            LuaError::ExternalError(ext) => ext.downcast_ref::<Self>().cloned(),
+            _ => None,
+        }
+    }
+
+    /// The short name of the kind of violation, as passed to Lua.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Instructions(_) => "instructions",
+            Self::Memory(_) => "memory",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct InstructionBudget {
+    limit: u64,
+    used: Arc<AtomicU64>,
+    active: Arc<AtomicBool>,
+}
+
+impl InstructionBudget {
+    fn charge(&self) -> LuaResult<()> {
+        if !self.active.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let used = self
+            .used
+            .fetch_add(HOOK_GRANULARITY as u64, Ordering::Relaxed)
+            + HOOK_GRANULARITY as u64;
+
+        if used > self.limit {
+            Err(LuaError::external(Violation::Instructions(self.limit)))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl LuaUserData for InstructionBudget {}
+
+/// Resets and enables the instruction budget for the duration of a single thread
+/// resume, restoring the previous budget state when dropped so that nested
+/// schedulers don't interfere with each other.
+pub(crate) struct ResumeGuard {
+    budget: InstructionBudget,
+    used: u64,
+    active: bool,
+}
+
+impl ResumeGuard {
+    pub fn begin(lua: LuaContext) -> LuaResult<Option<Self>> {
+        let ud = match lua
+            .named_registry_value::<_, Option<LuaAnyUserData>>(INSTRUCTION_BUDGET_REGISTRY_KEY)?
+        {
+            Some(ud) => ud,
+            None => return Ok(None),
+        };
+        let budget = ud.borrow::<InstructionBudget>()?.clone();
+        let used = budget.used.swap(0, Ordering::Relaxed);
+        let active = budget.active.swap(true, Ordering::Relaxed);
+
+        Ok(Some(Self {
+            budget,
+            used,
+            active,
+        }))
+    }
+}
+
+impl Drop for ResumeGuard {
+    fn drop(&mut self) {
+        self.budget.used.store(self.used, Ordering::Relaxed);
+        self.budget.active.store(self.active, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(lua: LuaContext) -> InstructionBudget {
+        lua.named_registry_value::<_, LuaAnyUserData>(INSTRUCTION_BUDGET_REGISTRY_KEY)
+            .unwrap()
+            .borrow::<InstructionBudget>()
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn whitelist_prunes_everything_else() -> Result<()> {
+        let lua = Lua::new();
+        Sandbox::new()
+            .with_whitelist(vec!["string", "math.floor", "os.missing"])
+            .apply(&lua)?;
+
+        lua.context(|lua| -> Result<()> {
+            let globals = lua.globals();
+            assert!(globals.get::<_, Option<LuaTable>>("string")?.is_some());
+            assert!(globals.get::<_, Option<LuaValue>>("print")?.is_none());
+            assert!(globals.get::<_, Option<LuaValue>>("io")?.is_none());
+
+            let math = globals.get::<_, LuaTable>("math")?;
+            assert!(math.get::<_, Option<LuaFunction>>("floor")?.is_some());
+            assert!(math.get::<_, Option<LuaValue>>("sin")?.is_none());
+
+            // Listing something which doesn't exist keeps nothing else in its table.
+            let os = globals.get::<_, LuaTable>("os")?;
+            assert_eq!(os.pairs::<LuaValue, LuaValue>().count(), 0);
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn budget_only_charges_while_active() -> Result<()> {
+        let lua = Lua::new();
+        Sandbox::new().with_instruction_limit(2500).apply(&lua)?;
+
+        lua.context(|lua| {
+            let budget = budget(lua);
+            for _ in 0..10 {
+                budget.charge().unwrap();
+            }
+            assert_eq!(budget.used.load(Ordering::Relaxed), 0);
+
+            let _guard = ResumeGuard::begin(lua).unwrap().unwrap();
+            budget.charge().unwrap();
+            budget.charge().unwrap();
+            let err = budget.charge().unwrap_err();
+            assert!(matches!(
+                Violation::find(&err),
+                Some(Violation::Instructions(2500))
+            ));
+        });
+
+        Ok(())
+    }
+
+    #[test]
+    fn resume_guards_nest_and_restore_the_budget() -> Result<()> {
+        let lua = Lua::new();
+        Sandbox::new().with_instruction_limit(10_000).apply(&lua)?;
+
+        lua.context(|lua| {
+            let budget = budget(lua);
+            assert!(!budget.active.load(Ordering::Relaxed));
+
+            let outer = ResumeGuard::begin(lua).unwrap().unwrap();
+            assert!(budget.active.load(Ordering::Relaxed));
+            budget.charge().unwrap();
+            budget.charge().unwrap();
+            assert_eq!(budget.used.load(Ordering::Relaxed), 2000);
+
+            {
+                // A nested scheduler's resume starts with a fresh budget...
+                let _inner = ResumeGuard::begin(lua).unwrap().unwrap();
+                assert_eq!(budget.used.load(Ordering::Relaxed), 0);
+                budget.charge().unwrap();
+                assert_eq!(budget.used.load(Ordering::Relaxed), 1000);
+            }
+
+            // ...and the outer resume picks up where it left off.
+            assert_eq!(budget.used.load(Ordering::Relaxed), 2000);
+            assert!(budget.active.load(Ordering::Relaxed));
+
+            drop(outer);
+            assert_eq!(budget.used.load(Ordering::Relaxed), 0);
+            assert!(!budget.active.load(Ordering::Relaxed));
+        });
+
+        Ok(())
+    }
+
+    #[test]
+    fn no_guard_without_an_instruction_limit() {
+        let lua = Lua::new();
+        lua.context(|lua| assert!(ResumeGuard::begin(lua).unwrap().is_none()));
+    }
+}
@@ -16,6 +16,23 @@
 //! This is basically identical in concept to the Amethyst engine's scene
 //! system, the only difference is the details of how the pieces are put
 //! together.
+//!
+//! Pushing, popping and replacing scenes changes the stack right away, but the scenes'
+//! `enter`, `exit`, `pause` and `resume` hooks need the context, so they're deferred
+//! until the start and end of [`SceneStack::update`] (or whenever [`SceneStack::apply`]
+//! is called). Any change can come with a [`Transition`], during which
+//! [`SceneStack::draw_with_transition`] draws the stack partway between its old and new
+//! states.
+//!
+//! Scene stacks usually own the spaces their scenes run, so they can't be stored in a
+//! space themselves. Lua changes the stack through a [`SceneQueue`] instead, which can
+//! be inserted into a space's resources and which refers to scenes by the names they
+//! were registered under with [`SceneStack::register`]:
+//!
+//! ```lua
+//! sludge.scene.push("gameplay", { type = "fade", duration = 0.5 })
+//! sludge.scene.pop({ type = "slide", duration = 0.25, direction = "down" })
+//! ```
 
 /*
  * MIT License
@@ -44,9 +61,27 @@
 use {
     anyhow::*,
     atomic_refcell::AtomicRefCell,
-    std::{borrow::Cow, fmt, sync::Arc},
+    crossbeam_channel::{Receiver, Sender},
+    hashbrown::HashMap,
+    rlua::prelude::*,
+    serde::{Deserialize, Serialize},
+    std::{
+        borrow::{BorrowMut, Cow},
+        fmt,
+        sync::Arc,
+    },
 };
 
+use crate::{
+    graphics::{Color, Drawable, Graphics, InstanceParam},
+    math::*,
+    SludgeLuaContextExt,
+};
+
+/// The default length of a tick of [`SceneStack::update`], in seconds, used to advance
+/// transitions.
+pub const DEFAULT_TIMESTEP: f32 = 1. / 60.;
+
 pub struct DynamicScene<C, Ev>(Arc<AtomicRefCell<dyn Scene<C, Ev>>>);
 
 impl<C, Ev> Clone for DynamicScene<C, Ev> {
@@ -102,6 +137,22 @@ impl<C, Ev> Scene<C, Ev> for DynamicScene<C, Ev> {
     fn draw_previous(&self) -> bool {
         self.0.borrow().draw_previous()
     }
+
+    fn enter(&mut self, ctx: &mut C) -> Result<()> {
+        self.map_mut_inner(|s| s.enter(ctx))
+    }
+
+    fn exit(&mut self, ctx: &mut C) -> Result<()> {
+        self.map_mut_inner(|s| s.exit(ctx))
+    }
+
+    fn pause(&mut self, ctx: &mut C) -> Result<()> {
+        self.map_mut_inner(|s| s.pause(ctx))
+    }
+
+    fn resume(&mut self, ctx: &mut C) -> Result<()> {
+        self.map_mut_inner(|s| s.resume(ctx))
+    }
 }
 
 /// A trait for you to implement on a scene.
@@ -119,38 +170,317 @@ pub trait Scene<C, Ev> {
     fn draw_previous(&self) -> bool {
         false
     }
+
+    /// Called when the scene is pushed onto the stack.
+    fn enter(&mut self, _ctx: &mut C) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when the scene is popped off of the stack or replaced.
+    fn exit(&mut self, _ctx: &mut C) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when another scene is pushed on top of this one.
+    fn pause(&mut self, _ctx: &mut C) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when this scene becomes the top of the stack again after the scene on
+    /// top of it is popped.
+    fn resume(&mut self, _ctx: &mut C) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The direction scenes move in during a [`Transition::Slide`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlideDirection {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+impl SlideDirection {
+    /// The direction as a vector in normalized device coordinates.
+    fn to_ndc(self) -> Vector2<f32> {
+        match self {
+            Self::Left => Vector2::new(-1., 0.),
+            Self::Right => Vector2::new(1., 0.),
+            Self::Up => Vector2::new(0., 1.),
+            Self::Down => Vector2::new(0., -1.),
+        }
+    }
+}
+
+/// An effect played while the scene stack changes.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Transition {
+    /// Fade out to a color and then fade back in to the new state of the stack. The
+    /// stack switches over halfway through.
+    Fade {
+        duration: f32,
+        #[serde(default = "default_fade_color")]
+        color: Color,
+    },
+    /// Slide the old state of the stack off the screen while the new state slides in
+    /// behind it.
+    ///
+    /// Slides are drawn by offsetting the projection, so scenes which set their own
+    /// projection when drawing won't move.
+    Slide {
+        duration: f32,
+        direction: SlideDirection,
+    },
+}
+
+fn default_fade_color() -> Color {
+    Color::BLACK
+}
+
+impl Transition {
+    pub fn fade(duration: f32) -> Self {
+        Self::Fade {
+            duration,
+            color: Color::BLACK,
+        }
+    }
+
+    pub fn slide(duration: f32, direction: SlideDirection) -> Self {
+        Self::Slide {
+            duration,
+            direction,
+        }
+    }
+
+    pub fn duration(&self) -> f32 {
+        match self {
+            Self::Fade { duration, .. } | Self::Slide { duration, .. } => *duration,
+        }
+    }
+}
+
+impl<'lua> FromLua<'lua> for Transition {
+    fn from_lua(lua_value: LuaValue<'lua>, _lua: LuaContext<'lua>) -> LuaResult<Self> {
+        rlua_serde::from_value(lua_value)
+    }
+}
+
+struct ActiveTransition<C, Ev> {
+    transition: Transition,
+    elapsed: f32,
+    /// The stack as it was before the change which started the transition.
+    before: Vec<DynamicScene<C, Ev>>,
+}
+
+impl<C, Ev> ActiveTransition<C, Ev> {
+    fn progress(&self) -> f32 {
+        let duration = self.transition.duration();
+        if duration > 0. {
+            (self.elapsed / duration).min(1.)
+        } else {
+            1.
+        }
+    }
+}
+
+/// A hook owed to a scene by a change to the stack.
+enum SceneHook<C, Ev> {
+    Enter(DynamicScene<C, Ev>),
+    Exit(DynamicScene<C, Ev>),
+    Pause(DynamicScene<C, Ev>),
+    Resume(DynamicScene<C, Ev>),
+}
+
+/// A change to a scene stack requested from Lua, referring to scenes by name.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SceneCommand {
+    Push {
+        name: String,
+        transition: Option<Transition>,
+    },
+    Pop {
+        transition: Option<Transition>,
+    },
+    Replace {
+        name: String,
+        transition: Option<Transition>,
+    },
+}
+
+/// A handle for queueing changes to a [`SceneStack`] from elsewhere, including from
+/// Lua through the `sludge.scene` module when it's inserted into a space's resources.
+#[derive(Debug, Clone)]
+pub struct SceneQueue {
+    sender: Sender<SceneCommand>,
+}
+
+impl SceneQueue {
+    pub fn push_command(&self, command: SceneCommand) {
+        self.sender
+            .try_send(command)
+            .expect("unbounded channel should never fail to send");
+    }
+
+    pub fn push(&self, name: impl Into<String>, transition: Option<Transition>) {
+        self.push_command(SceneCommand::Push {
+            name: name.into(),
+            transition,
+        });
+    }
+
+    pub fn pop(&self, transition: Option<Transition>) {
+        self.push_command(SceneCommand::Pop { transition });
+    }
+
+    pub fn replace(&self, name: impl Into<String>, transition: Option<Transition>) {
+        self.push_command(SceneCommand::Replace {
+            name: name.into(),
+            transition,
+        });
+    }
 }
 
 /// A stack of `Scene`'s, together with a context object.
 pub struct SceneStack<C, Ev> {
     scenes: Vec<DynamicScene<C, Ev>>,
+    /// Hooks owed to scenes by changes which haven't been applied yet.
+    pending: Vec<SceneHook<C, Ev>>,
+    transition: Option<ActiveTransition<C, Ev>>,
+    /// How far transitions advance on every update, in seconds.
+    timestep: f32,
+    factories: HashMap<String, Box<dyn Fn() -> DynamicScene<C, Ev>>>,
+    queue: SceneQueue,
+    commands: Receiver<SceneCommand>,
 }
 
 impl<C, Ev> SceneStack<C, Ev> {
     pub fn new() -> Self {
-        Self { scenes: Vec::new() }
+        let (sender, commands) = crossbeam_channel::unbounded();
+        Self {
+            scenes: Vec::new(),
+            pending: Vec::new(),
+            transition: None,
+            timestep: DEFAULT_TIMESTEP,
+            factories: HashMap::new(),
+            queue: SceneQueue { sender },
+            commands,
+        }
+    }
+
+    /// How far transitions advance on every [update](SceneStack::update), in seconds.
+    /// Defaults to a sixtieth of a second.
+    pub fn timestep(&self) -> f32 {
+        self.timestep
+    }
+
+    pub fn set_timestep(&mut self, timestep: f32) {
+        self.timestep = timestep;
+    }
+
+    /// Register a constructor for a scene under a name, so that it can be pushed by
+    /// name through a [`SceneQueue`].
+    pub fn register<F>(&mut self, name: impl Into<String>, factory: F)
+    where
+        F: Fn() -> DynamicScene<C, Ev> + 'static,
+    {
+        self.factories.insert(name.into(), Box::new(factory));
+    }
+
+    /// A handle for queueing changes to this stack.
+    pub fn queue(&self) -> SceneQueue {
+        self.queue.clone()
+    }
+
+    /// Start a transition from the stack as it was before the change being made.
+    fn begin_transition(&mut self, transition: Option<Transition>) {
+        if let Some(transition) = transition {
+            self.transition = Some(ActiveTransition {
+                transition,
+                elapsed: 0.,
+                before: self.scenes.clone(),
+            });
+        }
+    }
+
+    fn push_inner(&mut self, scene: DynamicScene<C, Ev>, transition: Option<Transition>) {
+        self.begin_transition(transition);
+        if let Some(current) = self.scenes.last() {
+            self.pending.push(SceneHook::Pause(current.clone()));
+        }
+        self.pending.push(SceneHook::Enter(scene.clone()));
+        self.scenes.push(scene);
+    }
+
+    fn pop_inner(&mut self, transition: Option<Transition>) -> DynamicScene<C, Ev> {
+        self.begin_transition(transition);
+        let popped = self
+            .scenes
+            .pop()
+            .expect("ERROR: Popped an empty scene stack.");
+        self.pending.push(SceneHook::Exit(popped.clone()));
+        if let Some(current) = self.scenes.last() {
+            self.pending.push(SceneHook::Resume(current.clone()));
+        }
+        popped
+    }
+
+    fn replace_inner(
+        &mut self,
+        scene: DynamicScene<C, Ev>,
+        transition: Option<Transition>,
+    ) -> DynamicScene<C, Ev> {
+        self.begin_transition(transition);
+        let replaced = self
+            .scenes
+            .pop()
+            .expect("ERROR: Replaced the top of an empty scene stack.");
+        self.pending.push(SceneHook::Exit(replaced.clone()));
+        self.pending.push(SceneHook::Enter(scene.clone()));
+        self.scenes.push(scene);
+        replaced
     }
 
     /// Add a new scene to the top of the stack.
     pub fn push(&mut self, scene: DynamicScene<C, Ev>) {
-        self.scenes.push(scene)
+        self.push_inner(scene, None);
+    }
+
+    /// Add a new scene to the top of the stack, with a transition.
+    pub fn push_with(&mut self, scene: DynamicScene<C, Ev>, transition: Transition) {
+        self.push_inner(scene, Some(transition));
     }
 
     /// Remove the top scene from the stack and returns it;
     /// panics if there is none.
     pub fn pop(&mut self) -> DynamicScene<C, Ev> {
-        self.scenes
-            .pop()
-            .expect("ERROR: Popped an empty scene stack.")
+        self.pop_inner(None)
+    }
+
+    /// Remove the top scene from the stack with a transition, and return it; panics if
+    /// there is none.
+    pub fn pop_with(&mut self, transition: Transition) -> DynamicScene<C, Ev> {
+        self.pop_inner(Some(transition))
     }
 
     /// Replace the top scene on the stack by popping and then
     /// pushing a new scene. Will panic if the stack is empty.
     /// Returns the replaced scene.
     pub fn replace(&mut self, scene: DynamicScene<C, Ev>) -> DynamicScene<C, Ev> {
-        let replaced = self.pop();
-        self.push(scene);
-        replaced
+        self.replace_inner(scene, None)
+    }
+
+    /// Replace the top scene on the stack with a transition, returning the replaced
+    /// scene. Will panic if the stack is empty.
+    pub fn replace_with(
+        &mut self,
+        scene: DynamicScene<C, Ev>,
+        transition: Transition,
+    ) -> DynamicScene<C, Ev> {
+        self.replace_inner(scene, Some(transition))
     }
 
     /// Returns the current scene; panics if there is none.
@@ -160,16 +490,85 @@ impl<C, Ev> SceneStack<C, Ev> {
             .expect("ERROR: Tried to get current scene of an empty scene stack.")
     }
 
+    pub fn len(&self) -> usize {
+        self.scenes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scenes.is_empty()
+    }
+
+    /// Whether a transition is currently playing.
+    pub fn is_transitioning(&self) -> bool {
+        self.transition.is_some()
+    }
+
+    fn instantiate(&self, name: &str) -> Result<DynamicScene<C, Ev>> {
+        let factory = self
+            .factories
+            .get(name)
+            .ok_or_else(|| anyhow!("no scene registered with name `{}`", name))?;
+        Ok(factory())
+    }
+
+    /// Carry out every change queued through a [`SceneQueue`], and then call the hooks
+    /// owed to scenes by every change made so far, in the order the changes were made.
+    pub fn apply(&mut self, ctx: &mut C) -> Result<()> {
+        for command in self.commands.try_iter().collect::<Vec<_>>() {
+            match command {
+                SceneCommand::Push { name, transition } => {
+                    let scene = self.instantiate(&name)?;
+                    self.push_inner(scene, transition);
+                }
+                SceneCommand::Pop { transition } => {
+                    ensure!(!self.scenes.is_empty(), "popped an empty scene stack");
+                    self.pop_inner(transition);
+                }
+                SceneCommand::Replace { name, transition } => {
+                    ensure!(
+                        !self.scenes.is_empty(),
+                        "replaced the top of an empty scene stack"
+                    );
+                    let scene = self.instantiate(&name)?;
+                    self.replace_inner(scene, transition);
+                }
+            }
+        }
+
+        for hook in std::mem::take(&mut self.pending) {
+            match hook {
+                SceneHook::Enter(mut scene) => scene.enter(ctx)?,
+                SceneHook::Exit(mut scene) => scene.exit(ctx)?,
+                SceneHook::Pause(mut scene) => scene.pause(ctx)?,
+                SceneHook::Resume(mut scene) => scene.resume(ctx)?,
+            }
+        }
+
+        Ok(())
+    }
+
     // These functions must be on the SceneStack because otherwise
     // if you try to get the current scene and the world to call
     // update() on the current scene it causes a double-borrow.  :/
     pub fn update(&mut self, ctx: &mut C) -> Result<()> {
+        // Changes made since the last update, including the first push onto an empty
+        // stack, have to be applied before there's a current scene to update.
+        self.apply(ctx)?;
+
+        if let Some(transition) = self.transition.as_mut() {
+            transition.elapsed += self.timestep;
+            if transition.progress() >= 1. {
+                self.transition = None;
+            }
+        }
+
         let mut current_scene = self
             .scenes
             .last()
             .cloned()
             .expect("Tried to update empty scene stack");
-        current_scene.update(self, ctx)
+        current_scene.update(self, ctx)?;
+        self.apply(ctx)
     }
 
     /// We walk down the scene stack until we find a scene where we aren't
@@ -188,11 +587,92 @@ impl<C, Ev> SceneStack<C, Ev> {
         }
     }
 
-    /// Draw the current scene.
+    /// Draw the current scene. Transitions aren't drawn, since that needs the graphics
+    /// context; see [`SceneStack::draw_with_transition`].
     pub fn draw(&mut self, ctx: &mut C) -> Result<()> {
         SceneStack::draw_scenes(&mut self.scenes, ctx)
     }
 
+    /// Draw a stack of scenes offset by a vector in normalized device coordinates.
+    fn draw_scenes_offset(
+        scenes: &mut [DynamicScene<C, Ev>],
+        ctx: &mut C,
+        offset: Vector2<f32>,
+    ) -> Result<()>
+    where
+        C: BorrowMut<Graphics>,
+    {
+        if scenes.is_empty() {
+            return Ok(());
+        }
+
+        let gfx: &mut Graphics = ctx.borrow_mut();
+        let projection = gfx.projection;
+        gfx.set_projection(Translation3::new(offset.x, offset.y, 0.).to_homogeneous() * projection);
+        gfx.apply_transforms();
+
+        let result = Self::draw_scenes(scenes, ctx);
+        ctx.borrow_mut().set_projection(projection);
+        result
+    }
+
+    /// Cover the whole screen in a color.
+    fn draw_fade(gfx: &mut Graphics, color: Color) {
+        let projection = gfx.projection;
+        gfx.set_projection(Matrix4::identity());
+        gfx.push_transform(Matrix4::identity());
+        gfx.apply_transforms();
+
+        let param = InstanceParam::new()
+            .color(color)
+            .translate2(Vector2::new(-1., -1.))
+            .scale2(Vector2::new(2., 2.));
+        gfx.null_texture.clone().load().draw(gfx, param);
+
+        gfx.pop_transform();
+        gfx.set_projection(projection);
+        gfx.apply_transforms();
+    }
+
+    /// Draw the current scene, along with the active transition if there is one.
+    pub fn draw_with_transition(&mut self, ctx: &mut C) -> Result<()>
+    where
+        C: BorrowMut<Graphics>,
+    {
+        let transition = match self.transition.as_mut() {
+            Some(transition) => transition,
+            None => return SceneStack::draw_scenes(&mut self.scenes, ctx),
+        };
+
+        let t = transition.progress();
+        match transition.transition {
+            Transition::Fade { color, .. } => {
+                let alpha = if t < 0.5 {
+                    Self::draw_scenes_offset(&mut transition.before, ctx, Vector2::zeros())?;
+                    t * 2.
+                } else {
+                    Self::draw_scenes_offset(&mut self.scenes, ctx, Vector2::zeros())?;
+                    (1. - t) * 2.
+                };
+
+                Self::draw_fade(
+                    ctx.borrow_mut(),
+                    Color {
+                        a: color.a * alpha,
+                        ..color
+                    },
+                );
+            }
+            Transition::Slide { direction, .. } => {
+                let d = direction.to_ndc() * 2.;
+                Self::draw_scenes_offset(&mut self.scenes, ctx, d * (t - 1.))?;
+                Self::draw_scenes_offset(&mut transition.before, ctx, d * t)?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Feeds the given event to the current scene.
     pub fn event(&mut self, ctx: &mut C, event: Ev) {
         let current_scene = self
@@ -202,3 +682,203 @@ impl<C, Ev> SceneStack<C, Ev> {
         current_scene.event(ctx, event);
     }
 }
+
+inventory::submit! {
+    crate::api::Module::parse("sludge.scene", |lua| {
+        let table = lua.create_table()?;
+
+        table.set(
+            "push",
+            lua.create_function(|lua, (name, transition): (String, Option<Transition>)| {
+                lua.fetch_one::<SceneQueue>()?.borrow().push(name, transition);
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "pop",
+            lua.create_function(|lua, transition: Option<Transition>| {
+                lua.fetch_one::<SceneQueue>()?.borrow().pop(transition);
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "replace",
+            lua.create_function(|lua, (name, transition): (String, Option<Transition>)| {
+                lua.fetch_one::<SceneQueue>()?.borrow().replace(name, transition);
+                Ok(())
+            })?,
+        )?;
+
+        Ok(LuaValue::Table(table))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A context which records every hook called, in order.
+    type Log = Vec<String>;
+
+    /// A scene which logs its hooks and, on its first update, pushes another scene.
+    struct Logged {
+        name: &'static str,
+        push_on_update: Option<&'static str>,
+    }
+
+    impl Logged {
+        fn scene(name: &'static str) -> DynamicScene<Log, ()> {
+            DynamicScene::new(Self {
+                name,
+                push_on_update: None,
+            })
+        }
+    }
+
+    impl Scene<Log, ()> for Logged {
+        fn update(&mut self, scene_stack: &mut SceneStack<Log, ()>, ctx: &mut Log) -> Result<()> {
+            ctx.push(format!("update {}", self.name));
+            if let Some(name) = self.push_on_update.take() {
+                scene_stack.push(Logged::scene(name));
+            }
+            Ok(())
+        }
+
+        fn draw(&mut self, _ctx: &mut Log) -> Result<()> {
+            Ok(())
+        }
+
+        fn event(&mut self, _ctx: &mut Log, _event: ()) {}
+
+        fn name(&self) -> Cow<'_, str> {
+            self.name.into()
+        }
+
+        fn enter(&mut self, ctx: &mut Log) -> Result<()> {
+            ctx.push(format!("enter {}", self.name));
+            Ok(())
+        }
+
+        fn exit(&mut self, ctx: &mut Log) -> Result<()> {
+            ctx.push(format!("exit {}", self.name));
+            Ok(())
+        }
+
+        fn pause(&mut self, ctx: &mut Log) -> Result<()> {
+            ctx.push(format!("pause {}", self.name));
+            Ok(())
+        }
+
+        fn resume(&mut self, ctx: &mut Log) -> Result<()> {
+            ctx.push(format!("resume {}", self.name));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn first_push_is_applied_before_update() -> Result<()> {
+        let mut stack = SceneStack::new();
+        let mut log = Log::new();
+
+        stack.push(Logged::scene("title"));
+        assert_eq!(stack.len(), 1);
+        assert!(log.is_empty());
+
+        stack.update(&mut log)?;
+        assert_eq!(log, ["enter title", "update title"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn hooks_run_in_order_of_changes() -> Result<()> {
+        let mut stack = SceneStack::new();
+        let mut log = Log::new();
+
+        stack.push(Logged::scene("a"));
+        stack.push(Logged::scene("b"));
+        let popped = stack.pop();
+        assert_eq!(popped.name(), "b");
+        let replaced = stack.replace(Logged::scene("c"));
+        assert_eq!(replaced.name(), "a");
+        assert_eq!(stack.current().name(), "c");
+
+        stack.apply(&mut log)?;
+        assert_eq!(
+            log,
+            ["enter a", "pause a", "enter b", "exit b", "resume a", "exit a", "enter c"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn pushes_during_update_are_applied_after_it() -> Result<()> {
+        let mut stack = SceneStack::new();
+        let mut log = Log::new();
+
+        stack.push(DynamicScene::new(Logged {
+            name: "game",
+            push_on_update: Some("pause menu"),
+        }));
+        stack.update(&mut log)?;
+        assert_eq!(
+            log,
+            [
+                "enter game",
+                "update game",
+                "pause game",
+                "enter pause menu"
+            ]
+        );
+        assert_eq!(stack.current().name(), "pause menu");
+
+        log.clear();
+        stack.update(&mut log)?;
+        assert_eq!(log, ["update pause menu"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn queued_commands_use_registered_scenes() -> Result<()> {
+        let mut stack = SceneStack::new();
+        let mut log = Log::new();
+        stack.register("title", || Logged::scene("title"));
+        stack.register("game", || Logged::scene("game"));
+
+        let queue = stack.queue();
+        queue.push("title", None);
+        queue.replace("game", Some(Transition::fade(0.5)));
+        stack.update(&mut log)?;
+
+        assert_eq!(
+            log,
+            ["enter title", "exit title", "enter game", "update game"]
+        );
+        assert_eq!(stack.len(), 1);
+        assert!(stack.is_transitioning());
+
+        queue.push("credits", None);
+        assert!(stack.apply(&mut log).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn transitions_advance_by_the_timestep() -> Result<()> {
+        let mut stack = SceneStack::new();
+        let mut log = Log::new();
+        stack.set_timestep(0.25);
+
+        stack.push_with(Logged::scene("a"), Transition::fade(0.5));
+        stack.update(&mut log)?;
+        assert!(stack.is_transitioning());
+        stack.update(&mut log)?;
+        assert!(!stack.is_transitioning());
+
+        Ok(())
+    }
+}
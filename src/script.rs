@@ -0,0 +1,496 @@
+//! Lua scripts attached to entities.
+//!
+//! A [`Script`] component gives an entity its own instance of a Lua class: a table
+//! whose metatable's `__index` is the class. The class can define any of three
+//! callbacks, all of which are passed the instance as `self`, with `self.entity` set
+//! to the entity the script is attached to:
+//!
+//! ```lua
+//! local Turret = {}
+//!
+//! function Turret:on_spawn()
+//!     self.cooldown = 0
+//! end
+//!
+//! function Turret:on_update()
+//!     if self.cooldown == 0 and player_in_range(self.entity) then
+//!         fire(self.entity)
+//!         self.cooldown = 30
+//!         sludge.thread.yield(5) -- on_update can wait; it runs on its own thread.
+//!     end
+//!     self.cooldown = math.max(self.cooldown - 1, 0)
+//! end
+//!
+//! function Turret:on_despawn()
+//!     print("turret destroyed")
+//! end
+//!
+//! sludge.spawn { Script = Turret }
+//! sludge.spawn { Script = { class = Turret, cooldown = 60 } } -- with initial fields
+//! ```
+//!
+//! Scripts are managed by the [`ScriptSystem`]. Once a `Script` is added to an entity,
+//! `on_spawn` is called directly on the system's next update, and a scheduler thread is
+//! started which calls `on_update` once per tick for as long as the script is attached.
+//! When the `Script` is removed or the entity is despawned, the thread is killed and
+//! `on_despawn` is called. By then the entity's components are already gone, so
+//! anything `on_despawn` needs should be kept on `self`.
+//!
+//! Instances and their threads are saved when the space is persisted; loaded scripts
+//! pick up where they left off, without calling `on_spawn` again. Classes shouldn't
+//! have fields named `class` or `instance`, which are used to tell the different forms
+//! accepted when spawning a `Script` apart.
+
+use {anyhow::*, hashbrown::HashMap, rlua::prelude::*, sludge_macros::SimpleComponent};
+
+use crate::{
+    api::{LuaComponent, LuaComponentInterface, LuaEntity},
+    ecs::*,
+    resources::{OwnedResources, Resources, SharedResources, UnifiedResources},
+    SludgeLuaContextExt, System,
+};
+
+/// The body of every script's update thread.
+const UPDATE_LOOP: &'static str = r#"
+return function(self)
+    while true do
+        local on_update = self.on_update
+        if on_update then
+            on_update(self)
+        end
+        sludge.thread.yield(1)
+    end
+end
+"#;
+
+/// An entity's script instance. See the [module documentation](self) for details.
+#[derive(Debug, SimpleComponent)]
+pub struct Script {
+    instance: LuaRegistryKey,
+    /// Whether this script was loaded from a save, in which case it's already been
+    /// spawned once.
+    loaded: bool,
+    /// The update thread saved along with a loaded script, waiting to be picked up by
+    /// the [`ScriptManager`].
+    saved_thread: Option<LuaRegistryKey>,
+}
+
+impl Script {
+    /// Create a new instance of a class, with no fields of its own.
+    pub fn new<'lua>(lua: LuaContext<'lua>, class: LuaTable<'lua>) -> Result<Self> {
+        Self::with_fields(lua, class, lua.create_table()?)
+    }
+
+    /// Create a new instance of a class, using `fields` as the instance table.
+    pub fn with_fields<'lua>(
+        lua: LuaContext<'lua>,
+        class: LuaTable<'lua>,
+        fields: LuaTable<'lua>,
+    ) -> Result<Self> {
+        let metatable = lua.create_table()?;
+        metatable.set("__index", class)?;
+        fields.set_metatable(Some(metatable));
+
+        Ok(Self {
+            instance: lua.create_registry_value(fields)?,
+            loaded: false,
+            saved_thread: None,
+        })
+    }
+
+    pub fn instance<'lua>(&self, lua: LuaContext<'lua>) -> Result<LuaTable<'lua>> {
+        Ok(lua.registry_value(&self.instance)?)
+    }
+}
+
+#[derive(Debug)]
+struct RunningScript {
+    instance: LuaRegistryKey,
+    thread: Option<LuaRegistryKey>,
+}
+
+/// Keeps track of which entities have running scripts, so that their threads can be
+/// killed and their `on_despawn` callbacks called after their `Script`s are gone.
+#[derive(Debug)]
+pub struct ScriptManager {
+    events: ComponentSubscriber<Script>,
+    running: HashMap<Entity, RunningScript>,
+}
+
+impl ScriptManager {
+    pub fn new(world: &mut World) -> Self {
+        Self {
+            events: world.track::<Script>(),
+            running: HashMap::new(),
+        }
+    }
+
+    /// Whether the entity's script has been started and not yet stopped.
+    pub fn is_running(&self, entity: Entity) -> bool {
+        self.running.contains_key(&entity)
+    }
+
+    /// The update thread of an entity's script, if it's running.
+    pub fn thread<'lua>(
+        &self,
+        lua: LuaContext<'lua>,
+        entity: Entity,
+    ) -> Result<Option<LuaThread<'lua>>> {
+        match self.running.get(&entity).and_then(|r| r.thread.as_ref()) {
+            Some(key) => Ok(Some(lua.registry_value(key)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn call_script<'lua>(instance: &LuaTable<'lua>, callback: &str) -> Result<()> {
+    if let Some(f) = instance.get::<_, Option<LuaFunction>>(callback)? {
+        f.call::<_, ()>(instance.clone())
+            .with_context(|| anyhow!("error in script callback `{}`", callback))?;
+    }
+    Ok(())
+}
+
+fn stop<'lua>(lua: LuaContext<'lua>, running: RunningScript) -> Result<()> {
+    if let Some(key) = running.thread {
+        let thread = lua.registry_value::<LuaThread>(&key)?;
+        lua.remove_registry_value(key)?;
+        if thread.status() != LuaThreadStatus::Unresumable {
+            lua.kill(thread, ())?;
+        }
+    }
+
+    let instance = lua.registry_value::<LuaTable>(&running.instance)?;
+    lua.remove_registry_value(running.instance)?;
+    call_script(&instance, "on_despawn")
+}
+
+fn start<'lua>(lua: LuaContext<'lua>, entity: Entity) -> Result<()> {
+    let started = {
+        let world = lua.fetch_one::<World>()?;
+        let world = world.borrow();
+        match world.get_mut::<Script>(entity) {
+            Ok(mut script) => Some((
+                lua.registry_value::<LuaTable>(&script.instance)?,
+                script.loaded,
+                script.saved_thread.take(),
+            )),
+            // The script was removed again before it could be started.
+            Err(_) => None,
+        }
+    };
+
+    let (instance, loaded, saved_thread) = match started {
+        Some(started) => started,
+        None => return Ok(()),
+    };
+
+    instance.set("entity", LuaEntity::from(entity))?;
+
+    let thread = if loaded {
+        saved_thread
+    } else {
+        call_script(&instance, "on_spawn")?;
+        let update_loop = lua
+            .load(UPDATE_LOOP)
+            .set_name("sludge.script")?
+            .eval::<LuaFunction>()?;
+        let thread = lua.spawn(update_loop, instance.clone())?;
+        Some(lua.create_registry_value(thread)?)
+    };
+
+    let running = RunningScript {
+        instance: lua.create_registry_value(instance)?,
+        thread,
+    };
+
+    let replaced = lua
+        .fetch_one::<ScriptManager>()?
+        .borrow_mut()
+        .running
+        .insert(entity, running);
+
+    if let Some(replaced) = replaced {
+        stop(lua, replaced)?;
+    }
+
+    Ok(())
+}
+
+/// Starts and stops entities' [`Script`]s as they're added and removed. Inserts a
+/// [`ScriptManager`] into the space's resources if it doesn't already have one.
+pub struct ScriptSystem;
+
+impl System for ScriptSystem {
+    fn init(
+        &self,
+        _lua: LuaContext,
+        local: &mut OwnedResources,
+        _global: Option<&SharedResources>,
+    ) -> Result<()> {
+        if !local.has_value::<ScriptManager>() {
+            let manager = ScriptManager::new(&mut local.fetch_one::<World>()?.borrow_mut());
+            local.insert(manager);
+        }
+        Ok(())
+    }
+
+    fn update(&self, lua: LuaContext, resources: &UnifiedResources) -> Result<()> {
+        let events = {
+            let (world, manager) = resources.fetch::<(World, ScriptManager)>()?;
+            let world = world.borrow();
+            let mut manager = manager.borrow_mut();
+            world
+                .poll::<Script>(&mut manager.events)
+                .copied()
+                .collect::<Vec<_>>()
+        };
+
+        // Callbacks can spawn and despawn entities, so no borrows are held while they
+        // run.
+        for event in events {
+            match event {
+                ComponentEvent::Inserted(entity) => start(lua, entity)?,
+                ComponentEvent::Removed(entity) => {
+                    let running = resources
+                        .fetch_one::<ScriptManager>()?
+                        .borrow_mut()
+                        .running
+                        .remove(&entity);
+                    if let Some(running) = running {
+                        stop(lua, running)?;
+                    }
+                }
+                ComponentEvent::Modified(_) => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ScriptAccessor(Entity);
+
+impl LuaUserData for ScriptAccessor {
+    fn add_methods<'lua, T: LuaUserDataMethods<'lua, Self>>(methods: &mut T) {
+        methods.add_method("get", |lua, this, ()| {
+            let tmp = lua.fetch_one::<World>()?;
+            let world = tmp.borrow();
+            let script = world.get::<Script>(this.0).to_lua_err()?;
+            script.instance(lua).to_lua_err()
+        });
+
+        methods.add_method("to_table", |lua, this, ()| {
+            let (world, manager) = lua.fetch::<(World, ScriptManager)>()?;
+            let world = world.borrow();
+            let script = world.get::<Script>(this.0).to_lua_err()?;
+
+            let table = lua.create_table()?;
+            table.set("instance", script.instance(lua).to_lua_err()?)?;
+            table.set("thread", manager.borrow().thread(lua, this.0).to_lua_err()?)?;
+            Ok(table)
+        });
+    }
+}
+
+impl LuaComponentInterface for Script {
+    fn accessor<'lua>(lua: LuaContext<'lua>, entity: Entity) -> LuaResult<LuaValue<'lua>> {
+        ScriptAccessor(entity).to_lua(lua)
+    }
+
+    /// Accepts a class, a table of initial fields with the class under `class`, or the
+    /// `instance` and `thread` produced by the accessor's `to_table`.
+    fn bundler<'lua>(
+        lua: LuaContext<'lua>,
+        args: LuaValue<'lua>,
+        builder: &mut EntityBuilder,
+    ) -> LuaResult<()> {
+        let table = LuaTable::from_lua(args, lua)?;
+
+        let script = if let Some(instance) = table.get::<_, Option<LuaTable>>("instance")? {
+            let thread = table.get::<_, Option<LuaThread>>("thread")?;
+            Script {
+                instance: lua.create_registry_value(instance)?,
+                loaded: true,
+                saved_thread: thread.map(|t| lua.create_registry_value(t)).transpose()?,
+            }
+        } else if let Some(class) = table.get::<_, Option<LuaTable>>("class")? {
+            table.set("class", LuaValue::Nil)?;
+            Script::with_fields(lua, class, table).to_lua_err()?
+        } else {
+            Script::new(lua, table).to_lua_err()?
+        };
+
+        builder.add(script);
+        Ok(())
+    }
+}
+
+inventory::submit! {
+    LuaComponent::new::<Script>("Script")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{components::Persistent, Space};
+
+    const COUNTER: &'static str = r#"
+        despawned = {}
+        Counter = {}
+
+        function Counter:on_spawn()
+            self.spawned = (self.spawned or 0) + 1
+            self.ticks = 0
+        end
+
+        function Counter:on_update()
+            self.ticks = self.ticks + 1
+        end
+
+        function Counter:on_despawn()
+            table.insert(despawned, self.name)
+        end
+    "#;
+
+    fn space() -> Result<Space> {
+        let mut space = Space::new()?;
+        space.register(ScriptSystem, "Script", &[])?;
+        space.maintain()?;
+        space.lua().context(|lua| lua.load(COUNTER).exec())?;
+        Ok(space)
+    }
+
+    fn spawn(space: &Space, source: &str) -> Result<Entity> {
+        let entity = space
+            .lua()
+            .context(|lua| lua.load(source).eval::<LuaEntity>())?;
+        Ok(entity.into())
+    }
+
+    fn field<T: for<'lua> FromLua<'lua>>(space: &Space, entity: Entity, name: &str) -> Result<T> {
+        space.lua().context(|lua| {
+            let world = lua.fetch_one::<World>()?;
+            let world = world.borrow();
+            let script = world.get::<Script>(entity)?;
+            Ok(script.instance(lua)?.get(name)?)
+        })
+    }
+
+    fn despawned(space: &Space) -> Result<Vec<String>> {
+        Ok(space
+            .lua()
+            .context(|lua| lua.globals().get::<_, Vec<String>>("despawned"))?)
+    }
+
+    #[test]
+    fn scripts_spawn_update_and_despawn() -> Result<()> {
+        let mut space = space()?;
+        let entity = spawn(
+            &space,
+            r#"return sludge.spawn { Script = { class = Counter, name = "turret" } }"#,
+        )?;
+
+        space.fixed_update()?;
+        assert_eq!(field::<u32>(&space, entity, "spawned")?, 1);
+        assert_eq!(field::<String>(&space, entity, "name")?, "turret");
+        assert_eq!(field::<LuaEntity>(&space, entity, "entity")?, entity.into());
+        assert!(space
+            .fetch_one::<ScriptManager>()?
+            .borrow()
+            .is_running(entity));
+
+        space.fixed_update()?;
+        let ticks = field::<u32>(&space, entity, "ticks")?;
+        assert!(ticks > 0);
+        for _ in 0..3 {
+            space.fixed_update()?;
+        }
+        assert_eq!(field::<u32>(&space, entity, "ticks")?, ticks + 3);
+        assert_eq!(field::<u32>(&space, entity, "spawned")?, 1);
+
+        space.world()?.borrow_mut().despawn(entity)?;
+        space.fixed_update()?;
+        assert_eq!(despawned(&space)?, ["turret"]);
+        assert!(!space
+            .fetch_one::<ScriptManager>()?
+            .borrow()
+            .is_running(entity));
+
+        // The update thread was killed along with the script.
+        space.fixed_update()?;
+        assert_eq!(despawned(&space)?, ["turret"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn removing_a_script_stops_it() -> Result<()> {
+        let mut space = space()?;
+        let entity = spawn(
+            &space,
+            r#"return sludge.spawn { Script = { class = Counter, name = "removed" } }"#,
+        )?;
+        space.fixed_update()?;
+        space.fixed_update()?;
+
+        space.world()?.borrow_mut().remove_one::<Script>(entity)?;
+        space.fixed_update()?;
+        assert_eq!(despawned(&space)?, ["removed"]);
+        assert!(space.world()?.borrow().contains(entity));
+        assert!(!space
+            .fetch_one::<ScriptManager>()?
+            .borrow()
+            .is_running(entity));
+
+        Ok(())
+    }
+
+    #[test]
+    fn scripts_persist_without_spawning_again() -> Result<()> {
+        let mut space = space()?;
+        let entity = spawn(
+            &space,
+            r#"
+            return sludge.spawn {
+                Persistent = true,
+                Script = { class = Counter, name = "saved" },
+            }
+            "#,
+        )?;
+        for _ in 0..3 {
+            space.fixed_update()?;
+        }
+        let saved_ticks = field::<u32>(&space, entity, "ticks")?;
+
+        let mut bytes = Vec::<u8>::new();
+        space.save(&mut bytes)?;
+        let mut loaded = self::space()?;
+        loaded.load(&mut &bytes[..])?;
+        loaded.fixed_update()?;
+
+        let entity = loaded
+            .world()?
+            .borrow()
+            .query::<()>()
+            .with::<Script>()
+            .with::<Persistent>()
+            .iter()
+            .next()
+            .map(|(entity, _)| entity)
+            .expect("no script was loaded");
+        assert_eq!(field::<u32>(&loaded, entity, "spawned")?, 1);
+        assert_eq!(field::<String>(&loaded, entity, "name")?, "saved");
+        let ticks = field::<u32>(&loaded, entity, "ticks")?;
+        assert!(ticks >= saved_ticks);
+        loaded.fixed_update()?;
+        assert_eq!(field::<u32>(&loaded, entity, "ticks")?, ticks + 1);
+        assert!(loaded
+            .fetch_one::<ScriptManager>()?
+            .borrow()
+            .is_running(entity));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,460 @@
+//! Player-facing settings, kept in a TOML file in the user's config directory.
+//!
+//! [`Conf`] only decides how the window is created, and is baked into the game. The
+//! [`Settings`] resource holds everything the player can change from an options menu,
+//! like the window size, audio volumes and key bindings, plus whatever sections the game
+//! adds of its own. They're read from [`SETTINGS_PATH`] in the user's config directory
+//! when the first space is built with a [`Filesystem`](crate::filesystem::Filesystem) in
+//! its global resources, and inserted into the global resources. Settings needed before
+//! that, like the window size, can be loaded by hand and inserted ahead of time instead:
+//!
+//! ```ignore
+//! let settings = Settings::load(fs.user_path(settings::SETTINGS_PATH)?)?;
+//! settings.graphics().apply(&mut conf);
+//! global_resources.borrow_mut().insert(settings);
+//! ```
+//!
+//! ```toml
+//! [graphics]
+//! window_width = 1280
+//! window_height = 720
+//!
+//! [audio]
+//! music = 0.5
+//!
+//! [keybinds]
+//! jump = ["Space", "Z"]
+//!
+//! [game]
+//! difficulty = "hard"
+//! ```
+//!
+//! Settings are addressed by dotted keys, such as `"audio.music"`, and read and written
+//! either as whole typed sections, like [`AudioSettings`], or one value at a time with
+//! [`Settings::get`] and [`Settings::set`]. Every change is queued up, and [`update`]
+//! broadcasts each changed key to Lua as [`CHANGED_EVENT`], with its new value, and
+//! then writes the file back out if anything was set;
+//! [`Space::fixed_update`](crate::Space::fixed_update) calls it every tick. The file
+//! can also be edited while the game is running and read back in with
+//! [`Settings::reload`], which queues up a change for every value which differs.
+//!
+//! From Lua, settings are read with `sludge.settings.get(key)` and written with
+//! `sludge.settings.set(key, value)`:
+//!
+//! ```lua
+//! sludge.settings.set("audio.music", 0.25)
+//! local _, _, key, value = yield(sludge.settings.CHANGED_EVENT)
+//! ```
+
+use {
+    anyhow::*,
+    rlua::prelude::*,
+    serde::{de::DeserializeOwned, Deserialize, Serialize},
+    std::{
+        collections::{BTreeMap, BTreeSet},
+        fs,
+        path::{Path, PathBuf},
+    },
+    toml::{value::Table, Value},
+};
+
+use crate::{conf::Conf, SludgeLuaContextExt};
+
+/// Where the settings file is kept, in the user's config directory.
+pub const SETTINGS_PATH: &'static str = "/settings.toml";
+
+/// Broadcast for every setting which changed, with its dotted key and its new value,
+/// which is `nil` if the setting was removed.
+pub const CHANGED_EVENT: &'static str = "sludge.settings.changed";
+
+/// The `graphics` section.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GraphicsSettings {
+    pub window_width: u32,
+    pub window_height: u32,
+    pub fullscreen: bool,
+    pub high_dpi: bool,
+}
+
+impl Default for GraphicsSettings {
+    fn default() -> Self {
+        let conf = Conf::default();
+        Self {
+            window_width: conf.window_width,
+            window_height: conf.window_height,
+            fullscreen: conf.fullscreen,
+            high_dpi: conf.high_dpi,
+        }
+    }
+}
+
+impl GraphicsSettings {
+    /// Overwrite the window settings in a [`Conf`] with these.
+    pub fn apply(&self, conf: &mut Conf) {
+        conf.window_width = self.window_width;
+        conf.window_height = self.window_height;
+        conf.fullscreen = self.fullscreen;
+        conf.high_dpi = self.high_dpi;
+    }
+}
+
+/// The `audio` section. Volumes range from `0` to `1`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioSettings {
+    pub master: f32,
+    pub music: f32,
+    pub sfx: f32,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self {
+            master: 1.,
+            music: 1.,
+            sfx: 1.,
+        }
+    }
+}
+
+/// The `keybinds` section: the names of the keys bound to each action. Key names are
+/// left for the game to interpret.
+pub type KeybindSettings = BTreeMap<String, Vec<String>>;
+
+/// Settings loaded from, and saved back to, a TOML file. See the [module
+/// documentation](self) for details.
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    path: Option<PathBuf>,
+    values: Table,
+    autosave: bool,
+    dirty: bool,
+    changed: BTreeSet<String>,
+}
+
+impl Settings {
+    /// Settings which aren't backed by a file, and so are never saved.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load settings from a TOML file, which is saved back to whenever a setting is
+    /// changed. A missing file is treated as an empty one, and created on the first save.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let values = Self::read(&path)?;
+        Ok(Self {
+            path: Some(path),
+            values,
+            autosave: true,
+            dirty: false,
+            changed: BTreeSet::new(),
+        })
+    }
+
+    fn read(path: &Path) -> Result<Table> {
+        if !path.exists() {
+            return Ok(Table::new());
+        }
+
+        let text = fs::read_to_string(path)
+            .with_context(|| anyhow!("error reading settings from {}", path.display()))?;
+        toml::from_str(&text)
+            .with_context(|| anyhow!("error parsing settings from {}", path.display()))
+    }
+
+    /// The file the settings are saved to, if there is one.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Whether changed settings are saved by [`update`]. On by default for settings
+    /// loaded from a file.
+    pub fn autosave(&self) -> bool {
+        self.autosave
+    }
+
+    pub fn set_autosave(&mut self, autosave: bool) {
+        self.autosave = autosave;
+    }
+
+    /// Get a setting by its dotted key, or `None` if it's missing or isn't a `T`.
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        self.get_value(key)?.clone().try_into().ok()
+    }
+
+    /// Get a setting by its dotted key, or `default` if it's missing or isn't a `T`.
+    pub fn get_or<T: DeserializeOwned>(&self, key: &str, default: T) -> T {
+        self.get(key).unwrap_or(default)
+    }
+
+    pub fn get_value(&self, key: &str) -> Option<&Value> {
+        let mut parts = key.split('.');
+        let mut value = self.values.get(parts.next()?)?;
+        for part in parts {
+            value = value.as_table()?.get(part)?;
+        }
+        Some(value)
+    }
+
+    /// Set a setting by its dotted key, creating any tables along the way.
+    pub fn set<T: Serialize>(&mut self, key: &str, value: T) -> Result<()> {
+        self.set_value(key, Some(Value::try_from(value)?))
+    }
+
+    /// Remove a setting, so that it goes back to its default.
+    pub fn remove(&mut self, key: &str) -> Result<()> {
+        self.set_value(key, None)
+    }
+
+    fn set_value(&mut self, key: &str, value: Option<Value>) -> Result<()> {
+        let mut parts = key.split('.').collect::<Vec<_>>();
+        let last = parts.pop().filter(|last| !last.is_empty());
+        let last = last.ok_or_else(|| anyhow!("invalid settings key `{}`", key))?;
+
+        let mut table = &mut self.values;
+        for part in parts {
+            table = table
+                .entry(part)
+                .or_insert_with(|| Value::Table(Table::new()))
+                .as_table_mut()
+                .ok_or_else(|| anyhow!("`{}` is not a table, setting `{}`", part, key))?;
+        }
+
+        let changed = match value {
+            Some(value) => table.insert(last.to_owned(), value.clone()) != Some(value),
+            None => table.remove(last).is_some(),
+        };
+
+        if changed {
+            self.changed.insert(key.to_owned());
+            self.dirty = true;
+        }
+
+        Ok(())
+    }
+
+    /// Read a whole section, falling back to its default if it's missing or malformed.
+    pub fn section<T: DeserializeOwned + Default>(&self, name: &str) -> T {
+        match self.get_value(name) {
+            Some(value) => value.clone().try_into().unwrap_or_else(|err| {
+                log::warn!("malformed `{}` settings, using defaults: {}", name, err);
+                T::default()
+            }),
+            None => T::default(),
+        }
+    }
+
+    /// Replace a whole section.
+    pub fn set_section<T: Serialize>(&mut self, name: &str, section: &T) -> Result<()> {
+        self.set(name, section)
+    }
+
+    pub fn graphics(&self) -> GraphicsSettings {
+        self.section("graphics")
+    }
+
+    pub fn audio(&self) -> AudioSettings {
+        self.section("audio")
+    }
+
+    pub fn keybinds(&self) -> KeybindSettings {
+        self.section("keybinds")
+    }
+
+    /// Read the file back in, replacing every setting, and queue up a change for each
+    /// value which differs. Unsaved changes are lost.
+    pub fn reload(&mut self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return Ok(()),
+        };
+
+        let values = Self::read(&path)?;
+        let mut changed = BTreeSet::new();
+        diff_tables("", &self.values, &values, &mut changed);
+        self.changed.extend(changed);
+        self.values = values;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Write the settings out to their file, if they have one.
+    pub fn save(&mut self) -> Result<()> {
+        if let Some(path) = &self.path {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            // Serializing the table as a `Value` sorts plain values ahead of tables, as
+            // TOML needs; serializing the map itself doesn't.
+            let text = toml::to_string_pretty(&Value::Table(self.values.clone()))?;
+            fs::write(path, text)
+                .with_context(|| anyhow!("error saving settings to {}", path.display()))?;
+        }
+
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Take the dotted key of every setting changed since the last drain.
+    pub fn drain_changes(&mut self) -> impl Iterator<Item = String> {
+        std::mem::take(&mut self.changed).into_iter()
+    }
+}
+
+/// Collect the dotted keys of every leaf value which differs between two tables.
+fn diff_tables(prefix: &str, old: &Table, new: &Table, changed: &mut BTreeSet<String>) {
+    for key in old.keys().chain(new.keys()) {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+
+        match (old.get(key), new.get(key)) {
+            (Some(Value::Table(old)), Some(Value::Table(new))) => {
+                diff_tables(&path, old, new, changed)
+            }
+            (old, new) if old != new => {
+                changed.insert(path);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Broadcast every queued change to the [`Settings`] to Lua, and save them if anything
+/// was set. Does nothing if there are no `Settings`.
+pub fn update(lua: LuaContext) -> Result<()> {
+    let settings = match lua.fetch_one::<Settings>() {
+        Ok(settings) => settings,
+        Err(_) => return Ok(()),
+    };
+
+    let changes = {
+        let mut settings = settings.borrow_mut();
+        if settings.dirty && settings.autosave {
+            settings.save()?;
+        }
+
+        settings
+            .drain_changes()
+            .map(|key| {
+                let value = settings.get_value(&key).cloned();
+                (key, value)
+            })
+            .collect::<Vec<_>>()
+    };
+
+    for (key, value) in changes {
+        let value = match value {
+            Some(value) => rlua_serde::to_value(lua, &value)?,
+            None => LuaValue::Nil,
+        };
+        lua.broadcast(CHANGED_EVENT, (key, value))?;
+    }
+
+    Ok(())
+}
+
+inventory::submit! {
+    crate::api::Module::parse("sludge.settings", |lua| {
+        let table = lua.create_table()?;
+
+        table.set(
+            "get",
+            lua.create_function(|lua, (key, default): (String, LuaValue)| {
+                let value = lua.fetch_one::<Settings>()?.borrow().get_value(&key).cloned();
+                match value {
+                    Some(value) => rlua_serde::to_value(lua, &value),
+                    None => Ok(default),
+                }
+            })?,
+        )?;
+
+        table.set(
+            "set",
+            lua.create_function(|lua, (key, value): (String, LuaValue)| {
+                let settings = lua.fetch_one::<Settings>()?;
+                let mut settings = settings.borrow_mut();
+                match value {
+                    LuaValue::Nil => settings.remove(&key),
+                    value => {
+                        let value = rlua_serde::from_value::<Value>(value)?;
+                        settings.set(&key, value)
+                    }
+                }
+                .to_lua_err()
+            })?,
+        )?;
+
+        table.set(
+            "reload",
+            lua.create_function(|lua, ()| {
+                lua.fetch_one::<Settings>()?.borrow_mut().reload().to_lua_err()
+            })?,
+        )?;
+
+        table.set(
+            "save",
+            lua.create_function(|lua, ()| {
+                lua.fetch_one::<Settings>()?.borrow_mut().save().to_lua_err()
+            })?,
+        )?;
+
+        table.set("CHANGED_EVENT", CHANGED_EVENT)?;
+
+        Ok(LuaValue::Table(table))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dotted_keys_create_tables_and_record_changes() -> Result<()> {
+        let mut settings = Settings::new();
+        settings.set("audio.music", 0.5)?;
+        settings.set("audio.music", 0.5)?;
+        settings.set("game.difficulty", "hard")?;
+
+        assert_eq!(settings.get::<f32>("audio.music"), Some(0.5));
+        assert_eq!(settings.audio().music, 0.5);
+        assert_eq!(settings.audio().sfx, 1.);
+        assert_eq!(settings.get_or("game.lives", 3), 3);
+        assert!(settings.set("audio.music.left", 1.).is_err());
+
+        let changes = settings.drain_changes().collect::<Vec<_>>();
+        assert_eq!(changes, vec!["audio.music", "game.difficulty"]);
+        Ok(())
+    }
+
+    #[test]
+    fn save_and_reload_mixed_tables() -> Result<()> {
+        let path = std::env::temp_dir().join(format!(
+            "sludge-settings-{}/settings.toml",
+            std::process::id()
+        ));
+        let mut settings = Settings::load(&path)?;
+        settings.set("audio.music", 0.5)?;
+        settings.set("volume", 3)?;
+        settings.set("name", "player")?;
+        settings.set("keybinds.jump", vec!["Space", "Z"])?;
+        settings.set("graphics.window.width", 640)?;
+        settings.save()?;
+
+        let reloaded = Settings::load(&path)?;
+        fs::remove_dir_all(path.parent().unwrap())?;
+
+        assert_eq!(reloaded.values, settings.values);
+        assert_eq!(reloaded.get::<i64>("volume"), Some(3));
+        assert_eq!(reloaded.get::<i64>("graphics.window.width"), Some(640));
+        assert_eq!(
+            reloaded.keybinds()["jump"],
+            vec!["Space".to_owned(), "Z".to_owned()]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+//! Explicit teardown of a [`Space`](crate::Space).
+//!
+//! Simply dropping a space leaves the order its Lua state, its resources and anything
+//! holding onto GPU or FMOD handles are destroyed in up to Rust's drop order, which
+//! isn't always one that works: a script might still be holding an event instance
+//! when the FMOD system goes away, for example. [`Space::shutdown`] tears the space
+//! down in a fixed order instead, by running every registered [`Finalizer`] one
+//! [`ShutdownStage`] at a time and then dropping the Lua state.
+//!
+//! Finalizers are registered with `inventory::submit!`, like [`Module`]s, and are run
+//! for every space which is shut down:
+//!
+//! ```ignore
+//! inventory::submit! {
+//!     Finalizer::new("fmod", ShutdownStage::ReleaseInstances, |_lua, resources| {
+//!         if let Ok(fmod) = resources.local.fetch_one::<Fmod>() {
+//!             fmod.borrow().release_instances()?;
+//!         }
+//!         Ok(())
+//!     })
+//! }
+//! ```
+//!
+//! Finalizers should only touch resources the space owns, which are the ones in its
+//! local resources; global resources are shared with other spaces and outlive it. Once
+//! every space is shut down, [`finalize_global`] runs the same finalizers over the
+//! global resources, as though they were the local resources of one last space, so
+//! that a global FMOD system or asset cache is torn down in the same order.
+//!
+//! [`Space::shutdown`]: crate::Space::shutdown
+//! [`Module`]: crate::api::Module
+
+use {anyhow::*, rlua::prelude::*};
+
+use crate::{
+    assets::DefaultCache,
+    ecs::World,
+    resources::{Resources, SharedResources, UnifiedResources},
+    worlds::Worlds,
+    Scheduler,
+};
+
+/// The stages a space is torn down in, in the order they run. Once every stage has
+/// run, the space's Lua state is dropped, followed by its resources.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ShutdownStage {
+    /// Stop every script, so that nothing else runs on the Lua side.
+    StopScripts,
+    /// Stop and release sound instances and anything else owned by an external
+    /// system which has to go before the system itself.
+    ReleaseInstances,
+    /// Drop anything owning GPU handles, so that they're queued for deletion while the
+    /// graphics context is still around to delete them.
+    QueueGpu,
+}
+
+pub type FinalizerFn =
+    Box<dyn for<'lua> Fn(LuaContext<'lua>, &UnifiedResources<'static>) -> Result<()> + 'static>;
+
+/// A step of tearing down a space, registered with `inventory::submit!`. See the
+/// [module documentation](self) for details.
+pub struct Finalizer {
+    name: &'static str,
+    stage: ShutdownStage,
+    run: FinalizerFn,
+}
+
+impl Finalizer {
+    pub fn new<F>(name: &'static str, stage: ShutdownStage, run: F) -> Self
+    where
+        F: for<'lua> Fn(LuaContext<'lua>, &UnifiedResources<'static>) -> Result<()> + 'static,
+    {
+        Self {
+            name,
+            stage,
+            run: Box::new(run),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn stage(&self) -> ShutdownStage {
+        self.stage
+    }
+}
+
+inventory::collect!(Finalizer);
+
+/// Run every registered finalizer, stage by stage. A finalizer which fails doesn't
+/// stop the rest from running; the first error is returned once they all have.
+pub(crate) fn finalize(lua: LuaContext, resources: &UnifiedResources<'static>) -> Result<()> {
+    let mut finalizers = inventory::iter::<Finalizer>.into_iter().collect::<Vec<_>>();
+    finalizers.sort_by_key(|finalizer| finalizer.stage);
+
+    let mut first_err = None;
+    for finalizer in finalizers {
+        if let Err(err) = (finalizer.run)(lua, resources) {
+            let err = err.context(format!("error running finalizer `{}`", finalizer.name));
+            log::error!("{:?}", err);
+            first_err.get_or_insert(err);
+        }
+    }
+
+    first_err.map_or(Ok(()), Err)
+}
+
+/// Run every registered finalizer over the global resources, stage by stage, with a
+/// fresh Lua state standing in for a space's. Call this once every space sharing them
+/// has been [shut down](crate::Space::shutdown), just before dropping them.
+pub fn finalize_global(global: &SharedResources<'static>) -> Result<()> {
+    let resources = UnifiedResources {
+        local: global.clone(),
+        global: SharedResources::new(),
+    };
+    Lua::new().context(|lua| finalize(lua, &resources))
+}
+
+inventory::submit! {
+    Finalizer::new("sludge.scheduler", ShutdownStage::StopScripts, |lua, resources| {
+        if let Ok(scheduler) = resources.local.fetch_one::<Scheduler>() {
+            scheduler.borrow_mut().clear(lua)?;
+        }
+        Ok(())
+    })
+}
+
+inventory::submit! {
+    Finalizer::new("sludge.worlds", ShutdownStage::QueueGpu, |_lua, resources| {
+        if let Ok(worlds) = resources.local.fetch_one::<Worlds>() {
+            let mut worlds = worlds.borrow_mut();
+            let names = worlds.names().map(str::to_owned).collect::<Vec<_>>();
+            for name in names {
+                worlds.remove(&name)?;
+            }
+        }
+
+        if let Ok(world) = resources.local.fetch_one::<World>() {
+            world.borrow_mut().clear();
+        }
+
+        Ok(())
+    })
+}
+
+inventory::submit! {
+    Finalizer::new("sludge.assets", ShutdownStage::QueueGpu, |_lua, resources| {
+        if let Ok(cache) = resources.local.fetch_one::<DefaultCache>() {
+            cache.borrow().clear();
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {
+        crate::{components::Name, Space},
+        std::sync::{Arc, Mutex},
+    };
+
+    /// Finalizers are run for every space shut down in any test, so the ones here only
+    /// do anything in spaces with a `ShutdownLog`.
+    #[derive(Default)]
+    struct ShutdownLog {
+        stages: Arc<Mutex<Vec<ShutdownStage>>>,
+        fail: bool,
+    }
+
+    fn record(resources: &UnifiedResources<'static>, stage: ShutdownStage) -> Result<()> {
+        if let Ok(log) = resources.local.fetch_one::<ShutdownLog>() {
+            let log = log.borrow();
+            log.stages.lock().unwrap().push(stage);
+            ensure!(!log.fail, "failed at {:?}", stage);
+        }
+        Ok(())
+    }
+
+    inventory::submit! {
+        Finalizer::new("test.gpu", ShutdownStage::QueueGpu, |_lua, resources| {
+            record(resources, ShutdownStage::QueueGpu)
+        })
+    }
+
+    inventory::submit! {
+        Finalizer::new("test.stop", ShutdownStage::StopScripts, |_lua, resources| {
+            record(resources, ShutdownStage::StopScripts)
+        })
+    }
+
+    inventory::submit! {
+        Finalizer::new("test.release", ShutdownStage::ReleaseInstances, |_lua, resources| {
+            record(resources, ShutdownStage::ReleaseInstances)
+        })
+    }
+
+    #[test]
+    fn stages_run_in_order() -> Result<()> {
+        let space = Space::new()?;
+        let log = ShutdownLog::default();
+        let stages = log.stages.clone();
+        space.resources().borrow_mut().insert(log);
+
+        let world = space.world()?;
+        world.borrow_mut().spawn((Name("doomed".to_owned()),));
+        space.lua().context(|lua| {
+            lua.load("sludge.thread.spawn(function() while true do yield() end end)")
+                .exec()
+        })?;
+
+        space.shutdown()?;
+
+        assert_eq!(
+            *stages.lock().unwrap(),
+            vec![
+                ShutdownStage::StopScripts,
+                ShutdownStage::ReleaseInstances,
+                ShutdownStage::QueueGpu,
+            ]
+        );
+        assert_eq!(world.borrow().iter().count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn failing_finalizers_dont_stop_the_rest() -> Result<()> {
+        let space = Space::new()?;
+        let log = ShutdownLog {
+            fail: true,
+            ..ShutdownLog::default()
+        };
+        let stages = log.stages.clone();
+        space.resources().borrow_mut().insert(log);
+
+        let err = space.shutdown().unwrap_err();
+        assert!(format!("{:?}", err).contains("StopScripts"), "{:?}", err);
+        assert_eq!(stages.lock().unwrap().len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn global_resources_are_finalized_as_local_ones() -> Result<()> {
+        let global = SharedResources::new();
+        let log = ShutdownLog::default();
+        let stages = log.stages.clone();
+        global.borrow_mut().insert(log);
+
+        finalize_global(&global)?;
+        assert_eq!(stages.lock().unwrap().len(), 3);
+        Ok(())
+    }
+}
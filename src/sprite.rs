@@ -12,7 +12,9 @@ use crate::{
     assets::{Asset, Cache, Cached, DefaultCache, Key, Loaded},
     ecs::*,
     filesystem::Filesystem,
+    layers::{self, Layer},
     math::*,
+    persist::PersistPolicy,
     Resources, SludgeResultExt,
 };
 
@@ -69,6 +71,12 @@ impl From<aseprite::Direction> for Direction {
     }
 }
 
+impl Default for Direction {
+    fn default() -> Self {
+        Self::Forward
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Tag {
     pub name: String,
@@ -119,19 +127,178 @@ impl Tag {
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Frame {
+    /// The frame's (possibly trimmed) rectangle in the sheet's image, in pixels.
     pub frame: Box2<u32>,
+    /// Where the trimmed rectangle sits inside the untrimmed source frame.
     pub frame_source: Box2<u32>,
     pub source_size: Vector2<u32>,
+    /// The point in the untrimmed source frame which the sprite is positioned and
+    /// rotated around, in pixels. Defaults to the center of the frame.
+    #[serde(default)]
+    pub pivot: Vector2<f32>,
+    /// Where the top-left corner of the trimmed frame is drawn relative to the pivot.
     pub offset: Vector2<f32>,
     pub uvs: Box2<f32>,
     pub duration: u32,
 }
 
+impl Frame {
+    /// Describe a frame cut out of an image of size `sheet_size`. If `pivot` is `None`,
+    /// the frame pivots around its center.
+    pub fn new(
+        sheet_size: Vector2<u32>,
+        frame: Box2<u32>,
+        frame_source: Box2<u32>,
+        source_size: Vector2<u32>,
+        pivot: Option<Vector2<f32>>,
+        duration: u32,
+    ) -> Self {
+        let pivot = pivot.unwrap_or_else(|| source_size.map(|n| n as f32) / 2.);
+        let extents = frame.extents();
+        let uvs = Box2::new(
+            frame.mins.x as f32 / sheet_size.x as f32,
+            frame.mins.y as f32 / sheet_size.y as f32,
+            extents.x as f32 / sheet_size.x as f32,
+            extents.y as f32 / sheet_size.y as f32,
+        );
+
+        let mut this = Self {
+            frame,
+            frame_source,
+            source_size,
+            pivot,
+            offset: Vector2::zeros(),
+            uvs,
+            duration,
+        };
+        this.set_pivot(pivot);
+        this
+    }
+
+    /// Move the frame's pivot, updating its offset.
+    pub fn set_pivot(&mut self, pivot: Vector2<f32>) {
+        self.pivot = pivot;
+        self.offset = (self.frame_source.mins.coords.map(|n| n as f32) - pivot).map(f32::floor);
+    }
+}
+
+/// An Aseprite export's slices, which aren't parsed by the `aseprite` crate.
+#[derive(Debug, Deserialize)]
+struct AsepriteSlices {
+    meta: AsepriteSlicesMeta,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AsepriteSlicesMeta {
+    #[serde(default)]
+    slices: Vec<AsepriteSlice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AsepriteSlice {
+    name: String,
+    keys: Vec<AsepriteSliceKey>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AsepriteSliceKey {
+    frame: u32,
+    bounds: AsepritePoint,
+    #[serde(default)]
+    pivot: Option<AsepritePoint>,
+}
+
+/// Either a slice's bounds or its pivot; we only need the position of the former.
+#[derive(Debug, Deserialize)]
+struct AsepritePoint {
+    x: i32,
+    y: i32,
+}
+
+/// A uniform grid of frames in a [`SpriteSheetDef`], read left to right and then top to
+/// bottom.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GridDef {
+    /// The size of each cell.
+    pub cell: (u32, u32),
+    /// How many cells to take. Defaults to every cell which fits in the image.
+    #[serde(default)]
+    pub count: Option<u32>,
+    /// The position of the top-left corner of the first cell.
+    #[serde(default)]
+    pub margin: (u32, u32),
+    /// Space left between cells.
+    #[serde(default)]
+    pub spacing: (u32, u32),
+    #[serde(default = "default_frame_duration")]
+    pub duration: u32,
+    /// The pivot of every cell, relative to the cell's top-left corner.
+    #[serde(default)]
+    pub pivot: Option<(f32, f32)>,
+}
+
+/// A single hand-placed frame in a [`SpriteSheetDef`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FrameDef {
+    #[serde(default)]
+    pub name: Option<String>,
+    /// The frame's rectangle in the image, as `(x, y, w, h)`.
+    pub src: (u32, u32, u32, u32),
+    /// The pivot, relative to the frame's top-left corner.
+    #[serde(default)]
+    pub pivot: Option<(f32, f32)>,
+    #[serde(default = "default_frame_duration")]
+    pub duration: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TagDef {
+    pub name: String,
+    pub from: u32,
+    pub to: u32,
+    #[serde(default)]
+    pub direction: Direction,
+}
+
+fn default_frame_duration() -> u32 {
+    100
+}
+
+/// A hand-written description of how to slice an image into frames, for sheets which
+/// weren't exported from Aseprite. Sprite sheets with a `.ron` extension are loaded
+/// from this format:
+///
+/// ```ron
+/// (
+///     image: "/sprites/player.png",
+///     size: (128, 64),
+///     grid: Some((cell: (32, 32), pivot: Some((16.0, 30.0)))),
+///     frames: [(name: Some("portrait"), src: (0, 32, 64, 32))],
+///     tags: [(name: "walk", from: 0, to: 3)],
+/// )
+/// ```
+///
+/// Grid frames come first, followed by the hand-placed frames.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpriteSheetDef {
+    pub image: String,
+    pub size: (u32, u32),
+    #[serde(default)]
+    pub grid: Option<GridDef>,
+    #[serde(default)]
+    pub frames: Vec<FrameDef>,
+    #[serde(default)]
+    pub tags: Vec<TagDef>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SpriteSheet {
     pub image: String,
     pub tag_ids: HashMap<String, TagId>,
     pub tags: Vec<Tag>,
+    /// Frames which were given names by the sheet's definition.
+    #[serde(default)]
+    pub frame_ids: HashMap<String, FrameId>,
     pub frames: Vec<Frame>,
     pub size: Vector2<u32>,
 }
@@ -186,30 +353,152 @@ impl SpriteSheet {
             let sb = ase_frame.sprite_source_size;
             let ss = ase_frame.source_size;
 
-            let duration = ase_frame.duration;
-            let frame = Box2::new(fr.x, fr.y, fr.w, fr.h);
-            let frame_source = Box2::new(sb.x, sb.y, sb.w, sb.h);
-            let source_size = Vector2::new(ss.w, ss.h);
-            let offset = (Vector2::new(sb.x as f32, sb.y as f32)
-                - Vector2::new(ss.w as f32, ss.h as f32) / 2.)
-                .map(f32::floor);
-            let uvs = Box2::new(
-                fr.x as f32 / size.x as f32,
-                fr.y as f32 / size.y as f32,
-                fr.w as f32 / size.x as f32,
-                fr.h as f32 / size.y as f32,
-            );
+            frames.push(Frame::new(
+                size,
+                Box2::new(fr.x, fr.y, fr.w, fr.h),
+                Box2::new(sb.x, sb.y, sb.w, sb.h),
+                Vector2::new(ss.w, ss.h),
+                None,
+                ase_frame.duration,
+            ));
+        }
 
-            frames.push(Frame {
-                frame,
-                frame_source,
-                source_size,
-                offset,
-                uvs,
-                duration,
+        // Aseprite slices with pivots set the pivots of the frames their keys cover. If
+        // there's a slice named `pivot`, it's used; otherwise the first slice with a
+        // pivot is.
+        let slices = serde_json::from_str::<AsepriteSlices>(s)
+            .map(|data| data.meta.slices)
+            .unwrap_or_default();
+        let pivot_slice = slices
+            .iter()
+            .find(|slice| slice.name == "pivot")
+            .or_else(|| {
+                slices
+                    .iter()
+                    .find(|slice| slice.keys.iter().any(|key| key.pivot.is_some()))
             });
+        if let Some(slice) = pivot_slice {
+            for (i, key) in slice.keys.iter().enumerate() {
+                let pivot = match &key.pivot {
+                    Some(pivot) => Vector2::new(
+                        (key.bounds.x + pivot.x) as f32,
+                        (key.bounds.y + pivot.y) as f32,
+                    ),
+                    None => continue,
+                };
+                let end = slice
+                    .keys
+                    .get(i + 1)
+                    .map_or(frames.len(), |next| next.frame as usize);
+                for frame in frames.iter_mut().take(end).skip(key.frame as usize) {
+                    frame.set_pivot(pivot);
+                }
+            }
+        }
+
+        let tags = spritesheet_data
+            .meta
+            .frame_tags
+            .into_iter()
+            .flatten()
+            .map(|frame_tag| Tag {
+                name: frame_tag.name,
+                from: frame_tag.from,
+                to: frame_tag.to,
+                direction: Direction::from(frame_tag.direction),
+            });
+
+        Self::from_parts(
+            spritesheet_data
+                .meta
+                .image
+                .ok_or_else(|| anyhow!("no image path"))?,
+            size,
+            frames,
+            HashMap::new(),
+            tags,
+        )
+    }
+
+    /// Slice up an image according to a [`SpriteSheetDef`].
+    pub fn from_def(def: SpriteSheetDef) -> Result<Self> {
+        let size = Vector2::new(def.size.0, def.size.1);
+        let mut frames = Vec::new();
+        let mut frame_ids = HashMap::new();
+
+        if let Some(grid) = &def.grid {
+            let (cw, ch) = grid.cell;
+            ensure!(cw > 0 && ch > 0, "grid cells must not be empty");
+            let stride = (cw + grid.spacing.0, ch + grid.spacing.1);
+            let columns = (size.x.saturating_sub(grid.margin.0) + grid.spacing.0) / stride.0;
+            let rows = (size.y.saturating_sub(grid.margin.1) + grid.spacing.1) / stride.1;
+            let count = grid.count.unwrap_or(columns * rows);
+            ensure!(
+                count <= columns * rows,
+                "grid of {} cells doesn't fit in a {}x{} image",
+                count,
+                size.x,
+                size.y
+            );
+
+            for i in 0..count {
+                let x = grid.margin.0 + (i % columns) * stride.0;
+                let y = grid.margin.1 + (i / columns) * stride.1;
+                frames.push(Frame::new(
+                    size,
+                    Box2::new(x, y, cw, ch),
+                    Box2::new(0, 0, cw, ch),
+                    Vector2::new(cw, ch),
+                    grid.pivot.map(|(px, py)| Vector2::new(px, py)),
+                    grid.duration,
+                ));
+            }
+        }
+
+        for frame_def in def.frames {
+            let (x, y, w, h) = frame_def.src;
+            ensure!(
+                x + w <= size.x && y + h <= size.y,
+                "frame {:?} lies outside of the {}x{} image",
+                frame_def.src,
+                size.x,
+                size.y
+            );
+
+            if let Some(name) = frame_def.name {
+                frame_ids.insert(name, FrameId(frames.len() as u32));
+            }
+
+            frames.push(Frame::new(
+                size,
+                Box2::new(x, y, w, h),
+                Box2::new(0, 0, w, h),
+                Vector2::new(w, h),
+                frame_def.pivot.map(|(px, py)| Vector2::new(px, py)),
+                frame_def.duration,
+            ));
         }
 
+        let tags = def.tags.into_iter().map(|tag| Tag {
+            name: tag.name,
+            from: tag.from,
+            to: tag.to,
+            direction: tag.direction,
+        });
+
+        Self::from_parts(def.image, size, frames, frame_ids, tags)
+    }
+
+    /// Put together a sheet, adding the implicit unnamed tag covering every frame.
+    fn from_parts(
+        image: String,
+        size: Vector2<u32>,
+        frames: Vec<Frame>,
+        frame_ids: HashMap<String, FrameId>,
+        extra_tags: impl IntoIterator<Item = Tag>,
+    ) -> Result<Self> {
+        ensure!(!frames.is_empty(), "sprite sheet has no frames");
+
         let mut tags = vec![Tag {
             name: String::new(),
             from: 0,
@@ -217,13 +506,13 @@ impl SpriteSheet {
             direction: Direction::Forward,
         }];
 
-        for frame_tag in spritesheet_data.meta.frame_tags.into_iter().flatten() {
-            tags.push(Tag {
-                name: frame_tag.name,
-                from: frame_tag.from,
-                to: frame_tag.to,
-                direction: Direction::from(frame_tag.direction),
-            });
+        for tag in extra_tags {
+            ensure!(
+                tag.from <= tag.to && (tag.to as usize) < frames.len(),
+                "tag `{}` covers frames which don't exist",
+                tag.name
+            );
+            tags.push(tag);
         }
 
         let tag_ids = tags
@@ -233,12 +522,10 @@ impl SpriteSheet {
             .collect::<HashMap<_, _>>();
 
         Ok(Self {
-            image: spritesheet_data
-                .meta
-                .image
-                .ok_or_else(|| anyhow!("no image path"))?,
+            image,
             tag_ids,
             tags,
+            frame_ids,
             frames,
             size,
         })
@@ -292,6 +579,11 @@ impl SpriteSheet {
         self.tag_ids.get(s.as_ref()).copied()
     }
 
+    /// Look up a frame by the name it was given in the sheet's definition.
+    pub fn get_frame<K: AsRef<str>>(&self, s: K) -> Option<FrameId> {
+        self.frame_ids.get(s.as_ref()).copied()
+    }
+
     pub fn at_tag(&self, tag_id: TagId, should_loop: bool) -> (SpriteFrame, SpriteTag) {
         let tag = &self[tag_id];
         let ff = tag.first_frame();
@@ -341,10 +633,26 @@ impl Default for SpriteTag {
 }
 
 impl<'a> SmartComponent<ScContext<'a>> for SpriteName {}
+
+inventory::submit! {
+    CloneComponent::of::<SpriteName>()
+}
 impl<'a> SmartComponent<ScContext<'a>> for SpriteFrame {}
+
+inventory::submit! {
+    CloneComponent::of::<SpriteFrame>()
+}
 impl<'a> SmartComponent<ScContext<'a>> for SpriteTag {}
+
+inventory::submit! {
+    CloneComponent::of::<SpriteTag>()
+}
 impl<'a> SmartComponent<ScContext<'a>> for SpriteSheet {}
 
+inventory::submit! {
+    CloneComponent::of::<SpriteSheet>()
+}
+
 impl Asset for SpriteSheet {
     fn load<'a, R: Resources<'a>>(
         key: &Key,
@@ -358,7 +666,12 @@ impl Asset for SpriteSheet {
             .open(&path)?;
         let mut buf = String::new();
         fh.read_to_string(&mut buf)?;
-        Ok(SpriteSheet::from_json(&buf)?.into())
+
+        if path.extension().map_or(false, |ext| ext == "ron") {
+            Ok(SpriteSheet::from_def(ron::de::from_str(&buf)?)?.into())
+        } else {
+            Ok(SpriteSheet::from_json(&buf)?.into())
+        }
     }
 }
 
@@ -391,21 +704,41 @@ impl SpriteAnimation {
 
 impl<'a> SmartComponent<ScContext<'a>> for SpriteAnimation {}
 
+inventory::submit! {
+    CloneComponent::of::<SpriteAnimation>()
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct SpriteAnimationAccessor(Entity);
 
 impl LuaUserData for SpriteAnimationAccessor {
     fn add_methods<'lua, T: LuaUserDataMethods<'lua, Self>>(methods: &mut T) {
-        methods.add_method("goto_frame", |lua, this, frame: u32| {
+        methods.add_method("goto_frame", |lua, this, frame: LuaValue| {
             let tmp = lua.fetch_one::<World>()?;
             let world = tmp.borrow();
             let animation = &mut *world
                 .get_mut::<SpriteAnimation>(this.0.into())
                 .to_lua_err()?;
-            animation.frame.0 = FrameId(frame);
+            animation.frame.0 = match frame {
+                LuaValue::String(name) => animation
+                    .sheet
+                    .load_cached()
+                    .get_frame(name.to_str()?)
+                    .ok_or_else(|| anyhow!("no such frame"))
+                    .to_lua_err()?,
+                other => FrameId::from_lua(other, lua)?,
+            };
             Ok(())
         });
 
+        methods.add_method("pivot", |lua, this, ()| {
+            let tmp = lua.fetch_one::<World>()?;
+            let world = tmp.borrow();
+            let animation = world.get::<SpriteAnimation>(this.0.into()).to_lua_err()?;
+            let pivot = animation.current().pivot;
+            Ok((pivot.x, pivot.y))
+        });
+
         methods.add_method(
             "goto_tag",
             |lua, this, (tag_name, should_loop): (LuaString, Option<bool>)| {
@@ -466,7 +799,7 @@ impl LuaComponentInterface for SpriteAnimation {
         let tmp = lua.fetch_one::<DefaultCache>()?;
         let mut sprite_sheet = tmp
             .borrow()
-            .get::<SpriteSheet>(&Key::from_path(path.to_str()?))
+            .get::<SpriteSheet>(&Key::path(path.to_str()?))
             .to_lua_err()?;
 
         let should_loop = table.get::<_, Option<bool>>("should_loop")?.unwrap_or(true);
@@ -486,6 +819,11 @@ impl LuaComponentInterface for SpriteAnimation {
             tag,
             sheet: sprite_sheet,
         });
+
+        if let Some(layer) = table.get::<_, Option<LuaValue>>("layer")? {
+            builder.add(Layer(layers::from_lua_layer(lua, layer)?));
+        }
+
         Ok(())
     }
 }
@@ -493,3 +831,36 @@ impl LuaComponentInterface for SpriteAnimation {
 inventory::submit! {
     LuaComponent::new::<SpriteAnimation>("SpriteAnimation")
 }
+
+// The accessor has no `to_table`, and the cached sheet doesn't remember the path it was
+// loaded from, so there's nothing to save which the bundler could respawn it from.
+inventory::submit! {
+    PersistPolicy::skip::<SpriteAnimation>("SpriteAnimation")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grid_frames_use_pivot() {
+        let def = ron::de::from_str::<SpriteSheetDef>(
+            r#"(
+                image: "sheet.png",
+                size: (64, 32),
+                grid: Some((cell: (16, 16), count: Some(6), pivot: Some((8.0, 14.0)))),
+                frames: [(name: Some("big"), src: (32, 16, 32, 16))],
+            )"#,
+        )
+        .unwrap();
+        let sheet = SpriteSheet::from_def(def).unwrap();
+
+        assert_eq!(sheet.frames.len(), 7);
+        assert_eq!(sheet.frames[5].frame, Box2::new(16, 16, 16, 16));
+        assert_eq!(sheet.frames[5].offset, Vector2::new(-8., -14.));
+
+        let big = sheet[sheet.get_frame("big").unwrap()];
+        assert_eq!(big.pivot, Vector2::new(16., 8.));
+        assert_eq!(sheet[TagId(0)].to, 6);
+    }
+}
@@ -1,10 +1,11 @@
 use {anyhow::*, rlua::prelude::*, std::marker::PhantomData};
 
 use crate::{
+    api::{self, WorldEventSubscriptions},
     components::Parent,
     ecs::World,
     hierarchy::{HierarchyManager, ParentComponent},
-    transform::TransformManager,
+    transform::{Transform2dManager, TransformManager},
     OwnedResources, Resources, SharedResources, SludgeResultExt, UnifiedResources,
 };
 
@@ -21,17 +22,20 @@ impl crate::System for WorldEventSystem {
         if !resources.has_value::<World>() {
             resources.insert(World::new());
         }
+        if !resources.has_value::<WorldEventSubscriptions>() {
+            resources.insert(WorldEventSubscriptions::new());
+        }
         Ok(())
     }
 
-    fn update(&self, _lua: LuaContext, resources: &UnifiedResources) -> Result<()> {
+    fn update(&self, lua: LuaContext, resources: &UnifiedResources) -> Result<()> {
         let _ = resources
             .fetch_one::<World>()?
             .borrow_mut()
             .flush_queue()
             .log_error_err("sludge::ecs");
 
-        Ok(())
+        api::dispatch_component_events(lua)
     }
 }
 
@@ -105,3 +109,37 @@ impl<C: ParentComponent> crate::System for TransformSystem<C> {
             .update(resources)
     }
 }
+
+pub struct Transform2dSystem<C: ParentComponent>(PhantomData<C>);
+
+pub type DefaultTransform2dSystem = Transform2dSystem<Parent>;
+
+impl<C: ParentComponent> Transform2dSystem<C> {
+    pub fn new() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<C: ParentComponent> crate::System for Transform2dSystem<C> {
+    fn init(
+        &self,
+        _lua: LuaContext,
+        resources: &mut OwnedResources,
+        _: Option<&SharedResources>,
+    ) -> Result<()> {
+        if !resources.has_value::<Transform2dManager<C>>() {
+            let (world, hierarchy) = resources.fetch::<(World, HierarchyManager<C>)>()?;
+            let transform_graph =
+                Transform2dManager::<C>::new(&mut world.borrow_mut(), &mut hierarchy.borrow_mut());
+            resources.insert(transform_graph);
+        }
+        Ok(())
+    }
+
+    fn update(&self, _lua: LuaContext, resources: &UnifiedResources) -> Result<()> {
+        resources
+            .fetch_one::<Transform2dManager<C>>()?
+            .borrow_mut()
+            .update(resources)
+    }
+}
@@ -0,0 +1,324 @@
+//! Long-running Rust work, spread across frames or run on worker threads.
+//!
+//! Some work invoked from Lua is too heavy to finish in a single tick: finding a path
+//! across a big map, or spawning a whole level's worth of prefabs. Doing it all at once
+//! stalls the frame it's started on. A [`ChunkedTask`] is instead a resumable piece of
+//! work which does a little at a time each time it's [stepped](ChunkedTask::step). The
+//! space's [`ChunkedTasks`] resource steps every running task, round-robin, until its
+//! per-frame time budget is used up, so big jobs are amortized over as many frames as
+//! they need.
+//!
+//! Tasks are stepped by [`update`], which
+//! [`Space::fixed_update`](crate::Space::fixed_update) calls once per tick, right after
+//! updating the scheduler. Games which drive the scheduler by hand should call it
+//! themselves, once per tick:
+//!
+//! ```ignore
+//! space.lua().context(|lua| sludge::task::update(lua))?;
+//! ```
+//!
+//! Starting a task with [`spawn`] returns a promise for its result, which can be handed
+//! to Lua. Lua threads wait for it with `sludge.task.await`, which returns the task's
+//! result or raises its error. With a game-provided `load_level` function which
+//! spawns a level through a chunked task:
+//!
+//! ```lua
+//! local entities = sludge.task.await(load_level("caves"))
+//! ```
+//!
+//! Work which can run without touching Lua or the space's resources, like decoding an
+//! image or decompressing an archive, can go off the main thread entirely on a
+//! [`TaskPool`]. Jobs started with [`spawn_pooled`] return promises in the same way,
+//! and the main-thread halves of finished jobs are run by [`update`] too.
+//!
+//! When a task finishes, successfully or not, [`FINISHED_EVENT`] is broadcast with its
+//! promise. Tasks are Rust closures, so they can't be saved; a space with a promise
+//! reachable from Lua can't be saved either.
+
+use {
+    anyhow::*,
+    rlua::prelude::*,
+    std::{
+        mem,
+        sync::Mutex,
+        time::{Duration, Instant},
+    },
+};
+
+use crate::SludgeLuaContextExt;
+
+mod pool;
+
+pub use pool::{spawn_pooled, Completions, TaskPool};
+
+/// Broadcast with a task's promise when the task finishes.
+pub const FINISHED_EVENT: &'static str = "sludge.task.finished";
+
+/// What a [`ChunkedTask`] has to say after being stepped.
+pub enum Progress<'lua> {
+    /// There's more work to do.
+    Pending,
+    /// The task is finished, with the given result.
+    Done(LuaValue<'lua>),
+}
+
+/// A resumable piece of Rust work. See the [module documentation](self) for details.
+///
+/// This is implemented for any suitable closure.
+pub trait ChunkedTask: Send + 'static {
+    /// Do a small piece of the work, returning the result once there's nothing left to
+    /// do. Each step should be short; the time budget is only checked between steps.
+    fn step<'lua>(&mut self, lua: LuaContext<'lua>) -> Result<Progress<'lua>>;
+}
+
+impl<F> ChunkedTask for F
+where
+    F: for<'lua> FnMut(LuaContext<'lua>) -> Result<Progress<'lua>> + Send + 'static,
+{
+    fn step<'lua>(&mut self, lua: LuaContext<'lua>) -> Result<Progress<'lua>> {
+        self(lua)
+    }
+}
+
+/// The state of a task, as seen through its [`Promise`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TaskStatus {
+    Running,
+    Done,
+    Failed(String),
+    Cancelled,
+}
+
+/// The Lua side of a task: a promise for its result, which is kept in the userdata's
+/// user value once the task is done.
+#[derive(Debug)]
+pub struct Promise {
+    status: TaskStatus,
+}
+
+impl Promise {
+    pub fn status(&self) -> &TaskStatus {
+        &self.status
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.status != TaskStatus::Running
+    }
+}
+
+impl LuaUserData for Promise {
+    fn add_methods<'lua, T: LuaUserDataMethods<'lua, Self>>(methods: &mut T) {
+        methods.add_method("is_done", |_lua, this, ()| Ok(this.is_done()));
+
+        methods.add_method("status", |_lua, this, ()| {
+            Ok(match this.status {
+                TaskStatus::Running => "running",
+                TaskStatus::Done => "done",
+                TaskStatus::Failed(_) => "failed",
+                TaskStatus::Cancelled => "cancelled",
+            })
+        });
+
+        methods.add_function("result", |_lua, ud: LuaAnyUserData| {
+            let status = ud.borrow::<Promise>()?.status.clone();
+            match status {
+                TaskStatus::Done => ud.get_user_value::<LuaValue>(),
+                TaskStatus::Running => Err(anyhow!("task hasn't finished yet")).to_lua_err(),
+                TaskStatus::Failed(err) => Err(anyhow!("task failed: {}", err)).to_lua_err(),
+                TaskStatus::Cancelled => Err(anyhow!("task was cancelled")).to_lua_err(),
+            }
+        });
+
+        methods.add_method_mut("cancel", |_lua, this, ()| {
+            if this.status == TaskStatus::Running {
+                this.status = TaskStatus::Cancelled;
+            }
+            Ok(())
+        });
+    }
+}
+
+struct Running {
+    task: Mutex<Box<dyn ChunkedTask>>,
+    promise: LuaRegistryKey,
+}
+
+/// Every running [`ChunkedTask`] in a space, and how much time per frame they get.
+pub struct ChunkedTasks {
+    running: Vec<Running>,
+    budget: Duration,
+}
+
+impl Default for ChunkedTasks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChunkedTasks {
+    /// The default per-frame time budget, shared between every running task.
+    pub const DEFAULT_BUDGET: Duration = Duration::from_millis(2);
+
+    pub fn new() -> Self {
+        Self {
+            running: Vec::new(),
+            budget: Self::DEFAULT_BUDGET,
+        }
+    }
+
+    pub fn budget(&self) -> Duration {
+        self.budget
+    }
+
+    /// Set how long tasks may run each frame. Every frame with running tasks steps at
+    /// least one of them, no matter how small the budget is.
+    pub fn set_budget(&mut self, budget: Duration) {
+        self.budget = budget;
+    }
+
+    pub fn len(&self) -> usize {
+        self.running.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.running.is_empty()
+    }
+}
+
+/// Start running a task, returning the promise for its result.
+pub fn spawn<'lua>(lua: LuaContext<'lua>, task: impl ChunkedTask) -> Result<LuaAnyUserData<'lua>> {
+    let promise = promise(lua)?;
+    lua.fetch_one::<ChunkedTasks>()?
+        .borrow_mut()
+        .running
+        .push(Running {
+            task: Mutex::new(Box::new(task)),
+            promise: lua.create_registry_value(promise.clone())?,
+        });
+
+    Ok(promise)
+}
+
+/// Record the outcome of a task in its promise, and let everyone know it's finished.
+/// Outcomes of cancelled tasks are thrown away.
+pub(crate) fn resolve<'lua>(
+    lua: LuaContext<'lua>,
+    promise: LuaRegistryKey,
+    outcome: Result<LuaValue<'lua>>,
+) -> Result<()> {
+    let ud = lua.registry_value::<LuaAnyUserData>(&promise)?;
+    lua.remove_registry_value(promise)?;
+
+    if ud.borrow::<Promise>()?.status == TaskStatus::Cancelled {
+        return Ok(());
+    }
+
+    let status = match outcome {
+        Ok(value) => {
+            ud.set_user_value(value)?;
+            TaskStatus::Done
+        }
+        Err(err) => {
+            log::error!("task failed: {:?}", err);
+            TaskStatus::Failed(err.to_string())
+        }
+    };
+    ud.borrow_mut::<Promise>()?.status = status;
+
+    lua.broadcast(FINISHED_EVENT, ud)?;
+    Ok(())
+}
+
+/// Create a promise for a task which hasn't finished yet.
+pub(crate) fn promise<'lua>(lua: LuaContext<'lua>) -> Result<LuaAnyUserData<'lua>> {
+    Ok(lua.create_userdata(Promise {
+        status: TaskStatus::Running,
+    })?)
+}
+
+/// Run the main-thread halves of any [`TaskPool`] jobs which have finished, and then step
+/// the space's running tasks, round-robin, until they've all finished or the budget for
+/// this frame is used up. Cancelled tasks are dropped without being stepped.
+pub fn update(lua: LuaContext) -> Result<()> {
+    pool::update(lua)?;
+
+    let tasks = lua.fetch_one::<ChunkedTasks>()?;
+    let start = Instant::now();
+
+    // Tasks are taken out of the resource while they run, so that they can spawn more
+    // tasks; those are only stepped starting next frame.
+    let (mut running, budget) = {
+        let mut tasks = tasks.borrow_mut();
+        (mem::take(&mut tasks.running), tasks.budget)
+    };
+
+    let mut kept = Vec::with_capacity(running.len());
+    for task in running.drain(..) {
+        let promise = lua.registry_value::<LuaAnyUserData>(&task.promise)?;
+        if promise.borrow::<Promise>()?.status == TaskStatus::Running {
+            kept.push(task);
+        } else {
+            lua.remove_registry_value(task.promise)?;
+        }
+    }
+    running = kept;
+
+    let mut i = 0;
+    while !running.is_empty() {
+        i %= running.len();
+        let progress = running[i].task.get_mut().unwrap().step(lua);
+        match progress {
+            Ok(Progress::Pending) => i += 1,
+            Ok(Progress::Done(value)) => resolve(lua, running.remove(i).promise, Ok(value))?,
+            Err(err) => resolve(lua, running.remove(i).promise, Err(err))?,
+        }
+
+        if start.elapsed() >= budget {
+            break;
+        }
+    }
+
+    // Pick up where we left off next frame, so the first task doesn't get favored.
+    if !running.is_empty() {
+        let len = running.len();
+        running.rotate_left(i % len);
+    }
+
+    let mut tasks = tasks.borrow_mut();
+    running.append(&mut tasks.running);
+    tasks.running = running;
+
+    Ok(())
+}
+
+inventory::submit! {
+    crate::api::Module::parse("sludge.task", |lua| {
+        let table = lua.create_table()?;
+
+        table.set(
+            "budget",
+            lua.create_function(|lua, ()| {
+                Ok(lua.fetch_one::<ChunkedTasks>()?.borrow().budget().as_secs_f64())
+            })?,
+        )?;
+
+        table.set(
+            "set_budget",
+            lua.create_function(|lua, seconds: f64| {
+                lua.fetch_one::<ChunkedTasks>()?
+                    .borrow_mut()
+                    .set_budget(Duration::from_secs_f64(seconds.max(0.)));
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "count",
+            lua.create_function(|lua, ()| Ok(lua.fetch_one::<ChunkedTasks>()?.borrow().len()))?,
+        )?;
+
+        table.set("FINISHED_EVENT", FINISHED_EVENT)?;
+
+        Ok(LuaValue::Table(table))
+    })
+}
@@ -0,0 +1,177 @@
+//! A small pool of worker threads for work which doesn't need Lua.
+
+use {
+    anyhow::*,
+    crossbeam_channel::{Receiver, Sender},
+    rlua::prelude::*,
+    std::{
+        any::Any,
+        panic::{self, AssertUnwindSafe},
+        sync::Arc,
+        thread::{self, JoinHandle},
+    },
+};
+
+use crate::SludgeLuaContextExt;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The main-thread half of a finished job.
+type Completion = Box<dyn for<'lua> FnOnce(LuaContext<'lua>) -> Result<()> + Send>;
+
+#[derive(Debug)]
+struct Workers {
+    handles: Vec<JoinHandle<()>>,
+}
+
+/// A handle to a pool of worker threads. Cloning the handle shares the pool, and the
+/// workers shut down once every handle is dropped and they've run out of jobs.
+///
+/// A pool isn't tied to any one space, so a single pool is usually inserted into the
+/// global resources and shared. Jobs spawned from a space have their main-thread
+/// halves run by that space's [`update`](super::update), through its [`Completions`].
+/// Panics in jobs are caught: the worker carries on, and the promise for the job's
+/// result, if it has one, fails.
+#[derive(Debug, Clone)]
+pub struct TaskPool {
+    jobs: Sender<Job>,
+    workers: Arc<Workers>,
+}
+
+impl TaskPool {
+    /// Start a pool with `threads` worker threads.
+    pub fn new(threads: usize) -> Result<Self> {
+        let (jobs, receiver) = crossbeam_channel::unbounded::<Job>();
+        let handles = (0..threads.max(1))
+            .map(|i| {
+                let receiver = receiver.clone();
+                thread::Builder::new()
+                    .name(format!("sludge-pool-{}", i))
+                    .spawn(move || {
+                        // A panicking job mustn't take its worker down with it.
+                        for job in receiver {
+                            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                                log::error!("pooled job panicked: {}", panic_message(&*payload));
+                            }
+                        }
+                    })
+                    .map_err(Error::from)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            jobs,
+            workers: Arc::new(Workers { handles }),
+        })
+    }
+
+    pub fn threads(&self) -> usize {
+        self.workers.handles.len()
+    }
+
+    /// Run `job` on a worker thread, without anything to do when it finishes.
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        self.jobs
+            .send(Box::new(job))
+            .expect("the pool's workers only stop once every handle is dropped");
+    }
+
+    /// Run `job` on a worker thread, and then `then` with its result on the main thread,
+    /// the next time the space it was spawned from is updated. If `job` panics, `then`
+    /// gets an error instead.
+    pub fn spawn_then<T, F, C>(&self, completions: &Completions, job: F, then: C)
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T> + Send + 'static,
+        C: for<'lua> FnOnce(LuaContext<'lua>, Result<T>) -> Result<()> + Send + 'static,
+    {
+        let sender = completions.sender.clone();
+        self.execute(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(job)).unwrap_or_else(|payload| {
+                Err(anyhow!("job panicked: {}", panic_message(&*payload)))
+            });
+            let completion: Completion = Box::new(move |lua| then(lua, result));
+            // If the space is gone, there's nobody left to care about the result.
+            let _ = sender.send(completion);
+        });
+    }
+}
+
+/// The message a panic was started with, if it was started with one.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "<no message>"
+    }
+}
+
+/// The queue of finished [`TaskPool`] jobs waiting to run their main-thread halves in a
+/// space. Every space has one.
+#[derive(Debug)]
+pub struct Completions {
+    sender: Sender<Completion>,
+    receiver: Receiver<Completion>,
+}
+
+impl Default for Completions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Completions {
+    pub fn new() -> Self {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        Self { sender, receiver }
+    }
+
+    /// How many finished jobs are waiting for the next update.
+    pub fn len(&self) -> usize {
+        self.receiver.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.receiver.is_empty()
+    }
+}
+
+/// Run `job` on the space's [`TaskPool`], returning a promise for its result. The
+/// result is converted to a Lua value once the job has finished, on the main thread.
+pub fn spawn_pooled<'lua, T, F>(lua: LuaContext<'lua>, job: F) -> Result<LuaAnyUserData<'lua>>
+where
+    T: for<'a> ToLua<'a> + Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    let promise = super::promise(lua)?;
+    let key = lua.create_registry_value(promise.clone())?;
+
+    let pool = lua.fetch_one::<TaskPool>()?;
+    let completions = lua.fetch_one::<Completions>()?;
+    pool.borrow()
+        .spawn_then(&completions.borrow(), job, move |lua, result| {
+            let outcome = result.and_then(|value| Ok(value.to_lua(lua)?));
+            super::resolve(lua, key, outcome)
+        });
+
+    Ok(promise)
+}
+
+/// Run the main-thread halves of every job which has finished since the last update.
+/// Errors are logged rather than returned, so one failed job doesn't hold up the rest.
+pub(super) fn update(lua: LuaContext) -> Result<()> {
+    let finished = match lua.fetch_one::<Completions>() {
+        Ok(completions) => completions.borrow().receiver.try_iter().collect::<Vec<_>>(),
+        Err(_) => return Ok(()),
+    };
+
+    for completion in finished {
+        if let Err(err) = completion(lua) {
+            log::error!("error completing a pooled job: {:?}", err);
+        }
+    }
+
+    Ok(())
+}
@@ -54,6 +54,19 @@ fn deserialize_properties<T: Properties>(
     serde_json::from_value(Value::Object(json_map)).map_err(Error::from)
 }
 
+/// Whether an image layer's properties turn on repeating in the given direction, for
+/// maps made with versions of Tiled which can't set it on the layer itself.
+fn repeat_property(properties: &xml_parser::Properties, key: &str) -> bool {
+    matches!(
+        properties.get(key),
+        Some(xml_parser::PropertyValue::BoolValue(true))
+    )
+}
+
+fn default_parallax() -> f32 {
+    1.0
+}
+
 pub trait Properties: DeserializeOwned + Serialize + Send + Sync + Clone + 'static {}
 impl<T> Properties for T where T: DeserializeOwned + Serialize + Send + Sync + Clone + 'static {}
 
@@ -244,6 +257,30 @@ impl<T> TileSheet<T> {
     }
 }
 
+/// How a tile is flipped, as set with the flip and rotate tools in Tiled. The diagonal
+/// flip swaps the tile's axes and is applied before the horizontal and vertical flips;
+/// together they make up Tiled's rotations.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TileFlip {
+    pub horizontal: bool,
+    pub vertical: bool,
+    pub diagonal: bool,
+}
+
+impl TileFlip {
+    fn from_tiled(tile: &xml_parser::LayerTile) -> Self {
+        Self {
+            horizontal: tile.flip_h,
+            vertical: tile.flip_v,
+            diagonal: tile.flip_d,
+        }
+    }
+
+    pub fn is_flipped(&self) -> bool {
+        self.horizontal || self.vertical || self.diagonal
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Chunk {
     pub x: i32,
@@ -251,9 +288,38 @@ pub struct Chunk {
     pub w: u32,
     pub h: u32,
     pub data: Vec<u32>,
+    /// How each tile in `data` is flipped. Empty if none of them are.
+    #[serde(default)]
+    pub flips: Vec<TileFlip>,
 }
 
 impl Chunk {
+    fn from_tiled<'a>(
+        x: i32,
+        y: i32,
+        w: u32,
+        h: u32,
+        tiles: impl IntoIterator<Item = &'a xml_parser::LayerTile>,
+    ) -> Self {
+        let (data, mut flips): (Vec<_>, Vec<_>) = tiles
+            .into_iter()
+            .map(|lt| (lt.gid, TileFlip::from_tiled(lt)))
+            .unzip();
+
+        if !flips.iter().any(TileFlip::is_flipped) {
+            flips.clear();
+        }
+
+        Self {
+            x,
+            y,
+            w,
+            h,
+            data,
+            flips,
+        }
+    }
+
     pub fn tiles(&self) -> impl Iterator<Item = ((i32, i32), u32)> + '_ {
         let (w, x, y) = (self.w, self.x, self.y);
         self.data
@@ -263,6 +329,18 @@ impl Chunk {
             .map(move |(i, n)| ((i as u32 % w, i as u32 / w), n))
             .map(move |((i, j), n)| ((i as i32 + x, j as i32 + y), n))
     }
+
+    /// Like [`Chunk::tiles`], along with how each tile is flipped.
+    pub fn tiles_with_flips(&self) -> impl Iterator<Item = ((i32, i32), u32, TileFlip)> + '_ {
+        self.tiles()
+            .enumerate()
+            .map(move |(i, (coords, gid))| (coords, gid, self.flip(i)))
+    }
+
+    /// How the tile at `index` into `data` is flipped.
+    pub fn flip(&self, index: usize) -> TileFlip {
+        self.flips.get(index).copied().unwrap_or_default()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -271,6 +349,11 @@ pub struct TileLayer<L> {
     pub name: Option<String>,
     pub opacity: f32,
     pub visible: bool,
+    /// How fast the layer scrolls relative to the camera; see [`Layer::parallax`].
+    #[serde(default = "default_parallax")]
+    pub parallax_x: f32,
+    #[serde(default = "default_parallax")]
+    pub parallax_y: f32,
     pub chunks: HashMap<(i32, i32), Chunk>,
     pub properties: L,
 }
@@ -283,6 +366,8 @@ impl Default for DefaultTileLayer {
             name: None,
             opacity: 1.0,
             visible: true,
+            parallax_x: 1.0,
+            parallax_y: 1.0,
             chunks: HashMap::new(),
             properties: Value::Object(Default::default()),
         }
@@ -303,6 +388,19 @@ pub struct ImageLayer<L> {
     pub visible: bool,
     pub offset_x: f32,
     pub offset_y: f32,
+    /// How fast the layer scrolls relative to the camera; see [`Layer::parallax`].
+    #[serde(default = "default_parallax")]
+    pub parallax_x: f32,
+    #[serde(default = "default_parallax")]
+    pub parallax_y: f32,
+    /// Whether the image is repeated horizontally to fill the screen. Set either with
+    /// the layer's own setting in Tiled or a boolean `repeat_x` property.
+    #[serde(default)]
+    pub repeat_x: bool,
+    /// Whether the image is repeated vertically to fill the screen. Set either with the
+    /// layer's own setting in Tiled or a boolean `repeat_y` property.
+    #[serde(default)]
+    pub repeat_y: bool,
     pub source: PathBuf,
     pub image_width: u32,
     pub image_height: u32,
@@ -339,6 +437,11 @@ pub struct ObjectLayer<L, O> {
     pub name: String,
     pub opacity: f32,
     pub visible: bool,
+    /// How fast the layer scrolls relative to the camera; see [`Layer::parallax`].
+    #[serde(default = "default_parallax")]
+    pub parallax_x: f32,
+    #[serde(default = "default_parallax")]
+    pub parallax_y: f32,
     pub objects: Vec<Object<O>>,
     pub properties: L,
 }
@@ -351,6 +454,44 @@ pub enum Layer<L, O> {
     ObjectLayer(ObjectLayer<L, O>),
 }
 
+impl<L, O> Layer<L, O> {
+    /// The layer's parallax factors. A layer moves across the screen at this fraction of
+    /// the speed of the camera, so `1` is the default of moving with the rest of the
+    /// world, `0` stays fixed on screen, and anything in between looks further away.
+    /// Drawn at a camera position `p`, a layer is shifted by `p * (1 - parallax)`.
+    pub fn parallax(&self) -> Vector2<f32> {
+        let (x, y) = match self {
+            Layer::TileLayer(l) => (l.parallax_x, l.parallax_y),
+            Layer::ImageLayer(l) => (l.parallax_x, l.parallax_y),
+            Layer::ObjectLayer(l) => (l.parallax_x, l.parallax_y),
+        };
+        Vector2::new(x, y)
+    }
+
+    /// How far the layer is shifted when drawn with the camera centered on `camera`.
+    pub fn parallax_offset(&self, camera: &Point2<f32>) -> Vector2<f32> {
+        camera
+            .coords
+            .component_mul(&(Vector2::repeat(1.) - self.parallax()))
+    }
+
+    pub fn is_visible(&self) -> bool {
+        match self {
+            Layer::TileLayer(l) => l.visible,
+            Layer::ImageLayer(l) => l.visible,
+            Layer::ObjectLayer(l) => l.visible,
+        }
+    }
+
+    pub fn opacity(&self) -> f32 {
+        match self {
+            Layer::TileLayer(l) => l.opacity,
+            Layer::ImageLayer(l) => l.opacity,
+            Layer::ObjectLayer(l) => l.opacity,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(bound(deserialize = "L: Properties, T: Properties, O: Properties"))]
 pub struct TiledMap<L, T, O> {
@@ -393,31 +534,20 @@ impl<L, T, O> TiledMap<L, T, O> {
                 LayerData::Finite(data) => {
                     chunks.insert(
                         (0, 0),
-                        Chunk {
-                            x: 0,
-                            y: 0,
-                            w: tiled.width,
-                            h: tiled.height,
-                            data: data.iter().flatten().map(|lt| lt.gid).collect(),
-                        },
+                        Chunk::from_tiled(0, 0, tiled.width, tiled.height, data.iter().flatten()),
                     );
                 }
                 LayerData::Infinite(tiled_chunks) => {
                     for (&(x, y), tiled_chunk) in tiled_chunks.iter() {
                         chunks.insert(
                             (x, y),
-                            Chunk {
-                                x: tiled_chunk.x,
-                                y: tiled_chunk.y,
-                                w: tiled_chunk.width,
-                                h: tiled_chunk.height,
-                                data: tiled_chunk
-                                    .tiles
-                                    .iter()
-                                    .flatten()
-                                    .map(|lt| lt.gid)
-                                    .collect(),
-                            },
+                            Chunk::from_tiled(
+                                tiled_chunk.x,
+                                tiled_chunk.y,
+                                tiled_chunk.width,
+                                tiled_chunk.height,
+                                tiled_chunk.tiles.iter().flatten(),
+                            ),
                         );
                     }
                 }
@@ -427,6 +557,8 @@ impl<L, T, O> TiledMap<L, T, O> {
                 name: Some(layer.name.clone()),
                 visible: layer.visible,
                 opacity: layer.opacity,
+                parallax_x: layer.parallax_x,
+                parallax_y: layer.parallax_y,
                 chunks,
                 properties: deserialize_properties(
                     &layer.properties,
@@ -454,6 +586,10 @@ impl<L, T, O> TiledMap<L, T, O> {
                 opacity: layer.opacity,
                 offset_x: layer.offset_x,
                 offset_y: layer.offset_y,
+                parallax_x: layer.parallax_x,
+                parallax_y: layer.parallax_y,
+                repeat_x: layer.repeat_x || repeat_property(&layer.properties, "repeat_x"),
+                repeat_y: layer.repeat_y || repeat_property(&layer.properties, "repeat_y"),
                 source: image.source.clone(),
                 image_width: image.width as u32,
                 image_height: image.height as u32,
@@ -527,6 +663,8 @@ impl<L, T, O> TiledMap<L, T, O> {
                 name: layer.name.clone(),
                 opacity: layer.opacity,
                 visible: layer.visible,
+                parallax_x: layer.parallax_x,
+                parallax_y: layer.parallax_y,
                 objects,
                 properties: deserialize_properties(
                     &layer.properties,
@@ -633,13 +771,13 @@ where
         let mut deps = vec![];
         for ts in tiled.tilesets.iter() {
             if let Some(src) = ts.source.as_ref() {
-                deps.push(Key::from_path(Path::new(src)).clone_static());
+                deps.push(Key::path(Path::new(src)).clone_static());
             }
         }
 
         for ls in tiled.image_layers.iter() {
             if let Some(img) = &ls.image {
-                deps.push(Key::from_path(Path::new(&img.source)).clone_static());
+                deps.push(Key::path(Path::new(&img.source)).clone_static());
             }
         }
 
@@ -699,7 +837,7 @@ where
         let mut tiled_map = resources
             .fetch_one::<crate::assets::DefaultCache>()?
             .borrow()
-            .get::<TiledMap<L, T, O>>(&Key::from_path(&map_path))
+            .get::<TiledMap<L, T, O>>(&Key::path(&map_path))
             .with_context(|| {
                 anyhow!(
                     "error loading {} from path {} while inserting into bundle from Lua",
@@ -714,3 +852,91 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<map version="1.5" orientation="orthogonal" renderorder="right-down" width="2" height="1" tilewidth="16" tileheight="16" infinite="0">
+ <tileset firstgid="1" name="tiles" tilewidth="16" tileheight="16" tilecount="4" columns="2">
+  <image source="tiles.png" width="32" height="32"/>
+ </tileset>
+ <layer id="1" name="ground" width="2" height="1" parallaxx="0.5" parallaxy="0.25">
+  <data encoding="csv">1,2147483650</data>
+ </layer>
+ <imagelayer id="2" name="sky" parallaxx="0" repeatx="1">
+  <image source="sky.png" width="64" height="32"/>
+ </imagelayer>
+ <imagelayer id="3" name="hills">
+  <properties>
+   <property name="repeat_y" type="bool" value="true"/>
+  </properties>
+  <image source="hills.png" width="64" height="32"/>
+ </imagelayer>
+</map>"#;
+
+    fn load() -> Result<TiledMap<Value, Value, Value>> {
+        let mut fs = Filesystem::new("sludge-tiled-test", "sludge")?;
+        let tiled = xml_parser::parse(&mut fs, MAP.as_bytes())?;
+        TiledMap::from_tiled(Path::new("test.tmx"), &tiled)
+    }
+
+    #[test]
+    fn parallax_and_repeat_are_parsed() -> Result<()> {
+        let map = load()?;
+        let layers = map.layers();
+        assert_eq!(layers.len(), 3);
+
+        assert_eq!(layers[0].parallax(), Vector2::new(0.5, 0.25));
+        assert_eq!(
+            layers[0].parallax_offset(&Point2::new(100., 100.)),
+            Vector2::new(50., 75.)
+        );
+
+        match &layers[1] {
+            Layer::ImageLayer(sky) => {
+                assert_eq!((sky.parallax_x, sky.parallax_y), (0., 1.));
+                assert!(sky.repeat_x && !sky.repeat_y);
+            }
+            other => panic!("expected an image layer, got {:?}", other),
+        }
+
+        match &layers[2] {
+            Layer::ImageLayer(hills) => {
+                assert_eq!(layers[2].parallax(), Vector2::repeat(1.));
+                assert!(!hills.repeat_x && hills.repeat_y);
+            }
+            other => panic!("expected an image layer, got {:?}", other),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn flipped_tiles_keep_their_flips() -> Result<()> {
+        let map = load()?;
+        let chunk = match &map.layers()[0] {
+            Layer::TileLayer(ground) => ground.chunks().next().unwrap().1.clone(),
+            other => panic!("expected a tile layer, got {:?}", other),
+        };
+
+        let tiles = chunk.tiles_with_flips().collect::<Vec<_>>();
+        assert_eq!(
+            tiles,
+            vec![
+                ((0, 0), 1, TileFlip::default()),
+                (
+                    (1, 0),
+                    2,
+                    TileFlip {
+                        horizontal: true,
+                        ..TileFlip::default()
+                    }
+                ),
+            ]
+        );
+
+        Ok(())
+    }
+}
@@ -668,6 +668,8 @@ pub struct Layer {
     pub tiles: LayerData,
     pub properties: Properties,
     pub layer_index: u32,
+    pub parallax_x: f32,
+    pub parallax_y: f32,
 }
 
 impl Layer {
@@ -678,11 +680,13 @@ impl Layer {
         layer_index: u32,
         infinite: bool,
     ) -> Result<Layer, Error> {
-        let ((o, v), n) = get_attrs!(
+        let ((o, v, px, py), n) = get_attrs!(
             attrs,
             optionals: [
                 ("opacity", opacity, |v:String| v.parse().ok()),
                 ("visible", visible, |v:String| v.parse().ok().map(|x:i32| x == 1)),
+                ("parallaxx", parallax_x, |v:String| v.parse().ok()),
+                ("parallaxy", parallax_y, |v:String| v.parse().ok()),
             ],
             required: [
                 ("name", name, |v| Some(v)),
@@ -713,6 +717,8 @@ impl Layer {
             tiles: tiles,
             properties: properties,
             layer_index,
+            parallax_x: px.unwrap_or(1.0),
+            parallax_y: py.unwrap_or(1.0),
         })
     }
 }
@@ -771,6 +777,10 @@ pub struct ImageLayer {
     pub image: Option<Image>,
     pub properties: Properties,
     pub layer_index: u32,
+    pub parallax_x: f32,
+    pub parallax_y: f32,
+    pub repeat_x: bool,
+    pub repeat_y: bool,
 }
 
 impl ImageLayer {
@@ -780,13 +790,17 @@ impl ImageLayer {
         layer_index: u32,
         map_path: Option<&Path>,
     ) -> Result<ImageLayer, Error> {
-        let ((o, v, ox, oy), n) = get_attrs!(
+        let ((o, v, ox, oy, px, py, rx, ry), n) = get_attrs!(
             attrs,
             optionals: [
                 ("opacity", opacity, |v:String| v.parse().ok()),
                 ("visible", visible, |v:String| v.parse().ok().map(|x:i32| x == 1)),
                 ("offsetx", offset_x, |v:String| v.parse().ok()),
                 ("offsety", offset_y, |v:String| v.parse().ok()),
+                ("parallaxx", parallax_x, |v:String| v.parse().ok()),
+                ("parallaxy", parallax_y, |v:String| v.parse().ok()),
+                ("repeatx", repeat_x, |v:String| v.parse().ok().map(|x:i32| x == 1)),
+                ("repeaty", repeat_y, |v:String| v.parse().ok().map(|x:i32| x == 1)),
             ],
             required: [
                 ("name", name, |v| Some(v)),
@@ -813,6 +827,10 @@ impl ImageLayer {
             image,
             properties,
             layer_index,
+            parallax_x: px.unwrap_or(1.0),
+            parallax_y: py.unwrap_or(1.0),
+            repeat_x: rx.unwrap_or(false),
+            repeat_y: ry.unwrap_or(false),
         })
     }
 }
@@ -829,6 +847,8 @@ pub struct ObjectGroup {
      */
     pub layer_index: Option<u32>,
     pub properties: Properties,
+    pub parallax_x: f32,
+    pub parallax_y: f32,
 }
 
 impl ObjectGroup {
@@ -837,13 +857,15 @@ impl ObjectGroup {
         attrs: Vec<OwnedAttribute>,
         layer_index: Option<u32>,
     ) -> Result<ObjectGroup, Error> {
-        let ((o, v, c, n), ()) = get_attrs!(
+        let ((o, v, c, n, px, py), ()) = get_attrs!(
             attrs,
             optionals: [
                 ("opacity", opacity, |v:String| v.parse().ok()),
                 ("visible", visible, |v:String| v.parse().ok().map(|x:i32| x == 1)),
                 ("color", colour, |v:String| v.parse().ok()),
                 ("name", name, |v:String| v.into()),
+                ("parallaxx", parallax_x, |v:String| v.parse().ok()),
+                ("parallaxy", parallax_y, |v:String| v.parse().ok()),
             ],
             required: [],
             TiledError::MalformedAttributes("object groups must have a name".to_string())
@@ -868,6 +890,8 @@ impl ObjectGroup {
             colour: c,
             layer_index,
             properties,
+            parallax_x: px.unwrap_or(1.0),
+            parallax_y: py.unwrap_or(1.0),
         })
     }
 }
@@ -48,10 +48,20 @@ use std::time;
 
 type Instant = f64;
 
+/// The current time, in seconds since the Unix epoch.
+#[cfg(feature = "miniquad")]
 pub fn time() -> f64 {
     miniquad::date::now()
 }
 
+/// The current time, in seconds since the Unix epoch.
+#[cfg(not(feature = "miniquad"))]
+pub fn time() -> f64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map_or(0., |d| d.as_secs_f64())
+}
+
 /// A simple buffer that fills
 /// up to a limit and then holds the last
 /// N items that have been inserted into it,
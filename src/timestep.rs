@@ -0,0 +1,76 @@
+//! The length of a space's fixed tick.
+//!
+//! Every [`Space`](crate::Space) has a [`FixedTimestep`] in its local resources. It's
+//! [`FIXED_FPS`](crate::FIXED_FPS) ticks per second unless the space was built with
+//! [`SpaceBuilder::with_fixed_fps`](crate::SpaceBuilder::with_fixed_fps), and
+//! [`Space::update`](crate::Space::update) runs that many
+//! [fixed ticks](crate::Space::fixed_update) per second. The scheduler counts whole
+//! ticks, so anything running in the fixed stage which advances by seconds should step
+//! by [`fixed_dt`] rather than assuming a rate of its own.
+
+use crate::{resources::Resources, FIXED_FPS};
+
+/// How many fixed ticks a space runs per second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FixedTimestep {
+    fps: u32,
+}
+
+impl Default for FixedTimestep {
+    fn default() -> Self {
+        Self::new(FIXED_FPS)
+    }
+}
+
+impl FixedTimestep {
+    /// Panics if `fps` is zero.
+    pub fn new(fps: u32) -> Self {
+        assert!(
+            fps > 0,
+            "a fixed timestep needs at least one tick per second"
+        );
+        Self { fps }
+    }
+
+    /// How many fixed ticks run per second.
+    pub fn fps(&self) -> u32 {
+        self.fps
+    }
+
+    /// The length of a fixed tick, in seconds.
+    pub fn dt(&self) -> f32 {
+        1. / self.fps as f32
+    }
+}
+
+/// The [`FixedTimestep`] in `resources`, or the default if there isn't one.
+pub fn fixed_timestep<'a, R: Resources<'a>>(resources: &R) -> FixedTimestep {
+    resources
+        .fetch_one::<FixedTimestep>()
+        .map_or_else(|_| FixedTimestep::default(), |timestep| *timestep.borrow())
+}
+
+/// The length of a fixed tick in `resources`, in seconds. See [`fixed_timestep`].
+pub fn fixed_dt<'a, R: Resources<'a>>(resources: &R) -> f32 {
+    fixed_timestep(resources).dt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Space;
+
+    #[test]
+    fn spaces_default_to_fixed_fps() -> anyhow::Result<()> {
+        let space = Space::new()?;
+        assert_eq!(fixed_timestep(space.resources()).fps(), FIXED_FPS);
+        Ok(())
+    }
+
+    #[test]
+    fn builder_sets_the_timestep() -> anyhow::Result<()> {
+        let space = Space::builder().with_fixed_fps(120).build()?;
+        assert_eq!(fixed_dt(space.resources()), 1. / 120.);
+        Ok(())
+    }
+}
@@ -9,9 +9,9 @@ use {
 
 use crate::{
     components::Parent,
-    ecs::{ComponentEvent, ComponentSubscriber, Entity, World},
+    ecs::{ComponentEvent, ComponentSubscriber, Disabled, Entity, World},
     hierarchy::{HierarchyEvent, HierarchyManager, ParentComponent},
-    math::Transform3,
+    math::{homogeneous_mat3_to_mat4, Isometry2, Matrix3, Matrix4, Point2, Transform3, Vector2},
     Resources,
 };
 
@@ -45,6 +45,7 @@ impl Transform {
 pub struct TransformManager<P: ParentComponent = Parent> {
     hierarchy_events: ReaderId<HierarchyEvent>,
     transform_events: ComponentSubscriber<Transform>,
+    disabled_events: ComponentSubscriber<Disabled>,
 
     modified: HashSet<Entity>,
     removed: HashSet<Entity>,
@@ -55,11 +56,13 @@ pub struct TransformManager<P: ParentComponent = Parent> {
 impl<P: ParentComponent> TransformManager<P> {
     pub fn new(world: &mut World, hierarchy: &mut HierarchyManager<P>) -> Self {
         let transform_events = world.track::<Transform>();
+        let disabled_events = world.track::<Disabled>();
         let hierarchy_events = hierarchy.track();
 
         Self {
             hierarchy_events,
             transform_events,
+            disabled_events,
 
             modified: HashSet::new(),
             removed: HashSet::new(),
@@ -102,6 +105,16 @@ impl<P: ParentComponent> TransformManager<P> {
             }
         }
 
+        // Disabled entities are skipped below, so they need a fresh global transform
+        // once they're enabled again.
+        for &event in world.poll::<Disabled>(&mut self.disabled_events) {
+            if let ComponentEvent::Removed(entity) = event {
+                self.modified.insert(entity);
+                self.modified
+                    .extend(hierarchy.children(entity).iter().copied());
+            }
+        }
+
         for entity in self.removed.iter().copied() {
             if let Ok(mut transform) = world.get_mut_raw::<Transform>(entity) {
                 transform.global = transform.local;
@@ -110,6 +123,10 @@ impl<P: ParentComponent> TransformManager<P> {
 
         for entity in hierarchy.all().iter().copied() {
             if self.modified.remove(&entity) {
+                if !world.is_enabled(entity) {
+                    continue;
+                }
+
                 self.modified.extend(hierarchy.children(entity));
 
                 let parent_global = world
@@ -126,6 +143,10 @@ impl<P: ParentComponent> TransformManager<P> {
         }
 
         for entity in self.modified.iter().copied() {
+            if !world.is_enabled(entity) {
+                continue;
+            }
+
             if let Ok(mut transform) = world.get_mut_raw::<Transform>(entity) {
                 transform.global = transform.local;
             }
@@ -135,6 +156,241 @@ impl<P: ParentComponent> TransformManager<P> {
     }
 }
 
+/// A 2D placement: an isometry (translation and rotation), followed by a
+/// (possibly non-uniform) scale, along with a z-order used for sorting and
+/// depth when rendering.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Placement2 {
+    pub isometry: Isometry2<f32>,
+    pub scale: Vector2<f32>,
+    pub z: f32,
+}
+
+impl Default for Placement2 {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+impl From<Isometry2<f32>> for Placement2 {
+    fn from(isometry: Isometry2<f32>) -> Self {
+        Self::new(isometry, Vector2::repeat(1.), 0.)
+    }
+}
+
+impl Placement2 {
+    pub fn new(isometry: Isometry2<f32>, scale: Vector2<f32>, z: f32) -> Self {
+        Self { isometry, scale, z }
+    }
+
+    pub fn identity() -> Self {
+        Self::from(Isometry2::identity())
+    }
+
+    /// Compose this placement with a placement local to it, producing the
+    /// placement of the child in this placement's parent space. Translations,
+    /// rotations and z-orders compose exactly; scales are multiplied
+    /// component-wise, which is exact for uniform scaling but ignores the skew
+    /// which would result from rotating a child under a non-uniform scale.
+    pub fn compose(&self, local: &Placement2) -> Placement2 {
+        let scaled = local.isometry.translation.vector.component_mul(&self.scale);
+        let translation = self.isometry * Point2::from(scaled);
+
+        Self {
+            isometry: Isometry2::new(
+                translation.coords,
+                self.isometry.rotation.angle() + local.isometry.rotation.angle(),
+            ),
+            scale: self.scale.component_mul(&local.scale),
+            z: self.z + local.z,
+        }
+    }
+
+    /// The inverse of [`Placement2::compose`]: find the placement which, local to
+    /// `parent`, puts a child at this placement. The parent's scale must not have any
+    /// zero components.
+    pub fn relative_to(&self, parent: &Placement2) -> Placement2 {
+        let unrotated = parent
+            .isometry
+            .inverse_transform_point(&Point2::from(self.isometry.translation.vector));
+
+        Self {
+            isometry: Isometry2::new(
+                unrotated.coords.component_div(&parent.scale),
+                self.isometry.rotation.angle() - parent.isometry.rotation.angle(),
+            ),
+            scale: self.scale.component_div(&parent.scale),
+            z: self.z - parent.z,
+        }
+    }
+
+    pub fn transform_point(&self, point: &Point2<f32>) -> Point2<f32> {
+        self.isometry * Point2::from(point.coords.component_mul(&self.scale))
+    }
+
+    pub fn to_matrix3(&self) -> Matrix3<f32> {
+        self.isometry.to_homogeneous() * Matrix3::from_diagonal(&self.scale.push(1.))
+    }
+
+    /// Convert to a 3D matrix, with the z-order as the z translation.
+    pub fn to_matrix4(&self) -> Matrix4<f32> {
+        let mut matrix = homogeneous_mat3_to_mat4(&self.to_matrix3());
+        matrix[(2, 3)] = self.z;
+        matrix
+    }
+
+    pub fn to_transform3(&self) -> Transform3<f32> {
+        Transform3::from_matrix_unchecked(self.to_matrix4())
+    }
+}
+
+/// A 2D-first alternative to [`Transform`], which stores a [`Placement2`] rather
+/// than a full 3D matrix. Global placements are propagated through the hierarchy
+/// by the [`Transform2dManager`], and only converted into matrices at render time.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, TrackedComponent)]
+pub struct Transform2d {
+    pub(crate) local: Placement2,
+    pub(crate) global: Placement2,
+}
+
+impl Transform2d {
+    pub fn new(placement: impl Into<Placement2>) -> Self {
+        let placement = placement.into();
+        Self {
+            local: placement,
+            global: placement,
+        }
+    }
+
+    pub fn local(&self) -> &Placement2 {
+        &self.local
+    }
+
+    pub fn local_mut(&mut self) -> &mut Placement2 {
+        &mut self.local
+    }
+
+    pub fn global(&self) -> &Placement2 {
+        &self.global
+    }
+
+    /// The global placement as a 3D matrix, for rendering.
+    pub fn to_matrix4(&self) -> Matrix4<f32> {
+        self.global.to_matrix4()
+    }
+}
+
+pub struct Transform2dManager<P: ParentComponent = Parent> {
+    hierarchy_events: ReaderId<HierarchyEvent>,
+    transform_events: ComponentSubscriber<Transform2d>,
+    disabled_events: ComponentSubscriber<Disabled>,
+
+    modified: HashSet<Entity>,
+    removed: HashSet<Entity>,
+
+    _marker: PhantomData<P>,
+}
+
+impl<P: ParentComponent> Transform2dManager<P> {
+    pub fn new(world: &mut World, hierarchy: &mut HierarchyManager<P>) -> Self {
+        let transform_events = world.track::<Transform2d>();
+        let disabled_events = world.track::<Disabled>();
+        let hierarchy_events = hierarchy.track();
+
+        Self {
+            hierarchy_events,
+            transform_events,
+            disabled_events,
+
+            modified: HashSet::new(),
+            removed: HashSet::new(),
+
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn update<'a, R: Resources<'a>>(&mut self, resources: &R) -> Result<()> {
+        self.modified.clear();
+        self.removed.clear();
+
+        let (shared_world, shared_hierarchy) = resources.fetch::<(World, HierarchyManager<P>)>()?;
+        let hierarchy = shared_hierarchy.borrow_mut();
+        let world = shared_world.borrow_mut();
+
+        for event in hierarchy.changed().read(&mut self.hierarchy_events) {
+            match event {
+                HierarchyEvent::ModifiedOrCreated(entity) => {
+                    self.modified.insert(*entity);
+                }
+                HierarchyEvent::Removed(entity) => {
+                    self.removed.insert(*entity);
+                }
+            }
+        }
+
+        for &event in world.poll::<Transform2d>(&mut self.transform_events) {
+            match event {
+                ComponentEvent::Inserted(entity) | ComponentEvent::Modified(entity) => {
+                    self.modified.insert(entity);
+                }
+                ComponentEvent::Removed(entity) => {
+                    self.modified
+                        .extend(hierarchy.children(entity).iter().copied());
+                }
+            }
+        }
+
+        // Disabled entities are skipped below, so they need a fresh global transform
+        // once they're enabled again.
+        for &event in world.poll::<Disabled>(&mut self.disabled_events) {
+            if let ComponentEvent::Removed(entity) = event {
+                self.modified.insert(entity);
+                self.modified
+                    .extend(hierarchy.children(entity).iter().copied());
+            }
+        }
+
+        for entity in self.removed.iter().copied() {
+            if let Ok(mut transform) = world.get_mut_raw::<Transform2d>(entity) {
+                transform.global = transform.local;
+            }
+        }
+
+        for entity in hierarchy.all().iter().copied() {
+            if self.modified.remove(&entity) {
+                if !world.is_enabled(entity) {
+                    continue;
+                }
+
+                self.modified.extend(hierarchy.children(entity));
+
+                // Unlike `Transform`, a parent without a `Transform2d` is treated as
+                // being at the origin, so that 2D and 3D transforms can share a hierarchy.
+                let parent_global = world
+                    .get_raw::<Transform2d>(hierarchy.parent(entity).expect("exists in hierarchy"))
+                    .map(|parent| parent.global)
+                    .unwrap_or_else(|_| Placement2::identity());
+
+                if let Ok(mut transform) = world.get_mut_raw::<Transform2d>(entity) {
+                    transform.global = parent_global.compose(&transform.local);
+                }
+            }
+        }
+
+        for entity in self.modified.iter().copied() {
+            if !world.is_enabled(entity) {
+                continue;
+            }
+
+            if let Ok(mut transform) = world.get_mut_raw::<Transform2d>(entity) {
+                transform.global = transform.local;
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -229,4 +485,197 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn parent_update_2d() -> Result<()> {
+        let resources = SharedResources::new();
+
+        let mut world = World::new();
+        let mut hierarchy = HierarchyManager::<Parent>::new(&mut world);
+        let transforms = Transform2dManager::new(&mut world, &mut hierarchy);
+
+        resources.borrow_mut().insert(world);
+        resources.borrow_mut().insert(hierarchy);
+        resources.borrow_mut().insert(transforms);
+
+        let update = || -> Result<()> {
+            resources
+                .fetch_one::<HierarchyManager<Parent>>()?
+                .borrow_mut()
+                .update(&resources)?;
+            resources
+                .fetch_one::<Transform2dManager>()?
+                .borrow_mut()
+                .update(&resources)
+        };
+
+        let e1 = resources
+            .fetch_one::<World>()?
+            .borrow_mut()
+            .spawn((Transform2d::new(Placement2::new(
+                Isometry2::new(Vector2::new(-5., -7.), ::std::f32::consts::PI),
+                Vector2::repeat(2.),
+                1.,
+            )),));
+        update()?;
+
+        let e2 = resources.fetch_one::<World>()?.borrow_mut().spawn((
+            Transform2d::new(Placement2::new(
+                Isometry2::translation(5., 3.),
+                Vector2::repeat(1.),
+                0.5,
+            )),
+            Parent::new(e1),
+        ));
+        update()?;
+
+        let tx2 = *resources
+            .fetch_one::<World>()?
+            .borrow()
+            .get::<Transform2d>(e2)
+            .unwrap();
+
+        assert_relative_eq!(
+            tx2.global().transform_point(&Point2::origin()),
+            Point2::new(-15., -13.),
+            epsilon = 1e-4
+        );
+        assert_relative_eq!(tx2.global().z, 1.5);
+        assert_relative_eq!(
+            tx2.to_matrix4().transform_point(&Point3::origin()),
+            Point3::new(-15., -13., 1.5),
+            epsilon = 1e-4
+        );
+
+        resources
+            .fetch_one::<World>()?
+            .borrow_mut()
+            .despawn(e1)
+            .unwrap();
+        update()?;
+
+        let tx2 = *resources
+            .fetch_one::<World>()?
+            .borrow()
+            .get::<Transform2d>(e2)
+            .unwrap();
+
+        assert_relative_eq!(
+            tx2.global().transform_point(&Point2::origin()),
+            Point2::new(5., 3.)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn disabled_child_catches_up() -> Result<()> {
+        let resources = SharedResources::new();
+
+        let mut world = World::new();
+        let mut hierarchy = HierarchyManager::<Parent>::new(&mut world);
+        let transforms = Transform2dManager::new(&mut world, &mut hierarchy);
+
+        resources.borrow_mut().insert(world);
+        resources.borrow_mut().insert(hierarchy);
+        resources.borrow_mut().insert(transforms);
+
+        let update = || -> Result<()> {
+            resources
+                .fetch_one::<HierarchyManager<Parent>>()?
+                .borrow_mut()
+                .update(&resources)?;
+            resources
+                .fetch_one::<Transform2dManager>()?
+                .borrow_mut()
+                .update(&resources)
+        };
+
+        let e1 = resources
+            .fetch_one::<World>()?
+            .borrow_mut()
+            .spawn((Transform2d::new(Isometry2::translation(1., 0.)),));
+        let e2 = resources.fetch_one::<World>()?.borrow_mut().spawn((
+            Transform2d::new(Isometry2::translation(0., 1.)),
+            Parent::new(e1),
+        ));
+        update()?;
+
+        let global = |entity| -> Result<Point2<f32>> {
+            let world = resources.fetch_one::<World>()?;
+            let world = world.borrow();
+            let transform = world.get::<Transform2d>(entity).unwrap();
+            Ok(transform.global().transform_point(&Point2::origin()))
+        };
+        assert_relative_eq!(global(e2)?, Point2::new(1., 1.));
+
+        resources
+            .fetch_one::<World>()?
+            .borrow_mut()
+            .insert_one(e2, Disabled)?;
+        resources
+            .fetch_one::<World>()?
+            .borrow()
+            .get_mut::<Transform2d>(e2)
+            .unwrap()
+            .local_mut()
+            .isometry = Isometry2::translation(0., 3.);
+        update()?;
+        assert_relative_eq!(global(e2)?, Point2::new(1., 1.));
+
+        resources
+            .fetch_one::<World>()?
+            .borrow_mut()
+            .remove_one::<Disabled>(e2)?;
+        update()?;
+        assert_relative_eq!(global(e2)?, Point2::new(1., 3.));
+
+        Ok(())
+    }
+
+    #[test]
+    fn set_parent_keeps_world_placement() -> Result<()> {
+        let resources = SharedResources::new();
+
+        let mut world = World::new();
+        let mut hierarchy = HierarchyManager::<Parent>::new(&mut world);
+        let transforms = Transform2dManager::new(&mut world, &mut hierarchy);
+
+        let parent = world.spawn((Transform2d::new(Placement2::new(
+            Isometry2::new(Vector2::new(4., -2.), ::std::f32::consts::FRAC_PI_2),
+            Vector2::repeat(2.),
+            1.,
+        )),));
+        let child = world.spawn((Transform2d::new(Isometry2::translation(3., 5.)),));
+
+        crate::hierarchy::set_parent(&mut world, child, Some(parent), true)?;
+
+        resources.borrow_mut().insert(world);
+        resources.borrow_mut().insert(hierarchy);
+        resources.borrow_mut().insert(transforms);
+
+        resources
+            .fetch_one::<HierarchyManager<Parent>>()?
+            .borrow_mut()
+            .update(&resources)?;
+        resources
+            .fetch_one::<Transform2dManager>()?
+            .borrow_mut()
+            .update(&resources)?;
+
+        let tx = *resources
+            .fetch_one::<World>()?
+            .borrow()
+            .get::<Transform2d>(child)
+            .unwrap();
+
+        assert_relative_eq!(
+            tx.global().transform_point(&Point2::origin()),
+            Point2::new(3., 5.),
+            epsilon = 1e-4
+        );
+        assert_relative_eq!(tx.global().z, 0., epsilon = 1e-4);
+
+        Ok(())
+    }
 }
@@ -216,7 +216,7 @@ impl VMetadata for PhysicalMetadata {
 /// to turn an absolute path into a relative path with the same
 /// components (other than the first), and pushing an absolute `Path`
 /// onto a `PathBuf` just completely nukes its existing contents.
-fn sanitize_path(path: &path::Path) -> Option<PathBuf> {
+pub(crate) fn sanitize_path(path: &path::Path) -> Option<PathBuf> {
     // FIXME: stop relying on `path_clean` and make our own implementation that
     // doesn't need this backslash-to-forward-slash hack. The hack is here because
     // `path_clean` is a port of a routine for UNIX systems, and doesn't know about
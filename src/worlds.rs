@@ -0,0 +1,273 @@
+//! Secondary worlds, for simulations which run alongside the main one in the same
+//! [`Space`](crate::Space), like the background of a menu, a level being edited or a
+//! prediction of where things will be.
+//!
+//! Every space has a default [`World`], and code which fetches the `World` resource gets
+//! that one. A named secondary world is created with
+//! [`Space::create_world`](crate::Space::create_world), and selected with
+//! [`Space::with_world`](crate::Space::with_world): for the duration of the closure, the
+//! secondary world stands in for the default one, so existing systems and Lua APIs work
+//! on it without knowing it's there.
+//!
+//! ```ignore
+//! space.create_world("preview")?;
+//! space.with_world("preview", |space| space.maintain())?;
+//! ```
+//!
+//! A secondary world is more than the `World` itself. When it's created, the space's
+//! maintenance systems are [initialized](crate::System::init) into a fresh set of
+//! resources holding the new world, so resources which track a world, like the
+//! [`HierarchyManager`](crate::hierarchy::HierarchyManager), get copies of their own.
+//! While the world is selected, all of those resources shadow the originals; anything
+//! else is shared with the default world. Resources can be added to a world's scope
+//! with [`Worlds::insert_scoped`].
+//!
+//! From Lua, `sludge.worlds.with(name, f)` calls `f` with the named world selected, and
+//! `sludge.worlds.current()` returns the name of the selected world, or `nil` for the
+//! default one:
+//!
+//! ```lua
+//! sludge.worlds.with("preview", function()
+//!     sludge.spawn { Name = "ghost" }
+//! end)
+//! ```
+//!
+//! Entities belong to the world they were spawned in, and mean nothing in another; and
+//! threads spawned while a world is selected run later with the default world, like
+//! every other thread.
+
+use {
+    anyhow::*,
+    hashbrown::HashMap,
+    rlua::prelude::*,
+    std::sync::{Arc, Mutex, TryLockError},
+};
+
+use crate::{
+    ecs::World,
+    resources::{Fetchable, OwnedResources, Resources, Shared},
+    SludgeLuaContextExt,
+};
+
+/// The secondary worlds of a space, and which one is selected. See the
+/// [module documentation](self) for details.
+#[derive(Debug, Default)]
+pub struct Worlds {
+    worlds: HashMap<String, Arc<Mutex<OwnedResources<'static>>>>,
+    selected: Vec<String>,
+}
+
+impl Worlds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.worlds.contains_key(name)
+    }
+
+    /// The names of every secondary world, in no particular order.
+    pub fn names(&self) -> impl Iterator<Item = &str> + '_ {
+        self.worlds.keys().map(String::as_str)
+    }
+
+    /// The name of the selected world, or `None` if it's the default one.
+    pub fn selected(&self) -> Option<&str> {
+        self.selected.last().map(String::as_str)
+    }
+
+    /// Add a world with the resources which are scoped to it, which must include a
+    /// [`World`].
+    pub fn insert(
+        &mut self,
+        name: impl Into<String>,
+        scoped: OwnedResources<'static>,
+    ) -> Result<()> {
+        let name = name.into();
+        ensure!(
+            scoped.has_value::<World>(),
+            "world `{}` has no `World` resource",
+            name
+        );
+        ensure!(!self.contains(&name), "world `{}` already exists", name);
+        self.worlds.insert(name, Arc::new(Mutex::new(scoped)));
+        Ok(())
+    }
+
+    /// Add a resource to the scope of a world, to stand in for the space's own while
+    /// the world is selected.
+    pub fn insert_scoped<T: Fetchable>(&self, name: &str, resource: T) -> Result<()> {
+        let scoped = self
+            .worlds
+            .get(name)
+            .ok_or_else(|| anyhow!("no world named `{}`", name))?;
+        let mut scoped = scoped.lock().unwrap();
+        ensure!(
+            !scoped.has_value::<T>(),
+            "world `{}` already has a `{}`",
+            name,
+            std::any::type_name::<T>()
+        );
+        scoped.insert(resource);
+        Ok(())
+    }
+
+    /// Remove a world, returning whether there was one by that name. A world can't be
+    /// removed while it's selected.
+    pub fn remove(&mut self, name: &str) -> Result<bool> {
+        ensure!(
+            !self.selected.iter().any(|selected| selected == name),
+            "world `{}` can't be removed while it's selected",
+            name
+        );
+        Ok(self.worlds.remove(name).is_some())
+    }
+}
+
+/// Call `f` with the world named `name` standing in for the default world in
+/// `resources`, which must hold the [`Worlds`].
+pub fn with_world<R, T>(resources: &R, name: &str, f: impl FnOnce() -> T) -> Result<T>
+where
+    R: Resources<'static> + ?Sized,
+{
+    let worlds = resources.fetch_one::<Worlds>()?;
+    let scoped = worlds
+        .borrow()
+        .worlds
+        .get(name)
+        .cloned()
+        .ok_or_else(|| anyhow!("no world named `{}`", name))?;
+
+    // A world whose closure panicked is left poisoned, but its resources are still
+    // fine to select again.
+    let scoped = match scoped.try_lock() {
+        Ok(scoped) => scoped,
+        Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+        Err(TryLockError::WouldBlock) => bail!("world `{}` is already selected", name),
+    };
+
+    worlds.borrow_mut().selected.push(name.to_owned());
+    let _deselect = DeselectOnDrop { worlds: &worlds };
+    Ok(resources.scope_overlay(&*scoped, f))
+}
+
+/// Pops the selected world when a [`with_world`] scope ends, even if it ends in a panic.
+struct DeselectOnDrop<'a> {
+    worlds: &'a Shared<'static, Worlds>,
+}
+
+impl<'a> Drop for DeselectOnDrop<'a> {
+    fn drop(&mut self) {
+        self.worlds.borrow_mut().selected.pop();
+    }
+}
+
+inventory::submit! {
+    crate::api::Module::parse("sludge.worlds", |lua| {
+        let table = lua.create_table()?;
+
+        table.set(
+            "with",
+            lua.create_function(|lua, (name, f): (String, LuaFunction)| {
+                let resources = lua.resources();
+                with_world(&resources, &name, || f.call::<_, LuaMultiValue>(()))
+                    .to_lua_err()?
+            })?,
+        )?;
+
+        table.set(
+            "current",
+            lua.create_function(|lua, ()| {
+                let worlds = lua.fetch_one::<Worlds>()?;
+                let selected = worlds.borrow().selected().map(str::to_owned);
+                Ok(selected)
+            })?,
+        )?;
+
+        table.set(
+            "exists",
+            lua.create_function(|lua, name: String| {
+                Ok(lua.fetch_one::<Worlds>()?.borrow().contains(&name))
+            })?,
+        )?;
+
+        table.set(
+            "names",
+            lua.create_function(|lua, ()| {
+                let worlds = lua.fetch_one::<Worlds>()?;
+                let names = worlds
+                    .borrow()
+                    .names()
+                    .map(str::to_owned)
+                    .collect::<Vec<_>>();
+                Ok(names)
+            })?,
+        )?;
+
+        Ok(LuaValue::Table(table))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::resources::UnifiedResources};
+
+    #[test]
+    fn selected_world_shadows_default() -> Result<()> {
+        let resources = UnifiedResources::new();
+        resources.borrow_mut().insert(World::new());
+        resources.borrow_mut().insert(Worlds::new());
+
+        let mut scoped = OwnedResources::new();
+        scoped.insert(World::new());
+        resources
+            .fetch_one::<Worlds>()?
+            .borrow_mut()
+            .insert("preview", scoped)?;
+
+        let ghost = with_world(&resources, "preview", || {
+            resources
+                .fetch_one::<World>()
+                .unwrap()
+                .borrow_mut()
+                .spawn(())
+        })?;
+
+        let world = resources.fetch_one::<World>()?;
+        assert!(!world.borrow().contains(ghost));
+        with_world(&resources, "preview", || {
+            let world = resources.fetch_one::<World>().unwrap();
+            assert!(world.borrow().contains(ghost));
+        })?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn panics_deselect_the_world() -> Result<()> {
+        let resources = UnifiedResources::new();
+        resources.borrow_mut().insert(World::new());
+        resources.borrow_mut().insert(Worlds::new());
+        let mut scoped = OwnedResources::new();
+        scoped.insert(World::new());
+        resources
+            .fetch_one::<Worlds>()?
+            .borrow_mut()
+            .insert("preview", scoped)?;
+
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            with_world(&resources, "preview", || panic!("oh no"))
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(resources.fetch_one::<Worlds>()?.borrow().selected(), None);
+
+        let selected = with_world(&resources, "preview", || {
+            let worlds = resources.fetch_one::<Worlds>().unwrap();
+            let selected = worlds.borrow().selected().map(str::to_owned);
+            selected
+        })?;
+        assert_eq!(selected.as_deref(), Some("preview"));
+
+        Ok(())
+    }
+}
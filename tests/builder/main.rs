@@ -0,0 +1,111 @@
+use {rlua::StdLib, sludge::prelude::*};
+
+#[test]
+fn std_lib_without_string_is_rejected() {
+    let result = Space::builder()
+        .with_std_lib(StdLib::BASE | StdLib::COROUTINE | StdLib::TABLE | StdLib::ERIS)
+        .build();
+    let err = result.err().expect("space built without `StdLib::STRING`");
+    assert!(err.to_string().contains("StdLib::STRING"), "{}", err);
+}
+
+#[test]
+fn std_lib_without_eris_is_accepted() -> Result<()> {
+    let space = Space::builder()
+        .with_std_lib(
+            StdLib::BASE | StdLib::COROUTINE | StdLib::TABLE | StdLib::STRING | StdLib::MATH,
+        )
+        .build()?;
+    let length = space
+        .lua()
+        .context(|lua| lua.load("return string.len('sludge')").eval::<i64>())?;
+    assert_eq!(length, 6);
+
+    Ok(())
+}
+
+#[test]
+fn lazy_modules_are_installed_on_require() -> Result<()> {
+    let space = Space::builder().with_lazy_module("sludge.rng").build()?;
+    space.lua().context(|lua| -> Result<()> {
+        let loaded = lua
+            .load(
+                r#"
+                local before = sludge.rng
+                local rng = require("sludge.rng")
+                return before == nil, type(rng), rng == sludge.rng, rng == require("sludge.rng")
+                "#,
+            )
+            .eval::<(bool, String, bool, bool)>()?;
+        assert_eq!(loaded, (true, "table".to_owned(), true, true));
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+#[test]
+fn lazy_modules_are_loaded_before_unpersisting() -> Result<()> {
+    let space = Space::builder().with_lazy_module("sludge.rng").build()?;
+    space.lua().context(|lua| {
+        lua.load(
+            r#"
+            local rng = require("sludge.rng")
+            sludge.thread.spawn(function()
+                yield("ping")
+                same_rng = rng == sludge.rng
+            end)
+            "#,
+        )
+        .exec()
+    })?;
+    let scheduler = space.scheduler()?;
+    space
+        .lua()
+        .context(|lua| scheduler.borrow_mut().update(lua, 1.))?;
+
+    let mut bytes = Vec::new();
+    space.save(&mut bytes)?;
+
+    let loaded = Space::builder().with_lazy_module("sludge.rng").build()?;
+    loaded.load(&mut &bytes[..])?;
+    let scheduler = loaded.scheduler()?;
+    let same = loaded.lua().context(|lua| -> Result<_> {
+        lua.broadcast("ping", ())?;
+        scheduler.borrow_mut().update(lua, 1.)?;
+        Ok(lua.globals().get::<_, Option<bool>>("same_rng")?)
+    })?;
+    assert_eq!(same, Some(true));
+
+    Ok(())
+}
+
+#[test]
+fn excluded_modules_are_left_out() -> Result<()> {
+    let space = Space::builder().without_module("sludge.rng").build()?;
+    let missing = space
+        .lua()
+        .context(|lua| lua.load("return sludge.rng == nil").eval::<bool>())?;
+    assert!(missing);
+
+    Ok(())
+}
+
+#[test]
+fn required_modules_cannot_be_left_out_or_lazy() {
+    for builder in vec![
+        Space::builder().without_module("sludge"),
+        Space::builder().without_module("sludge.thread"),
+        Space::builder().with_lazy_module("sludge"),
+    ] {
+        let err = builder
+            .build()
+            .err()
+            .expect("space built without the prelude's modules");
+        assert!(
+            err.to_string().contains("required by the sludge Lua API"),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use sludge::{bus::SpaceBus, prelude::*};
+
+/// Build a space sharing `global`, with a thread which records the points of every
+/// `scores` event it sees.
+fn bus_space(global: &SharedResources<'static>) -> Result<Space> {
+    let mut space = Space::builder()
+        .with_global_resources(global.clone())
+        .build()?;
+    // The bus system creates the space's subscriptions when it's first maintained.
+    space.maintain()?;
+    exec(
+        &space,
+        r#"
+        received = {}
+        sludge.thread.spawn(function()
+            while true do
+                local _, _, score = yield("scores")
+                table.insert(received, score.points)
+            end
+        end)
+        "#,
+    )?;
+    update_scheduler(&space)?;
+    Ok(space)
+}
+
+fn shared_bus() -> SharedResources<'static> {
+    let global = SharedResources::new();
+    global.borrow_mut().insert(SpaceBus::new());
+    global
+}
+
+fn exec(space: &Space, code: &str) -> Result<()> {
+    space.lua().context(|lua| lua.load(code).exec())?;
+    Ok(())
+}
+
+fn update_scheduler(space: &Space) -> Result<()> {
+    let scheduler = space.scheduler()?;
+    space
+        .lua()
+        .context(|lua| scheduler.borrow_mut().update(lua, 1.))
+}
+
+fn received(space: &Space) -> Result<Vec<i64>> {
+    Ok(space
+        .lua()
+        .context(|lua| lua.globals().get::<_, Vec<i64>>("received"))?)
+}
+
+#[test]
+fn messages_cross_to_subscribed_spaces() -> Result<()> {
+    let global = shared_bus();
+    let mut sender = bus_space(&global)?;
+    let mut listener = bus_space(&global)?;
+    let mut bystander = bus_space(&global)?;
+    exec(&listener, r#"sludge.bus.subscribe("score", "scores")"#)?;
+
+    exec(&sender, r#"sludge.bus.publish("score", { points = 10 })"#)?;
+    for space in &mut [&mut sender, &mut listener, &mut bystander] {
+        space.fixed_update()?;
+        space.fixed_update()?;
+    }
+
+    assert_eq!(received(&listener)?, vec![10]);
+    // Publishing doesn't deliver to the publisher, or to spaces which didn't subscribe.
+    assert!(received(&sender)?.is_empty());
+    assert!(received(&bystander)?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn unsubscribing_drops_undelivered_messages() -> Result<()> {
+    let global = shared_bus();
+    let sender = bus_space(&global)?;
+    let mut listener = bus_space(&global)?;
+    exec(&listener, r#"sludge.bus.subscribe("score", "scores")"#)?;
+
+    exec(&sender, r#"sludge.bus.publish("score", { points = 1 })"#)?;
+    exec(&listener, r#"sludge.bus.unsubscribe("score")"#)?;
+    exec(&sender, r#"sludge.bus.publish("score", { points = 2 })"#)?;
+    listener.fixed_update()?;
+    listener.fixed_update()?;
+    assert!(received(&listener)?.is_empty());
+
+    exec(&listener, r#"sludge.bus.subscribe("score", "scores")"#)?;
+    exec(&sender, r#"sludge.bus.publish("score", { points = 3 })"#)?;
+    listener.fixed_update()?;
+    listener.fixed_update()?;
+    assert_eq!(received(&listener)?, vec![3]);
+
+    Ok(())
+}
+
+#[test]
+fn messages_arrive_on_the_next_scheduler_update_in_order() -> Result<()> {
+    let global = shared_bus();
+    let mut space = bus_space(&global)?;
+    exec(
+        &space,
+        r#"
+        sludge.bus.subscribe("score", "scores")
+        sludge.thread.spawn(function()
+            for points = 1, 3 do
+                sludge.bus.publish("score", { points = points })
+            end
+        end)
+        "#,
+    )?;
+
+    // The thread publishes during this tick's scheduler update, and the bus system
+    // hands the messages to the scheduler after it, in the same tick.
+    space.fixed_update()?;
+    assert!(received(&space)?.is_empty());
+
+    space.fixed_update()?;
+    assert_eq!(received(&space)?, vec![1, 2, 3]);
+
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use {
+    sludge::{
+        assets::{DefaultCache, Key},
+        dispatcher::Dispatcher,
+        filesystem::Filesystem,
+        graphics::{NullGraphics, Texture},
+        lifetime::Lifetime,
+        prelude::*,
+    },
+    std::path::PathBuf,
+};
+
+fn headless_space() -> Result<Space> {
+    let mut global = OwnedResources::new();
+
+    let mut fs = Filesystem::new("sludge-headless-test", "sludge")?;
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("resources");
+    fs.mount(&path, true);
+
+    global.insert(fs);
+    global.insert(NullGraphics::new(320., 240.));
+
+    let space = Space::with_global_resources(SharedResources::from(global))?;
+    let cache = DefaultCache::new(space.resources().clone());
+    space.resources().borrow_mut().insert(cache);
+
+    Ok(space)
+}
+
+#[test]
+fn headless_texture() -> Result<()> {
+    let space = headless_space()?;
+    let cache = space.fetch_one::<DefaultCache>()?;
+    let texture = cache.borrow().get::<Texture>(&Key::path("/tile.png"))?;
+
+    assert_eq!(texture.load().width(), 171);
+    assert_eq!(texture.load().height(), 167);
+
+    Ok(())
+}
+
+#[test]
+fn headless_update() -> Result<()> {
+    let space = headless_space()?;
+    let mut dispatcher = Dispatcher::new();
+    space.refresh(&mut dispatcher)?;
+
+    space.lua().context(|lua| -> Result<()> {
+        lua.load(
+            r#"
+            ticks = 0
+            sludge.thread.spawn(function()
+                while true do
+                    ticks = ticks + 1
+                    sludge.thread.yield(1)
+                end
+            end)
+            "#,
+        )
+        .exec()?;
+        Ok(())
+    })?;
+
+    let scheduler = space.scheduler()?;
+    for _ in 0..3 {
+        space
+            .lua()
+            .context(|lua| scheduler.borrow_mut().update(lua, 1.0))?;
+        space.dispatch(&mut dispatcher)?;
+    }
+
+    let ticks = space
+        .lua()
+        .context(|lua| lua.globals().get::<_, u32>("ticks"))?;
+    assert!(ticks > 0);
+
+    Ok(())
+}
+
+#[test]
+fn world_events_dispatch_once_per_tick() -> Result<()> {
+    let mut space = Space::new()?;
+    space.lua().context(|lua| -> Result<()> {
+        lua.load(
+            r#"
+            added = 0
+            sludge.world.on("Lifetime", "added", function(entities)
+                added = added + #entities
+            end)
+            "#,
+        )
+        .exec()?;
+        Ok(())
+    })?;
+
+    let added = |space: &Space| -> Result<u32> {
+        Ok(space
+            .lua()
+            .context(|lua| lua.globals().get::<_, u32>("added"))?)
+    };
+
+    space.world()?.borrow_mut().spawn((Lifetime(10.),));
+    space.frame_update()?;
+    assert_eq!(added(&space)?, 0);
+    space.fixed_update()?;
+    assert_eq!(added(&space)?, 1);
+    space.frame_update()?;
+    space.fixed_update()?;
+    assert_eq!(added(&space)?, 1);
+
+    Ok(())
+}
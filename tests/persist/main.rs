@@ -1,6 +1,15 @@
-use sludge::{
-    components::{Name, Persistent},
-    prelude::*,
+use {
+    sludge::{
+        api::{LuaComponent, LuaComponentInterface},
+        components::{Name, Persistent},
+        persist::{PersistPolicies, PersistPolicy, SaveError, SaveFormat, Snapshot},
+        prelude::*,
+        task::{Promise, TaskPool, TaskStatus},
+    },
+    std::{
+        fs,
+        time::{Duration, Instant},
+    },
 };
 
 fn roundtrip(space: &Space) -> Result<Space> {
@@ -63,3 +72,381 @@ fn persist_simple() -> Result<()> {
 
     Ok(())
 }
+
+fn update_scheduler(space: &Space) -> Result<()> {
+    let scheduler = space.scheduler()?;
+    space
+        .lua()
+        .context(|lua| scheduler.borrow_mut().update(lua, 1.))
+}
+
+fn global_flag(space: &Space, name: &str) -> Result<bool> {
+    Ok(space
+        .lua()
+        .context(|lua| lua.globals().get::<_, Option<bool>>(name))?
+        .unwrap_or(false))
+}
+
+#[test]
+fn persist_scheduler() -> Result<()> {
+    let space = Space::new()?;
+    space.lua().context(|lua| {
+        lua.load(
+            r#"
+            sludge.thread.spawn(function()
+                yield(5)
+                woken_by_timer = true
+            end)
+
+            sludge.thread.spawn(function()
+                yield("ping")
+                woken_by_event = true
+            end)
+            "#,
+        )
+        .exec()
+    })?;
+
+    // Run the threads up to their first yields.
+    update_scheduler(&space)?;
+
+    let mut bytes = Vec::<u8>::new();
+    space.save(&mut bytes)?;
+    let new_space = Space::new()?;
+    new_space.load(&mut &bytes[..])?;
+
+    for _ in 0..4 {
+        update_scheduler(&new_space)?;
+    }
+    assert!(!global_flag(&new_space, "woken_by_timer")?);
+
+    update_scheduler(&new_space)?;
+    assert!(global_flag(&new_space, "woken_by_timer")?);
+
+    assert!(!global_flag(&new_space, "woken_by_event")?);
+    new_space.lua().context(|lua| lua.broadcast("ping", ()))?;
+    update_scheduler(&new_space)?;
+    assert!(global_flag(&new_space, "woken_by_event")?);
+
+    Ok(())
+}
+
+#[test]
+fn load_saves_from_before_the_container() -> Result<()> {
+    let space = Space::new()?;
+    space.lua().context(|lua| {
+        lua.load(
+            r#"
+            sludge.thread.spawn(function()
+                yield("ping")
+                woken_by_event = true
+            end)
+            "#,
+        )
+        .exec()
+    })?;
+    update_scheduler(&space)?;
+
+    // A raw Eris dump, as `Space::save` wrote before saves had a container.
+    let mut bytes = Vec::<u8>::new();
+    space
+        .lua()
+        .context(|lua| sludge::persist::persist(lua, &space, &mut bytes))?;
+    let new_space = Space::new()?;
+    let err = new_space.load(&mut &bytes[..]).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<SaveError>(),
+        Some(SaveError::NotASave)
+    ));
+    new_space.load_legacy(&mut &bytes[..])?;
+
+    new_space.lua().context(|lua| lua.broadcast("ping", ()))?;
+    update_scheduler(&new_space)?;
+    assert!(global_flag(&new_space, "woken_by_event")?);
+
+    // Containers are still checked, rather than being mistaken for raw dumps.
+    let mut sealed = Vec::<u8>::new();
+    space.save(&mut sealed)?;
+    *sealed.last_mut().unwrap() ^= 0xff;
+    let err = Space::new()?.load(&mut &sealed[..]).unwrap_err();
+    assert!(err
+        .downcast_ref::<SaveError>()
+        .map_or(false, SaveError::is_corruption));
+
+    Ok(())
+}
+
+#[test]
+fn load_refuses_bytes_which_are_not_a_save() -> Result<()> {
+    let space = Space::new()?;
+    for bytes in &[&b"definitely not a save"[..], &[][..]] {
+        let err = space.load(&mut &bytes[..]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SaveError>(),
+            Some(SaveError::NotASave)
+        ));
+    }
+
+    // A container isn't a legacy save either, so it isn't handed to Eris as one.
+    let mut sealed = Vec::<u8>::new();
+    space.save(&mut sealed)?;
+    assert!(Space::new()?.load_legacy(&mut &sealed[..]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn persist_thread_locals_and_names() -> Result<()> {
+    let space = Space::new()?;
+    space.lua().context(|lua| {
+        lua.load(
+            r#"
+            sludge.thread.spawn(function()
+                sludge.thread.set_name("keeper")
+                sludge.thread.set_local("owner", 7)
+                yield("ping")
+                kept_owner = sludge.thread.get_local("owner")
+                kept_name = sludge.thread.name()
+            end)
+            "#,
+        )
+        .exec()
+    })?;
+
+    update_scheduler(&space)?;
+
+    let mut bytes = Vec::<u8>::new();
+    space.save(&mut bytes)?;
+    let new_space = Space::new()?;
+    new_space.load(&mut &bytes[..])?;
+
+    new_space.lua().context(|lua| lua.broadcast("ping", ()))?;
+    update_scheduler(&new_space)?;
+
+    let (owner, name) = new_space.lua().context(|lua| {
+        let globals = lua.globals();
+        Ok::<_, LuaError>((
+            globals.get::<_, Option<i64>>("kept_owner")?,
+            globals.get::<_, Option<String>>("kept_name")?,
+        ))
+    })?;
+    assert_eq!(owner, Some(7));
+    assert_eq!(name.as_deref(), Some("keeper"));
+
+    Ok(())
+}
+
+#[test]
+fn snapshot_scheduler() -> Result<()> {
+    let space = Space::new()?;
+    space.lua().context(|lua| {
+        lua.load(
+            r#"
+            sludge.thread.spawn(function()
+                sludge.thread.set_name("sleeper")
+                sludge.thread.set_local("owner", 7)
+                yield(5)
+            end)
+
+            sludge.thread.spawn(function()
+                assert(sludge.thread.get_local("owner") == nil)
+                yield("ping")
+            end)
+            "#,
+        )
+        .exec()
+    })?;
+    update_scheduler(&space)?;
+
+    let snapshot = space.snapshot()?;
+    assert_eq!(snapshot.scheduler.threads.len(), 2);
+    assert_eq!(snapshot.scheduler.waiting["ping"].len(), 1);
+    assert_eq!(
+        snapshot.scheduler.names.values().collect::<Vec<_>>(),
+        vec!["sleeper"]
+    );
+    assert!(snapshot
+        .scheduler
+        .queue
+        .iter()
+        .any(|w| w.kind == "timed" && w.ticks.is_some()));
+
+    let mut bytes = Vec::<u8>::new();
+    space.save_as(&mut bytes, SaveFormat::Json)?;
+    assert_eq!(serde_json::from_slice::<Snapshot>(&bytes)?, snapshot);
+
+    Ok(())
+}
+
+fn promise_status(space: &Space, key: &LuaRegistryKey) -> Result<TaskStatus> {
+    space.lua().context(|lua| {
+        let promise = lua.registry_value::<LuaAnyUserData>(key)?;
+        let status = promise.borrow::<Promise>()?.status().clone();
+        Ok(status)
+    })
+}
+
+#[test]
+fn save_async_overlapping() -> Result<()> {
+    let mut space = Space::new()?;
+    space.resources().borrow_mut().insert(TaskPool::new(2)?);
+    space.lua().context(|lua| {
+        lua.load(
+            r#"
+            saves = 0
+            sludge.thread.spawn(function()
+                while true do
+                    local _, _, path, err = yield(sludge.persist.SAVED_EVENT)
+                    assert(err == nil, err)
+                    saves = saves + 1
+                end
+            end)
+            "#,
+        )
+        .exec()
+    })?;
+    update_scheduler(&space)?;
+
+    let dir = std::env::temp_dir().join(format!("sludge-save-async-{}", std::process::id()));
+    let path = dir.join("save.json");
+    let first = space.save_async(&path, SaveFormat::Json)?;
+    let second = space.save_async(&path, SaveFormat::Json)?;
+
+    let start = Instant::now();
+    while promise_status(&space, &first)? == TaskStatus::Running
+        || promise_status(&space, &second)? == TaskStatus::Running
+    {
+        assert!(start.elapsed() < Duration::from_secs(5), "save timed out");
+        space.fixed_update()?;
+        std::thread::sleep(Duration::from_millis(1));
+    }
+
+    assert_eq!(promise_status(&space, &first)?, TaskStatus::Done);
+    assert_eq!(promise_status(&space, &second)?, TaskStatus::Done);
+
+    // Give the waiting thread a tick to hear about the second save.
+    space.fixed_update()?;
+    let saves = space
+        .lua()
+        .context(|lua| lua.globals().get::<_, u32>("saves"))?;
+    assert_eq!(saves, 2);
+
+    // The saved file is whole, whichever save won.
+    serde_json::from_slice::<Snapshot>(&fs::read(&path)?)?;
+    assert_eq!(
+        fs::read_dir(&dir)?.count(),
+        1,
+        "temporary files left behind"
+    );
+
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+/// Stands in for a component holding something which can't be saved, like a GPU handle.
+#[derive(Debug, Clone, Copy)]
+struct Scratch;
+
+impl<'a> SmartComponent<ScContext<'a>> for Scratch {}
+
+impl LuaComponentInterface for Scratch {
+    fn accessor<'lua>(_lua: LuaContext<'lua>, _entity: Entity) -> LuaResult<LuaValue<'lua>> {
+        Ok(LuaValue::Nil)
+    }
+
+    fn bundler<'lua>(
+        _lua: LuaContext<'lua>,
+        _args: LuaValue<'lua>,
+        _builder: &mut EntityBuilder,
+    ) -> LuaResult<()> {
+        panic!("skipped components are never bundled")
+    }
+}
+
+inventory::submit! {
+    LuaComponent::new::<Scratch>("PersistTestScratch")
+}
+
+inventory::submit! {
+    PersistPolicy::skip::<Scratch>("PersistTestScratch")
+}
+
+/// A component only its custom policy knows how to save.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Counter(u32);
+
+impl<'a> SmartComponent<ScContext<'a>> for Counter {}
+
+impl LuaComponentInterface for Counter {
+    fn accessor<'lua>(_lua: LuaContext<'lua>, _entity: Entity) -> LuaResult<LuaValue<'lua>> {
+        Ok(LuaValue::Nil)
+    }
+
+    fn bundler<'lua>(
+        _lua: LuaContext<'lua>,
+        _args: LuaValue<'lua>,
+        _builder: &mut EntityBuilder,
+    ) -> LuaResult<()> {
+        panic!("custom-saved components are never bundled")
+    }
+}
+
+inventory::submit! {
+    LuaComponent::new::<Counter>("PersistTestCounter")
+}
+
+fn save_counter<'lua>(lua: LuaContext<'lua>, entity: Entity) -> LuaResult<LuaValue<'lua>> {
+    let world = lua.fetch_one::<World>()?;
+    let count = world.borrow().get::<Counter>(entity).to_lua_err()?.0;
+    count.to_lua(lua)
+}
+
+fn load_counter<'lua>(
+    lua: LuaContext<'lua>,
+    entity: Entity,
+    saved: LuaValue<'lua>,
+) -> LuaResult<()> {
+    let count = u32::from_lua(saved, lua)?;
+    lua.fetch_one::<World>()?
+        .borrow_mut()
+        .insert_one(entity, Counter(count + 1))
+        .to_lua_err()
+}
+
+inventory::submit! {
+    PersistPolicy::custom::<Counter, _, _>("PersistTestCounter", save_counter, load_counter)
+}
+
+#[test]
+fn persist_policies() -> Result<()> {
+    let space = Space::new()?;
+    space.world()?.borrow_mut().spawn((
+        Name("policed".to_owned()),
+        Persistent,
+        Scratch,
+        Counter(41),
+    ));
+
+    let space = roundtrip(&space)?;
+    let world = space.world()?;
+    let world = world.borrow();
+    let mut query = world.query::<(&Name, Option<&Scratch>, Option<&Counter>)>();
+    let loaded = query
+        .iter()
+        .map(|(_, (name, scratch, counter))| (name.0.clone(), scratch.is_some(), counter.copied()));
+    assert_eq!(
+        loaded.collect::<Vec<_>>(),
+        vec![("policed".to_owned(), false, Some(Counter(42)))]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn duplicate_persist_policies_are_an_error() {
+    let policies = PersistPolicies::from_policies(vec![
+        PersistPolicy::skip::<Scratch>("PersistTestScratch"),
+        PersistPolicy::skip::<Scratch>("PersistTestScratch"),
+    ]);
+    assert!(policies.is_err());
+}
@@ -0,0 +1,109 @@
+use sludge::{prelude::*, sandbox::Sandbox};
+
+fn sandboxed_space(sandbox: Sandbox) -> Result<Space> {
+    let space = Space::builder().with_sandbox(sandbox).build()?;
+    space.lua().context(|lua| {
+        lua.load(
+            r#"
+            ticks = 0
+            sludge.thread.spawn(function()
+                while true do
+                    ticks = ticks + 1
+                    yield(1)
+                end
+            end)
+            sludge.thread.spawn(function()
+                local _, _, thread, kind, message = yield("script.violation")
+                victim, violation, violation_message = thread, kind, message
+            end)
+            "#,
+        )
+        .exec()
+    })?;
+    Ok(space)
+}
+
+fn update_scheduler(space: &Space) -> Result<()> {
+    let scheduler = space.scheduler()?;
+    space
+        .lua()
+        .context(|lua| scheduler.borrow_mut().update(lua, 1.))
+}
+
+fn global<T: for<'lua> FromLua<'lua>>(space: &Space, name: &str) -> Result<T> {
+    Ok(space.lua().context(|lua| lua.globals().get::<_, T>(name))?)
+}
+
+/// Spawn `body` as a thread of its own, kept in the global `offender`, and run the
+/// scheduler until the violation has been broadcast and received.
+fn run_offender(space: &Space, body: &str) -> Result<()> {
+    space.lua().context(|lua| {
+        lua.load(&format!(
+            "offender = sludge.thread.spawn(function() {} end)",
+            body
+        ))
+        .exec()
+    })?;
+
+    for _ in 0..3 {
+        update_scheduler(space)?;
+    }
+
+    Ok(())
+}
+
+fn offender_was_the_victim(space: &Space) -> Result<bool> {
+    Ok(space
+        .lua()
+        .context(|lua| lua.load("return victim == offender").eval::<bool>())?)
+}
+
+#[test]
+fn runaway_loops_only_kill_their_own_thread() -> Result<()> {
+    let space = sandboxed_space(Sandbox::new().with_instruction_limit(100_000))?;
+    run_offender(&space, "while true do end")?;
+
+    assert!(offender_was_the_victim(&space)?);
+    assert_eq!(global::<String>(&space, "violation")?, "instructions");
+    assert!(global::<String>(&space, "violation_message")?.contains("100000"));
+
+    // Everything else keeps running.
+    let before = global::<u32>(&space, "ticks")?;
+    update_scheduler(&space)?;
+    assert_eq!(global::<u32>(&space, "ticks")?, before + 1);
+
+    Ok(())
+}
+
+#[test]
+fn instruction_limits_are_per_resume() -> Result<()> {
+    let space = sandboxed_space(Sandbox::new().with_instruction_limit(100_000))?;
+    // Well under the limit on each resume, but far over it in total.
+    run_offender(
+        &space,
+        "for _ = 1, 50 do for i = 1, 1000 do end yield(1) end finished = true",
+    )?;
+    for _ in 0..50 {
+        update_scheduler(&space)?;
+    }
+
+    assert_eq!(global::<Option<String>>(&space, "violation")?, None);
+    assert!(global::<bool>(&space, "finished")?);
+
+    Ok(())
+}
+
+#[test]
+fn memory_limits_kill_the_allocating_thread() -> Result<()> {
+    let space = sandboxed_space(Sandbox::new().with_memory_limit(32 * 1024 * 1024))?;
+    run_offender(&space, r#"local huge = string.rep("x", 64 * 1024 * 1024)"#)?;
+
+    assert!(offender_was_the_victim(&space)?);
+    assert_eq!(global::<String>(&space, "violation")?, "memory");
+
+    let before = global::<u32>(&space, "ticks")?;
+    update_scheduler(&space)?;
+    assert_eq!(global::<u32>(&space, "ticks")?, before + 1);
+
+    Ok(())
+}
@@ -0,0 +1,392 @@
+use {
+    sludge::{prelude::*, ErrorPolicy, OverflowPolicy, ScriptError, SCRIPT_ERROR_EVENT},
+    std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        thread,
+        time::Duration,
+    },
+};
+
+fn update_scheduler(space: &Space) -> Result<()> {
+    let scheduler = space.scheduler()?;
+    space
+        .lua()
+        .context(|lua| scheduler.borrow_mut().update(lua, 1.))
+}
+
+fn spawn_failing_thread(space: &Space) -> Result<()> {
+    space.lua().context(|lua| {
+        lua.load(
+            r#"
+            sludge.thread.spawn(function()
+                sludge.thread.set_name("boss")
+                yield()
+                error("the boss fell over")
+            end)
+            "#,
+        )
+        .exec()
+    })?;
+    Ok(())
+}
+
+#[test]
+fn errors_are_broadcast_by_default() -> Result<()> {
+    let space = Space::new()?;
+    space.lua().context(|lua| {
+        lua.load(&format!(
+            r#"
+            sludge.thread.spawn(function()
+                local _, _, err = yield("{}")
+                error_name = err.name
+                error_message = err.message
+                error_traceback = err.traceback
+            end)
+            "#,
+            SCRIPT_ERROR_EVENT
+        ))
+        .exec()
+    })?;
+    spawn_failing_thread(&space)?;
+
+    for _ in 0..3 {
+        update_scheduler(&space)?;
+    }
+
+    let (name, message, traceback) = space.lua().context(|lua| -> Result<_> {
+        let globals = lua.globals();
+        Ok((
+            globals.get::<_, String>("error_name")?,
+            globals.get::<_, String>("error_message")?,
+            globals.get::<_, String>("error_traceback")?,
+        ))
+    })?;
+    assert_eq!(name, "boss");
+    assert!(message.contains("the boss fell over"), "{}", message);
+    assert!(!traceback.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn hooks_see_errors_without_broadcasts() -> Result<()> {
+    let space = Space::new()?;
+    let seen = Arc::new(Mutex::new(Vec::<ScriptError>::new()));
+    let hook_seen = seen.clone();
+    space.scheduler()?.borrow_mut().set_error_policy(
+        ErrorPolicy {
+            log: false,
+            broadcast: false,
+            hook: None,
+        }
+        .with_hook(move |error| hook_seen.lock().unwrap().push(error.clone())),
+    );
+
+    space.lua().context(|lua| {
+        lua.load(&format!(
+            r#"
+            sludge.thread.spawn(function()
+                yield("{}")
+                heard_error = true
+            end)
+            "#,
+            SCRIPT_ERROR_EVENT
+        ))
+        .exec()
+    })?;
+    spawn_failing_thread(&space)?;
+
+    for _ in 0..3 {
+        update_scheduler(&space)?;
+    }
+
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 1);
+    assert_eq!(seen[0].name.as_deref(), Some("boss"));
+    assert!(seen[0].message.contains("the boss fell over"));
+
+    let heard = space
+        .lua()
+        .context(|lua| lua.globals().get::<_, Option<bool>>("heard_error"))?;
+    assert_eq!(heard, None);
+
+    Ok(())
+}
+
+fn global<T: for<'lua> FromLua<'lua>>(space: &Space, name: &str) -> Result<T> {
+    Ok(space.lua().context(|lua| lua.globals().get::<_, T>(name))?)
+}
+
+#[test]
+fn wait_until_wakes_on_any_truthy_value() -> Result<()> {
+    let space = Space::new()?;
+    space.lua().context(|lua| {
+        lua.load(
+            r#"
+            ready = nil
+            woken = 0
+            sludge.thread.spawn(function()
+                sludge.thread.wait_until(function() return ready end)
+                woken = woken + 1
+            end)
+            "#,
+        )
+        .exec()
+    })?;
+
+    for value in &["nil", "false"] {
+        space
+            .lua()
+            .context(|lua| lua.load(&format!("ready = {}", value)).exec())?;
+        update_scheduler(&space)?;
+        assert_eq!(global::<i64>(&space, "woken")?, 0, "woke on `{}`", value);
+    }
+
+    // Zero is truthy in Lua, unlike in Rust's idea of a condition.
+    space.lua().context(|lua| lua.load("ready = 0").exec())?;
+    update_scheduler(&space)?;
+    assert_eq!(global::<i64>(&space, "woken")?, 1);
+
+    Ok(())
+}
+
+#[test]
+fn failing_conditions_kill_their_threads() -> Result<()> {
+    let space = Space::new()?;
+    space.lua().context(|lua| {
+        lua.load(
+            r#"
+            sludge.thread.spawn(function()
+                yield(function() error("no condition here") end)
+                woken = true
+            end)
+            "#,
+        )
+        .exec()
+    })?;
+
+    for _ in 0..3 {
+        update_scheduler(&space)?;
+    }
+    assert_eq!(global::<Option<bool>>(&space, "woken")?, None);
+
+    Ok(())
+}
+
+fn bounded_space(bound: usize, overflow: OverflowPolicy) -> Result<Space> {
+    let space = Space::builder()
+        .with_channel_bound(bound)
+        .with_overflow_policy(overflow)
+        .build()?;
+    // Start from empty channels, whatever building the space queued up.
+    update_scheduler(&space)?;
+    Ok(space)
+}
+
+#[test]
+fn full_channels_reject_messages_by_default() -> Result<()> {
+    let space = bounded_space(2, OverflowPolicy::default())?;
+    let err = space.lua().context(|lua| -> Result<_> {
+        lua.broadcast("a", ())?;
+        lua.broadcast("b", ())?;
+        Ok(lua.broadcast("c", ()).unwrap_err())
+    })?;
+    assert!(
+        format!("{:?}", err).contains("event channel is full"),
+        "{:?}",
+        err
+    );
+
+    let events = space.scheduler()?.borrow().stats().events;
+    assert_eq!(
+        (events.capacity, events.depth, events.high_water),
+        (2, 2, 2)
+    );
+    assert_eq!((events.dropped, events.rejected), (0, 1));
+
+    update_scheduler(&space)?;
+    let events = space.scheduler()?.borrow().stats().events;
+    assert_eq!((events.depth, events.high_water), (0, 2));
+
+    Ok(())
+}
+
+#[test]
+fn drop_oldest_makes_room_for_new_messages() -> Result<()> {
+    let space = bounded_space(2, OverflowPolicy::DropOldest)?;
+    space.lua().context(|lua| {
+        lua.load(
+            r#"
+            sludge.thread.spawn(function() yield("first"); got_first = true end)
+            sludge.thread.spawn(function() yield("third"); got_third = true end)
+            "#,
+        )
+        .exec()
+    })?;
+    update_scheduler(&space)?;
+
+    space.lua().context(|lua| -> LuaResult<()> {
+        for &event in &["first", "second", "third"] {
+            lua.broadcast(event, ())?;
+        }
+        Ok(())
+    })?;
+    let events = space.scheduler()?.borrow().stats().events;
+    assert_eq!((events.depth, events.dropped, events.rejected), (2, 1, 0));
+
+    update_scheduler(&space)?;
+    assert_eq!(global::<Option<bool>>(&space, "got_first")?, None);
+    assert_eq!(global::<Option<bool>>(&space, "got_third")?, Some(true));
+
+    Ok(())
+}
+
+#[test]
+fn blocked_pushes_wait_for_the_scheduler() -> Result<()> {
+    let space = bounded_space(1, OverflowPolicy::Block)?;
+    let queue = space.scheduler()?.borrow().queue().clone();
+    let (first, second) = space.lua().context(|lua| -> LuaResult<_> {
+        let f = lua.create_function(|_, ()| Ok(()))?;
+        Ok((
+            lua.create_registry_value(lua.create_thread(f.clone())?)?,
+            lua.create_registry_value(lua.create_thread(f)?)?,
+        ))
+    })?;
+    queue.push_spawn(first)?;
+
+    let pushed = Arc::new(AtomicBool::new(false));
+    let pusher = {
+        let (queue, pushed) = (queue.clone(), pushed.clone());
+        thread::spawn(move || {
+            queue.push_spawn(second).unwrap();
+            pushed.store(true, Ordering::SeqCst);
+        })
+    };
+
+    thread::sleep(Duration::from_millis(50));
+    assert!(!pushed.load(Ordering::SeqCst), "the spawn channel is full");
+    assert_eq!(queue.stats().spawns.depth, 1);
+
+    update_scheduler(&space)?;
+    pusher.join().unwrap();
+    assert!(pushed.load(Ordering::SeqCst));
+
+    update_scheduler(&space)?;
+    let spawns = queue.stats().spawns;
+    assert_eq!((spawns.depth, spawns.high_water), (0, 1));
+    assert_eq!((spawns.dropped, spawns.rejected), (0, 0));
+
+    Ok(())
+}
+
+#[test]
+fn delayed_broadcasts_go_out_in_order_once_due() -> Result<()> {
+    let space = Space::new()?;
+    space.lua().context(|lua| {
+        lua.load(
+            r#"
+            sludge.thread.spawn(function()
+                local _, name, value = yield("first", "second")
+                woken_by, woken_with = name, value
+            end)
+            sludge.thread.spawn(function()
+                sludge.thread.broadcast_in(3, "second", 2)
+                sludge.thread.broadcast_in(3, "first", 1)
+            end)
+            "#,
+        )
+        .exec()
+    })?;
+
+    update_scheduler(&space)?;
+    assert_eq!(space.scheduler()?.borrow().delayed_events(), 2);
+    for _ in 0..2 {
+        update_scheduler(&space)?;
+        assert_eq!(global::<Option<String>>(&space, "woken_by")?, None);
+    }
+
+    update_scheduler(&space)?;
+    assert_eq!(global::<String>(&space, "woken_by")?, "second");
+    assert_eq!(global::<i64>(&space, "woken_with")?, 2);
+    assert_eq!(space.scheduler()?.borrow().delayed_events(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn delayed_notifications_wake_their_thread() -> Result<()> {
+    let space = Space::new()?;
+    space.lua().context(|lua| {
+        lua.load(
+            r#"
+            local sleeper = sludge.thread.spawn(function()
+                local _, message = yield()
+                notified = message
+            end)
+            sludge.thread.spawn(function()
+                sludge.thread.notify_in(2, sleeper, "wake up")
+            end)
+            "#,
+        )
+        .exec()
+    })?;
+
+    for _ in 0..2 {
+        update_scheduler(&space)?;
+        assert_eq!(global::<Option<String>>(&space, "notified")?, None);
+    }
+    update_scheduler(&space)?;
+    assert_eq!(global::<String>(&space, "notified")?, "wake up");
+
+    Ok(())
+}
+
+#[test]
+fn delayed_events_survive_saving_and_loading() -> Result<()> {
+    let space = Space::new()?;
+    space.lua().context(|lua| {
+        lua.load(
+            r#"
+            local sleeper = sludge.thread.spawn(function()
+                local _, message = yield()
+                notified = message
+            end)
+            sludge.thread.spawn(function()
+                local _, name, value = yield("ping")
+                woken_by, woken_with = name, value
+            end)
+            sludge.thread.spawn(function()
+                sludge.thread.broadcast_in(3, "ping", 7)
+                sludge.thread.notify_in(3, sleeper, "wake up")
+            end)
+            "#,
+        )
+        .exec()
+    })?;
+
+    update_scheduler(&space)?;
+    assert_eq!(space.scheduler()?.borrow().delayed_events(), 2);
+
+    let mut bytes = Vec::new();
+    space.save(&mut bytes)?;
+    let loaded = Space::new()?;
+    loaded.load(&mut &bytes[..])?;
+    assert_eq!(loaded.scheduler()?.borrow().delayed_events(), 2);
+
+    for _ in 0..2 {
+        update_scheduler(&loaded)?;
+        assert_eq!(global::<Option<String>>(&loaded, "woken_by")?, None);
+        assert_eq!(global::<Option<String>>(&loaded, "notified")?, None);
+    }
+
+    update_scheduler(&loaded)?;
+    assert_eq!(global::<String>(&loaded, "woken_by")?, "ping");
+    assert_eq!(global::<i64>(&loaded, "woken_with")?, 7);
+    assert_eq!(global::<String>(&loaded, "notified")?, "wake up");
+    assert_eq!(loaded.scheduler()?.borrow().delayed_events(), 0);
+
+    Ok(())
+}
@@ -0,0 +1,149 @@
+use {
+    sludge::{
+        prelude::*,
+        task::{self, ChunkedTask, Progress, TaskPool},
+    },
+    std::time::{Duration, Instant},
+};
+
+/// Counts up to a limit, one step at a time.
+struct CountTo {
+    count: u32,
+    limit: u32,
+}
+
+impl ChunkedTask for CountTo {
+    fn step<'lua>(&mut self, lua: LuaContext<'lua>) -> Result<Progress<'lua>> {
+        self.count += 1;
+        if self.count < self.limit {
+            Ok(Progress::Pending)
+        } else {
+            Ok(Progress::Done(self.count.to_lua(lua)?))
+        }
+    }
+}
+
+/// Never finishes, but takes long enough to use up the budget every step.
+struct Slow;
+
+impl ChunkedTask for Slow {
+    fn step<'lua>(&mut self, _lua: LuaContext<'lua>) -> Result<Progress<'lua>> {
+        std::thread::sleep(Duration::from_millis(5));
+        Ok(Progress::Pending)
+    }
+}
+
+fn status(space: &Space, promise: &str) -> Result<String> {
+    space.lua().context(|lua| {
+        Ok(lua
+            .load(&format!("return {}:status()", promise))
+            .eval::<String>()?)
+    })
+}
+
+#[test]
+fn chunked_task_finishes_through_space_updates() -> Result<()> {
+    let mut space = Space::new()?;
+    space.lua().context(|lua| -> Result<()> {
+        let promise = task::spawn(lua, CountTo { count: 0, limit: 3 })?;
+        lua.globals().set("promise", promise)?;
+        Ok(())
+    })?;
+
+    assert_eq!(status(&space, "promise")?, "running");
+    for _ in 0..10 {
+        space.fixed_update()?;
+    }
+
+    assert_eq!(status(&space, "promise")?, "done");
+    let result = space
+        .lua()
+        .context(|lua| lua.load("return promise:result()").eval::<u32>())?;
+    assert_eq!(result, 3);
+
+    Ok(())
+}
+
+#[test]
+fn chunked_tasks_are_spread_across_ticks() -> Result<()> {
+    let mut space = Space::new()?;
+    space.lua().context(|lua| -> Result<()> {
+        let slow = task::spawn(lua, Slow)?;
+        let quick = task::spawn(lua, CountTo { count: 0, limit: 1 })?;
+        lua.globals().set("slow", slow)?;
+        lua.globals().set("quick", quick)?;
+        Ok(())
+    })?;
+
+    // The slow task uses up the whole budget, so the quick one has to wait a tick.
+    space.fixed_update()?;
+    assert_eq!(status(&space, "quick")?, "running");
+    space.fixed_update()?;
+    assert_eq!(status(&space, "quick")?, "done");
+    assert_eq!(status(&space, "slow")?, "running");
+
+    Ok(())
+}
+
+/// Update the space until the named promise is done, or give up after a few seconds.
+fn wait_for(space: &mut Space, promise: &str) -> Result<String> {
+    let start = Instant::now();
+    loop {
+        space.fixed_update()?;
+        let status = status(space, promise)?;
+        if status != "running" || start.elapsed() > Duration::from_secs(5) {
+            return Ok(status);
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+fn pooled_space() -> Result<Space> {
+    let space = Space::new()?;
+    space.resources().borrow_mut().insert(TaskPool::new(2)?);
+    Ok(space)
+}
+
+#[test]
+fn pooled_job_resolves_its_promise() -> Result<()> {
+    let mut space = pooled_space()?;
+    space.lua().context(|lua| -> Result<()> {
+        let promise = task::spawn_pooled(lua, || Ok((1..=10).sum::<u32>()))?;
+        lua.globals().set("promise", promise)?;
+        Ok(())
+    })?;
+
+    assert_eq!(wait_for(&mut space, "promise")?, "done");
+    let result = space
+        .lua()
+        .context(|lua| lua.load("return promise:result()").eval::<u32>())?;
+    assert_eq!(result, 55);
+
+    Ok(())
+}
+
+#[test]
+fn panicking_job_fails_its_promise() -> Result<()> {
+    let mut space = pooled_space()?;
+    space.lua().context(|lua| -> Result<()> {
+        let panicked = task::spawn_pooled(lua, || -> Result<u32> { panic!("oh no") })?;
+        lua.globals().set("panicked", panicked)?;
+        Ok(())
+    })?;
+
+    assert_eq!(wait_for(&mut space, "panicked")?, "failed");
+
+    // The pool still has both of its workers.
+    space.lua().context(|lua| -> Result<()> {
+        let first = task::spawn_pooled(lua, || Ok(1))?;
+        let second = task::spawn_pooled(lua, || Ok(2))?;
+        lua.globals().set("first", first)?;
+        lua.globals().set("second", second)?;
+        Ok(())
+    })?;
+
+    assert_eq!(wait_for(&mut space, "first")?, "done");
+    assert_eq!(wait_for(&mut space, "second")?, "done");
+
+    Ok(())
+}